@@ -22,16 +22,26 @@
             rustc = rust;
             cargo = rust;
           };
+          # the tests run with the same features as the build
+          bnuystore = { features, noDefaultFeatures ? false }: platform.buildRustPackage {
+            name = "bnuystore";
+            src = ./.;
+            cargoLock = { lockFile = ./Cargo.lock; };
+            buildFeatures = features;
+            buildNoDefaultFeatures = noDefaultFeatures;
+
+            nativeBuildInputs = [ pkgs.pkg-config ];
+            buildInputs = [ pkgs.openssl ];
+          };
       in rec {
         # includes bin/bnuystore-diagnose,front-node,storage-node
-        packages.bnuystore = platform.buildRustPackage {
-          name = "bnuystore";
-          src = ./.;
-          cargoLock = { lockFile = ./Cargo.lock; };
-          buildFeatures = [ "front-node" ];
-
-          nativeBuildInputs = [ pkgs.pkg-config ];
-          buildInputs = [ pkgs.openssl ];
+        packages.bnuystore = bnuystore { features = [ "front-node" ]; };
+        # every supported feature combination has to build and pass its tests
+        checks = {
+          default = packages.bnuystore;
+          storage-only = bnuystore { features = []; noDefaultFeatures = true; };
+          http-only = bnuystore { features = [ "front-node" ]; noDefaultFeatures = true; };
+          all = bnuystore { features = [ "front-node" "dev-mode" "testing" ]; };
         };
         devShells.default = pkgs.mkShell {
           packages = [ rust ];
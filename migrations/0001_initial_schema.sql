@@ -0,0 +1,134 @@
+-- The schema as it was before migrations were tracked. Every statement is safe to run again, so this
+-- can be applied to databases which were set up by hand with initialize_schema.sql.
+-- Later schema changes go in new files after this one, see src/front_node/migrations.rs
+
+-- the root directory below has id 0
+SET sql_mode = 'NO_AUTO_VALUE_ON_ZERO';
+
+CREATE TABLE IF NOT EXISTS nodes (
+    id INT NOT NULL AUTO_INCREMENT,
+    name TEXT NOT NULL,
+    -- name references config file, all settings are taken from there
+    -- business logic ensures that all node names must exist in the config file
+    -- and if new machines are added in the config file, they are added to this table
+    -- nodes may never be dropped from this table
+
+    -- todo: store whether the machine is reachable
+    -- todo: store information about upload speed, download speed, uptime
+
+    PRIMARY KEY (id)
+);
+
+-- storage tier, set from the config on startup. lower tiers are faster, new files go to the lowest tier with space
+ALTER TABLE nodes ADD COLUMN IF NOT EXISTS tier INT NOT NULL DEFAULT 0;
+
+-- disabled nodes are disconnected from within node_monitor.poll_interval_s, and not connected to until re-enabled
+ALTER TABLE nodes ADD COLUMN IF NOT EXISTS disabled BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE TABLE IF NOT EXISTS directories (
+    id INT NOT NULL AUTO_INCREMENT,
+    name TEXT NOT NULL,
+    parent_id INT, -- the root directory has parent_id NULL
+
+    PRIMARY KEY (id),
+    FOREIGN KEY (parent_id) REFERENCES directories(id)
+);
+
+-- no two directories in a directory share a name, even when created concurrently. fails on databases
+-- which already have such duplicates, which have to be renamed or merged by hand first
+ALTER TABLE directories ADD UNIQUE INDEX IF NOT EXISTS unique_name_in_parent (parent_id, name);
+
+CREATE TABLE IF NOT EXISTS root_directory (
+    directory_id INT NOT NULL,
+
+    uniqueness_constraint ENUM('1') NOT NULL DEFAULT '1' UNIQUE,
+
+    FOREIGN KEY (directory_id) REFERENCES directories(id)
+);
+
+INSERT INTO directories(id, name, parent_id)
+    SELECT
+        0, '<root>', NULL
+        WHERE NOT EXISTS (SELECT * FROM directories);
+
+INSERT INTO root_directory(directory_id)
+    SELECT 0
+        WHERE NOT EXISTS (SELECT * FROM root_directory);
+
+CREATE TABLE IF NOT EXISTS files (
+    uuid BINARY(16) NOT NULL,
+    name BLOB NOT NULL,
+    directory_id INT NOT NULL,
+
+    stored_on_node_id INT NOT NULL,
+
+    PRIMARY KEY (uuid),
+    FOREIGN KEY (stored_on_node_id) REFERENCES nodes(id),
+    FOREIGN KEY (directory_id) REFERENCES directories(id)
+);
+
+-- no two files in a directory share a name, even when uploaded concurrently. fails on databases which
+-- already have such duplicates, of which all but one have to be deleted or renamed by hand first
+ALTER TABLE files ADD UNIQUE INDEX IF NOT EXISTS unique_name_in_directory (directory_id, name);
+
+-- when the file was last downloaded, NULL if never. updated in batches, so may lag behind a bit
+ALTER TABLE files ADD COLUMN IF NOT EXISTS last_accessed DATETIME NULL;
+
+-- content digest, and the algorithm it was computed with (blake3 or sha256). NULL for files uploaded
+-- before digests were stored, until they are rehashed
+ALTER TABLE files ADD COLUMN IF NOT EXISTS hash_algorithm VARCHAR(16) NULL;
+ALTER TABLE files ADD COLUMN IF NOT EXISTS digest VARBINARY(64) NULL;
+
+-- size in bytes. NULL for files uploaded before sizes were stored, until they are rehashed
+ALTER TABLE files ADD COLUMN IF NOT EXISTS size BIGINT UNSIGNED NULL;
+
+-- the Content-Type the file was uploaded with. NULL if none was given, in which case downloads guess
+-- it from the file name
+ALTER TABLE files ADD COLUMN IF NOT EXISTS content_type VARCHAR(255) NULL;
+
+-- files waiting to be pushed to the backup sink. rows are added in the same transaction as the file,
+-- and marked done only after the sink has the file, so a crash at worst pushes a file twice
+CREATE TABLE IF NOT EXISTS backup_queue (
+    uuid BINARY(16) NOT NULL,
+    enqueued_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    attempts INT NOT NULL DEFAULT 0,
+    next_attempt_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_error TEXT NULL,
+    done_at DATETIME NULL,
+
+    PRIMARY KEY (uuid),
+    INDEX (done_at, next_attempt_at),
+    FOREIGN KEY (uuid) REFERENCES files(uuid)
+);
+
+CREATE TABLE IF NOT EXISTS users (
+    username TEXT NOT NULL,
+    ssh_pubkey TEXT NOT NULL, -- used fo SFTP authentication
+    home_directory INT NOT NULL,
+
+    FOREIGN KEY (home_directory) REFERENCES directories(id)
+);
+
+-- SHA-256 of the token the user sends over HTTP, as `Authorization: Bearer <token>`, when
+-- http_server.scope_to_home_directories is on. set with e.g.
+-- UPDATE users SET http_token_sha256 = UNHEX(SHA2('<token>', 256)) WHERE username = 'xenia';
+-- users without one can't use HTTP in that mode
+ALTER TABLE users ADD COLUMN IF NOT EXISTS http_token_sha256 BINARY(32) NULL;
+
+-- argon2 hash of the user's SFTP password, in PHC string form. only checked with
+-- sftp_server.allow_password_auth, and set with PUT /admin/users/<name>/password
+ALTER TABLE users ADD COLUMN IF NOT EXISTS password_hash TEXT NULL;
+
+-- restrictions on uploads into a directory and everything below it, until a subdirectory has its own
+-- policy. the *_names and allowed_content_types columns hold one entry per line. names are matched
+-- against globs (* and ?), content types against prefixes. NULL columns don't restrict anything
+CREATE TABLE IF NOT EXISTS upload_policies (
+    directory_id INT NOT NULL,
+    denied_names TEXT NULL,
+    allowed_names TEXT NULL,
+    max_file_size BIGINT UNSIGNED NULL,
+    allowed_content_types TEXT NULL,
+
+    PRIMARY KEY (directory_id),
+    FOREIGN KEY (directory_id) REFERENCES directories(id)
+);
@@ -0,0 +1,9 @@
+-- users get an id, for the /admin/users routes
+ALTER TABLE users ADD COLUMN IF NOT EXISTS id INT NOT NULL AUTO_INCREMENT PRIMARY KEY FIRST;
+
+-- no two users share a name. fails on databases which already have such duplicates, of which all but
+-- one have to be deleted or renamed by hand first
+ALTER TABLE users ADD UNIQUE INDEX IF NOT EXISTS unique_username (username);
+
+-- users created through /admin/users may have no key, only a password
+ALTER TABLE users MODIFY ssh_pubkey TEXT NULL;
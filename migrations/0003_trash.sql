@@ -0,0 +1,31 @@
+-- deleted files are moved to the trash directory, which has no parent so no path leads to it, under
+-- their UUID as the name. where they were deleted from is kept, for restoring them. with trash
+-- disabled, files are deleted right away instead
+CREATE TABLE IF NOT EXISTS trash_directory (
+    directory_id INT NOT NULL,
+
+    uniqueness_constraint ENUM('1') NOT NULL DEFAULT '1' UNIQUE,
+
+    FOREIGN KEY (directory_id) REFERENCES directories(id)
+);
+
+INSERT INTO directories(name, parent_id)
+    SELECT '<trash>', NULL
+        WHERE NOT EXISTS (SELECT * FROM trash_directory);
+
+INSERT INTO trash_directory(directory_id)
+    SELECT id FROM directories
+        WHERE name = '<trash>' AND parent_id IS NULL AND NOT EXISTS (SELECT * FROM trash_directory)
+        ORDER BY id DESC
+        LIMIT 1;
+
+-- when the file was deleted, NULL for files which aren't in the trash. files deleted longer than
+-- trash.retention_s ago are deleted for good
+ALTER TABLE files ADD COLUMN IF NOT EXISTS deleted_at DATETIME NULL;
+ALTER TABLE files ADD INDEX IF NOT EXISTS deleted_at (deleted_at);
+
+-- the directory and name the file had before it was deleted. not a foreign key, as the directory
+-- may be removed while the file is in the trash, after which the file can't be restored
+ALTER TABLE files ADD COLUMN IF NOT EXISTS deleted_from_directory_id INT NULL;
+ALTER TABLE files ADD COLUMN IF NOT EXISTS deleted_name BLOB NULL;
+ALTER TABLE files ADD INDEX IF NOT EXISTS deleted_from_directory_id (deleted_from_directory_id);
@@ -0,0 +1,34 @@
+-- with versioning, an overwritten file's row is moved to the versions directory under its UUID as the
+-- name, like the trash directory, and recorded here as a version of the file which replaced it
+CREATE TABLE IF NOT EXISTS versions_directory (
+    directory_id INT NOT NULL,
+
+    uniqueness_constraint ENUM('1') NOT NULL DEFAULT '1' UNIQUE,
+
+    FOREIGN KEY (directory_id) REFERENCES directories(id)
+);
+
+INSERT INTO directories(name, parent_id)
+    SELECT '<versions>', NULL
+        WHERE NOT EXISTS (SELECT * FROM versions_directory);
+
+INSERT INTO versions_directory(directory_id)
+    SELECT id FROM directories
+        WHERE name = '<versions>' AND parent_id IS NULL AND NOT EXISTS (SELECT * FROM versions_directory)
+        ORDER BY id DESC
+        LIMIT 1;
+
+-- current_uuid is the file at the path now. when it's replaced in turn, its versions move over to the
+-- new file, so they follow it into the trash and are deleted along with it
+CREATE TABLE IF NOT EXISTS file_versions (
+    uuid BINARY(16) NOT NULL,
+    current_uuid BINARY(16) NOT NULL,
+    -- 1 for the first file at the path. the current file is one past the highest version
+    version INT UNSIGNED NOT NULL,
+    replaced_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    PRIMARY KEY (uuid),
+    INDEX (current_uuid, version),
+    FOREIGN KEY (uuid) REFERENCES files(uuid),
+    FOREIGN KEY (current_uuid) REFERENCES files(uuid)
+);
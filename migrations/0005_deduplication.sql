@@ -0,0 +1,9 @@
+-- the UUID the file's contents are stored under on its storage node, when an upload turned out to
+-- have the same contents as an earlier file and shares them. NULL for files stored under their own
+-- UUID. not a foreign key, as the earlier file may be deleted while others still share its contents.
+-- the contents are only deleted from the node once no row refers to them by either column
+ALTER TABLE files ADD COLUMN IF NOT EXISTS blob_uuid BINARY(16) NULL;
+ALTER TABLE files ADD INDEX IF NOT EXISTS blob_uuid (blob_uuid);
+
+-- for finding files with the same contents as an upload
+ALTER TABLE files ADD INDEX IF NOT EXISTS digest (digest);
@@ -0,0 +1,3 @@
+-- the most bytes a user's home directory may hold, counting everything below it. NULL for no limit.
+-- quotas are set directly in the database, and only checked for SFTP writes. see quotas.rs
+ALTER TABLE users ADD COLUMN IF NOT EXISTS quota_bytes BIGINT UNSIGNED NULL;
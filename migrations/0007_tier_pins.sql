@@ -0,0 +1,10 @@
+-- keeps the files in a directory and everything below it on one storage tier, until a subdirectory
+-- has a pin of its own. the tiering task moves files to their pinned tier however recently they were
+-- read, see tiering.rs. set with PUT /admin/tier-pins/<path>?tier=<tier>
+CREATE TABLE IF NOT EXISTS tier_pins (
+    directory_id INT NOT NULL,
+    tier INT NOT NULL,
+
+    PRIMARY KEY (directory_id),
+    FOREIGN KEY (directory_id) REFERENCES directories(id)
+);
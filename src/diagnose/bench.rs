@@ -0,0 +1,179 @@
+//! Load on a storage node: many WriteFiles and ReadFiles in flight at once over one connection.
+
+use std::time::{Duration, Instant};
+
+use futures::StreamExt;
+use rand::RngCore;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::message::Message;
+use super::client::Client;
+
+/// How one kind of request fared
+#[derive(Debug, Serialize)]
+pub struct PhaseResults {
+    pub requests: usize,
+    /// answered with Error or ErrorCode
+    pub error_replies: usize,
+    /// not answered at all, or answered with the wrong message type
+    pub failures: usize,
+    /// of the requests which succeeded
+    pub p50_us: u64,
+    pub p90_us: u64,
+    pub p99_us: u64,
+    pub max_us: u64,
+    pub requests_per_s: f64,
+    /// file contents written or read per second
+    pub bytes_per_s: f64,
+}
+
+impl std::fmt::Display for PhaseResults {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} requests, {} errors, {} failed. latency p50 {:.2} ms, p90 {:.2} ms, p99 {:.2} ms, max {:.2} ms. {:.1} requests/s, {:.2} MiB/s",
+            self.requests, self.error_replies, self.failures,
+            self.p50_us as f64 / 1000., self.p90_us as f64 / 1000., self.p99_us as f64 / 1000., self.max_us as f64 / 1000.,
+            self.requests_per_s, self.bytes_per_s / (1 << 20) as f64,
+        )
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct BenchResults {
+    pub write: PhaseResults,
+    pub read: PhaseResults,
+}
+
+/// How a single request ended
+enum Outcome {
+    Ok { latency: Duration, bytes: u64 },
+    ErrorReply(String),
+    Failed(String),
+}
+
+/// The latency below which `fraction` of the sorted latencies are
+fn percentile(sorted: &[Duration], fraction: f64) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let i = ((sorted.len() as f64 * fraction).ceil() as usize).clamp(1, sorted.len()) - 1;
+    sorted[i].as_micros() as u64
+}
+
+fn summarize(outcomes: &[Outcome], elapsed: Duration) -> PhaseResults {
+    let mut latencies = Vec::new();
+    let (mut bytes, mut error_replies, mut failures) = (0, 0, 0);
+    for outcome in outcomes {
+        match outcome {
+            Outcome::Ok { latency, bytes: n } => {
+                latencies.push(*latency);
+                bytes += n;
+            }
+            Outcome::ErrorReply(_) => error_replies += 1,
+            Outcome::Failed(_) => failures += 1,
+        }
+    }
+    latencies.sort();
+    let secs = elapsed.as_secs_f64().max(f64::EPSILON);
+    PhaseResults {
+        requests: outcomes.len(),
+        error_replies,
+        failures,
+        p50_us: percentile(&latencies, 0.5),
+        p90_us: percentile(&latencies, 0.9),
+        p99_us: percentile(&latencies, 0.99),
+        max_us: latencies.last().map_or(0, |latency| latency.as_micros() as u64),
+        requests_per_s: latencies.len() as f64 / secs,
+        bytes_per_s: bytes as f64 / secs,
+    }
+}
+
+/// Prints the first few problems, so a node failing every request doesn't flood the terminal
+fn print_problems(what: &str, outcomes: &[Outcome]) {
+    let problems = outcomes.iter().filter_map(|outcome| match outcome {
+        Outcome::ErrorReply(e) | Outcome::Failed(e) => Some(e),
+        Outcome::Ok { .. } => None,
+    });
+    for problem in problems.take(5) {
+        eprintln!("{what}: {problem}");
+    }
+}
+
+/// Sends `count` requests, at most `concurrency` at a time
+async fn run_phase<F, Fut>(count: usize, concurrency: usize, request: F) -> (Vec<Outcome>, Duration)
+where
+    F: Fn(usize) -> Fut,
+    Fut: std::future::Future<Output = Outcome>,
+{
+    let started = Instant::now();
+    let outcomes = futures::stream::iter(0..count)
+        .map(request)
+        .buffer_unordered(concurrency.max(1))
+        .collect::<Vec<_>>()
+        .await;
+    (outcomes, started.elapsed())
+}
+
+/// Writes `count` files of `size` random bytes, reads each back, then deletes them unless `keep`
+pub async fn bench(client: &Client, count: usize, concurrency: usize, size: usize, keep: bool) -> BenchResults {
+    let mut data = vec![0; size];
+    rand::thread_rng().fill_bytes(&mut data);
+    let uuids: Vec<Uuid> = (0..count).map(|_| Uuid::now_v7()).collect();
+
+    let (writes, elapsed) = run_phase(count, concurrency, |i| {
+        let request = Message::WriteFile(uuids[i], data.clone());
+        async move {
+            let sent = Instant::now();
+            match client.request(request).await {
+                Ok(Message::Ack) => Outcome::Ok { latency: sent.elapsed(), bytes: size as u64 },
+                Ok(Message::WriteAck { bytes_written, .. }) => Outcome::Ok { latency: sent.elapsed(), bytes: bytes_written },
+                Ok(reply @ (Message::Error(_) | Message::ErrorCode { .. })) => Outcome::ErrorReply(reply.to_string()),
+                Ok(reply) => Outcome::Failed(format!("expected Ack or WriteAck, got {reply}")),
+                Err(e) => Outcome::Failed(e.to_string()),
+            }
+        }
+    }).await;
+    print_problems("WriteFile", &writes);
+    let write = summarize(&writes, elapsed);
+
+    let (reads, elapsed) = run_phase(count, concurrency, |i| {
+        let request = Message::ReadFile(uuids[i]);
+        async move {
+            let sent = Instant::now();
+            match client.request(request).await {
+                Ok(Message::FileContents(data) | Message::CheckedFileContents { data, .. }) if data.len() == size => {
+                    Outcome::Ok { latency: sent.elapsed(), bytes: size as u64 }
+                }
+                Ok(Message::FileContents(data) | Message::CheckedFileContents { data, .. }) => {
+                    Outcome::Failed(format!("read {} bytes back, wrote {size}", data.len()))
+                }
+                Ok(reply @ (Message::Error(_) | Message::ErrorCode { .. })) => Outcome::ErrorReply(reply.to_string()),
+                Ok(reply) => Outcome::Failed(format!("expected FileContents, got {reply}")),
+                Err(e) => Outcome::Failed(e.to_string()),
+            }
+        }
+    }).await;
+    print_problems("ReadFile", &reads);
+    let read = summarize(&reads, elapsed);
+
+    if !keep {
+        let (deletes, _elapsed) = run_phase(count, concurrency, |i| {
+            let request = Message::DeleteFile(uuids[i]);
+            async move {
+                match client.request(request).await {
+                    Ok(Message::Ack) => Outcome::Ok { latency: Duration::ZERO, bytes: 0 },
+                    Ok(reply) => Outcome::Failed(reply.to_string()),
+                    Err(e) => Outcome::Failed(e.to_string()),
+                }
+            }
+        }).await;
+        let left_behind = deletes.iter().filter(|outcome| !matches!(outcome, Outcome::Ok { .. })).count();
+        if left_behind > 0 {
+            eprintln!("Could not delete {left_behind} of the files written");
+        }
+    }
+
+    BenchResults { write, read }
+}
@@ -0,0 +1,181 @@
+//! A connection to a storage node which can have many requests in flight.
+//!
+//! Each request gets its own MessageID, and replies are matched to requests by it, so they may
+//! arrive in any order. Messages the node sends on its own, like Activity, are queued separately.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use tokio::io::{AsyncRead, AsyncWrite, WriteHalf};
+use tokio::sync::{Mutex, mpsc, oneshot};
+
+use crate::message::{self, Message, MessageID, MessageLimits, ParseMessageError, parse_message, write_message};
+use crate::owned_task::OwnedTask;
+
+/// A TCP stream, or a TLS session over one
+pub trait Connection: AsyncRead + AsyncWrite + Send + Unpin {}
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Connection for T {}
+
+#[derive(Debug)]
+pub enum ClientError {
+    SendFailed(ParseMessageError),
+    /// the connection was lost before the reply arrived, with why
+    Disconnected(String),
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ClientError::SendFailed(e) => write!(f, "Could not send request: {e:?}"),
+            ClientError::Disconnected(why) => write!(f, "Lost the connection to the node: {why}"),
+        }
+    }
+}
+
+struct ClientInner {
+    stream: WriteHalf<Box<dyn Connection>>,
+    next_message_id: MessageID,
+    waiting_responses: HashMap<MessageID, oneshot::Sender<Message>>,
+    /// why the connection was lost. Sticky, once set every request fails
+    lost: Option<String>,
+}
+
+pub struct Client {
+    inner: Arc<Mutex<ClientInner>>,
+    unsolicited: Mutex<mpsc::UnboundedReceiver<Message>>,
+    /// reads replies. Dropped with the client, which closes the read half
+    _recv_task: OwnedTask,
+}
+
+impl Client {
+    pub fn new(stream: Box<dyn Connection>, limits: MessageLimits) -> Self {
+        let (mut read, write) = tokio::io::split(stream);
+        let inner = Arc::new(Mutex::new(ClientInner {
+            stream: write,
+            next_message_id: MessageID(0),
+            waiting_responses: HashMap::new(),
+            lost: None,
+        }));
+        let (unsolicited_tx, unsolicited_rx) = mpsc::unbounded_channel();
+
+        let recv_task = OwnedTask::spawn({
+            let inner = inner.clone();
+            async move {
+                let why = loop {
+                    match parse_message(&mut read, &limits).await {
+                        Ok((id, msg)) if id == message::UNSOLICITED_ID => {
+                            // nobody listening is fine, the message is dropped
+                            let _ = unsolicited_tx.send(msg);
+                        }
+                        Ok((id, msg)) => {
+                            let sender = inner.lock().await.waiting_responses.remove(&id);
+                            match sender {
+                                Some(sender) => {
+                                    let _ = sender.send(msg);
+                                }
+                                None => eprintln!("Got reply to a request which wasn't sent, {id:?}: {msg}"),
+                            }
+                        }
+                        // the message was read in full, so the stream is still in sync. The request
+                        // it answers fails
+                        Err(e @ (ParseMessageError::ParseJsonError { id, .. }
+                            | ParseMessageError::ParseUuidError { id, .. }
+                            | ParseMessageError::DecompressError { id, .. }
+                            | ParseMessageError::ParseBinaryError { id, .. })) => {
+                            eprintln!("Skipping a reply which couldn't be parsed: {e:?}");
+                            let sender = inner.lock().await.waiting_responses.remove(&id);
+                            if let Some(sender) = sender {
+                                let _ = sender.send(Message::Error(format!("could not parse reply: {e:?}")));
+                            }
+                        }
+                        Err(ParseMessageError::MessageTooLarge { id, unread }) => {
+                            if let Err(e) = message::skip_message(&mut read, unread).await {
+                                break format!("{e:?}");
+                            }
+                            let sender = inner.lock().await.waiting_responses.remove(&id);
+                            if let Some(sender) = sender {
+                                let _ = sender.send(Message::Error(format!("reply of {unread} bytes is over the limits, {limits:?}")));
+                            }
+                        }
+                        Err(ParseMessageError::IOError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                            break "the node closed the connection".to_string();
+                        }
+                        Err(e) => break format!("{e:?}"),
+                    }
+                };
+                let mut inner = inner.lock().await;
+                inner.lost = Some(why);
+                // fails the requests waiting
+                inner.waiting_responses.clear();
+            }
+        });
+
+        Client {
+            inner,
+            unsolicited: Mutex::new(unsolicited_rx),
+            _recv_task: recv_task,
+        }
+    }
+
+    /// Sends a request, returning where its reply will arrive. Only waits for the requests being
+    /// sent before it, not for their replies
+    pub async fn send(&self, request: Message) -> Result<oneshot::Receiver<Message>, ClientError> {
+        let mut inner = self.inner.lock().await;
+        if let Some(why) = &inner.lost {
+            return Err(ClientError::Disconnected(why.clone()));
+        }
+        let id = inner.next_message_id;
+        while {
+            inner.next_message_id.0 = inner.next_message_id.0.wrapping_add(1);
+            inner.next_message_id == message::UNSOLICITED_ID
+                || inner.waiting_responses.contains_key(&inner.next_message_id)
+        } {}
+
+        let (sender, listener) = oneshot::channel();
+        inner.waiting_responses.insert(id, sender);
+        if let Err(e) = write_message(&mut inner.stream, id, request).await {
+            inner.waiting_responses.remove(&id);
+            return Err(ClientError::SendFailed(e));
+        }
+        Ok(listener)
+    }
+
+    /// Waits for a reply from send
+    pub async fn reply(&self, listener: oneshot::Receiver<Message>) -> Result<Message, ClientError> {
+        match listener.await {
+            Ok(reply) => Ok(reply),
+            Err(_) => Err(self.lost().await),
+        }
+    }
+
+    /// Why the connection was lost, if it was
+    pub async fn lost_reason(&self) -> Option<String> {
+        self.inner.lock().await.lost.clone()
+    }
+
+    async fn lost(&self) -> ClientError {
+        let why = self.inner.lock().await.lost.clone();
+        ClientError::Disconnected(why.unwrap_or_else(|| "no reply".to_string()))
+    }
+
+    /// Sends a request and waits for its reply
+    pub async fn request(&self, request: Message) -> Result<Message, ClientError> {
+        let listener = self.send(request).await?;
+        self.reply(listener).await
+    }
+
+    /// Waits for the next message the node sends on its own. Fails once the connection is lost
+    pub async fn next_unsolicited(&self) -> Result<Message, ClientError> {
+        let message = self.unsolicited.lock().await.recv().await;
+        match message {
+            Some(message) => Ok(message),
+            None => Err(self.lost().await),
+        }
+    }
+
+    /// Forgets the unsolicited messages which arrived so far
+    pub async fn clear_unsolicited(&self) {
+        let mut unsolicited = self.unsolicited.lock().await;
+        while unsolicited.try_recv().is_ok() {}
+    }
+}
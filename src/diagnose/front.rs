@@ -0,0 +1,111 @@
+//! Requests to a front node's HTTP API, to tell problems with the front node apart from problems
+//! with its storage nodes.
+//!
+//! Each request opens its own connection, as when front nodes forward requests to each other.
+
+use http::{HeaderMap, Method, Request, StatusCode, Uri};
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper_util::rt::TokioIo;
+
+#[derive(Debug)]
+pub enum FrontError {
+    IO(std::io::Error),
+    Http(hyper::Error),
+}
+
+impl std::fmt::Display for FrontError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FrontError::IO(e) => write!(f, "Could not connect to the front node: {e}"),
+            FrontError::Http(e) => write!(f, "HTTP request to the front node failed: {e}"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Reply {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl Reply {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|value| value.to_str().ok())
+    }
+}
+
+pub struct Front {
+    /// host:port
+    authority: String,
+    /// of the base URL, without a trailing slash. Empty if the API is at the root
+    prefix: String,
+    /// sent as a bearer token, e.g. a user's token or the admin token
+    token: Option<String>,
+}
+
+/// Escapes everything in a path but unreserved characters and /, so names with spaces and the like
+/// survive the trip
+fn encode_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+impl Front {
+    /// `base_url` is e.g. `http://localhost:8080`. Only plain HTTP is supported
+    pub fn new(base_url: &str, token: Option<String>) -> Result<Self, String> {
+        let uri: Uri = base_url.parse().map_err(|e| format!("Invalid URL {base_url:?}: {e}"))?;
+        match uri.scheme_str() {
+            Some("http") => {}
+            Some(scheme) => return Err(format!("Only http:// URLs are supported, not {scheme}://")),
+            None => return Err(format!("URL {base_url:?} must start with http://")),
+        }
+        let authority = uri.authority().ok_or_else(|| format!("URL {base_url:?} has no host"))?;
+        let authority = match authority.port() {
+            Some(_) => authority.to_string(),
+            None => format!("{authority}:80"),
+        };
+        Ok(Front { authority, prefix: uri.path().trim_end_matches('/').to_string(), token })
+    }
+
+    /// `route` is e.g. `/list-directory`, and `path` a path of a file or directory on the front node.
+    /// The query, if any, is appended as is
+    pub async fn send(&self, method: Method, route: &str, path: &str, query: &str, body: Vec<u8>) -> Result<Reply, FrontError> {
+        let stream = tokio::net::TcpStream::connect(&self.authority).await.map_err(FrontError::IO)?;
+        let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+            .await
+            .map_err(FrontError::Http)?;
+        tokio::spawn(async move {
+            // the request fails with the reason too
+            let _ = connection.await;
+        });
+
+        let mut uri = format!("{}{route}/{}", self.prefix, encode_path(path.trim_start_matches('/')));
+        if !query.is_empty() {
+            uri = format!("{uri}?{query}");
+        }
+        let mut request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(http::header::HOST, &self.authority)
+            .header(http::header::CONTENT_LENGTH, body.len());
+        if let Some(token) = &self.token {
+            request = request.header(http::header::AUTHORIZATION, format!("Bearer {token}"));
+        }
+        let request = request
+            .body(Full::new(Bytes::from(body)))
+            .map_err(|e| FrontError::IO(std::io::Error::new(std::io::ErrorKind::InvalidInput, e)))?;
+
+        let response = sender.send_request(request).await.map_err(FrontError::Http)?;
+        let (parts, body) = response.into_parts();
+        let body = body.collect().await.map_err(FrontError::Http)?.to_bytes();
+        Ok(Reply { status: parts.status, headers: parts.headers, body })
+    }
+}
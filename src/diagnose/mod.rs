@@ -0,0 +1,4 @@
+pub mod client;
+pub mod bench;
+pub mod front;
+pub mod prompt;
@@ -0,0 +1,98 @@
+//! Reading commands in interactive mode.
+//!
+//! On a terminal, lines are read with a line editor, with history kept across sessions in
+//! HISTORY_FILE in the home directory. Piped commands are read line by line.
+
+use std::path::PathBuf;
+
+use rustyline::DefaultEditor;
+use rustyline::error::ReadlineError;
+use tokio::io::{AsyncBufReadExt, BufReader, Stdin};
+
+const HISTORY_FILE: &str = ".bnuystore_diagnose_history";
+
+pub enum Prompt {
+    Editor { editor: Box<DefaultEditor>, history: Option<PathBuf> },
+    Piped(BufReader<Stdin>),
+}
+
+impl Prompt {
+    /// A line editor if stdin is a terminal, and it can be set up
+    pub fn new() -> Self {
+        use std::io::IsTerminal;
+        if !std::io::stdin().is_terminal() {
+            return Prompt::Piped(BufReader::new(tokio::io::stdin()));
+        }
+        let mut editor = match DefaultEditor::new() {
+            Ok(editor) => editor,
+            Err(e) => {
+                eprintln!("Could not set up line editing, reading lines as is: {e}");
+                return Prompt::Piped(BufReader::new(tokio::io::stdin()));
+            }
+        };
+        let history = std::env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE));
+        if let Some(path) = &history {
+            match editor.load_history(path) {
+                Ok(()) => {}
+                // first run
+                Err(ReadlineError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => eprintln!("Could not load history from {}: {e}", path.display()),
+            }
+        }
+        Prompt::Editor { editor: Box::new(editor), history }
+    }
+
+    /// Asks a yes or no question, yes by default. Piped commands can't answer, so it's always yes
+    pub async fn confirm(&mut self, question: &str) -> bool {
+        let Prompt::Editor { editor, .. } = self else {
+            eprintln!("{question}y");
+            return true;
+        };
+        match tokio::task::block_in_place(|| editor.readline(question)) {
+            Ok(answer) => matches!(answer.trim(), "" | "y" | "Y" | "yes"),
+            Err(_) => false,
+        }
+    }
+
+    /// The next line, None at the end of input. Ctrl-C discards the line being typed
+    pub async fn next_line(&mut self) -> Option<String> {
+        match self {
+            Prompt::Editor { editor, history } => loop {
+                // the editor blocks until a line is entered
+                match tokio::task::block_in_place(|| editor.readline("> ")) {
+                    Ok(line) => {
+                        if !line.trim().is_empty() {
+                            let _ = editor.add_history_entry(line.as_str());
+                            if let Some(path) = history {
+                                // saved after each line, so it's kept however the session ends
+                                if let Err(e) = editor.save_history(path) {
+                                    eprintln!("Could not save history to {}: {e}", path.display());
+                                }
+                            }
+                        }
+                        return Some(line);
+                    }
+                    Err(ReadlineError::Interrupted) => continue,
+                    Err(ReadlineError::Eof) => return None,
+                    Err(e) => {
+                        eprintln!("error reading line from stdin: {e}. Exitting");
+                        return None;
+                    }
+                }
+            },
+            Prompt::Piped(stdin) => {
+                eprint!("> ");
+                let mut line = String::new();
+                match stdin.read_line(&mut line).await {
+                    // only the end of input reads nothing, empty lines have their newline
+                    Ok(0) => None,
+                    Ok(_) => Some(line),
+                    Err(e) => {
+                        eprintln!("error reading line from stdin: {e}. Exitting");
+                        None
+                    }
+                }
+            }
+        }
+    }
+}
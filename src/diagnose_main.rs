@@ -1,73 +1,249 @@
 use std::ffi::OsString;
+use std::future::Future;
+use std::io::IsTerminal;
 use std::path::PathBuf;
+use std::pin::Pin;
+use std::time::Duration;
 use std::net::SocketAddr;
 
 use clap::{Parser, Subcommand};
-use tokio::net::{TcpSocket, TcpStream};
-use tokio::io::{BufReader, AsyncBufReadExt, AsyncWriteExt};
+use serde::Serialize;
+use tokio::net::TcpSocket;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 mod message;
+mod hashing;
+mod owned_task;
+mod tls;
+#[cfg(test)]
+mod fault_injection;
+#[cfg(test)]
+mod storage_node;
+
+mod diagnose;
+use diagnose::client::{Client, ClientError, Connection};
 
 use uuid::Uuid;
 
 #[derive(Debug, Parser)]
 #[command(version, about)]
-struct CLI {
+struct Cli {
     /// interface to connect through
     #[arg(short='I', long="iface")]
     bind_iface: Option<String>,
 
-    /// address connect to, ip:port
-    bind_addr: String,
+    /// address of the storage node to connect to, ip:port. may be left out with --http, for
+    /// front commands only
+    #[arg(required_unless_present="http_base_url")]
+    bind_addr: Option<String>,
+
+    /// file containing the node's auth token, for nodes started with --auth-token-file
+    #[arg(long="auth-token-file")]
+    auth_token_file: Option<PathBuf>,
+
+    /// connect over TLS, checking the node's certificate against the CAs in this PEM file
+    #[arg(long="tls-ca-file")]
+    tls_ca_file: Option<PathBuf>,
+
+    /// what the node's certificate is for. defaults to its IP address
+    #[arg(long="tls-server-name", requires="tls_ca_file")]
+    tls_server_name: Option<String>,
+
+    /// base URL of a front node's HTTP API, e.g. http://localhost:8080, for front commands
+    #[arg(long="http")]
+    http_base_url: Option<String>,
+
+    /// file containing a token to send to the front node, e.g. a user's token or the admin token
+    #[arg(long="http-token-file", requires="http_base_url")]
+    http_token_file: Option<PathBuf>,
+
+    /// give up connecting to the storage node after this many seconds
+    #[arg(long="connect-timeout", default_value_t=10.0, value_parser=parse_timeout)]
+    connect_timeout_s: f64,
+
+    /// connect attempts to retry before giving up, waiting longer between each
+    #[arg(long="retries", default_value_t=0)]
+    retries: u32,
 
-    /// command to execute against the server
+    /// print the outcome of each command to stdout as a line of JSON
+    #[arg(long="json")]
+    json: bool,
+
+    /// command to execute against the server. exits with 0 if the node gave the expected reply, 1
+    /// if it replied with an error, and 2 on other failures, e.g. a broken connection
     #[command(subcommand)]
     command: Option<DiagnosticsCommand>,
 }
 
-#[tokio::main]
-async fn main() {
-    let cli = CLI::parse();
+/// A number of seconds which is positive and fits in a Duration
+fn parse_timeout(s: &str) -> Result<f64, String> {
+    let secs: f64 = s.parse().map_err(|e| format!("{e}"))?;
+    match Duration::try_from_secs_f64(secs) {
+        Ok(duration) if !duration.is_zero() => Ok(secs),
+        _ => Err(format!("{s} is not a positive number of seconds")),
+    }
+}
+
+/// What commands are sent to. Either can be missing, if its address wasn't given
+struct Targets {
+    node: Option<Client>,
+    front: Option<diagnose::front::Front>,
+}
 
-    let addr: SocketAddr = cli.bind_addr.parse().expect("Could not parse socket address");
+/// Before the first retry of a connection. Doubled after each one
+const INITIAL_RETRY_DELAY: Duration = Duration::from_millis(500);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(10);
 
+/// A stream to the storage node, over TLS if asked for. Both the TCP connection and the TLS
+/// handshake get the connect timeout
+async fn open_stream(cli: &Cli, addr: SocketAddr) -> Result<Box<dyn Connection>, String> {
+    let timeout = Duration::from_secs_f64(cli.connect_timeout_s);
     let socket = match addr {
         SocketAddr::V4(_) => TcpSocket::new_v4(),
         SocketAddr::V6(_) => TcpSocket::new_v6(),
-    }.expect("Could not create TCP socket");
-    if let Some(iface) = cli.bind_iface {
+    }.map_err(|e| format!("Could not create TCP socket: {e}"))?;
+    if let Some(iface) = &cli.bind_iface {
         let mut bytes = iface.as_bytes().to_vec();
         bytes.push(0); // zero terminator for linux moment
-        socket.bind_device(Some(bytes.as_slice())).expect("Could not bind to interface");
+        socket.bind_device(Some(bytes.as_slice())).map_err(|e| format!("Could not bind to interface {iface}: {e}"))?;
     }
-    let mut stream = socket.connect(addr).await.expect("Could not bind socket to address");
+    let stream = match tokio::time::timeout(timeout, socket.connect(addr)).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(e)) => return Err(format!("Could not connect to {addr}: {e}")),
+        Err(_) => return Err(format!("Could not connect to {addr}: timed out after {} seconds", cli.connect_timeout_s)),
+    };
+    let Some(ca_path) = &cli.tls_ca_file else {
+        return Ok(Box::new(stream));
+    };
+    let connector = tls::connector(ca_path).map_err(|e| format!("Could not load the TLS CA file: {e}"))?;
+    let name = cli.tls_server_name.clone().unwrap_or_else(|| addr.ip().to_string());
+    let server_name = tls::server_name(&name).map_err(|e| format!("Invalid TLS server name: {e}"))?;
+    match tokio::time::timeout(timeout, connector.connect(server_name, stream)).await {
+        Ok(Ok(stream)) => Ok(Box::new(stream)),
+        Ok(Err(e)) => Err(format!("TLS handshake with {addr} failed: {e}")),
+        Err(_) => Err(format!("TLS handshake with {addr} timed out after {} seconds", cli.connect_timeout_s)),
+    }
+}
+
+/// Connects to the storage node, retrying as asked for, and authenticates if given a token
+async fn connect_node(cli: &Cli, bind_addr: &str, report: &mut Report) -> Result<Client, Failure> {
+    let addr: SocketAddr = bind_addr.parse()
+        .map_err(|e| report.fail(Failure::Failed, format!("Could not parse socket address {bind_addr:?}: {e}")))?;
+
+    let mut delay = INITIAL_RETRY_DELAY;
+    let mut attempt = 0;
+    let stream = loop {
+        match open_stream(cli, addr).await {
+            Ok(stream) => break stream,
+            Err(e) if attempt < cli.retries => {
+                attempt += 1;
+                eprintln!("{e}. Retrying in {:.1} seconds ({attempt}/{})", delay.as_secs_f64(), cli.retries);
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_RETRY_DELAY);
+            }
+            Err(e) => return Err(report.fail(Failure::Failed, e)),
+        }
+    };
+    let client = Client::new(stream, message::MessageLimits::default());
+
+    if let Some(path) = &cli.auth_token_file {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| report.fail(Failure::Failed, format!("Could not read auth token file {}: {e}", path.display())))?;
+        let token = message::AuthToken(contents.trim_end_matches(['\r', '\n']).to_string());
+        match request(&client, message::Message::Authenticate(token), report).await? {
+            message::Message::Ack => {}
+            response => return Err(report.fail(Failure::Failed, format!("Node refused the auth token: {response}"))),
+        }
+    }
+    Ok(client)
+}
+
+/// In interactive mode, offers to reconnect to a storage node which was lost since the last
+/// command. Declining, or failing to reconnect, keeps the lost connection, so commands fail
+async fn reconnect_if_lost(cli: &Cli, targets: &mut Targets, prompt: &mut diagnose::prompt::Prompt) {
+    let (Some(bind_addr), Some(client)) = (&cli.bind_addr, &targets.node) else {
+        return;
+    };
+    let Some(why) = client.lost_reason().await else {
+        return;
+    };
+    eprintln!("Lost the connection to the node: {why}");
+    if !prompt.confirm("Reconnect? [Y/n] ").await {
+        return;
+    }
+    // with --json, stdout only has the reports of commands. failures are printed either way
+    if let Ok(client) = connect_node(cli, bind_addr, &mut Report::default()).await {
+        eprintln!("Reconnected. Features agreed on with hello need another hello");
+        targets.node = Some(client);
+    }
+}
+
+/// Checks the front node's URL, and reads the token to send it if given one
+fn connect_front(cli: &Cli, base_url: &str, report: &mut Report) -> Result<diagnose::front::Front, Failure> {
+    let token = match &cli.http_token_file {
+        Some(path) => {
+            let contents = std::fs::read_to_string(path)
+                .map_err(|e| report.fail(Failure::Failed, format!("Could not read HTTP token file {}: {e}", path.display())))?;
+            Some(contents.trim_end_matches(['\r', '\n']).to_string())
+        }
+        None => None,
+    };
+    diagnose::front::Front::new(base_url, token).map_err(|e| report.fail(Failure::Failed, e))
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+
+    let node = match &cli.bind_addr {
+        Some(bind_addr) => {
+            let mut report = Report::default();
+            match connect_node(&cli, bind_addr, &mut report).await {
+                Ok(client) => Some(client),
+                Err(failure) => {
+                    report.finish(Err(failure), cli.json);
+                    std::process::exit(failure.exit_code());
+                }
+            }
+        }
+        None => None,
+    };
+    let front = match &cli.http_base_url {
+        Some(base_url) => {
+            let mut report = Report::default();
+            match connect_front(&cli, base_url, &mut report) {
+                Ok(front) => Some(front),
+                Err(failure) => {
+                    report.finish(Err(failure), cli.json);
+                    std::process::exit(failure.exit_code());
+                }
+            }
+        }
+        None => None,
+    };
+    let mut targets = Targets { node, front };
 
     if let Some(command) = cli.command {
-        command.run(&mut stream).await;
+        let mut report = Report::default();
+        let result = command.run(&targets, &mut report, cli.json).await;
+        report.finish(result, cli.json);
+        if let Err(failure) = result {
+            std::process::exit(failure.exit_code());
+        }
     } else {
-        let mut stdin = BufReader::new(tokio::io::stdin());
+        let mut prompt = diagnose::prompt::Prompt::new();
         loop {
-            let mut line = String::new();
-            eprint!("> ");
-            if let Err(e) = stdin.read_line(&mut line).await {
-                if e.kind() == std::io::ErrorKind::UnexpectedEof {
-                    break;
-                } else {
-                    eprintln!("error reading line from stdin: {e}. Exitting");
-                }
-                return;
-            }
-            if line.len() == 0 {
+            let Some(line) = prompt.next_line().await else {
                 eprintln!("\nbunny bye 🐇");
                 return;
-            }
+            };
 
             let Some(mut words) = shlex::split(&line) else {
                 eprintln!("Invalid quoted line: {line:?}");
                 continue;
             };
 
-            if words.len() == 0 {
+            if words.is_empty() {
                 continue;
             }
 
@@ -83,7 +259,17 @@ async fn main() {
                 Ok(DiagnosticsCommand::Bye) => {
                     break;
                 }
-                Ok(cmd) => cmd.run(&mut stream).await,
+                Ok(DiagnosticsCommand::WriteFile { stdin: true, .. }) => {
+                    eprintln!("--stdin can't be used in interactive mode, where stdin has the commands. Use -f instead");
+                }
+                Ok(cmd) => {
+                    if !matches!(cmd, DiagnosticsCommand::Front { .. }) {
+                        reconnect_if_lost(&cli, &mut targets, &mut prompt).await;
+                    }
+                    let mut report = Report::default();
+                    let result = cmd.run(&targets, &mut report, cli.json).await;
+                    report.finish(result, cli.json);
+                }
                 Err(e) => e.print().expect("could not print command error"),
             }
         }
@@ -97,6 +283,16 @@ enum DiagnosticsCommand {
     Bye,
     /// sends a GetVersion message to the node
     GetVersion,
+    /// sends a Hello to the node, showing the protocol version and features agreed on. the
+    /// features stay enabled for the rest of the session
+    Hello {
+        /// features to ask for. if left empty, asks for every feature known
+        features: Vec<String>,
+    },
+    /// sends a StorageInfo to the node, showing its free space and number of files
+    StorageInfo,
+    /// sends a ListFiles to the node, printing the UUID and size of each file it stores
+    ListFiles,
     /// sends a WriteFile to the node
     WriteFile {
         /// UUID for file. if left empty, a UUID is generated
@@ -107,124 +303,771 @@ enum DiagnosticsCommand {
         #[arg(short='f', long="file")]
         file: Option<PathBuf>,
 
+        /// read the contents to write from stdin, e.g. when piping. not in interactive mode, where
+        /// stdin has the commands
+        #[arg(long="stdin")]
+        stdin: bool,
+
         /// contents to write, verbatim
         contents: Option<OsString>,
     },
-    /// sends a ReadFile to the node
+    /// sends a ReadFile to the node. with --offset or --len, sends a ReadFileRange instead
     ReadFile {
         /// UUID for file
         uuid: String,
 
+        /// where to start reading
+        #[arg(long="offset")]
+        offset: Option<u64>,
+
+        /// how many bytes to read at most
+        #[arg(long="len")]
+        len: Option<u32>,
+
         /// local path to write the output to
         #[arg(short='o', long="output")]
         output_path: Option<PathBuf>,
     },
+    /// sends a DeleteFile to the node, e.g. to clean up files written with write-file
+    DeleteFile {
+        /// UUID for file
+        uuid: String,
+    },
+    /// sends a HashFile to the node. with -f, checks the digest against a local file
+    HashFile {
+        /// UUID for file
+        uuid: String,
+
+        /// blake3 or sha256
+        #[arg(short='a', long="algorithm", default_value="blake3")]
+        algorithm: String,
+
+        /// local copy of the file to compare with
+        #[arg(short='f', long="file")]
+        file: Option<PathBuf>,
+    },
+    /// shows operations the node performs, as they happen
+    Activity {
+        /// keep showing operations until ctrl-c
+        #[arg(short='F', long="follow")]
+        follow: bool,
+
+        /// without --follow, stop after this many operations
+        #[arg(short='n', long="count", default_value_t=10)]
+        count: usize,
+
+        /// print each event as a line of JSON
+        #[arg(long="json")]
+        json: bool,
+    },
+    /// requests to the front node given with --http
+    Front {
+        #[command(subcommand)]
+        command: FrontCommand,
+    },
+    /// writes files to the node, reads them back and deletes them, with many requests in flight at
+    /// once, then shows the latencies and throughput
+    Bench {
+        /// number of files to write and read
+        #[arg(short='n', long="count", default_value_t=100)]
+        count: usize,
+
+        /// requests in flight at once
+        #[arg(short='c', long="concurrency", default_value_t=8)]
+        concurrency: usize,
+
+        /// bytes in each file
+        #[arg(short='s', long="size", default_value_t=64 * 1024)]
+        size: usize,
+
+        /// leave the files on the node afterwards
+        #[arg(long="keep")]
+        keep: bool,
+    },
+}
+
+#[derive(Debug, Subcommand, Clone)]
+enum FrontCommand {
+    /// uploads a local file to a path on the front node
+    Upload {
+        /// local path of file to upload
+        local: PathBuf,
+
+        /// where to upload it, e.g. /photos/bnuy.png
+        remote: String,
+
+        /// replace the file if it exists
+        #[arg(long="overwrite")]
+        overwrite: bool,
+    },
+    /// downloads a file from the front node
+    Download {
+        /// e.g. /photos/bnuy.png
+        remote: String,
+
+        /// local path to write the file to
+        #[arg(short='o', long="output")]
+        output_path: Option<PathBuf>,
+    },
+    /// lists a directory on the front node, printing the JSON listing
+    Ls {
+        #[arg(default_value="/")]
+        path: String,
+    },
+    /// creates a directory on the front node
+    Mkdir {
+        path: String,
+
+        /// succeed if the directory exists already
+        #[arg(long="exist-ok")]
+        exist_ok: bool,
+    },
+}
+
+/// HH:MM:SS.mmm, in UTC
+fn format_timestamp(at_ms: u64) -> String {
+    let (seconds, millis) = (at_ms / 1000, at_ms % 1000);
+    format!("{:02}:{:02}:{:02}.{millis:03}", seconds / 3600 % 24, seconds / 60 % 60, seconds % 60)
+}
+
+fn print_activity(message: &message::Message, json: bool) {
+    match message {
+        message::Message::Activity(event) if json => {
+            println!("{}", serde_json::to_string(event).expect("events always serialize"));
+        }
+        message::Message::Activity(event) => {
+            let uuid = event.uuid.map_or("-".to_string(), |uuid| uuid.to_string());
+            let result = event.error.as_deref().unwrap_or("ok");
+            println!(
+                "{} {:<12} {uuid:<36} {:>10} B {:>8} us  {result}",
+                format_timestamp(event.at_ms), event.op, event.bytes, event.duration_us,
+            );
+        }
+        message::Message::ActivityDropped { count } if json => {
+            println!("{}", serde_json::json!({ "dropped": count }));
+        }
+        message::Message::ActivityDropped { count } => {
+            println!("... {count} events dropped, the node's buffer overflowed ...");
+        }
+        _ => eprintln!("Got unexpected unsolicited message: {message:?}"),
+    }
+}
+
+/// Why a command failed. A command given on the command line exits with its exit_code
+#[derive(Debug, Clone, Copy)]
+enum Failure {
+    /// the node answered with Error or ErrorCode
+    ErrorReply,
+    /// anything else, e.g. a broken connection, a reply of the wrong type or a bad argument
+    Failed,
+}
+
+impl Failure {
+    fn exit_code(self) -> i32 {
+        match self {
+            Failure::ErrorReply => 1,
+            Failure::Failed => 2,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ListedFile {
+    uuid: Uuid,
+    size: u64,
+}
+
+/// What a command did, printed as a line of JSON with --json
+#[derive(Debug, Default, Serialize)]
+struct Report {
+    /// the type of the node's last reply, e.g. "FileContents"
+    message: Option<&'static str>,
+    /// from front commands, the HTTP status of the reply
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<u16>,
+    /// from front commands, the storage node the file is on, from X-Node-Name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    node_name: Option<String>,
+    uuid: Option<Uuid>,
+    bytes_sent: Option<u64>,
+    bytes_received: Option<u64>,
+    /// from list-files
+    #[serde(skip_serializing_if = "Option::is_none")]
+    files: Option<Vec<ListedFile>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bench: Option<diagnose::bench::BenchResults>,
+    /// from front ls
+    #[serde(skip_serializing_if = "Option::is_none")]
+    listing: Option<serde_json::Value>,
+    error: Option<String>,
+    exit_code: i32,
+}
+
+impl Report {
+    /// Prints the error, and keeps it for the JSON output
+    fn fail(&mut self, failure: Failure, error: String) -> Failure {
+        eprintln!("{error}");
+        self.error = Some(error);
+        failure
+    }
+
+    fn finish(mut self, result: Result<(), Failure>, json: bool) {
+        self.exit_code = result.err().map_or(0, Failure::exit_code);
+        if json {
+            println!("{}", serde_json::to_string(&self).expect("reports always serialize"));
+        }
+    }
+}
+
+/// Sends a request and reads the reply to it. An Error or ErrorCode reply is a failure
+async fn request(client: &Client, request: message::Message, report: &mut Report) -> Result<message::Message, Failure> {
+    let response = client.request(request).await.map_err(|e| report.fail(Failure::Failed, e.to_string()))?;
+    reply(response, report)
+}
+
+/// Notes the reply's type in the report. An Error or ErrorCode reply is a failure
+fn reply(response: message::Message, report: &mut Report) -> Result<message::Message, Failure> {
+    report.message = Some(response.name());
+    match response {
+        message::Message::Error(_) | message::Message::ErrorCode { .. } => {
+            Err(report.fail(Failure::ErrorReply, format!("Node answered with an error: {response}")))
+        }
+        response => Ok(response),
+    }
+}
+
+/// Writes file contents to `output_path`. Without one, to stdout if it's piped, and otherwise
+/// through $PAGER
+async fn show_contents(data: &[u8], output_path: Option<PathBuf>, report: &mut Report, json: bool) -> Result<(), Failure> {
+    if let Some(path) = output_path {
+        match tokio::fs::write(&path, data).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(report.fail(Failure::Failed, format!("Could not find output file {}!", path.display())));
+            }
+            Err(e) => {
+                return Err(report.fail(Failure::Failed, format!("Could not write to output file {}: {e:?}", path.display())));
+            }
+        }
+    } else if json {
+        // stdout has the report
+        eprintln!("Not showing the contents with --json; use -o to keep them");
+    } else if !std::io::stdout().is_terminal() {
+        let mut stdout = tokio::io::stdout();
+        if let Err(e) = stdout.write_all(data).await.and(stdout.flush().await) {
+            return Err(report.fail(Failure::Failed, format!("Could not write to stdout: {e:?}")));
+        }
+    } else {
+        let pager = std::env::var("PAGER").unwrap_or("less".to_string());
+        let mut child = tokio::process::Command::new(&pager)
+            .stdin(std::process::Stdio::piped())
+            .spawn()
+            .map_err(|e| report.fail(Failure::Failed, format!("Could not spawn $PAGER ({pager}): {e:?}")))?;
+
+        let mut child_stdin = child.stdin.take().unwrap();
+        // the pager quitting early closes its stdin, which is fine
+        let _ = child_stdin.write_all(data).await;
+        std::mem::drop(child_stdin);
+        child.wait().await.expect("Could not wait for $PAGER to quit");
+    }
+    Ok(())
+}
+
+fn parse_uuid(uuid: &str, report: &mut Report) -> Result<Uuid, Failure> {
+    match Uuid::parse_str(uuid) {
+        Ok(u) => {
+            report.uuid = Some(u);
+            Ok(u)
+        }
+        Err(e) => Err(report.fail(Failure::Failed, format!("Could not parse UUID: {e:?}"))),
+    }
 }
 
 impl DiagnosticsCommand {
-    async fn run(self, connection: &mut TcpStream) {
+    /// With json, nothing but the report is printed to stdout
+    async fn run(self, targets: &Targets, report: &mut Report, json: bool) -> Result<(), Failure> {
+        if let DiagnosticsCommand::Front { command } = self {
+            let Some(front) = &targets.front else {
+                return Err(report.fail(Failure::Failed, "Front commands need the front node's URL, given with --http".to_string()));
+            };
+            return command.run(front, report, json).await;
+        }
+        let Some(client) = &targets.node else {
+            return Err(report.fail(Failure::Failed, "This command needs the storage node's address".to_string()));
+        };
         match self {
             DiagnosticsCommand::Bye => {
                 eprintln!("whar the hell");
             }
             DiagnosticsCommand::GetVersion => {
-                let request = message::Message::GetVersion;
-                let id = message::MessageID(0);
-                message::write_message(connection, id, request).await.expect("Could not send request");
-                let (_rid, response) = message::parse_message(connection).await.expect("Could not acquire reply");
-                eprintln!("Got response: {response:?}");
-            }
-            DiagnosticsCommand::WriteFile { uuid, file, contents } => {
-                let uuid = match uuid.map(|x| Uuid::parse_str(&x)) {
-                    Some(Ok(u)) => u,
-                    Some(Err(e)) => {
-                        eprintln!("Could not parse UUID: {e:?}");
-                        return;
+                let response = request(client, message::Message::GetVersion, report).await?;
+                let message::Message::MyVersionIs(version) = response else {
+                    return Err(report.fail(Failure::Failed, format!("got wrong response type from node; expected MyVersionIs, got {response:?}")));
+                };
+                eprintln!("Node version: {version}");
+            }
+            DiagnosticsCommand::Hello { mut features } => {
+                if features.is_empty() {
+                    features = message::FEATURES.iter().map(|feature| feature.to_string()).collect();
+                }
+                let ours = message::ProtocolVersions::OURS;
+                let hello = message::Message::Hello { protocol: ours, features, compression: None };
+                let response = request(client, hello, report).await?;
+
+                let message::Message::HelloBack { protocol, features } = response else {
+                    return Err(report.fail(Failure::Failed, format!("got wrong response type from node; expected HelloBack, got {response:?}")));
+                };
+                eprintln!("node speaks protocol versions {protocol}, this tool {ours}");
+                match ours.negotiate(&protocol) {
+                    Some(version) => eprintln!("agreed on protocol version {version}"),
+                    None => eprintln!("no protocol version in common; the node enabled no features"),
+                }
+                eprintln!("features agreed on: {features:?}");
+            }
+            DiagnosticsCommand::StorageInfo => {
+                let response = request(client, message::Message::StorageInfo, report).await?;
+
+                let message::Message::StorageInfoIs(info) = response else {
+                    return Err(report.fail(Failure::Failed, format!("got wrong response type from node; expected StorageInfoIs, got {response:?}")));
+                };
+                let file_count = info.file_count.map_or("unknown".to_string(), |count| count.to_string());
+                eprintln!("{} of {} bytes available, files stored: {file_count}", info.bytes_available, info.bytes_total);
+            }
+            DiagnosticsCommand::ListFiles => {
+                let response = request(client, message::Message::ListFiles, report).await?;
+
+                let message::Message::FileList(mut files) = response else {
+                    return Err(report.fail(Failure::Failed, format!("got wrong response type from node; expected FileList, got {response:?}")));
+                };
+                files.sort();
+                if json {
+                    report.files = Some(files.iter().map(|&(uuid, size)| ListedFile { uuid, size }).collect());
+                } else {
+                    for (uuid, size) in &files {
+                        println!("{uuid} {size}");
                     }
+                }
+                eprintln!("{} files, {} bytes", files.len(), files.iter().map(|(_, size)| size).sum::<u64>());
+            }
+            DiagnosticsCommand::WriteFile { uuid, file, stdin, contents } => {
+                let uuid = match uuid {
+                    Some(uuid) => parse_uuid(&uuid, report)?,
                     None => {
                         let u = Uuid::now_v7();
                         eprintln!("Writing to UUID {}", u.hyphenated().encode_lower(&mut Uuid::encode_buffer()));
+                        report.uuid = Some(u);
                         u
                     }
                 };
 
-                let data: Vec<u8> = match (file, contents) {
-                    (Some(path), None) => {
-                        // TODO: This fails if the file contains invalid UTF-8
-                        match tokio::fs::read_to_string(&path).await {
-                            Ok(data) => data.bytes().collect(),
+                let data: Vec<u8> = match (file, stdin, contents) {
+                    (Some(path), false, None) => {
+                        match tokio::fs::read(&path).await {
+                            Ok(data) => data,
                             Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
-                                eprintln!("Could not find file {}!", path.display());
-                                return;
+                                return Err(report.fail(Failure::Failed, format!("Could not find file {}!", path.display())));
                             }
                             Err(e) => {
-                                eprintln!("Could not read file {}: {e:?}", path.display());
-                                return;
+                                return Err(report.fail(Failure::Failed, format!("Could not read file {}: {e:?}", path.display())));
                             }
                         }
                     }
-                    (None, Some(data)) => data.as_encoded_bytes().to_vec(),
-                    (None, None) => {
-                        eprintln!("Must specify either -f or supply data to write!");
-                        return;
+                    (None, true, None) => {
+                        let mut data = Vec::new();
+                        if let Err(e) = tokio::io::stdin().read_to_end(&mut data).await {
+                            return Err(report.fail(Failure::Failed, format!("Could not read stdin: {e:?}")));
+                        }
+                        data
+                    }
+                    (None, false, Some(data)) => data.as_encoded_bytes().to_vec(),
+                    (None, false, None) => {
+                        return Err(report.fail(Failure::Failed, "Must specify either -f, --stdin or supply data to write!".to_string()));
                     }
-                    (Some(_), Some(_)) => {
-                        eprintln!("Must not specify both -f and supply data to write!");
-                        return;
+                    _ => {
+                        return Err(report.fail(Failure::Failed, "Must specify only one of -f, --stdin and data to write!".to_string()));
                     }
                 };
                 eprintln!("Writing {} bytes", data.len());
+                report.bytes_sent = Some(data.len() as u64);
 
-                let request = message::Message::WriteFile(uuid, data);
-                let id = message::MessageID(0);
-                message::write_message(connection, id, request).await.expect("Could not send request");
-                let (_rid, response) = message::parse_message(connection).await.expect("Could not acquire reply");
-                eprintln!("Got response: {response:?}");
+                let response = request(client, message::Message::WriteFile(uuid, data), report).await?;
+                match response {
+                    message::Message::Ack => eprintln!("Written"),
+                    // after a hello with the write-ack feature
+                    message::Message::WriteAck { bytes_written, fsynced } => {
+                        eprintln!("Wrote {bytes_written} bytes, fsynced: {fsynced}");
+                    }
+                    response => {
+                        return Err(report.fail(Failure::Failed, format!("got wrong response type from node; expected Ack or WriteAck, got {response:?}")));
+                    }
+                }
             }
-            DiagnosticsCommand::ReadFile { uuid, output_path } => {
-                let uuid = match Uuid::parse_str(&uuid) {
-                    Ok(u) => u,
-                    Err(e) => {
-                        eprintln!("Could not parse UUID: {e:?}");
-                        return;
+            DiagnosticsCommand::ReadFile { uuid, offset, len, output_path } => {
+                let uuid = parse_uuid(&uuid, report)?;
+
+                let read = if offset.is_some() || len.is_some() {
+                    message::Message::ReadFileRange(uuid, offset.unwrap_or(0), len.unwrap_or(u32::MAX))
+                } else {
+                    message::Message::ReadFile(uuid)
+                };
+                let response = request(client, read, report).await?;
+
+                let data = match response {
+                    message::Message::FileContents(data) => data,
+                    // after a hello with the checksums feature
+                    message::Message::CheckedFileContents { digest, data } => {
+                        if let Some(digest) = digest {
+                            eprintln!("Written with digest {digest}");
+                        }
+                        data
+                    }
+                    message::Message::FileRange { file_size, data } => {
+                        eprintln!("Got {} bytes of a {file_size} byte file", data.len());
+                        data
+                    }
+                    response => {
+                        return Err(report.fail(Failure::Failed, format!("got wrong response type from node; expected FileContents, CheckedFileContents or FileRange, got {response:?}")));
                     }
                 };
+                report.bytes_received = Some(data.len() as u64);
+                show_contents(&data, output_path, report, json).await?;
+            }
+            DiagnosticsCommand::DeleteFile { uuid } => {
+                let uuid = parse_uuid(&uuid, report)?;
 
-                let request = message::Message::ReadFile(uuid);
-                let id = message::MessageID(0);
-                message::write_message(connection, id, request).await.expect("Could not send request");
-                let (_rid, response) = message::parse_message(connection).await.expect("Could not acquire reply");
+                let response = request(client, message::Message::DeleteFile(uuid), report).await?;
+                let message::Message::Ack = response else {
+                    return Err(report.fail(Failure::Failed, format!("got wrong response type from node; expected Ack, got {response:?}")));
+                };
+                eprintln!("Deleted {uuid}");
+            }
+            DiagnosticsCommand::HashFile { uuid, algorithm, file } => {
+                let uuid = parse_uuid(&uuid, report)?;
+                let Some(algorithm) = hashing::HashAlgorithm::from_name(&algorithm) else {
+                    return Err(report.fail(Failure::Failed, format!("Unknown hash algorithm {algorithm:?}. Must be blake3 or sha256")));
+                };
 
-                let message::Message::FileContents(data) = response else {
-                    eprintln!("got wrong response type from node; expected FileContents, got {response:?}");
-                    return;
+                let response = request(client, message::Message::HashFile(uuid, algorithm), report).await?;
+                let message::Message::FileHash { size, digest } = response else {
+                    return Err(report.fail(Failure::Failed, format!("got wrong response type from node; expected FileHash, got {response:?}")));
                 };
+                eprintln!("{size} bytes, {digest}");
 
-                if let Some(path) = output_path {
-                    match tokio::fs::write(&path, data).await {
-                        Ok(()) => {}
-                        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
-                            eprintln!("Could not find output file {}!", path.display());
-                            return;
-                        }
+                if let Some(path) = file {
+                    let data = match tokio::fs::read(&path).await {
+                        Ok(data) => data,
                         Err(e) => {
-                            eprintln!("Could not write to output file {}: {e:?}", path.display());
-                            return;
+                            return Err(report.fail(Failure::Failed, format!("Could not read file {}: {e:?}", path.display())));
+                        }
+                    };
+                    let local_digest = hashing::ContentDigest::of(algorithm, &data);
+                    if local_digest == digest {
+                        eprintln!("Matches {}", path.display());
+                    } else {
+                        eprintln!("Does NOT match {}, which is {} bytes, {local_digest}", path.display(), data.len());
+                    }
+                }
+            }
+            DiagnosticsCommand::Activity { follow, count, json: json_events } => {
+                let failed = |e: ClientError, report: &mut Report| report.fail(Failure::Failed, e.to_string());
+                let listener = client.send(message::Message::SubscribeActivity).await.map_err(|e| failed(e, report))?;
+                // the reply to the latest request
+                let mut pending: Pin<Box<dyn Future<Output = _>>> = Box::pin(client.reply(listener));
+                let mut shown = 0;
+                let mut unsubscribed = false;
+                let ctrl_c = tokio::signal::ctrl_c();
+                tokio::pin!(ctrl_c);
+                loop {
+                    tokio::select! {
+                        event = client.next_unsolicited() => {
+                            let event = event.map_err(|e| failed(e, report))?;
+                            // events keep arriving until the node has seen the unsubscribe
+                            if !unsubscribed {
+                                print_activity(&event, json || json_events);
+                                shown += 1;
+                                if !follow && shown >= count {
+                                    let listener = client.send(message::Message::UnsubscribeActivity).await.map_err(|e| failed(e, report))?;
+                                    pending = Box::pin(client.reply(listener));
+                                    unsubscribed = true;
+                                }
+                            }
+                        }
+                        _ = &mut ctrl_c, if follow && !unsubscribed => {
+                            eprintln!();
+                            let listener = client.send(message::Message::UnsubscribeActivity).await.map_err(|e| failed(e, report))?;
+                            pending = Box::pin(client.reply(listener));
+                            unsubscribed = true;
+                        }
+                        response = &mut pending => {
+                            let response = response.map_err(|e| failed(e, report))?;
+                            match reply(response, report)? {
+                                message::Message::Ack if unsubscribed => break,
+                                message::Message::Ack => {
+                                    eprintln!("Subscribed, waiting for activity");
+                                    pending = Box::pin(futures::future::pending());
+                                }
+                                response => {
+                                    return Err(report.fail(Failure::Failed, format!("got wrong response type from node; expected Ack, got {response:?}")));
+                                }
+                            }
                         }
                     }
-                } else {
-                    let pager = std::env::var("PAGER").unwrap_or("less".to_string());
-                    let mut child = tokio::process::Command::new(pager)
-                        .stdin(std::process::Stdio::piped())
-                        .spawn()
-                        .expect("Could not spawn $PAGER");
-
-                    let mut child_stdin = child.stdin.take().unwrap();
-                    child_stdin.write_all(&data).await.expect("Could not write stdin of $PAGER");
-                    std::mem::drop(child_stdin);
-                    child.wait().await.expect("Could not wait for $PAGER to quit");
                 }
+                // events which arrived before the node saw the unsubscribe
+                client.clear_unsolicited().await;
             }
+            DiagnosticsCommand::Front { .. } => unreachable!("handled above"),
+            DiagnosticsCommand::Bench { count, concurrency, size, keep } => {
+                eprintln!("Writing and reading {count} files of {size} bytes, {concurrency} at a time");
+                let results = diagnose::bench::bench(client, count, concurrency, size, keep).await;
+                eprintln!("WriteFile: {}", results.write);
+                eprintln!("ReadFile:  {}", results.read);
+
+                let error_replies = results.write.error_replies + results.read.error_replies;
+                let failures = results.write.failures + results.read.failures;
+                report.bench = Some(results);
+                if failures > 0 {
+                    return Err(report.fail(Failure::Failed, format!("{failures} requests failed")));
+                }
+                if error_replies > 0 {
+                    return Err(report.fail(Failure::ErrorReply, format!("{error_replies} requests were answered with an error")));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FrontCommand {
+    async fn run(self, front: &diagnose::front::Front, report: &mut Report, json: bool) -> Result<(), Failure> {
+        let listing = matches!(self, FrontCommand::Ls { .. });
+        let (method, route, path, query, body, output_path) = match self {
+            FrontCommand::Upload { local, remote, overwrite } => {
+                let data = match tokio::fs::read(&local).await {
+                    Ok(data) => data,
+                    Err(e) => {
+                        return Err(report.fail(Failure::Failed, format!("Could not read file {}: {e:?}", local.display())));
+                    }
+                };
+                eprintln!("Uploading {} bytes", data.len());
+                report.bytes_sent = Some(data.len() as u64);
+                let query = if overwrite { "overwrite=true" } else { "" };
+                (http::Method::POST, "/upload/file-by-path", remote, query, data, None)
+            }
+            FrontCommand::Download { remote, output_path } => {
+                (http::Method::GET, "/get/file-by-path", remote, "", Vec::new(), Some(output_path))
+            }
+            FrontCommand::Ls { path } => (http::Method::GET, "/list-directory", path, "", Vec::new(), None),
+            FrontCommand::Mkdir { path, exist_ok } => {
+                let query = if exist_ok { "exist_ok=true" } else { "" };
+                (http::Method::POST, "/create/directory-by-path", path, query, Vec::new(), None)
+            }
+        };
+
+        let reply = front.send(method, route, &path, query, body).await
+            .map_err(|e| report.fail(Failure::Failed, e.to_string()))?;
+        eprintln!("HTTP {}", reply.status);
+        report.status = Some(reply.status.as_u16());
+        for header in ["X-File-UUID", "X-Node-Name", "X-Directory-ID"] {
+            if let Some(value) = reply.header(header) {
+                eprintln!("{header}: {value}");
+            }
+        }
+        report.uuid = reply.header("X-File-UUID").and_then(|uuid| Uuid::parse_str(uuid).ok());
+        report.node_name = reply.header("X-Node-Name").map(|name| name.to_string());
+
+        if !reply.status.is_success() {
+            let detail = String::from_utf8_lossy(&reply.body);
+            return Err(report.fail(Failure::ErrorReply, format!("Front node answered {}: {}", reply.status, detail.trim_end())));
+        }
+        match output_path {
+            // a download
+            Some(output_path) => {
+                report.bytes_received = Some(reply.body.len() as u64);
+                show_contents(&reply.body, output_path, report, json).await?;
+            }
+            None if json && listing => report.listing = serde_json::from_slice(&reply.body).ok(),
+            None if json => {}
+            None => {
+                let body = String::from_utf8_lossy(&reply.body);
+                println!("{}", body.trim_end());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs a command as typed in interactive mode, against `client`
+    async fn run(client: Client, line: &str) -> (Result<(), Failure>, Client) {
+        #[derive(Debug, Parser)]
+        struct DiagnosticsCLI {
+            #[command(subcommand)]
+            cmd: DiagnosticsCommand,
+        }
+        let mut words = shlex::split(line).unwrap();
+        words.insert(0, "cli".to_string());
+        let command = DiagnosticsCLI::try_parse_from(words).unwrap().cmd;
+
+        let targets = Targets { node: Some(client), front: None };
+        let result = command.run(&targets, &mut Report::default(), true).await;
+        (result, targets.node.unwrap())
+    }
+
+    #[test]
+    fn connect_timeouts_are_positive_and_finite() {
+        let parse = |timeout: &str| Cli::try_parse_from(["diagnose".to_string(), "127.0.0.1:2700".to_string(), format!("--connect-timeout={timeout}")]);
+        assert_eq!(parse("0.5").unwrap().connect_timeout_s, 0.5);
+        for timeout in ["-1", "0", "NaN", "inf", "1e300", "soon"] {
+            let e = parse(timeout).err().unwrap_or_else(|| panic!("{timeout} was accepted"));
+            assert_eq!(e.kind(), clap::error::ErrorKind::ValueValidation, "{timeout}");
+            assert_eq!(e.exit_code(), 2);
         }
     }
+
+    #[test]
+    fn unreadable_http_token_files_are_failures() {
+        let cli = Cli::parse_from(["diagnose", "--http", "http://localhost:8080", "--http-token-file", "/nonexistent/bnuy-token"]);
+        let mut report = Report::default();
+        let failure = connect_front(&cli, "http://localhost:8080", &mut report).err().unwrap();
+        assert_eq!(failure.exit_code(), 2);
+        assert!(report.error.unwrap().contains("/nonexistent/bnuy-token"));
+    }
+
+    /// Answers one HTTP request with `response`, and hands over the request as received
+    async fn stub_front(response: &'static str) -> (String, tokio::task::JoinHandle<String>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let request = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            // the head, and then as much body as Content-Length says
+            loop {
+                let n = socket.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request).to_string();
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let length = head.lines()
+                        .find_map(|line| line.strip_prefix("content-length: "))
+                        .map_or(0, |length| length.parse().unwrap());
+                    if body.len() >= length {
+                        break;
+                    }
+                }
+                assert!(n > 0, "connection closed before the whole request");
+            }
+            socket.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        (format!("http://{addr}/api/"), request)
+    }
+
+    /// Runs a front command as typed in interactive mode
+    async fn run_front(front: diagnose::front::Front, line: &str) -> (Result<(), Failure>, Report) {
+        #[derive(Debug, Parser)]
+        struct DiagnosticsCLI {
+            #[command(subcommand)]
+            cmd: DiagnosticsCommand,
+        }
+        let mut words = shlex::split(line).unwrap();
+        words.insert(0, "cli".to_string());
+        let command = DiagnosticsCLI::try_parse_from(words).unwrap().cmd;
+
+        let targets = Targets { node: None, front: Some(front) };
+        let mut report = Report::default();
+        let result = command.run(&targets, &mut report, true).await;
+        (result, report)
+    }
+
+    #[tokio::test]
+    async fn front_commands_send_requests() {
+        let dir = std::env::temp_dir().join(format!("bnuystore-test-{}", Uuid::now_v7()));
+        std::fs::create_dir(&dir).unwrap();
+        let local = dir.join("bnuy.txt");
+        std::fs::write(&local, b"bnuy").unwrap();
+
+        let uuid = Uuid::now_v7();
+        let response = format!("HTTP/1.1 200 OK\r\nX-File-UUID: {uuid}\r\nX-Node-Name: burrow\r\ncontent-length: 0\r\n\r\n");
+        let (url, request) = stub_front(response.leak()).await;
+        let front = diagnose::front::Front::new(&url, Some("bnuy-token".to_string())).unwrap();
+        let (result, report) = run_front(front, &format!("front upload {} '/docs/a b.txt' --overwrite", local.display())).await;
+        assert!(result.is_ok());
+        assert_eq!((report.status, report.uuid, report.node_name.as_deref()), (Some(200), Some(uuid), Some("burrow")));
+        assert_eq!(report.bytes_sent, Some(4));
+
+        let request = request.await.unwrap();
+        assert!(request.starts_with("POST /api/upload/file-by-path/docs/a%20b.txt?overwrite=true HTTP/1.1\r\n"), "{request}");
+        assert!(request.contains("authorization: Bearer bnuy-token\r\n"), "{request}");
+        assert!(request.ends_with("\r\n\r\nbnuy"), "{request}");
+
+        let listing = r#"[{"name":"carrots","type":"directory"}]"#;
+        let response = format!("HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n{listing}", listing.len());
+        let (url, request) = stub_front(response.leak()).await;
+        let (result, report) = run_front(diagnose::front::Front::new(&url, None).unwrap(), "front ls /burrow").await;
+        assert!(result.is_ok());
+        assert_eq!(report.listing, Some(serde_json::from_str(listing).unwrap()));
+        let request = request.await.unwrap();
+        assert!(request.starts_with("GET /api/list-directory/burrow HTTP/1.1\r\n"), "{request}");
+        assert!(!request.contains("authorization"), "{request}");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn front_failures_have_exit_codes() {
+        let response = "HTTP/1.1 404 Not Found\r\ncontent-length: 12\r\n\r\nNo such file";
+        let (url, _) = stub_front(response).await;
+        let (result, report) = run_front(diagnose::front::Front::new(&url, None).unwrap(), "front download /missing").await;
+        assert_eq!(result.unwrap_err().exit_code(), 1);
+        assert_eq!(report.status, Some(404));
+        assert!(report.error.unwrap().contains("No such file"));
+
+        // nothing listens there any more
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+        let (result, report) = run_front(diagnose::front::Front::new(&url, None).unwrap(), "front mkdir /burrow").await;
+        assert_eq!(result.unwrap_err().exit_code(), 2);
+        assert_eq!(report.status, None);
+
+        let targets = Targets { node: None, front: None };
+        let command = DiagnosticsCommand::Front { command: FrontCommand::Ls { path: "/".to_string() } };
+        assert_eq!(command.run(&targets, &mut Report::default(), true).await.unwrap_err().exit_code(), 2);
+
+        for bad in ["https://localhost", "localhost:8080", "http:///nohost"] {
+            assert!(diagnose::front::Front::new(bad, None).is_err(), "{bad}");
+        }
+    }
+
+    #[tokio::test]
+    async fn binary_files_round_trip() {
+        let dir = std::env::temp_dir().join(format!("bnuystore-test-{}", Uuid::now_v7()));
+        std::fs::create_dir(&dir).unwrap();
+        let node = storage_node::Node::new(dir.join("node"), storage_node::DEFAULT_LOCK_WARN_AFTER, None).await.unwrap();
+        let (ours, theirs) = tokio::io::duplex(1 << 16);
+        tokio::spawn(storage_node::serve_connection(node, theirs, message::MessageLimits::default()));
+        let client = Client::new(Box::new(ours), message::MessageLimits::default());
+
+        // not UTF-8 anywhere, and every byte value
+        let mut contents = vec![0xff, 0xfe, 0xc3, 0x28, 0x80];
+        contents.extend(0..=u8::MAX);
+        let local = dir.join("in.bin");
+        std::fs::write(&local, &contents).unwrap();
+        let output = dir.join("out.bin");
+
+        let uuid = Uuid::now_v7();
+        let (written, client) = run(client, &format!("write-file -u {uuid} -f {}", local.display())).await;
+        assert!(written.is_ok());
+        let (read, _) = run(client, &format!("read-file {uuid} -o {}", output.display())).await;
+        assert!(read.is_ok());
+        assert_eq!(std::fs::read(&output).unwrap(), contents);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 }
@@ -0,0 +1,153 @@
+//! Hooks to make components misbehave on demand, for exercising error handling.
+//!
+//! Components take a `FaultInjector` when constructed and consult it at their hook points. Without
+//! the `testing` feature the injector is an empty struct whose hooks never fire, so the hooks cost
+//! nothing in normal builds.
+
+#[allow(unused)]
+use tracing::{trace, debug, info, warn, error, instrument};
+
+use std::io::ErrorKind;
+use std::time::Duration;
+
+/// Faults for `StorageNodeConnection::communicate`. Each fault applies to one message
+#[derive(Debug, Clone)]
+#[allow(unused)]
+pub enum ConnectionFault {
+    /// wait before sending the message
+    Delay(Duration),
+    /// send the message, but never deliver the response. useful for testing timeouts
+    DropResponse,
+    /// flip every bit of the response data. only affects responses carrying data, i.e. FileContents and FileRange
+    CorruptPayload,
+    /// fail without sending the message
+    Disconnect,
+}
+
+/// Faults for `FileLock::write`. Each fault applies to one write
+#[derive(Debug, Clone)]
+#[allow(unused)]
+pub enum WriteFault {
+    IOError(ErrorKind),
+    /// only write the first n bytes, but report success
+    ShortWrite(usize),
+}
+
+#[cfg(feature = "testing")]
+pub use enabled::FaultInjector;
+#[cfg(not(feature = "testing"))]
+pub use disabled::FaultInjector;
+
+#[cfg(feature = "testing")]
+mod enabled {
+    use super::*;
+
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+
+    /// Queues of faults to inject. Clones share the same queues, so a test can keep one handle and
+    /// give clones to the components under test
+    #[derive(Debug, Clone, Default)]
+    pub struct FaultInjector(Arc<Mutex<Faults>>);
+
+    #[derive(Debug, Default)]
+    struct Faults {
+        connection: VecDeque<ConnectionFault>,
+        read: VecDeque<ErrorKind>,
+        write: VecDeque<WriteFault>,
+        /// number of queries until one fails. 1 means the next query fails
+        fail_query_in: Option<usize>,
+        /// metadata queries made so far, failed or not
+        queries: usize,
+    }
+
+    #[allow(unused)]
+    impl FaultInjector {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        pub fn inject_connection_fault(&self, fault: ConnectionFault) {
+            self.0.lock().unwrap().connection.push_back(fault);
+        }
+
+        pub fn inject_read_fault(&self, kind: ErrorKind) {
+            self.0.lock().unwrap().read.push_back(kind);
+        }
+
+        pub fn inject_write_fault(&self, fault: WriteFault) {
+            self.0.lock().unwrap().write.push_back(fault);
+        }
+
+        /// Makes the n:th metadata query from now fail. n = 1 is the next query
+        pub fn fail_nth_query(&self, n: usize) {
+            self.0.lock().unwrap().fail_query_in = Some(n);
+        }
+
+        /// Number of metadata queries made by components given this injector, or a clone of it
+        pub fn queries_made(&self) -> usize {
+            self.0.lock().unwrap().queries
+        }
+
+        pub fn next_connection_fault(&self) -> Option<ConnectionFault> {
+            let fault = self.0.lock().unwrap().connection.pop_front();
+            if let Some(ref fault) = fault {
+                warn!(?fault, "Injecting connection fault");
+            }
+            fault
+        }
+
+        pub fn next_read_fault(&self) -> Option<ErrorKind> {
+            let fault = self.0.lock().unwrap().read.pop_front();
+            if let Some(ref fault) = fault {
+                warn!(?fault, "Injecting read fault");
+            }
+            fault
+        }
+
+        pub fn next_write_fault(&self) -> Option<WriteFault> {
+            let fault = self.0.lock().unwrap().write.pop_front();
+            if let Some(ref fault) = fault {
+                warn!(?fault, "Injecting write fault");
+            }
+            fault
+        }
+
+        pub fn query_should_fail(&self) -> bool {
+            let mut faults = self.0.lock().unwrap();
+            faults.queries += 1;
+            match faults.fail_query_in {
+                Some(1) => {
+                    faults.fail_query_in = None;
+                    warn!("Injecting query fault");
+                    true
+                }
+                Some(ref mut n) => {
+                    *n = n.saturating_sub(1);
+                    false
+                }
+                None => false,
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "testing"))]
+mod disabled {
+    use super::*;
+
+    #[derive(Debug, Clone, Default)]
+    pub struct FaultInjector(());
+
+    #[allow(unused)]
+    impl FaultInjector {
+        #[inline(always)]
+        pub fn next_connection_fault(&self) -> Option<ConnectionFault> { None }
+        #[inline(always)]
+        pub fn next_read_fault(&self) -> Option<ErrorKind> { None }
+        #[inline(always)]
+        pub fn next_write_fault(&self) -> Option<WriteFault> { None }
+        #[inline(always)]
+        pub fn query_should_fail(&self) -> bool { false }
+    }
+}
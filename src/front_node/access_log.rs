@@ -0,0 +1,320 @@
+#[allow(unused)]
+use tracing::{trace, debug, info, warn, error, instrument};
+
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use tokio::fs::File;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+
+use super::config::{AccessLogOptions, AccessLogFormat};
+use crate::listing_format::{civil_from_days, MONTHS};
+
+/// Entries waiting to be written. If the writer falls this far behind, entries are dropped
+const QUEUE_LENGTH: usize = 4096;
+
+/// One line in the access log
+#[derive(Debug, serde::Serialize)]
+pub struct AccessLogEntry {
+    #[serde(serialize_with = "serialize_rfc3339")]
+    pub time: SystemTime,
+    pub client: Option<IpAddr>,
+    pub user: Option<String>,
+    /// the HTTP method, or SFTP_READ/SFTP_SESSION for SFTP
+    pub method: String,
+    pub path: String,
+    /// the protocol, e.g. HTTP/1.1 or SFTP
+    pub protocol: String,
+    /// the HTTP status. 0 for SFTP
+    pub status: u16,
+    pub bytes: u64,
+    #[serde(rename = "duration_us", serialize_with = "serialize_micros")]
+    pub duration: Duration,
+}
+
+/// Writes access logs in the background. Cloning gives another handle to the same log.
+/// Logging never blocks or fails the caller; problems are counted in `failures` instead
+#[derive(Debug, Clone)]
+pub struct AccessLog {
+    tx: Option<mpsc::Sender<AccessLogEntry>>,
+    /// entries which were dropped or could not be written
+    pub failures: Arc<AtomicU64>,
+}
+
+impl AccessLog {
+    pub fn disabled() -> Self {
+        AccessLog { tx: None, failures: Arc::new(AtomicU64::new(0)) }
+    }
+
+    /// Opens the log file and starts the writer task. The file is reopened on SIGUSR1, so it can be
+    /// rotated externally, and rotated to `<path>.1` when it grows past max_bytes
+    pub async fn start(cfg: AccessLogOptions) -> std::io::Result<Self> {
+        let file = open_log(&cfg.path).await?;
+        let written = file.metadata().await?.len();
+        let reopen = signal(SignalKind::user_defined1())?;
+
+        let (tx, rx) = mpsc::channel(QUEUE_LENGTH);
+        let failures = Arc::new(AtomicU64::new(0));
+        let writer = LogWriter {
+            cfg,
+            file: BufWriter::new(file),
+            written,
+            failures: failures.clone(),
+        };
+        tokio::spawn(writer.run(rx, reopen));
+
+        Ok(AccessLog { tx: Some(tx), failures })
+    }
+
+    pub fn log(&self, entry: AccessLogEntry) {
+        let Some(ref tx) = self.tx else {
+            return;
+        };
+        if tx.try_send(entry).is_err() {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+async fn open_log(path: &PathBuf) -> std::io::Result<File> {
+    File::options().create(true).append(true).open(path).await
+}
+
+struct LogWriter {
+    cfg: AccessLogOptions,
+    file: BufWriter<File>,
+    written: u64,
+    failures: Arc<AtomicU64>,
+}
+
+impl LogWriter {
+    #[instrument(level = "debug", name = "access_log", skip_all)]
+    async fn run(mut self, mut rx: mpsc::Receiver<AccessLogEntry>, mut reopen: tokio::signal::unix::Signal) {
+        loop {
+            tokio::select! {
+                entry = rx.recv() => {
+                    let Some(entry) = entry else {
+                        break;
+                    };
+                    self.write(&entry).await;
+                    // buffer lines while more are queued, but don't leave them in the buffer
+                    if rx.is_empty() {
+                        self.flush().await;
+                    }
+                    if self.cfg.max_bytes.is_some_and(|max_bytes| self.written >= max_bytes) {
+                        self.rotate().await;
+                    }
+                }
+                _ = reopen.recv() => {
+                    info!(path = %self.cfg.path.display(), "Got SIGUSR1, reopening access log");
+                    self.flush().await;
+                    self.reopen().await;
+                }
+            }
+        }
+        self.flush().await;
+    }
+
+    async fn write(&mut self, entry: &AccessLogEntry) {
+        let mut line = match self.cfg.format {
+            AccessLogFormat::Common => format_common(entry),
+            AccessLogFormat::Json => serde_json::to_string(entry).expect("access log entries always serialize"),
+        };
+        line.push('\n');
+
+        match self.file.write_all(line.as_bytes()).await {
+            Ok(()) => self.written += line.len() as u64,
+            Err(e) => {
+                error!(?e, "Could not write access log");
+                self.failures.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    async fn flush(&mut self) {
+        if let Err(e) = self.file.flush().await {
+            error!(?e, "Could not flush access log");
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    async fn rotate(&mut self) {
+        self.flush().await;
+        let mut rotated = self.cfg.path.clone().into_os_string();
+        rotated.push(".1");
+        debug!(path = %self.cfg.path.display(), "Rotating access log");
+        if let Err(e) = tokio::fs::rename(&self.cfg.path, &rotated).await {
+            error!(?e, "Could not rotate access log");
+            self.failures.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.reopen().await;
+    }
+
+    // if reopening fails, we keep writing to the old file
+    async fn reopen(&mut self) {
+        match open_log(&self.cfg.path).await {
+            Ok(file) => {
+                self.written = file.metadata().await.map(|m| m.len()).unwrap_or(0);
+                self.file = BufWriter::new(file);
+            }
+            Err(e) => {
+                error!(?e, path = %self.cfg.path.display(), "Could not reopen access log");
+                self.failures.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+// (year, month [1-12], day, hour, minute, second) in UTC
+fn utc_parts(time: SystemTime) -> (i64, u32, u32, u64, u64, u64) {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let secs_of_day = secs % 86400;
+    (year, month, day, secs_of_day / 3600, (secs_of_day / 60) % 60, secs_of_day % 60)
+}
+
+// Common Log Format, with the duration in microseconds appended
+// `127.0.0.1 - user [10/Oct/2000:13:55:36 +0000] "GET /path HTTP/1.1" 200 2326 1234`
+fn format_common(entry: &AccessLogEntry) -> String {
+    let (year, month, day, hour, minute, second) = utc_parts(entry.time);
+    format!(
+        "{client} - {user} [{day:02}/{month}/{year}:{hour:02}:{minute:02}:{second:02} +0000] \"{method} {path} {protocol}\" {status} {bytes} {duration}",
+        client = entry.client.map_or("-".to_string(), |ip| ip.to_string()),
+        user = entry.user.as_deref().unwrap_or("-"),
+        month = MONTHS[month as usize - 1],
+        method = entry.method,
+        path = entry.path.escape_default(),
+        protocol = entry.protocol,
+        status = entry.status,
+        bytes = entry.bytes,
+        duration = entry.duration.as_micros(),
+    )
+}
+
+fn serialize_rfc3339<S: serde::Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+    let (year, month, day, hour, minute, second) = utc_parts(*time);
+    serializer.collect_str(&format_args!("{year:04}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}Z"))
+}
+
+fn serialize_micros<S: serde::Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(duration.as_micros() as u64)
+}
+
+/// Logs an entry when dropped, so the number of bytes sent can be counted while a response body
+/// is streamed, and the line is written once it has finished (or been aborted)
+pub struct PendingEntry {
+    log: AccessLog,
+    entry: Option<AccessLogEntry>,
+    started: Instant,
+}
+
+impl PendingEntry {
+    pub fn new(log: AccessLog, entry: AccessLogEntry, started: Instant) -> Self {
+        PendingEntry { log, entry: Some(entry), started }
+    }
+
+    pub fn add_bytes(&mut self, n: u64) {
+        if let Some(ref mut entry) = self.entry {
+            entry.bytes += n;
+        }
+    }
+}
+
+impl Drop for PendingEntry {
+    fn drop(&mut self) {
+        if let Some(mut entry) = self.entry.take() {
+            entry.duration = self.started.elapsed();
+            self.log.log(entry);
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    fn entry() -> AccessLogEntry {
+        AccessLogEntry {
+            // 2024-03-05 14:29:07 UTC
+            time: UNIX_EPOCH + Duration::from_secs(1709648947),
+            client: Some("192.0.2.7".parse().unwrap()),
+            user: Some("bnuy".to_string()),
+            method: "GET".to_string(),
+            path: "/get/file-by-path/burrow/\"carrots\".txt".to_string(),
+            protocol: "HTTP/1.1".to_string(),
+            status: 200,
+            bytes: 2326,
+            duration: Duration::from_micros(1234),
+        }
+    }
+
+    #[test]
+    fn common_format() {
+        assert_eq!(
+            format_common(&entry()),
+            r#"192.0.2.7 - bnuy [05/Mar/2024:14:29:07 +0000] "GET /get/file-by-path/burrow/\"carrots\".txt HTTP/1.1" 200 2326 1234"#,
+        );
+        let anonymous = AccessLogEntry { client: None, user: None, ..entry() };
+        assert_eq!(
+            format_common(&anonymous),
+            r#"- - - [05/Mar/2024:14:29:07 +0000] "GET /get/file-by-path/burrow/\"carrots\".txt HTTP/1.1" 200 2326 1234"#,
+        );
+    }
+
+    #[test]
+    fn json_format() {
+        let line: serde_json::Value = serde_json::from_str(&serde_json::to_string(&entry()).unwrap()).unwrap();
+        assert_eq!(line, serde_json::json!({
+            "time": "2024-03-05T14:29:07Z",
+            "client": "192.0.2.7",
+            "user": "bnuy",
+            "method": "GET",
+            "path": "/get/file-by-path/burrow/\"carrots\".txt",
+            "protocol": "HTTP/1.1",
+            "status": 200,
+            "bytes": 2326,
+            "duration_us": 1234,
+        }));
+    }
+
+    /// Reads the lines of `path` once there are `n` of them
+    pub async fn wait_for_lines(path: &std::path::Path, n: usize) -> Vec<String> {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let contents = tokio::fs::read_to_string(path).await.unwrap_or_default();
+                let lines: Vec<String> = contents.lines().map(str::to_string).collect();
+                if lines.len() >= n {
+                    return lines;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.expect("the access log wasn't written")
+    }
+
+    #[tokio::test]
+    async fn log_is_rotated_when_full() {
+        let path = std::env::temp_dir().join(format!("bnuystore-test-{}.log", uuid::Uuid::now_v7()));
+        let line_len = format_common(&entry()).len() as u64 + 1;
+        let cfg = AccessLogOptions { path: path.clone(), format: AccessLogFormat::Common, max_bytes: Some(2 * line_len) };
+        let log = AccessLog::start(cfg).await.unwrap();
+
+        for _ in 0..3 {
+            log.log(entry());
+        }
+        let mut rotated = path.clone().into_os_string();
+        rotated.push(".1");
+        let rotated = PathBuf::from(rotated);
+        assert_eq!(wait_for_lines(&path, 1).await, [format_common(&entry())]);
+        assert_eq!(wait_for_lines(&rotated, 2).await.len(), 2);
+        assert_eq!(log.failures.load(Ordering::Relaxed), 0);
+
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(rotated).unwrap();
+    }
+}
@@ -0,0 +1,420 @@
+#[allow(unused)]
+use tracing::{trace, debug, info, warn, error, instrument};
+
+use mysql_async::prelude::*;
+use uuid::Uuid;
+use async_trait::async_trait;
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
+
+use super::config::{BackupOptions, BackupSinkOptions};
+use super::storage_node_connection::StorageNodeConnection;
+use super::tys::{StorageNodeID, Error};
+use super::rehash::stored_digest;
+use super::leader::Leadership;
+use super::s3::S3Sink;
+use crate::hashing::{ContentDigest, HashAlgorithm};
+
+/// How long the worker sleeps when nothing in the queue is due
+const IDLE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// Queue entries taken at a time
+const BATCH_SIZE: u32 = 32;
+/// Failed pushes are retried after 2^attempts seconds, up to this
+const MAX_BACKOFF_S: u64 = 3600;
+
+/// Somewhere to push copies of files. Pushing the same UUID twice must be harmless, as a crash
+/// between pushing and marking the queue entry done makes the worker push it again
+#[async_trait]
+pub trait BackupSink: Send + Sync + std::fmt::Debug {
+    async fn put(&self, uuid: Uuid, data: &[u8]) -> std::io::Result<()>;
+    /// None if the sink doesn't have the file
+    async fn get(&self, uuid: Uuid) -> std::io::Result<Option<Vec<u8>>>;
+}
+
+pub fn sink_from_config(cfg: &BackupSinkOptions) -> std::io::Result<Arc<dyn BackupSink>> {
+    Ok(match cfg {
+        BackupSinkOptions::Directory { path } => Arc::new(DirectorySink { path: path.clone() }),
+        BackupSinkOptions::S3(s3) => Arc::new(S3Sink::new(s3)?),
+    })
+}
+
+/// Stores each file as `<path>/<uuid>`
+#[derive(Debug)]
+pub struct DirectorySink {
+    path: PathBuf,
+}
+
+#[async_trait]
+impl BackupSink for DirectorySink {
+    async fn put(&self, uuid: Uuid, data: &[u8]) -> std::io::Result<()> {
+        // write to a temporary file first, so a crash never leaves a partial file under the real name
+        let final_path = self.path.join(uuid.to_string());
+        let tmp_path = self.path.join(format!("{uuid}.tmp"));
+        let mut file = tokio::fs::File::create(&tmp_path).await?;
+        tokio::io::AsyncWriteExt::write_all(&mut file, data).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&tmp_path, &final_path).await
+    }
+
+    async fn get(&self, uuid: Uuid) -> std::io::Result<Option<Vec<u8>>> {
+        match tokio::fs::read(self.path.join(uuid.to_string())).await {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Backlog of the backup queue
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct BackupStatus {
+    /// files not yet pushed
+    pub pending: u64,
+    /// pending files which have failed at least once
+    pub failing: u64,
+    /// how long the oldest pending file has waited
+    pub lag_s: Option<u64>,
+    /// backed up files which didn't match the stored file when verified, since startup
+    pub verify_mismatches: u64,
+}
+
+/// Handle to the backup workers
+#[derive(Debug)]
+pub struct Backup {
+    verify_mismatches: Arc<AtomicU64>,
+    /// the worker tasks, for the shutdown drain report
+    pub(super) jobs: Vec<(&'static str, JoinHandle<()>)>,
+}
+
+impl Backup {
+    /// Starts the push and verify workers, if this node runs them. They only act while this node is
+    /// the leader, so that front nodes sharing the database don't push the same files
+    pub fn start(
+        cfg: &BackupOptions,
+        conn_pool: mysql_async::Pool,
+        active_connections: Arc<RwLock<HashMap<StorageNodeID, Arc<StorageNodeConnection>>>>,
+    ) -> std::io::Result<Backup> {
+        let verify_mismatches = Arc::new(AtomicU64::new(0));
+        let mut jobs = Vec::new();
+
+        if cfg.run_worker {
+            let worker = Worker {
+                leadership: Leadership::start(conn_pool.clone(), "backup"),
+                conn_pool,
+                active_connections,
+                sink: sink_from_config(&cfg.sink)?,
+                verify_mismatches: verify_mismatches.clone(),
+            };
+            let worker = Arc::new(worker);
+            jobs.push(("backup", tokio::spawn(worker.clone().push_queued())));
+            if cfg.verify_sample_size > 0 {
+                let verify = worker.verify_samples(cfg.verify_sample_size, Duration::from_secs(cfg.verify_interval_s));
+                jobs.push(("backup_verify", tokio::spawn(verify)));
+            }
+        } else {
+            info!("Not running the backup worker; another front node is expected to");
+        }
+
+        Ok(Backup { verify_mismatches, jobs })
+    }
+
+    pub async fn status(&self, conn_pool: &mysql_async::Pool) -> Result<BackupStatus, Error> {
+        let query = r#"
+            SELECT
+                COUNT(*),
+                COUNT(IF(attempts > 0, 1, NULL)),
+                TIMESTAMPDIFF(SECOND, MIN(enqueued_at), NOW())
+                FROM backup_queue WHERE done_at IS NULL;
+        "#;
+        let (pending, failing, lag_s): (u64, u64, Option<u64>) = query.first(conn_pool).await?
+            .expect("aggregate queries always return a row");
+        Ok(BackupStatus {
+            pending,
+            failing,
+            lag_s,
+            verify_mismatches: self.verify_mismatches.load(Ordering::Relaxed),
+        })
+    }
+}
+
+struct Worker {
+    leadership: Leadership,
+    conn_pool: mysql_async::Pool,
+    active_connections: Arc<RwLock<HashMap<StorageNodeID, Arc<StorageNodeConnection>>>>,
+    sink: Arc<dyn BackupSink>,
+    verify_mismatches: Arc<AtomicU64>,
+}
+
+impl Worker {
+    #[instrument(level = "info", name = "backup", skip_all)]
+    async fn push_queued(self: Arc<Self>) {
+        loop {
+            if !self.leadership.is_leader() {
+                trace!("Not the leader; another front node pushes files");
+                tokio::time::sleep(IDLE_POLL_INTERVAL).await;
+                continue;
+            }
+
+            let query = r#"
+                SELECT uuid FROM backup_queue
+                    WHERE done_at IS NULL AND next_attempt_at <= NOW()
+                    ORDER BY next_attempt_at
+                    LIMIT :batch_size;
+            "#;
+            let due: Vec<Uuid> = match query.with(params! { "batch_size" => BATCH_SIZE }).fetch(&self.conn_pool).await {
+                Ok(due) => due,
+                Err(e) => {
+                    error!(?e, "Could not read backup queue");
+                    tokio::time::sleep(IDLE_POLL_INTERVAL).await;
+                    continue;
+                }
+            };
+            if due.is_empty() {
+                tokio::time::sleep(IDLE_POLL_INTERVAL).await;
+                continue;
+            }
+
+            for uuid in due {
+                // leadership can move to another front node partway through a batch, which then
+                // pushes the rest. pushing a file twice is harmless, but wasted
+                if !self.leadership.is_leader() {
+                    break;
+                }
+                let result = self.push(uuid).await;
+                if let Err(e) = self.mark(uuid, result).await {
+                    // the entry stays due, and is pushed again
+                    error!(?e, %uuid, "Could not update backup queue");
+                }
+            }
+        }
+    }
+
+    async fn push(&self, uuid: Uuid) -> Result<(), Error> {
+        let data = read_from_node(&self.conn_pool, &self.active_connections, uuid).await?;
+        self.sink.put(uuid, &data).await?;
+        trace!(%uuid, len = data.len(), "Backed up file");
+        Ok(())
+    }
+
+    async fn mark(&self, uuid: Uuid, result: Result<(), Error>) -> Result<(), Error> {
+        match result {
+            Ok(()) => {
+                let query = "UPDATE backup_queue SET done_at = NOW(), last_error = NULL WHERE uuid = :uuid AND done_at IS NULL;";
+                query.with(params! { "uuid" => uuid }).ignore(&self.conn_pool).await?;
+            }
+            Err(e) => {
+                warn!(?e, %uuid, "Could not back up file, retrying later");
+                // attempts is already incremented when next_attempt_at is computed
+                let query = r#"
+                    UPDATE backup_queue SET
+                        attempts = attempts + 1,
+                        last_error = :error,
+                        next_attempt_at = NOW() + INTERVAL LEAST(POW(2, attempts), :max_backoff) SECOND
+                        WHERE uuid = :uuid;
+                "#;
+                query.with(params! {
+                    "uuid" => uuid,
+                    "error" => format!("{e:?}"),
+                    "max_backoff" => MAX_BACKOFF_S,
+                }).ignore(&self.conn_pool).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads back a random sample of backed up files and compares them with the stored digests.
+    /// Files which don't match are queued again
+    #[instrument(level = "info", name = "backup_verify", skip(self))]
+    async fn verify_samples(self: Arc<Self>, sample_size: u32, interval: Duration) {
+        loop {
+            tokio::time::sleep(interval).await;
+            if !self.leadership.is_leader() {
+                continue;
+            }
+
+            let query = "SELECT uuid FROM backup_queue WHERE done_at IS NOT NULL ORDER BY RAND() LIMIT :sample_size;";
+            let sample: Vec<Uuid> = match query.with(params! { "sample_size" => sample_size }).fetch(&self.conn_pool).await {
+                Ok(sample) => sample,
+                Err(e) => {
+                    error!(?e, "Could not sample backup queue");
+                    continue;
+                }
+            };
+
+            let mut verified = 0;
+            for uuid in sample {
+                match self.verify(uuid).await {
+                    Ok(true) => verified += 1,
+                    Ok(false) => {
+                        self.verify_mismatches.fetch_add(1, Ordering::Relaxed);
+                        error!(%uuid, "Backed up file does not match the stored file; queueing it again");
+                        let query = "UPDATE backup_queue SET done_at = NULL, next_attempt_at = NOW() WHERE uuid = :uuid;";
+                        if let Err(e) = query.with(params! { "uuid" => uuid }).ignore(&self.conn_pool).await {
+                            error!(?e, %uuid, "Could not queue file again");
+                        }
+                    }
+                    // not the backup's fault, e.g. the storage node is down
+                    Err(e) => warn!(?e, %uuid, "Could not verify backed up file"),
+                }
+            }
+            debug!(verified, "Verified backup sample");
+        }
+    }
+
+    async fn verify(&self, uuid: Uuid) -> Result<bool, Error> {
+        let Some(backed_up) = self.sink.get(uuid).await? else {
+            return Ok(false);
+        };
+
+        let query = "SELECT hash_algorithm, digest FROM files WHERE uuid = :uuid;";
+        let row: Option<(Option<String>, Option<Vec<u8>>)> = query.with(params! { "uuid" => uuid }).first(&self.conn_pool).await?;
+        let Some((algorithm, digest)) = row else {
+            return Err(Error::UnknownUUID);
+        };
+        let expected = match stored_digest(algorithm, digest) {
+            Some(digest) => digest,
+            // files from before digests were stored are compared with the storage node's copy
+            None => {
+                let stored = read_from_node(&self.conn_pool, &self.active_connections, uuid).await?;
+                ContentDigest::of(HashAlgorithm::default(), &stored)
+            }
+        };
+        Ok(ContentDigest::of(expected.algorithm, &backed_up) == expected)
+    }
+}
+
+// like FrontNode::get_file, but doesn't count as an access
+async fn read_from_node(
+    conn_pool: &mysql_async::Pool,
+    active_connections: &RwLock<HashMap<StorageNodeID, Arc<StorageNodeConnection>>>,
+    uuid: Uuid,
+) -> Result<Vec<u8>, Error> {
+    let query = "SELECT stored_on_node_id, COALESCE(blob_uuid, uuid) FROM files WHERE uuid = :uuid;";
+    let Some((id, blob)): Option<(StorageNodeID, Uuid)> = query.with(params! { "uuid" => uuid }).first(conn_pool).await? else {
+        return Err(Error::UnknownUUID);
+    };
+    let conn = match active_connections.read().await.get(&id) {
+        Some(conn) => conn.clone(),
+        None => return Err(Error::NotConnectedToNode),
+    };
+    conn.read_file(blob).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::fault_injection::FaultInjector;
+    use crate::front_node::UploadOptions;
+    use crate::front_node::config::S3SinkOptions;
+    use crate::front_node::test_support::{self, DatabaseFrontNode};
+
+    fn sink_directory() -> PathBuf {
+        let path = std::env::temp_dir().join(format!("bnuystore-test-{}", Uuid::now_v7()));
+        std::fs::create_dir(&path).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn directory_sink_round_trip() {
+        let path = sink_directory();
+        let sink = sink_from_config(&BackupSinkOptions::Directory { path: path.clone() }).unwrap();
+        let uuid = Uuid::now_v7();
+
+        assert_eq!(sink.get(uuid).await.unwrap(), None);
+        sink.put(uuid, b"bnuy").await.unwrap();
+        assert_eq!(sink.get(uuid).await.unwrap().as_deref(), Some(b"bnuy".as_slice()));
+        // pushed again after a crash, which replaces it
+        sink.put(uuid, b"bun").await.unwrap();
+        assert_eq!(sink.get(uuid).await.unwrap().as_deref(), Some(b"bun".as_slice()));
+
+        let names: Vec<_> = std::fs::read_dir(&path).unwrap().map(|entry| entry.unwrap().file_name().into_string().unwrap()).collect();
+        assert_eq!(names, [uuid.to_string()], "temporary files should be renamed");
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn sinks_are_checked_at_startup() {
+        let node = test_support::offline(&test_support::offline_config());
+        let cfg = BackupOptions {
+            sink: BackupSinkOptions::S3(S3SinkOptions {
+                endpoint: "https://s3.eu-north-1.amazonaws.com".to_string(),
+                bucket: "bnuy".to_string(),
+                region: "eu-north-1".to_string(),
+                prefix: String::new(),
+                access_key_id: "bnuy-key".to_string(),
+                secret_access_key: "bnuy-secret".to_string(),
+                tls_ca_file: "/nonexistent".into(),
+            }),
+            run_worker: true,
+            verify_sample_size: 0,
+            verify_interval_s: 3600,
+        };
+        let e = Backup::start(&cfg, node.conn_pool.clone(), node.active_connections.clone()).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+
+        // the sink is only used by the worker
+        let cfg = BackupOptions { run_worker: false, ..cfg };
+        assert!(Backup::start(&cfg, node.conn_pool.clone(), node.active_connections.clone()).is_ok());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MySQL database, see test_support::DATABASE_URL_VAR"]
+    async fn uploads_are_pushed_and_verified() {
+        let path = sink_directory();
+        let mut cfg = test_support::database_config();
+        cfg.backup = Some(BackupOptions {
+            sink: BackupSinkOptions::Directory { path: path.clone() },
+            run_worker: true,
+            verify_sample_size: 0,
+            verify_interval_s: 3600,
+        });
+        let front = DatabaseFrontNode::start_with_faults(&cfg, FaultInjector::default()).await;
+
+        let uuid = front.node.upload_file("backed up".to_string(), front.dir, b"bnuy".to_vec(), UploadOptions::default()).await.unwrap();
+        let sink = sink_from_config(&cfg.backup.as_ref().unwrap().sink).unwrap();
+        let pushed = tokio::time::timeout(IDLE_POLL_INTERVAL * 4, async {
+            loop {
+                if let Some(data) = sink.get(uuid).await.unwrap() {
+                    return data;
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }).await.expect("the file wasn't pushed");
+        assert_eq!(pushed, b"bnuy");
+
+        // other tests share the queue, so only this file's entry is checked
+        let query = "SELECT done_at IS NOT NULL, attempts FROM backup_queue WHERE uuid = :uuid;";
+        let marked = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let row: (bool, u32) = query.with(params! { "uuid" => uuid }).first(&front.node.conn_pool).await.unwrap().unwrap();
+                if row.0 {
+                    return row;
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }).await.expect("the queue entry wasn't marked done");
+        assert_eq!(marked, (true, 0));
+
+        let worker = Worker {
+            leadership: Leadership::start(front.node.conn_pool.clone(), "backup-verify-test"),
+            conn_pool: front.node.conn_pool.clone(),
+            active_connections: front.node.active_connections.clone(),
+            sink: sink.clone(),
+            verify_mismatches: Arc::new(AtomicU64::new(0)),
+        };
+        assert!(worker.verify(uuid).await.unwrap());
+        sink.put(uuid, b"tampered").await.unwrap();
+        assert!(!worker.verify(uuid).await.unwrap());
+
+        front.node.remove_file(uuid, true).await.unwrap();
+        std::fs::remove_dir_all(path).unwrap();
+    }
+}
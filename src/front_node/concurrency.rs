@@ -0,0 +1,190 @@
+#[allow(unused)]
+use tracing::{trace, debug, info, warn, error, instrument};
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use super::config::ConcurrencyLimitOptions;
+
+/// Counts concurrent holders per key. Acquiring never waits: if the key is at its limit, the caller
+/// is expected to reject the request. Keys are removed as soon as their last permit is dropped, so
+/// idle keys don't accumulate
+#[derive(Debug, Clone, Default)]
+pub struct KeyedLimiter(Arc<Mutex<HashMap<String, usize>>>);
+
+/// Held for the duration of the limited operation
+#[derive(Debug)]
+pub struct Permit {
+    limiter: KeyedLimiter,
+    key: String,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut in_use = self.limiter.0.lock().unwrap();
+        match in_use.get_mut(&self.key) {
+            Some(1) => { in_use.remove(&self.key); }
+            Some(n) => *n -= 1,
+            None => warn!(self.key, "Permit dropped for a key with no permits"),
+        }
+    }
+}
+
+impl KeyedLimiter {
+    pub fn try_acquire(&self, key: &str, limit: usize) -> Option<Permit> {
+        let mut in_use = self.0.lock().unwrap();
+        // refusals don't add the key, as there'd be no permit to remove it again
+        if in_use.get(key).is_some_and(|&n| n >= limit) || limit == 0 {
+            return None;
+        }
+        *in_use.entry(key.to_string()).or_insert(0) += 1;
+        Some(Permit { limiter: self.clone(), key: key.to_string() })
+    }
+
+    /// Number of permits held for each key with any permits held
+    #[allow(unused)]
+    pub fn utilization(&self) -> Vec<(String, usize)> {
+        let in_use = self.0.lock().unwrap();
+        in_use.iter()
+            .filter(|(_, n)| **n > 0)
+            .map(|(key, n)| (key.clone(), *n))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RouteClass {
+    Reads,
+    Writes,
+    Listings,
+}
+
+impl RouteClass {
+    pub const ALL: [RouteClass; 3] = [RouteClass::Reads, RouteClass::Writes, RouteClass::Listings];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            RouteClass::Reads => "reads",
+            RouteClass::Writes => "writes",
+            RouteClass::Listings => "listings",
+        }
+    }
+}
+
+/// Concurrency limits shared between the HTTP and SFTP servers
+#[derive(Debug)]
+pub struct ConcurrencyLimits {
+    cfg: ConcurrencyLimitOptions,
+    routes: KeyedLimiter,
+    users: KeyedLimiter,
+    /// SFTP sessions by user. Not limited, as they already hold one of their user's permits
+    sftp_sessions: KeyedLimiter,
+}
+
+impl ConcurrencyLimits {
+    pub fn new(cfg: ConcurrencyLimitOptions) -> Self {
+        ConcurrencyLimits {
+            cfg,
+            routes: KeyedLimiter::default(),
+            users: KeyedLimiter::default(),
+            sftp_sessions: KeyedLimiter::default(),
+        }
+    }
+
+    pub fn try_acquire_route(&self, class: RouteClass) -> Option<Permit> {
+        let limit = match class {
+            RouteClass::Reads => self.cfg.reads,
+            RouteClass::Writes => self.cfg.writes,
+            RouteClass::Listings => self.cfg.listings,
+        };
+        let permit = self.routes.try_acquire(class.name(), limit);
+        if permit.is_none() {
+            warn!(class = class.name(), limit, "Route concurrency limit reached");
+        }
+        permit
+    }
+
+    /// Only authenticated users have a name to limit by: SFTP users, and HTTP users with
+    /// scope_to_home_directories
+    pub fn try_acquire_user(&self, user: &str) -> Option<Permit> {
+        let permit = self.users.try_acquire(user, self.cfg.per_user);
+        if permit.is_none() {
+            warn!(user, limit = self.cfg.per_user, "Per-user concurrency limit reached");
+        }
+        permit
+    }
+
+    /// Counts an SFTP session of `user` for as long as the permit is held, to tell sessions apart
+    /// from HTTP requests in user_utilization
+    #[cfg_attr(not(feature = "sftp"), allow(unused))]
+    pub fn count_sftp_session(&self, user: &str) -> Permit {
+        self.sftp_sessions.try_acquire(user, usize::MAX).expect("SFTP sessions aren't limited")
+    }
+
+    pub fn route_utilization(&self) -> Vec<(String, usize)> {
+        self.routes.utilization()
+    }
+
+    /// HTTP requests and SFTP sessions, by user
+    pub fn user_utilization(&self) -> Vec<(String, usize)> {
+        self.users.utilization()
+    }
+
+    pub fn sftp_session_utilization(&self) -> Vec<(String, usize)> {
+        self.sftp_sessions.utilization()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(per_user: usize) -> ConcurrencyLimits {
+        ConcurrencyLimits::new(toml::from_str(&format!("reads = 2\nwrites = 1\nlistings = 1\nper_user = {per_user}")).unwrap())
+    }
+
+    #[test]
+    fn users_are_capped_separately() {
+        let limits = limits(2);
+        let bnuy = [limits.try_acquire_user("bnuy").unwrap(), limits.try_acquire_user("bnuy").unwrap()];
+        assert!(limits.try_acquire_user("bnuy").is_none());
+
+        // someone else isn't held up by bnuy
+        let hare = limits.try_acquire_user("hare").unwrap();
+        let mut utilization = limits.user_utilization();
+        utilization.sort();
+        assert_eq!(utilization, [("bnuy".to_string(), 2), ("hare".to_string(), 1)]);
+
+        drop(hare);
+        let [first, _second] = bnuy;
+        drop(first);
+        assert!(limits.try_acquire_user("bnuy").is_some());
+    }
+
+    #[test]
+    fn route_classes_are_capped_separately() {
+        let limits = limits(1);
+        let reads = [limits.try_acquire_route(RouteClass::Reads).unwrap(), limits.try_acquire_route(RouteClass::Reads).unwrap()];
+        assert!(limits.try_acquire_route(RouteClass::Reads).is_none());
+        let _write = limits.try_acquire_route(RouteClass::Writes).unwrap();
+        assert!(limits.try_acquire_route(RouteClass::Writes).is_none());
+        assert!(limits.try_acquire_route(RouteClass::Listings).is_some());
+
+        drop(reads);
+        assert!(limits.try_acquire_route(RouteClass::Reads).is_some());
+    }
+
+    #[test]
+    fn idle_keys_are_evicted() {
+        let limits = limits(4);
+        let permits: Vec<Permit> = (0..100).map(|i| limits.try_acquire_user(&format!("user {i}")).unwrap()).collect();
+        assert_eq!(limits.user_utilization().len(), 100);
+        drop(permits);
+        assert!(limits.user_utilization().is_empty());
+        assert!(limits.users.0.lock().unwrap().is_empty());
+
+        // a limit of zero refuses everything, and doesn't leave the key behind either
+        assert!(limits.routes.try_acquire("nothing", 0).is_none());
+        assert!(limits.routes.0.lock().unwrap().is_empty());
+    }
+}
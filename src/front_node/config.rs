@@ -1,15 +1,51 @@
 #[allow(unused)]
 use tracing::{trace, debug, info, warn, error, instrument};
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use std::collections::HashMap;
 
+use crate::message::{self, AuthToken, MessageLimits};
+
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct Config {
     pub database_connection: DatabaseConnectionOptions,
     pub http_server: HTTPServerOptions,
-    pub sftp_server: SFTPServerOptions,
+    /// no SFTP server is started if this is left out. only allowed in builds with the sftp feature
+    #[serde(default)]
+    pub sftp_server: Option<SFTPServerOptions>,
+    #[serde(default)]
+    pub uploads: UploadOptions,
+    #[serde(default)]
+    pub concurrency_limits: ConcurrencyLimitOptions,
+    #[serde(default)]
+    pub download_caching: DownloadCachingOptions,
+    #[serde(default)]
+    pub failover: FailoverOptions,
+    /// no access log is written if this is left out
+    #[serde(default)]
+    pub access_log: Option<AccessLogOptions>,
+    /// new files are not backed up if this is left out
+    #[serde(default)]
+    pub backup: Option<BackupOptions>,
+    /// other front nodes sharing the database. without this, requests for sessions owned by another
+    /// front node are refused
+    #[serde(default)]
+    pub cluster: Option<ClusterOptions>,
+    /// the /admin routes are not served if this is left out
+    #[serde(default)]
+    pub admin: Option<AdminOptions>,
+    #[serde(default)]
+    pub node_monitor: NodeMonitorOptions,
+    #[serde(default)]
+    pub path_cache: PathCacheOptions,
+    #[serde(default)]
+    pub trash: Option<TrashOptions>,
+    #[serde(default)]
+    pub versioning: Option<VersioningOptions>,
+    /// files stay on the tier they were uploaded to if this is left out
+    #[serde(default)]
+    pub tiering: Option<TieringOptions>,
 
     pub storage_nodes: HashMap<String, StorageNodeConfig>,
 }
@@ -17,63 +53,766 @@ pub struct Config {
 impl Config {
     // prints error and exists if the config is malformed
     pub async fn read_from_path(path: PathBuf) -> Self {
-        let contents = match tokio::fs::read_to_string(&path).await {
+        match Config::try_read_from_path(&path).await {
             Ok(c) => c,
             Err(e) => {
-                if e.kind() == std::io::ErrorKind::NotFound {
-                    error!(path = %path.display(), "Could not find config file");
-                } else {
-                    error!(?e, "Could not read config file");
-                }
+                error!(e, "Could not load config file");
                 std::process::exit(1);
             }
-        };
-        match toml::from_str(&contents) {
-            Ok(c) => c,
-            Err(e) => {
-                error!(?e, "Could not parse config file");
-                std::process::exit(1);
+        }
+    }
+
+    /// Like read_from_path, but the problem is returned rather than exiting, e.g. for reloading
+    pub async fn try_read_from_path(path: &Path) -> Result<Self, String> {
+        let contents = tokio::fs::read_to_string(path).await.map_err(|e| {
+            if e.kind() == std::io::ErrorKind::NotFound {
+                format!("Could not find config file {}", path.display())
+            } else {
+                format!("Could not read config file {}: {e}", path.display())
+            }
+        })?;
+        toml::from_str(&contents).map_err(|e| format!("Could not parse config file {}: {e}", path.display()))
+    }
+}
+
+impl Config {
+    /// Problems with the config which prevent starting. All of them are listed, so they can be fixed
+    /// in one go
+    pub fn errors(&self) -> Vec<String> {
+        let mut errors = self.database_connection.errors();
+
+        let mut names: Vec<&String> = self.storage_nodes.keys().collect();
+        names.sort();
+        for name in names {
+            if let Err(e) = self.storage_nodes[name].host_and_port() {
+                errors.push(format!("storage node {name}: {e}"));
+            }
+            let node = &self.storage_nodes[name];
+            if node.auth_token.is_some() && node.auth_token_file.is_some() {
+                errors.push(format!("storage node {name}: has both auth_token and auth_token_file; give only one"));
+            }
+            if node.tls && node.tls_ca_file.is_none() {
+                errors.push(format!("storage node {name}: tls needs tls_ca_file, the CA which signed the node's certificate"));
+            }
+            if !node.tls && (node.tls_ca_file.is_some() || node.tls_server_name.is_some()) {
+                errors.push(format!("storage node {name}: tls_ca_file and tls_server_name are only used with tls = true"));
+            }
+            if self.storage_nodes[name].max_in_flight == 0 {
+                errors.push(format!("storage node {name}: max_in_flight must be at least 1"));
+            }
+            if node.connections == 0 {
+                errors.push(format!("storage node {name}: connections must be at least 1"));
+            }
+            if node.heartbeat_interval_s != 0 && node.heartbeat_max_missed == 0 {
+                errors.push(format!("storage node {name}: heartbeat_max_missed must be at least 1. Set heartbeat_interval_s = 0 to not ping"));
+            }
+            if node.max_header_bytes == 0 || node.max_data_bytes == 0 {
+                errors.push(format!("storage node {name}: max_header_bytes and max_data_bytes must be at least 1"));
+            }
+            if let Some(ref compression) = self.storage_nodes[name].compression {
+                let levels = zstd::compression_level_range();
+                if !levels.contains(&compression.level) {
+                    errors.push(format!(
+                        "storage node {name}: compression.level must be from {} to {}",
+                        levels.start(), levels.end(),
+                    ));
+                }
+            }
+        }
+
+        if self.node_monitor.poll_interval_s == 0 {
+            errors.push("node_monitor.poll_interval_s must be at least 1".to_string());
+        }
+        if self.trash.as_ref().is_some_and(|trash| trash.purge_interval_s == 0) {
+            errors.push("trash.purge_interval_s must be at least 1".to_string());
+        }
+        if self.versioning.as_ref().is_some_and(|versioning| versioning.keep_versions == 0) {
+            errors.push("versioning.keep_versions must be at least 1. Leave out [versioning] to not keep versions".to_string());
+        }
+        if let Some(ref tiering) = self.tiering {
+            if tiering.interval_s == 0 {
+                errors.push("tiering.interval_s must be at least 1".to_string());
+            }
+            if tiering.bytes_per_s == 0 {
+                errors.push("tiering.bytes_per_s must be at least 1".to_string());
+            }
+        }
+
+        if let Some(BackupSinkOptions::S3(ref s3)) = self.backup.as_ref().map(|backup| &backup.sink) {
+            if !s3.endpoint.starts_with("http://") && !s3.endpoint.starts_with("https://") {
+                errors.push("backup.sink.endpoint must start with http:// or https://".to_string());
+            }
+        }
+
+        errors
+    }
+
+    /// Problems with the config which don't prevent starting, but are probably mistakes
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+
+        if let Some(ref sftp) = self.sftp_server {
+            if sftp.open_handles_soft_limit > sftp.open_handles_hard_limit {
+                warnings.push(format!(
+                    "sftp_server.open_handles_soft_limit ({}) is above open_handles_hard_limit ({}), so it will never be hit",
+                    sftp.open_handles_soft_limit, sftp.open_handles_hard_limit,
+                ));
+            }
+        }
+
+        if let Some(ref cluster) = self.cluster {
+            if !cluster.instance_id.chars().all(|c| c.is_ascii_graphic()) {
+                warnings.push(format!("cluster.instance_id {:?} isn't printable ASCII, so requests can't be forwarded to or from this node", cluster.instance_id));
+            }
+            if cluster.peers.contains_key(&cluster.instance_id) {
+                warnings.push(format!("cluster.peers lists this node's own instance_id {}", cluster.instance_id));
+            }
+        }
+
+        if self.admin.as_ref().is_some_and(|admin| admin.token.len() < 16) {
+            warnings.push("admin.token is shorter than 16 characters".to_string());
+        }
+
+        for (name, node) in &self.storage_nodes {
+            for previous_name in &node.previous_names {
+                if self.storage_nodes.contains_key(previous_name) {
+                    warnings.push(format!(
+                        "storage node {name} lists {previous_name} as a previous name, but {previous_name} is also configured",
+                    ));
+                }
             }
         }
+
+        warnings
     }
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct DatabaseConnectionOptions {
-    // TODO: allow to connect using host-port-password?
     pub database: String,
-    pub socket_path: String,
+    /// exactly one of socket_path and host is given
+    #[serde(default)]
+    pub socket_path: Option<String>,
+    /// for connecting over TCP, e.g. to a database on another machine
+    #[serde(default)]
+    pub host: Option<String>,
+    #[serde(default = "default_database_port")]
+    pub port: u16,
     pub user: String,
+    /// at most one of password and password_file is given. the file's contents are the password,
+    /// without a trailing newline, so that it can be kept out of the config
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub password_file: Option<PathBuf>,
+    /// at startup, the database is tried this many times before giving up, as it may still be starting
+    #[serde(default = "default_startup_attempts")]
+    pub startup_attempts: u32,
+    #[serde(default = "default_startup_retry_interval")]
+    pub startup_retry_interval_s: u64,
+    /// apply pending schema migrations at startup. otherwise they are only listed, and can be
+    /// applied with --migrate-only
+    #[serde(default)]
+    pub auto_migrate: bool,
 }
 
+const fn default_database_port() -> u16 { 3306 }
+const fn default_startup_attempts() -> u32 { 10 }
+const fn default_startup_retry_interval() -> u64 { 3 }
+
 impl DatabaseConnectionOptions {
-    pub async fn mysql_opts(&self) -> mysql_async::Opts {
-        mysql_async::OptsBuilder::default()
-            .socket(Some(&self.socket_path))
+    /// Human-readable description of what we connect to
+    pub fn describe(&self) -> String {
+        match (&self.socket_path, &self.host) {
+            (Some(socket_path), _) => format!("{} via socket {} as {}", self.database, socket_path, self.user),
+            (None, Some(host)) => format!("{} at {}:{} as {}", self.database, host, self.port, self.user),
+            (None, None) => format!("{} as {}", self.database, self.user),
+        }
+    }
+
+    fn errors(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        match (&self.socket_path, &self.host) {
+            (Some(_), Some(_)) => errors.push("database_connection has both socket_path and host; give only one".to_string()),
+            (None, None) => errors.push("database_connection needs either socket_path or host".to_string()),
+            _ => {}
+        }
+        if self.password.is_some() && self.password_file.is_some() {
+            errors.push("database_connection has both password and password_file; give only one".to_string());
+        }
+        errors
+    }
+
+    /// Reads the password file, if there is one
+    pub async fn mysql_opts(&self) -> std::io::Result<mysql_async::Opts> {
+        let password = match self.password_file {
+            Some(ref path) => {
+                let contents = tokio::fs::read_to_string(path).await.map_err(|e| {
+                    error!(?e, path = %path.display(), "Could not read the database password file");
+                    e
+                })?;
+                Some(contents.trim_end_matches(['\r', '\n']).to_string())
+            }
+            None => self.password.clone(),
+        };
+
+        let builder = mysql_async::OptsBuilder::default()
             .user(Some(&self.user))
-            .db_name(Some(&self.database))
-            .into()
+            .pass(password)
+            .db_name(Some(&self.database));
+        let builder = match self.host {
+            Some(ref host) => builder.ip_or_hostname(host).tcp_port(self.port).prefer_socket(false),
+            None => builder.socket(self.socket_path.as_ref()),
+        };
+        Ok(builder.into())
     }
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct HTTPServerOptions {
     pub listen_addr: String,
+    /// include internal error details in HTTP error responses. only meant for development
+    #[serde(default)]
+    pub debug_errors: bool,
+    /// directory listings are streamed in chunks of about this many bytes, which bounds the memory
+    /// used per listing regardless of the number of entries
+    #[serde(default = "default_listing_chunk_bytes")]
+    pub listing_chunk_bytes: usize,
+    /// recursive listings go at most this many directories down, and list at most
+    /// recursive_listing_max_entries entries. requests can ask for less
+    #[serde(default = "default_recursive_listing_max_depth")]
+    pub recursive_listing_max_depth: u32,
+    #[serde(default = "default_recursive_listing_max_entries")]
+    pub recursive_listing_max_entries: u64,
+    /// requests for paths must carry a user's token as `Authorization: Bearer <token>`, see
+    /// users.http_token_sha256, and their paths are resolved from the user's home directory. the
+    /// admin token resolves them from the root. requests by UUID are refused for files outside the
+    /// user's home
+    #[serde(default)]
+    pub scope_to_home_directories: bool,
+    /// on SIGTERM or ctrl-c, how long in-flight HTTP requests and SFTP sessions get to finish
+    /// before the process exits anyway
+    #[serde(default = "default_shutdown_grace_s")]
+    pub shutdown_grace_s: u64,
 }
 
+const fn default_listing_chunk_bytes() -> usize { 64 * 1024 }
+const fn default_recursive_listing_max_depth() -> u32 { 32 }
+const fn default_recursive_listing_max_entries() -> u64 { 100_000 }
+const fn default_shutdown_grace_s() -> u64 { 30 }
+
+const fn default_open_handles_soft_limit() -> usize { 64 }
+const fn default_open_handles_hard_limit() -> usize { 256 }
+const fn default_handle_idle_timeout() -> u64 { 600 }
+const fn default_max_write_bytes() -> u64 { 128 * 1024 * 1024 }
+const fn default_auth_timeout() -> u64 { 30 }
+const fn default_init_timeout() -> u64 { 10 }
+const fn default_max_init_packet_bytes() -> u32 { 16 * 1024 }
+const fn default_max_client_extensions() -> usize { 64 }
+const fn default_session_idle_timeout() -> u64 { 600 }
+fn default_listing_group() -> String { "bnuy".to_string() }
+
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct SFTPServerOptions {
     pub listen_addr: String,
     pub public_key: String,
     pub private_key: String,
+
+    /// above this many open handles in one session, a warning is logged
+    #[serde(default = "default_open_handles_soft_limit")]
+    pub open_handles_soft_limit: usize,
+    /// at this many open handles in one session, further open/opendir calls fail
+    #[serde(default = "default_open_handles_hard_limit")]
+    pub open_handles_hard_limit: usize,
+    /// handles not used for this long are closed server-side
+    #[serde(default = "default_handle_idle_timeout")]
+    pub handle_idle_timeout_s: u64,
+    /// files opened for writing are kept in memory until their handle is closed, and can't grow
+    /// past this. existing files larger than this can only be replaced, by opening them with TRUNCATE
+    #[serde(default = "default_max_write_bytes")]
+    pub max_write_bytes: u64,
+
+    /// let users log in with a password, see users.password_hash, for clients which can't use keys
+    #[serde(default)]
+    pub allow_password_auth: bool,
+    /// connections which haven't authenticated this long after connecting are closed
+    #[serde(default = "default_auth_timeout")]
+    pub auth_timeout_s: u64,
+    /// sessions whose client hasn't sent the SFTP init packet this long after requesting the
+    /// subsystem are closed
+    #[serde(default = "default_init_timeout")]
+    pub init_timeout_s: u64,
+    /// sessions whose init packet is larger than this, or has more extensions than
+    /// max_client_extensions, are closed. real clients send a few hundred bytes at most
+    #[serde(default = "default_max_init_packet_bytes")]
+    pub max_init_packet_bytes: u32,
+    #[serde(default = "default_max_client_extensions")]
+    pub max_client_extensions: usize,
+    /// connections which send nothing for this long are closed
+    #[serde(default = "default_session_idle_timeout")]
+    pub session_idle_timeout_s: u64,
+
+    /// group shown in directory listings, as we don't track these. the owner shown is the user
+    /// who is logged in
+    #[serde(default = "default_listing_group")]
+    pub listing_group: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct UploadOptions {
+    /// After a file is written, ask the storage node for its size and digest and compare them to
+    /// what was sent before recording the file. Can be overridden per request with the X-Verify header
+    #[serde(default)]
+    pub verify: bool,
+    /// for the digests of new files. existing files keep the algorithm they were hashed with until
+    /// rehashed
+    #[serde(default)]
+    pub hash_algorithm: crate::hashing::HashAlgorithm,
+    /// nodes are not given files which would leave them with less free space than this
+    #[serde(default)]
+    pub min_free_bytes: u64,
+    /// uploads with the same size and digest as a stored file share its contents rather than being
+    /// written again
+    #[serde(default = "default_deduplicate")]
+    pub deduplicate: bool,
+}
+
+const fn default_deduplicate() -> bool { true }
+
+impl Default for UploadOptions {
+    fn default() -> Self {
+        UploadOptions {
+            verify: false,
+            hash_algorithm: Default::default(),
+            min_free_bytes: 0,
+            deduplicate: default_deduplicate(),
+        }
+    }
+}
+
+/// Cache headers for downloads. Files never change once stored under a UUID, so downloads by UUID
+/// can be cached forever, while downloads by path must be revalidated
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct DownloadCachingOptions {
+    /// without this, no Cache-Control headers are sent
+    #[serde(default = "default_caching_enabled")]
+    pub enabled: bool,
+    /// max-age for downloads by UUID
+    #[serde(default = "default_immutable_max_age_s")]
+    pub immutable_max_age_s: u64,
+    /// answer downloads by path with a 302 to the download by UUID, so caches key on the UUID
+    #[serde(default)]
+    pub redirect_paths_to_uuids: bool,
+}
+
+const fn default_caching_enabled() -> bool { true }
+const fn default_immutable_max_age_s() -> u64 { 365 * 24 * 60 * 60 }
+
+impl Default for DownloadCachingOptions {
+    fn default() -> Self {
+        DownloadCachingOptions {
+            enabled: default_caching_enabled(),
+            immutable_max_age_s: default_immutable_max_age_s(),
+            redirect_paths_to_uuids: false,
+        }
+    }
+}
+
+/// Retries of storage node operations, see failover.rs
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct FailoverOptions {
+    /// attempts per operation, including the first. each attempt goes to the next node in the
+    /// placement, if there is one
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: usize,
+    /// an attempt taking longer than this counts as failed
+    #[serde(default = "default_attempt_timeout_s")]
+    pub attempt_timeout_s: u64,
+}
+
+const fn default_max_attempts() -> usize { 3 }
+const fn default_attempt_timeout_s() -> u64 { 120 }
+
+impl Default for FailoverOptions {
+    fn default() -> Self {
+        FailoverOptions {
+            max_attempts: default_max_attempts(),
+            attempt_timeout_s: default_attempt_timeout_s(),
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct NodeMonitorOptions {
+    /// how often the nodes table is read to connect to new, re-enabled or lost nodes, and to drop
+    /// connections to disabled ones
+    #[serde(default = "default_poll_interval_s")]
+    pub poll_interval_s: u64,
+}
+
+const fn default_poll_interval_s() -> u64 { 30 }
+
+impl Default for NodeMonitorOptions {
+    fn default() -> Self {
+        NodeMonitorOptions {
+            poll_interval_s: default_poll_interval_s(),
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct PathCacheOptions {
+    /// how long a resolved path is reused for. changes made by other front nodes sharing the
+    /// database can go unnoticed this long. 0 turns the cache off
+    #[serde(default = "default_path_cache_ttl_ms")]
+    pub ttl_ms: u64,
+    /// paths cached at once, counted separately for directories and files
+    #[serde(default = "default_path_cache_max_entries")]
+    pub max_entries: usize,
+}
+
+const fn default_path_cache_ttl_ms() -> u64 { 2000 }
+const fn default_path_cache_max_entries() -> usize { 10000 }
+
+impl Default for PathCacheOptions {
+    fn default() -> Self {
+        PathCacheOptions {
+            ttl_ms: default_path_cache_ttl_ms(),
+            max_entries: default_path_cache_max_entries(),
+        }
+    }
+}
+
+/// Deleted files are kept in the trash for a while, see trash.rs
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct TrashOptions {
+    /// how long deleted files can be restored for. their contents are deleted after that
+    #[serde(default = "default_retention_s")]
+    pub retention_s: u64,
+    /// how often files past retention_s are looked for
+    #[serde(default = "default_purge_interval_s")]
+    pub purge_interval_s: u64,
+}
+
+const fn default_retention_s() -> u64 { 7 * 24 * 60 * 60 }
+const fn default_purge_interval_s() -> u64 { 3600 }
+
+/// Overwritten files are kept as versions of the new file, see versions.rs
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct VersioningOptions {
+    /// old versions kept per file. older ones are deleted when a new version is uploaded
+    #[serde(default = "default_keep_versions")]
+    pub keep_versions: u32,
+}
+
+const fn default_keep_versions() -> u32 { 10 }
+
+/// Files which aren't read for a while are moved to slower tiers, see tiering.rs
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct TieringOptions {
+    /// how long a file goes unread before it is moved to the next slower tier
+    #[serde(default = "default_cold_after_s")]
+    pub cold_after_s: u64,
+    /// how often files are looked through for ones to move
+    #[serde(default = "default_tiering_interval_s")]
+    pub interval_s: u64,
+    /// how fast files are moved, as bytes read from one node and written to another
+    #[serde(default = "default_tiering_bytes_per_s")]
+    pub bytes_per_s: u64,
+}
+
+const fn default_cold_after_s() -> u64 { 30 * 24 * 60 * 60 }
+const fn default_tiering_interval_s() -> u64 { 3600 }
+const fn default_tiering_bytes_per_s() -> u64 { 10 << 20 }
+
+/// Max number of concurrent requests. Requests over the limit are rejected with 429 instead of queued
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct ConcurrencyLimitOptions {
+    /// file downloads
+    #[serde(default = "default_reads_limit")]
+    pub reads: usize,
+    /// uploads and directory creation
+    #[serde(default = "default_writes_limit")]
+    pub writes: usize,
+    /// directory listings
+    #[serde(default = "default_listings_limit")]
+    pub listings: usize,
+    /// per authenticated user. each SFTP session counts once
+    #[serde(default = "default_per_user_limit")]
+    pub per_user: usize,
+}
+
+const fn default_reads_limit() -> usize { 256 }
+const fn default_writes_limit() -> usize { 32 }
+const fn default_listings_limit() -> usize { 32 }
+const fn default_per_user_limit() -> usize { 16 }
+
+impl Default for ConcurrencyLimitOptions {
+    fn default() -> Self {
+        ConcurrencyLimitOptions {
+            reads: default_reads_limit(),
+            writes: default_writes_limit(),
+            listings: default_listings_limit(),
+            per_user: default_per_user_limit(),
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct AccessLogOptions {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub format: AccessLogFormat,
+    /// when the log grows past this, it's moved to `<path>.1` and a new one is started.
+    /// without this, the log can be rotated externally and SIGUSR1 sent to reopen it
+    pub max_bytes: Option<u64>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    /// Common Log Format, with the duration in microseconds appended
+    #[default]
+    Common,
+    /// one JSON object per line
+    Json,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct ClusterOptions {
+    /// unique among the front nodes. embedded in session tokens, so must not change while sessions
+    /// are in progress
+    pub instance_id: String,
+    /// sent with forwarded requests. must be the same on all front nodes
+    pub shared_secret: String,
+    /// instance ID to HTTP address (ip:port) of the other front nodes
+    #[serde(default)]
+    pub peers: HashMap<String, String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct AdminOptions {
+    /// admin requests must have an `Authorization: Bearer <token>` header
+    pub token: String,
+    /// users created with POST /admin/users/<name> get the home directory `<home_parent>/<name>`.
+    /// a path from the root, created if it doesn't exist
+    #[serde(default = "default_home_parent")]
+    pub home_parent: String,
+}
+
+fn default_home_parent() -> String { "home".to_string() }
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct BackupOptions {
+    pub sink: BackupSinkOptions,
+    /// whether this front node may push queued files to the sink. when several front nodes share a
+    /// database, only the leader among the ones with this on pushes. files are queued either way
+    #[serde(default = "default_run_worker")]
+    pub run_worker: bool,
+    /// how many backed up files are read back and compared every verify_interval_s. 0 turns
+    /// verification off
+    #[serde(default)]
+    pub verify_sample_size: u32,
+    #[serde(default = "default_verify_interval_s")]
+    pub verify_interval_s: u64,
+}
+
+const fn default_run_worker() -> bool { true }
+const fn default_verify_interval_s() -> u64 { 3600 }
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum BackupSinkOptions {
+    /// one file per UUID in a local directory, e.g. a mounted network share
+    Directory { path: PathBuf },
+    /// one object per UUID in a bucket of an S3-compatible object store
+    S3(S3SinkOptions),
 }
 
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct S3SinkOptions {
+    /// e.g. "https://s3.eu-north-1.amazonaws.com" or "http://minio:9000", without the bucket
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    /// put in front of each object's UUID, e.g. "bnuystore/"
+    #[serde(default)]
+    pub prefix: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    /// the CAs an https endpoint's certificate is checked against, a PEM file
+    #[serde(default = "default_s3_tls_ca_file")]
+    pub tls_ca_file: PathBuf,
+}
+
+fn default_s3_tls_ca_file() -> PathBuf { "/etc/ssl/certs/ca-certificates.crt".into() }
+
 const fn default_timeout() -> u64 { 1 }
+const fn default_request_timeout() -> u64 { 30 }
+const fn default_max_in_flight() -> usize { 16 }
+const fn default_connections() -> usize { 2 }
+const fn default_heartbeat_interval_s() -> u64 { 10 }
+const fn default_heartbeat_max_missed() -> u32 { 3 }
+const fn default_max_header_bytes() -> u32 { message::DEFAULT_MAX_HEADER_BYTES }
+const fn default_max_data_bytes() -> u64 { message::DEFAULT_MAX_DATA_BYTES }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct StorageNodeConfig {
     pub addr: String,
+    /// for connecting and the initial hello
     #[serde(default = "default_timeout")]
     pub timeout_s: u64,
+    /// for each request once connected. longer than timeout_s, as requests carry whole files
+    #[serde(default = "default_request_timeout")]
+    pub request_timeout_s: u64,
+    /// requests sent and not yet answered at once, on each of the connections. further requests wait
+    /// for one to be answered
+    #[serde(default = "default_max_in_flight")]
+    pub max_in_flight: usize,
+    /// TCP connections to the node. requests go to the least busy one, so that a large file being
+    /// sent doesn't hold up everything else
+    #[serde(default = "default_connections")]
+    pub connections: usize,
+    /// responses from the node with a longer header or more data are skipped, and the request
+    /// fails. files larger than max_data_bytes can't be read
+    #[serde(default = "default_max_header_bytes")]
+    pub max_header_bytes: u32,
+    #[serde(default = "default_max_data_bytes")]
+    pub max_data_bytes: u64,
+    /// connections which were idle this long are pinged, and lost after heartbeat_max_missed pings
+    /// in a row go unanswered. finds nodes which went away without closing the connection. 0 to
+    /// never ping
+    #[serde(default = "default_heartbeat_interval_s")]
+    pub heartbeat_interval_s: u64,
+    #[serde(default = "default_heartbeat_max_missed")]
+    pub heartbeat_max_missed: u32,
+    /// Names this node previously had in the config. If the nodes table has a row with one of these names,
+    /// it is renamed instead of a new row being created, so the files stored on the node are kept
+    #[serde(default)]
+    pub previous_names: Vec<String>,
+    /// Lower tiers are faster. New files are placed on the lowest tier which has space
+    #[serde(default)]
+    pub tier: u32,
+    /// compress file data sent to and from the node, if it supports it
+    #[serde(default)]
+    pub compression: Option<CompressionOptions>,
+    /// the token the node was given with --auth-token-file, sent before anything else. at most one
+    /// of auth_token and auth_token_file is given, like the database password
+    #[serde(default)]
+    pub auth_token: Option<String>,
+    #[serde(default)]
+    pub auth_token_file: Option<PathBuf>,
+    /// connect over TLS, for nodes started with --tls-cert. The node's certificate has to be signed by
+    /// a CA in tls_ca_file, and be for tls_server_name, or the host in addr if that's left out
+    #[serde(default)]
+    pub tls: bool,
+    #[serde(default)]
+    pub tls_ca_file: Option<PathBuf>,
+    #[serde(default)]
+    pub tls_server_name: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct CompressionOptions {
+    /// zstd level. higher levels compress more but take longer, and negative levels are faster still
+    #[serde(default = "default_compression_level")]
+    pub level: i32,
+    /// files and ranges shorter than this are sent as they are
+    #[serde(default = "default_compression_min_bytes")]
+    pub min_bytes: usize,
 }
 
+const fn default_compression_level() -> i32 { 3 }
+const fn default_compression_min_bytes() -> usize { 4096 }
+
+impl StorageNodeConfig {
+    pub fn message_limits(&self) -> MessageLimits {
+        MessageLimits { max_header_bytes: self.max_header_bytes, max_data_bytes: self.max_data_bytes }
+    }
+
+    /// Reads the auth token file, if there is one
+    pub async fn auth_token(&self) -> std::io::Result<Option<AuthToken>> {
+        let token = match self.auth_token_file {
+            Some(ref path) => {
+                let contents = tokio::fs::read_to_string(path).await.map_err(|e| {
+                    error!(?e, path = %path.display(), "Could not read the storage node's auth token file");
+                    e
+                })?;
+                Some(contents.trim_end_matches(['\r', '\n']).to_string())
+            }
+            None => self.auth_token.clone(),
+        };
+        Ok(token.map(AuthToken))
+    }
+
+    /// addr split into a host and port. The host is a hostname, an IPv4 address, or an IPv6 address
+    /// in brackets, e.g. `[::1]:8080`. Hostnames are resolved when connecting
+    pub fn host_and_port(&self) -> Result<(&str, u16), String> {
+        let invalid = |why: &str| format!("invalid addr {:?}: {why}", self.addr);
+
+        let (host, port) = match self.addr.strip_prefix('[') {
+            Some(bracketed) => {
+                let (ip, port) = bracketed.split_once(']').ok_or_else(|| invalid("missing ]"))?;
+                if ip.parse::<std::net::Ipv6Addr>().is_err() {
+                    return Err(invalid("not an IPv6 address in the brackets"));
+                }
+                let port = port.strip_prefix(':').ok_or_else(|| invalid("no port after the brackets"))?;
+                (ip, port)
+            }
+            None => {
+                let (host, port) = self.addr.rsplit_once(':').ok_or_else(|| invalid("no port, expected HOST:PORT"))?;
+                if host.contains(':') {
+                    return Err(invalid("IPv6 addresses need brackets, like [::1]:PORT"));
+                }
+                if host.is_empty() {
+                    return Err(invalid("no host"));
+                }
+                (host, port)
+            }
+        };
+        match port.parse::<u16>() {
+            Ok(port) if port != 0 => Ok((host, port)),
+            _ => Err(invalid("port must be a number from 1 to 65535")),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn storage_node(addr: &str) -> StorageNodeConfig {
+        toml::from_str(&format!("addr = {addr:?}")).unwrap()
+    }
+
+    #[test]
+    fn storage_node_addrs() {
+        let valid = [
+            ("192.0.2.1:2700", ("192.0.2.1", 2700)),
+            ("[::1]:2700", ("::1", 2700)),
+            ("[2001:db8::7]:65535", ("2001:db8::7", 65535)),
+            ("localhost:1", ("localhost", 1)),
+            ("storage-3.bnuy.example:2700", ("storage-3.bnuy.example", 2700)),
+        ];
+        for (addr, expected) in valid {
+            assert_eq!(storage_node(addr).host_and_port(), Ok(expected), "{addr}");
+        }
+
+        let invalid = [
+            ("192.0.2.1", "no port"),
+            ("::1:2700", "need brackets"),
+            ("[::1]", "no port after the brackets"),
+            ("[::1:2700", "missing ]"),
+            ("[192.0.2.1]:2700", "not an IPv6 address"),
+            (":2700", "no host"),
+            ("localhost:0", "port must be"),
+            ("localhost:65536", "port must be"),
+            ("localhost:http", "port must be"),
+        ];
+        for (addr, why) in invalid {
+            let e = storage_node(addr).host_and_port().unwrap_err();
+            assert!(e.contains(why), "{addr}: {e}");
+        }
+    }
+}
@@ -0,0 +1,90 @@
+//! Content types of downloads: the one given when the file was uploaded, or else one guessed from
+//! the file's extension.
+
+/// Sent for files whose type is neither stored nor guessable
+pub const FALLBACK: &str = "application/octet-stream";
+
+/// Longest content type which is stored, the size of files.content_type
+const MAX_STORED_LEN: usize = 255;
+
+/// Types which say how a request body was sent rather than what the file is. curl sends the first
+/// by default with --data-binary
+const FORM_TYPES: &[&str] = &["application/x-www-form-urlencoded", "multipart/form-data"];
+
+const BY_EXTENSION: &[(&str, &str)] = &[
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("css", "text/css"),
+    ("js", "text/javascript"),
+    ("mjs", "text/javascript"),
+    ("json", "application/json"),
+    ("xml", "application/xml"),
+    ("txt", "text/plain"),
+    ("md", "text/markdown"),
+    ("csv", "text/csv"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("svg", "image/svg+xml"),
+    ("ico", "image/x-icon"),
+    ("bmp", "image/bmp"),
+    ("tif", "image/tiff"),
+    ("tiff", "image/tiff"),
+    ("mp4", "video/mp4"),
+    ("m4v", "video/mp4"),
+    ("webm", "video/webm"),
+    ("mkv", "video/x-matroska"),
+    ("mov", "video/quicktime"),
+    ("avi", "video/x-msvideo"),
+    ("mp3", "audio/mpeg"),
+    ("m4a", "audio/mp4"),
+    ("ogg", "audio/ogg"),
+    ("opus", "audio/opus"),
+    ("flac", "audio/flac"),
+    ("wav", "audio/wav"),
+    ("pdf", "application/pdf"),
+    ("epub", "application/epub+zip"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("tar", "application/x-tar"),
+    ("xz", "application/x-xz"),
+    ("zst", "application/zstd"),
+    ("7z", "application/x-7z-compressed"),
+    ("wasm", "application/wasm"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+];
+
+/// Guessed from the extension of `name`, ignoring case
+pub fn guess(name: &str) -> &'static str {
+    let Some((_, extension)) = name.rsplit_once('.') else {
+        return FALLBACK;
+    };
+    BY_EXTENSION.iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(extension))
+        .map_or(FALLBACK, |(_, content_type)| content_type)
+}
+
+/// What to store for the Content-Type of an upload. None if it isn't a `type/subtype`, optionally
+/// with parameters, or is one of the form types
+pub fn from_upload(header: &str) -> Option<String> {
+    let header = header.trim();
+    if header.len() > MAX_STORED_LEN || !header.bytes().all(|b| b.is_ascii_graphic() || b == b' ') {
+        return None;
+    }
+    let essence = header.split(';').next().unwrap_or_default().trim();
+    let (kind, subtype) = essence.split_once('/')?;
+    let is_token = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$&-^_.+".contains(&b));
+    if !is_token(kind) || !is_token(subtype) {
+        return None;
+    }
+    if FORM_TYPES.iter().any(|form_type| essence.eq_ignore_ascii_case(form_type)) {
+        return None;
+    }
+    Some(header.to_string())
+}
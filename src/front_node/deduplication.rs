@@ -0,0 +1,62 @@
+//! Uploads with the same contents as an existing file share its contents on the storage node, if
+//! uploads.deduplicate is set.
+//!
+//! Files are matched by size and digest, so only files hashed with the same algorithm are found. A
+//! file sharing contents gets its own row and UUID as usual, with files.blob_uuid set to the UUID
+//! the contents are stored under, on the node of the file it matched. Nothing counts references:
+//! the contents stored under a UUID are in use as long as a row has it as its uuid or blob_uuid,
+//! and are deleted from the node once the last such row is.
+
+#[allow(unused)]
+use tracing::{trace, debug, info, warn, error, instrument};
+
+use mysql_async::prelude::*;
+use uuid::Uuid;
+
+use super::FrontNode;
+use super::tys::{StorageNodeID, Error};
+use crate::hashing::ContentDigest;
+
+/// Locks a row using the contents stored under `blob`, so that they can't be deleted until the
+/// transaction ends. False if no row uses them anymore. The read sees rows committed since the
+/// transaction started, and waits for any which are being written
+pub(super) async fn lock_contents(transaction: &mut mysql_async::Transaction<'_>, blob: Uuid) -> Result<bool, Error> {
+    let query = "SELECT uuid FROM files WHERE uuid = :blob OR blob_uuid = :blob LIMIT 1 FOR UPDATE;";
+    let row: Option<Uuid> = query.with(params! { "blob" => blob }).first(&mut *transaction).await?;
+    Ok(row.is_some())
+}
+
+impl FrontNode {
+    /// Existing contents with the given size and digest, as the UUID they are stored under and
+    /// their node. Only nodes which are connected are considered, as the contents are read from them
+    pub(super) async fn find_contents(&self, digest: &ContentDigest, size: u64) -> Result<Option<(Uuid, StorageNodeID)>, Error> {
+        let query = r#"
+            SELECT COALESCE(blob_uuid, uuid), stored_on_node_id FROM files
+                WHERE digest = :digest AND hash_algorithm = :hash_algorithm AND size = :size;
+        "#;
+        let candidates: Vec<(Uuid, StorageNodeID)> = query.with(params! {
+            "digest" => &digest.bytes,
+            "hash_algorithm" => digest.algorithm.name(),
+            "size" => size,
+        }).fetch(self.pool()?).await?;
+
+        let connections = self.active_connections.read().await;
+        let found = candidates.into_iter().find(|(_, node)| connections.contains_key(node));
+        trace!(?found, "Looked for existing contents");
+        Ok(found)
+    }
+
+    /// Deletes the contents stored under `blob` from the node, unless a file still uses them. For
+    /// after a file was deleted or replaced; failures only leave an orphan, which scrubbing finds
+    pub(super) async fn delete_contents_if_unused(&self, node: StorageNodeID, blob: Uuid) -> Result<(), Error> {
+        let mut transaction = self.pool()?.start_transaction(mysql_async::TxOpts::default()).await?;
+        if lock_contents(&mut transaction, blob).await? {
+            debug!(%blob, "Contents still in use by another file; keeping them");
+            return Ok(());
+        }
+        // the transaction is kept open, so a file can't start using them while they're deleted
+        self.delete_contents(node, blob).await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,217 @@
+//! `--dev-mode`: a front node with a storage node in the same process, for trying bnuystore out.
+//!
+//! Only the storage node is embedded. Metadata is still kept in MariaDB, as every query is written
+//! for it, and an embedded metadata backend is out of scope. Without the database, startup fails
+//! at once, with a hint saying where it was looked for.
+
+#[allow(unused)]
+use tracing::{trace, debug, info, warn, error, instrument};
+
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "sftp")]
+use ssh_key::{private::PrivateKey, Algorithm, LineEnding};
+
+use rand::{distributions::Alphanumeric, Rng};
+
+use super::config::{AdminOptions, Config};
+use super::startup_summary::{StartupSummary, StorageNodeOutcome};
+use super::storage_node_connection::StorageNodeConnection;
+use super::FrontNode;
+use crate::storage_node::{self, Node};
+use crate::message::MessageLimits;
+
+const EMBEDDED_NODE_NAME: &str = "dev-embedded";
+
+/// Length of the generated admin token
+const ADMIN_TOKEN_LENGTH: usize = 32;
+
+/// How much data can be in flight between the front node and the embedded storage node
+const DUPLEX_BUFFER_SIZE: usize = 1 << 20;
+
+/// Config used in dev mode when no config file is given. Everything listens on localhost. The
+/// metadata is still kept in MariaDB, by default the local server's `bnuybase` database
+pub fn default_config() -> Config {
+    let user = std::env::var("USER").unwrap_or("root".to_string());
+    #[allow(unused_mut)]
+    let mut contents = format!(r#"
+        [database_connection]
+        database = "bnuybase"
+        socket_path = "/run/mysqld/mysqld.sock"
+        user = "{user}"
+        auto_migrate = true
+        # a local server which isn't there won't turn up by waiting
+        startup_attempts = 1
+
+        [http_server]
+        listen_addr = "127.0.0.1:8080"
+        debug_errors = true
+
+        [storage_nodes]
+    "#);
+    #[cfg(feature = "sftp")]
+    contents.push_str(r#"
+        [sftp_server]
+        listen_addr = "127.0.0.1:2222"
+        # generated by prepare
+        private_key = ""
+        public_key = ""
+    "#);
+    toml::from_str(&contents).expect("dev mode config is malformed")
+}
+
+/// Creates the data directory, generates SFTP host keys in it (if SFTP is served and they don't exist yet), enables the
+/// admin routes with a generated token (unless a token is configured), and replaces the configured storage nodes with
+/// the embedded one. Returns the data directory. Exits if any of this fails
+pub async fn prepare(cfg: &mut Config, data_dir: Option<PathBuf>) -> PathBuf {
+    let data_dir = data_dir.unwrap_or_else(|| std::env::temp_dir().join(format!("bnuystore-dev-{}", std::process::id())));
+    if let Err(e) = tokio::fs::create_dir_all(&data_dir).await {
+        error!(?e, data_dir = %data_dir.display(), "Could not create dev mode data directory");
+        std::process::exit(1);
+    }
+    info!(data_dir = %data_dir.display(), "Running in dev mode");
+
+    #[cfg(feature = "sftp")]
+    if let Some(ref mut sftp_cfg) = cfg.sftp_server {
+        let private_key = data_dir.join("sftp_ed25519");
+        let public_key = data_dir.join("sftp_ed25519.pub");
+        if !private_key.exists() {
+            if let Err(e) = generate_host_key(&private_key, &public_key) {
+                error!(?e, "Could not generate SFTP host key");
+                std::process::exit(1);
+            }
+        }
+        sftp_cfg.private_key = private_key.display().to_string();
+        sftp_cfg.public_key = public_key.display().to_string();
+    }
+
+    if cfg.admin.is_none() {
+        match admin_token(&data_dir.join("admin_token")).await {
+            Ok(token) => cfg.admin = Some(AdminOptions { token, home_parent: "home".to_string() }),
+            Err(e) => {
+                error!(?e, "Could not generate admin token");
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if !cfg.storage_nodes.is_empty() {
+        warn!("Ignoring configured storage nodes in dev mode");
+        cfg.storage_nodes.clear();
+    }
+
+    data_dir
+}
+
+// the token in `path`, or a new one saved there. kept across restarts like the host key, so scripts
+// using it keep working
+async fn admin_token(path: &Path) -> std::io::Result<String> {
+    match tokio::fs::read_to_string(path).await {
+        Ok(token) => return Ok(token.trim().to_string()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    debug!(path = %path.display(), "Generating admin token");
+    let token: String = rand::rngs::OsRng.sample_iter(&Alphanumeric).take(ADMIN_TOKEN_LENGTH).map(char::from).collect();
+    tokio::fs::write(path, &token).await?;
+    Ok(token)
+}
+
+#[cfg(feature = "sftp")]
+fn generate_host_key(private_path: &Path, public_path: &Path) -> Result<(), ssh_key::Error> {
+    debug!(private_path = %private_path.display(), "Generating SFTP host key");
+    let key = PrivateKey::random(&mut rand::rngs::OsRng, Algorithm::Ed25519)?;
+    key.write_openssh_file(private_path, LineEnding::LF)?;
+    key.public_key().write_openssh_file(public_path)?;
+    Ok(())
+}
+
+/// Starts a storage node in this process, storing its files in the data directory,
+/// and connects the front node to it over an in-memory stream
+#[instrument(skip(front_node, summary))]
+pub async fn start_embedded_node(front_node: &FrontNode, data_dir: &Path, summary: &mut StartupSummary) {
+    let connected = match Node::new(data_dir.join("files"), storage_node::DEFAULT_LOCK_WARN_AFTER, None).await {
+        Ok(node) => {
+            let (front_end, node_end) = tokio::io::duplex(DUPLEX_BUFFER_SIZE);
+            tokio::spawn(storage_node::serve_connection(node, node_end, MessageLimits::default()));
+
+            let conn = StorageNodeConnection::from_stream(front_end);
+            match front_node.attach_connection(EMBEDDED_NODE_NAME, conn).await {
+                Ok(id) => {
+                    info!(?id, "Embedded storage node started");
+                    Ok(())
+                }
+                Err(e) => {
+                    error!(?e, "Could not register embedded storage node");
+                    Err(format!("{e:?}"))
+                }
+            }
+        }
+        Err(e) => {
+            error!(?e, "Could not start embedded storage node");
+            Err(format!("{e:?}"))
+        }
+    };
+
+    summary.storage_nodes.push(StorageNodeOutcome {
+        name: EMBEDDED_NODE_NAME.to_string(),
+        addr: "in-process".to_string(),
+        connected,
+    });
+    summary.features.push("dev mode".to_string());
+}
+
+pub fn print_urls(cfg: &Config) {
+    println!();
+    println!("bnuystore is running in dev mode 🐇");
+    println!("  HTTP: http://{}/list-directory/", cfg.http_server.listen_addr);
+    if let Some((host, port)) = cfg.sftp_server.as_ref().and_then(|sftp_cfg| sftp_cfg.listen_addr.rsplit_once(':')) {
+        println!("  SFTP: sftp -P {port} {host}");
+    }
+    if let Some(ref admin) = cfg.admin {
+        println!("  Admin token: {}", admin.token);
+        println!("    e.g. curl -H 'Authorization: Bearer {}' http://{}/admin/users", admin.token, cfg.http_server.listen_addr);
+    }
+    println!();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn prepare_sets_up_the_data_directory() {
+        let data_dir = std::env::temp_dir().join(format!("bnuystore-test-{}", uuid::Uuid::now_v7()));
+        let mut cfg = default_config();
+        cfg.storage_nodes = toml::from_str(r#"elsewhere = { addr = "192.0.2.1:2700" }"#).unwrap();
+
+        assert_eq!(prepare(&mut cfg, Some(data_dir.clone())).await, data_dir);
+        assert!(cfg.storage_nodes.is_empty());
+        assert!(cfg.errors().is_empty(), "{:?}", cfg.errors());
+        let token = cfg.admin.as_ref().unwrap().token.clone();
+        assert_eq!(token.len(), ADMIN_TOKEN_LENGTH);
+        assert!(token.chars().all(|c| c.is_ascii_alphanumeric()), "{token}");
+
+        // kept across restarts, and a configured token is left alone
+        let mut again = default_config();
+        prepare(&mut again, Some(data_dir.clone())).await;
+        assert_eq!(again.admin.unwrap().token, token);
+        let mut configured = default_config();
+        configured.admin = Some(AdminOptions { token: "configured-admin-token".to_string(), home_parent: "home".to_string() });
+        prepare(&mut configured, Some(data_dir.clone())).await;
+        assert_eq!(configured.admin.unwrap().token, "configured-admin-token");
+        #[cfg(feature = "sftp")]
+        {
+            let sftp_cfg = cfg.sftp_server.as_ref().unwrap();
+            let private_key = std::fs::read_to_string(&sftp_cfg.private_key).unwrap();
+            let key = PrivateKey::from_openssh(&private_key).unwrap();
+            assert_eq!(key.algorithm(), Algorithm::Ed25519);
+
+            // kept across restarts, so clients don't see the host key change
+            let mut again = default_config();
+            prepare(&mut again, Some(data_dir.clone())).await;
+            assert_eq!(std::fs::read_to_string(&again.sftp_server.unwrap().private_key).unwrap(), private_key);
+        }
+        std::fs::remove_dir_all(data_dir).unwrap();
+    }
+}
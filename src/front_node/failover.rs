@@ -0,0 +1,272 @@
+//! Retrying storage node operations, on other nodes where there are any.
+//!
+//! Operations get a placement, the nodes which could serve them in order of preference. Nodes
+//! which recently failed are moved to the back, and each attempt goes to the next node, wrapping
+//! around when there are fewer nodes than attempts. Errors which would fail the same way on any
+//! node end the operation right away.
+
+#[allow(unused)]
+use tracing::{trace, debug, info, warn, error, instrument};
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::time::Duration;
+
+use super::config::FailoverOptions;
+use super::storage_node_connection::ConnectionError;
+use super::tys::{StorageNodeID, Error};
+use crate::message::ErrorCode;
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: usize,
+    pub attempt_timeout: Duration,
+}
+
+impl RetryPolicy {
+    pub fn from_config(cfg: &FailoverOptions) -> Self {
+        RetryPolicy {
+            max_attempts: cfg.max_attempts.max(1),
+            attempt_timeout: Duration::from_secs(cfg.attempt_timeout_s),
+        }
+    }
+}
+
+/// Whether an error could go away by trying again, possibly on another node. Errors about the
+/// request itself or the metadata database would fail the same way everywhere
+pub fn is_retryable(e: &Error) -> bool {
+    matches!(
+        e,
+        Error::Connection(_)
+            | Error::NotConnectedToNode
+            | Error::AttemptTimedOut
+            | Error::UnexpectedResponse(_)
+            | Error::ShortWrite { .. }
+            | Error::VerificationFailed { .. }
+            | Error::DigestMismatch { .. }
+    ) || matches!(e, Error::StorageNode { code, .. } if *code != ErrorCode::BadRequest)
+}
+
+#[derive(Debug)]
+pub struct FailedAttempt {
+    pub node: StorageNodeID,
+    pub error: Error,
+}
+
+impl FailedAttempt {
+    /// Whether the node didn't answer in time, as opposed to failing
+    pub fn timed_out(&self) -> bool {
+        matches!(self.error, Error::AttemptTimedOut | Error::Connection(ConnectionError::Timeout))
+    }
+}
+
+impl std::fmt::Display for FailedAttempt {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "node {}: ", self.node.0)?;
+        match self.error {
+            Error::NotConnectedToNode => write!(f, "not connected"),
+            Error::AttemptTimedOut | Error::Connection(ConnectionError::Timeout) => write!(f, "timed out"),
+            Error::Connection(ConnectionError::ClientDisconnected) => write!(f, "connection lost"),
+            Error::ShortWrite { expected, written, .. } => write!(f, "short write ({written} of {expected} bytes)"),
+            Error::VerificationFailed { .. } | Error::DigestMismatch { .. } => write!(f, "verification failed"),
+            Error::UnexpectedResponse(ref response) => write!(f, "unexpected response {response}"),
+            Error::StorageNode { code, ref detail } => write!(f, "{code:?} error: {detail}"),
+            ref e => write!(f, "{e:?}"),
+        }
+    }
+}
+
+/// Outcomes of attempts on each node since the front node started
+#[derive(Debug, Default, Clone, Copy, serde::Serialize)]
+pub struct NodeStatus {
+    pub successes: u64,
+    pub failures: u64,
+    /// reset by any success. nodes with more of these are tried later
+    pub consecutive_failures: u32,
+}
+
+#[derive(Debug, Default)]
+pub struct NodeHealth(std::sync::Mutex<HashMap<StorageNodeID, NodeStatus>>);
+
+impl NodeHealth {
+    pub fn record(&self, node: StorageNodeID, succeeded: bool) {
+        let mut statuses = self.0.lock().unwrap();
+        let status = statuses.entry(node).or_default();
+        if succeeded {
+            status.successes += 1;
+            status.consecutive_failures = 0;
+        } else {
+            status.failures += 1;
+            status.consecutive_failures += 1;
+        }
+    }
+
+    /// Moves failing nodes to the back. The sort is stable, so the placement's own order decides
+    /// between equally healthy nodes
+    pub fn order(&self, placement: &mut [StorageNodeID]) {
+        let statuses = self.0.lock().unwrap();
+        placement.sort_by_key(|node| statuses.get(node).map_or(0, |status| status.consecutive_failures));
+    }
+
+    #[allow(unused)]
+    pub fn statuses(&self) -> HashMap<StorageNodeID, NodeStatus> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+/// Runs `attempt` on nodes from the placement until it succeeds, returning the result and the node
+/// which served it. If every attempt fails, the error lists all of them
+pub async fn with_failover<T, F, Fut>(
+    policy: &RetryPolicy,
+    health: &NodeHealth,
+    mut placement: Vec<StorageNodeID>,
+    mut attempt: F,
+) -> Result<(T, StorageNodeID), Error>
+where
+    F: FnMut(StorageNodeID) -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    if placement.is_empty() {
+        return Err(Error::NotConnectedToAnyNode);
+    }
+    health.order(&mut placement);
+
+    let mut failed = Vec::new();
+    for &node in placement.iter().cycle().take(policy.max_attempts) {
+        let result = match tokio::time::timeout(policy.attempt_timeout, attempt(node)).await {
+            Ok(result) => result,
+            Err(_) => Err(Error::AttemptTimedOut),
+        };
+        match result {
+            Ok(x) => {
+                health.record(node, true);
+                if !failed.is_empty() {
+                    info!(?node, failed_attempts = failed.len(), "Succeeded after failing over");
+                }
+                return Ok((x, node));
+            }
+            Err(e) if is_retryable(&e) => {
+                health.record(node, false);
+                let failure = FailedAttempt { node, error: e };
+                warn!(%failure, "Attempt failed");
+                failed.push(failure);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Err(Error::AllAttemptsFailed(failed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+
+    const POLICY: RetryPolicy = RetryPolicy { max_attempts: 4, attempt_timeout: Duration::from_millis(200) };
+
+    enum Outcome {
+        Succeed,
+        Fail(Error),
+        Hang,
+    }
+
+    /// Runs with_failover where each attempt takes the next scripted outcome, returning the result
+    /// and which nodes were tried
+    async fn run(
+        health: &NodeHealth,
+        placement: &[i64],
+        outcomes: impl IntoIterator<Item = Outcome>,
+    ) -> (Result<((), StorageNodeID), Error>, Vec<i64>) {
+        let outcomes = Mutex::new(outcomes.into_iter().collect::<VecDeque<_>>());
+        let tried = Mutex::new(Vec::new());
+        let placement = placement.iter().map(|&id| StorageNodeID(id)).collect();
+        let result = with_failover(&POLICY, health, placement, |node| {
+            tried.lock().unwrap().push(node.0);
+            let outcome = outcomes.lock().unwrap().pop_front().expect("more attempts than scripted");
+            async move {
+                match outcome {
+                    Outcome::Succeed => Ok(()),
+                    Outcome::Fail(e) => Err(e),
+                    Outcome::Hang => std::future::pending().await,
+                }
+            }
+        }).await;
+        (result, tried.into_inner().unwrap())
+    }
+
+    fn storage_error(code: ErrorCode) -> Error {
+        Error::StorageNode { code, detail: "scripted".to_string() }
+    }
+
+    fn rendered(result: Result<((), StorageNodeID), Error>) -> Vec<String> {
+        match result {
+            Err(Error::AllAttemptsFailed(attempts)) => attempts.iter().map(ToString::to_string).collect(),
+            other => panic!("expected every attempt to fail, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn fails_over_to_the_next_node() {
+        let health = NodeHealth::default();
+        let (result, tried) = run(&health, &[1, 2, 3], [
+            Outcome::Fail(Error::NotConnectedToNode),
+            Outcome::Fail(Error::Connection(ConnectionError::ClientDisconnected)),
+            Outcome::Succeed,
+        ]).await;
+        assert_eq!(result.unwrap().1, StorageNodeID(3));
+        assert_eq!(tried, [1, 2, 3]);
+
+        let statuses = health.statuses();
+        assert_eq!(statuses[&StorageNodeID(1)].consecutive_failures, 1);
+        assert_eq!(statuses[&StorageNodeID(3)].successes, 1);
+
+        // the failing nodes are now tried last, in their original order
+        let (result, tried) = run(&health, &[1, 2, 3], [Outcome::Fail(Error::NotConnectedToNode), Outcome::Succeed]).await;
+        assert_eq!(result.unwrap().1, StorageNodeID(1));
+        assert_eq!(tried, [3, 1]);
+        assert_eq!(health.statuses()[&StorageNodeID(1)].consecutive_failures, 0);
+    }
+
+    #[tokio::test]
+    async fn attempts_wrap_around_and_are_all_reported() {
+        let health = NodeHealth::default();
+        let (result, tried) = run(&health, &[1, 2], [
+            Outcome::Fail(Error::NotConnectedToNode),
+            Outcome::Fail(storage_error(ErrorCode::NoSpace)),
+            Outcome::Hang,
+            Outcome::Fail(Error::ShortWrite { uuid: uuid::Uuid::nil(), expected: 10, written: 4 }),
+        ]).await;
+        assert_eq!(tried, [1, 2, 1, 2]);
+        assert_eq!(rendered(result), [
+            "node 1: not connected",
+            "node 2: NoSpace error: scripted",
+            "node 1: timed out",
+            "node 2: short write (4 of 10 bytes)",
+        ]);
+        assert_eq!(health.statuses()[&StorageNodeID(2)].failures, 2);
+    }
+
+    #[tokio::test]
+    async fn requests_which_fail_everywhere_are_not_retried() {
+        let health = NodeHealth::default();
+        let (result, tried) = run(&health, &[1, 2], [Outcome::Fail(storage_error(ErrorCode::BadRequest))]).await;
+        assert!(matches!(result, Err(Error::StorageNode { code: ErrorCode::BadRequest, .. })), "{result:?}");
+        assert_eq!(tried, [1]);
+        // not the node's fault
+        assert!(health.statuses().is_empty());
+
+        let (result, tried) = run(&health, &[1, 2], [Outcome::Fail(Error::UnknownUUID)]).await;
+        assert!(matches!(result, Err(Error::UnknownUUID)), "{result:?}");
+        assert_eq!(tried, [1]);
+    }
+
+    #[tokio::test]
+    async fn no_nodes_means_no_attempts() {
+        let (result, tried) = run(&NodeHealth::default(), &[], []).await;
+        assert!(matches!(result, Err(Error::NotConnectedToAnyNode)), "{result:?}");
+        assert!(tried.is_empty());
+    }
+}
@@ -0,0 +1,119 @@
+//! Forwarding of requests for node-local sessions to the front node owning them.
+//!
+//! Some state, like upload progress, only lives in the memory of one front node. Session tokens
+//! are `<instance_id>:<uuid>`, so any front node can tell which one owns a session, and proxy
+//! requests for it there over HTTP.
+
+#[allow(unused)]
+use tracing::{trace, debug, info, warn, error, instrument};
+
+use std::time::Duration;
+
+use axum::body::Body;
+use http::{HeaderValue, Request, Response};
+use hyper_util::rt::TokioIo;
+use uuid::Uuid;
+
+use super::config::ClusterOptions;
+
+/// Set on forwarded requests to the instance ID of the forwarding node. Forwarded requests are never
+/// forwarded again, which prevents loops if the peers disagree about who owns a session
+pub const FORWARDED_BY_HEADER: &str = "X-Bnuy-Forwarded-By";
+/// The cluster's shared secret. Forwarded requests without it are refused
+pub const SECRET_HEADER: &str = "X-Bnuy-Cluster-Secret";
+/// Sent with every response, so clients and operators can tell which front node answered
+pub const INSTANCE_HEADER: &str = "X-Bnuy-Instance";
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Compares in constant time, so a secret can't be guessed byte by byte
+pub fn secrets_match(expected: &[u8], actual: &[u8]) -> bool {
+    expected.len() == actual.len() && expected.iter().zip(actual).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[derive(Debug)]
+#[allow(unused)]
+pub enum ForwardError {
+    /// the owner isn't in cluster.peers
+    UnknownPeer,
+    IO(std::io::Error),
+    Http(hyper::Error),
+}
+
+#[derive(Debug)]
+pub struct Forwarder {
+    instance_id: String,
+    /// None without a cluster config, in which case nothing is forwarded
+    cluster: Option<ClusterOptions>,
+}
+
+impl Forwarder {
+    /// Without a cluster config, the instance ID is random, so tokens are still unique
+    pub fn new(cluster: Option<ClusterOptions>) -> Self {
+        let instance_id = match cluster {
+            Some(ref cluster) => cluster.instance_id.clone(),
+            None => Uuid::now_v7().simple().to_string(),
+        };
+        Forwarder { instance_id, cluster }
+    }
+
+    pub fn instance_id(&self) -> &str {
+        &self.instance_id
+    }
+
+    pub fn new_session_token(&self) -> String {
+        format!("{}:{}", self.instance_id, Uuid::now_v7().as_hyphenated())
+    }
+
+    /// The instance owning a session, if it isn't this one. Tokens which aren't session tokens,
+    /// e.g. upload IDs chosen by the client, are always local
+    pub fn remote_owner<'a>(&self, token: &'a str) -> Option<&'a str> {
+        let (owner, id) = token.rsplit_once(':')?;
+        Uuid::try_parse(id).ok()?;
+        (owner != self.instance_id).then_some(owner)
+    }
+
+    /// Whether a forwarded request carries the cluster's secret
+    pub fn is_authenticated(&self, secret: Option<&HeaderValue>) -> bool {
+        let (Some(cluster), Some(secret)) = (&self.cluster, secret) else {
+            return false;
+        };
+        secrets_match(cluster.shared_secret.as_bytes(), secret.as_bytes())
+    }
+
+    /// Sends the request to the owner and returns its response, streaming both bodies
+    #[instrument(level = "debug", skip(self, request), fields(uri = %request.uri()))]
+    pub async fn forward(&self, owner: &str, mut request: Request<Body>) -> Result<Response<Body>, ForwardError> {
+        let Some(cluster) = &self.cluster else {
+            return Err(ForwardError::UnknownPeer);
+        };
+        let Some(addr) = cluster.peers.get(owner) else {
+            return Err(ForwardError::UnknownPeer);
+        };
+
+        let stream = match tokio::time::timeout(CONNECT_TIMEOUT, tokio::net::TcpStream::connect(addr)).await {
+            Ok(stream) => stream.map_err(ForwardError::IO)?,
+            Err(_) => return Err(ForwardError::IO(std::io::ErrorKind::TimedOut.into())),
+        };
+        let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+            .await
+            .map_err(ForwardError::Http)?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                debug!(?e, "Forwarding connection failed");
+            }
+        });
+
+        // HTTP/1.1 requests to a server only carry the path
+        let path = request.uri().path_and_query().map_or("/", |path| path.as_str()).to_string();
+        *request.uri_mut() = path.parse().expect("a path and query is a valid URI");
+        let headers = request.headers_mut();
+        headers.insert(http::header::HOST, HeaderValue::from_str(addr).map_err(|e| ForwardError::IO(std::io::Error::other(e)))?);
+        headers.insert(FORWARDED_BY_HEADER, HeaderValue::from_str(&self.instance_id).map_err(|e| ForwardError::IO(std::io::Error::other(e)))?);
+        headers.insert(SECRET_HEADER, HeaderValue::from_str(&cluster.shared_secret).map_err(|e| ForwardError::IO(std::io::Error::other(e)))?);
+
+        debug!(owner, addr, "Forwarding request");
+        let response = sender.send_request(request).await.map_err(ForwardError::Http)?;
+        Ok(response.map(Body::new))
+    }
+}
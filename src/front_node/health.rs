@@ -0,0 +1,163 @@
+//! Liveness of the metadata database and storage nodes, for GET /health.
+//!
+//! Storage nodes are pinged with GetVersion in the background, so a health check never waits on a
+//! node which has stopped answering. While shutting down, the report says what is still draining.
+
+#[allow(unused)]
+use tracing::{trace, debug, info, warn, error, instrument};
+
+use mysql_async::prelude::*;
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::RwLock;
+
+use super::FrontNode;
+use super::shutdown::DrainReport;
+use super::storage_node_connection::StorageNodeConnection;
+use super::tys::StorageNodeID;
+use crate::message::Message;
+
+/// How often storage nodes are pinged
+const PING_INTERVAL: Duration = Duration::from_secs(10);
+/// A node which takes longer than this to answer a ping is considered down
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long the database gets to answer `SELECT 1`
+const DATABASE_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy)]
+pub struct PingStatus {
+    /// whether the latest ping was answered in time
+    pub ok: bool,
+    /// round trip time of the latest answered ping
+    pub latency: Option<Duration>,
+    /// when the node last answered a ping
+    pub last_seen: Option<Instant>,
+}
+
+pub type NodePings = Arc<RwLock<HashMap<StorageNodeID, PingStatus>>>;
+
+pub async fn ping_nodes(
+    active_connections: Arc<RwLock<HashMap<StorageNodeID, Arc<StorageNodeConnection>>>>,
+    pings: NodePings,
+) {
+    loop {
+        let connections: Vec<(StorageNodeID, Arc<StorageNodeConnection>)> = active_connections.read().await
+            .iter()
+            .map(|(id, conn)| (*id, conn.clone()))
+            .collect();
+
+        for (id, conn) in connections {
+            let started = Instant::now();
+            let answered = match tokio::time::timeout(PING_TIMEOUT, conn.communicate(Message::GetVersion)).await {
+                Ok(Ok(Message::MyVersionIs(_))) => true,
+                Ok(Ok(x)) => {
+                    warn!(?id, %x, "Unexpected response to ping");
+                    false
+                }
+                Ok(Err(e)) => {
+                    debug!(?id, ?e, "Ping failed");
+                    false
+                }
+                Err(_) => {
+                    debug!(?id, "Ping timed out");
+                    false
+                }
+            };
+
+            let mut pings = pings.write().await;
+            let status = pings.entry(id).or_insert(PingStatus { ok: false, latency: None, last_seen: None });
+            if answered {
+                let now = Instant::now();
+                trace!(?id, latency = ?now - started, "Pinged storage node");
+                *status = PingStatus { ok: true, latency: Some(now - started), last_seen: Some(now) };
+            } else {
+                if status.ok {
+                    warn!(?id, "Storage node stopped answering pings");
+                }
+                status.ok = false;
+            }
+        }
+
+        tokio::time::sleep(PING_INTERVAL).await;
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct NodeHealthReport {
+    pub id: i64,
+    pub name: String,
+    /// connected, and answering pings
+    pub connected: bool,
+    pub latency_ms: Option<f64>,
+    pub last_seen_s_ago: Option<f64>,
+    /// of the latest heartbeat ping on the connection, see StorageNodeConfig::heartbeat_interval_s
+    pub heartbeat_latency_ms: Option<f64>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct HealthReport {
+    pub database_reachable: bool,
+    pub storage_nodes: Vec<NodeHealthReport>,
+    /// what shutdown is waiting for. None unless shutting down
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub draining: Option<DrainReport>,
+}
+
+impl HealthReport {
+    /// Nothing can be read or written without a storage node. A node shutting down is unhealthy, so
+    /// load balancers stop sending it requests
+    pub fn is_healthy(&self) -> bool {
+        self.draining.is_none() && self.storage_nodes.iter().any(|node| node.connected)
+    }
+}
+
+impl FrontNode {
+    /// Nodes which haven't been pinged yet count as connected if there is a connection to them
+    #[instrument(level = "trace", skip(self))]
+    pub async fn health(&self) -> HealthReport {
+        let database_reachable = match tokio::time::timeout(DATABASE_CHECK_TIMEOUT, "SELECT 1;".ignore(&self.conn_pool)).await {
+            Ok(Ok(())) => true,
+            Ok(Err(e)) => {
+                warn!(?e, "Health check could not query database");
+                false
+            }
+            Err(_) => {
+                warn!("Health check database query timed out");
+                false
+            }
+        };
+
+        let (active, heartbeats): (HashSet<StorageNodeID>, HashMap<StorageNodeID, Duration>) = {
+            let connections = self.active_connections.read().await;
+            let heartbeats = connections.iter().filter_map(|(id, conn)| Some((*id, conn.ping_latency()?))).collect();
+            (connections.keys().copied().collect(), heartbeats)
+        };
+        let pings = self.node_pings.read().await.clone();
+        let names = self.node_names.read().unwrap().clone();
+
+        let mut ids: Vec<StorageNodeID> = names.keys().chain(active.iter()).copied().collect();
+        ids.sort_by_key(|id| id.0);
+        ids.dedup();
+        let storage_nodes = ids.into_iter().map(|id| {
+            let ping = pings.get(&id);
+            NodeHealthReport {
+                id: id.0,
+                name: names.get(&id).cloned().unwrap_or_default(),
+                connected: active.contains(&id) && ping.is_none_or(|ping| ping.ok),
+                latency_ms: ping.and_then(|ping| ping.latency).map(|latency| latency.as_secs_f64() * 1000.0),
+                last_seen_s_ago: ping.and_then(|ping| ping.last_seen).map(|last_seen| last_seen.elapsed().as_secs_f64()),
+                heartbeat_latency_ms: heartbeats.get(&id).map(|latency| latency.as_secs_f64() * 1000.0),
+            }
+        }).collect();
+
+        let draining = match self.shutdown_remaining() {
+            Some(remaining) => Some(self.drain_report(remaining).await),
+            None => None,
+        };
+
+        HealthReport { database_reachable, storage_nodes, draining }
+    }
+}
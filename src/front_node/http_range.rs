@@ -0,0 +1,127 @@
+/// A parsed `Range` header, resolved against the length of the file
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RangeRequest {
+    /// no (usable) range was requested, the whole file should be sent
+    Full,
+    /// both ends inclusive
+    Partial { start: u64, end: u64 },
+    Unsatisfiable,
+}
+
+impl RangeRequest {
+    /// Only single ranges are supported. Multiple ranges or malformed headers are ignored,
+    /// which is allowed by RFC 9110 and results in the whole file being sent
+    pub fn parse(header: &str, file_len: u64) -> RangeRequest {
+        let Some(spec) = header.trim().strip_prefix("bytes=") else {
+            return RangeRequest::Full;
+        };
+        if spec.contains(',') {
+            return RangeRequest::Full;
+        }
+        let Some((start, end)) = spec.trim().split_once('-') else {
+            return RangeRequest::Full;
+        };
+
+        match (start.parse::<u64>(), end.parse::<u64>()) {
+            // bytes=a-b
+            (Ok(start), Ok(end)) => {
+                if end < start {
+                    RangeRequest::Full
+                } else if start >= file_len {
+                    RangeRequest::Unsatisfiable
+                } else {
+                    RangeRequest::Partial { start, end: end.min(file_len - 1) }
+                }
+            }
+            // bytes=a-
+            (Ok(start), Err(_)) if end.is_empty() => {
+                if start >= file_len {
+                    RangeRequest::Unsatisfiable
+                } else {
+                    RangeRequest::Partial { start, end: file_len - 1 }
+                }
+            }
+            // bytes=-n, the last n bytes
+            (Err(_), Ok(suffix_len)) if start.is_empty() => {
+                if suffix_len == 0 || file_len == 0 {
+                    RangeRequest::Unsatisfiable
+                } else {
+                    RangeRequest::Partial { start: file_len.saturating_sub(suffix_len), end: file_len - 1 }
+                }
+            }
+            _ => RangeRequest::Full,
+        }
+    }
+}
+
+/// Checks an `If-Range` header against the current ETag of the file. Only strong ETags can match,
+/// and as we don't send Last-Modified, date validators never match
+pub fn if_range_matches(if_range: &str, etag: &str) -> bool {
+    let if_range = if_range.trim();
+    !if_range.starts_with("W/") && if_range == etag
+}
+
+/// Checks an `If-None-Match` header against the current ETag of the file. Uses weak comparison,
+/// as RFC 9110 requires for If-None-Match
+pub fn if_none_match_matches(if_none_match: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    if_none_match.split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_are_resolved_against_the_file() {
+        use RangeRequest::*;
+        let cases = [
+            ("bytes=0-99", 1000, Partial { start: 0, end: 99 }),
+            ("bytes=500-", 1000, Partial { start: 500, end: 999 }),
+            ("bytes=-100", 1000, Partial { start: 900, end: 999 }),
+            // clamped to the file
+            ("bytes=900-5000", 1000, Partial { start: 900, end: 999 }),
+            ("bytes=-5000", 1000, Partial { start: 0, end: 999 }),
+            (" bytes= 3-3 ", 1000, Partial { start: 3, end: 3 }),
+            ("bytes=1000-", 1000, Unsatisfiable),
+            ("bytes=1000-2000", 1000, Unsatisfiable),
+            ("bytes=-0", 1000, Unsatisfiable),
+            ("bytes=0-", 0, Unsatisfiable),
+            ("bytes=-10", 0, Unsatisfiable),
+            // ignored, so the whole file is sent
+            ("bytes=5-1", 1000, Full),
+            ("bytes=0-1,5-6", 1000, Full),
+            ("bytes=-", 1000, Full),
+            ("bytes=a-b", 1000, Full),
+            ("bytes=0", 1000, Full),
+            ("items=0-1", 1000, Full),
+            ("", 1000, Full),
+        ];
+        for (header, file_len, expected) in cases {
+            assert_eq!(RangeRequest::parse(header, file_len), expected, "{header:?} of {file_len} bytes");
+        }
+    }
+
+    #[test]
+    fn if_range_needs_the_same_strong_etag() {
+        let etag = "\"0192b2d4-4d0e-7c53-a5d7-5e1e5f3f2c11\"";
+        assert!(if_range_matches(etag, etag));
+        assert!(if_range_matches(&format!(" {etag} "), etag));
+        assert!(!if_range_matches(&format!("W/{etag}"), etag));
+        assert!(!if_range_matches("\"0192b2d4-4d0e-7c53-a5d7-000000000000\"", etag));
+        assert!(!if_range_matches("Wed, 21 Oct 2015 07:28:00 GMT", etag));
+    }
+
+    #[test]
+    fn if_none_match_compares_weakly() {
+        let etag = "\"bnuy\"";
+        assert!(if_none_match_matches("\"bnuy\"", etag));
+        assert!(if_none_match_matches("W/\"bnuy\"", etag));
+        assert!(if_none_match_matches("\"carrot\", \"bnuy\"", etag));
+        assert!(if_none_match_matches("*", etag));
+        assert!(!if_none_match_matches("\"carrot\"", etag));
+        assert!(!if_none_match_matches("bnuy", etag));
+    }
+}
@@ -0,0 +1,163 @@
+//! Picking one front node to run a background job when several share the database.
+//!
+//! The leader is whoever holds a named lock, taken with GET_LOCK. The lock belongs to the
+//! connection which took it, so it is released when the leader exits or loses its connection to
+//! the database, and another front node takes over at its next try. Named locks are per server
+//! rather than per database, so the lock name includes the database's name.
+
+#[allow(unused)]
+use tracing::{trace, debug, info, warn, error, instrument, Instrument};
+
+use mysql_async::prelude::*;
+
+use std::sync::{Arc, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use super::tys::Error;
+
+/// How often a front node which isn't the leader tries to take the lock, and how often the leader
+/// checks that it still holds it
+pub const LEADERSHIP_CHECK_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Whether this front node leads for a job. Tries to become the leader in the background until
+/// dropped, and then gives up leadership
+pub struct Leadership {
+    leading: Arc<AtomicBool>,
+}
+
+impl Leadership {
+    pub fn start(conn_pool: mysql_async::Pool, job: &'static str) -> Leadership {
+        Leadership::start_checking_every(conn_pool, job, LEADERSHIP_CHECK_INTERVAL)
+    }
+
+    pub fn start_checking_every(conn_pool: mysql_async::Pool, job: &'static str, interval: Duration) -> Leadership {
+        let leading = Arc::new(AtomicBool::new(false));
+        let span = tracing::info_span!("leadership", job);
+        tokio::spawn(hold_lock(conn_pool, job, interval, Arc::downgrade(&leading)).instrument(span));
+        Leadership { leading }
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.leading.load(Ordering::Relaxed)
+    }
+}
+
+// takes the lock whenever no one else holds it, and keeps the connection holding it until `leading`
+// is dropped
+async fn hold_lock(conn_pool: mysql_async::Pool, job: &'static str, interval: Duration, leading: Weak<AtomicBool>) {
+    let mut held: Option<mysql_async::Conn> = None;
+    let mut lock_name = None;
+    let mut ticks = tokio::time::interval(interval);
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticks.tick().await;
+        let Some(leading) = leading.upgrade() else {
+            break;
+        };
+
+        let name = match lock_name {
+            Some(ref name) => name,
+            None => match qualified_lock_name(&conn_pool, job).await {
+                Ok(name) => lock_name.insert(name),
+                Err(e) => {
+                    debug!(?e, "Could not ask the database for its name");
+                    continue;
+                }
+            },
+        };
+        let still_held = match held {
+            Some(ref mut conn) => check_held(conn, name).await,
+            None => false,
+        };
+        if still_held {
+            continue;
+        }
+        if held.take().is_some() {
+            warn!("Lost leadership");
+            leading.store(false, Ordering::Relaxed);
+        }
+
+        match try_lock(&conn_pool, name).await {
+            Ok(Some(conn)) => {
+                info!("Became the leader");
+                held = Some(conn);
+                leading.store(true, Ordering::Relaxed);
+            }
+            Ok(None) => trace!("Another front node is the leader"),
+            Err(e) => debug!(?e, "Could not try to become the leader"),
+        }
+    }
+
+    // dropped, so give up leadership rather than giving the connection back to the pool with the
+    // lock still held
+    if let (Some(mut conn), Some(name)) = (held, lock_name) {
+        if let Err(e) = "SELECT RELEASE_LOCK(:name);".with(params! { "name" => &name }).ignore(&mut conn).await {
+            debug!(?e, "Could not release the leadership lock");
+        }
+        if let Err(e) = conn.disconnect().await {
+            debug!(?e, "Error closing the leadership connection");
+        }
+        debug!("Gave up leadership");
+    }
+}
+
+async fn qualified_lock_name(conn_pool: &mysql_async::Pool, job: &str) -> Result<String, Error> {
+    let database: Option<Option<String>> = "SELECT DATABASE();".first(conn_pool).await?;
+    Ok(format!("bnuystore_{}_{job}", database.flatten().unwrap_or_default()))
+}
+
+// a connection holding the lock, None if someone else holds it
+async fn try_lock(conn_pool: &mysql_async::Pool, name: &str) -> Result<Option<mysql_async::Conn>, Error> {
+    let mut conn = conn_pool.get_conn().await?;
+    let locked: Option<Option<u32>> = "SELECT GET_LOCK(:name, 0);".with(params! { "name" => name }).first(&mut conn).await?;
+    Ok((locked == Some(Some(1))).then_some(conn))
+}
+
+async fn check_held(conn: &mut mysql_async::Conn, name: &str) -> bool {
+    let held: Result<Option<Option<bool>>, _> = "SELECT IS_USED_LOCK(:name) = CONNECTION_ID();"
+        .with(params! { "name" => name })
+        .first(conn)
+        .await;
+    match held {
+        Ok(held) => held.flatten().unwrap_or(false),
+        Err(e) => {
+            warn!(?e, "Could not check the leadership lock");
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::front_node::test_support;
+    use crate::fault_injection::FaultInjector;
+
+    #[tokio::test]
+    async fn no_leadership_without_the_database() {
+        let node = test_support::offline(&test_support::offline_config());
+        let leadership = Leadership::start_checking_every(node.conn_pool.clone(), "test", Duration::from_millis(10));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!leadership.is_leader());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MySQL database, see test_support::DATABASE_URL_VAR"]
+    async fn one_leader_at_a_time() {
+        let front = test_support::DatabaseFrontNode::start_with_faults(&test_support::database_config(), FaultInjector::default()).await;
+        let interval = Duration::from_millis(50);
+        let job = "leadership-test";
+        let first = Leadership::start_checking_every(front.node.conn_pool.clone(), job, interval);
+        tokio::time::sleep(interval * 4).await;
+        let second = Leadership::start_checking_every(front.node.conn_pool.clone(), job, interval);
+        tokio::time::sleep(interval * 4).await;
+        assert!(first.is_leader());
+        assert!(!second.is_leader());
+
+        drop(first);
+        tokio::time::sleep(interval * 6).await;
+        assert!(second.is_leader());
+    }
+}
@@ -0,0 +1,314 @@
+#[allow(unused)]
+use tracing::{trace, debug, info, warn, error, instrument};
+
+use futures::{Stream, StreamExt};
+
+use uuid::Uuid;
+
+use super::ListingRow;
+use super::recursive_listing::TreeRow;
+use super::tys::{DirectoryID, Error};
+
+/// How many rows the listing task may queue up ahead of the encoder
+pub const LISTING_BUFFER_ROWS: usize = 256;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Section {
+    Start,
+    Files,
+    Directories,
+    Done,
+}
+
+/// Encodes a streamed listing as the JSON object
+/// `{"file_uuids_and_names":[[uuid,name,size],...],"directory_ids_and_names":[[id,name],...]}`,
+/// where size is null for files uploaded before sizes were stored
+/// in chunks of about `chunk_bytes` bytes. Relies on FrontNode::list_directory sending all files
+/// before any directories
+struct ListingEncoder<S> {
+    rows: S,
+    section: Section,
+    needs_comma: bool,
+    chunk_bytes: usize,
+}
+
+impl<S: Stream<Item = Result<ListingRow, Error>> + Unpin> ListingEncoder<S> {
+    async fn next_chunk(&mut self) -> Option<std::io::Result<Vec<u8>>> {
+        if self.section == Section::Done {
+            return None;
+        }
+
+        let mut chunk = Vec::with_capacity(self.chunk_bytes);
+        if self.section == Section::Start {
+            chunk.extend_from_slice(br#"{"file_uuids_and_names":["#);
+            self.section = Section::Files;
+        }
+
+        while chunk.len() < self.chunk_bytes {
+            match self.rows.next().await {
+                Some(Ok(ListingRow::File(uuid, name, size))) => {
+                    self.push_entry(&mut chunk, &(uuid, name, size));
+                }
+                Some(Ok(ListingRow::Directory(dir_id, name))) => {
+                    self.start_directories(&mut chunk);
+                    self.push_entry(&mut chunk, &(dir_id, name));
+                }
+                // only listed with include_deleted, which this format doesn't take
+                Some(Ok(ListingRow::DeletedFile(..))) => {}
+                Some(Err(e)) => {
+                    // the status code has already been sent, so all we can do is cut the response short
+                    error!(?e, "Listing failed mid-response, aborting");
+                    self.section = Section::Done;
+                    return Some(Err(std::io::Error::other("directory listing failed")));
+                }
+                None => {
+                    self.start_directories(&mut chunk);
+                    chunk.extend_from_slice(b"]}");
+                    self.section = Section::Done;
+                    break;
+                }
+            }
+        }
+
+        Some(Ok(chunk))
+    }
+
+    fn start_directories(&mut self, chunk: &mut Vec<u8>) {
+        if self.section == Section::Files {
+            chunk.extend_from_slice(br#"],"directory_ids_and_names":["#);
+            self.section = Section::Directories;
+            self.needs_comma = false;
+        }
+    }
+
+    fn push_entry<T: serde::Serialize>(&mut self, chunk: &mut Vec<u8>, entry: &T) {
+        if self.needs_comma {
+            chunk.push(b',');
+        }
+        serde_json::to_writer(&mut *chunk, entry).expect("listing entries always serialize");
+        self.needs_comma = true;
+    }
+}
+
+pub fn encode_listing<S: Stream<Item = Result<ListingRow, Error>> + Unpin>(
+    rows: S,
+    chunk_bytes: usize,
+) -> impl Stream<Item = std::io::Result<Vec<u8>>> {
+    let encoder = ListingEncoder {
+        rows,
+        section: Section::Start,
+        needs_comma: false,
+        chunk_bytes,
+    };
+    futures::stream::unfold(encoder, |mut encoder| async move {
+        encoder.next_chunk().await.map(|chunk| (chunk, encoder))
+    })
+}
+
+/// One element of `entries` in a page of a listing
+#[derive(serde::Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+enum Entry {
+    File { name: String, uuid: Uuid, size: Option<u64> },
+    Directory { name: String, id: DirectoryID },
+    /// in the trash. deleted_at is in seconds since the unix epoch
+    Deleted { name: String, uuid: Uuid, size: Option<u64>, deleted_at: u64 },
+}
+
+impl From<ListingRow> for Entry {
+    fn from(row: ListingRow) -> Self {
+        match row {
+            ListingRow::File(uuid, name, size) => Entry::File { name, uuid, size },
+            ListingRow::Directory(id, name) => Entry::Directory { name, id },
+            ListingRow::DeletedFile(uuid, name, size, deleted_at) => Entry::Deleted { name, uuid, size, deleted_at },
+        }
+    }
+}
+
+/// What follows `entries`
+enum Ending {
+    /// `"next_offset":offset`, where the next page starts, or null if this page is the last. A full
+    /// page may be followed by an empty one
+    NextOffset { offset: u64, limit: Option<u64> },
+    /// `"truncated":bool`. The rows go on past max_entries if there are more entries than that
+    Truncated { max_entries: u64 },
+}
+
+/// Encodes streamed entries as the JSON object `{"entries":[entry,...],...}` in chunks of about
+/// `chunk_bytes` bytes, ending as the Ending says
+struct EntriesEncoder<S> {
+    rows: S,
+    ending: Ending,
+    started: bool,
+    done: bool,
+    /// entries encoded so far
+    count: u64,
+    chunk_bytes: usize,
+}
+
+impl<T, S> EntriesEncoder<S>
+where
+    T: serde::Serialize,
+    S: Stream<Item = Result<T, Error>> + Unpin,
+{
+    async fn next_chunk(&mut self) -> Option<std::io::Result<Vec<u8>>> {
+        if self.done {
+            return None;
+        }
+
+        let mut chunk = Vec::with_capacity(self.chunk_bytes);
+        if !self.started {
+            chunk.extend_from_slice(br#"{"entries":["#);
+            self.started = true;
+        }
+
+        while chunk.len() < self.chunk_bytes {
+            match self.rows.next().await {
+                Some(Ok(_)) if matches!(self.ending, Ending::Truncated { max_entries } if self.count >= max_entries) => {
+                    self.end(&mut chunk, true);
+                    break;
+                }
+                Some(Ok(entry)) => {
+                    if self.count > 0 {
+                        chunk.push(b',');
+                    }
+                    serde_json::to_writer(&mut chunk, &entry).expect("listing entries always serialize");
+                    self.count += 1;
+                }
+                Some(Err(e)) => {
+                    // the status code has already been sent, so all we can do is cut the response short
+                    error!(?e, "Listing failed mid-response, aborting");
+                    self.done = true;
+                    return Some(Err(std::io::Error::other("directory listing failed")));
+                }
+                None => {
+                    self.end(&mut chunk, false);
+                    break;
+                }
+            }
+        }
+
+        Some(Ok(chunk))
+    }
+
+    fn end(&mut self, chunk: &mut Vec<u8>, more_rows: bool) {
+        match self.ending {
+            Ending::NextOffset { offset, limit } => {
+                let next_offset = limit
+                    .filter(|&limit| self.count >= limit)
+                    .map(|_| offset + self.count);
+                chunk.extend_from_slice(br#"],"next_offset":"#);
+                serde_json::to_writer(&mut *chunk, &next_offset).expect("offsets always serialize");
+            }
+            Ending::Truncated { .. } => {
+                chunk.extend_from_slice(br#"],"truncated":"#);
+                serde_json::to_writer(&mut *chunk, &more_rows).expect("bools always serialize");
+            }
+        }
+        chunk.push(b'}');
+        self.done = true;
+    }
+}
+
+fn encode_with_ending<T, S>(rows: S, ending: Ending, chunk_bytes: usize) -> impl Stream<Item = std::io::Result<Vec<u8>>>
+where
+    T: serde::Serialize,
+    S: Stream<Item = Result<T, Error>> + Unpin,
+{
+    let encoder = EntriesEncoder {
+        rows,
+        ending,
+        started: false,
+        done: false,
+        count: 0,
+        chunk_bytes,
+    };
+    futures::stream::unfold(encoder, |mut encoder| async move {
+        encoder.next_chunk().await.map(|chunk| (chunk, encoder))
+    })
+}
+
+/// Encodes a page of a listing as
+/// `{"entries":[{"kind":"file","name":name,"uuid":uuid,"size":size},{"kind":"directory","name":name,"id":id},...],"next_offset":offset}`,
+/// with entries of kind "deleted" for files in the trash if they were listed.
+/// `rows` should come from FrontNode::list_directory_page with the same `offset` and `limit`
+pub fn encode_entries<S: Stream<Item = Result<ListingRow, Error>> + Unpin>(
+    rows: S,
+    offset: u64,
+    limit: Option<u64>,
+    chunk_bytes: usize,
+) -> impl Stream<Item = std::io::Result<Vec<u8>>> {
+    let rows = rows.map(|row| row.map(Entry::from));
+    encode_with_ending(rows, Ending::NextOffset { offset, limit }, chunk_bytes)
+}
+
+/// Encodes a recursive listing as `{"entries":[TreeRow,...],"truncated":bool}`, where truncated is
+/// set if there were more than `max_entries` rows. `rows` should come from FrontNode::list_tree with
+/// the same `max_entries`
+pub fn encode_tree<S: Stream<Item = Result<TreeRow, Error>> + Unpin>(
+    rows: S,
+    max_entries: u64,
+    chunk_bytes: usize,
+) -> impl Stream<Item = std::io::Result<Vec<u8>>> {
+    encode_with_ending(rows, Ending::Truncated { max_entries }, chunk_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::test_support;
+
+    use futures::executor::block_on;
+
+    fn rows(files: u64, directories: i64) -> impl Stream<Item = Result<ListingRow, Error>> + Unpin {
+        let files = (0..files).map(|i| ListingRow::File(Uuid::from_u128(i as u128), format!("file {i}.txt"), Some(i)));
+        let directories = (0..directories).map(|i| ListingRow::Directory(DirectoryID(i), format!("dir {i}")));
+        futures::stream::iter(files.chain(directories).map(Ok))
+    }
+
+    /// How listings were encoded before they were streamed, all at once
+    #[derive(serde::Serialize)]
+    struct DirectoryListing {
+        file_uuids_and_names: Vec<(Uuid, String, Option<u64>)>,
+        directory_ids_and_names: Vec<(DirectoryID, String)>,
+    }
+
+    #[test]
+    fn streamed_listing_parses_like_the_old_one() {
+        let mut old = DirectoryListing { file_uuids_and_names: Vec::new(), directory_ids_and_names: Vec::new() };
+        block_on(rows(1000, 100).for_each(|row| {
+            match row.unwrap() {
+                ListingRow::File(uuid, name, size) => old.file_uuids_and_names.push((uuid, name, size)),
+                ListingRow::Directory(id, name) => old.directory_ids_and_names.push((id, name)),
+                ListingRow::DeletedFile(..) => unreachable!(),
+            }
+            async {}
+        }));
+
+        for (files, directories) in [(1000, 100), (0, 0), (1, 0), (0, 1)] {
+            let chunks: Vec<Vec<u8>> = block_on(encode_listing(rows(files, directories), 512).map(Result::unwrap).collect());
+            assert!(chunks.len() > 1 || files < 10);
+            let streamed: serde_json::Value = serde_json::from_slice(&chunks.concat()).unwrap();
+
+            let old = DirectoryListing {
+                file_uuids_and_names: old.file_uuids_and_names[..files as usize].to_vec(),
+                directory_ids_and_names: old.directory_ids_and_names[..directories as usize].to_vec(),
+            };
+            assert_eq!(streamed, serde_json::to_value(&old).unwrap());
+        }
+    }
+
+    #[test]
+    fn huge_listing_takes_bounded_memory() {
+        let chunk_bytes = 64 * 1024;
+        let (total_bytes, peak) = test_support::peak_allocation(|| {
+            // the chunks are dropped as they're sent, as they would be by the HTTP server
+            block_on(encode_listing(rows(500_000, 1000), chunk_bytes).fold(0, |total, chunk| async move {
+                total + chunk.unwrap().len()
+            }))
+        });
+        // tens of megabytes were encoded, but only about a chunk at a time
+        assert!(total_bytes > 30_000_000, "{total_bytes}");
+        assert!(peak < 4 * chunk_bytes, "peak allocation {peak} of {total_bytes} bytes encoded");
+    }
+}
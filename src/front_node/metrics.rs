@@ -0,0 +1,312 @@
+//! Counters and histograms for GET /metrics, in the Prometheus text format.
+//!
+//! HTTP requests are counted by route pattern rather than by path, so that the number of series
+//! stays bounded. Gauges such as connected storage nodes, requests in flight and open SFTP sessions are read when the
+//! metrics are rendered rather than tracked here, as are the storage nodes' own metrics, which are
+//! polled from each node and labelled with its name.
+
+#[allow(unused)]
+use tracing::{trace, debug, info, warn, error, instrument};
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use super::FrontNode;
+use super::concurrency::RouteClass;
+use crate::message::NodeMetrics;
+
+/// Upper bounds of the histogram buckets, in seconds
+const BUCKETS_S: [f64; 14] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// Database errors since the front node started. Counted where mysql_async errors are converted,
+/// which has no FrontNode to count in
+static DATABASE_ERRORS: AtomicU64 = AtomicU64::new(0);
+
+pub fn count_database_error() {
+    DATABASE_ERRORS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Durations, counted in buckets. Observations past the last bucket are only in +Inf, the count and the sum
+#[derive(Debug, Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKETS_S.len()],
+    count: AtomicU64,
+    sum_us: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        if let Some(i) = BUCKETS_S.iter().position(|&le| secs <= le) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_us.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    // `labels` are written as is, e.g. `node="a"`
+    fn write(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (le, bucket) in BUCKETS_S.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{name}_bucket{{{labels},le=\"{le}\"}} {cumulative}");
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_us.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{name}_bucket{{{labels},le=\"+Inf\"}} {count}");
+        let _ = writeln!(out, "{name}_sum{{{labels}}} {sum}");
+        let _ = writeln!(out, "{name}_count{{{labels}}} {count}");
+    }
+}
+
+/// A family re-exported from the storage nodes' own metrics: its name, kind and help text, and its
+/// value in a node's NodeMetrics
+type StorageNodeMetric = (&'static str, &'static str, &'static str, fn(&NodeMetrics) -> f64);
+
+const STORAGE_NODE_METRICS: [StorageNodeMetric; 11] = [
+    ("bnuystore_storage_node_files_stored", "gauge", "Files stored on a storage node, by node", |m| m.files_stored as f64),
+    ("bnuystore_storage_node_stored_bytes", "gauge", "Bytes of files stored on a storage node, by node", |m| m.bytes_stored as f64),
+    ("bnuystore_storage_node_filesystem_bytes", "gauge", "Size of the filesystem a storage node stores its files on, by node", |m| m.bytes_total as f64),
+    ("bnuystore_storage_node_available_bytes", "gauge", "Bytes available on the filesystem a storage node stores its files on, by node", |m| m.bytes_available as f64),
+    ("bnuystore_storage_node_reads_total", "counter", "Files read by a storage node since it started, by node", |m| m.reads as f64),
+    ("bnuystore_storage_node_writes_total", "counter", "Files written by a storage node since it started, by node", |m| m.writes as f64),
+    ("bnuystore_storage_node_read_bytes_total", "counter", "Bytes read by a storage node since it started, by node", |m| m.bytes_read as f64),
+    ("bnuystore_storage_node_written_bytes_total", "counter", "Bytes written by a storage node since it started, by node", |m| m.bytes_written as f64),
+    ("bnuystore_storage_node_errors_total", "counter", "Requests a storage node answered with an error since it started, by node", |m| m.errors as f64),
+    ("bnuystore_storage_node_lock_acquisitions_total", "counter", "File locks taken by a storage node since it started, by node", |m| m.lock_acquisitions as f64),
+    ("bnuystore_storage_node_lock_wait_seconds_total", "counter", "Time a storage node spent waiting for file locks since it started, by node", |m| m.lock_wait_us as f64 / 1e6),
+];
+
+/// What is counted for each HTTP route
+#[derive(Debug, Default)]
+pub struct RouteMetrics {
+    /// responses by status code
+    responses: Mutex<HashMap<u16, u64>>,
+    /// until the response headers, not the whole body
+    pub duration: Histogram,
+    pub request_bytes: AtomicU64,
+    pub response_bytes: AtomicU64,
+}
+
+impl RouteMetrics {
+    pub fn count_response(&self, status: u16) {
+        *self.responses.lock().unwrap().entry(status).or_insert(0) += 1;
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// by route pattern, e.g. /get/file-by-uuid/:uuid
+    routes: Mutex<HashMap<String, Arc<RouteMetrics>>>,
+    pub sftp_read_bytes: AtomicU64,
+    /// counted once the files are stored, when their handles are closed
+    pub sftp_written_bytes: AtomicU64,
+}
+
+impl Metrics {
+    pub fn route(&self, route: &str) -> Arc<RouteMetrics> {
+        self.routes.lock().unwrap().entry(route.to_string()).or_default().clone()
+    }
+}
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+impl FrontNode {
+    /// Everything counted so far, in the Prometheus text format
+    #[instrument(level = "debug", skip(self))]
+    pub async fn render_metrics(&self) -> String {
+        let mut out = String::new();
+
+        let mut routes: Vec<(String, Arc<RouteMetrics>)> = self.metrics.routes.lock().unwrap()
+            .iter()
+            .map(|(route, metrics)| (escape_label(route), metrics.clone()))
+            .collect();
+        routes.sort_by(|a, b| a.0.cmp(&b.0));
+
+        family(&mut out, "bnuystore_http_requests_total", "counter", "HTTP requests, by route and response status");
+        for (route, metrics) in &routes {
+            let mut responses: Vec<(u16, u64)> = metrics.responses.lock().unwrap().iter().map(|(s, n)| (*s, *n)).collect();
+            responses.sort();
+            for (status, n) in responses {
+                let _ = writeln!(out, "bnuystore_http_requests_total{{route=\"{route}\",status=\"{status}\"}} {n}");
+            }
+        }
+        family(&mut out, "bnuystore_http_request_duration_seconds", "histogram", "Time until the response headers were sent, by route");
+        for (route, metrics) in &routes {
+            metrics.duration.write(&mut out, "bnuystore_http_request_duration_seconds", &format!("route=\"{route}\""));
+        }
+        family(&mut out, "bnuystore_http_request_bytes_total", "counter", "Request body bytes received, e.g. uploads, by route");
+        for (route, metrics) in &routes {
+            let _ = writeln!(out, "bnuystore_http_request_bytes_total{{route=\"{route}\"}} {}", metrics.request_bytes.load(Ordering::Relaxed));
+        }
+        family(&mut out, "bnuystore_http_response_bytes_total", "counter", "Response body bytes sent, e.g. downloads, by route");
+        for (route, metrics) in &routes {
+            let _ = writeln!(out, "bnuystore_http_response_bytes_total{{route=\"{route}\"}} {}", metrics.response_bytes.load(Ordering::Relaxed));
+        }
+
+        let route_utilization = self.limits.route_utilization();
+        family(&mut out, "bnuystore_http_requests_in_flight", "gauge", "HTTP requests holding a permit of their route class, by class");
+        for class in RouteClass::ALL {
+            let n = route_utilization.iter().find(|(name, _)| name == class.name()).map_or(0, |(_, n)| *n);
+            let _ = writeln!(out, "bnuystore_http_requests_in_flight{{class=\"{}\"}} {n}", class.name());
+        }
+        // only users with anything in flight, so that the number of series stays bounded
+        let mut user_utilization = self.limits.user_utilization();
+        user_utilization.sort();
+        family(&mut out, "bnuystore_user_requests_in_flight", "gauge", "HTTP requests and SFTP sessions holding a permit of their user, by user");
+        for (user, n) in &user_utilization {
+            let _ = writeln!(out, "bnuystore_user_requests_in_flight{{user=\"{}\"}} {n}", escape_label(user));
+        }
+
+        let node_names = self.node_names.read().unwrap().clone();
+        let node_label = |id: &super::tys::StorageNodeID| node_names.get(id).map_or_else(|| id.0.to_string(), |name| escape_label(name));
+        let connections = self.active_connections.read().await.clone();
+        // as last polled. nodes which were disconnected since are left out
+        let mut node_metrics: Vec<(String, NodeMetrics)> = self.node_metrics().await.into_iter()
+            .filter(|(id, _)| connections.contains_key(id))
+            .map(|(id, metrics)| (node_label(&id), metrics))
+            .collect();
+        node_metrics.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut connections: Vec<_> = connections.into_iter()
+            .map(|(id, conn)| (node_label(&id), conn))
+            .collect();
+        connections.sort_by(|a, b| a.0.cmp(&b.0));
+
+        family(&mut out, "bnuystore_storage_nodes_connected", "gauge", "Storage nodes with an open connection");
+        let _ = writeln!(out, "bnuystore_storage_nodes_connected {}", connections.len());
+        family(&mut out, "bnuystore_storage_node_requests_in_flight", "gauge", "Requests sent to a storage node and not yet answered, by node");
+        for (node, conn) in &connections {
+            let _ = writeln!(out, "bnuystore_storage_node_requests_in_flight{{node=\"{node}\"}} {}", conn.requests_in_flight());
+        }
+        family(&mut out, "bnuystore_storage_node_request_duration_seconds", "histogram", "Time until a storage node answered a request, by node");
+        for (node, conn) in &connections {
+            conn.latency.write(&mut out, "bnuystore_storage_node_request_duration_seconds", &format!("node=\"{node}\""));
+        }
+        family(&mut out, "bnuystore_storage_node_ping_seconds", "gauge", "Round trip time of the latest heartbeat ping answered by a storage node, by node");
+        for (node, conn) in &connections {
+            if let Some(latency) = conn.ping_latency() {
+                let _ = writeln!(out, "bnuystore_storage_node_ping_seconds{{node=\"{node}\"}} {}", latency.as_secs_f64());
+            }
+        }
+        for (name, kind, help, value) in STORAGE_NODE_METRICS {
+            family(&mut out, name, kind, help);
+            for (node, metrics) in &node_metrics {
+                let _ = writeln!(out, "{name}{{node=\"{node}\"}} {}", value(metrics));
+            }
+        }
+
+        let path_lookups = [("directory", &self.path_cache.directory_lookups), ("file", &self.path_cache.file_lookups)];
+        family(&mut out, "bnuystore_path_cache_hits_total", "counter", "Path lookups answered from the path cache, by kind");
+        for (kind, counters) in path_lookups {
+            let _ = writeln!(out, "bnuystore_path_cache_hits_total{{kind=\"{kind}\"}} {}", counters.hits.load(Ordering::Relaxed));
+        }
+        family(&mut out, "bnuystore_path_cache_misses_total", "counter", "Path lookups which had to query the database, by kind");
+        for (kind, counters) in path_lookups {
+            let _ = writeln!(out, "bnuystore_path_cache_misses_total{{kind=\"{kind}\"}} {}", counters.misses.load(Ordering::Relaxed));
+        }
+        family(&mut out, "bnuystore_database_errors_total", "counter", "Failed database queries");
+        let _ = writeln!(out, "bnuystore_database_errors_total {}", DATABASE_ERRORS.load(Ordering::Relaxed));
+        family(&mut out, "bnuystore_upload_verification_failures_total", "counter", "Uploads whose written file didn't match what was sent");
+        let _ = writeln!(out, "bnuystore_upload_verification_failures_total {}", self.verification_failures.load(Ordering::Relaxed));
+        family(&mut out, "bnuystore_short_writes_total", "counter", "Writes acked with fewer bytes than were sent");
+        let _ = writeln!(out, "bnuystore_short_writes_total {}", self.short_writes.load(Ordering::Relaxed));
+        family(&mut out, "bnuystore_access_log_failures_total", "counter", "Access log entries which were dropped or could not be written");
+        let _ = writeln!(out, "bnuystore_access_log_failures_total {}", self.access_log.failures.load(Ordering::Relaxed));
+
+        let sftp_sessions: usize = self.limits.sftp_session_utilization().iter().map(|(_, n)| n).sum();
+        family(&mut out, "bnuystore_sftp_sessions", "gauge", "Open SFTP sessions");
+        let _ = writeln!(out, "bnuystore_sftp_sessions {sftp_sessions}");
+        family(&mut out, "bnuystore_sftp_read_bytes_total", "counter", "Bytes read by SFTP clients");
+        let _ = writeln!(out, "bnuystore_sftp_read_bytes_total {}", self.metrics.sftp_read_bytes.load(Ordering::Relaxed));
+        family(&mut out, "bnuystore_sftp_written_bytes_total", "counter", "Bytes stored by SFTP clients");
+        let _ = writeln!(out, "bnuystore_sftp_written_bytes_total {}", self.metrics.sftp_written_bytes.load(Ordering::Relaxed));
+        #[cfg(feature = "sftp")]
+        {
+            family(&mut out, "bnuystore_sftp_open_handles", "gauge", "Open SFTP handles across all sessions");
+            let _ = writeln!(out, "bnuystore_sftp_open_handles {}", super::sftp::total_open_handles());
+            family(&mut out, "bnuystore_sftp_terminations_total", "counter", "SSH connections closed by the front node, by reason");
+            for (reason, n) in super::sftp::session_terminations() {
+                let _ = writeln!(out, "bnuystore_sftp_terminations_total{{reason=\"{reason}\"}} {n}");
+            }
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use super::super::test_support;
+    use super::super::tys::StorageNodeID;
+
+    #[tokio::test]
+    async fn storage_node_metrics_are_labelled_by_node() {
+        let front = test_support::offline(&test_support::offline_config());
+        let conn = test_support::mock_node(&[], |_| None).await;
+        test_support::attach_offline(&front, StorageNodeID(1), conn).await;
+        front.node_names.write().unwrap().insert(StorageNodeID(1), "bnuy \"one\"".to_string());
+        let metrics = NodeMetrics { files_stored: 3, bytes_stored: 4096, writes: 7, lock_wait_us: 1_500_000, ..Default::default() };
+        front.node_metrics.write().await.insert(StorageNodeID(1), metrics.clone());
+        // reported before it was disconnected
+        front.node_metrics.write().await.insert(StorageNodeID(2), metrics);
+
+        let rendered = front.render_metrics().await;
+        for line in [
+            r#"bnuystore_storage_node_files_stored{node="bnuy \"one\""} 3"#,
+            r#"bnuystore_storage_node_stored_bytes{node="bnuy \"one\""} 4096"#,
+            r#"bnuystore_storage_node_writes_total{node="bnuy \"one\""} 7"#,
+            r#"bnuystore_storage_node_lock_wait_seconds_total{node="bnuy \"one\""} 1.5"#,
+            "# TYPE bnuystore_storage_node_reads_total counter",
+        ] {
+            assert!(rendered.lines().any(|rendered| rendered == line), "no {line:?} in\n{rendered}");
+        }
+        assert!(!rendered.contains(r#"node="2""#), "{rendered}");
+
+        // every sample belongs to a family which was declared before it
+        let mut families = HashSet::new();
+        for line in rendered.lines() {
+            if let Some(family) = line.strip_prefix("# TYPE ") {
+                families.insert(family.split(' ').next().unwrap().to_string());
+            } else if !line.starts_with('#') {
+                let name = line.split(['{', ' ']).next().unwrap();
+                let family = ["_bucket", "_sum", "_count"].iter().find_map(|suffix| name.strip_suffix(suffix)).unwrap_or(name);
+                assert!(families.contains(name) || families.contains(family), "{line:?} has no family");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_in_flight_are_labelled_by_class_and_user() {
+        let front = test_support::offline(&test_support::offline_config());
+        let _reads = [front.limits.try_acquire_route(RouteClass::Reads).unwrap(), front.limits.try_acquire_route(RouteClass::Reads).unwrap()];
+        let _user = front.limits.try_acquire_user("bnuy \"one\"").unwrap();
+        let _session = front.limits.count_sftp_session("bnuy \"one\"");
+
+        let rendered = front.render_metrics().await;
+        for line in [
+            r#"bnuystore_http_requests_in_flight{class="reads"} 2"#,
+            r#"bnuystore_http_requests_in_flight{class="writes"} 0"#,
+            r#"bnuystore_http_requests_in_flight{class="listings"} 0"#,
+            r#"bnuystore_user_requests_in_flight{user="bnuy \"one\""} 1"#,
+            "bnuystore_sftp_sessions 1",
+        ] {
+            assert!(rendered.lines().any(|rendered| rendered == line), "no {line:?} in\n{rendered}");
+        }
+
+        drop(_user);
+        let rendered = front.render_metrics().await;
+        assert!(!rendered.contains("bnuystore_user_requests_in_flight{"), "{rendered}");
+    }
+}
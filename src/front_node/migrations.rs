@@ -0,0 +1,125 @@
+//! Schema migrations, embedded in the binary from migrations/. Each is applied once, in order, and
+//! recorded in schema_version.
+//!
+//! MariaDB can't roll back schema changes, so a migration which fails partway is run again from the
+//! start the next time. Migrations are written to be safe to run again, e.g. with IF NOT EXISTS.
+//! Front nodes sharing a database take a named lock while migrating, so only one of them applies
+//! each migration.
+
+#[allow(unused)]
+use tracing::{trace, debug, info, warn, error, instrument};
+
+use mysql_async::prelude::*;
+
+use super::config::DatabaseConnectionOptions;
+use super::tys::Error;
+
+struct Migration {
+    version: u32,
+    description: &'static str,
+    sql: &'static str,
+}
+
+/// In the order they are applied. New migrations get the next version
+const MIGRATIONS: &[Migration] = &[
+    Migration { version: 1, description: "initial schema", sql: include_str!("../../migrations/0001_initial_schema.sql") },
+    Migration { version: 2, description: "user ids", sql: include_str!("../../migrations/0002_user_ids.sql") },
+    Migration { version: 3, description: "trash", sql: include_str!("../../migrations/0003_trash.sql") },
+    Migration { version: 4, description: "file versions", sql: include_str!("../../migrations/0004_file_versions.sql") },
+    Migration { version: 5, description: "deduplication", sql: include_str!("../../migrations/0005_deduplication.sql") },
+    Migration { version: 6, description: "quotas", sql: include_str!("../../migrations/0006_quotas.sql") },
+    Migration { version: 7, description: "tier pins", sql: include_str!("../../migrations/0007_tier_pins.sql") },
+];
+
+/// Taken with GET_LOCK, which is per server rather than per database
+const LOCK_NAME: &str = "bnuystore_migrations";
+/// How long to wait for another front node to finish migrating
+const LOCK_TIMEOUT_S: u32 = 300;
+
+/// MariaDB's error code for a table which doesn't exist
+const ER_NO_SUCH_TABLE: u16 = 1146;
+
+/// The latest migration which was applied, 0 for none
+async fn applied_version(conn: &mut mysql_async::Conn) -> Result<u32, Error> {
+    match "SELECT COALESCE(MAX(version), 0) FROM schema_version;".first::<u32, _>(conn).await {
+        Ok(version) => Ok(version.unwrap_or(0)),
+        Err(mysql_async::Error::Server(e)) if e.code == ER_NO_SUCH_TABLE => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Versions of the migrations which haven't been applied yet
+#[instrument(level = "debug", skip_all)]
+pub async fn pending(conn_pool: &mysql_async::Pool) -> Result<Vec<u32>, Error> {
+    let mut conn = conn_pool.get_conn().await?;
+    let applied = applied_version(&mut conn).await?;
+    Ok(MIGRATIONS.iter().map(|migration| migration.version).filter(|&version| version > applied).collect())
+}
+
+/// Applies the migrations which haven't been applied yet, and returns their versions
+#[instrument(level = "info", skip_all)]
+pub async fn apply_pending(conn_pool: &mysql_async::Pool) -> Result<Vec<u32>, Error> {
+    // migrations may change the session's sql_mode, so the connection isn't given back to the pool
+    let mut conn = conn_pool.get_conn().await?;
+
+    r#"
+        CREATE TABLE IF NOT EXISTS schema_version (
+            version INT UNSIGNED NOT NULL,
+            description TEXT NOT NULL,
+            applied_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+            PRIMARY KEY (version)
+        );
+    "#.ignore(&mut conn).await?;
+
+    let locked: Option<Option<u32>> = "SELECT GET_LOCK(:name, :timeout);"
+        .with(params! { "name" => LOCK_NAME, "timeout" => LOCK_TIMEOUT_S })
+        .first(&mut conn)
+        .await?;
+    if locked != Some(Some(1)) {
+        return Err(Error::IO(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            format!("another front node held the migration lock for over {LOCK_TIMEOUT_S} seconds"),
+        )));
+    }
+
+    let applied = async {
+        // checked under the lock, as another front node may have just migrated
+        let current = applied_version(&mut conn).await?;
+        let mut applied = Vec::new();
+        for migration in MIGRATIONS.iter().filter(|migration| migration.version > current) {
+            info!(migration.version, migration.description, "Applying migration");
+            conn.query_drop(migration.sql).await?;
+            "INSERT INTO schema_version(version, description) VALUES (:version, :description);"
+                .with(params! { "version" => migration.version, "description" => migration.description })
+                .ignore(&mut conn)
+                .await?;
+            applied.push(migration.version);
+        }
+        Ok::<_, Error>(applied)
+    }.await;
+
+    if let Err(e) = "SELECT RELEASE_LOCK(:name);".with(params! { "name" => LOCK_NAME }).ignore(&mut conn).await {
+        warn!(?e, "Could not release the migration lock. It is released once the connection closes");
+    }
+    if let Err(e) = conn.disconnect().await {
+        debug!(?e, "Error closing the migration connection");
+    }
+
+    let applied = applied?;
+    match applied.as_slice() {
+        [] => debug!("Schema is up to date"),
+        _ => info!(?applied, "Applied migrations"),
+    }
+    Ok(applied)
+}
+
+/// For --migrate-only. Retries like startup does, as the database may still be starting
+pub async fn migrate_only(cfg: &DatabaseConnectionOptions) -> Result<Vec<u32>, Error> {
+    let conn_pool = mysql_async::Pool::new(cfg.mysql_opts().await?);
+    let applied = super::retry_at_startup(cfg, "Applying migrations", || apply_pending(&conn_pool)).await;
+    if let Err(e) = conn_pool.disconnect().await {
+        debug!(?e, "Error disconnecting from the database");
+    }
+    applied
+}
@@ -1,68 +1,458 @@
 #[allow(unused)]
-use tracing::{trace, debug, info, warn, error, instrument, Level};
+use tracing::{trace, debug, info, warn, error, instrument, Level, Span, Instrument};
 
 use mysql_async::prelude::*;
 use uuid::Uuid;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 
 use tokio::sync::RwLock;
+use futures::{channel::mpsc, SinkExt, StreamExt};
 
 pub mod tys;
 pub mod config;
 pub mod storage_node_connection;
+#[cfg(feature = "sftp")]
 pub mod sftp;
+pub mod upload_progress;
+pub mod http_range;
+pub mod startup_summary;
+pub mod listing_json;
+pub mod recursive_listing;
+pub mod recursive_delete;
+pub mod passwords;
+pub mod users;
+pub mod metrics;
+pub mod concurrency;
+pub mod access_log;
+pub mod backup;
+pub mod s3;
+pub mod shutdown;
+pub mod rehash;
+pub mod scrub;
+pub mod forwarding;
+pub mod upload_policy;
+pub mod leader;
+pub mod tiering;
+pub mod quotas;
+pub mod failover;
+pub mod health;
+pub mod node_monitor;
+pub mod migrations;
+pub mod path_cache;
+pub mod trash;
+pub mod versions;
+pub mod deduplication;
+pub mod streaming;
+pub mod content_type;
+#[cfg(feature = "dev-mode")]
+pub mod dev_mode;
+#[cfg(test)]
+pub mod test_support;
 
 use storage_node_connection::StorageNodeConnection;
+use startup_summary::StartupSummary;
 
-use crate::message::Message;
+use crate::message::{self, Message, NodeMetrics};
+use crate::fault_injection::FaultInjector;
+use crate::hashing::{ContentDigest, HashAlgorithm};
 use tys::{StorageNodeID, DirectoryID, Error};
+use versions::Replacement;
 
 pub struct FrontNode {
     #[allow(unused)]
     conn_pool: mysql_async::Pool,
 
     // active_connections has one reference in a task that monitors the nodes table
-    // and tries to spawn/respawn/unspawn connections
+    // and tries to spawn/respawn/unspawn connections, see node_monitor
     #[allow(unused)]
     active_connections: Arc<RwLock<HashMap<StorageNodeID, Arc<StorageNodeConnection>>>>,
+
+    /// whether uploads are verified when the uploader doesn't say otherwise
+    pub verify_uploads_by_default: bool,
+    /// number of uploads where the storage node's view of the file didn't match what we sent.
+    /// these indicate serious node bugs or disk issues
+    pub verification_failures: AtomicU64,
+    /// number of writes the storage node acked with fewer bytes than were sent
+    pub short_writes: AtomicU64,
+    /// for the digests of new files
+    hash_algorithm: HashAlgorithm,
+    /// free space each node must keep after an upload
+    min_free_bytes: u64,
+    /// let uploads share the contents of files with the same digest, see deduplication.rs
+    deduplicate: bool,
+
+    storage_space_cache: std::sync::Mutex<Option<(Instant, HashMap<StorageNodeID, StorageSpace>)>>,
+    /// effective upload policy by directory
+    policy_cache: upload_policy::PolicyCache,
+    /// usage and quota by user name
+    usage_cache: quotas::UsageCache,
+    /// directory and file lookups by path
+    path_cache: path_cache::PathCache,
+
+    retry_policy: failover::RetryPolicy,
+    /// outcomes of storage node operations, for picking which node to try first
+    pub node_health: failover::NodeHealth,
+
+    /// from the nodes table. nodes missing here are in tier 0
+    node_tiers: Arc<std::sync::RwLock<HashMap<StorageNodeID, u32>>>,
+    /// files downloaded since the last time last_accessed was updated
+    accessed_files: Arc<std::sync::Mutex<HashSet<Uuid>>>,
+
+    faults: FaultInjector,
+
+    /// the latest metrics reported by each storage node. nodes which stop answering keep their
+    /// last report
+    node_metrics: Arc<RwLock<HashMap<StorageNodeID, NodeMetrics>>>,
+    /// names of the configured storage nodes, whether connected or not
+    node_names: Arc<std::sync::RwLock<HashMap<StorageNodeID, String>>>,
+    /// sent to the node monitor when the config is reloaded
+    storage_node_configs: tokio::sync::watch::Sender<node_monitor::NodeConfigs>,
+    /// outcome of the latest ping to each storage node
+    node_pings: health::NodePings,
+    scrub_reports: scrub::ScrubReports,
+
+    /// None if backups aren't configured
+    backup: Option<backup::Backup>,
+    /// None if deleted files are deleted right away
+    trash: Option<config::TrashOptions>,
+    /// None if overwritten files aren't kept
+    versioning: Option<config::VersioningOptions>,
+    /// None if files stay on the tier they were uploaded to
+    tiering: Option<config::TieringOptions>,
+    /// tasks started along with the node, by name
+    background_jobs: Vec<(&'static str, tokio::task::JoinHandle<()>)>,
+    /// when in-flight work has to be done by, once shutdown has begun. see shutdown.rs
+    shutdown_deadline: std::sync::Mutex<Option<Instant>>,
+
+    /// shared by the HTTP and SFTP servers
+    pub limits: concurrency::ConcurrencyLimits,
+    pub access_log: access_log::AccessLog,
+    /// for GET /metrics
+    pub metrics: metrics::Metrics,
+}
+
+/// How long the result of storage_space is reused for
+const STORAGE_SPACE_CACHE_TTL: Duration = Duration::from_secs(10);
+
+/// How often files.last_accessed is updated for downloaded files. Updating it on every download
+/// would turn each read into a write
+const ACCESS_FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often storage nodes are asked for their metrics. Nodes list their data folder to answer,
+/// so this shouldn't be too frequent
+const METRICS_POLL_INTERVAL: Duration = Duration::from_secs(15);
+
+/// MySQL's error code for inserting a row which breaks a unique index
+const ER_DUP_ENTRY: u16 = 1062;
+
+/// How long an attached storage node gets to answer Hello
+#[cfg(any(feature = "dev-mode", test))]
+const HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Space summed over all connected storage nodes
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StorageSpace {
+    #[cfg_attr(not(feature = "sftp"), allow(unused))]
+    pub bytes_total: u64,
+    pub bytes_available: u64,
+}
+
+/// How a file is uploaded, as opposed to what is uploaded
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UploadOptions<'a> {
+    /// as sent by the uploader, e.g. a Content-Type header
+    pub content_type: Option<&'a str>,
+    /// hash the written file on the storage node before recording it
+    pub verify: bool,
+    /// replace a file with the same name, rather than failing with Error::FileExists
+    pub overwrite: bool,
+    /// told about each chunk sent to the storage node, see upload_progress.rs
+    pub progress: Option<&'a upload_progress::UploadProgressHandle>,
+    /// the user whose quota the upload counts against, see quotas.rs
+    pub user: Option<&'a str>,
 }
 
+#[derive(Clone)]
 struct UploadFileInfo {
-    #[allow(unused)]
     data_length: usize,
+    /// as sent by the uploader, see content_type::from_upload
+    content_type: Option<String>,
+    overwrite: bool,
+}
+
+/// name, directory_id, node name, size, content_type, hash_algorithm, digest
+type StatRow = (Vec<u8>, DirectoryID, String, Option<u64>, Option<String>, Option<String>, Option<Vec<u8>>);
+
+/// What the files table knows about a file, without asking its storage node
+#[derive(Debug, serde::Serialize)]
+pub struct FileStat {
+    pub uuid: Uuid,
+    pub name: String,
+    pub directory_id: DirectoryID,
+    #[serde(rename = "node")]
+    pub node_name: String,
+    /// None for files uploaded before sizes were stored
+    pub size: Option<u64>,
+    /// when the file was uploaded, in seconds since the unix epoch, read from its v7 UUID. Contents
+    /// never change under a UUID, so this is also when they were last modified. None for other UUIDs
+    pub modified: Option<u64>,
+    /// as uploaded, or else guessed from the name
+    pub content_type: String,
+    /// e.g. `blake3:<hex>`. None for files uploaded before digests were stored
+    pub digest: Option<String>,
+}
+
+/// Whatever is at a path
+#[derive(Debug, serde::Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum PathStat {
+    File(FileStat),
+    Directory { id: DirectoryID },
 }
 
 pub struct GetFileInfo {
     pub uuid: Uuid,
     pub node_name: String,
+    /// of the whole file, also when only a range was read
+    pub file_size: u64,
+    /// stored with the file, or guessed from its name
+    pub content_type: String,
+    /// hex SHA-256 of the whole file, if that's the digest stored for it
+    pub sha256: Option<String>,
+}
+
+// see file_location
+struct FileLocation {
+    node: StorageNodeID,
+    node_name: String,
+    /// what the contents are stored under on the node, see deduplication.rs
+    blob: Uuid,
+    content_type: String,
+    sha256: Option<String>,
 }
 
-#[derive(serde::Serialize)]
-pub struct DirectoryListing {
-    file_uuids_and_names: Vec<(Uuid, String)>,
-    directory_ids_and_names: Vec<(DirectoryID, String)>,
+/// stored_on_node_id, node name, name, content_type, contents' UUID, hash_algorithm, digest
+type LocationRow = (StorageNodeID, String, Vec<u8>, Option<String>, Uuid, Option<String>, Option<Vec<u8>>);
+
+/// One entry in a directory listing
+#[derive(Debug)]
+pub enum ListingRow {
+    /// the size is None for files uploaded before sizes were stored
+    File(Uuid, String, Option<u64>),
+    Directory(DirectoryID, String),
+    /// a file in the trash, with the name it had and when it was deleted, in seconds since the unix
+    /// epoch. only listed with include_deleted
+    DeletedFile(Uuid, String, Option<u64>, u64),
 }
 
 impl FrontNode {
     pub async fn start_from_config(
-        cfg: &config::Config
+        cfg: &config::Config,
+        summary: &mut StartupSummary,
     ) -> Result<FrontNode, Error> {
-        let connection_options = cfg.database_connection.mysql_opts().await;
+        FrontNode::start_with_faults(cfg, summary, FaultInjector::default()).await
+    }
+
+    /// The fault injector is also given to all storage node connections
+    pub async fn start_with_faults(
+        cfg: &config::Config,
+        summary: &mut StartupSummary,
+        faults: FaultInjector,
+    ) -> Result<FrontNode, Error> {
+        let connection_options = cfg.database_connection.mysql_opts().await?;
         trace!("Opening database connection");
         let conn_pool = mysql_async::Pool::new(connection_options);
 
+        summary.database_target = cfg.database_connection.describe();
+        let auto_migrate = cfg.database_connection.auto_migrate;
+        let migrations = match auto_migrate {
+            true => retry_at_startup(&cfg.database_connection, "Applying migrations", || migrations::apply_pending(&conn_pool)).await,
+            false => retry_at_startup(&cfg.database_connection, "Querying the database", || migrations::pending(&conn_pool)).await,
+        };
+        match migrations {
+            Ok(versions) if versions.is_empty() => {}
+            Ok(applied) if auto_migrate => summary.features.push(format!("applied schema migrations {applied:?}")),
+            Ok(pending) => summary.warnings.push(format!(
+                "schema migrations {pending:?} are pending. Apply them with --migrate-only, or set database_connection.auto_migrate",
+            )),
+            Err(e) => {
+                // the server answered, just not with success
+                summary.database_reachable = Some(matches!(e, Error::Database(mysql_async::Error::Server(_))));
+                return Err(e);
+            }
+        }
+        let root_query = "SELECT count(*) FROM root_directory;";
+        let root_count = retry_at_startup(&cfg.database_connection, "Querying the database", || async {
+            Ok(root_query.first::<u64, _>(&conn_pool).await?)
+        }).await;
+        match root_count {
+            Ok(count) => {
+                summary.database_reachable = Some(true);
+                summary.root_directory_present = Some(count.unwrap_or(0) > 0);
+            }
+            Err(e) => {
+                summary.database_reachable = Some(false);
+                return Err(e);
+            }
+        }
+        if summary.root_directory_present == Some(false) {
+            if create_root_directory(&conn_pool).await? {
+                summary.warnings.push("root_directory was empty; set up a root directory".to_string());
+            }
+            summary.root_directory_present = Some(true);
+        }
+
+        if cfg.http_server.debug_errors {
+            summary.warnings.push("http_server.debug_errors is on; internal error details are sent to clients".to_string());
+        }
+        if cfg.uploads.verify {
+            summary.features.push("upload verification".to_string());
+        }
+        if cfg.uploads.deduplicate {
+            summary.features.push("deduplication".to_string());
+        }
+
         let active_connections = Arc::new(RwLock::new(HashMap::new()));
 
-        let _monitor_task = tokio::spawn(monitor_connections(conn_pool.clone(), active_connections.clone(), cfg.clone()));
+        // safe to run again if it fails partway, as every step checks the table first
+        retry_at_startup(&cfg.database_connection, "Updating the nodes table", || {
+            node_monitor::sync_nodes_table(&conn_pool, &cfg.storage_nodes)
+        }).await?;
+        let mut monitor = node_monitor::NodeMonitor::new(conn_pool.clone(), active_connections.clone(), faults.clone(), cfg.storage_nodes.clone());
+        debug!("Spawning connections to all nodes");
+        summary.storage_nodes = monitor.reconcile().await?;
+        let node_tiers = monitor.node_tiers.clone();
+        let node_names = monitor.node_names.clone();
+
+        let access_log = match cfg.access_log {
+            Some(ref access_log_cfg) => {
+                let access_log = access_log::AccessLog::start(access_log_cfg.clone()).await.map_err(|e| {
+                    error!(?e, path = %access_log_cfg.path.display(), "Could not open access log");
+                    e
+                })?;
+                summary.features.push(format!("access log at {}", access_log_cfg.path.display()));
+                access_log
+            }
+            None => access_log::AccessLog::disabled(),
+        };
+
+        let accessed_files = Arc::new(std::sync::Mutex::new(HashSet::new()));
+        let mut background_jobs = Vec::new();
+        let (storage_node_configs, reloads) = tokio::sync::watch::channel(cfg.storage_nodes.clone());
+        let poll_interval = Duration::from_secs(cfg.node_monitor.poll_interval_s);
+        background_jobs.push(("monitor_connections", tokio::spawn(monitor.run(poll_interval, reloads))));
+        background_jobs.push(("flush_accessed_files", tokio::spawn(flush_accessed_files(conn_pool.clone(), accessed_files.clone()))));
+
+        let node_metrics = Arc::new(RwLock::new(HashMap::new()));
+        background_jobs.push(("poll_node_metrics", tokio::spawn(poll_node_metrics(active_connections.clone(), node_metrics.clone()))));
+
+        let node_pings = Arc::new(RwLock::new(HashMap::new()));
+        background_jobs.push(("ping_nodes", tokio::spawn(health::ping_nodes(active_connections.clone(), node_pings.clone()))));
+
+        let backup = match cfg.backup {
+            Some(ref backup_cfg) => {
+                let backup = backup::Backup::start(backup_cfg, conn_pool.clone(), active_connections.clone()).map_err(|e| {
+                    error!(?e, "Could not set up the backup sink");
+                    e
+                })?;
+                summary.features.push("backups".to_string());
+                Some(backup)
+            }
+            None => None,
+        };
+        if let Some(ref trash) = cfg.trash {
+            summary.features.push(format!("trash, kept for {}s", trash.retention_s));
+        }
+        if let Some(ref versioning) = cfg.versioning {
+            summary.features.push(format!("file versioning, keeping {} old versions", versioning.keep_versions));
+        }
+        if let Some(ref tiering) = cfg.tiering {
+            summary.features.push(format!("tiering, moving files unread for {}s to slower tiers", tiering.cold_after_s));
+        }
 
         Ok(FrontNode {
             conn_pool,
             active_connections,
+            verify_uploads_by_default: cfg.uploads.verify,
+            verification_failures: AtomicU64::new(0),
+            short_writes: AtomicU64::new(0),
+            hash_algorithm: cfg.uploads.hash_algorithm,
+            min_free_bytes: cfg.uploads.min_free_bytes,
+            deduplicate: cfg.uploads.deduplicate,
+            storage_space_cache: std::sync::Mutex::new(None),
+            policy_cache: std::sync::Mutex::new(HashMap::new()),
+            usage_cache: std::sync::Mutex::new(HashMap::new()),
+            path_cache: path_cache::PathCache::new(cfg.path_cache.clone()),
+            retry_policy: failover::RetryPolicy::from_config(&cfg.failover),
+            node_health: failover::NodeHealth::default(),
+            node_tiers,
+            accessed_files,
+            faults,
+            node_metrics,
+            node_names,
+            storage_node_configs,
+            node_pings,
+            scrub_reports: std::sync::Mutex::new(HashMap::new()),
+            backup,
+            trash: cfg.trash.clone(),
+            versioning: cfg.versioning.clone(),
+            tiering: cfg.tiering.clone(),
+            background_jobs,
+            shutdown_deadline: std::sync::Mutex::new(None),
+            limits: concurrency::ConcurrencyLimits::new(cfg.concurrency_limits.clone()),
+            access_log,
+            metrics: metrics::Metrics::default(),
         })
     }
 
+    // all metadata queries go through here, so query faults can be injected
+    fn pool(&self) -> Result<&mysql_async::Pool, Error> {
+        if self.faults.query_should_fail() {
+            return Err(Error::IO(std::io::Error::other("injected query fault")));
+        }
+        Ok(&self.conn_pool)
+    }
+
+    /// Replaces the configured storage nodes, e.g. after the config file changed. The nodes table is
+    /// synced, and connections are made or dropped, in the background
+    pub fn reload_storage_nodes(&self, nodes: node_monitor::NodeConfigs) {
+        self.storage_node_configs.send_replace(nodes);
+    }
+
+    /// Adds a connection to a storage node which isn't in the config, e.g. one running in-process.
+    /// The node is added to the nodes table if it isn't there already
+    #[cfg(any(feature = "dev-mode", test))]
+    #[instrument(level = "info", skip(self, conn))]
+    pub async fn attach_connection(
+        &self,
+        name: &str,
+        conn: StorageNodeConnection,
+    ) -> Result<StorageNodeID, Error> {
+        let query = "SELECT id FROM nodes WHERE name = :name;";
+        let id: Option<StorageNodeID> = query.with(params! { "name" => name }).first(self.pool()?).await?;
+        let id = match id {
+            Some(id) => id,
+            None => {
+                debug!("Not in nodes table; inserting");
+                let insert_query = "INSERT INTO nodes(name) VALUES (:name);";
+                insert_query.with(params! { "name" => name }).ignore(self.pool()?).await?;
+                query.with(params! { "name" => name })
+                    .first(self.pool()?)
+                    .await?
+                    .expect("Node not in nodes table after insertion")
+            }
+        };
+
+        conn.hello(HELLO_TIMEOUT).await?;
+        self.active_connections.write().await.insert(id, Arc::new(conn));
+        self.node_names.write().unwrap().insert(id, name.to_string());
+        Ok(id)
+    }
+
     // path should NOT have a starting slash
     // base == None selects the root directory
     #[instrument(level = "trace", skip(self))]
@@ -71,49 +461,88 @@ impl FrontNode {
         path: &str,
         base: Option<DirectoryID>,
     ) -> Result<DirectoryID, Error> {
-        let base = match base {
-            Some(base) => base,
-            None => {
-                let root_query = r#"SELECT directory_id FROM root_directory"#;
-                root_query
-                    .first(&self.conn_pool)
-                    .await?
-                    .expect("root_directory table is empty")
+        let key = (base, path.to_string());
+        let generation = match self.path_cache.directory(&key) {
+            Ok(cached) => {
+                trace!(?cached, "Cached");
+                return cached.map_err(|topmost_existing_directory| Error::NoSuchDirectory { topmost_existing_directory });
             }
+            Err(generation) => generation,
         };
-
-        if path.len() == 0 {
-            return Ok(base);
+        let resolved = self.resolve_directory(path, base).await;
+        match resolved {
+            Ok(dir) => self.path_cache.insert_directory(key, Ok(dir), generation),
+            Err(Error::NoSuchDirectory { ref topmost_existing_directory }) => {
+                self.path_cache.insert_directory(key, Err(topmost_existing_directory.clone()), generation);
+            }
+            Err(_) => {}
         }
+        resolved
+    }
 
-        let mut current_directory = base;
+    // the path is followed in a single query, as SFTP clients resolve paths constantly
+    async fn resolve_directory(&self, path: &str, base: Option<DirectoryID>) -> Result<DirectoryID, Error> {
+        let segments: Vec<&str> = match (path.len(), base) {
+            (0, Some(base)) => return Ok(base),
+            (0, None) => Vec::new(),
+            _ => path.split('/').collect(),
+        };
 
-        let mut topmost_existing_directory = String::new();
+        // walks down one segment per step, so the deepest row is as far as the path exists.
+        // segment n is the nth part of the path, split on /
+        let query = r#"
+            WITH RECURSIVE walk (depth, id) AS (
+                SELECT CAST(0 AS UNSIGNED), COALESCE(:base, (SELECT directory_id FROM root_directory))
+                UNION ALL
+                SELECT walk.depth + 1, directories.id
+                FROM walk JOIN directories ON directories.parent_id = walk.id
+                WHERE walk.depth < :n_segments
+                    AND directories.name = SUBSTRING_INDEX(SUBSTRING_INDEX(:path, '/', walk.depth + 1), '/', -1)
+            )
+            SELECT depth, id FROM walk ORDER BY depth DESC LIMIT 1;
+        "#;
+        let (depth, deepest): (usize, Option<DirectoryID>) = query
+            .with(params! { "base" => base, "n_segments" => segments.len(), "path" => path })
+            .first(self.pool()?)
+            .await?
+            .expect("path resolution returns at least the base");
+        let Some(deepest) = deepest else {
+            error!("root_directory table is empty");
+            return Err(Error::DatabaseInconsistent { detail: "root_directory table is empty".to_string() });
+        };
+        trace!(depth, ?deepest, "Followed");
 
-        for segment in path.split('/') {
-            trace!(?segment, ?current_directory, "Following");
+        if depth == segments.len() {
+            return Ok(deepest);
+        }
+        debug!(segment = segments[depth], "Not found");
+        let topmost_existing_directory = segments[..depth].iter().map(|segment| format!("{segment}/")).collect();
+        Err(Error::NoSuchDirectory { topmost_existing_directory })
+    }
 
-            current_directory = {
-                let subdir_query = r#"
-                    SELECT id FROM directories WHERE name = :segment AND parent_id = :current_directory;
-                "#;
-                let next_directory = subdir_query
-                    .with(params! { "segment" => segment, "current_directory" => current_directory })
-                    .first(&self.conn_pool)
-                    .await?;
+    /// Like directory_id_for_path, but the directories which don't exist are created, like mkdir -p
+    #[instrument(level = "info", skip(self))]
+    pub async fn create_directories(
+        &self,
+        path: &str,
+        base: Option<DirectoryID>,
+    ) -> Result<DirectoryID, Error> {
+        let topmost_existing_directory = match self.directory_id_for_path(path, base).await {
+            Ok(dir) => return Ok(dir),
+            Err(Error::NoSuchDirectory { topmost_existing_directory }) => topmost_existing_directory,
+            Err(e) => return Err(e),
+        };
 
-                if let Some(next_directory) = next_directory {
-                    topmost_existing_directory.push_str(&segment);
-                    topmost_existing_directory.push('/');
-                    trace!(?next_directory, "Found");
-                    next_directory
-                } else {
-                    debug!("Not found");
-                    return Err(Error::NoSuchDirectory { topmost_existing_directory });
-                }
+        let existing_path = topmost_existing_directory.strip_suffix('/').unwrap_or_default();
+        let mut current_directory = self.directory_id_for_path(existing_path, base).await?;
+        for segment in path[topmost_existing_directory.len()..].split('/') {
+            current_directory = match self.create_directory(current_directory, segment.to_string()).await {
+                Ok(created) => created,
+                // created by someone else since it was looked up
+                Err(Error::AlreadyExists) => self.directory_id_for_path(segment, Some(current_directory)).await?,
+                Err(e) => return Err(e),
             };
         }
-
         Ok(current_directory)
     }
 
@@ -131,6 +560,15 @@ impl FrontNode {
 
         trace!(?path, ?file, "Split file from parent");
 
+        let key = (base, full_path.to_string());
+        let generation = match self.path_cache.file(&key) {
+            Ok(cached) => {
+                trace!(?cached, "Cached");
+                return cached.ok_or(Error::NoSuchFile);
+            }
+            Err(generation) => generation,
+        };
+
         let dir = self.directory_id_for_path(&path, base).await?;
         trace!(?dir, "Found directory");
 
@@ -140,17 +578,15 @@ impl FrontNode {
                 WHERE files.name = :filename AND directory_id = :dir;
             "#;
 
-        if let Some(uuid) = query
+        let uuid: Option<Uuid> = query
             .with(params!("filename" => file, "dir" => dir))
-            .first(&self.conn_pool)
-            .await?
-        {
-            Ok(uuid)
-        } else {
-            Err(Error::NoSuchFile)
-        }
+            .first(self.pool()?)
+            .await?;
+        self.path_cache.insert_file(key, uuid, generation);
+        uuid.ok_or(Error::NoSuchFile)
     }
 
+    #[cfg_attr(not(feature = "sftp"), allow(unused))]
     #[instrument(level = "trace", skip(self))]
     pub async fn home_for_user(
         &self,
@@ -164,7 +600,7 @@ impl FrontNode {
 
         if let Some(id) = query
             .with(params! { "name" => name })
-            .first(&self.conn_pool)
+            .first(self.pool()?)
             .await?
         {
             Ok(id)
@@ -173,201 +609,1559 @@ impl FrontNode {
         }
     }
 
-    // None = file not found
-    // TODO: Add NoSuchFile to Error?
+    /// The user whose HTTP token this is, and their home directory. Tokens are stored as SHA-256
+    /// digests in users.http_token_sha256, so they can be looked up without comparing secrets
+    #[instrument(level = "trace", skip(self, token))]
+    pub async fn user_for_http_token(&self, token: &str) -> Result<Option<(String, DirectoryID)>, Error> {
+        let digest = ContentDigest::of(HashAlgorithm::Sha256, token.as_bytes());
+        let query = "SELECT username, home_directory FROM users WHERE http_token_sha256 = :digest;";
+        Ok(query.with(params! { "digest" => digest.bytes }).first(self.pool()?).await?)
+    }
+
+    /// Whether a file is in `dir` or below it. Old versions are where their current file is, and
+    /// files in the trash where they were deleted from. False if there is no such file
+    #[instrument(level = "trace", skip(self))]
+    pub async fn file_is_under(&self, uuid: Uuid, dir: DirectoryID) -> Result<bool, Error> {
+        let query = r#"
+            WITH RECURSIVE ancestors (id) AS (
+                SELECT COALESCE(files.deleted_from_directory_id, files.directory_id) FROM files
+                    WHERE files.uuid = COALESCE((SELECT current_uuid FROM file_versions WHERE uuid = :uuid), :uuid)
+                UNION ALL
+                SELECT directories.parent_id FROM ancestors JOIN directories ON directories.id = ancestors.id
+                    WHERE directories.parent_id IS NOT NULL
+            )
+            SELECT EXISTS(SELECT * FROM ancestors WHERE id = :dir);
+        "#;
+        let under: Option<bool> = query.with(params! { "uuid" => uuid, "dir" => dir }).first(self.pool()?).await?;
+        Ok(under.unwrap_or(false))
+    }
+
+    /// The stored size of a file. None for files uploaded before sizes were stored
+    #[instrument(level = "trace", skip(self))]
+    pub async fn file_size(&self, uuid: Uuid) -> Result<Option<u64>, Error> {
+        let query = "SELECT size FROM files WHERE uuid = :uuid;";
+        let size: Option<Option<u64>> = query.with(params! { "uuid" => uuid }).first(self.pool()?).await?;
+        size.ok_or(Error::NoSuchFile)
+    }
+
+    /// The node a file is stored on and its name, what the contents are stored under there, and the
+    /// file's content type and SHA-256
+    async fn file_location(&self, uuid: Uuid) -> Result<FileLocation, Error> {
+        let query = r#"
+            SELECT files.stored_on_node_id, nodes.name, files.name, files.content_type,
+                   COALESCE(files.blob_uuid, files.uuid), files.hash_algorithm, files.digest
+                FROM files INNER JOIN nodes ON files.stored_on_node_id = nodes.id
+                WHERE files.uuid = :uuid
+            "#;
+
+        let Some((id, node_name, filename, content_type, blob, algorithm, digest)): Option<LocationRow> = query
+            .with(params! { "uuid" => uuid })
+            .first(self.pool()?)
+            .await?
+        else {
+            return Err(Error::UnknownUUID);
+        };
+        trace!(?id, ?node_name, %blob, ?content_type, "Found file");
+        let content_type = content_type
+            .unwrap_or_else(|| content_type::guess(&String::from_utf8_lossy(&filename)).to_string());
+        let sha256 = rehash::stored_digest(algorithm, digest)
+            .filter(|digest| digest.algorithm == HashAlgorithm::Sha256)
+            .map(|digest| digest.to_hex());
+        Ok(FileLocation { node: id, node_name, blob, content_type, sha256 })
+    }
+
+    /// Like SFTP, a directory is preferred over a file with the same path. If there is neither,
+    /// Error::NoSuchDirectory says how far the path resolved
     #[instrument(level = "debug", skip(self))]
-    pub async fn get_file(
-        &self,
-        uuid: Uuid,
-    ) -> Result<(Vec<u8>, GetFileInfo), Error> {
+    pub async fn stat_path(&self, path: &str, base: Option<DirectoryID>) -> Result<PathStat, Error> {
+        let topmost_existing_directory = match self.directory_id_for_path(path, base).await {
+            Ok(id) => return Ok(PathStat::Directory { id }),
+            Err(Error::NoSuchDirectory { topmost_existing_directory }) => topmost_existing_directory,
+            Err(e) => return Err(e),
+        };
+        match self.file_uuid_for_path(path, base).await {
+            Ok(uuid) => Ok(PathStat::File(self.file_stat(uuid).await?)),
+            Err(Error::NoSuchDirectory { .. } | Error::NoSuchFile) => Err(Error::NoSuchDirectory { topmost_existing_directory }),
+            Err(e) => Err(e),
+        }
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    pub async fn file_stat(&self, uuid: Uuid) -> Result<FileStat, Error> {
         let query = r#"
-            SELECT files.stored_on_node_id, nodes.name
+            SELECT files.name, files.directory_id, nodes.name, files.size, files.content_type, files.hash_algorithm, files.digest
                 FROM files INNER JOIN nodes ON files.stored_on_node_id = nodes.id
                 WHERE files.uuid = :uuid
             "#;
 
-        let Some((id, node_name)) = query
+        let Some((name, directory_id, node_name, size, content_type, algorithm, digest)): Option<StatRow> = query
             .with(params! { "uuid" => uuid })
-            .first(&self.conn_pool)
+            .first(self.pool()?)
             .await?
         else {
             return Err(Error::UnknownUUID);
         };
-        trace!(?id, ?node_name, "Found file");
+        let name = String::from_utf8_lossy(&name).into_owned();
+        Ok(FileStat {
+            uuid,
+            directory_id,
+            node_name,
+            size,
+            modified: uuid.get_timestamp().map(|timestamp| timestamp.to_unix().0),
+            content_type: content_type.unwrap_or_else(|| content_type::guess(&name).to_string()),
+            digest: rehash::stored_digest(algorithm, digest).map(|digest| digest.to_string()),
+            name,
+        })
+    }
 
-        let conn = {
-            let active_connections = self.active_connections.read().await;
-            match active_connections.get(&id) {
-                Some(conn) => conn.clone(),
-                None => return Err(Error::NotConnectedToNode),
-            }
+    // None = file not found
+    // TODO: Add NoSuchFile to Error?
+    #[instrument(level = "debug", skip(self))]
+    pub async fn get_file(
+        &self,
+        uuid: Uuid,
+    ) -> Result<(Vec<u8>, GetFileInfo), Error> {
+        let location = self.file_location(uuid).await?;
+        let blob = location.blob;
+
+        // files are only stored on one node, so failing over means retrying it
+        let (contents, _) = self.with_node_failover(vec![location.node], |_, conn| async move {
+            conn.read_file(blob).await
+        }).await?;
+
+        self.accessed_files.lock().unwrap().insert(uuid);
+        let info = GetFileInfo {
+            uuid,
+            node_name: location.node_name,
+            file_size: contents.len() as u64,
+            content_type: location.content_type,
+            sha256: location.sha256,
         };
+        Ok((contents, info))
+    }
 
-        match conn.communicate(Message::ReadFile(uuid)).await? {
-            Message::FileContents(c) => {
-                let info = GetFileInfo {
-                    uuid,
-                    node_name,
-                };
-                Ok((c, info))
+    /// Reads at most `len` bytes from `offset`, without sending the rest of the file over from the
+    /// storage node. Reading at or past the end is Error::ReadPastEnd
+    #[instrument(level = "debug", skip(self))]
+    pub async fn read_file_range(
+        &self,
+        uuid: Uuid,
+        offset: u64,
+        len: u32,
+    ) -> Result<(Vec<u8>, GetFileInfo), Error> {
+        let location = self.file_location(uuid).await?;
+        let blob = location.blob;
+
+        let ((data, file_size), _) = self.with_node_failover(vec![location.node], |_, conn| async move {
+            // nodes without ranges send the whole file, which is cut down here instead
+            if !conn.has_feature(message::FEATURE_READ_RANGE) {
+                let mut data = conn.read_file(blob).await?;
+                let file_size = data.len() as u64;
+                let mut data = data.split_off(offset.min(file_size) as usize);
+                data.truncate(len as usize);
+                return Ok((data, file_size));
             }
-            x => Err(Error::UnexpectedResponse(x))
+            match conn.request(Message::ReadFileRange(blob, offset, len)).await? {
+                Message::FileRange { file_size, data } => Ok((data, file_size)),
+                x => Err(Error::UnexpectedResponse(x)),
+            }
+        }).await?;
+
+        if offset >= file_size {
+            return Err(Error::ReadPastEnd { file_size });
         }
+
+        self.accessed_files.lock().unwrap().insert(uuid);
+        let info = GetFileInfo {
+            uuid,
+            node_name: location.node_name,
+            file_size,
+            content_type: location.content_type,
+            sha256: location.sha256,
+        };
+        Ok((data, info))
     }
 
+    /// Runs `attempt` against nodes from the placement according to the retry policy, see failover.rs.
+    /// Nodes which aren't connected count as failed attempts
+    pub async fn with_node_failover<T, F, Fut>(
+        &self,
+        placement: Vec<StorageNodeID>,
+        mut attempt: F,
+    ) -> Result<(T, StorageNodeID), Error>
+    where
+        F: FnMut(StorageNodeID, Arc<StorageNodeConnection>) -> Fut,
+        Fut: std::future::Future<Output = Result<T, Error>>,
+    {
+        let connections: HashMap<StorageNodeID, Arc<StorageNodeConnection>> = {
+            let active_connections = self.active_connections.read().await;
+            placement.iter()
+                .filter_map(|id| Some((*id, active_connections.get(id)?.clone())))
+                .collect()
+        };
+
+        failover::with_failover(&self.retry_policy, &self.node_health, placement, |id| {
+            let attempt = connections.get(&id).map(|conn| attempt(id, conn.clone()));
+            async move {
+                match attempt {
+                    Some(attempt) => attempt.await,
+                    None => Err(Error::NotConnectedToNode),
+                }
+            }
+        }).await
+    }
+
+    /// Lists a directory without holding the whole listing in memory. The rows are fetched by a
+    /// separate task using streaming queries, and at most `buffer_rows` of them are queued at a time.
+    /// All files are listed before any directories. If listing fails, the error is the last item.
+    /// Dropping the receiver stops the listing
     #[instrument(level = "debug", skip(self))]
-    pub async fn list_directory(
+    pub fn list_directory(
         &self,
         dir: DirectoryID,
-    ) -> Result<DirectoryListing, Error> {
-        let query_files = r#"
-            SELECT uuid, name FROM files
-                WHERE directory_id = :dir;
-            "#;
+        buffer_rows: usize,
+    ) -> mpsc::Receiver<Result<ListingRow, Error>> {
+        let (mut tx, rx) = mpsc::channel(buffer_rows);
+        let pool = self.pool().cloned();
 
-        let query_dirs = r#"
-            SELECT id, name FROM directories
-                WHERE parent_id = :dir;
-            "#;
+        tokio::spawn(async move {
+            let result = async {
+                let mut conn = pool?.get_conn().await?;
 
-        let file_uuids_and_names: Vec<(Uuid, String)> = query_files.with(params! { "dir" => &dir })
-            .fetch(&self.conn_pool)
-            .await?;
+                let query_files = r#"
+                    SELECT uuid, name, size FROM files
+                        WHERE directory_id = :dir;
+                    "#;
+                let mut files = query_files.with(params! { "dir" => &dir })
+                    .stream::<(Uuid, String, Option<u64>), _>(&mut conn)
+                    .await?;
+                let mut n_files = 0;
+                while let Some(row) = files.next().await {
+                    let (uuid, name, size) = row?;
+                    if tx.send(Ok(ListingRow::File(uuid, name, size))).await.is_err() {
+                        trace!("Receiver dropped, stopping listing");
+                        return Ok(());
+                    }
+                    n_files += 1;
+                }
+                drop(files);
 
-        let directory_ids_and_names: Vec<(DirectoryID, String)> = query_dirs.with(params! { "dir" => &dir })
-            .fetch(&self.conn_pool)
-            .await?;
+                let query_dirs = r#"
+                    SELECT id, name FROM directories
+                        WHERE parent_id = :dir;
+                    "#;
+                let mut dirs = query_dirs.with(params! { "dir" => &dir })
+                    .stream::<(DirectoryID, String), _>(&mut conn)
+                    .await?;
+                let mut n_dirs = 0;
+                while let Some(row) = dirs.next().await {
+                    let (dir_id, name) = row?;
+                    if tx.send(Ok(ListingRow::Directory(dir_id, name))).await.is_err() {
+                        trace!("Receiver dropped, stopping listing");
+                        return Ok(());
+                    }
+                    n_dirs += 1;
+                }
+
+                trace!(n_files, n_dirs, "Listed contents");
+                Ok::<(), Error>(())
+            }.await;
+
+            if let Err(e) = result {
+                error!(?e, "Error listing directory");
+                let _ = tx.send(Err(e)).await;
+            }
+        }.instrument(Span::current()));
+
+        rx
+    }
+
+    /// Like list_directory, but files and directories are listed together, ordered by name, and only
+    /// `limit` entries starting at `offset` are listed. With `include_deleted`, files deleted from the
+    /// directory which are still in the trash are listed too
+    #[instrument(level = "debug", skip(self))]
+    pub fn list_directory_page(
+        &self,
+        dir: DirectoryID,
+        offset: u64,
+        limit: Option<u64>,
+        include_deleted: bool,
+        buffer_rows: usize,
+    ) -> mpsc::Receiver<Result<ListingRow, Error>> {
+        let (mut tx, rx) = mpsc::channel(buffer_rows);
+        let pool = self.pool().cloned();
+
+        tokio::spawn(async move {
+            let result = async {
+                let mut conn = pool?.get_conn().await?;
+
+                // files.name is a BLOB, so names sort by their bytes. ties are broken by kind and then
+                // id, so pages neither overlap nor skip entries
+                let deleted = match include_deleted {
+                    true => r#"
+                        UNION ALL
+                        SELECT 'deleted' AS kind, uuid, NULL AS id, deleted_name AS name, size, UNIX_TIMESTAMP(deleted_at) FROM files
+                            WHERE deleted_from_directory_id = :dir AND deleted_at IS NOT NULL
+                        "#,
+                    false => "",
+                };
+                let query = format!(r#"
+                    SELECT kind, uuid, id, name, size, deleted_at FROM (
+                        SELECT 'file' AS kind, uuid, NULL AS id, name, size, NULL AS deleted_at FROM files
+                            WHERE directory_id = :dir
+                        UNION ALL
+                        SELECT 'directory' AS kind, NULL AS uuid, id, name, NULL AS size, NULL AS deleted_at FROM directories
+                            WHERE parent_id = :dir
+                        {deleted}
+                    ) AS entries
+                        ORDER BY name, kind, uuid, id
+                        LIMIT :limit OFFSET :offset;
+                    "#);
+                let mut entries = query.with(params! {
+                    "dir" => &dir,
+                    // as large as MySQL takes, for no limit
+                    "limit" => limit.unwrap_or(u64::MAX),
+                    "offset" => offset,
+                }).stream::<(String, Option<Uuid>, Option<DirectoryID>, String, Option<u64>, Option<u64>), _>(&mut conn).await?;
+
+                let mut n_entries = 0;
+                while let Some(row) = entries.next().await {
+                    let row = match row? {
+                        (_, Some(uuid), _, name, size, Some(deleted_at)) => ListingRow::DeletedFile(uuid, name, size, deleted_at),
+                        (_, Some(uuid), _, name, size, None) => ListingRow::File(uuid, name, size),
+                        (_, None, Some(dir_id), name, _, _) => ListingRow::Directory(dir_id, name),
+                        (kind, None, None, name, _, _) => {
+                            warn!(kind, name, "Listed entry with neither UUID nor id");
+                            continue;
+                        }
+                    };
+                    if tx.send(Ok(row)).await.is_err() {
+                        trace!("Receiver dropped, stopping listing");
+                        return Ok(());
+                    }
+                    n_entries += 1;
+                }
 
-        trace!(file_uuids_and_names.len = file_uuids_and_names.len(), directory_ids_and_names.len = directory_ids_and_names.len(), "Listed contents");
+                trace!(n_entries, "Listed page of contents");
+                Ok::<(), Error>(())
+            }.await;
 
-        Ok(DirectoryListing { file_uuids_and_names, directory_ids_and_names })
+            if let Err(e) = result {
+                error!(?e, "Error listing directory");
+                let _ = tx.send(Err(e)).await;
+            }
+        }.instrument(Span::current()));
+
+        rx
     }
 
+    /// Error::AlreadyExists if the parent already has a directory with that name
     #[instrument(level = "info", skip(self))]
     pub async fn create_directory(
         &self,
         parent: DirectoryID,
         dir_name: String,
-    ) -> Result<(), Error> {
+    ) -> Result<DirectoryID, Error> {
+        if dir_name.is_empty() || dir_name.contains('/') {
+            return Err(Error::InvalidName { name: dir_name });
+        }
+
         let query = r#"
             INSERT INTO directories
                 (name, parent_id) VALUES
                 (:dir_name, :parent);
         "#;
 
-        query
-            .with(params! { "dir_name" => dir_name, "parent" => parent })
-            .ignore(&self.conn_pool)
+        let mut conn = self.pool()?.get_conn().await?;
+        match query.with(params! { "dir_name" => dir_name, "parent" => parent }).ignore(&mut conn).await {
+            Ok(()) => {}
+            Err(mysql_async::Error::Server(e)) if e.code == ER_DUP_ENTRY => {
+                // created elsewhere, so the cache may say it doesn't exist
+                self.path_cache.invalidate_directories();
+                return Err(Error::AlreadyExists);
+            }
+            Err(e) => return Err(e.into()),
+        }
+        let id = conn.last_insert_id().expect("directories.id is AUTO_INCREMENT");
+        self.path_cache.invalidate_directories();
+        Ok(DirectoryID(id as i64))
+    }
+
+    /// Removes an empty directory, and its upload policy if it has one. Error::DirectoryIsHome if
+    /// it's a user's home directory
+    #[instrument(level = "info", skip(self))]
+    pub async fn remove_directory(&self, dir: DirectoryID) -> Result<(), Error> {
+        let mut transaction = self.pool()?.start_transaction(mysql_async::TxOpts::default()).await?;
+
+        let query = "SELECT parent_id FROM directories WHERE id = :dir FOR UPDATE;";
+        let parent: Option<Option<DirectoryID>> = query.with(params! { "dir" => dir }).first(&mut transaction).await?;
+        match parent {
+            None => return Err(Error::NoSuchDirectory { topmost_existing_directory: String::new() }),
+            Some(None) => return Err(Error::CannotRemoveRoot),
+            Some(Some(_)) => {}
+        }
+
+        // anything added after this check makes the delete fail on the foreign keys
+        let query = r#"
+            SELECT EXISTS(SELECT * FROM files WHERE directory_id = :dir)
+                OR EXISTS(SELECT * FROM directories WHERE parent_id = :dir);
+        "#;
+        let not_empty: Option<bool> = query.with(params! { "dir" => dir }).first(&mut transaction).await?;
+        if not_empty.unwrap_or(false) {
+            return Err(Error::DirectoryNotEmpty);
+        }
+        let query = "SELECT EXISTS(SELECT * FROM users WHERE home_directory = :dir);";
+        let is_home: Option<bool> = query.with(params! { "dir" => dir }).first(&mut transaction).await?;
+        if is_home.unwrap_or(false) {
+            return Err(Error::DirectoryIsHome);
+        }
+
+        "DELETE FROM upload_policies WHERE directory_id = :dir;"
+            .with(params! { "dir" => dir })
+            .ignore(&mut transaction)
+            .await?;
+        "DELETE FROM tier_pins WHERE directory_id = :dir;"
+            .with(params! { "dir" => dir })
+            .ignore(&mut transaction)
             .await?;
+        "DELETE FROM directories WHERE id = :dir;"
+            .with(params! { "dir" => dir })
+            .ignore(&mut transaction)
+            .await?;
+        transaction.commit().await?;
+        self.invalidate_upload_policies();
+        self.path_cache.invalidate_directories();
         Ok(())
     }
 
-    async fn get_appropriate_node_for(
+    /// Renames a file and/or moves it to another directory. Only the files row changes, the contents
+    /// stay where they are
+    #[instrument(level = "info", skip(self))]
+    pub async fn move_file(
         &self,
-        _file_info: &UploadFileInfo,
-    ) -> Result<StorageNodeID, Error> {
-        let connections = self.active_connections.read().await;
-        if let Some(i) = connections.keys().next() {
-            Ok(*i)
+        uuid: Uuid,
+        new_dir: DirectoryID,
+        new_name: String,
+    ) -> Result<(), Error> {
+        let mut transaction = self.pool()?.start_transaction(mysql_async::TxOpts::default()).await?;
+
+        let dir_query = "SELECT id FROM directories WHERE id = :dir;";
+        let dir: Option<DirectoryID> = dir_query.with(params! { "dir" => new_dir }).first(&mut transaction).await?;
+        if dir.is_none() {
+            return Err(Error::NoSuchDirectory { topmost_existing_directory: String::new() });
+        }
+
+        // locked until the commit, so two moves can't both take the name
+        let existing_query = "SELECT uuid FROM files WHERE name = :name AND directory_id = :dir FOR UPDATE;";
+        let existing: Option<Uuid> = existing_query
+            .with(params! { "name" => &new_name, "dir" => new_dir })
+            .first(&mut transaction)
+            .await?;
+        match existing {
+            // moving a file to where it already is
+            Some(existing) if existing == uuid => return Ok(()),
+            Some(_) => return Err(Error::FileExists),
+            None => {}
+        }
+
+        let query = "UPDATE files SET name = :name, directory_id = :dir WHERE uuid = :uuid;";
+        query.with(params! { "name" => new_name, "dir" => new_dir, "uuid" => uuid }).ignore(&mut transaction).await?;
+        if transaction.affected_rows() == 0 {
+            return Err(Error::NoSuchFile);
+        }
+        transaction.commit().await?;
+        self.path_cache.invalidate_files();
+        Ok(())
+    }
+
+    /// Deletes a file, both its contents on the storage node and its row, along with its old versions
+    #[instrument(level = "info", skip(self))]
+    pub async fn delete_file(&self, uuid: Uuid) -> Result<(), Error> {
+        // the versions first, as they refer to the file. if one can't be deleted, the file is kept
+        // so that deleting it again picks up the rest
+        let query = "SELECT uuid FROM file_versions WHERE current_uuid = :uuid;";
+        let versions: Vec<Uuid> = query.with(params! { "uuid" => uuid }).fetch(self.pool()?).await?;
+        for version in versions {
+            match self.delete_single_file(version).await {
+                Ok(()) | Err(Error::NoSuchFile) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.delete_single_file(uuid).await
+    }
+
+    /// Deletes one file, or one old version of a file, but not the versions of it. The row is kept
+    /// locked until the storage node has deleted the contents, so if that fails, the file is left as
+    /// it was. Contents another file shares are left on the node, see deduplication.rs
+    async fn delete_single_file(&self, uuid: Uuid) -> Result<(), Error> {
+        let mut transaction = self.pool()?.start_transaction(mysql_async::TxOpts::default()).await?;
+
+        let query = "SELECT stored_on_node_id, COALESCE(blob_uuid, uuid) FROM files WHERE uuid = :uuid FOR UPDATE;";
+        let Some((node_id, blob)): Option<(StorageNodeID, Uuid)> = query.with(params! { "uuid" => uuid }).first(&mut transaction).await? else {
+            return Err(Error::NoSuchFile);
+        };
+
+        "DELETE FROM backup_queue WHERE uuid = :uuid;"
+            .with(params! { "uuid" => uuid })
+            .ignore(&mut transaction)
+            .await?;
+        "DELETE FROM file_versions WHERE uuid = :uuid;"
+            .with(params! { "uuid" => uuid })
+            .ignore(&mut transaction)
+            .await?;
+        "DELETE FROM files WHERE uuid = :uuid;"
+            .with(params! { "uuid" => uuid })
+            .ignore(&mut transaction)
+            .await?;
+
+        if deduplication::lock_contents(&mut transaction, blob).await? {
+            debug!(%blob, "Contents still in use by another file; keeping them");
         } else {
-            Err(Error::NotConnectedToAnyNode)
+            self.delete_contents(node_id, blob).await?;
+        }
+
+        transaction.commit().await?;
+        self.path_cache.invalidate_files();
+        Ok(())
+    }
+
+    /// Renames a directory and/or moves it into another one, along with everything in it. Upload
+    /// policies inherited from the old parent no longer apply, and those of the new one do
+    #[instrument(level = "info", skip(self))]
+    pub async fn move_directory(
+        &self,
+        dir: DirectoryID,
+        new_parent: DirectoryID,
+        new_name: String,
+    ) -> Result<(), Error> {
+        let mut transaction = self.pool()?.start_transaction(mysql_async::TxOpts::default()).await?;
+
+        let query = "SELECT id FROM directories WHERE id = :dir FOR UPDATE;";
+        let existing: Option<DirectoryID> = query.with(params! { "dir" => dir }).first(&mut transaction).await?;
+        if existing.is_none() {
+            return Err(Error::NoSuchDirectory { topmost_existing_directory: String::new() });
+        }
+
+        // the ancestors are locked as they're walked, so a concurrent move can't make a cycle
+        let query = "SELECT parent_id FROM directories WHERE id = :dir FOR UPDATE;";
+        let mut ancestor = Some(new_parent);
+        while let Some(current) = ancestor {
+            if current == dir {
+                return Err(Error::MoveIntoOwnSubtree);
+            }
+            let parent: Option<Option<DirectoryID>> = query.with(params! { "dir" => current }).first(&mut transaction).await?;
+            match parent {
+                Some(parent) => ancestor = parent,
+                None => return Err(Error::NoSuchDirectory { topmost_existing_directory: String::new() }),
+            }
+        }
+
+        let existing_query = "SELECT id FROM directories WHERE name = :name AND parent_id = :parent FOR UPDATE;";
+        let existing: Option<DirectoryID> = existing_query
+            .with(params! { "name" => &new_name, "parent" => new_parent })
+            .first(&mut transaction)
+            .await?;
+        match existing {
+            Some(existing) if existing == dir => return Ok(()),
+            Some(_) => return Err(Error::FileExists),
+            None => {}
+        }
+
+        let query = "UPDATE directories SET name = :name, parent_id = :parent WHERE id = :dir;";
+        query.with(params! { "name" => new_name, "parent" => new_parent, "dir" => dir }).ignore(&mut transaction).await?;
+        transaction.commit().await?;
+        self.invalidate_upload_policies();
+        self.path_cache.invalidate_directories();
+        Ok(())
+    }
+
+    /// Asks every connected storage node how much space it has. Nodes which fail to answer are
+    /// left out. The result is cached, as SFTP clients may ask for this before every upload
+    #[instrument(level = "debug", skip(self))]
+    pub async fn storage_space(&self) -> Result<StorageSpace, Error> {
+        let spaces = self.node_spaces().await?;
+        Ok(spaces.values().fold(StorageSpace::default(), |total, space| StorageSpace {
+            bytes_total: total.bytes_total + space.bytes_total,
+            bytes_available: total.bytes_available + space.bytes_available,
+        }))
+    }
+
+    /// The latest metrics from each storage node, polled every METRICS_POLL_INTERVAL
+    pub async fn node_metrics(&self) -> HashMap<StorageNodeID, NodeMetrics> {
+        self.node_metrics.read().await.clone()
+    }
+
+    /// Writes out what is buffered for the database and closes its connections, once nothing is in
+    /// flight. Queries made after this fail
+    #[instrument(level = "info", skip(self))]
+    pub async fn disconnect(&self) {
+        write_accessed_files(&self.conn_pool, &self.accessed_files).await;
+        match self.conn_pool.clone().disconnect().await {
+            Ok(()) => debug!("Disconnected from the database"),
+            Err(e) => warn!(?e, "Error disconnecting from the database"),
+        }
+    }
+
+    /// How long in-flight work has left to finish, once shutdown has begun
+    pub fn shutdown_remaining(&self) -> Option<Duration> {
+        self.shutdown_deadline.lock().unwrap().map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// What is still in progress, for the shutdown drain report
+    pub async fn drain_report(&self, deadline_remaining: Duration) -> shutdown::DrainReport {
+        let mut pending_messages = Vec::new();
+        for (id, conn) in self.active_connections.read().await.iter() {
+            let n = conn.pending_messages().await;
+            if n > 0 {
+                pending_messages.push((id.0, n));
+            }
+        }
+
+        let backup_jobs = self.backup.iter().flat_map(|backup| backup.jobs.iter());
+        let background_jobs = self.background_jobs.iter().chain(backup_jobs)
+            .filter(|(_, handle)| !handle.is_finished())
+            .map(|(name, _)| *name)
+            .collect();
+
+        shutdown::DrainReport {
+            deadline_remaining_s: deadline_remaining.as_secs_f64(),
+            http_in_flight: self.limits.route_utilization(),
+            sftp_sessions: self.limits.sftp_session_utilization(),
+            #[cfg(feature = "sftp")]
+            sftp_open_handles: sftp::total_open_handles(),
+            #[cfg(not(feature = "sftp"))]
+            sftp_open_handles: 0,
+            pending_messages,
+            background_jobs,
+        }
+    }
+
+    /// None if backups aren't configured
+    pub async fn backup_status(&self) -> Result<Option<backup::BackupStatus>, Error> {
+        match self.backup {
+            Some(ref backup) => Ok(Some(backup.status(self.pool()?).await?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn node_spaces(&self) -> Result<HashMap<StorageNodeID, StorageSpace>, Error> {
+        if let Some((fetched_at, ref spaces)) = *self.storage_space_cache.lock().unwrap() {
+            if fetched_at.elapsed() < STORAGE_SPACE_CACHE_TTL {
+                return Ok(spaces.clone());
+            }
+        }
+
+        let connections: Vec<(StorageNodeID, Arc<StorageNodeConnection>)> = self.active_connections.read().await
+            .iter()
+            .map(|(id, conn)| (*id, conn.clone()))
+            .collect();
+        if connections.is_empty() {
+            return Err(Error::NotConnectedToAnyNode);
+        }
+
+        let mut spaces = HashMap::new();
+        for (id, conn) in connections {
+            match conn.storage_info().await {
+                Ok(info) => {
+                    spaces.insert(id, StorageSpace { bytes_total: info.bytes_total, bytes_available: info.bytes_available });
+                }
+                Err(Error::StorageNode { code, detail }) => warn!(?id, ?code, detail, "Storage node could not report its storage space"),
+                Err(e @ Error::UnexpectedResponse(_)) => return Err(e),
+                Err(e) => warn!(?id, ?e, "Could not ask storage node for its storage space"),
+            }
+        }
+
+        *self.storage_space_cache.lock().unwrap() = Some((Instant::now(), spaces.clone()));
+        Ok(spaces)
+    }
+
+    // the connected nodes with room for the file and min_free_bytes to spare, fastest tier first,
+    // and within a tier the ones with the most free space first. nodes which didn't report their
+    // space are assumed to have room
+    async fn placement_for(
+        &self,
+        file_info: &UploadFileInfo,
+    ) -> Result<Vec<StorageNodeID>, Error> {
+        let spaces = self.node_spaces().await.unwrap_or_default();
+        let connections = self.active_connections.read().await;
+        if connections.is_empty() {
+            return Err(Error::NotConnectedToAnyNode);
+        }
+
+        let mut placement: Vec<StorageNodeID> = connections.keys()
+            .filter(|id| spaces.get(id).is_none_or(|space| {
+                space.bytes_available >= (file_info.data_length as u64).saturating_add(self.min_free_bytes)
+            }))
+            .copied()
+            .collect();
+        if placement.is_empty() {
+            return Err(Error::NoNodeWithSpace);
+        }
+        let node_tiers = self.node_tiers.read().unwrap();
+        placement.sort_by_key(|id| {
+            let tier = node_tiers.get(id).copied().unwrap_or(0);
+            let available = spaces.get(id).map_or(0, |space| space.bytes_available);
+            (tier, std::cmp::Reverse(available))
+        });
+        Ok(placement)
+    }
+
+    // writes a new file to one node, and checks the result if verify is set
+    async fn write_to_node(
+        &self,
+        conn: &StorageNodeConnection,
+        uuid: Uuid,
+        contents: Vec<u8>,
+        digest: &ContentDigest,
+        verify: bool,
+    ) -> Result<(), Error> {
+        let expected = contents.len() as u64;
+        // nodes with checksums check the contents before writing them, and keep the digest
+        let request = match conn.has_feature(message::FEATURE_CHECKSUMS) {
+            true => Message::WriteFileChecked(uuid, digest.clone(), contents),
+            false => Message::WriteFile(uuid, contents),
+        };
+        let response = conn.request(request).await?;
+        self.check_write_ack(conn, uuid, expected, response)?;
+        if verify {
+            self.verify_written(conn, uuid, expected, digest).await?;
+        }
+        Ok(())
+    }
+
+    // checks the response to a WriteFile or WriteFileEnd
+    fn check_write_ack(
+        &self,
+        conn: &StorageNodeConnection,
+        uuid: Uuid,
+        expected: u64,
+        response: Message,
+    ) -> Result<(), Error> {
+        match response {
+            // nodes without write acks can't tell us how much they wrote
+            Message::Ack if !conn.has_feature(message::FEATURE_WRITE_ACK) => Ok(()),
+            Message::WriteAck { bytes_written, fsynced } => {
+                if bytes_written != expected {
+                    self.short_writes.fetch_add(1, Ordering::Relaxed);
+                    error!(%uuid, expected, bytes_written, "Storage node wrote fewer bytes than were sent");
+                    return Err(Error::ShortWrite { uuid, expected, written: bytes_written });
+                }
+                if !fsynced {
+                    warn!(%uuid, "Storage node acked write without fsyncing");
+                }
+                Ok(())
+            }
+            x => Err(Error::UnexpectedResponse(x))
+        }
+    }
+
+    // hashes a file which was just written on its node, and compares it with what was sent
+    async fn verify_written(
+        &self,
+        conn: &StorageNodeConnection,
+        uuid: Uuid,
+        expected: u64,
+        digest: &ContentDigest,
+    ) -> Result<(), Error> {
+        let (actual_size, actual_digest) = match conn.request(Message::HashFile(uuid, digest.algorithm)).await {
+            Ok(Message::FileHash { size, digest }) => (Some(size), Some(digest)),
+            Err(Error::StorageNode { code, detail }) => {
+                error!(%uuid, ?code, detail, "Storage node could not hash file it just wrote");
+                (None, None)
+            }
+            Err(e) => return Err(e),
+            Ok(x) => return Err(Error::UnexpectedResponse(x)),
+        };
+        if actual_size != Some(expected) {
+            self.verification_failures.fetch_add(1, Ordering::Relaxed);
+            error!(%uuid, expected_size = expected, actual_size, "Upload verification failed");
+            return Err(Error::VerificationFailed { uuid, expected_size: expected, actual_size });
+        }
+        if let Some(actual) = actual_digest.filter(|actual| actual != digest) {
+            self.verification_failures.fetch_add(1, Ordering::Relaxed);
+            error!(%uuid, expected = %digest, %actual, "Upload verification failed: digests differ");
+            return Err(Error::DigestMismatch { uuid, expected: digest.clone(), actual });
+        }
+        trace!(%uuid, "Upload verified");
+        Ok(())
+    }
+
+    /// The checks upload_file makes before writing anything, for rejecting uploads before their
+    /// contents are received. Without a known size, the size limit, quota and free space can't be
+    /// checked, so those are left for upload_file. Without `dir_exists`, `dir` is the deepest existing
+    /// directory above the one the file goes in, which is only created once the upload is accepted.
+    /// Its policy is the one which will apply, and the new directory has no file to collide with
+    #[instrument(level = "debug", skip(self))]
+    pub async fn check_upload(
+        &self,
+        filename: &str,
+        dir: DirectoryID,
+        dir_exists: bool,
+        size: Option<u64>,
+        options: UploadOptions<'_>,
+    ) -> Result<(), Error> {
+        // first, as the usage is usually cached
+        if let (Some(user), Some(size)) = (options.user, size) {
+            self.check_quota(user, filename, dir, dir_exists && options.overwrite, size).await?;
+        }
+        if let Some(policy) = self.upload_policy(dir).await? {
+            if let Err(rule) = policy.check(filename, size, options.content_type) {
+                info!(rule, "Upload refused by policy");
+                return Err(Error::PolicyViolation { rule });
+            }
+        }
+        if dir_exists {
+            self.check_name_free(filename, dir, options.overwrite).await?;
+        }
+        if let Some(size) = size {
+            let info = UploadFileInfo { data_length: size as usize, content_type: None, overwrite: options.overwrite };
+            self.placement_for(&info).await?;
+        }
+        Ok(())
+    }
+
+    /// Error::FileExists if the directory already has a file with that name, unless it's to be
+    /// overwritten. Checked before writing anything, so that a taken name doesn't leave an orphan
+    /// behind. record_upload checks again, as the name may be taken meanwhile
+    async fn check_name_free(&self, filename: &str, dir: DirectoryID, overwrite: bool) -> Result<(), Error> {
+        if overwrite {
+            return Ok(());
+        }
+        let query = "SELECT EXISTS(SELECT * FROM files WHERE name = :name AND directory_id = :dir);";
+        let exists: Option<bool> = query.with(params! { "name" => filename, "dir" => dir }).first(self.pool()?).await?;
+        if exists.unwrap_or(false) {
+            debug!(filename, "A file with that name exists already");
+            return Err(Error::FileExists);
         }
+        Ok(())
     }
 
+    // if options.verify is set, the written file is hashed on the storage node before it is recorded.
+    // the upload must follow the directory's upload policy, see upload_policy.rs
     #[instrument(level = "info", skip(self, contents), fields(contents.len = contents.len()))]
     pub async fn upload_file(
         &self,
         filename: String,
         dir: DirectoryID,
         contents: Vec<u8>,
+        options: UploadOptions<'_>,
     ) -> Result<Uuid, Error> {
+        if let Some(policy) = self.upload_policy(dir).await? {
+            if let Err(rule) = policy.check(&filename, Some(contents.len() as u64), options.content_type) {
+                info!(rule, "Upload refused by policy");
+                return Err(Error::PolicyViolation { rule });
+            }
+        }
+        self.check_name_free(&filename, dir, options.overwrite).await?;
+        let growth = match options.user {
+            Some(user) => self.check_quota(user, &filename, dir, options.overwrite, contents.len() as u64).await?,
+            None => 0,
+        };
+
         let info = UploadFileInfo {
             data_length: contents.len(),
+            content_type: options.content_type.and_then(content_type::from_upload),
+            overwrite: options.overwrite,
         };
 
         let uuid = Uuid::now_v7();
+        let digest = ContentDigest::of(self.hash_algorithm, &contents);
 
-        let storage_node_id = {
-            // We grab a read-lock for connections before we do get_appropriate_node_for.
-            // As no write-lock can be obtained between this and getting the conneciton,
-            // unwrapping the result is safe.
-            let conns = self.active_connections.read().await;
-            let id = self.get_appropriate_node_for(&info).await?;
-            let conn = conns.get(&id).unwrap();
-
-            match conn.communicate(Message::WriteFile(uuid, contents)).await? {
-                Message::Ack => {},
-                x => return Err(Error::UnexpectedResponse(x))
+        if self.deduplicate {
+            if let Some((blob, node)) = self.find_contents(&digest, contents.len() as u64).await? {
+                match self.insert_upload(uuid, filename.clone(), dir, node, Some(blob), info.clone(), digest.clone()).await {
+                    Ok(()) => {
+                        debug!(%blob, "Upload shares the contents of an existing file");
+                        if let Some(user) = options.user {
+                            self.add_usage(user, growth);
+                        }
+                        return Ok(uuid);
+                    }
+                    // deleted meanwhile, so they're written after all
+                    Err(Error::UnknownUUID) => debug!(%blob, "Contents to share were deleted; writing them"),
+                    Err(e) => return Err(e),
+                }
             }
+        }
+
+        let placement = self.placement_for(&info).await?;
+        let ((), storage_node_id) = self.with_node_failover(placement, |_, conn| {
+            let contents = contents.clone();
+            let digest = &digest;
+            async move { self.write_to_node(&conn, uuid, contents, digest, options.verify).await }
+        }).await?;
+
+        self.record_upload(uuid, filename, dir, storage_node_id, info, digest).await?;
+        if let Some(user) = options.user {
+            self.add_usage(user, growth);
+        }
+        Ok(uuid)
+    }
 
-            id
+    // adds a file which was written to a storage node to the files table. with info.overwrite, a file
+    // with the same name is replaced, and its contents deleted once the new file is recorded. if the
+    // file can't be recorded, its contents are deleted from the node, as nothing would refer to them.
+    // with deduplication, a file whose contents turn out to be stored already shares those instead,
+    // and the contents just written are deleted
+    async fn record_upload(
+        &self,
+        uuid: Uuid,
+        filename: String,
+        dir: DirectoryID,
+        storage_node_id: StorageNodeID,
+        info: UploadFileInfo,
+        digest: ContentDigest,
+    ) -> Result<(), Error> {
+        let existing = match self.deduplicate {
+            true => self.find_contents(&digest, info.data_length as u64).await,
+            false => Ok(None),
         };
+        let inserted = match existing {
+            Ok(Some((blob, node))) => {
+                match self.insert_upload(uuid, filename.clone(), dir, node, Some(blob), info.clone(), digest.clone()).await {
+                    Ok(()) => {
+                        debug!(%blob, "Upload shares the contents of an existing file");
+                        match self.delete_contents(storage_node_id, uuid).await {
+                            Ok(()) => trace!(%uuid, "Deleted the duplicate contents"),
+                            Err(e) => warn!(?e, %uuid, ?storage_node_id, "Could not delete duplicate contents; orphan possible"),
+                        }
+                        return Ok(());
+                    }
+                    // deleted meanwhile, so the file keeps the contents just written
+                    Err(Error::UnknownUUID) => self.insert_upload(uuid, filename, dir, storage_node_id, None, info, digest).await,
+                    Err(e) => Err(e),
+                }
+            }
+            Ok(None) => self.insert_upload(uuid, filename, dir, storage_node_id, None, info, digest).await,
+            Err(e) => Err(e),
+        };
+        let Err(e) = inserted else {
+            return Ok(());
+        };
+
+        // a commit which failed midway may still have gone through, in which case the contents are in use
+        let query = "SELECT EXISTS(SELECT * FROM files WHERE uuid = :uuid);";
+        let recorded: Result<Option<bool>, Error> = async {
+            Ok(query.with(params! { "uuid" => uuid }).first(self.pool()?).await?)
+        }.await;
+        match recorded {
+            Ok(Some(false)) => match self.delete_contents(storage_node_id, uuid).await {
+                Ok(()) => debug!(%uuid, "Deleted the contents of the unrecorded file"),
+                Err(delete_error) => warn!(?delete_error, %uuid, ?storage_node_id, "Could not delete the contents of an unrecorded file; orphan possible"),
+            },
+            Ok(_) => warn!(%uuid, "Recording the file failed, but it is in the files table"),
+            Err(check_error) => warn!(?check_error, %uuid, ?storage_node_id, "Could not check whether the file was recorded; orphan possible"),
+        }
+        Err(e)
+    }
+
+    // `blob` is the UUID of existing contents on storage_node_id the file shares, see
+    // deduplication.rs. Error::UnknownUUID if no file uses those anymore
+    #[allow(clippy::too_many_arguments)]
+    async fn insert_upload(
+        &self,
+        uuid: Uuid,
+        filename: String,
+        dir: DirectoryID,
+        storage_node_id: StorageNodeID,
+        blob: Option<Uuid>,
+        info: UploadFileInfo,
+        digest: ContentDigest,
+    ) -> Result<(), Error> {
+        let mut transaction = self.pool()?.start_transaction(mysql_async::TxOpts::default()).await?;
+
+        if let Some(blob) = blob {
+            if !deduplication::lock_contents(&mut transaction, blob).await? {
+                return Err(Error::UnknownUUID);
+            }
+        }
+
+        let query = r#"
+            SELECT uuid, stored_on_node_id, COALESCE(blob_uuid, uuid) FROM files
+                WHERE name = :name AND directory_id = :dir
+                FOR UPDATE;
+        "#;
+        let replaced: Option<(Uuid, StorageNodeID, Uuid)> = query
+            .with(params! { "name" => &filename, "dir" => dir })
+            .first(&mut transaction)
+            .await?;
+        let replacement = self.replacement();
+        match replaced {
+            Some(_) if !info.overwrite => return Err(Error::FileExists),
+            // kept until purged, like any other deleted file. the row is locked, so it's still there
+            Some((old_uuid, ..)) if replacement == Replacement::Trashed => {
+                trash::move_to_trash(&mut transaction, old_uuid).await?;
+            }
+            // out of the way of the new file, which it's then made a version of or deleted after
+            Some((old_uuid, ..)) => versions::move_to_versions_directory(&mut transaction, old_uuid).await?,
+            None => {}
+        }
 
         let query = r#"
             INSERT INTO files
-                (uuid, name, directory_id, stored_on_node_id) VALUES
-                (:uuid, :name, :dir, :stored_on_node_id);
+                (uuid, name, directory_id, stored_on_node_id, blob_uuid, hash_algorithm, digest, size, content_type) VALUES
+                (:uuid, :name, :dir, :stored_on_node_id, :blob_uuid, :hash_algorithm, :digest, :size, :content_type);
         "#;
 
-        query.with(params! {
+        let inserted = query.with(params! {
             "uuid" => uuid,
             "name" => filename,
+            "size" => info.data_length as u64,
+            "content_type" => info.content_type,
             "dir" => dir,
             "stored_on_node_id" => storage_node_id,
-        }).ignore(&self.conn_pool).await?;
+            "blob_uuid" => blob,
+            "hash_algorithm" => digest.algorithm.name(),
+            "digest" => digest.bytes,
+        }).ignore(&mut transaction).await;
+        match inserted {
+            Ok(()) => {}
+            // uploaded by someone else since the name was checked
+            Err(mysql_async::Error::Server(e)) if e.code == ER_DUP_ENTRY => return Err(Error::FileExists),
+            Err(e) => return Err(e.into()),
+        }
+        if let Some((old_uuid, ..)) = replaced.filter(|_| replacement != Replacement::Trashed) {
+            versions::replace(&mut transaction, old_uuid, uuid, replacement == Replacement::Versioned).await?;
+        }
 
-        Ok(uuid)
+        // queued in the same transaction, so no file is left out of the backup
+        if self.backup.is_some() {
+            let query = "INSERT INTO backup_queue(uuid) VALUES (:uuid);";
+            query.with(params! { "uuid" => uuid }).ignore(&mut transaction).await?;
+        }
+
+        transaction.commit().await?;
+        self.path_cache.invalidate_files();
+
+        // the file is already replaced, so failing to delete the old contents only leaves an orphan,
+        // which scrubbing finds
+        if let Some((old_uuid, old_node, old_blob)) = replaced {
+            info!(%old_uuid, %uuid, ?replacement, "Replaced file");
+            match (replacement, &self.versioning) {
+                (Replacement::Deleted, _) => {
+                    if let Err(e) = self.delete_contents_if_unused(old_node, old_blob).await {
+                        warn!(?e, %old_uuid, "Could not delete the contents of a replaced file");
+                    }
+                }
+                (Replacement::Versioned, Some(versioning)) => self.trim_versions(uuid, versioning.keep_versions).await,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // deletes a file's contents from its node, leaving the files table as it is
+    async fn delete_contents(&self, node: StorageNodeID, uuid: Uuid) -> Result<(), Error> {
+        self.with_node_failover(vec![node], |_, conn| async move {
+            match conn.request(Message::DeleteFile(uuid)).await? {
+                Message::Ack => Ok(()),
+                x => Err(Error::UnexpectedResponse(x)),
+            }
+        }).await?;
+        Ok(())
     }
 }
 
+/// Creates a root directory for a database whose root_directory table is empty. A single directory
+/// without a parent is taken as the root rather than creating another. Returns false if another
+/// front node got there first, which the unique row of root_directory makes us wait for
 #[instrument(level = "info", skip_all)]
-async fn monitor_connections(
+async fn create_root_directory(conn_pool: &mysql_async::Pool) -> Result<bool, Error> {
+    let mut transaction = conn_pool.start_transaction(mysql_async::TxOpts::default()).await?;
+
+    // the trash and versions directories have no parent either. not looked up in their tables, which
+    // don't exist before migrations 3 and 4
+    let parentless: Vec<DirectoryID> = "SELECT id FROM directories WHERE parent_id IS NULL AND name NOT IN ('<trash>', '<versions>') LIMIT 2;"
+        .fetch(&mut transaction)
+        .await?;
+    let id = match parentless.as_slice() {
+        [root] => root.0 as u64,
+        _ => {
+            "INSERT INTO directories(name, parent_id) VALUES ('<root>', NULL);".ignore(&mut transaction).await?;
+            transaction.last_insert_id().expect("directories.id is AUTO_INCREMENT")
+        }
+    };
+
+    let query = "INSERT INTO root_directory(directory_id) VALUES (:id);";
+    match query.with(params! { "id" => id }).ignore(&mut transaction).await {
+        Ok(()) => {}
+        Err(mysql_async::Error::Server(e)) if e.code == ER_DUP_ENTRY => {
+            // dropping the transaction rolls back our directory
+            debug!("Another front node created the root directory first");
+            return Ok(false);
+        }
+        Err(e) => return Err(e.into()),
+    }
+    transaction.commit().await?;
+    info!(id, "Set up the root directory");
+    Ok(true)
+}
+
+/// Runs `f` until it succeeds or fails with a server error, which won't go away by waiting, at most
+/// database_connection.startup_attempts times. For startup, when the database may still be starting
+async fn retry_at_startup<T, F, Fut>(cfg: &config::DatabaseConnectionOptions, what: &str, mut f: F) -> Result<T, Error>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T, Error>>,
+{
+    let interval = Duration::from_secs(cfg.startup_retry_interval_s);
+    let mut attempt = 1;
+    loop {
+        match f().await {
+            Ok(x) => return Ok(x),
+            Err(e @ Error::Database(mysql_async::Error::Server(_))) => {
+                error!(?e, "{what} failed");
+                return Err(e);
+            }
+            Err(e) if attempt < cfg.startup_attempts => {
+                warn!(?e, attempt, max_attempts = cfg.startup_attempts, "{what} failed; retrying in {interval:?}");
+                tokio::time::sleep(interval).await;
+                attempt += 1;
+            }
+            Err(e) => {
+                error!(?e, attempts = attempt, "{what} failed; giving up");
+                return Err(e);
+            }
+        }
+    }
+}
+
+#[instrument(level = "debug", skip_all)]
+async fn flush_accessed_files(
     conn_pool: mysql_async::Pool,
+    accessed_files: Arc<std::sync::Mutex<HashSet<Uuid>>>,
+) {
+    loop {
+        tokio::time::sleep(ACCESS_FLUSH_INTERVAL).await;
+        write_accessed_files(&conn_pool, &accessed_files).await;
+    }
+}
+
+async fn write_accessed_files(conn_pool: &mysql_async::Pool, accessed_files: &std::sync::Mutex<HashSet<Uuid>>) {
+    let uuids: Vec<Uuid> = accessed_files.lock().unwrap().drain().collect();
+    if uuids.is_empty() {
+        return;
+    }
+
+    let query = "UPDATE files SET last_accessed = NOW() WHERE uuid = :uuid;";
+    let result = query
+        .with(uuids.iter().map(|uuid| params! { "uuid" => uuid }))
+        .batch(conn_pool)
+        .await;
+    match result {
+        Ok(()) => trace!(n_files = uuids.len(), "Updated last_accessed"),
+        Err(e) => {
+            // the accesses are lost, which only makes the files look colder than they are
+            error!(?e, n_files = uuids.len(), "Could not update last_accessed");
+        }
+    }
+}
+
+#[instrument(level = "debug", skip_all)]
+async fn poll_node_metrics(
     active_connections: Arc<RwLock<HashMap<StorageNodeID, Arc<StorageNodeConnection>>>>,
-    cfg: config::Config,
+    node_metrics: Arc<RwLock<HashMap<StorageNodeID, NodeMetrics>>>,
 ) {
-    // insert all nodes not in db into db
-    debug!("Making nodes consistent");
-    for (name, _cfg) in &cfg.storage_nodes {
-        trace!(name, "Checking");
-        let query = "SELECT count(*) FROM nodes WHERE name = :name;";
-        let count: u32 = query.with(params! {
-            "name" => name,
-        }).first(&conn_pool).await.unwrap().unwrap();
-        if count == 0 {
-            debug!(name, "Not in nodes table; inserting");
-            let query = "INSERT INTO nodes(name) VALUES (:name);";
-            query.with(params! {
-                "name" => name,
-            }).run(&conn_pool).await.unwrap();
-        }
-    }
-
-    // spawn connections for all nodes
-    debug!("Spawning connections to all nodes");
-    {
-        let mut active_connections = active_connections.write().await;
-        for (name, node_cfg) in &cfg.storage_nodes {
-            trace!(name, "Finding id");
-            let query = "SELECT id FROM nodes WHERE name = :name;";
-
-            // raw indexing should be safe because we inserted all of these into the table before
-            let id: StorageNodeID = query.with(params! {
-                "name" => name,
-            }).first(&conn_pool).await.unwrap().expect("Node not in nodes table");
-
-            debug!(name, ?id, "Connecting");
-            match StorageNodeConnection::connect(node_cfg).await {
-                Ok(conn) => {
-                    info!(name, "Connected successfully");
-                    active_connections.insert(id, Arc::new(conn));
-                }
-                Err(e) => {
-                    error!(name, ?e, "Could not connect");
-                    continue;
+    loop {
+        tokio::time::sleep(METRICS_POLL_INTERVAL).await;
+
+        let connections: Vec<(StorageNodeID, Arc<StorageNodeConnection>)> = active_connections.read().await
+            .iter()
+            .map(|(id, conn)| (*id, conn.clone()))
+            .collect();
+
+        for (id, conn) in connections {
+            match conn.request(Message::GetMetrics).await {
+                Ok(Message::Metrics(metrics)) => {
+                    trace!(?id, ?metrics, "Got storage node metrics");
+                    node_metrics.write().await.insert(id, metrics);
                 }
-            };
+                Err(Error::StorageNode { code, detail }) => warn!(?id, ?code, detail, "Storage node could not report its metrics"),
+                Ok(x) => warn!(?id, %x, "Unexpected response to GetMetrics"),
+                Err(e) => warn!(?id, ?e, "Could not ask storage node for its metrics"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::test_support;
+    #[cfg(feature = "testing")]
+    use super::test_support::{TestStorageNode, DatabaseFrontNode};
+    #[cfg(feature = "testing")]
+    use crate::fault_injection::{ConnectionFault, WriteFault};
+
+    #[cfg(feature = "testing")]
+    /// Stores `contents` under `uuid` on the node, with their digest
+    async fn store(conn: &StorageNodeConnection, uuid: Uuid, contents: &[u8]) {
+        let digest = ContentDigest::of(HashAlgorithm::Sha256, contents);
+        let response = conn.request(Message::WriteFileChecked(uuid, digest, contents.to_vec())).await.unwrap();
+        assert!(matches!(response, Message::WriteAck { .. }), "{response}");
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn download_fails_over_after_node_error() {
+        let front_node = test_support::offline(&test_support::offline_config());
+        let (a, b) = (TestStorageNode::start().await, TestStorageNode::start().await);
+        let faults = FaultInjector::new();
+        let (a_conn, b_conn) = (a.connect(faults.clone()).await, b.connect(FaultInjector::new()).await);
+
+        let uuid = Uuid::now_v7();
+        store(&a_conn, uuid, b"bnuy").await;
+        store(&b_conn, uuid, b"bnuy").await;
+        test_support::attach_offline(&front_node, StorageNodeID(1), a_conn).await;
+        test_support::attach_offline(&front_node, StorageNodeID(2), b_conn).await;
+
+        faults.inject_connection_fault(ConnectionFault::Disconnect);
+        let (contents, node) = front_node.with_node_failover(vec![StorageNodeID(1), StorageNodeID(2)], |_, conn| async move {
+            conn.read_file(uuid).await
+        }).await.unwrap();
+        assert_eq!(contents, b"bnuy");
+        assert_eq!(node, StorageNodeID(2));
+
+        let statuses = front_node.node_health.statuses();
+        assert_eq!(statuses[&StorageNodeID(1)].consecutive_failures, 1);
+        assert_eq!(statuses[&StorageNodeID(2)].successes, 1);
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn corrupt_download_is_retried() {
+        let front_node = test_support::offline(&test_support::offline_config());
+        let storage = TestStorageNode::start().await;
+        let faults = FaultInjector::new();
+        let conn = storage.connect(faults.clone()).await;
+        let uuid = Uuid::now_v7();
+        store(&conn, uuid, b"bnuy").await;
+        test_support::attach_offline(&front_node, StorageNodeID(1), conn).await;
+
+        // files are only on one node, so it's tried again
+        faults.inject_connection_fault(ConnectionFault::CorruptPayload);
+        let (contents, _) = front_node.with_node_failover(vec![StorageNodeID(1)], |_, conn| async move {
+            conn.read_file(uuid).await
+        }).await.unwrap();
+        assert_eq!(contents, b"bnuy");
+        assert_eq!(front_node.node_health.statuses()[&StorageNodeID(1)].failures, 1);
+
+        // and gives up once out of attempts
+        for _ in 0..front_node.retry_policy.max_attempts {
+            faults.inject_connection_fault(ConnectionFault::CorruptPayload);
+        }
+        let read = front_node.with_node_failover(vec![StorageNodeID(1)], |_, conn| async move {
+            conn.read_file(uuid).await
+        }).await;
+        match read {
+            Err(Error::AllAttemptsFailed(attempts)) => {
+                assert_eq!(attempts.len(), front_node.retry_policy.max_attempts);
+                assert!(attempts.iter().all(|attempt| matches!(attempt.error, Error::DigestMismatch { .. })));
+            }
+            x => panic!("expected all attempts to fail, got {x:?}"),
+        }
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    #[ignore = "needs a MySQL database, see test_support::DATABASE_URL_VAR"]
+    async fn upload_is_deleted_when_recording_it_fails() {
+        let mut cfg = test_support::database_config();
+        // so that recording the upload starts with the insert
+        cfg.uploads.deduplicate = false;
+        let faults = FaultInjector::new();
+        let front = DatabaseFrontNode::start_with_faults(&cfg, faults.clone()).await;
+
+        let uuid = Uuid::now_v7();
+        let contents = b"bnuy".to_vec();
+        let digest = ContentDigest::of(front.node.hash_algorithm, &contents);
+        let conn = front.node.active_connections.read().await[&front.storage_id].clone();
+        front.node.write_to_node(&conn, uuid, contents.clone(), &digest, false).await.unwrap();
+        assert!(front.storage.has_file(uuid));
+
+        let info = UploadFileInfo { data_length: contents.len(), content_type: None, overwrite: false };
+        faults.fail_nth_query(1);
+        let recorded = front.node.record_upload(uuid, "bnuy.txt".to_string(), front.dir, front.storage_id, info, digest).await;
+        assert!(recorded.is_err());
+        assert!(!front.storage.has_file(uuid));
+        assert!(matches!(front.node.file_uuid_for_path(&format!("{}/bnuy.txt", front.path), None).await, Err(Error::NoSuchFile)));
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn query_faults_fail_without_asking_the_database() {
+        let faults = FaultInjector::new();
+        let front_node = test_support::offline_with_faults(&test_support::offline_config(), faults.clone());
+        let uuid = Uuid::now_v7();
+
+        faults.fail_nth_query(2);
+        // the database isn't there, so the first query fails on its own
+        let e = front_node.file_stat(uuid).await.unwrap_err();
+        assert!(!format!("{e:?}").contains("injected"), "{e:?}");
+        let e = front_node.file_stat(uuid).await.unwrap_err();
+        assert!(format!("{e:?}").contains("injected query fault"), "{e:?}");
+        let e = front_node.file_stat(uuid).await.unwrap_err();
+        assert!(!format!("{e:?}").contains("injected"), "{e:?}");
+        assert_eq!(faults.queries_made(), 3);
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn unconfirmed_uploads_keep_their_contents() {
+        let mut cfg = test_support::offline_config();
+        cfg.uploads.deduplicate = false;
+        let faults = FaultInjector::new();
+        let front_node = test_support::offline_with_faults(&cfg, faults.clone());
+        let storage = TestStorageNode::start().await;
+        let conn = storage.connect(FaultInjector::new()).await;
+        let uuid = Uuid::now_v7();
+        let contents = b"bnuy".to_vec();
+        let digest = ContentDigest::of(front_node.hash_algorithm, &contents);
+        front_node.write_to_node(&conn, uuid, contents.clone(), &digest, false).await.unwrap();
+        test_support::attach_offline(&front_node, StorageNodeID(1), conn).await;
+
+        faults.fail_nth_query(1);
+        let info = UploadFileInfo { data_length: contents.len(), content_type: None, overwrite: false };
+        let recorded = front_node.record_upload(uuid, "bnuy.txt".to_string(), DirectoryID(1), StorageNodeID(1), info, digest).await;
+        assert!(format!("{:?}", recorded.unwrap_err()).contains("injected query fault"));
+        // whether the insert went through can't be checked either, and deleting the contents of a
+        // file which was recorded after all would lose it
+        assert!(storage.has_file(uuid));
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn short_writes_fail() {
+        let front_node = test_support::offline(&test_support::offline_config());
+        let faults = FaultInjector::new();
+        let storage = TestStorageNode::start_with_faults(faults.clone()).await;
+        let conn = storage.connect(FaultInjector::new()).await;
+        let contents = b"bnuy".to_vec();
+        let digest = ContentDigest::of(HashAlgorithm::Blake3, &contents);
+
+        faults.inject_write_fault(WriteFault::ShortWrite(2));
+        let written = front_node.write_to_node(&conn, Uuid::now_v7(), contents.clone(), &digest, false).await;
+        assert!(matches!(written, Err(Error::ShortWrite { expected: 4, written: 2, .. })), "{written:?}");
+        assert_eq!(front_node.short_writes.load(Ordering::Relaxed), 1);
+
+        front_node.write_to_node(&conn, Uuid::now_v7(), contents, &digest, false).await.unwrap();
+        assert_eq!(front_node.short_writes.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn plain_acks_are_only_accepted_from_old_nodes() {
+        let front_node = test_support::offline(&test_support::offline_config());
+        let contents = b"bnuy".to_vec();
+        let digest = ContentDigest::of(HashAlgorithm::Blake3, &contents);
+        let acking_node = |features| test_support::mock_node(features, |request| match request {
+            Message::WriteFile(..) => Some(Message::Ack),
+            _ => None,
+        });
+
+        let old = acking_node(&[]).await;
+        front_node.write_to_node(&old, Uuid::now_v7(), contents.clone(), &digest, false).await.unwrap();
+        let new = acking_node(&[message::FEATURE_WRITE_ACK]).await;
+        let written = front_node.write_to_node(&new, Uuid::now_v7(), contents, &digest, false).await;
+        assert!(matches!(written, Err(Error::UnexpectedResponse(Message::Ack))), "{written:?}");
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    #[ignore = "needs a MySQL database, see test_support::DATABASE_URL_VAR"]
+    async fn short_written_uploads_are_not_recorded() {
+        let faults = FaultInjector::new();
+        let front = DatabaseFrontNode::start_with_faults(&test_support::database_config(), faults.clone()).await;
+        let max_attempts = front.node.retry_policy.max_attempts;
+
+        for _ in 0..max_attempts {
+            faults.inject_write_fault(WriteFault::ShortWrite(2));
         }
+        let uploaded = front.node.upload_file("short".to_string(), front.dir, b"bnuy".to_vec(), UploadOptions::default()).await;
+        assert!(matches!(uploaded, Err(Error::AllAttemptsFailed(_))), "{uploaded:?}");
+        assert_eq!(front.node.short_writes.load(Ordering::Relaxed), max_attempts as u64);
+        assert!(matches!(front.node.file_uuid_for_path(&format!("{}/short", front.path), None).await, Err(Error::NoSuchFile)));
+
+        // written whole when tried again
+        faults.inject_write_fault(WriteFault::ShortWrite(2));
+        let uuid = front.node.upload_file("short".to_string(), front.dir, b"bnuy".to_vec(), UploadOptions::default()).await.unwrap();
+        assert_eq!(front.node.get_file(uuid).await.unwrap().0, b"bnuy");
+        front.node.remove_file(uuid, true).await.unwrap();
+    }
+
+    /// A node which writes everything it's sent, and then claims to have `hashed` when asked for the
+    /// hash of what it wrote. Counts the HashFile requests
+    async fn lying_node(hashed: Option<(u64, &'static [u8])>) -> (StorageNodeConnection, Arc<AtomicU64>) {
+        let hash_requests = Arc::new(AtomicU64::new(0));
+        let counter = hash_requests.clone();
+        let features = [message::FEATURE_WRITE_ACK, message::FEATURE_CHECKSUMS];
+        let conn = test_support::mock_node(&features, move |request| match request {
+            Message::WriteFileChecked(_, _, data) => Some(Message::WriteAck { bytes_written: data.len() as u64, fsynced: true }),
+            Message::HashFile(_, algorithm) => {
+                counter.fetch_add(1, Ordering::Relaxed);
+                Some(match hashed {
+                    Some((size, contents)) => Message::FileHash { size, digest: ContentDigest::of(algorithm, contents) },
+                    None => Message::ErrorCode { code: message::ErrorCode::Io, detail: "disk on fire".to_string() },
+                })
+            }
+            _ => None,
+        }).await;
+        (conn, hash_requests)
+    }
+
+    #[tokio::test]
+    async fn lying_node_fails_verification() {
+        let front_node = test_support::offline(&test_support::offline_config());
+        let contents = b"bnuy".to_vec();
+        let digest = ContentDigest::of(HashAlgorithm::Blake3, &contents);
+        let write = |conn, verify| {
+            let (front_node, contents, digest) = (front_node.clone(), contents.clone(), digest.clone());
+            async move { front_node.write_to_node(&conn, Uuid::now_v7(), contents, &digest, verify).await }
+        };
+
+        let (honest, hash_requests) = lying_node(Some((4, b"bnuy"))).await;
+        write(honest, true).await.unwrap();
+        assert_eq!(hash_requests.load(Ordering::Relaxed), 1);
+
+        // without verification, there's no extra round trip to notice anything
+        let (wrong_size, hash_requests) = lying_node(Some((3, b"bnu"))).await;
+        write(wrong_size, false).await.unwrap();
+        assert_eq!(hash_requests.load(Ordering::Relaxed), 0);
+        assert_eq!(front_node.verification_failures.load(Ordering::Relaxed), 0);
+
+        let (wrong_size, _) = lying_node(Some((3, b"bnu"))).await;
+        assert!(matches!(
+            write(wrong_size, true).await,
+            Err(Error::VerificationFailed { expected_size: 4, actual_size: Some(3), .. })
+        ));
+        let (wrong_contents, _) = lying_node(Some((4, b"yunb"))).await;
+        assert!(matches!(write(wrong_contents, true).await, Err(Error::DigestMismatch { .. })));
+        let (unhashable, _) = lying_node(None).await;
+        assert!(matches!(
+            write(unhashable, true).await,
+            Err(Error::VerificationFailed { expected_size: 4, actual_size: None, .. })
+        ));
+        assert_eq!(front_node.verification_failures.load(Ordering::Relaxed), 3);
+    }
+
+    /// A node reporting `bytes_available` when asked, or an error for u64::MAX
+    async fn node_with_space(bytes_available: Arc<AtomicU64>) -> StorageNodeConnection {
+        test_support::mock_node(&[], move |request| match request {
+            Message::StorageInfo => Some(match bytes_available.load(Ordering::Relaxed) {
+                u64::MAX => Message::ErrorCode { code: message::ErrorCode::Io, detail: "statvfs failed".to_string() },
+                bytes_available => Message::StorageInfoIs(message::StorageInfo { bytes_total: 1 << 40, bytes_available, file_count: None }),
+            }),
+            _ => None,
+        }).await
+    }
+
+    #[tokio::test]
+    async fn uploads_are_placed_on_the_fastest_tier_with_room() {
+        let mut cfg = test_support::offline_config();
+        cfg.uploads.min_free_bytes = 100;
+        let front_node = test_support::offline(&cfg);
+        // id, tier and space available. 5 has no tier set, and can't tell its space
+        let nodes = [(1, Some(0), 500), (2, Some(0), 10_000), (3, Some(1), 1_000_000), (4, Some(1), 50), (5, None, u64::MAX)];
+        let mut spaces = HashMap::new();
+        for (id, tier, available) in nodes {
+            let available = Arc::new(AtomicU64::new(available));
+            test_support::attach_offline(&front_node, StorageNodeID(id), node_with_space(available.clone()).await).await;
+            if let Some(tier) = tier {
+                front_node.node_tiers.write().unwrap().insert(StorageNodeID(id), tier);
+            }
+            spaces.insert(id, available);
+        }
+        let placement = |data_length| {
+            let front_node = front_node.clone();
+            async move {
+                let info = UploadFileInfo { data_length, content_type: None, overwrite: false };
+                let placement = front_node.placement_for(&info).await.unwrap();
+                placement.into_iter().map(|id| id.0).collect::<Vec<_>>()
+            }
+        };
+
+        // within a tier, the most space first. nodes which can't tell are assumed to have room
+        assert_eq!(placement(300).await, [2, 1, 5, 3]);
+        // 1 wouldn't have min_free_bytes left
+        assert_eq!(placement(450).await, [2, 5, 3]);
+        assert_eq!(placement(2_000_000).await, [5]);
+
+        // spaces are cached for a while, so 2 filling up isn't noticed right away
+        spaces[&2].store(0, Ordering::Relaxed);
+        assert_eq!(placement(300).await, [2, 1, 5, 3]);
+        let fetched_at = Instant::now().checked_sub(STORAGE_SPACE_CACHE_TTL).unwrap();
+        front_node.storage_space_cache.lock().unwrap().as_mut().unwrap().0 = fetched_at;
+        assert_eq!(placement(300).await, [1, 5, 3]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MySQL database, see test_support::DATABASE_URL_VAR"]
+    async fn downloads_are_recorded_in_batches() {
+        let cfg = test_support::database_config();
+        let front = test_support::DatabaseFrontNode::start_with_faults(&cfg, FaultInjector::default()).await;
+        let uuid = front.node.upload_file("cold".to_string(), front.dir, b"bnuy".to_vec(), UploadOptions::default()).await.unwrap();
+        let last_accessed = || async {
+            "SELECT last_accessed IS NOT NULL FROM files WHERE uuid = :uuid;"
+                .with(params! { "uuid" => uuid })
+                .first::<bool, _>(&front.node.conn_pool).await.unwrap().unwrap()
+        };
+
+        assert!(!last_accessed().await);
+        for _ in 0..3 {
+            front.node.get_file(uuid).await.unwrap();
+        }
+        // noted, but not written until the next flush
+        assert_eq!(front.node.accessed_files.lock().unwrap().len(), 1);
+        assert!(!last_accessed().await);
+        write_accessed_files(&front.node.conn_pool, &front.node.accessed_files).await;
+        assert!(last_accessed().await);
+        assert!(front.node.accessed_files.lock().unwrap().is_empty());
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    #[ignore = "needs a MySQL database, see test_support::DATABASE_URL_VAR"]
+    async fn deep_paths_are_resolved_in_one_query() {
+        let cfg = test_support::database_config();
+        let faults = FaultInjector::new();
+        let front = DatabaseFrontNode::start_with_faults(&cfg, faults.clone()).await;
+        let deep = "a/b/c/d/e/f/g/h";
+        let created = front.node.create_directories(deep, Some(front.dir)).await.unwrap();
+
+        let before = faults.queries_made();
+        assert_eq!(front.node.resolve_directory(deep, Some(front.dir)).await.unwrap(), created);
+        assert_eq!(faults.queries_made() - before, 1);
+
+        // so is finding how far a missing path exists
+        let before = faults.queries_made();
+        let missing = front.node.resolve_directory("a/b/c/d/e/nope/g/h", Some(front.dir)).await;
+        assert!(matches!(missing, Err(Error::NoSuchDirectory { ref topmost_existing_directory }) if topmost_existing_directory == "a/b/c/d/e/"), "{missing:?}");
+        assert_eq!(faults.queries_made() - before, 1);
+
+        // and once resolved, the path is cached
+        let path = format!("{}/{deep}", front.path);
+        assert_eq!(front.node.directory_id_for_path(&path, None).await.unwrap(), created);
+        let before = faults.queries_made();
+        assert_eq!(front.node.directory_id_for_path(&path, None).await.unwrap(), created);
+        assert_eq!(faults.queries_made(), before);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MySQL database, see test_support::DATABASE_URL_VAR"]
+    async fn created_directories_are_found_right_away() {
+        let cfg = test_support::database_config();
+        let front = test_support::DatabaseFrontNode::start_with_faults(&cfg, FaultInjector::default()).await;
+        let path = format!("{}/new", front.path);
+        let hits = || front.node.path_cache.directory_lookups.hits.load(Ordering::Relaxed);
+
+        let hits_before = hits();
+        for _ in 0..2 {
+            let missing = front.node.directory_id_for_path(&path, None).await;
+            assert!(matches!(missing, Err(Error::NoSuchDirectory { .. })), "{missing:?}");
+        }
+        assert_eq!(hits() - hits_before, 1);
+
+        // well within the cache's TTL
+        let created = front.node.create_directory(front.dir, "new".to_string()).await.unwrap();
+        assert_eq!(front.node.directory_id_for_path(&path, None).await.unwrap(), created);
     }
-    debug!("All nodes connected to");
 }
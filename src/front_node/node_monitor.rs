@@ -0,0 +1,333 @@
+//! Keeps the storage node connections in line with the config and the nodes table.
+//!
+//! The nodes table is read every node_monitor.poll_interval_s. Configured nodes which aren't
+//! disabled in the table are connected to, lost connections are made again, lost streams of a
+//! connection are replaced, and connections to nodes which were removed or disabled are dropped. The storage_nodes section of the config is
+//! reread on SIGHUP, after which the nodes table is synced with it as at startup. Connections
+//! attached in dev mode are left alone.
+
+#[allow(unused)]
+use tracing::{trace, debug, info, warn, error, instrument};
+
+use mysql_async::prelude::*;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{watch, RwLock};
+
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::FrontNode;
+use super::config::{Config, StorageNodeConfig};
+use super::startup_summary::StorageNodeOutcome;
+use super::storage_node_connection::StorageNodeConnection;
+use super::tys::{StorageNodeID, Error};
+use crate::fault_injection::FaultInjector;
+
+/// The storage_nodes section of the config, by name
+pub type NodeConfigs = HashMap<String, StorageNodeConfig>;
+
+pub struct NodeMonitor {
+    conn_pool: mysql_async::Pool,
+    active_connections: Arc<RwLock<HashMap<StorageNodeID, Arc<StorageNodeConnection>>>>,
+    /// shared with the FrontNode, which reads them
+    pub node_tiers: Arc<std::sync::RwLock<HashMap<StorageNodeID, u32>>>,
+    pub node_names: Arc<std::sync::RwLock<HashMap<StorageNodeID, String>>>,
+    faults: FaultInjector,
+    nodes: NodeConfigs,
+    /// connections made here, with the config they were made with. other connections were attached
+    managed: HashMap<StorageNodeID, StorageNodeConfig>,
+    /// nodes which couldn't be connected to last time, so that the error is only logged once
+    unreachable: HashSet<StorageNodeID>,
+}
+
+impl NodeMonitor {
+    pub fn new(
+        conn_pool: mysql_async::Pool,
+        active_connections: Arc<RwLock<HashMap<StorageNodeID, Arc<StorageNodeConnection>>>>,
+        faults: FaultInjector,
+        nodes: NodeConfigs,
+    ) -> Self {
+        NodeMonitor {
+            conn_pool,
+            active_connections,
+            node_tiers: Arc::new(std::sync::RwLock::new(HashMap::new())),
+            node_names: Arc::new(std::sync::RwLock::new(HashMap::new())),
+            faults,
+            nodes,
+            managed: HashMap::new(),
+            unreachable: HashSet::new(),
+        }
+    }
+
+    /// Reads the nodes table, drops the connections which aren't wanted anymore, and connects to
+    /// the wanted nodes which aren't connected. Returns what happened to each configured node
+    #[instrument(level = "debug", skip(self))]
+    pub async fn reconcile(&mut self) -> Result<Vec<StorageNodeOutcome>, Error> {
+        let rows: Vec<(StorageNodeID, String, u32, bool)> = "SELECT id, name, tier, disabled FROM nodes;"
+            .fetch(&self.conn_pool)
+            .await?;
+
+        *self.node_tiers.write().unwrap() = rows.iter().map(|(id, _, tier, _)| (*id, *tier)).collect();
+
+        let mut outcomes = Vec::new();
+        let mut wanted = HashMap::new();
+        for (id, name, _, disabled) in &rows {
+            let Some(node_cfg) = self.nodes.get(name) else {
+                continue;
+            };
+            if *disabled {
+                outcomes.push(outcome(name, node_cfg, Err("disabled in the nodes table".to_string())));
+            } else {
+                wanted.insert(*id, (name, node_cfg));
+            }
+        }
+
+        let mut active_connections = self.active_connections.write().await;
+        {
+            // attached nodes aren't configured, so their names are kept as they are
+            let mut node_names = self.node_names.write().unwrap();
+            node_names.retain(|id, _| active_connections.contains_key(id) && !self.managed.contains_key(id));
+            for (id, name, _, _) in &rows {
+                if self.nodes.contains_key(name) {
+                    node_names.insert(*id, name.clone());
+                }
+            }
+        }
+
+        let mut to_repair = Vec::new();
+        let managed: Vec<StorageNodeID> = self.managed.keys().copied().collect();
+        for id in managed {
+            let reason = match wanted.get(&id) {
+                None => "removed or disabled",
+                Some((_, node_cfg)) if !same_connection(&self.managed[&id], node_cfg) => "connection settings changed",
+                Some(_) if active_connections.get(&id).is_none_or(|conn| conn.is_disconnected()) => "connection lost",
+                Some(_) => {
+                    if let Some(conn) = active_connections.get(&id).filter(|conn| conn.lost_streams() > 0) {
+                        to_repair.push((id, conn.clone()));
+                    }
+                    continue;
+                }
+            };
+            info!(?id, reason, "Dropping storage node connection");
+            self.managed.remove(&id);
+            active_connections.remove(&id);
+        }
+
+        let mut to_connect = Vec::new();
+        for (id, (name, node_cfg)) in wanted {
+            if self.managed.contains_key(&id) {
+                outcomes.push(outcome(name, node_cfg, Ok(())));
+            } else if !active_connections.contains_key(&id) {
+                to_connect.push((id, name, node_cfg));
+            }
+        }
+        // connecting can take up to timeout_s, which requests shouldn't wait for
+        std::mem::drop(active_connections);
+
+        for (id, conn) in to_repair {
+            match conn.replace_lost_streams(&self.managed[&id]).await {
+                Ok(()) => info!(?id, "Replaced lost streams to storage node"),
+                Err(e) => warn!(?id, ?e, "Could not replace lost streams to storage node; the others carry on"),
+            }
+        }
+
+        for (id, name, node_cfg) in to_connect {
+            debug!(name, ?id, "Connecting");
+            let connected = match StorageNodeConnection::connect(node_cfg, self.faults.clone()).await {
+                Ok(conn) => {
+                    info!(name, "Connected successfully");
+                    self.active_connections.write().await.insert(id, Arc::new(conn));
+                    self.managed.insert(id, node_cfg.clone());
+                    self.unreachable.remove(&id);
+                    Ok(())
+                }
+                Err(e) => {
+                    if self.unreachable.insert(id) {
+                        error!(name, ?e, "Could not connect");
+                    } else {
+                        debug!(name, ?e, "Still could not connect");
+                    }
+                    Err(e.to_string())
+                }
+            };
+            outcomes.push(outcome(name, node_cfg, connected));
+        }
+
+        outcomes.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(outcomes)
+    }
+
+    /// Reconciles every `interval`, and as soon as the storage nodes are reloaded. Returns once the
+    /// front node is dropped
+    #[instrument(level = "info", name = "monitor_connections", skip_all)]
+    pub async fn run(mut self, interval: Duration, mut reloads: watch::Receiver<NodeConfigs>) {
+        let mut ticks = tokio::time::interval(interval);
+        ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        // the first tick is immediate, and startup has just reconciled
+        ticks.tick().await;
+        loop {
+            tokio::select! {
+                _ = ticks.tick() => {}
+                changed = reloads.changed() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                    let nodes = reloads.borrow_and_update().clone();
+                    if let Err(e) = sync_nodes_table(&self.conn_pool, &nodes).await {
+                        error!(?e, "Could not update the nodes table for the reloaded storage nodes; keeping the previous ones");
+                        continue;
+                    }
+                    info!(n_nodes = nodes.len(), "Storage nodes reloaded");
+                    self.nodes = nodes;
+                }
+            }
+            if let Err(e) = self.reconcile().await {
+                warn!(?e, "Could not check the storage nodes; trying again in {interval:?}");
+            }
+        }
+    }
+}
+
+fn outcome(name: &str, node_cfg: &StorageNodeConfig, connected: Result<(), String>) -> StorageNodeOutcome {
+    StorageNodeOutcome {
+        name: name.to_string(),
+        addr: node_cfg.addr.clone(),
+        connected,
+    }
+}
+
+// whether a connection made with `a` is as good as one made with `b`. the tier and previous names
+// only matter for the nodes table
+fn same_connection(a: &StorageNodeConfig, b: &StorageNodeConfig) -> bool {
+    a.addr == b.addr
+        && a.timeout_s == b.timeout_s
+        && a.request_timeout_s == b.request_timeout_s
+        && a.max_in_flight == b.max_in_flight
+        && a.connections == b.connections
+        && a.max_header_bytes == b.max_header_bytes
+        && a.max_data_bytes == b.max_data_bytes
+        && a.heartbeat_interval_s == b.heartbeat_interval_s
+        && a.heartbeat_max_missed == b.heartbeat_max_missed
+        && a.compression == b.compression
+        && a.auth_token == b.auth_token
+        && a.auth_token_file == b.auth_token_file
+        && a.tls == b.tls
+        && a.tls_ca_file == b.tls_ca_file
+        && a.tls_server_name == b.tls_server_name
+}
+
+/// Inserts nodes from the config which aren't in the nodes table, renaming nodes which are in it
+/// under a previous name, and sets their tiers
+#[instrument(level = "debug", skip_all)]
+pub async fn sync_nodes_table(conn_pool: &mysql_async::Pool, nodes: &NodeConfigs) -> Result<(), Error> {
+    debug!("Making nodes consistent");
+    for (name, node_cfg) in nodes {
+        trace!(name, "Checking");
+        let query = "SELECT count(*) FROM nodes WHERE name = :name;";
+        let count: u32 = query.with(params! {
+            "name" => name,
+        }).first(conn_pool).await?.unwrap_or(0);
+
+        let mut previous_names_in_db = Vec::new();
+        for previous_name in &node_cfg.previous_names {
+            let query = "SELECT id FROM nodes WHERE name = :name;";
+            let id: Option<StorageNodeID> = query.with(params! {
+                "name" => previous_name,
+            }).first(conn_pool).await?;
+            if let Some(id) = id {
+                previous_names_in_db.push((previous_name, id));
+            }
+        }
+
+        match (count, previous_names_in_db.as_slice()) {
+            (0, []) => {
+                warn!(
+                    name,
+                    "Node is not in the nodes table; inserting it as a new node. \
+                    If this node was renamed, add its old name to previous_names to keep its files",
+                );
+                let query = "INSERT INTO nodes(name) VALUES (:name);";
+                query.with(params! {
+                    "name" => name,
+                }).run(conn_pool).await?;
+            }
+            (0, [(previous_name, id)]) => {
+                info!(name, previous_name, ?id, "Renaming node");
+                let query = "UPDATE nodes SET name = :name WHERE id = :id;";
+                query.with(params! {
+                    "name" => name,
+                    "id" => id,
+                }).run(conn_pool).await?;
+            }
+            (0, _) => {
+                let previous_names: Vec<&String> = previous_names_in_db.iter().map(|(n, _)| *n).collect();
+                error!(name, ?previous_names, "Multiple previous names of node are in the nodes table. Not renaming any of them");
+            }
+            (_, []) => {}
+            (_, _) => {
+                // both the new and the old name exist. this is fine as long as the old rows are not in use
+                for (previous_name, id) in previous_names_in_db {
+                    let query = "SELECT count(*) FROM files WHERE stored_on_node_id = :id;";
+                    let n_files: u64 = query.with(params! {
+                        "id" => id,
+                    }).first(conn_pool).await?.unwrap_or(0);
+                    if n_files > 0 {
+                        error!(
+                            name, previous_name, ?id, n_files,
+                            "Both the node and its previous name are in the nodes table, and files are stored under the previous name. \
+                            Refusing to merge them automatically; move the files to the new node's row by hand",
+                        );
+                    } else {
+                        debug!(name, previous_name, ?id, "Previous name of node is in the nodes table, but has no files");
+                    }
+                }
+            }
+        }
+
+        let query = "UPDATE nodes SET tier = :tier WHERE name = :name;";
+        query.with(params! {
+            "tier" => node_cfg.tier,
+            "name" => name,
+        }).run(conn_pool).await?;
+    }
+
+    // nodes in the db but not in the config are most likely renamed or removed by mistake
+    let query = "SELECT name FROM nodes;";
+    let names_in_db: Vec<String> = query.fetch(conn_pool).await?;
+    for name in names_in_db {
+        if !nodes.contains_key(&name) {
+            warn!(name, "Node in nodes table is not in the config. Files stored on it will be unavailable");
+        }
+    }
+    Ok(())
+}
+
+/// Rereads the storage nodes from the config file on SIGHUP. A config with errors is ignored as a
+/// whole. Changes to the other sections need a restart
+pub fn reload_on_sighup(node: Arc<FrontNode>, path: PathBuf) -> std::io::Result<()> {
+    let mut sighup = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while sighup.recv().await.is_some() {
+            info!(path = %path.display(), "Got SIGHUP; reloading storage nodes");
+            let cfg = match Config::try_read_from_path(&path).await {
+                Ok(cfg) => cfg,
+                Err(e) => {
+                    error!(e, "Could not reload config; keeping the previous storage nodes");
+                    continue;
+                }
+            };
+            let errors = cfg.errors();
+            if !errors.is_empty() {
+                for e in &errors {
+                    error!("Invalid config: {e}");
+                }
+                error!("Keeping the previous storage nodes");
+                continue;
+            }
+            node.reload_storage_nodes(cfg.storage_nodes);
+        }
+    });
+    Ok(())
+}
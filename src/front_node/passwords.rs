@@ -0,0 +1,80 @@
+//! SFTP passwords, for clients which can't do public key authentication. Passwords are stored as
+//! argon2id hashes in PHC string form, which includes the salt and parameters, in users.password_hash.
+
+#[allow(unused)]
+use tracing::{trace, debug, info, warn, error, instrument};
+
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::{SaltString, rand_core::OsRng};
+use mysql_async::prelude::*;
+
+use super::FrontNode;
+use super::tys::Error;
+
+fn hash(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("hashing with the default parameters can't fail")
+        .to_string()
+}
+
+fn verify(password: &str, stored: &str) -> bool {
+    match PasswordHash::new(stored) {
+        Ok(stored) => Argon2::default().verify_password(password.as_bytes(), &stored).is_ok(),
+        Err(e) => {
+            error!(?e, "Stored password hash is malformed");
+            false
+        }
+    }
+}
+
+impl FrontNode {
+    /// Whether the user exists, has a password, and this is it. Hashing takes a while, so it is done
+    /// off the async threads
+    #[cfg_attr(not(feature = "sftp"), allow(unused))]
+    #[instrument(level = "debug", skip(self, password))]
+    pub async fn check_password(&self, user: &str, password: &str) -> Result<bool, Error> {
+        let query = "SELECT password_hash FROM users WHERE username = :user;";
+        let stored: Option<Option<String>> = query.with(params! { "user" => user }).first(self.pool()?).await?;
+        let Some(Some(stored)) = stored else {
+            debug!("No such user, or the user has no password");
+            return Ok(false);
+        };
+
+        let password = password.to_string();
+        let matches = tokio::task::spawn_blocking(move || verify(&password, &stored))
+            .await
+            .map_err(|e| Error::IO(std::io::Error::other(e)))?;
+        Ok(matches)
+    }
+
+    /// Sets or, with None, removes a user's password. Error::NoSuchUser if there is no such user
+    #[instrument(level = "info", skip(self, password))]
+    pub async fn set_password(&self, user: &str, password: Option<&str>) -> Result<(), Error> {
+        let password_hash = match password {
+            Some(password) => {
+                let password = password.to_string();
+                let hashed = tokio::task::spawn_blocking(move || hash(&password))
+                    .await
+                    .map_err(|e| Error::IO(std::io::Error::other(e)))?;
+                Some(hashed)
+            }
+            None => None,
+        };
+
+        let mut conn = self.pool()?.get_conn().await?;
+        let query = "UPDATE users SET password_hash = :password_hash WHERE username = :user;";
+        query.with(params! { "password_hash" => password_hash, "user" => user }).ignore(&mut conn).await?;
+        if conn.affected_rows() == 0 {
+            // also 0 when removing a password the user doesn't have
+            let query = "SELECT count(*) FROM users WHERE username = :user;";
+            let count: Option<u64> = query.with(params! { "user" => user }).first(&mut conn).await?;
+            if count.unwrap_or(0) == 0 {
+                return Err(Error::NoSuchUser { name: user.to_string() });
+            }
+        }
+        info!(has_password = password.is_some(), "Set password");
+        Ok(())
+    }
+}
@@ -0,0 +1,238 @@
+//! Recently resolved paths, so that SFTP clients statting the same paths over and over don't each
+//! cost a query.
+//!
+//! Paths which don't exist are cached too. Entries expire after path_cache.ttl_ms, which bounds how
+//! long changes made by other front nodes sharing the database go unnoticed. Changes made through
+//! this front node clear the cache right away: any change to a directory clears everything, and any
+//! change to a file clears the file entries.
+
+#[allow(unused)]
+use tracing::{trace, debug, info, warn, error, instrument};
+
+use uuid::Uuid;
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use super::config::PathCacheOptions;
+use super::tys::DirectoryID;
+
+/// A path and the directory it's relative to, None for the root
+pub type PathKey = (Option<DirectoryID>, String);
+
+/// Where a directory path leads, or the topmost existing directory of one which doesn't exist
+pub type DirectoryLookup = Result<DirectoryID, String>;
+
+#[derive(Debug)]
+struct Entries<V> {
+    entries: HashMap<PathKey, (Instant, V)>,
+    /// bumped on every invalidation, so that a lookup which raced with a change isn't cached
+    generation: u64,
+}
+
+impl<V: Clone> Entries<V> {
+    fn new() -> Self {
+        Entries { entries: HashMap::new(), generation: 0 }
+    }
+
+    fn get(&self, key: &PathKey, ttl: Duration) -> Option<V> {
+        self.entries.get(key)
+            .filter(|(cached_at, _)| cached_at.elapsed() < ttl)
+            .map(|(_, value)| value.clone())
+    }
+
+    fn insert(&mut self, key: PathKey, value: V, generation: u64, options: &PathCacheOptions) {
+        if generation != self.generation {
+            trace!("Changed since the lookup started; not caching");
+            return;
+        }
+        if self.entries.len() >= options.max_entries {
+            let ttl = Duration::from_millis(options.ttl_ms);
+            self.entries.retain(|_, (cached_at, _)| cached_at.elapsed() < ttl);
+            if self.entries.len() >= options.max_entries {
+                debug!(n_entries = self.entries.len(), "Path cache full; clearing it");
+                self.entries.clear();
+            }
+        }
+        self.entries.insert(key, (Instant::now(), value));
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.generation += 1;
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Counters {
+    pub hits: AtomicU64,
+    pub misses: AtomicU64,
+}
+
+#[derive(Debug)]
+pub struct PathCache {
+    options: PathCacheOptions,
+    directories: Mutex<Entries<DirectoryLookup>>,
+    /// None for files which don't exist
+    files: Mutex<Entries<Option<Uuid>>>,
+    pub directory_lookups: Counters,
+    pub file_lookups: Counters,
+}
+
+impl PathCache {
+    pub fn new(options: PathCacheOptions) -> Self {
+        PathCache {
+            options,
+            directories: Mutex::new(Entries::new()),
+            files: Mutex::new(Entries::new()),
+            directory_lookups: Counters::default(),
+            file_lookups: Counters::default(),
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.options.ttl_ms > 0 && self.options.max_entries > 0
+    }
+
+    /// The cached directory lookup, or else a generation to pass to insert_directory
+    pub fn directory(&self, key: &PathKey) -> Result<DirectoryLookup, u64> {
+        lookup(&self.directories, &self.directory_lookups, key, self.enabled(), self.options.ttl_ms)
+    }
+
+    pub fn insert_directory(&self, key: PathKey, value: DirectoryLookup, generation: u64) {
+        if self.enabled() {
+            self.directories.lock().unwrap().insert(key, value, generation, &self.options);
+        }
+    }
+
+    /// The cached file lookup, or else a generation to pass to insert_file
+    pub fn file(&self, key: &PathKey) -> Result<Option<Uuid>, u64> {
+        lookup(&self.files, &self.file_lookups, key, self.enabled(), self.options.ttl_ms)
+    }
+
+    pub fn insert_file(&self, key: PathKey, value: Option<Uuid>, generation: u64) {
+        if self.enabled() {
+            self.files.lock().unwrap().insert(key, value, generation, &self.options);
+        }
+    }
+
+    /// For after directories are created, moved or removed. File paths go through directories, so
+    /// they are forgotten too
+    pub fn invalidate_directories(&self) {
+        self.directories.lock().unwrap().clear();
+        self.invalidate_files();
+    }
+
+    /// For after files are added, moved or deleted
+    pub fn invalidate_files(&self) {
+        self.files.lock().unwrap().clear();
+    }
+}
+
+fn lookup<V: Clone>(
+    entries: &Mutex<Entries<V>>,
+    counters: &Counters,
+    key: &PathKey,
+    enabled: bool,
+    ttl_ms: u64,
+) -> Result<V, u64> {
+    let entries = entries.lock().unwrap();
+    if enabled {
+        if let Some(value) = entries.get(key, Duration::from_millis(ttl_ms)) {
+            counters.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(value);
+        }
+        counters.misses.fetch_add(1, Ordering::Relaxed);
+    }
+    Err(entries.generation)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(path: &str) -> PathKey {
+        (None, path.to_string())
+    }
+
+    fn hits_and_misses(counters: &Counters) -> (u64, u64) {
+        (counters.hits.load(Ordering::Relaxed), counters.misses.load(Ordering::Relaxed))
+    }
+
+    #[test]
+    fn missing_directories_are_forgotten_once_changed() {
+        let cache = PathCache::new(PathCacheOptions::default());
+        let key = key("burrow/carrots");
+
+        let generation = cache.directory(&key).unwrap_err();
+        cache.insert_directory(key.clone(), Err("burrow/".to_string()), generation);
+        assert_eq!(cache.directory(&key), Ok(Err("burrow/".to_string())));
+        // e.g. after burrow/carrots was created
+        cache.invalidate_directories();
+        let generation = cache.directory(&key).unwrap_err();
+        cache.insert_directory(key.clone(), Ok(DirectoryID(7)), generation);
+        assert_eq!(cache.directory(&key), Ok(Ok(DirectoryID(7))));
+
+        assert_eq!(hits_and_misses(&cache.directory_lookups), (2, 2));
+        assert_eq!(hits_and_misses(&cache.file_lookups), (0, 0));
+    }
+
+    #[test]
+    fn lookups_racing_with_changes_are_not_cached() {
+        let cache = PathCache::new(PathCacheOptions::default());
+        let key = key("burrow/carrots");
+
+        // the directory is created while its lookup is querying the database
+        let generation = cache.directory(&key).unwrap_err();
+        cache.invalidate_directories();
+        cache.insert_directory(key.clone(), Err("burrow/".to_string()), generation);
+        assert!(cache.directory(&key).is_err());
+
+        // files go through directories, so they are forgotten with them
+        let generation = cache.file(&key).unwrap_err();
+        cache.invalidate_directories();
+        cache.insert_file(key.clone(), None, generation);
+        assert!(cache.file(&key).is_err());
+    }
+
+    #[test]
+    fn file_changes_keep_directories() {
+        let cache = PathCache::new(PathCacheOptions::default());
+        let (dir, file) = (key("burrow"), key("burrow/carrots.txt"));
+        cache.insert_directory(dir.clone(), Ok(DirectoryID(7)), cache.directory(&dir).unwrap_err());
+        cache.insert_file(file.clone(), None, cache.file(&file).unwrap_err());
+
+        cache.invalidate_files();
+        assert!(cache.file(&file).is_err());
+        assert_eq!(cache.directory(&dir), Ok(Ok(DirectoryID(7))));
+    }
+
+    #[test]
+    fn entries_expire_and_are_bounded() {
+        let cache = PathCache::new(PathCacheOptions { ttl_ms: 50, max_entries: 2 });
+        let insert = |path: &str| {
+            let key = key(path);
+            cache.insert_directory(key.clone(), Ok(DirectoryID(1)), cache.directory(&key).unwrap_err());
+        };
+
+        insert("a");
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(cache.directory(&key("a")).is_err());
+        // a is expired, so it makes room for c
+        insert("b");
+        insert("c");
+        assert!(cache.directory(&key("b")).is_ok() && cache.directory(&key("c")).is_ok());
+        // full of fresh entries, which are all dropped
+        insert("d");
+        assert!(cache.directory(&key("b")).is_err() && cache.directory(&key("c")).is_err());
+        assert!(cache.directory(&key("d")).is_ok());
+
+        let off = PathCache::new(PathCacheOptions { ttl_ms: 0, max_entries: 2 });
+        off.insert_directory(key("a"), Ok(DirectoryID(1)), off.directory(&key("a")).unwrap_err());
+        assert!(off.directory(&key("a")).is_err());
+        assert_eq!(hits_and_misses(&off.directory_lookups), (0, 0));
+    }
+}
@@ -0,0 +1,238 @@
+//! Per-user storage quotas, from users.quota_bytes.
+//!
+//! A user's usage is the total size of the files in their home directory and everything below it.
+//! Files in the trash and old versions aren't under any home directory, so they don't count.
+//! Summing the sizes walks the whole tree, so usage is cached, and writes through this front node
+//! adjust the cached figure instead of summing again. SFTP sessions check their user's quota as files
+//! are stored, and HTTP uploads check it for the user whose token they carry.
+
+#[allow(unused)]
+use tracing::{trace, debug, info, warn, error, instrument};
+
+use mysql_async::prelude::*;
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use super::{FrontNode, StorageSpace};
+use super::tys::{DirectoryID, Error};
+
+/// How long a user's usage and quota are reused for. Writes through other front nodes, and quotas
+/// changed in the database, take up to this long to be seen
+const USAGE_CACHE_TTL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserUsage {
+    pub used_bytes: u64,
+    /// None if the user has no quota
+    pub quota_bytes: Option<u64>,
+}
+
+pub type UsageCache = std::sync::Mutex<HashMap<String, (Instant, UserUsage)>>;
+
+impl UserUsage {
+    /// What the user may use of `space`: no more than their quota in total, and no more than what
+    /// remains of it free
+    #[cfg_attr(not(feature = "sftp"), allow(unused))]
+    pub fn limit(&self, space: StorageSpace) -> StorageSpace {
+        let Some(quota) = self.quota_bytes else {
+            return space;
+        };
+        StorageSpace {
+            bytes_total: space.bytes_total.min(quota),
+            bytes_available: space.bytes_available.min(quota.saturating_sub(self.used_bytes)),
+        }
+    }
+
+    /// Whether the user may grow their usage by `growth` bytes. Shrinking is always allowed, even
+    /// over quota, so users can make room by replacing files with smaller ones
+    pub fn check(&self, growth: i64) -> Result<(), String> {
+        let Some(quota) = self.quota_bytes else {
+            return Ok(());
+        };
+        if growth > 0 && self.used_bytes.saturating_add(growth as u64) > quota {
+            return Err(format!("quota exceeded: {} bytes used of {quota}", self.used_bytes));
+        }
+        Ok(())
+    }
+}
+
+impl FrontNode {
+    /// The user's usage and quota. Error::NoSuchUser if there is no such user
+    #[instrument(level = "trace", skip(self))]
+    pub async fn usage_for_user(&self, name: &str) -> Result<UserUsage, Error> {
+        if let Some((fetched_at, usage)) = self.usage_cache.lock().unwrap().get(name) {
+            if fetched_at.elapsed() < USAGE_CACHE_TTL {
+                return Ok(*usage);
+            }
+        }
+
+        let query = r#"
+            WITH RECURSIVE tree (id) AS (
+                SELECT home_directory FROM users WHERE username = :name
+                UNION ALL
+                SELECT directories.id FROM directories JOIN tree ON directories.parent_id = tree.id
+            )
+            SELECT quota_bytes,
+                   (SELECT CAST(COALESCE(SUM(files.size), 0) AS UNSIGNED) FROM files
+                        WHERE files.directory_id IN (SELECT id FROM tree))
+                FROM users WHERE username = :name;
+        "#;
+        let row: Option<(Option<u64>, u64)> = query.with(params! { "name" => name }).first(self.pool()?).await?;
+        let Some((quota_bytes, used_bytes)) = row else {
+            return Err(Error::NoSuchUser { name: name.to_string() });
+        };
+        let usage = UserUsage { used_bytes, quota_bytes };
+
+        trace!(?usage, "Summed usage");
+        self.usage_cache.lock().unwrap().insert(name.to_string(), (Instant::now(), usage));
+        Ok(usage)
+    }
+
+    /// Adjusts the cached usage of the user after they stored or removed `growth` bytes, so the
+    /// next check sees it without summing the tree again
+    pub fn add_usage(&self, name: &str, growth: i64) {
+        if let Some((_, usage)) = self.usage_cache.lock().unwrap().get_mut(name) {
+            usage.used_bytes = usage.used_bytes.saturating_add_signed(growth);
+        }
+    }
+
+    /// How much storing `size` bytes as `filename` in `dir` grows the user's usage: the size, less
+    /// that of the file it replaces with `overwrite`. Error::QuotaExceeded if their quota doesn't
+    /// allow for that
+    pub(super) async fn check_quota(
+        &self,
+        name: &str,
+        filename: &str,
+        dir: DirectoryID,
+        overwrite: bool,
+        size: u64,
+    ) -> Result<i64, Error> {
+        let replaced_size = match overwrite {
+            true => {
+                let query = "SELECT size FROM files WHERE name = :name AND directory_id = :dir;";
+                let replaced: Option<Option<u64>> = query.with(params! { "name" => filename, "dir" => dir }).first(self.pool()?).await?;
+                replaced.flatten().unwrap_or(0)
+            }
+            false => 0,
+        };
+        let growth = size as i64 - replaced_size as i64;
+        if let Err(message) = self.usage_for_user(name).await?.check(growth) {
+            info!(name, filename, size, message, "Upload refused for the user's quota");
+            return Err(Error::QuotaExceeded { message });
+        }
+        Ok(growth)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::front_node::UploadOptions;
+    use crate::front_node::test_support::{self, DatabaseFrontNode};
+    use crate::fault_injection::FaultInjector;
+
+    #[test]
+    fn checks() {
+        let usage = UserUsage { used_bytes: 90, quota_bytes: Some(100) };
+        assert_eq!(usage.check(10), Ok(()));
+        assert_eq!(usage.check(11), Err("quota exceeded: 90 bytes used of 100".to_string()));
+        assert_eq!(usage.check(-50), Ok(()));
+
+        let over = UserUsage { used_bytes: 150, quota_bytes: Some(100) };
+        assert_eq!(over.check(0), Ok(()));
+        assert_eq!(over.check(-10), Ok(()));
+        assert!(over.check(1).is_err());
+
+        let unlimited = UserUsage { used_bytes: 90, quota_bytes: None };
+        assert_eq!(unlimited.check(i64::MAX), Ok(()));
+    }
+
+    #[test]
+    fn limits() {
+        let space = |bytes_total, bytes_available| StorageSpace { bytes_total, bytes_available };
+        let limited = |used_bytes, quota_bytes, device: StorageSpace| {
+            let user = UserUsage { used_bytes, quota_bytes }.limit(device);
+            (user.bytes_total, user.bytes_available)
+        };
+        // the quota is the smaller
+        assert_eq!(limited(90, Some(100), space(1000, 500)), (100, 10));
+        // the nodes have less room left than the quota
+        assert_eq!(limited(90, Some(100), space(1000, 5)), (100, 5));
+        assert_eq!(limited(90, Some(10_000), space(1000, 500)), (1000, 500));
+        assert_eq!(limited(150, Some(100), space(1000, 500)), (100, 0));
+        assert_eq!(limited(90, None, space(1000, 500)), (1000, 500));
+    }
+
+    #[tokio::test]
+    async fn cached_usage_is_adjusted_by_writes() {
+        let node = test_support::offline(&test_support::offline_config());
+        let usage = UserUsage { used_bytes: 100, quota_bytes: Some(1000) };
+        node.usage_cache.lock().unwrap().insert("bnuy".to_string(), (Instant::now(), usage));
+
+        // offline, so these can only come from the cache
+        assert_eq!(node.usage_for_user("bnuy").await.unwrap(), usage);
+        node.add_usage("bnuy", 50);
+        node.add_usage("bnuy", -20);
+        assert_eq!(node.usage_for_user("bnuy").await.unwrap().used_bytes, 130);
+        node.add_usage("bnuy", -1000);
+        assert_eq!(node.usage_for_user("bnuy").await.unwrap().used_bytes, 0);
+
+        // nothing cached to adjust, so the next lookup sums it instead
+        node.add_usage("xenia", 50);
+        assert!(node.usage_cache.lock().unwrap().get("xenia").is_none());
+
+        let fetched_at = Instant::now() - USAGE_CACHE_TTL;
+        node.usage_cache.lock().unwrap().insert("bnuy".to_string(), (fetched_at, usage));
+        assert!(node.usage_for_user("bnuy").await.is_err(), "expired usage should be summed again");
+    }
+
+    #[tokio::test]
+    async fn uploads_over_quota_are_refused_before_anything_else() {
+        let node = test_support::offline(&test_support::offline_config());
+        let usage = UserUsage { used_bytes: 90, quota_bytes: Some(100) };
+        node.usage_cache.lock().unwrap().insert("bnuy".to_string(), (Instant::now(), usage));
+        let options = UploadOptions { user: Some("bnuy"), ..Default::default() };
+
+        // offline, so refused without the database
+        let checked = node.check_upload("big", DirectoryID(1), true, Some(11), options).await;
+        assert!(matches!(checked, Err(Error::QuotaExceeded { ref message }) if message == "quota exceeded: 90 bytes used of 100"), "{checked:?}");
+        // within the quota, so the next checks need the database
+        let checked = node.check_upload("small", DirectoryID(1), true, Some(10), options).await;
+        assert!(matches!(checked, Err(Error::Database(_))), "{checked:?}");
+        let options = UploadOptions { user: None, ..options };
+        let checked = node.check_upload("big", DirectoryID(1), true, Some(11), options).await;
+        assert!(matches!(checked, Err(Error::Database(_))), "{checked:?}");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MySQL database, see test_support::DATABASE_URL_VAR"]
+    async fn usage_is_summed_from_the_home_directory() {
+        let front = DatabaseFrontNode::start_with_faults(&test_support::database_config(), FaultInjector::default()).await;
+        let node = &front.node;
+        let name = format!("quota-{}", uuid::Uuid::now_v7());
+        let (_, home) = node.create_user(&name, None, &front.path).await.unwrap();
+
+        let usage = node.usage_for_user(&name).await.unwrap();
+        assert_eq!(usage, UserUsage { used_bytes: 0, quota_bytes: None });
+
+        let deeper = node.create_directories("a/b", Some(home)).await.unwrap();
+        node.upload_file("top".to_string(), home, vec![0; 10], Default::default()).await.unwrap();
+        node.upload_file("deep".to_string(), deeper, vec![0; 5], Default::default()).await.unwrap();
+        // outside the home directory, so not counted
+        node.upload_file(format!("{name}-elsewhere"), front.dir, vec![0; 1000], Default::default()).await.unwrap();
+        "UPDATE users SET quota_bytes = 100 WHERE username = :name;"
+            .with(params! { "name" => &name })
+            .ignore(&node.conn_pool)
+            .await
+            .unwrap();
+
+        // still cached from before
+        assert_eq!(node.usage_for_user(&name).await.unwrap().used_bytes, 0);
+        node.usage_cache.lock().unwrap().clear();
+        assert_eq!(node.usage_for_user(&name).await.unwrap(), UserUsage { used_bytes: 15, quota_bytes: Some(100) });
+
+        assert!(matches!(node.usage_for_user("no such user").await, Err(Error::NoSuchUser { .. })));
+    }
+}
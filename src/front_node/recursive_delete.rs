@@ -0,0 +1,132 @@
+//! Deleting a directory along with everything in it.
+//!
+//! Files are deleted one by one with remove_file, so a file whose storage node can't delete it keeps
+//! its row. With trash configured, files are moved to the trash instead. They can't be restored by
+//! path once their directory is removed, but their contents are kept until they are purged.
+//! Directories are then removed deepest first, and only once empty, so the directories holding a
+//! file which couldn't be deleted are kept too. Nothing is left referencing a deleted row, and
+//! deleting the directory again later picks up where this left off.
+
+#[allow(unused)]
+use tracing::{trace, debug, info, warn, error, instrument};
+
+use futures::StreamExt;
+use mysql_async::prelude::*;
+use uuid::Uuid;
+
+use super::FrontNode;
+use super::tys::{DirectoryID, Error};
+
+/// How many files are deleted at once
+const DELETE_CONCURRENCY: usize = 8;
+
+/// Reports list at most this many failed files. The count is always complete
+const MAX_LISTED_FAILURES: usize = 1000;
+
+#[derive(Debug, Default, Clone, serde::Serialize)]
+pub struct DirectoryDeletion {
+    pub files_deleted: u64,
+    pub directories_deleted: u64,
+    /// files which could not be deleted, e.g. because their storage node is down
+    pub files_failed_count: u64,
+    pub files_failed: Vec<Uuid>,
+    /// directories kept because something in them could not be deleted, or was added meanwhile
+    pub directories_kept: u64,
+}
+
+impl DirectoryDeletion {
+    pub fn is_complete(&self) -> bool {
+        self.files_failed_count == 0 && self.directories_kept == 0
+    }
+}
+
+impl FrontNode {
+    /// Deletes a directory. Without `recursive`, it has to be empty, see remove_directory. With it,
+    /// everything in it is deleted first, and failures are reported rather than returned. Files
+    /// skip the trash if `permanent`
+    #[instrument(level = "info", skip(self))]
+    pub async fn delete_directory(&self, dir: DirectoryID, recursive: bool, permanent: bool) -> Result<DirectoryDeletion, Error> {
+        if !recursive {
+            self.remove_directory(dir).await?;
+            return Ok(DirectoryDeletion { directories_deleted: 1, ..Default::default() });
+        }
+
+        let query = "SELECT parent_id FROM directories WHERE id = :dir;";
+        let parent: Option<Option<DirectoryID>> = query.with(params! { "dir" => dir }).first(self.pool()?).await?;
+        match parent {
+            None => return Err(Error::NoSuchDirectory { topmost_existing_directory: String::new() }),
+            Some(None) => return Err(Error::CannotRemoveRoot),
+            Some(Some(_)) => {}
+        }
+        // checked before anything in it is deleted. remove_directory checks it again
+        let query = "SELECT EXISTS(SELECT * FROM users WHERE home_directory = :dir);";
+        let is_home: Option<bool> = query.with(params! { "dir" => dir }).first(self.pool()?).await?;
+        if is_home.unwrap_or(false) {
+            return Err(Error::DirectoryIsHome);
+        }
+
+        let tree = r#"
+            WITH RECURSIVE tree (id, depth) AS (
+                SELECT :dir, 0
+                UNION ALL
+                SELECT directories.id, tree.depth + 1
+                    FROM directories JOIN tree ON directories.parent_id = tree.id
+            )
+        "#;
+        let directories: Vec<DirectoryID> = format!("{tree} SELECT id FROM tree ORDER BY depth DESC;")
+            .with(params! { "dir" => dir })
+            .fetch(self.pool()?)
+            .await?;
+        let files: Vec<Uuid> = format!("{tree} SELECT files.uuid FROM files JOIN tree ON files.directory_id = tree.id;")
+            .with(params! { "dir" => dir })
+            .fetch(self.pool()?)
+            .await?;
+        debug!(n_directories = directories.len(), n_files = files.len(), "Deleting directory tree");
+
+        let mut report = DirectoryDeletion::default();
+        let mut deletions = futures::stream::iter(files)
+            .map(|uuid| async move { (uuid, self.remove_file(uuid, permanent).await) })
+            .buffer_unordered(DELETE_CONCURRENCY);
+        while let Some((uuid, result)) = deletions.next().await {
+            match result {
+                // NoSuchFile if it was deleted meanwhile
+                Ok(()) | Err(Error::NoSuchFile) => report.files_deleted += 1,
+                Err(e) => {
+                    warn!(?e, %uuid, "Could not delete file");
+                    report.files_failed_count += 1;
+                    if report.files_failed.len() < MAX_LISTED_FAILURES {
+                        report.files_failed.push(uuid);
+                    }
+                }
+            }
+        }
+        drop(deletions);
+
+        for directory in directories {
+            match self.remove_directory(directory).await {
+                Ok(()) | Err(Error::NoSuchDirectory { .. }) => report.directories_deleted += 1,
+                Err(Error::DirectoryNotEmpty) => {
+                    debug!(?directory, "Directory is not empty; keeping it");
+                    report.directories_kept += 1;
+                }
+                Err(e) => {
+                    warn!(?e, ?directory, "Could not remove directory");
+                    report.directories_kept += 1;
+                }
+            }
+        }
+
+        if report.is_complete() {
+            info!(report.files_deleted, report.directories_deleted, "Deleted directory tree");
+        } else {
+            warn!(
+                report.files_deleted,
+                report.directories_deleted,
+                report.files_failed_count,
+                report.directories_kept,
+                "Directory tree was only partly deleted",
+            );
+        }
+        Ok(report)
+    }
+}
@@ -0,0 +1,112 @@
+//! Listing a whole subtree at once, for mirroring it without a request per directory.
+//!
+//! The tree is walked by the database with a recursive CTE, down to a depth limit. Directories at
+//! the limit are listed, and marked if they have contents which weren't.
+
+#[allow(unused)]
+use tracing::{trace, debug, info, warn, error, instrument, Instrument, Span};
+
+use futures::{channel::mpsc, SinkExt, StreamExt};
+use mysql_async::prelude::*;
+use uuid::Uuid;
+
+use super::FrontNode;
+use super::tys::{DirectoryID, Error};
+
+/// One entry in a recursive listing. Paths are relative to the listed directory, separated by `/`
+#[derive(Debug, serde::Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum TreeRow {
+    /// the size is None for files uploaded before sizes were stored
+    File { path: String, uuid: Uuid, size: Option<u64> },
+    /// depth_limited is set if the directory has contents which are too deep to be listed
+    Directory { path: String, id: DirectoryID, depth_limited: bool },
+}
+
+/// kind, uuid, id, path, size, depth_limited
+type TreeQueryRow = (String, Option<Uuid>, Option<DirectoryID>, String, Option<u64>, bool);
+
+impl FrontNode {
+    /// Everything below `dir`, down to `max_depth` levels (1 lists only what is directly in `dir`), ordered by
+    /// path so that directories come before their contents. At most `max_entries + 1` rows are
+    /// sent, so that the receiver can tell whether the listing was cut short
+    #[instrument(level = "debug", skip(self))]
+    pub fn list_tree(
+        &self,
+        dir: DirectoryID,
+        max_depth: u32,
+        max_entries: u64,
+        buffer_rows: usize,
+    ) -> mpsc::Receiver<Result<TreeRow, Error>> {
+        let (mut tx, rx) = mpsc::channel(buffer_rows);
+        let pool = self.pool().cloned();
+
+        tokio::spawn(async move {
+            let result = async {
+                let mut conn = pool?.get_conn().await?;
+
+                // files.name is a BLOB, so paths are compared as bytes. a directory's path is a prefix
+                // of its contents' paths, so it always comes first
+                let query = r#"
+                    WITH RECURSIVE tree (id, path, depth) AS (
+                        SELECT :dir, CAST('' AS CHAR(4096)), 0
+                        UNION ALL
+                        SELECT directories.id,
+                            IF(tree.depth = 0, directories.name, CONCAT(tree.path, '/', directories.name)),
+                            tree.depth + 1
+                            FROM directories JOIN tree ON directories.parent_id = tree.id
+                            WHERE tree.depth < :max_depth
+                    )
+                    SELECT kind, uuid, id, path, size, depth_limited FROM (
+                        SELECT 'directory' AS kind, NULL AS uuid, tree.id, tree.path, NULL AS size,
+                            tree.depth = :max_depth AND (
+                                EXISTS (SELECT 1 FROM directories WHERE parent_id = tree.id)
+                                OR EXISTS (SELECT 1 FROM files WHERE directory_id = tree.id)
+                            ) AS depth_limited
+                            FROM tree WHERE tree.depth > 0
+                        UNION ALL
+                        SELECT 'file' AS kind, files.uuid, NULL AS id,
+                            IF(tree.depth = 0, files.name, CONCAT(tree.path, '/', files.name)) AS path,
+                            files.size, FALSE AS depth_limited
+                            FROM files JOIN tree ON files.directory_id = tree.id
+                            WHERE tree.depth < :max_depth
+                    ) AS entries
+                        ORDER BY path, kind
+                        LIMIT :limit;
+                    "#;
+                let mut entries = query.with(params! {
+                    "dir" => &dir,
+                    "max_depth" => max_depth,
+                    "limit" => max_entries.saturating_add(1),
+                }).stream::<TreeQueryRow, _>(&mut conn).await?;
+
+                let mut n_entries = 0;
+                while let Some(row) = entries.next().await {
+                    let row = match row? {
+                        (_, Some(uuid), _, path, size, _) => TreeRow::File { path, uuid, size },
+                        (_, None, Some(id), path, _, depth_limited) => TreeRow::Directory { path, id, depth_limited },
+                        (kind, None, None, path, _, _) => {
+                            warn!(kind, path, "Listed entry with neither UUID nor id");
+                            continue;
+                        }
+                    };
+                    if tx.send(Ok(row)).await.is_err() {
+                        trace!("Receiver dropped, stopping listing");
+                        return Ok(());
+                    }
+                    n_entries += 1;
+                }
+
+                trace!(n_entries, "Listed tree");
+                Ok::<(), Error>(())
+            }.await;
+
+            if let Err(e) = result {
+                error!(?e, "Error listing directory tree");
+                let _ = tx.send(Err(e)).await;
+            }
+        }.instrument(Span::current()));
+
+        rx
+    }
+}
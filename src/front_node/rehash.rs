@@ -0,0 +1,128 @@
+#[allow(unused)]
+use tracing::{trace, debug, info, warn, error, instrument};
+
+use mysql_async::prelude::*;
+use uuid::Uuid;
+
+use std::time::Duration;
+
+use super::FrontNode;
+use super::tys::{StorageNodeID, Error};
+use crate::hashing::{ContentDigest, HashAlgorithm};
+use crate::message::Message;
+
+/// Files are looked up this many at a time
+const REHASH_BATCH_SIZE: u32 = 100;
+
+/// uuid, stored_on_node_id, UUID of the contents, hash_algorithm, digest
+type FileDigestRow = (Uuid, StorageNodeID, Uuid, Option<String>, Option<Vec<u8>>);
+
+/// The digest stored in files.hash_algorithm and files.digest, if any
+pub(super) fn stored_digest(algorithm: Option<String>, digest: Option<Vec<u8>>) -> Option<ContentDigest> {
+    let algorithm = HashAlgorithm::from_name(&algorithm?)?;
+    Some(ContentDigest { algorithm, bytes: digest? })
+}
+
+#[derive(Debug, Default, Clone, Copy, serde::Serialize)]
+pub struct RehashSummary {
+    pub rehashed: u64,
+    /// files whose contents no longer match their old digest. these keep the old digest
+    pub mismatched: u64,
+    /// files which could not be hashed, e.g. because their storage node is down
+    pub failed: u64,
+}
+
+impl FrontNode {
+    /// Recomputes the digests of all files not hashed with `target`, including files without a
+    /// digest or size, whose size is filled in too. Hashing happens on the storage nodes, and reads are paced to about `bytes_per_s`.
+    /// Files with an old digest are checked against it first, so a corrupted file isn't given a
+    /// fresh digest of its corrupted contents
+    #[instrument(level = "info", skip(self))]
+    pub async fn rehash_files(&self, target: HashAlgorithm, bytes_per_s: u64) -> Result<RehashSummary, Error> {
+        let mut summary = RehashSummary::default();
+        // files are walked in UUID order, so files which fail aren't picked up again
+        let mut after = Uuid::nil();
+
+        loop {
+            let query = r#"
+                SELECT uuid, stored_on_node_id, COALESCE(blob_uuid, uuid), hash_algorithm, digest FROM files
+                    WHERE uuid > :after AND (hash_algorithm IS NULL OR hash_algorithm != :target OR size IS NULL)
+                    ORDER BY uuid
+                    LIMIT :batch_size;
+            "#;
+            let batch: Vec<FileDigestRow> = query.with(params! {
+                "after" => after,
+                "target" => target.name(),
+                "batch_size" => REHASH_BATCH_SIZE,
+            }).fetch(self.pool()?).await?;
+            let Some(&(last, ..)) = batch.last() else {
+                break;
+            };
+            after = last;
+
+            for (uuid, node_id, blob, algorithm, digest) in batch {
+                let old_digest = stored_digest(algorithm, digest);
+                match self.rehash_file(uuid, node_id, blob, old_digest, target, bytes_per_s).await {
+                    Ok(true) => summary.rehashed += 1,
+                    Ok(false) => summary.mismatched += 1,
+                    Err(e) => {
+                        warn!(?e, %uuid, "Could not rehash file");
+                        summary.failed += 1;
+                    }
+                }
+            }
+            debug!(?summary, "Rehashed batch");
+        }
+
+        info!(?summary, "Rehash done");
+        Ok(summary)
+    }
+
+    // false if the file doesn't match its old digest
+    async fn rehash_file(
+        &self,
+        uuid: Uuid,
+        node_id: StorageNodeID,
+        blob: Uuid,
+        old_digest: Option<ContentDigest>,
+        target: HashAlgorithm,
+        bytes_per_s: u64,
+    ) -> Result<bool, Error> {
+        let conn = match self.active_connections.read().await.get(&node_id) {
+            Some(conn) => conn.clone(),
+            None => return Err(Error::NotConnectedToNode),
+        };
+
+        let hash = |algorithm| {
+            let conn = conn.clone();
+            async move {
+                let (size, digest) = match conn.request(Message::HashFile(blob, algorithm)).await? {
+                    Message::FileHash { size, digest } => (size, digest),
+                    x => return Err(Error::UnexpectedResponse(x)),
+                };
+                // stay within the bandwidth budget
+                tokio::time::sleep(Duration::from_secs_f64(size as f64 / bytes_per_s.max(1) as f64)).await;
+                Ok((size, digest))
+            }
+        };
+
+        if let Some(old_digest) = old_digest {
+            let (_, current) = hash(old_digest.algorithm).await?;
+            if current != old_digest {
+                error!(%uuid, expected = %old_digest, actual = %current, "File does not match its digest; not rehashing");
+                return Ok(false);
+            }
+        }
+
+        let (size, digest) = hash(target).await?;
+        let query = "UPDATE files SET hash_algorithm = :hash_algorithm, digest = :digest, size = :size WHERE uuid = :uuid;";
+        query.with(params! {
+            "uuid" => uuid,
+            "size" => size,
+            "hash_algorithm" => digest.algorithm.name(),
+            "digest" => digest.bytes,
+        }).ignore(self.pool()?).await?;
+        trace!(%uuid, "Rehashed file");
+        Ok(true)
+    }
+}
@@ -0,0 +1,205 @@
+//! Comparing the files a storage node stores with what the files table says it stores.
+//!
+//! Files on a node without a row are orphans, left behind by uploads which failed after writing,
+//! e.g. on a node which was failed over from. Rows without a file on their node mean the file was
+//! lost, or removed by hand. Orphans can be deleted, but only once they are old enough that no
+//! upload could still be about to record them. Their age is read from their v7 UUID.
+//!
+//! Scrubs can also have the node check each recorded file against the digest it was written with,
+//! to find files which rotted on disk without sending their contents over. Only nodes with the
+//! checksums feature keep digests, and only for files written since.
+
+#[allow(unused)]
+use tracing::{trace, debug, info, warn, error, instrument};
+
+use mysql_async::prelude::*;
+use uuid::Uuid;
+
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, SystemTime};
+
+use super::FrontNode;
+use super::storage_node_connection::StorageNodeConnection;
+use super::tys::{StorageNodeID, Error};
+use crate::message::{self, Message};
+
+/// Reports list at most this many UUIDs of each kind. The counts are always complete
+const MAX_LISTED_UUIDS: usize = 1000;
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ScrubReport {
+    pub node: i64,
+    /// milliseconds since the unix epoch
+    pub finished_at_ms: u64,
+    /// files on the node
+    pub files_stored: usize,
+    /// rows in the files table for the node
+    pub files_recorded: usize,
+    pub orphaned_count: usize,
+    pub missing_count: usize,
+    /// stored but not recorded
+    pub orphaned: Vec<Uuid>,
+    /// recorded but not stored
+    pub missing: Vec<Uuid>,
+    pub orphans_deleted: usize,
+    pub orphan_deletions_failed: usize,
+    /// whether the files' contents were checked, see verify_contents in scrub_node
+    pub contents_verified: bool,
+    pub corrupted_count: usize,
+    /// stored and recorded, but no longer matching their digest
+    pub corrupted: Vec<Uuid>,
+    /// files which couldn't be checked, as they were written without a digest, or the check failed
+    pub unverified_count: usize,
+}
+
+/// The latest report for each node
+pub type ScrubReports = std::sync::Mutex<HashMap<StorageNodeID, ScrubReport>>;
+
+/// How long ago a v7 UUID was made. None for other versions
+fn uuid_age(uuid: Uuid) -> Option<Duration> {
+    let (seconds, nanos) = uuid.get_timestamp()?.to_unix();
+    let made = SystemTime::UNIX_EPOCH + Duration::new(seconds, nanos);
+    Some(SystemTime::now().duration_since(made).unwrap_or_default())
+}
+
+impl FrontNode {
+    /// Lists the node's files and compares them with the files table. With
+    /// `delete_orphans_older_than`, orphans older than that are deleted from the node. With
+    /// `verify_contents`, the node checks each recorded file against its digest, which reads every
+    /// file, so takes as long as reading the node's disk
+    #[instrument(level = "info", skip(self))]
+    pub async fn scrub_node(
+        &self,
+        node: StorageNodeID,
+        delete_orphans_older_than: Option<Duration>,
+        verify_contents: bool,
+    ) -> Result<ScrubReport, Error> {
+        let conn = match self.active_connections.read().await.get(&node) {
+            Some(conn) => conn.clone(),
+            None => return Err(Error::NotConnectedToNode),
+        };
+
+        // rows are read before listing, so files uploaded meanwhile can only look like orphans,
+        // which are too new to be deleted
+        // files sharing contents are stored under the UUID of the file they share them with
+        let query = "SELECT COALESCE(blob_uuid, uuid) FROM files WHERE stored_on_node_id = :node;";
+        let recorded: HashSet<Uuid> = query.with(params! { "node" => node })
+            .fetch(self.pool()?)
+            .await?
+            .into_iter()
+            .collect();
+
+        let stored: HashSet<Uuid> = match conn.request(Message::ListFiles).await? {
+            Message::FileList(files) => files.into_iter().map(|(uuid, _)| uuid).collect(),
+            x => return Err(Error::UnexpectedResponse(x)),
+        };
+
+        let mut orphaned: Vec<Uuid> = stored.difference(&recorded).copied().collect();
+        let mut missing: Vec<Uuid> = recorded.difference(&stored).copied().collect();
+        orphaned.sort();
+        missing.sort();
+        if !missing.is_empty() {
+            error!(?node, n_missing = missing.len(), "Recorded files are missing from storage node");
+        }
+
+        let (mut orphans_deleted, mut orphan_deletions_failed) = (0, 0);
+        if let Some(min_age) = delete_orphans_older_than {
+            for &uuid in &orphaned {
+                if uuid_age(uuid).is_none_or(|age| age < min_age) {
+                    continue;
+                }
+                match self.delete_orphan(&conn, uuid).await {
+                    Ok(true) => orphans_deleted += 1,
+                    Ok(false) => debug!(%uuid, "Orphan was recorded since listing; keeping it"),
+                    Err(e) => {
+                        warn!(?e, %uuid, "Could not delete orphan");
+                        orphan_deletions_failed += 1;
+                    }
+                }
+            }
+        }
+
+        let contents_verified = verify_contents && conn.has_feature(message::FEATURE_CHECKSUMS);
+        if verify_contents && !contents_verified {
+            warn!(?node, "Storage node does not keep checksums; not verifying contents");
+        }
+        let (mut corrupted, mut unverified_count) = (Vec::new(), 0);
+        if contents_verified {
+            let mut present: Vec<Uuid> = recorded.intersection(&stored).copied().collect();
+            present.sort();
+            for uuid in present {
+                match conn.request(Message::VerifyFile(uuid)).await {
+                    Ok(Message::FileVerified { matches: Some(true), .. }) => {}
+                    Ok(Message::FileVerified { matches: Some(false), .. }) => corrupted.push(uuid),
+                    Ok(Message::FileVerified { matches: None, .. }) => unverified_count += 1,
+                    // deleted since listing
+                    Err(Error::UnknownUUID) => {}
+                    Ok(x) => {
+                        warn!(%uuid, response = %x, "Unexpected response verifying file");
+                        unverified_count += 1;
+                    }
+                    Err(e) => {
+                        warn!(?e, %uuid, "Could not verify file");
+                        unverified_count += 1;
+                    }
+                }
+            }
+            if !corrupted.is_empty() {
+                error!(?node, n_corrupted = corrupted.len(), "Files on storage node no longer match their digests");
+            }
+        }
+
+        let report = ScrubReport {
+            node: node.0,
+            finished_at_ms: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |since_epoch| since_epoch.as_millis() as u64),
+            files_stored: stored.len(),
+            files_recorded: recorded.len(),
+            orphaned_count: orphaned.len(),
+            missing_count: missing.len(),
+            orphaned: orphaned.into_iter().take(MAX_LISTED_UUIDS).collect(),
+            missing: missing.into_iter().take(MAX_LISTED_UUIDS).collect(),
+            orphans_deleted,
+            orphan_deletions_failed,
+            contents_verified,
+            corrupted_count: corrupted.len(),
+            corrupted: corrupted.into_iter().take(MAX_LISTED_UUIDS).collect(),
+            unverified_count,
+        };
+        info!(
+            files_stored = report.files_stored,
+            orphaned = report.orphaned_count,
+            missing = report.missing_count,
+            corrupted = report.corrupted_count,
+            orphans_deleted,
+            "Scrubbed storage node",
+        );
+        self.scrub_reports.lock().unwrap().insert(node, report.clone());
+        Ok(report)
+    }
+
+    /// The latest scrub of each node since the front node started
+    pub fn scrub_reports(&self) -> Vec<ScrubReport> {
+        let mut reports: Vec<ScrubReport> = self.scrub_reports.lock().unwrap().values().cloned().collect();
+        reports.sort_by_key(|report| report.node);
+        reports
+    }
+
+    // false if the file has been recorded since it was listed
+    async fn delete_orphan(&self, conn: &StorageNodeConnection, uuid: Uuid) -> Result<bool, Error> {
+        let query = "SELECT count(*) FROM files WHERE uuid = :uuid OR blob_uuid = :uuid;";
+        let count: Option<u64> = query.with(params! { "uuid" => uuid }).first(self.pool()?).await?;
+        if count.unwrap_or(0) > 0 {
+            return Ok(false);
+        }
+
+        match conn.request(Message::DeleteFile(uuid)).await? {
+            Message::Ack => {
+                info!(%uuid, "Deleted orphan");
+                Ok(true)
+            }
+            x => Err(Error::UnexpectedResponse(x)),
+        }
+    }
+}
@@ -1,32 +1,43 @@
 #[allow(unused)]
 use tracing::{trace, debug, info, warn, error, instrument, Level};
 use async_trait::async_trait;
+use futures::{channel::mpsc, task::AtomicWaker, StreamExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
 use uuid::Uuid;
 
 use std::{net::SocketAddr, str::FromStr};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::path::Path;
-use std::collections::HashMap;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use russh::{
     Channel, ChannelId,
     server::{Server, Msg, Handler, Auth, Session},
 };
+use russh_sftp::extensions::{self, Statvfs};
 use russh_sftp::protocol::{
-    StatusCode,
+    StatusCode, Packet, ExtendedReply,
     Status,
     FileAttributes, OpenFlags,
     Handle as SFTPHandle, Name as SFTPName, File as SFTPFile, Attrs as SFTPAttrs, Data as SFTPData,
 };
 use ssh_key::{public::PublicKey, private::PrivateKey};
 
-use super::{tys::{DirectoryID, Error as NodeError}, FrontNode};
+use super::{tys::{DirectoryID, Error as NodeError}, FrontNode, ListingRow, UploadOptions};
 use super::config;
+use super::startup_summary::StartupSummary;
+use super::concurrency::Permit;
+use super::access_log::AccessLogEntry;
+use crate::listing_format::{format_longname, EntryKind, ListingEntry};
 
 #[derive(Debug)]
 #[allow(unused)]
 pub enum SSHError {
-    SSHError(russh::Error),
+    Russh(russh::Error),
     IO(std::io::Error),
     CouldNotParseAddr,
 
@@ -36,7 +47,7 @@ pub enum SSHError {
 
 impl From<russh::Error> for SSHError {
     fn from(v: russh::Error) -> Self {
-        Self::SSHError(v)
+        Self::Russh(v)
     }
 }
 
@@ -48,8 +59,220 @@ impl From<std::io::Error> for SSHError {
 
 type SSHResult<T> = std::result::Result<T, SSHError>;
 
+/// Total number of open handles across all SFTP sessions
+static OPEN_HANDLES: AtomicUsize = AtomicUsize::new(0);
+
+pub fn total_open_handles() -> usize {
+    OPEN_HANDLES.load(Ordering::Relaxed)
+}
+
+/// Reclaimed handles remembered per session. Past this, the ones reclaimed longest ago are
+/// forgotten, and using them fails like using any other unknown handle
+const MAX_RECLAIMED_HANDLES: usize = 1024;
+
+/// Why we closed a connection
+#[derive(Debug, Clone, Copy)]
+enum Termination {
+    AuthTimeout,
+    InitTimeout,
+    InitTooLarge,
+    TooManyExtensions,
+    Idle,
+}
+
+impl Termination {
+    const ALL: [Termination; 5] = [
+        Termination::AuthTimeout,
+        Termination::InitTimeout,
+        Termination::InitTooLarge,
+        Termination::TooManyExtensions,
+        Termination::Idle,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Termination::AuthTimeout => "auth_timeout",
+            Termination::InitTimeout => "init_timeout",
+            Termination::InitTooLarge => "init_too_large",
+            Termination::TooManyExtensions => "too_many_extensions",
+            Termination::Idle => "idle",
+        }
+    }
+
+    fn record(self, client_addr: Option<SocketAddr>) {
+        warn!(reason = self.name(), ?client_addr, "Terminating SSH connection");
+        TERMINATIONS[self as usize].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Connections closed by us since the front node started, indexed by Termination
+static TERMINATIONS: [AtomicU64; Termination::ALL.len()] = [const { AtomicU64::new(0) }; Termination::ALL.len()];
+
+pub fn session_terminations() -> Vec<(&'static str, u64)> {
+    Termination::ALL.iter()
+        .map(|&reason| (reason.name(), TERMINATIONS[reason as usize].load(Ordering::Relaxed)))
+        .collect()
+}
+
+/// Shared between an SSH session's handler and the task serving its connection
+#[derive(Default)]
+struct ConnectionControl {
+    authenticated: AtomicBool,
+    severed: AtomicBool,
+    read_waker: AtomicWaker,
+    write_waker: AtomicWaker,
+}
+
+impl ConnectionControl {
+    /// Closes the connection. russh only acts on disconnect requests once key exchange is done, so
+    /// rather than asking the session to disconnect, its socket is cut off
+    fn terminate(&self, reason: Termination, client_addr: Option<SocketAddr>) {
+        reason.record(client_addr);
+        self.severed.store(true, Ordering::Relaxed);
+        self.read_waker.wake();
+        self.write_waker.wake();
+    }
+}
+
+/// A stream whose reads and writes fail once its connection is terminated
+struct Severable<S> {
+    inner: S,
+    control: Arc<ConnectionControl>,
+}
+
+impl<S> Severable<S> {
+    // registered before checking, so a terminate between the two isn't missed
+    fn check_severed(&self, waker: &AtomicWaker, cx: &Context) -> std::io::Result<()> {
+        waker.register(cx.waker());
+        match self.control.severed.load(Ordering::Relaxed) {
+            true => Err(std::io::ErrorKind::ConnectionAborted.into()),
+            false => Ok(()),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Severable<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut ReadBuf) -> Poll<std::io::Result<()>> {
+        self.check_severed(&self.control.read_waker, cx)?;
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Severable<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        self.check_severed(&self.control.write_waker, cx)?;
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<std::io::Result<()>> {
+        self.check_severed(&self.control.write_waker, cx)?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Messages for failed requests whose handler can't return a Status, such as open, by request ID.
+/// russh-sftp answers those with just the status code, so the message is put into the answer on
+/// its way out, see WithStatusMessages
+#[derive(Default, Clone)]
+struct StatusMessages(Arc<std::sync::Mutex<HashMap<u32, String>>>);
+
+impl StatusMessages {
+    /// Fails request `id` with `message`, rather than with only the status code's name
+    fn fail(&self, id: u32, message: String) -> StatusCode {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).insert(id, message);
+        StatusCode::Failure
+    }
+
+    fn take(&self, id: u32) -> Option<String> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).remove(&id)
+    }
+}
+
+/// SSH_FXP_STATUS
+const STATUS_PACKET: u8 = 101;
+
+/// The sending half of an SFTP session, which puts the messages from StatusMessages into the
+/// status packets they are for. russh-sftp flushes after each packet, so what was written is
+/// only looked at on flush
+struct WithStatusMessages<W> {
+    inner: W,
+    messages: StatusMessages,
+    /// written to us since the last flush
+    pending: Vec<u8>,
+    /// to be written to inner, from `written` on
+    out: Vec<u8>,
+    written: usize,
+}
+
+impl<W> WithStatusMessages<W> {
+    fn new(inner: W, messages: StatusMessages) -> Self {
+        WithStatusMessages { inner, messages, pending: Vec::new(), out: Vec::new(), written: 0 }
+    }
+
+    // moves the whole packets in pending to out
+    fn rewrite_pending(&mut self) {
+        while let Some(length) = self.pending.first_chunk::<4>().map(|length| u32::from_be_bytes(*length) as usize) {
+            if self.pending.len() < 4 + length {
+                break;
+            }
+            let packet: Vec<u8> = self.pending.drain(..4 + length).collect();
+            // type, ID and status code
+            let message = match packet.get(4..13) {
+                Some(header) if header[0] == STATUS_PACKET => {
+                    let id = u32::from_be_bytes(header[1..5].try_into().unwrap());
+                    self.messages.take(id).map(|message| (header.to_vec(), message))
+                }
+                _ => None,
+            };
+            let Some((header, message)) = message else {
+                self.out.extend_from_slice(&packet);
+                continue;
+            };
+            let mut body = header;
+            for string in [message.as_bytes(), b"en-US"] {
+                body.extend_from_slice(&(string.len() as u32).to_be_bytes());
+                body.extend_from_slice(string);
+            }
+            self.out.extend_from_slice(&(body.len() as u32).to_be_bytes());
+            self.out.extend_from_slice(&body);
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for WithStatusMessages<W> {
+    fn poll_write(mut self: Pin<&mut Self>, _cx: &mut Context, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        self.pending.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        this.rewrite_pending();
+        while this.written < this.out.len() {
+            let n = std::task::ready!(Pin::new(&mut this.inner).poll_write(cx, &this.out[this.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            this.written += n;
+        }
+        this.out.clear();
+        this.written = 0;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<std::io::Result<()>> {
+        std::task::ready!(self.as_mut().poll_flush(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
 struct SSHServer {
     node: Arc<FrontNode>,
+    cfg: Arc<config::SFTPServerOptions>,
 }
 
 #[async_trait]
@@ -62,7 +285,9 @@ impl Server for SSHServer {
             client_addr,
             user: None,
             node: self.node.clone(),
+            cfg: self.cfg.clone(),
             open_channels: HashMap::new(),
+            control: Arc::new(ConnectionControl::default()),
         }
     }
 }
@@ -71,7 +296,9 @@ struct SSHSession {
     client_addr: Option<SocketAddr>,
     user: Option<String>,
     node: Arc<FrontNode>,
+    cfg: Arc<config::SFTPServerOptions>,
     open_channels: HashMap<ChannelId, Channel<Msg>>,
+    control: Arc<ConnectionControl>,
 }
 
 impl std::fmt::Debug for SSHSession {
@@ -90,22 +317,82 @@ impl std::fmt::Debug for SSHSession {
 }
 
 
+impl SSHSession {
+    // whether the key is one of the user's, see users.ssh_pubkey
+    async fn known_pubkey(&self, user: &str, pubkey: &ssh_key::public::PublicKey) -> bool {
+        match self.node.check_ssh_pubkey(user, pubkey).await {
+            Ok(true) => true,
+            Ok(false) => {
+                let fingerprint = pubkey.fingerprint(Default::default());
+                debug!(user, client_addr = ?self.client_addr, %fingerprint, "Unknown public key");
+                false
+            }
+            Err(e) => {
+                error!(?e, "Could not check public key");
+                false
+            }
+        }
+    }
+}
+
 #[async_trait]
 impl Handler for SSHSession {
     type Error = SSHError;
 
     // TODO: implement close
 
-    #[instrument(level = "debug", skip(_pubkey))]
-    async fn auth_publickey(&mut self, user: &str, _pubkey: &ssh_key::public::PublicKey)
+    // checked before the client signs anything, so it can move on to its next key
+    #[instrument(level = "debug", skip(pubkey))]
+    async fn auth_publickey_offered(&mut self, user: &str, pubkey: &ssh_key::public::PublicKey)
         -> SSHResult<Auth>
     {
-        // TODO: Verify the key, somehow
+        match self.known_pubkey(user, pubkey).await {
+            true => Ok(Auth::Accept),
+            false => Ok(Auth::Reject { proceed_with_methods: None }),
+        }
+    }
+
+    // called once the client has proven it holds the key, which is checked again in case the
+    // user's keys changed in between
+    #[instrument(level = "debug", skip(pubkey))]
+    async fn auth_publickey(&mut self, user: &str, pubkey: &ssh_key::public::PublicKey)
+        -> SSHResult<Auth>
+    {
+        if !self.known_pubkey(user, pubkey).await {
+            return Ok(Auth::Reject { proceed_with_methods: None });
+        }
         self.user = Some(user.to_owned());
-        debug!("user authing");
+        self.control.authenticated.store(true, Ordering::Relaxed);
+        debug!("user authed with public key");
         Ok(Auth::Accept)
     }
 
+    // rejections are delayed by russh to auth_rejection_time, like failed public keys
+    #[instrument(level = "debug", skip(password))]
+    async fn auth_password(&mut self, user: &str, password: &str) -> SSHResult<Auth> {
+        let reject = Auth::Reject { proceed_with_methods: None };
+        if !self.cfg.allow_password_auth {
+            debug!("Password authentication is disabled");
+            return Ok(reject);
+        }
+        match self.node.check_password(user, password).await {
+            Ok(true) => {
+                self.user = Some(user.to_owned());
+                self.control.authenticated.store(true, Ordering::Relaxed);
+                debug!("user authed with password");
+                Ok(Auth::Accept)
+            }
+            Ok(false) => {
+                info!(user, client_addr = ?self.client_addr, "Wrong password");
+                Ok(reject)
+            }
+            Err(e) => {
+                error!(?e, "Could not check password");
+                Ok(reject)
+            }
+        }
+    }
+
     #[instrument(level = "trace", skip(channel, _session))]
     async fn channel_open_session(&mut self, channel: Channel<Msg>, _session: &mut Session) -> SSHResult<bool> {
         let id = channel.id();
@@ -126,12 +413,33 @@ impl Handler for SSHSession {
             debug!(?id, "requesting sftp subsystem");
             let channel = self.open_channels.remove(&id).unwrap(); // russh guarantees(?) this channel_id is active
 
-            let sftp_connection = SFTPConnection::new(self.node.clone(), user, self.client_addr);
+            // held for as long as the SFTP session lasts
+            let Some(user_permit) = self.node.limits.try_acquire_user(&user) else {
+                session.channel_failure(id)?;
+                return Ok(());
+            };
 
-            russh_sftp::server::run(
-                channel.into_stream(),
-                sftp_connection,
-            ).await;
+            let sftp_connection = SFTPConnection::new(self.node.clone(), self.cfg.clone(), user, self.client_addr, user_permit);
+            let status_messages = sftp_connection.status_messages.clone();
+
+            // the channel's data only arrives once this handler returns, so the init packet is
+            // waited for in a separate task
+            let (cfg, control, client_addr) = (self.cfg.clone(), self.control.clone(), self.client_addr);
+            tokio::spawn(async move {
+                let mut stream = channel.into_stream();
+                let init_timeout = Duration::from_secs(cfg.init_timeout_s);
+                let init = match tokio::time::timeout(init_timeout, read_init_packet(&mut stream, &cfg)).await {
+                    Ok(Ok(init)) => init,
+                    Ok(Err(Some(reason))) => return control.terminate(reason, client_addr),
+                    Ok(Err(None)) => return debug!("Channel closed before SFTP init"),
+                    Err(_) => return control.terminate(Termination::InitTimeout, client_addr),
+                };
+
+                // russh-sftp reads the init packet again from the front of the stream
+                let (read, write) = tokio::io::split(stream);
+                let stream = tokio::io::join(std::io::Cursor::new(init).chain(read), WithStatusMessages::new(write, status_messages));
+                russh_sftp::server::run(stream, sftp_connection).await;
+            });
 
             session.channel_success(id)?;
         } else {
@@ -145,18 +453,16 @@ impl Handler for SSHSession {
 enum Handle {
     File(Uuid),
     Directory(DirectoryID),
+    /// the `n`th file opened for writing in the session, see WriteStatus
+    Write(u64),
 }
 
-impl ToString for Handle {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for Handle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Handle::File(uuid) => {
-                let uuid_str = uuid.hyphenated()
-                    .encode_lower(&mut Uuid::encode_buffer())
-                    .to_string();
-                format!("f:{uuid_str}")
-            }
-            Handle::Directory(id) => format!("d:{}", id.0),
+            Handle::File(uuid) => write!(f, "f:{}", uuid.hyphenated()),
+            Handle::Directory(id) => write!(f, "d:{}", id.0),
+            Handle::Write(n) => write!(f, "w:{n}"),
         }
     }
 }
@@ -171,6 +477,7 @@ impl std::fmt::Debug for Handle {
                 write!(f, "Handle::File({uuid_str})")
             }
             Handle::Directory(id) => write!(f, "Handle::Directory({})", id.0),
+            Handle::Write(n) => write!(f, "Handle::Write({n})"),
         }
     }
 }
@@ -186,25 +493,70 @@ impl FromStr for Handle {
                 Ok(Handle::File(uuid))
             }
             "d:" => {
-                let dir_id: i64 = suffix.parse().map_err(|_| StatusCode::BadMessage)?;
+                // handles from opendir end with :<open>, see directory_handle
+                let dir_id = suffix.split_once(':').map_or(suffix, |(dir_id, _open)| dir_id);
+                let dir_id: i64 = dir_id.parse().map_err(|_| StatusCode::BadMessage)?;
                 Ok(Handle::Directory(DirectoryID(dir_id)))
             }
+            "w:" => suffix.parse().map(Handle::Write).map_err(|_| StatusCode::BadMessage),
             _ => Err(StatusCode::BadMessage),
         }
     }
 }
 
-#[derive(PartialEq)]
+/// The handle for the `open`th opendir of a session. Each open gets its own handle, so clients listing
+/// the same directory twice at once (e.g. GUI clients refreshing panes) get separate listings
+fn directory_handle(dir: DirectoryID, open: u64) -> String {
+    format!("d:{}:{open}", dir.0)
+}
+
 enum DirectoryStatus {
-    Unread, Read
+    Unread,
+    /// partway through the listing
+    Listing(mpsc::Receiver<Result<ListingRow, NodeError>>),
+    Read,
 }
 
+/// Max number of entries returned by each readdir, same as OpenSSH's sftp-server
+const READDIR_BATCH_SIZE: usize = 100;
+
 struct FileStatus {
+    /// set when the file is removed in this session. reads fail until the handle is closed
+    deleted: bool,
+
+    // for the access log
+    path: String,
+    opened_at: Instant,
+    bytes_read: u64,
+}
+
+/// A file opened for writing. Writes go to `contents`, which are stored in place of the file when the
+/// handle is closed, so the file is never seen half written, and nothing is stored for a client which
+/// goes away before closing it
+struct WriteStatus {
+    dir: DirectoryID,
+    name: String,
+    contents: Vec<u8>,
+    /// opened with APPEND, so every write goes to the end whatever its offset
     append: bool,
+    /// whether closing stores the contents. not for an existing file which was opened without
+    /// TRUNCATE and never written to
+    dirty: bool,
+    /// size of the file this replaces, which stops counting against the user's quota once the
+    /// contents are stored
+    replaced_size: u64,
+    /// opened with CREATE and EXCLUDE, so storing fails if a file was stored at the path since
+    exclusive: bool,
+
+    // for the access log
+    path: String,
+    opened_at: Instant,
+    bytes_written: u64,
 }
 
 struct SFTPConnection {
     node: Arc<FrontNode>,
+    cfg: Arc<config::SFTPServerOptions>,
 
     #[allow(unused)]
     client_version: Option<u32>,
@@ -214,27 +566,73 @@ struct SFTPConnection {
     user: String,
     remote_addr: Option<SocketAddr>,
 
-    directory_status: HashMap<DirectoryID, DirectoryStatus>,
+    /// keyed by the handle string, see directory_handle
+    directory_status: HashMap<String, DirectoryStatus>,
+    /// opendirs so far, to make each handle unique
+    directory_opens: u64,
     file_status: HashMap<Uuid, FileStatus>,
+    /// keyed by the n of Handle::Write
+    write_status: HashMap<u64, WriteStatus>,
+    /// opens for writing so far, to make each handle unique
+    write_opens: u64,
+
+    /// When each open handle was last used, keyed by the handle string
+    handle_last_used: HashMap<String, Instant>,
+    /// Handles which were closed by us for being idle, oldest first. Kept so we can tell the client
+    /// why the handle is gone, up to MAX_RECLAIMED_HANDLES
+    reclaimed_handles: VecDeque<String>,
+    status_messages: StatusMessages,
+
+    _user_permit: Permit,
+    _session_count: Permit,
+
+    // for the access log
+    session_started: Instant,
+    session_bytes: u64,
 }
 
 impl SFTPConnection {
     fn new(
         node: Arc<FrontNode>,
+        cfg: Arc<config::SFTPServerOptions>,
         user: String, remote_addr: Option<SocketAddr>,
+        user_permit: Permit,
     ) -> Self {
+        let session_count = node.limits.count_sftp_session(&user);
         Self {
             node,
+            cfg,
             client_version: None,
             client_extensions: HashMap::new(),
             user,
             remote_addr,
             directory_status: HashMap::new(),
+            directory_opens: 0,
             file_status: HashMap::new(),
+            write_status: HashMap::new(),
+            write_opens: 0,
+            handle_last_used: HashMap::new(),
+            reclaimed_handles: VecDeque::new(),
+            status_messages: StatusMessages::default(),
+            _user_permit: user_permit,
+            _session_count: session_count,
+            session_started: Instant::now(),
+            session_bytes: 0,
         }
     }
 }
 
+impl Drop for SFTPConnection {
+    fn drop(&mut self) {
+        if !self.handle_last_used.is_empty() {
+            debug!(self.user, n_handles = self.handle_last_used.len(), "Session ended with open handles");
+        }
+        OPEN_HANDLES.fetch_sub(self.handle_last_used.len(), Ordering::Relaxed);
+
+        self.log_access("SFTP_SESSION", "-".to_string(), self.session_bytes, self.session_started.elapsed());
+    }
+}
+
 impl std::fmt::Debug for SFTPConnection {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "SFTPConnection for {}", self.user)?;
@@ -244,6 +642,17 @@ impl std::fmt::Debug for SFTPConnection {
 
 type SFTPResult<T> = std::result::Result<T, StatusCode>;
 
+// file UUIDs are v7, which embed the time they were generated
+fn file_creation_time(uuid: Uuid) -> SystemTime {
+    match uuid.get_timestamp() {
+        Some(ts) => {
+            let (secs, nanos) = ts.to_unix();
+            UNIX_EPOCH + Duration::new(secs, nanos)
+        }
+        None => UNIX_EPOCH,
+    }
+}
+
 fn status_ok(id: u32) -> Status {
     Status {
         id,
@@ -253,6 +662,143 @@ fn status_ok(id: u32) -> Status {
     }
 }
 
+// SFTP v3 has few status codes, so the message is what tells the user what went wrong
+fn status_failure(id: u32, message: &str) -> Status {
+    Status {
+        id,
+        status_code: StatusCode::Failure,
+        error_message: message.to_string(),
+        language_tag: "en-US".to_string(),
+    }
+}
+
+/// Reads the SFTP init packet, including its length. Err(None) if the channel closed first
+async fn read_init_packet<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    cfg: &config::SFTPServerOptions,
+) -> Result<Vec<u8>, Option<Termination>> {
+    let mut length = [0; 4];
+    stream.read_exact(&mut length).await.map_err(|_| None)?;
+    let length = u32::from_be_bytes(length);
+    if length > cfg.max_init_packet_bytes {
+        debug!(length, "SFTP init packet too large");
+        return Err(Some(Termination::InitTooLarge));
+    }
+
+    let mut packet = Vec::with_capacity(4 + length as usize);
+    packet.extend_from_slice(&length.to_be_bytes());
+    packet.resize(4 + length as usize, 0);
+    stream.read_exact(&mut packet[4..]).await.map_err(|_| None)?;
+
+    let extensions = init_extension_count(&packet[4..]);
+    if extensions > cfg.max_client_extensions {
+        debug!(extensions, "Too many SFTP extensions");
+        return Err(Some(Termination::TooManyExtensions));
+    }
+    Ok(packet)
+}
+
+// an init packet is its type and version, followed by extension name/value string pairs. malformed
+// packets are left for russh-sftp to reject
+fn init_extension_count(body: &[u8]) -> usize {
+    let mut rest = body.get(5..).unwrap_or_default();
+    let mut strings = 0;
+    while let Some((len, after_len)) = rest.split_first_chunk::<4>() {
+        let Some(after_string) = after_len.get(u32::from_be_bytes(*len) as usize..) else {
+            break;
+        };
+        rest = after_string;
+        strings += 1;
+    }
+    strings / 2
+}
+
+// TODO: set permissions once we have added those to the database schema
+fn file_attrs(size: Option<u64>) -> FileAttributes {
+    FileAttributes {
+        size,
+        permissions: Some(0o777 | ATTR_PERMISSION_FILE),
+        ..Default::default()
+    }
+}
+
+fn directory_attrs() -> FileAttributes {
+    FileAttributes {
+        permissions: Some(0o777 | ATTR_PERMISSION_DIRECTORY),
+        ..Default::default()
+    }
+}
+
+// an entry as readdir sends it, owned by the user who is logged in
+fn listing_file(cfg: &config::SFTPServerOptions, owner: &str, row: ListingRow, now: SystemTime) -> Option<SFTPFile> {
+    let (name, kind, attrs, mtime) = match row {
+        ListingRow::File(uuid, name, size) => (name, EntryKind::File, file_attrs(size), file_creation_time(uuid)),
+        // we don't know when directories were created
+        ListingRow::Directory(_, name) => (name, EntryKind::Directory, directory_attrs(), UNIX_EPOCH),
+        // only listed with include_deleted, which SFTP doesn't use
+        ListingRow::DeletedFile(..) => return None,
+    };
+    let longname = longname(cfg, owner, &name, kind, &attrs, mtime, now);
+
+    Some(SFTPFile {
+        filename: name,
+        longname,
+        attrs,
+    })
+}
+
+fn longname(cfg: &config::SFTPServerOptions, owner: &str, name: &str, kind: EntryKind, attrs: &FileAttributes, mtime: SystemTime, now: SystemTime) -> String {
+    let entry = ListingEntry {
+        name,
+        kind,
+        size: attrs.size.unwrap_or(0),
+        mtime,
+        permissions: attrs.permissions.unwrap_or(0),
+        owner,
+        group: &cfg.listing_group,
+    };
+    format_longname(&entry, now)
+}
+
+// a string in the SFTP wire format, a u32 length followed by that many bytes
+fn parse_sftp_string(data: &[u8]) -> Option<String> {
+    let (len, rest) = data.split_first_chunk::<4>()?;
+    let bytes = rest.get(..u32::from_be_bytes(*len) as usize)?;
+    String::from_utf8(bytes.to_vec()).ok()
+}
+
+/// Block size reported to clients. Storage nodes only report bytes, so this is arbitrary
+const REPORTED_BLOCK_SIZE: u64 = 4096;
+
+// `user` is what the user may use of `device`, see UserUsage::limit
+fn space_available_reply(device: super::StorageSpace, user: super::StorageSpace) -> Vec<u8> {
+    let mut reply = Vec::with_capacity(36);
+    reply.extend_from_slice(&device.bytes_total.to_be_bytes()); // bytes-on-device
+    reply.extend_from_slice(&device.bytes_available.to_be_bytes()); // unused-bytes-on-device
+    reply.extend_from_slice(&user.bytes_available.to_be_bytes()); // bytes-available-to-user
+    reply.extend_from_slice(&user.bytes_available.to_be_bytes()); // unused-bytes-available-to-user
+    reply.extend_from_slice(&(REPORTED_BLOCK_SIZE as u32).to_be_bytes()); // bytes-per-allocation-unit
+    reply
+}
+
+// with a quota, df shows the quota as the size, and what is left of it as available
+fn statvfs_reply(user: super::StorageSpace) -> Statvfs {
+    Statvfs {
+        block_size: REPORTED_BLOCK_SIZE,
+        fragment_size: REPORTED_BLOCK_SIZE,
+        blocks: user.bytes_total / REPORTED_BLOCK_SIZE,
+        blocks_free: user.bytes_available / REPORTED_BLOCK_SIZE,
+        blocks_avail: user.bytes_available / REPORTED_BLOCK_SIZE,
+        // there is no limit on the number of files
+        inodes: 0,
+        inodes_free: 0,
+        inodes_avail: 0,
+        fs_id: 0,
+        flags: 0,
+        name_max: 255,
+    }
+}
+
 const ATTR_PERMISSION_DIRECTORY: u32 = 0o0040000;
 const ATTR_PERMISSION_FILE: u32 = 0o0100000;
 
@@ -269,7 +815,7 @@ impl SFTPConnection {
         let mut parts = Vec::new();
         for part in path.split('/') {
             if part == ".." {
-                if parts.len() == 0 {
+                if parts.is_empty() {
                     return Err(StatusCode::BadMessage);
                 }
                 parts.pop();
@@ -334,20 +880,320 @@ impl SFTPConnection {
         Err(StatusCode::NoSuchFile)
     }
 
+    // for creating something new at the path. the parent directory must exist
+    async fn parent_and_name(&self, path: String) -> SFTPResult<(DirectoryID, String)> {
+        let (base, path) = self.absolutize_path(path).await?;
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", &path));
+        if name.is_empty() {
+            debug!(path, "No name given");
+            return Err(StatusCode::Failure);
+        }
+
+        match self.node.directory_id_for_path(parent, base).await {
+            Ok(dir) => Ok((dir, name.to_string())),
+            Err(NodeError::NoSuchDirectory { .. }) => {
+                debug!(parent, "Parent directory doesn't exist");
+                Err(StatusCode::NoSuchFile)
+            }
+            Err(e) => {
+                error!(?e, parent, "Could not fetch directory");
+                Err(StatusCode::Failure)
+            }
+        }
+    }
+
+    // the whole file, for changing part of it
+    async fn read_for_writing(&self, id: u32, uuid: Uuid) -> SFTPResult<Vec<u8>> {
+        match self.node.file_size(uuid).await {
+            Ok(Some(size)) if size > self.cfg.max_write_bytes => {
+                debug!(%uuid, size, "File too large to change in memory");
+                let message = format!(
+                    "Files larger than {} bytes can only be replaced. Open it with truncation to overwrite it",
+                    self.cfg.max_write_bytes,
+                );
+                return Err(self.status_messages.fail(id, message));
+            }
+            Ok(_) => {}
+            Err(NodeError::NoSuchFile) => return Err(StatusCode::NoSuchFile),
+            Err(e) => {
+                error!(?e, %uuid, "Could not get file size");
+                return Err(StatusCode::Failure);
+            }
+        }
+        match self.node.get_file(uuid).await {
+            Ok((contents, _info)) => Ok(contents),
+            // removed by someone else since it was looked up
+            Err(NodeError::UnknownUUID) => Err(StatusCode::NoSuchFile),
+            Err(e) => {
+                error!(?e, %uuid, "Could not read file to change it");
+                Err(StatusCode::Failure)
+            }
+        }
+    }
+
+    // nothing is created until the handle is closed, but the directory's upload policy is checked
+    // now, so the client isn't refused only after sending the whole file
+    async fn open_for_writing(&mut self, id: u32, path: String, existing_uuid: Option<Uuid>, open_flags: OpenFlags)
+        -> SFTPResult<SFTPHandle>
+    {
+        let (dir, name) = self.parent_and_name(path.clone()).await?;
+        let options = UploadOptions { overwrite: true, ..Default::default() };
+        match self.node.check_upload(&name, dir, true, None, options).await {
+            Ok(()) => {}
+            Err(NodeError::PolicyViolation { rule }) => {
+                info!(self.user, path, rule, "File creation refused by policy");
+                return Err(StatusCode::PermissionDenied);
+            }
+            Err(e) => {
+                error!(?e, path, "Could not check upload");
+                return Err(StatusCode::Failure);
+            }
+        }
+
+        let truncate = open_flags.contains(OpenFlags::TRUNCATE);
+        let contents = match existing_uuid {
+            Some(uuid) if !truncate => self.read_for_writing(id, uuid).await?,
+            _ => Vec::new(),
+        };
+        let replaced_size = match existing_uuid {
+            Some(_) if !truncate => contents.len() as u64,
+            Some(uuid) => match self.node.file_size(uuid).await {
+                Ok(size) => size.unwrap_or(0),
+                Err(NodeError::NoSuchFile) => 0,
+                Err(e) => {
+                    error!(?e, %uuid, "Could not get size of the file being replaced");
+                    return Err(StatusCode::Failure);
+                }
+            },
+            None => 0,
+        };
+        let status = WriteStatus {
+            dir,
+            name,
+            contents,
+            append: open_flags.contains(OpenFlags::APPEND),
+            dirty: existing_uuid.is_none() || truncate,
+            replaced_size,
+            exclusive: open_flags.contains(OpenFlags::CREATE | OpenFlags::EXCLUDE),
+            path,
+            opened_at: Instant::now(),
+            bytes_written: 0,
+        };
+
+        self.check_handle_limits(id)?;
+        let n = self.write_opens;
+        self.write_opens += 1;
+        self.write_status.insert(n, status);
+        self.remember_handle(Handle::Write(n).to_string());
+
+        Ok(SFTPHandle {
+            id,
+            handle: Handle::Write(n).to_string(),
+        })
+    }
+
+    // stores what was written to a handle, in place of whatever is at its path by now
+    async fn commit_write(&self, id: u32, status: WriteStatus) -> SFTPResult<Status> {
+        let WriteStatus { dir, name, contents, replaced_size, exclusive, path, opened_at, bytes_written, .. } = status;
+        let size = contents.len() as u64;
+        // checked again, as other handles may have been closed since this one was written to
+        let growth = size as i64 - replaced_size as i64;
+        if let Err(message) = self.check_quota(growth).await? {
+            info!(self.user, path, size, message, "File refused for the user's quota");
+            return Ok(status_failure(id, &message));
+        }
+        let options = UploadOptions { verify: self.node.verify_uploads_by_default, overwrite: !exclusive, ..Default::default() };
+        let result = self.node.upload_file(name, dir, contents, options).await;
+        self.log_access("SFTP_WRITE", path.clone(), bytes_written, opened_at.elapsed());
+        match result {
+            Ok(uuid) => {
+                info!(self.user, path, %uuid, size, "Stored file");
+                self.node.metrics.sftp_written_bytes.fetch_add(size, Ordering::Relaxed);
+                self.node.add_usage(&self.user, growth);
+                Ok(status_ok(id))
+            }
+            Err(NodeError::PolicyViolation { rule }) => {
+                info!(self.user, path, rule, "File refused by policy");
+                Err(StatusCode::PermissionDenied)
+            }
+            // opened with EXCLUDE, and another handle stored a file at the path first
+            Err(NodeError::FileExists) => {
+                info!(self.user, path, "File was stored by someone else while open with EXCLUDE");
+                Ok(status_failure(id, "A file with that name already exists"))
+            }
+            // the directory was removed while the file was being written
+            Err(NodeError::NoSuchDirectory { .. } | NodeError::UnknownUUID) => Err(StatusCode::NoSuchFile),
+            Err(NodeError::NoNodeWithSpace | NodeError::StorageNode { code: crate::message::ErrorCode::NoSpace, .. }) => {
+                error!(path, size, "No storage node has room for the file");
+                Ok(status_failure(id, "No storage node has room for the file"))
+            }
+            Err(NodeError::AllAttemptsFailed(attempts)) => {
+                let attempts: Vec<String> = attempts.iter().map(|attempt| attempt.to_string()).collect();
+                error!(path, ?attempts, "Could not store file");
+                Ok(status_failure(id, &format!("Could not store file: {}", attempts.join("; "))))
+            }
+            Err(e) => {
+                error!(?e, path, "Could not store file");
+                Err(StatusCode::Failure)
+            }
+        }
+    }
+
+    // the message to fail with if the user's usage can't grow by `growth` bytes
+    async fn check_quota(&self, growth: i64) -> SFTPResult<Result<(), String>> {
+        match self.node.usage_for_user(&self.user).await {
+            Ok(usage) => Ok(usage.check(growth)),
+            Err(e) => {
+                error!(?e, self.user, "Could not get the user's usage");
+                Err(StatusCode::Failure)
+            }
+        }
+    }
+
     async fn attrs_for_handle(&self, handle: Handle) -> Result<FileAttributes, StatusCode> {
-        // TODO: set permissions once we have added those to the database schema
         match handle {
-            Handle::File(_) => Ok(FileAttributes {
-                permissions: Some(0o777 | ATTR_PERMISSION_FILE),
-                ..Default::default()
-            }),
-            Handle::Directory(_) => Ok(FileAttributes {
-                permissions: Some(0o777 | ATTR_PERMISSION_DIRECTORY),
-                ..Default::default()
-            }),
+            Handle::File(uuid) => match self.node.file_size(uuid).await {
+                Ok(size) => Ok(file_attrs(size)),
+                Err(NodeError::NoSuchFile) => Err(StatusCode::NoSuchFile),
+                Err(e) => {
+                    error!(?e, %uuid, "Could not get file size");
+                    Err(StatusCode::Failure)
+                }
+            },
+            Handle::Directory(_) => Ok(directory_attrs()),
+            Handle::Write(n) => match self.write_status.get(&n) {
+                Some(status) => Ok(file_attrs(Some(status.contents.len() as u64))),
+                None => Err(StatusCode::Failure),
+            },
         }
     }
 
+    // called before a new handle is given out. reclaims idle handles, and fails if the client has
+    // too many handles open
+    fn check_handle_limits(&mut self, id: u32) -> SFTPResult<()> {
+        self.reclaim_idle_handles();
+
+        let n_open = self.handle_last_used.len();
+        if n_open >= self.cfg.open_handles_hard_limit {
+            error!(
+                self.user, remote_addr = ?self.remote_addr, n_open,
+                "Client hit the open handle limit; refusing to open more",
+            );
+            let message = format!(
+                "Too many open handles: at most {} can be open at once. Close some before opening more",
+                self.cfg.open_handles_hard_limit,
+            );
+            return Err(self.status_messages.fail(id, message));
+        }
+        if n_open >= self.cfg.open_handles_soft_limit {
+            let mut oldest: Vec<(&String, &Instant)> = self.handle_last_used.iter().collect();
+            oldest.sort_by_key(|(_, last_used)| **last_used);
+            let oldest: Vec<(&String, Duration)> = oldest.into_iter()
+                .take(5)
+                .map(|(handle, last_used)| (handle, last_used.elapsed()))
+                .collect();
+            warn!(
+                self.user, remote_addr = ?self.remote_addr, n_open, ?oldest,
+                "Client has many open handles. Is it leaking them?",
+            );
+        }
+        Ok(())
+    }
+
+    fn reclaim_idle_handles(&mut self) {
+        let idle_timeout = Duration::from_secs(self.cfg.handle_idle_timeout_s);
+        let idle: Vec<String> = self.handle_last_used.iter()
+            .filter(|(_, last_used)| last_used.elapsed() > idle_timeout)
+            .map(|(handle, _)| handle.clone())
+            .collect();
+
+        for handle_str in idle {
+            warn!(self.user, remote_addr = ?self.remote_addr, handle = handle_str, "Reclaiming idle handle");
+            self.forget_handle(&handle_str);
+            match handle_str.parse() {
+                Ok(Handle::File(uuid)) => { self.file_status.remove(&uuid); }
+                Ok(Handle::Directory(_)) => { self.directory_status.remove(&handle_str); }
+                Ok(Handle::Write(n)) => {
+                    if let Some(status) = self.write_status.remove(&n) {
+                        warn!(self.user, path = status.path, "Discarding what was written to a reclaimed handle");
+                    }
+                }
+                Err(_) => {}
+            }
+            if self.reclaimed_handles.len() >= MAX_RECLAIMED_HANDLES {
+                self.reclaimed_handles.pop_front();
+            }
+            self.reclaimed_handles.push_back(handle_str);
+        }
+    }
+
+    /// Whether the handle was reclaimed, forgetting that it was
+    fn take_reclaimed(&mut self, handle_str: &str) -> bool {
+        match self.reclaimed_handles.iter().position(|reclaimed| reclaimed == handle_str) {
+            Some(i) => self.reclaimed_handles.remove(i).is_some(),
+            None => false,
+        }
+    }
+
+    fn remember_handle(&mut self, handle_str: String) {
+        self.take_reclaimed(&handle_str);
+        if self.handle_last_used.insert(handle_str, Instant::now()).is_none() {
+            OPEN_HANDLES.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn forget_handle(&mut self, handle_str: &str) {
+        if self.handle_last_used.remove(handle_str).is_some() {
+            OPEN_HANDLES.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    // marks the handle as used. fails if the handle was reclaimed for being idle
+    fn touch_handle(&mut self, handle_str: &str) -> SFTPResult<()> {
+        if let Some(last_used) = self.handle_last_used.get_mut(handle_str) {
+            *last_used = Instant::now();
+        } else if self.reclaimed_handles.iter().any(|reclaimed| reclaimed == handle_str) {
+            warn!(self.user, handle = handle_str, "Client used a handle which was reclaimed for being idle");
+            return Err(StatusCode::Failure);
+        }
+        Ok(())
+    }
+
+    // the space-available and statvfs@openssh.com extended requests both take a single path. the
+    // space on the nodes, and what of it the user may use
+    async fn storage_space_for_request(&self, data: &[u8]) -> SFTPResult<(super::StorageSpace, super::StorageSpace)> {
+        let Some(path) = parse_sftp_string(data) else {
+            warn!(self.user, "Malformed path in extended request");
+            return Err(StatusCode::BadMessage);
+        };
+        // only used to check that the path exists, the space is the same everywhere
+        self.handle_from_path(path).await?;
+
+        let device = self.node.storage_space().await.map_err(|e| {
+            error!(?e, "Could not get storage space");
+            StatusCode::Failure
+        })?;
+        let usage = self.node.usage_for_user(&self.user).await.map_err(|e| {
+            error!(?e, self.user, "Could not get the user's usage");
+            StatusCode::Failure
+        })?;
+        Ok((device, usage.limit(device)))
+    }
+
+    fn log_access(&self, method: &str, path: String, bytes: u64, duration: Duration) {
+        self.node.access_log.log(AccessLogEntry {
+            time: SystemTime::now() - duration,
+            client: self.remote_addr.map(|addr| addr.ip()),
+            user: Some(self.user.clone()),
+            method: method.to_string(),
+            path,
+            protocol: "SFTP".to_string(),
+            status: 0,
+            bytes,
+            duration,
+        });
+    }
+
     // tail-called by stat, lstat and fstat
     #[instrument(level = "debug", skip(id))]
     async fn handle_stat(&mut self, id: u32, handle: Handle) -> SFTPResult<SFTPAttrs> {
@@ -372,7 +1218,10 @@ impl russh_sftp::server::Handler for SFTPConnection {
     {
         self.client_version = Some(client_version);
         self.client_extensions = extensions;
-        Ok(russh_sftp::protocol::Version::new())
+
+        let mut version = russh_sftp::protocol::Version::new();
+        version.extensions.insert(extensions::STATVFS.to_string(), "2".to_string());
+        Ok(version)
     }
 
     #[instrument(level = "debug", skip(id))]
@@ -394,60 +1243,58 @@ impl russh_sftp::server::Handler for SFTPConnection {
             return Err(StatusCode::NoSuchFile);
         };
 
-        self.directory_status.insert(dir_id, DirectoryStatus::Unread);
+        self.check_handle_limits(id)?;
+        let handle = directory_handle(dir_id, self.directory_opens);
+        self.directory_opens += 1;
+        self.directory_status.insert(handle.clone(), DirectoryStatus::Unread);
+        self.remember_handle(handle.clone());
 
-        Ok(SFTPHandle {
-            id,
-            handle: Handle::Directory(dir_id).to_string(),
-        })
+        Ok(SFTPHandle { id, handle })
     }
 
     #[instrument(level = "debug", skip(id))]
     async fn readdir(&mut self, id: u32, handle: String) -> SFTPResult<SFTPName> {
+        self.touch_handle(&handle)?;
         let Handle::Directory(dir) = handle.parse()? else {
             return Err(StatusCode::BadMessage);
         };
-        let Some(status) = self.directory_status.get_mut(&dir) else {
+        let Some(status) = self.directory_status.get_mut(&handle) else {
             warn!("Listing unopened directory");
             return Err(StatusCode::BadMessage);
         };
 
-        if *status == DirectoryStatus::Read {
-            return Err(StatusCode::Eof);
-        }
-
-        *status = DirectoryStatus::Read;
-
-        let listing = match self.node.list_directory(dir).await {
-            Ok(listing) => listing,
-            Err(e) => {
-                error!(?e, "error listing directory");
-                return Err(StatusCode::Failure);
-            }
+        // the listing is streamed, and each readdir takes the next batch from the stream
+        let mut rows = match std::mem::replace(status, DirectoryStatus::Read) {
+            DirectoryStatus::Read => return Err(StatusCode::Eof),
+            DirectoryStatus::Unread => self.node.list_directory(dir, READDIR_BATCH_SIZE),
+            DirectoryStatus::Listing(rows) => rows,
         };
 
-        let mut files = Vec::new();
-        for (uuid, name) in listing.file_uuids_and_names {
-            let attrs = self.attrs_for_handle(Handle::File(uuid)).await?;
-
-            files.push(SFTPFile {
-                filename: name.clone(),
-                // TODO: this should take the form of an ls listing
-                longname: format!("-rwxr-xr-x   1 mjos     staff      348911 Mar 25 14:29 t-filexfer"),
-                attrs,
-            });
+        let mut batch = Vec::with_capacity(READDIR_BATCH_SIZE);
+        let mut finished = false;
+        while batch.len() < READDIR_BATCH_SIZE {
+            match rows.next().await {
+                Some(Ok(row)) => batch.push(row),
+                Some(Err(e)) => {
+                    error!(?e, "error listing directory");
+                    return Err(StatusCode::Failure);
+                }
+                None => {
+                    finished = true;
+                    break;
+                }
+            }
         }
-        for (dir_id, name) in listing.directory_ids_and_names {
-            let attrs = self.attrs_for_handle(Handle::Directory(dir_id)).await?;
-
-            files.push(SFTPFile {
-                filename: name.clone(),
-                // TODO: this should take the form of an ls listing
-                longname: format!("-rwxr-xr-x   1 mjos     staff      348911 Mar 25 14:29 t-filexfer"),
-                attrs,
-            });
+        if batch.is_empty() {
+            return Err(StatusCode::Eof);
+        }
+        if !finished {
+            self.directory_status.insert(handle, DirectoryStatus::Listing(rows));
         }
 
+        let now = SystemTime::now();
+        let files = batch.into_iter().filter_map(|row| listing_file(&self.cfg, &self.user, row, now)).collect();
+
         Ok(SFTPName {
             id,
             files,
@@ -458,45 +1305,40 @@ impl russh_sftp::server::Handler for SFTPConnection {
     async fn open(&mut self, id: u32, path: String, open_flags: OpenFlags, _attrs: FileAttributes)
         -> SFTPResult<SFTPHandle>
     {
-        let existing_uuid: Option<Uuid> = match self.handle_from_path(path).await {
+        let existing_uuid: Option<Uuid> = match self.handle_from_path(path.clone()).await {
             Ok(Handle::File(uuid)) => Some(uuid),
+            Ok(Handle::Directory(_)) if open_flags.contains(OpenFlags::CREATE) => {
+                debug!("Tried to create a file where there is a directory");
+                return Err(StatusCode::Failure);
+            }
             Ok(Handle::Directory(_)) | Err(StatusCode::NoSuchFile) => None,
             _ => return Err(StatusCode::Failure),
         };
 
-        let uuid = {
-            if open_flags.contains(OpenFlags::CREATE) {
-                if open_flags.contains(OpenFlags::EXCLUDE) && existing_uuid.is_some() {
-                    debug!("Tried to open an existing file with CREATE + EXCLUDE");
-                    return Err(StatusCode::Failure);
-                }
-                if let Some(uuid) = existing_uuid {
-                    uuid
-                } else {
-                    // TODO: we kinda wanna rework the FrontNode API for creating/writing to/reading from files
-                    // currently, file_uuid_for_path gives an UUID for an existing file
-                    // get_file takes the UUID and returns the content (should probably be called read_file)
-                    // upload_file takes a directory ID, a name and data, and creates a file with that name and writes the data to it
-                    error!("Creating files not yet supported");
-                    return Err(StatusCode::Failure);
-                }
-            } else {
-                if let Some(uuid) = existing_uuid {
-                    uuid
-                } else {
-                    debug!("Tried opening non-existant file");
-                    return Err(StatusCode::Failure);
-                }
-            }
+        if open_flags.contains(OpenFlags::CREATE | OpenFlags::EXCLUDE) && existing_uuid.is_some() {
+            debug!("Tried to open an existing file with CREATE + EXCLUDE");
+            return Err(StatusCode::Failure);
+        }
+        if existing_uuid.is_none() && !open_flags.contains(OpenFlags::CREATE) {
+            debug!("Tried opening non-existant file");
+            return Err(StatusCode::Failure);
+        }
+        let writing = open_flags.intersects(OpenFlags::WRITE | OpenFlags::APPEND | OpenFlags::CREATE | OpenFlags::TRUNCATE);
+        let uuid = match existing_uuid {
+            Some(uuid) if !writing => uuid,
+            _ => return self.open_for_writing(id, path, existing_uuid, open_flags).await,
         };
 
-        // TODO: do we need to track if we open the file in read mode?
-        // i think it should be standard-compliant to allow writing to files opened ind read mode and vice-versa
         let status = FileStatus {
-            append: open_flags.contains(OpenFlags::APPEND),
+            deleted: false,
+            path,
+            opened_at: Instant::now(),
+            bytes_read: 0,
         };
 
+        self.check_handle_limits(id)?;
         self.file_status.insert(uuid, status);
+        self.remember_handle(Handle::File(uuid).to_string());
 
         Ok(SFTPHandle {
             id,
@@ -506,14 +1348,54 @@ impl russh_sftp::server::Handler for SFTPConnection {
 
     #[instrument(level = "debug", skip(id))]
     async fn read(&mut self, id: u32, handle: String, offset: u64, len: u32) -> SFTPResult<SFTPData> {
-        let Handle::File(uuid) = handle.parse()? else {
-            return Err(StatusCode::BadMessage);
+        self.touch_handle(&handle)?;
+        let uuid = match handle.parse()? {
+            Handle::File(uuid) => uuid,
+            // what has been written so far
+            Handle::Write(n) => {
+                let Some(status) = self.write_status.get(&n) else {
+                    return Err(StatusCode::Failure);
+                };
+                let start = offset.min(status.contents.len() as u64) as usize;
+                let data = &status.contents[start..];
+                if data.is_empty() {
+                    return Err(StatusCode::Eof);
+                }
+                let data = data[..data.len().min(len as usize)].to_vec();
+                return Ok(SFTPData { id, data });
+            }
+            Handle::Directory(_) => return Err(StatusCode::BadMessage),
         };
+        if self.file_status.get(&uuid).is_some_and(|status| status.deleted) {
+            debug!(%uuid, "Tried to read a removed file");
+            return Err(StatusCode::NoSuchFile);
+        }
 
-        let (mut data, _info) = match self.node.get_file(uuid).await {
+        // reading at (or past) the end gives Eof, like OpenSSH does. for an empty file this means
+        // the very first read returns Eof, which clients treat as an empty file
+        let (data, _info) = match self.node.read_file_range(uuid, offset, len).await {
             Ok(x) => x,
+            Err(NodeError::ReadPastEnd { file_size }) => {
+                trace!(file_size, "Read past end of file");
+                return Err(StatusCode::Eof);
+            }
+            // removed by someone else since it was opened
+            Err(NodeError::UnknownUUID) => {
+                debug!(%uuid, "Tried to read a removed file");
+                return Err(StatusCode::NoSuchFile);
+            }
             Err(NodeError::NotConnectedToNode) => {
                 warn!(%uuid, "Could not read file; node not connected");
+                return Err(StatusCode::NoConnection);
+            }
+            Err(NodeError::AllAttemptsFailed(attempts)) => {
+                let unreachable = attempts.iter().all(|attempt| matches!(attempt.error, NodeError::NotConnectedToNode));
+                let attempts: Vec<String> = attempts.iter().map(|attempt| attempt.to_string()).collect();
+                error!(%uuid, ?attempts, "Could not read file");
+                return Err(if unreachable { StatusCode::NoConnection } else { StatusCode::Failure });
+            }
+            Err(NodeError::StorageNode { code, detail }) => {
+                error!(%uuid, ?code, detail, "Storage node could not read file");
                 return Err(StatusCode::Failure);
             }
             Err(e) => {
@@ -522,14 +1404,11 @@ impl russh_sftp::server::Handler for SFTPConnection {
             }
         };
 
-        if offset as usize >= data.len() {
-            return Err(StatusCode::Eof);
-        }
-
-        let mut data = data.split_off(offset as usize);
-        if len as usize <= data.len() {
-            data.truncate(len as usize);
+        if let Some(status) = self.file_status.get_mut(&uuid) {
+            status.bytes_read += data.len() as u64;
         }
+        self.session_bytes += data.len() as u64;
+        self.node.metrics.sftp_read_bytes.fetch_add(data.len() as u64, Ordering::Relaxed);
 
         Ok(SFTPData {
             id,
@@ -537,6 +1416,52 @@ impl russh_sftp::server::Handler for SFTPConnection {
         })
     }
 
+    #[instrument(level = "debug", skip(id, data), fields(data.len = data.len()))]
+    async fn write(&mut self, id: u32, handle: String, offset: u64, data: Vec<u8>) -> SFTPResult<Status> {
+        self.touch_handle(&handle)?;
+        let n = match handle.parse()? {
+            Handle::Write(n) => n,
+            Handle::File(_) => {
+                debug!("Tried to write to a file opened for reading");
+                return Err(StatusCode::PermissionDenied);
+            }
+            Handle::Directory(_) => return Err(StatusCode::BadMessage),
+        };
+        let Some(status) = self.write_status.get(&n) else {
+            warn!(handle, "Tried to write to a non-opened handle");
+            return Err(StatusCode::Failure);
+        };
+
+        let offset = if status.append { status.contents.len() as u64 } else { offset };
+        let max_write_bytes = self.cfg.max_write_bytes;
+        let Some(end) = offset.checked_add(data.len() as u64).filter(|&end| end <= max_write_bytes) else {
+            debug!(offset, max_write_bytes, "Write past the largest file which can be written");
+            let message = format!("Files written over SFTP can be at most {max_write_bytes} bytes");
+            return Err(self.status_messages.fail(id, message));
+        };
+        // refused now rather than on close, so the client doesn't send the rest of the file first
+        if end > status.contents.len() as u64 {
+            let growth = end as i64 - status.replaced_size as i64;
+            if let Err(message) = self.check_quota(growth).await? {
+                debug!(self.user, message, "Write over the user's quota");
+                return Err(self.status_messages.fail(id, message));
+            }
+        }
+        let Some(status) = self.write_status.get_mut(&n) else {
+            return Err(StatusCode::Failure);
+        };
+        let (offset, end) = (offset as usize, end as usize);
+        if status.contents.len() < end {
+            // a gap left by writing past the end reads as zeros, like a sparse file
+            status.contents.resize(end, 0);
+        }
+        status.contents[offset..end].copy_from_slice(&data);
+        status.dirty = true;
+        status.bytes_written += data.len() as u64;
+        self.session_bytes += data.len() as u64;
+
+        Ok(status_ok(id))
+    }
 
     #[instrument(level = "debug", skip(id))]
     async fn stat(&mut self, id: u32, path: String) -> SFTPResult<SFTPAttrs> {
@@ -552,22 +1477,135 @@ impl russh_sftp::server::Handler for SFTPConnection {
 
     #[instrument(level = "debug", skip(id))]
     async fn fstat(&mut self, id: u32, handle: String) -> SFTPResult<SFTPAttrs> {
+        self.touch_handle(&handle)?;
         let handle: Handle = handle.parse()?;
         self.handle_stat(id, handle).await
     }
 
+    #[instrument(level = "debug", skip(id, _attrs))]
+    async fn mkdir(&mut self, id: u32, path: String, _attrs: FileAttributes) -> SFTPResult<Status> {
+        if self.handle_from_path(path.clone()).await.is_ok() {
+            debug!("Tried to create a directory where something exists already");
+            return Ok(status_failure(id, "File exists"));
+        }
+        let (parent, name) = self.parent_and_name(path).await?;
+        match self.node.create_directory(parent, name).await {
+            Ok(_) => Ok(status_ok(id)),
+            Err(NodeError::AlreadyExists) => Ok(status_failure(id, "File exists")),
+            Err(NodeError::InvalidName { .. }) => Ok(status_failure(id, "Invalid directory name")),
+            Err(e) => {
+                error!(?e, "Could not create directory");
+                Err(StatusCode::Failure)
+            }
+        }
+    }
+
     #[instrument(level = "debug", skip(id))]
-    async fn close(&mut self, id: u32, handle: String) -> SFTPResult<Status> {
-        let handle: Handle = handle.parse()?;
+    async fn rmdir(&mut self, id: u32, path: String) -> SFTPResult<Status> {
+        let Handle::Directory(dir) = self.handle_from_path(path).await? else {
+            return Ok(status_failure(id, "Not a directory"));
+        };
+        match self.node.remove_directory(dir).await {
+            Ok(()) => Ok(status_ok(id)),
+            Err(NodeError::DirectoryNotEmpty) => Ok(status_failure(id, "Directory not empty")),
+            Err(NodeError::DirectoryIsHome) => Ok(status_failure(id, "Directory is a user's home")),
+            Err(NodeError::CannotRemoveRoot) => Err(StatusCode::PermissionDenied),
+            Err(NodeError::NoSuchDirectory { .. }) => Err(StatusCode::NoSuchFile),
+            Err(e) => {
+                error!(?e, "Could not remove directory");
+                Err(StatusCode::Failure)
+            }
+        }
+    }
+
+    #[instrument(level = "debug", skip(id))]
+    async fn remove(&mut self, id: u32, filename: String) -> SFTPResult<Status> {
+        let Handle::File(uuid) = self.handle_from_path(filename.clone()).await? else {
+            debug!("Tried to remove a directory");
+            return Err(StatusCode::NoSuchFile);
+        };
+        // looked up first, as it's gone with the file. files without a stored size count as empty
+        let size = self.node.file_size(uuid).await.ok().flatten().unwrap_or(0);
+        match self.node.remove_file(uuid, false).await {
+            Ok(()) => {
+                info!(self.user, filename, %uuid, "Removed file");
+                self.node.add_usage(&self.user, -(size as i64));
+                if let Some(status) = self.file_status.get_mut(&uuid) {
+                    status.deleted = true;
+                }
+                Ok(status_ok(id))
+            }
+            Err(NodeError::NoSuchFile) => Err(StatusCode::NoSuchFile),
+            Err(NodeError::AllAttemptsFailed(attempts)) => {
+                let attempts: Vec<String> = attempts.iter().map(|attempt| attempt.to_string()).collect();
+                error!(?attempts, "Could not remove file");
+                Ok(status_failure(id, &format!("Could not remove file from storage: {}", attempts.join("; "))))
+            }
+            Err(e) => {
+                error!(?e, "Could not remove file");
+                Err(StatusCode::Failure)
+            }
+        }
+    }
+
+    #[instrument(level = "debug", skip(id))]
+    async fn rename(&mut self, id: u32, oldpath: String, newpath: String) -> SFTPResult<Status> {
+        let source = self.handle_from_path(oldpath.clone()).await?;
+        // SFTP v3 renames never overwrite
+        if self.handle_from_path(newpath.clone()).await.is_ok() {
+            debug!("Tried to rename onto something which exists already");
+            return Ok(status_failure(id, "File exists"));
+        }
+        let (new_dir, new_name) = self.parent_and_name(newpath.clone()).await?;
+
+        let result = match source {
+            Handle::File(uuid) => self.node.move_file(uuid, new_dir, new_name).await,
+            Handle::Directory(dir) => self.node.move_directory(dir, new_dir, new_name).await,
+            Handle::Write(_) => unreachable!("paths resolve to files and directories"),
+        };
+        match result {
+            Ok(()) => {
+                info!(self.user, oldpath, newpath, "Renamed");
+                Ok(status_ok(id))
+            }
+            Err(NodeError::NoSuchFile | NodeError::NoSuchDirectory { .. }) => Err(StatusCode::NoSuchFile),
+            Err(NodeError::FileExists) => Ok(status_failure(id, "File exists")),
+            Err(NodeError::MoveIntoOwnSubtree) => Ok(status_failure(id, "Cannot move a directory into itself")),
+            Err(e) => {
+                error!(?e, "Could not rename");
+                Err(StatusCode::Failure)
+            }
+        }
+    }
+
+    #[instrument(level = "debug", skip(id))]
+    async fn close(&mut self, id: u32, handle_str: String) -> SFTPResult<Status> {
+        if self.take_reclaimed(&handle_str) {
+            warn!(self.user, handle = handle_str, "Client closed a handle which was reclaimed for being idle");
+            return Err(StatusCode::Failure);
+        }
+        self.forget_handle(&handle_str);
+        let handle: Handle = handle_str.parse()?;
         match handle {
             Handle::File(ref uuid) => {
-                if self.file_status.remove(uuid).is_none() {
+                let Some(status) = self.file_status.remove(uuid) else {
+                    warn!(?handle, "Tried to close non-opened handle");
+                    return Err(StatusCode::Failure);
+                };
+                self.log_access("SFTP_READ", status.path, status.bytes_read, status.opened_at.elapsed());
+            }
+            Handle::Write(n) => {
+                let Some(status) = self.write_status.remove(&n) else {
                     warn!(?handle, "Tried to close non-opened handle");
                     return Err(StatusCode::Failure);
+                };
+                if status.dirty {
+                    return self.commit_write(id, status).await;
                 }
+                self.log_access("SFTP_WRITE", status.path, 0, status.opened_at.elapsed());
             }
-            Handle::Directory(ref dir_id) => {
-                if self.directory_status.remove(dir_id).is_none() {
+            Handle::Directory(_) => {
+                if self.directory_status.remove(&handle_str).is_none() {
                     warn!(?handle, "Tried to close non-opened directory");
                     return Err(StatusCode::Failure);
                 }
@@ -576,6 +1614,31 @@ impl russh_sftp::server::Handler for SFTPConnection {
         return Ok(status_ok(id));
     }
 
+    #[instrument(level = "debug", skip(id, data))]
+    async fn extended(&mut self, id: u32, request: String, data: Vec<u8>) -> SFTPResult<Packet> {
+        let reply = match request.as_str() {
+            // from draft-ietf-secsh-filexfer-13, section 9.2
+            "space-available" => {
+                let (device, user) = self.storage_space_for_request(&data).await?;
+                space_available_reply(device, user)
+            }
+            extensions::STATVFS => {
+                let (_, user) = self.storage_space_for_request(&data).await?;
+                russh_sftp::ser::to_bytes(&statvfs_reply(user))
+                    .map_err(|e| {
+                        error!(?e, "Could not serialize statvfs reply");
+                        StatusCode::Failure
+                    })?
+                    .to_vec()
+            }
+            _ => {
+                debug!(request, "Unsupported extended request");
+                return Err(StatusCode::OpUnsupported);
+            }
+        };
+        Ok(Packet::ExtendedReply(ExtendedReply { id, data: reply }))
+    }
+
 }
 
 // TODO: we should read these files asyncly
@@ -591,49 +1654,815 @@ fn read_server_keypair(
     Ok((public, private))
 }
 
-// Handles errors by printing to STDOUT and (probably) returning
-#[instrument(skip(cfg, node))]
-#[allow(unused_variables)]
-pub async fn launch_sftp_server(
+pub struct BoundSFTPServer {
+    server: SSHServer,
+    ssh_config: Arc<russh::server::Config>,
+    listener: tokio::net::TcpListener,
+}
+
+// Reads the server keys and binds the listening socket, recording the outcome in the summary.
+// Errors are logged, and result in None being returned
+#[instrument(skip(cfg, node, summary))]
+pub async fn bind_sftp_server(
     cfg: &config::SFTPServerOptions,
     node: Arc<FrontNode>,
-) {
+    summary: &mut StartupSummary,
+) -> Option<BoundSFTPServer> {
+    summary.sftp_listen_addr = Some(cfg.listen_addr.clone());
+
     let (_public_key, private_key) = match read_server_keypair(cfg) {
-        Ok(x) => x,
+        Ok(x) => {
+            summary.sftp_keys_loaded = Some(Ok(()));
+            x
+        }
         Err(SSHError::ReadPublicKeyError(e)) => {
             error!(?e, "Could not read public key");
-            return;
+            summary.sftp_keys_loaded = Some(Err(format!("could not read public key {}: {e}", cfg.public_key)));
+            return None;
         }
         Err(SSHError::ReadPrivateKeyError(e)) => {
             error!(?e, "Could not read private key");
-            return;
+            summary.sftp_keys_loaded = Some(Err(format!("could not read private key {}: {e}", cfg.private_key)));
+            return None;
         }
         _ => unreachable!(),
     };
 
-    use std::time::Duration;
     let ssh_config = russh::server::Config {
         auth_banner: Some("welcome to bnuystore!!\n"),
         auth_rejection_time: Duration::from_secs(3),
         auth_rejection_time_initial: Some(Duration::from_secs(0)),
+        inactivity_timeout: Some(Duration::from_secs(cfg.session_idle_timeout_s)),
         keys: vec![private_key],
+        methods: if cfg.allow_password_auth {
+            russh::MethodSet::all()
+        } else {
+            russh::MethodSet::all() - russh::MethodSet::PASSWORD
+        },
         ..Default::default()
     };
 
     let Ok(addr) =  cfg.listen_addr.parse::<SocketAddr>() else {
         error!("Could not parse SFTP address {}. Format must be IP:PORT", cfg.listen_addr);
-        return;
+        summary.sftp_bound = Some(Err("could not parse address. Format must be IP:PORT".to_string()));
+        return None;
     };
 
-    info!(%addr, "Launching SSH server");
-    let mut server = SSHServer { node };
-    match server.run_on_address(
-        Arc::new(ssh_config),
-        addr,
-    ).await {
-        Ok(_) => {},
+    let listener = match tokio::net::TcpListener::bind(addr).await {
+        Ok(l) => l,
         Err(e) => {
-            error!(?e, "Failed to run SSH server");
+            error!(%addr, ?e, "Could not bind to SFTP address");
+            summary.sftp_bound = Some(Err(e.to_string()));
+            return None;
+        }
+    };
+    summary.sftp_bound = Some(Ok(()));
+    summary.features.push(format!(
+        "sftp (handle limits {}/{})", cfg.open_handles_soft_limit, cfg.open_handles_hard_limit,
+    ));
+
+    Some(BoundSFTPServer {
+        server: SSHServer { node, cfg: Arc::new(cfg.clone()) },
+        ssh_config: Arc::new(ssh_config),
+        listener,
+    })
+}
+
+impl BoundSFTPServer {
+    // Accepts connections until the server fails or `shutdown` resolves. Sessions which are already
+    // open keep running
+    #[instrument(skip(self, shutdown))]
+    pub async fn run(mut self, shutdown: impl std::future::Future<Output = ()>) {
+        info!(addr = ?self.listener.local_addr(), "Launching SSH server");
+        tokio::pin!(shutdown);
+        loop {
+            let accepted = tokio::select! {
+                accepted = self.listener.accept() => accepted,
+                _ = &mut shutdown => {
+                    info!("Shutting down; no longer accepting SSH connections");
+                    return;
+                }
+            };
+            let (socket, client_addr) = match accepted {
+                Ok(x) => x,
+                Err(e) => {
+                    error!(?e, "Failed to run SSH server. Not restarting.");
+                    return;
+                }
+            };
+            let session = self.server.new_client(Some(client_addr));
+            tokio::spawn(serve_connection(self.ssh_config.clone(), socket, session));
+        }
+    }
+}
+
+/// Runs one SSH connection until it's closed, closing it if the client doesn't authenticate in time
+#[instrument(level = "debug", skip(ssh_config, socket, session), fields(client_addr = ?session.client_addr))]
+async fn serve_connection(ssh_config: Arc<russh::server::Config>, socket: tokio::net::TcpStream, session: SSHSession) {
+    let client_addr = session.client_addr;
+    let control = session.control.clone();
+    let auth_timeout = Duration::from_secs(session.cfg.auth_timeout_s);
+    let socket = Severable { inner: socket, control: control.clone() };
+
+    // the SSH version exchange in run_stream counts towards the auth timeout
+    let auth_deadline = tokio::time::sleep(auth_timeout);
+    tokio::pin!(auth_deadline);
+    let running = tokio::select! {
+        running = russh::server::run_stream(ssh_config, socket, session) => running,
+        () = &mut auth_deadline => return Termination::AuthTimeout.record(client_addr),
+    };
+    let running = match running {
+        Ok(running) => running,
+        Err(e) => return debug!(?e, "Connection setup failed"),
+    };
+
+    let auth_check = async {
+        auth_deadline.await;
+        if !control.authenticated.load(Ordering::Relaxed) {
+            control.terminate(Termination::AuthTimeout, client_addr);
+        }
+        std::future::pending::<()>().await
+    };
+    tokio::select! {
+        result = running => match result {
+            Ok(()) => debug!("Connection closed"),
+            Err(SSHError::Russh(russh::Error::InactivityTimeout)) => Termination::Idle.record(client_addr),
+            Err(e) => debug!(?e, "Connection closed with error"),
+        },
+        () = auth_check => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::test_support;
+    use russh_sftp::server::Handler;
+
+    fn sftp_options(extra: &str) -> config::SFTPServerOptions {
+        toml::from_str(&format!(r#"
+            listen_addr = "127.0.0.1:0"
+            public_key = "unused.pub"
+            private_key = "unused"
+            {extra}
+        "#)).unwrap()
+    }
+
+    fn connection(cfg: config::SFTPServerOptions) -> SFTPConnection {
+        connection_to(test_support::offline(&test_support::offline_config()), cfg)
+    }
+
+    fn connection_to(node: Arc<FrontNode>, cfg: config::SFTPServerOptions) -> SFTPConnection {
+        // without a quota, and cached, as the database isn't there to sum it
+        set_usage(&node, 0, None);
+        let permit = node.limits.try_acquire_user("bnuy").unwrap();
+        SFTPConnection::new(node, Arc::new(cfg), "bnuy".to_string(), None, permit)
+    }
+
+    fn set_usage(node: &FrontNode, used_bytes: u64, quota_bytes: Option<u64>) {
+        let usage = super::super::quotas::UserUsage { used_bytes, quota_bytes };
+        node.usage_cache.lock().unwrap().insert("bnuy".to_string(), (Instant::now(), usage));
+    }
+
+    fn file_handle() -> String {
+        Handle::File(Uuid::now_v7()).to_string()
+    }
+
+    /// The fields of an ls -l line: mode, links, owner, group, size, date and name. The date is
+    /// three fields, and the name is the rest of the line
+    fn parse_longname(longname: &str) -> (&str, u64, &str, &str, u64, String, &str) {
+        let mut rest = longname;
+        let mut fields = Vec::new();
+        for _ in 0..8 {
+            let (field, after) = rest.trim_start().split_once(' ').unwrap();
+            fields.push(field);
+            rest = after;
+        }
+        let date = fields[5..8].join(" ");
+        (fields[0], fields[1].parse().unwrap(), fields[2], fields[3], fields[4].parse().unwrap(), date, rest)
+    }
+
+    #[test]
+    fn readdir_longnames_parse() {
+        let cfg = sftp_options("listing_group = \"burrow\"");
+        let uuid = Uuid::now_v7();
+        let now = file_creation_time(uuid) + Duration::from_secs(60);
+
+        let file = listing_file(&cfg, "xenia", ListingRow::File(uuid, "carrot notes.txt".to_string(), Some(348911)), now).unwrap();
+        assert_eq!(file.filename, "carrot notes.txt");
+        let (mode, links, owner, group, size, date, name) = parse_longname(&file.longname);
+        assert_eq!(mode, "-rwxrwxrwx");
+        assert_eq!(file.attrs.permissions.unwrap() & 0o777, 0o777);
+        assert_eq!((links, owner, group, size), (1, "xenia", "burrow", 348911));
+        // created a minute ago, so the time of day is shown
+        assert!(date.contains(':'), "{date}");
+        assert_eq!(name, "carrot notes.txt");
+
+        let dir = listing_file(&cfg, "xenia", ListingRow::Directory(DirectoryID(1), "burrow".to_string()), now).unwrap();
+        let (mode, links, owner, group, size, date, name) = parse_longname(&dir.longname);
+        assert_eq!(mode, "drwxrwxrwx");
+        assert_eq!((links, owner, group, size), (1, "xenia", "burrow", 0));
+        assert_eq!(date, "Jan 1 1970");
+        assert_eq!(name, "burrow");
+
+        // files without a stored size show as empty, rather than breaking the columns
+        let no_size = listing_file(&cfg, "xenia", ListingRow::File(uuid, "old".to_string(), None), now).unwrap();
+        assert_eq!(parse_longname(&no_size.longname).4, 0);
+
+        assert!(listing_file(&cfg, "xenia", ListingRow::DeletedFile(uuid, "gone".to_string(), None, 0), now).is_none());
+    }
+
+    #[test]
+    fn each_opendir_gets_its_own_handle() {
+        let dir = DirectoryID(5);
+        let (first, second) = (directory_handle(dir, 0), directory_handle(dir, 1));
+        assert_ne!(first, second);
+        // and handles from before opens were counted still parse
+        for handle in [first, second, Handle::Directory(dir).to_string()] {
+            assert!(matches!(handle.parse::<Handle>(), Ok(Handle::Directory(DirectoryID(5)))), "{handle}");
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MySQL database, see test_support::DATABASE_URL_VAR"]
+    async fn interleaved_listings_of_one_directory() {
+        let node = Arc::new(FrontNode::start_from_config(&test_support::database_config(), &mut StartupSummary::default()).await.unwrap());
+        let permit = node.limits.try_acquire_user("bnuy").unwrap();
+        let mut conn = SFTPConnection::new(node.clone(), Arc::new(sftp_options("")), "bnuy".to_string(), None, permit);
+        let path = format!("test-{}", Uuid::now_v7());
+        let parent = node.create_directories(&path, None).await.unwrap();
+        // more than a batch, so each listing takes two readdirs
+        let count = READDIR_BATCH_SIZE + READDIR_BATCH_SIZE / 2;
+        for i in 0..count {
+            node.create_directories(&format!("d{i}"), Some(parent)).await.unwrap();
+        }
+        let dir = format!("/{path}");
+
+        let first = conn.opendir(1, dir.clone()).await.unwrap().handle;
+        let second = conn.opendir(2, dir).await.unwrap().handle;
+        assert_ne!(first, second);
+
+        let mut first_names = Vec::new();
+        let mut second_names = Vec::new();
+        first_names.extend(conn.readdir(3, first.clone()).await.unwrap().files.into_iter().map(|file| file.filename));
+        second_names.extend(conn.readdir(4, second.clone()).await.unwrap().files.into_iter().map(|file| file.filename));
+        first_names.extend(conn.readdir(5, first.clone()).await.unwrap().files.into_iter().map(|file| file.filename));
+        assert_eq!(conn.readdir(6, first.clone()).await.unwrap_err(), StatusCode::Eof);
+
+        // closing the first listing leaves the second where it was
+        conn.close(7, first.clone()).await.unwrap();
+        second_names.extend(conn.readdir(8, second.clone()).await.unwrap().files.into_iter().map(|file| file.filename));
+        assert_eq!(conn.readdir(9, second.clone()).await.unwrap_err(), StatusCode::Eof);
+        conn.close(10, second).await.unwrap();
+
+        let mut expected: Vec<String> = (0..count).map(|i| format!("d{i}")).collect();
+        expected.sort();
+        for mut names in [first_names, second_names] {
+            names.sort();
+            assert_eq!(names, expected);
+        }
+        assert_eq!(conn.close(11, first).await.unwrap_err(), StatusCode::Failure);
+    }
+
+    #[tokio::test]
+    async fn leaky_client_hits_the_handle_limits() {
+        let mut conn = connection(sftp_options("open_handles_soft_limit = 2\nopen_handles_hard_limit = 4"));
+        // past the soft limit, handles are still given out
+        for id in 0..4 {
+            conn.check_handle_limits(id).unwrap();
+            conn.remember_handle(file_handle());
         }
+
+        assert_eq!(conn.check_handle_limits(4), Err(StatusCode::Failure));
+        let message = conn.status_messages.take(4).unwrap();
+        assert!(message.contains("at most 4"), "{message}");
+
+        // closing one makes room again
+        let handle = conn.handle_last_used.keys().next().unwrap().clone();
+        conn.forget_handle(&handle);
+        conn.check_handle_limits(5).unwrap();
+        assert_eq!(conn.status_messages.take(5), None);
+    }
+
+    #[tokio::test]
+    async fn reclaimed_handles_are_capped() {
+        let mut conn = connection(sftp_options("handle_idle_timeout_s = 0"));
+        let handles: Vec<String> = (0..MAX_RECLAIMED_HANDLES + 10).map(|_| file_handle()).collect();
+        for handle in &handles {
+            conn.remember_handle(handle.clone());
+        }
+        std::thread::sleep(Duration::from_millis(1));
+        conn.reclaim_idle_handles();
+
+        assert!(conn.handle_last_used.is_empty());
+        assert_eq!(conn.reclaimed_handles.len(), MAX_RECLAIMED_HANDLES);
+        // the rest are forgotten, as if they were never opened
+        let still_reclaimed = handles.iter().filter(|handle| conn.touch_handle(handle).is_err()).count();
+        assert_eq!(still_reclaimed, MAX_RECLAIMED_HANDLES);
+    }
+
+    // a write handle as open_for_writing leaves it, without the database it needs to check the path
+    fn write_handle(conn: &mut SFTPConnection, contents: &[u8], append: bool) -> String {
+        let n = conn.write_opens;
+        conn.write_opens += 1;
+        let status = WriteStatus {
+            dir: DirectoryID(1),
+            name: "notes".to_string(),
+            contents: contents.to_vec(),
+            append,
+            dirty: false,
+            replaced_size: contents.len() as u64,
+            exclusive: false,
+            path: "/notes".to_string(),
+            opened_at: Instant::now(),
+            bytes_written: 0,
+        };
+        conn.write_status.insert(n, status);
+        let handle = Handle::Write(n).to_string();
+        conn.remember_handle(handle.clone());
+        handle
+    }
+
+    #[tokio::test]
+    async fn writes_are_buffered_until_closed() {
+        let mut conn = connection(sftp_options("max_write_bytes = 16"));
+        let handle = write_handle(&mut conn, b"hello world", false);
+        assert!(matches!(handle.parse(), Ok(Handle::Write(0))), "{handle}");
+
+        conn.write(1, handle.clone(), 0, b"J".to_vec()).await.unwrap();
+        // past the end, leaving a gap of zeros
+        conn.write(2, handle.clone(), 13, b"!".to_vec()).await.unwrap();
+        assert_eq!(conn.fstat(3, handle.clone()).await.unwrap().attrs.size, Some(14));
+        assert_eq!(conn.read(4, handle.clone(), 0, 5).await.unwrap().data, b"Jello");
+        assert_eq!(conn.read(5, handle.clone(), 11, 100).await.unwrap().data, b"\0\0!");
+        assert_eq!(conn.read(6, handle.clone(), 14, 100).await.unwrap_err(), StatusCode::Eof);
+        assert!(conn.write_status[&0].dirty);
+
+        assert_eq!(conn.write(7, handle.clone(), 10, b"way too long".to_vec()).await.unwrap_err(), StatusCode::Failure);
+        let message = conn.status_messages.take(7).unwrap();
+        assert!(message.contains("at most 16"), "{message}");
+        assert_eq!(conn.write_status[&0].contents.len(), 14);
+
+        let appending = write_handle(&mut conn, b"log", true);
+        conn.write(8, appending.clone(), 0, b"!".to_vec()).await.unwrap();
+        assert_eq!(conn.read(9, appending, 0, 100).await.unwrap().data, b"log!");
+
+        assert_eq!(conn.write(10, file_handle(), 0, b"read only".to_vec()).await.unwrap_err(), StatusCode::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn empty_write_handles() {
+        let mut conn = connection(sftp_options(""));
+        // as opened with TRUNCATE, replacing a file
+        let handle = write_handle(&mut conn, b"", false);
+        assert_eq!(conn.fstat(1, handle.clone()).await.unwrap().attrs.size, Some(0));
+        assert_eq!(conn.read(2, handle.clone(), 0, 4096).await.unwrap_err(), StatusCode::Eof);
+
+        conn.write(3, handle.clone(), 0, Vec::new()).await.unwrap();
+        assert_eq!(conn.fstat(4, handle.clone()).await.unwrap().attrs.size, Some(0));
+        assert_eq!(conn.read(5, handle.clone(), 0, 4096).await.unwrap_err(), StatusCode::Eof);
+
+        conn.write(6, handle.clone(), 0, b"bnuy".to_vec()).await.unwrap();
+        assert_eq!(conn.read(7, handle.clone(), 0, 4096).await.unwrap().data, b"bnuy");
+        assert_eq!(conn.read(8, handle, 4, 4096).await.unwrap_err(), StatusCode::Eof);
+    }
+
+    #[tokio::test]
+    async fn reclaimed_write_handles_are_discarded() {
+        let mut conn = connection(sftp_options("handle_idle_timeout_s = 0"));
+        let handle = write_handle(&mut conn, b"", false);
+        conn.write(1, handle.clone(), 0, b"bnuy".to_vec()).await.unwrap();
+        std::thread::sleep(Duration::from_millis(1));
+        conn.reclaim_idle_handles();
+
+        assert!(conn.write_status.is_empty());
+        assert_eq!(conn.write(2, handle.clone(), 4, b"!".to_vec()).await.unwrap_err(), StatusCode::Failure);
+        assert_eq!(conn.close(3, handle).await.unwrap_err(), StatusCode::Failure);
+    }
+
+    #[tokio::test]
+    async fn writes_over_quota_are_refused() {
+        let mut conn = connection(sftp_options(""));
+        set_usage(&conn.node, 90, Some(100));
+        // replacing a 5 byte file, so it can grow to 15 bytes
+        let handle = write_handle(&mut conn, b"hello", false);
+        conn.write(1, handle.clone(), 0, vec![b'a'; 15]).await.unwrap();
+        assert_eq!(conn.write(2, handle.clone(), 15, b"!".to_vec()).await.unwrap_err(), StatusCode::Failure);
+        assert_eq!(conn.status_messages.take(2).unwrap(), "quota exceeded: 90 bytes used of 100");
+        assert_eq!(conn.write_status[&0].contents.len(), 15);
+        // within what is already written, so nothing grows
+        conn.write(3, handle.clone(), 0, b"b".to_vec()).await.unwrap();
+
+        // another handle stored 10 bytes since, which this one is checked against again on close
+        conn.node.add_usage("bnuy", 10);
+        let status = conn.close(4, handle).await.unwrap();
+        assert_eq!(status.status_code, StatusCode::Failure);
+        assert_eq!(status.error_message, "quota exceeded: 100 bytes used of 100");
+
+        // shrinking is fine over quota. it only fails here for the database not being there
+        let shrinking = write_handle(&mut conn, b"hello", false);
+        conn.write(5, shrinking.clone(), 0, b"h".to_vec()).await.unwrap();
+        conn.write_status.get_mut(&1).unwrap().contents.truncate(1);
+        assert!(conn.close(6, shrinking).await.is_err());
+        assert!(conn.status_messages.take(6).is_none());
+
+        set_usage(&conn.node, 1 << 40, None);
+        let unlimited = write_handle(&mut conn, b"", false);
+        conn.write(7, unlimited, 0, vec![0; 1000]).await.unwrap();
+    }
+
+    #[test]
+    fn space_is_reported_for_the_user() {
+        let device = super::super::StorageSpace { bytes_total: 1 << 30, bytes_available: 1 << 20 };
+        let usage = super::super::quotas::UserUsage { used_bytes: 1 << 16, quota_bytes: Some(1 << 17) };
+        let user = usage.limit(device);
+
+        let reply = space_available_reply(device, user);
+        let fields: Vec<u64> = reply[..32].chunks(8).map(|field| u64::from_be_bytes(field.try_into().unwrap())).collect();
+        // bytes-on-device, unused-bytes-on-device, bytes-available-to-user, unused-bytes-available-to-user
+        assert_eq!(fields, [1 << 30, 1 << 20, 1 << 16, 1 << 16]);
+        assert_eq!(reply[32..], 4096u32.to_be_bytes());
+
+        let statvfs = statvfs_reply(user);
+        assert_eq!((statvfs.blocks, statvfs.blocks_free, statvfs.blocks_avail), (32, 16, 16));
+        let unlimited = statvfs_reply(device);
+        assert_eq!((unlimited.blocks, unlimited.blocks_free, unlimited.blocks_avail), (1 << 18, 1 << 8, 1 << 8));
+    }
+
+    // type, ID, status code, message and language tag, with the length in front
+    fn status_packet(id: u32, code: u32, message: &str) -> Vec<u8> {
+        let mut body = vec![STATUS_PACKET];
+        body.extend_from_slice(&id.to_be_bytes());
+        body.extend_from_slice(&code.to_be_bytes());
+        for string in [message, "en-US"] {
+            body.extend_from_slice(&(string.len() as u32).to_be_bytes());
+            body.extend_from_slice(string.as_bytes());
+        }
+        [(body.len() as u32).to_be_bytes().to_vec(), body].concat()
+    }
+
+    #[tokio::test]
+    async fn status_messages_reach_the_client() {
+        use tokio::io::AsyncWriteExt;
+        // SSH_FX_NO_SUCH_FILE and SSH_FX_FAILURE
+        let (no_such_file, failure) = (2, 4);
+
+        let messages = StatusMessages::default();
+        let mut writer = WithStatusMessages::new(Vec::new(), messages.clone());
+        messages.fail(7, "Too many open handles".to_string());
+
+        // as russh-sftp sends them
+        for packet in [status_packet(6, no_such_file, "No such file"), status_packet(7, failure, "Failure")] {
+            // in two writes, to check packets split across writes are put back together
+            let (start, end) = packet.split_at(3);
+            writer.write_all(start).await.unwrap();
+            writer.write_all(end).await.unwrap();
+            writer.flush().await.unwrap();
+        }
+
+        let expected = [
+            status_packet(6, no_such_file, "No such file"),
+            status_packet(7, failure, "Too many open handles"),
+        ].concat();
+        assert_eq!(writer.inner, expected);
+        assert_eq!(messages.take(7), None);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MySQL database, see test_support::DATABASE_URL_VAR"]
+    async fn empty_files() {
+        let front = test_support::DatabaseFrontNode::start_with_faults(&test_support::database_config(), Default::default()).await;
+        let mut conn = connection_to(front.node.clone(), sftp_options(""));
+        let dir = format!("/{}", front.path);
+        let path = format!("{dir}/empty");
+
+        let handle = conn.open(1, path.clone(), OpenFlags::CREATE | OpenFlags::WRITE | OpenFlags::TRUNCATE, FileAttributes::default()).await.unwrap().handle;
+        assert_eq!(conn.fstat(2, handle.clone()).await.unwrap().attrs.size, Some(0));
+        conn.close(3, handle).await.unwrap();
+        assert_eq!(conn.stat(4, path.clone()).await.unwrap().attrs.size, Some(0));
+
+        let handle = conn.opendir(5, dir).await.unwrap().handle;
+        let files = conn.readdir(6, handle.clone()).await.unwrap().files;
+        let names: Vec<_> = files.iter().map(|file| (file.filename.as_str(), file.attrs.size)).collect();
+        assert_eq!(names, [("empty", Some(0))]);
+        assert_eq!(conn.readdir(7, handle.clone()).await.unwrap_err(), StatusCode::Eof);
+        conn.close(8, handle).await.unwrap();
+
+        // the first read is already at the end
+        let handle = conn.open(9, path.clone(), OpenFlags::READ, FileAttributes::default()).await.unwrap().handle;
+        assert_eq!(conn.read(10, handle.clone(), 0, 4096).await.unwrap_err(), StatusCode::Eof);
+        conn.close(11, handle).await.unwrap();
+
+        assert_eq!(conn.remove(12, path.clone()).await.unwrap().status_code, StatusCode::Ok);
+        assert_eq!(conn.stat(13, path).await.unwrap_err(), StatusCode::NoSuchFile);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MySQL database, see test_support::DATABASE_URL_VAR"]
+    async fn removed_files_are_taken_off_the_usage() {
+        let front = test_support::DatabaseFrontNode::start_with_faults(&test_support::database_config(), Default::default()).await;
+        let mut conn = connection_to(front.node.clone(), sftp_options(""));
+        let path = format!("/{}/removed", front.path);
+
+        let handle = conn.open(1, path.clone(), OpenFlags::CREATE | OpenFlags::WRITE, FileAttributes::default()).await.unwrap().handle;
+        conn.write(2, handle.clone(), 0, b"hello".to_vec()).await.unwrap();
+        conn.close(3, handle).await.unwrap();
+        set_usage(&conn.node, 100, Some(100));
+
+        assert_eq!(conn.remove(4, path).await.unwrap().status_code, StatusCode::Ok);
+        let usage = conn.node.usage_cache.lock().unwrap()["bnuy"].1;
+        assert_eq!(usage.used_bytes, 95);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MySQL database, see test_support::DATABASE_URL_VAR"]
+    async fn home_directories_are_not_removed() {
+        let front = test_support::DatabaseFrontNode::start_with_faults(&test_support::database_config(), Default::default()).await;
+        let mut conn = connection_to(front.node.clone(), sftp_options(""));
+        let name = format!("hare-{}", Uuid::now_v7());
+        front.node.create_user(&name, None, &front.path).await.unwrap();
+        let path = format!("/{}/{name}", front.path);
+
+        let status = conn.rmdir(1, path.clone()).await.unwrap();
+        assert_eq!(status.status_code, StatusCode::Failure);
+        assert_eq!(status.error_message, "Directory is a user's home");
+        assert!(conn.stat(2, path).await.is_ok());
+    }
+
+    async fn read_whole_file(conn: &mut SFTPConnection, path: &str) -> Vec<u8> {
+        let handle = conn.open(100, path.to_string(), OpenFlags::READ, FileAttributes::default()).await.unwrap().handle;
+        let data = conn.read(101, handle.clone(), 0, 4096).await.unwrap().data;
+        conn.close(102, handle).await.unwrap();
+        data
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MySQL database, see test_support::DATABASE_URL_VAR"]
+    async fn written_files_are_stored_on_close() {
+        let front = test_support::DatabaseFrontNode::start_with_faults(&test_support::database_config(), Default::default()).await;
+        let mut conn = connection_to(front.node.clone(), sftp_options(""));
+        let path = format!("/{}/written", front.path);
+        let create = OpenFlags::CREATE | OpenFlags::WRITE | OpenFlags::TRUNCATE;
+
+        let handle = conn.open(1, path.clone(), create, FileAttributes::default()).await.unwrap().handle;
+        conn.write(2, handle.clone(), 0, b"hello ".to_vec()).await.unwrap();
+        conn.write(3, handle.clone(), 6, b"world".to_vec()).await.unwrap();
+        // nothing is there until the handle is closed
+        assert_eq!(conn.stat(4, path.clone()).await.unwrap_err(), StatusCode::NoSuchFile);
+        assert_eq!(conn.close(5, handle).await.unwrap().status_code, StatusCode::Ok);
+        assert_eq!(read_whole_file(&mut conn, &path).await, b"hello world");
+
+        // without TRUNCATE, the rest of the file is kept
+        let handle = conn.open(6, path.clone(), OpenFlags::WRITE, FileAttributes::default()).await.unwrap().handle;
+        conn.write(7, handle.clone(), 0, b"J".to_vec()).await.unwrap();
+        conn.close(8, handle).await.unwrap();
+        assert_eq!(read_whole_file(&mut conn, &path).await, b"Jello world");
+
+        // with it, the file is emptied even if nothing is written
+        let handle = conn.open(9, path.clone(), OpenFlags::WRITE | OpenFlags::TRUNCATE, FileAttributes::default()).await.unwrap().handle;
+        conn.close(10, handle).await.unwrap();
+        assert_eq!(conn.stat(11, path.clone()).await.unwrap().attrs.size, Some(0));
+
+        let exclusive = OpenFlags::CREATE | OpenFlags::EXCLUDE | OpenFlags::WRITE;
+        assert_eq!(conn.open(12, path.clone(), exclusive, FileAttributes::default()).await.unwrap_err(), StatusCode::Failure);
+
+        // a client going away before closing leaves nothing behind
+        let abandoned = format!("/{}/abandoned", front.path);
+        let handle = conn.open(13, abandoned.clone(), create, FileAttributes::default()).await.unwrap().handle;
+        conn.write(14, handle, 0, b"half".to_vec()).await.unwrap();
+        drop(conn);
+        let mut conn = connection_to(front.node.clone(), sftp_options(""));
+        assert_eq!(conn.stat(15, abandoned).await.unwrap_err(), StatusCode::NoSuchFile);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MySQL database, see test_support::DATABASE_URL_VAR"]
+    async fn exclusive_creates_are_checked_on_close() {
+        let front = test_support::DatabaseFrontNode::start_with_faults(&test_support::database_config(), Default::default()).await;
+        let mut first = connection_to(front.node.clone(), sftp_options(""));
+        let mut second = connection_to(front.node.clone(), sftp_options(""));
+        let path = format!("/{}/raced", front.path);
+        let exclusive = OpenFlags::CREATE | OpenFlags::EXCLUDE | OpenFlags::WRITE;
+
+        // nothing is there yet, so both opens succeed
+        let first_handle = first.open(1, path.clone(), exclusive, FileAttributes::default()).await.unwrap().handle;
+        let second_handle = second.open(1, path.clone(), exclusive, FileAttributes::default()).await.unwrap().handle;
+        first.write(2, first_handle.clone(), 0, b"first".to_vec()).await.unwrap();
+        second.write(2, second_handle.clone(), 0, b"second".to_vec()).await.unwrap();
+
+        assert_eq!(first.close(3, first_handle).await.unwrap().status_code, StatusCode::Ok);
+        let status = second.close(3, second_handle).await.unwrap();
+        assert_eq!(status.status_code, StatusCode::Failure);
+        assert_eq!(status.error_message, "A file with that name already exists");
+        assert_eq!(read_whole_file(&mut second, &path).await, b"first");
+    }
+
+    // an opendir of directory 1 whose listing has been started, without the database it is read from
+    fn started_listing(conn: &mut SFTPConnection, names: &[String]) -> String {
+        let handle = directory_handle(DirectoryID(1), conn.directory_opens);
+        conn.directory_opens += 1;
+        let (mut rows, listing) = mpsc::channel(names.len());
+        for (i, name) in names.iter().enumerate() {
+            rows.try_send(Ok(ListingRow::Directory(DirectoryID(i as i64 + 2), name.clone()))).unwrap();
+        }
+        conn.directory_status.insert(handle.clone(), DirectoryStatus::Listing(listing));
+        conn.remember_handle(handle.clone());
+        handle
+    }
+
+    #[tokio::test]
+    async fn interleaved_listings_are_kept_apart() {
+        let mut conn = connection(sftp_options(""));
+        let count = READDIR_BATCH_SIZE + READDIR_BATCH_SIZE / 2;
+        let names: Vec<String> = (0..count).map(|i| format!("d{i}")).collect();
+        let first = started_listing(&mut conn, &names);
+        let second = started_listing(&mut conn, &names);
+        assert_ne!(first, second);
+
+        let read = |files: Vec<russh_sftp::protocol::File>| files.into_iter().map(|file| file.filename).collect::<Vec<_>>();
+        let mut first_names = read(conn.readdir(1, first.clone()).await.unwrap().files);
+        let mut second_names = read(conn.readdir(2, second.clone()).await.unwrap().files);
+        assert_eq!(first_names.len(), READDIR_BATCH_SIZE);
+        first_names.extend(read(conn.readdir(3, first.clone()).await.unwrap().files));
+        assert_eq!(conn.readdir(4, first.clone()).await.unwrap_err(), StatusCode::Eof);
+
+        conn.close(5, first.clone()).await.unwrap();
+        second_names.extend(read(conn.readdir(6, second.clone()).await.unwrap().files));
+        assert_eq!(conn.readdir(7, second.clone()).await.unwrap_err(), StatusCode::Eof);
+        assert_eq!(first_names, names);
+        assert_eq!(second_names, names);
+
+        assert_eq!(conn.close(8, first.clone()).await.unwrap_err(), StatusCode::Failure);
+        assert_eq!(conn.readdir(9, first).await.unwrap_err(), StatusCode::BadMessage);
+        conn.close(10, second).await.unwrap();
+    }
+
+    fn terminations(reason: Termination) -> u64 {
+        TERMINATIONS[reason as usize].load(Ordering::Relaxed)
+    }
+
+    /// An init packet with `extensions` extension pairs
+    fn init_packet(extensions: usize) -> Vec<u8> {
+        // SSH_FXP_INIT, version 3
+        let mut body = vec![1, 0, 0, 0, 3];
+        for i in 0..extensions {
+            for string in [format!("ext{i}@bnuy"), "1".to_string()] {
+                body.extend_from_slice(&(string.len() as u32).to_be_bytes());
+                body.extend_from_slice(string.as_bytes());
+            }
+        }
+        let mut packet = (body.len() as u32).to_be_bytes().to_vec();
+        packet.extend(body);
+        packet
+    }
+
+    #[tokio::test]
+    async fn init_packets_are_capped() {
+        let cfg = sftp_options("max_init_packet_bytes = 1024\nmax_client_extensions = 2");
+
+        let packet = init_packet(2);
+        assert_eq!(read_init_packet(&mut packet.as_slice(), &cfg).await.unwrap(), packet);
+        let too_many = init_packet(3);
+        assert!(matches!(read_init_packet(&mut too_many.as_slice(), &cfg).await, Err(Some(Termination::TooManyExtensions))));
+        // refused from the length alone, without waiting for the rest
+        let too_large = 1025u32.to_be_bytes();
+        assert!(matches!(read_init_packet(&mut too_large.as_slice(), &cfg).await, Err(Some(Termination::InitTooLarge))));
+        assert!(matches!(read_init_packet(&mut &packet[..10], &cfg).await, Err(None)));
+    }
+
+    /// A client which trusts any host key
+    struct TrustingClient;
+
+    #[async_trait]
+    impl russh::client::Handler for TrustingClient {
+        type Error = russh::Error;
+
+        async fn check_server_key(&mut self, _key: &PublicKey) -> Result<bool, Self::Error> {
+            Ok(true)
+        }
+    }
+
+    /// Writes a fresh host key for `cfg` into a new directory, which is returned for removing
+    fn write_host_key(cfg: &mut config::SFTPServerOptions) -> std::path::PathBuf {
+        let key_dir = std::env::temp_dir().join(format!("bnuystore-test-{}", Uuid::now_v7()));
+        std::fs::create_dir(&key_dir).unwrap();
+        cfg.private_key = key_dir.join("host").display().to_string();
+        cfg.public_key = key_dir.join("host.pub").display().to_string();
+        let host_key = PrivateKey::random(&mut rand::rngs::OsRng, ssh_key::Algorithm::Ed25519).unwrap();
+        host_key.write_openssh_file(Path::new(&cfg.private_key), ssh_key::LineEnding::LF).unwrap();
+        host_key.public_key().write_openssh_file(Path::new(&cfg.public_key)).unwrap();
+        key_dir
+    }
+
+    /// Serves SFTP for `node` on a free local port, with a fresh host key
+    async fn serve(node: Arc<FrontNode>, cfg: &mut config::SFTPServerOptions) -> SocketAddr {
+        let key_dir = write_host_key(cfg);
+        let server = bind_sftp_server(cfg, node, &mut StartupSummary::default()).await.unwrap();
+        let addr = server.listener.local_addr().unwrap();
+        tokio::spawn(server.run(std::future::pending()));
+        std::fs::remove_dir_all(key_dir).unwrap();
+        addr
+    }
+
+    #[tokio::test]
+    async fn binding_is_summarized() {
+        let node = test_support::offline(&test_support::offline_config());
+
+        let mut summary = StartupSummary::default();
+        let mut cfg = sftp_options("");
+        assert!(bind_sftp_server(&cfg, node.clone(), &mut summary).await.is_none());
+        assert_eq!(summary.sftp_listen_addr.as_deref(), Some("127.0.0.1:0"));
+        let Some(Err(ref e)) = summary.sftp_keys_loaded else { panic!("{:?}", summary.sftp_keys_loaded) };
+        assert!(e.starts_with("could not read"), "{e}");
+        assert_eq!(summary.sftp_bound, None);
+        assert!(summary.hints().iter().any(|hint| hint.starts_with("SFTP host keys could not be read")), "{:#?}", summary.hints());
+
+        let key_dir = write_host_key(&mut cfg);
+        let taken = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        cfg.listen_addr = taken.local_addr().unwrap().to_string();
+        let mut summary = StartupSummary::default();
+        assert!(bind_sftp_server(&cfg, node.clone(), &mut summary).await.is_none());
+        assert_eq!(summary.sftp_keys_loaded, Some(Ok(())));
+        assert!(matches!(summary.sftp_bound, Some(Err(_))), "{:?}", summary.sftp_bound);
+
+        cfg.listen_addr = "bnuy:22".to_string();
+        let mut summary = StartupSummary::default();
+        assert!(bind_sftp_server(&cfg, node.clone(), &mut summary).await.is_none());
+        assert!(matches!(summary.sftp_bound, Some(Err(ref e)) if e.contains("IP:PORT")), "{:?}", summary.sftp_bound);
+
+        cfg.listen_addr = "127.0.0.1:0".to_string();
+        let mut summary = StartupSummary::default();
+        assert!(bind_sftp_server(&cfg, node, &mut summary).await.is_some());
+        assert_eq!((summary.sftp_keys_loaded, summary.sftp_bound), (Some(Ok(())), Some(Ok(()))));
+        std::fs::remove_dir_all(key_dir).unwrap();
+    }
+
+    // each stall must be cut off within its timeout, plus some slack
+    const REAPED_WITHIN: Duration = Duration::from_secs(4);
+
+    #[tokio::test]
+    async fn stalled_handshakes_are_reaped() {
+        use tokio::io::AsyncWriteExt;
+
+        let mut cfg = sftp_options("auth_timeout_s = 1");
+        let addr = serve(test_support::offline(&test_support::offline_config()), &mut cfg).await;
+
+        // silent from the start, and after the version exchange, i.e. during key exchange
+        for hello in ["", "SSH-2.0-stalling\r\n"] {
+            let auth_timeouts = terminations(Termination::AuthTimeout);
+            let mut socket = tokio::net::TcpStream::connect(addr).await.unwrap();
+            socket.write_all(hello.as_bytes()).await.unwrap();
+            let mut received = Vec::new();
+            let reaped = tokio::time::timeout(REAPED_WITHIN, socket.read_to_end(&mut received)).await;
+            assert!(reaped.is_ok(), "{hello:?}: connection still open");
+            assert_eq!(terminations(Termination::AuthTimeout), auth_timeouts + 1, "{hello:?}");
+        }
+    }
+
+    fn client_key(key: &PrivateKey) -> russh::keys::key::PrivateKeyWithHashAlg {
+        russh::keys::key::PrivateKeyWithHashAlg::new(Arc::new(key.clone()), None).unwrap()
+    }
+
+    #[tokio::test]
+    async fn keys_which_cant_be_checked_are_refused() {
+        let mut cfg = sftp_options("");
+        // the offline node's database is never there
+        let addr = serve(test_support::offline(&test_support::offline_config()), &mut cfg).await;
+        let key = PrivateKey::random(&mut rand::rngs::OsRng, ssh_key::Algorithm::Ed25519).unwrap();
+
+        let mut client = russh::client::connect(Default::default(), addr, TrustingClient).await.unwrap();
+        assert!(!client.authenticate_publickey("bnuy", client_key(&key)).await.unwrap());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MySQL database, see test_support::DATABASE_URL_VAR"]
+    async fn only_the_users_keys_are_accepted() {
+        let front = test_support::DatabaseFrontNode::start_with_faults(&test_support::database_config(), Default::default()).await;
+        let mut cfg = sftp_options("auth_timeout_s = 1\ninit_timeout_s = 2");
+        let addr = serve(front.node.clone(), &mut cfg).await;
+        let user = format!("bnuy-{}", Uuid::now_v7());
+        let key = PrivateKey::random(&mut rand::rngs::OsRng, ssh_key::Algorithm::Ed25519).unwrap();
+        let stranger = PrivateKey::random(&mut rand::rngs::OsRng, ssh_key::Algorithm::Ed25519).unwrap();
+        let pubkey = key.public_key().to_openssh().unwrap();
+        front.node.create_user(&user, Some(&pubkey), &format!("{}/home", front.path)).await.unwrap();
+
+        let mut client = russh::client::connect(Default::default(), addr, TrustingClient).await.unwrap();
+        assert!(!client.authenticate_publickey(&user, client_key(&stranger)).await.unwrap());
+        let mut client = russh::client::connect(Default::default(), addr, TrustingClient).await.unwrap();
+        assert!(!client.authenticate_publickey("nobody", client_key(&key)).await.unwrap());
+
+        // authenticated, but silent after asking for SFTP. being authenticated, it outlives the
+        // auth timeout
+        let (auth_timeouts, init_timeouts) = (terminations(Termination::AuthTimeout), terminations(Termination::InitTimeout));
+        let mut client = russh::client::connect(Default::default(), addr, TrustingClient).await.unwrap();
+        assert!(client.authenticate_publickey(&user, client_key(&key)).await.unwrap());
+        let mut channel = client.channel_open_session().await.unwrap();
+        channel.request_subsystem(true, "sftp").await.unwrap();
+        let started = Instant::now();
+        let closed = async { while channel.wait().await.is_some() {} };
+        assert!(tokio::time::timeout(REAPED_WITHIN, closed).await.is_ok(), "SFTP channel still open");
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert_eq!(terminations(Termination::InitTimeout), init_timeouts + 1);
+        assert_eq!(terminations(Termination::AuthTimeout), auth_timeouts);
     }
 }
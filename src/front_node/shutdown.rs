@@ -0,0 +1,168 @@
+#[allow(unused)]
+use tracing::{trace, debug, info, warn, error, instrument};
+
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+use super::FrontNode;
+
+/// How often the drain report is logged while shutting down
+const REPORT_INTERVAL: Duration = Duration::from_secs(2);
+
+/// What the front node is still waiting for while shutting down
+#[derive(Debug, serde::Serialize)]
+pub struct DrainReport {
+    pub deadline_remaining_s: f64,
+    /// by route class. routes without a concurrency limit aren't counted
+    pub http_in_flight: Vec<(String, usize)>,
+    /// by user
+    pub sftp_sessions: Vec<(String, usize)>,
+    pub sftp_open_handles: usize,
+    /// unanswered messages, by storage node ID
+    pub pending_messages: Vec<(i64, usize)>,
+    pub background_jobs: Vec<&'static str>,
+}
+
+impl DrainReport {
+    /// Background jobs run until the process exits, so they don't count
+    pub fn is_drained(&self) -> bool {
+        self.http_in_flight.is_empty() && self.sftp_sessions.is_empty() && self.pending_messages.is_empty()
+    }
+}
+
+/// Owns the shutdown deadline. Once SIGTERM or ctrl-c arrives, in-flight work has `grace` to
+/// finish, with a drain report logged every REPORT_INTERVAL until it has, and served on /health
+/// until the HTTP server stops. At the deadline the process exits
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    deadline: watch::Receiver<Option<Instant>>,
+}
+
+impl Shutdown {
+    pub fn start(node: Arc<FrontNode>, grace: Duration) -> std::io::Result<Self> {
+        let mut sigterm = signal(SignalKind::terminate())?;
+        let signalled = async move {
+            tokio::select! {
+                _ = sigterm.recv() => info!("Got SIGTERM"),
+                _ = tokio::signal::ctrl_c() => info!("Got ctrl-c"),
+            }
+        };
+        Ok(Shutdown::start_on(node, grace, signalled))
+    }
+
+    /// Like start, but shutdown begins once `trigger` resolves
+    pub fn start_on(node: Arc<FrontNode>, grace: Duration, trigger: impl Future<Output = ()> + Send + 'static) -> Self {
+        let (deadline_tx, deadline) = watch::channel(None);
+
+        tokio::spawn(async move {
+            trigger.await;
+            let deadline = Instant::now() + grace;
+            info!(grace_s = grace.as_secs(), "Shutting down; waiting for in-flight work");
+            *node.shutdown_deadline.lock().unwrap() = Some(deadline);
+            deadline_tx.send_replace(Some(deadline));
+            report_until(node, deadline).await;
+        });
+
+        Shutdown { deadline }
+    }
+
+    /// Resolves once shutdown has begun
+    pub async fn begun(mut self) {
+        // the sender lives until the process exits
+        let _ = self.deadline.wait_for(Option::is_some).await;
+    }
+
+    /// Resolves once shutdown has begun and no HTTP requests with a route class are in flight. Until
+    /// then, the HTTP server keeps answering, refusing new requests other than /health and /metrics
+    pub async fn http_drained(self, node: Arc<FrontNode>) {
+        self.begun().await;
+        while !node.limits.route_utilization().is_empty() {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    /// Waits until nothing is in flight. The process exits at the deadline if that takes too long
+    pub async fn drained(self, node: &FrontNode) {
+        let Some(deadline) = *self.deadline.borrow() else {
+            return;
+        };
+        while !node.drain_report(deadline.saturating_duration_since(Instant::now())).await.is_drained() {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+}
+
+#[instrument(level = "info", name = "shutdown", skip_all)]
+async fn report_until(node: Arc<FrontNode>, deadline: Instant) {
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let report = node.drain_report(remaining).await;
+        let report_json = serde_json::to_string(&report).expect("drain reports always serialize");
+        if remaining.is_zero() {
+            error!(report = report_json, "Shutdown deadline passed, exiting with work in flight");
+            std::process::exit(1);
+        }
+        if report.is_drained() {
+            info!(report = report_json, "Drained");
+            return;
+        }
+        info!(report = report_json, "Draining");
+        tokio::time::sleep(REPORT_INTERVAL.min(remaining)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::front_node::concurrency::RouteClass;
+    use crate::front_node::test_support;
+    use crate::front_node::tys::StorageNodeID;
+    use crate::message::Message;
+
+    #[tokio::test]
+    async fn drain_report_names_slow_requests() {
+        let mut cfg = test_support::offline_config();
+        cfg.concurrency_limits.reads = 4;
+        let node = test_support::offline(&cfg);
+        // a download still being sent, which holds its permit as limit_concurrency does
+        let download = node.limits.try_acquire_route(RouteClass::Reads).unwrap();
+        // and a storage node which never answers
+        let conn = test_support::mock_node(&[], |_| None).await;
+        test_support::attach_offline(&node, StorageNodeID(1), conn).await;
+        let conn = node.active_connections.read().await[&StorageNodeID(1)].clone();
+        let read = tokio::spawn(async move { conn.communicate(Message::GetVersion).await });
+        while node.drain_report(Duration::ZERO).await.pending_messages.is_empty() {
+            tokio::task::yield_now().await;
+        }
+
+        let grace = Duration::from_secs(60);
+        let (trigger, triggered) = tokio::sync::oneshot::channel();
+        let shutdown = Shutdown::start_on(node.clone(), grace, async { triggered.await.unwrap() });
+        trigger.send(()).unwrap();
+        shutdown.clone().begun().await;
+
+        let report = node.drain_report(grace).await;
+        assert_eq!(report.http_in_flight, [("reads".to_string(), 1)]);
+        assert_eq!(report.pending_messages, [(1, 1)]);
+        assert!(!report.is_drained());
+        let drained = tokio::spawn({
+            let node = node.clone();
+            async move { shutdown.drained(&node).await }
+        });
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(!drained.is_finished());
+
+        drop(download);
+        read.abort();
+        // as when the node monitor gives up on the node
+        node.active_connections.write().await.clear();
+        tokio::time::timeout(Duration::from_secs(1), drained).await
+            .expect("shutdown should finish once drained, well before the deadline")
+            .unwrap();
+    }
+}
@@ -0,0 +1,231 @@
+#[allow(unused)]
+use tracing::{trace, debug, info, warn, error, instrument};
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+/// Outcomes of the different startup steps, collected so they can be logged together once the front
+/// node is up. Each step fills in its part; `None` means the step did not run.
+#[derive(Debug, Default)]
+pub struct StartupSummary {
+    pub config_path: PathBuf,
+    pub database_target: String,
+    pub database_reachable: Option<bool>,
+    pub root_directory_present: Option<bool>,
+
+    pub http_listen_addr: String,
+    pub http_bound: Option<Result<(), String>>,
+
+    /// None if no SFTP server is configured
+    pub sftp_listen_addr: Option<String>,
+    pub sftp_keys_loaded: Option<Result<(), String>>,
+    pub sftp_bound: Option<Result<(), String>>,
+
+    pub storage_nodes: Vec<StorageNodeOutcome>,
+
+    /// optional features which are switched on, with a short description
+    pub features: Vec<String>,
+    /// problems with the config which don't stop startup
+    pub warnings: Vec<String>,
+    /// started with --dev-mode, see dev_mode.rs
+    pub dev_mode: bool,
+}
+
+#[derive(Debug)]
+pub struct StorageNodeOutcome {
+    pub name: String,
+    pub addr: String,
+    pub connected: Result<(), String>,
+}
+
+fn is_loopback(addr: &str) -> bool {
+    addr.parse::<SocketAddr>().is_ok_and(|addr| addr.ip().is_loopback())
+}
+
+impl StartupSummary {
+    /// Hints for commonly confused situations
+    pub fn hints(&self) -> Vec<String> {
+        let mut hints = Vec::new();
+
+        if let Some(Err(_)) = self.sftp_keys_loaded {
+            hints.push(
+                "SFTP host keys could not be read. Generate them with `ssh-keygen -t ed25519 -f keys/sftp_ed25519` \
+                and point sftp_server.private_key/public_key at them".to_string()
+            );
+        }
+        if self.storage_nodes.is_empty() {
+            hints.push("No storage nodes are configured. Add a [storage_nodes.<name>] section to the config".to_string());
+        } else if self.storage_nodes.iter().all(|node| node.connected.is_err()) {
+            hints.push("No storage node could be connected to. Are the storage nodes running, and reachable from here?".to_string());
+        }
+        if self.database_reachable == Some(false) {
+            hints.push(match self.dev_mode {
+                // the one thing dev mode doesn't bring along
+                true => format!(
+                    "Dev mode needs a MariaDB database at {}. Start MariaDB and create the database, or pass --config-file \
+                    with a [database_connection] section for another",
+                    self.database_target,
+                ),
+                false => format!("The database ({}) is not reachable. Is MariaDB running?", self.database_target),
+            });
+        }
+        if self.root_directory_present == Some(false) {
+            hints.push("The root_directory table is empty, and no root directory could be created. Have the migrations been applied?".to_string());
+        }
+        // neither HTTP nor SFTP currently authenticate users
+        if !is_loopback(&self.http_listen_addr) {
+            hints.push(format!(
+                "The HTTP API has no authentication, but is listening on the non-loopback address {}",
+                self.http_listen_addr,
+            ));
+        }
+        if let Some(ref sftp_listen_addr) = self.sftp_listen_addr {
+            if !is_loopback(sftp_listen_addr) {
+                hints.push(format!(
+                    "The SFTP server does not verify client keys, but is listening on the non-loopback address {sftp_listen_addr}",
+                ));
+            }
+        }
+
+        hints
+    }
+
+    pub fn log(&self) {
+        let connected: Vec<&str> = self.storage_nodes.iter()
+            .filter(|node| node.connected.is_ok())
+            .map(|node| node.name.as_str())
+            .collect();
+        let disconnected: Vec<(&str, &str, &str)> = self.storage_nodes.iter()
+            .filter_map(|node| {
+                node.connected.as_ref().err().map(|e| (node.name.as_str(), node.addr.as_str(), e.as_str()))
+            })
+            .collect();
+
+        info!(
+            config_path = %self.config_path.display(),
+            database = self.database_target,
+            database_reachable = ?self.database_reachable,
+            root_directory_present = ?self.root_directory_present,
+            http_listen_addr = self.http_listen_addr,
+            http_bound = ?self.http_bound,
+            sftp_listen_addr = ?self.sftp_listen_addr,
+            sftp_keys_loaded = ?self.sftp_keys_loaded,
+            sftp_bound = ?self.sftp_bound,
+            storage_nodes.configured = self.storage_nodes.len(),
+            storage_nodes.connected = connected.len(),
+            ?connected,
+            ?disconnected,
+            features = ?self.features,
+            "Startup summary",
+        );
+
+        for warning in &self.warnings {
+            warn!("Config warning: {warning}");
+        }
+        for hint in self.hints() {
+            info!("Hint: {hint}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{test_support, FrontNode};
+
+    fn node(name: &str, connected: Result<(), String>) -> StorageNodeOutcome {
+        StorageNodeOutcome { name: name.to_string(), addr: "127.0.0.1:2700".to_string(), connected }
+    }
+
+    fn healthy() -> StartupSummary {
+        StartupSummary {
+            database_target: "bnuystore at 127.0.0.1:3306 as bnuy".to_string(),
+            database_reachable: Some(true),
+            root_directory_present: Some(true),
+            http_listen_addr: "127.0.0.1:8080".to_string(),
+            http_bound: Some(Ok(())),
+            sftp_listen_addr: Some("[::1]:2222".to_string()),
+            sftp_keys_loaded: Some(Ok(())),
+            sftp_bound: Some(Ok(())),
+            storage_nodes: vec![node("a", Ok(())), node("b", Err("connection refused".to_string()))],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn healthy_startup_has_no_hints() {
+        assert_eq!(healthy().hints(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn confused_situations_are_hinted() {
+        let summary = StartupSummary {
+            database_reachable: Some(false),
+            root_directory_present: Some(false),
+            http_listen_addr: "0.0.0.0:8080".to_string(),
+            sftp_listen_addr: Some("192.0.2.1:2222".to_string()),
+            sftp_keys_loaded: Some(Err("No such file or directory".to_string())),
+            storage_nodes: vec![node("a", Err("connection refused".to_string()))],
+            ..healthy()
+        };
+        let hints = summary.hints();
+        let expected = [
+            "SFTP host keys could not be read",
+            "No storage node could be connected to",
+            "The database (bnuystore at 127.0.0.1:3306 as bnuy) is not reachable",
+            "The root_directory table is empty",
+            "non-loopback address 0.0.0.0:8080",
+            "non-loopback address 192.0.2.1:2222",
+        ];
+        assert_eq!(hints.len(), expected.len(), "{hints:#?}");
+        for (hint, expected) in hints.iter().zip(expected) {
+            assert!(hint.contains(expected), "{hint:?} should mention {expected:?}");
+        }
+
+        let summary = StartupSummary { storage_nodes: Vec::new(), ..healthy() };
+        assert_eq!(summary.hints().len(), 1);
+        assert!(summary.hints()[0].starts_with("No storage nodes are configured"));
+    }
+
+    #[test]
+    fn dev_mode_says_where_the_database_should_be() {
+        let summary = StartupSummary { database_reachable: Some(false), dev_mode: true, ..healthy() };
+        let hints = summary.hints();
+        assert_eq!(hints.len(), 1, "{hints:#?}");
+        assert!(hints[0].starts_with("Dev mode needs a MariaDB database at bnuystore at 127.0.0.1:3306 as bnuy."), "{hints:#?}");
+    }
+
+    #[tokio::test]
+    async fn unreachable_database_is_summarized() {
+        let mut cfg = test_support::offline_config();
+        cfg.database_connection.startup_attempts = 1;
+        let mut summary = StartupSummary::default();
+        assert!(FrontNode::start_from_config(&cfg, &mut summary).await.is_err());
+
+        assert_eq!(summary.database_target, "bnuystore_test at 127.0.0.1:9 as nobody");
+        assert_eq!(summary.database_reachable, Some(false));
+        assert_eq!(summary.root_directory_present, None);
+        assert!(summary.hints().iter().any(|hint| hint.contains("is not reachable")), "{:#?}", summary.hints());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MySQL database, see test_support::DATABASE_URL_VAR"]
+    async fn startup_outcomes_are_summarized() {
+        let mut cfg = test_support::database_config();
+        // nothing listens on the discard port
+        cfg.storage_nodes = toml::from_str(r#"gone = { addr = "127.0.0.1:9", timeout_s = 1 }"#).unwrap();
+        cfg.http_server.debug_errors = true;
+        cfg.uploads.verify = true;
+        let mut summary = StartupSummary::default();
+        let front_node = FrontNode::start_from_config(&cfg, &mut summary).await.unwrap();
+
+        assert_eq!((summary.database_reachable, summary.root_directory_present), (Some(true), Some(true)));
+        assert_eq!(summary.storage_nodes.len(), 1);
+        assert_eq!((summary.storage_nodes[0].name.as_str(), summary.storage_nodes[0].addr.as_str()), ("gone", "127.0.0.1:9"));
+        assert!(summary.storage_nodes[0].connected.is_err());
+        assert!(summary.features.contains(&"upload verification".to_string()), "{:?}", summary.features);
+        assert!(summary.warnings.iter().any(|warning| warning.contains("debug_errors")), "{:?}", summary.warnings);
+        assert!(summary.hints().iter().any(|hint| hint.contains("No storage node could be connected to")));
+        front_node.disconnect().await;
+    }
+}
@@ -3,123 +3,300 @@ use tracing::{trace, debug, info, warn, error, instrument, span, Instrument, Lev
 
 use std::collections::HashMap;
 use std::io::{Error, ErrorKind};
-use std::net::ToSocketAddrs;
-use std::sync::Arc;
+use std::sync::{Arc, Weak};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
 
-use tokio::net::{tcp, TcpSocket};
-use tokio::sync::{Mutex, Notify, oneshot};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpSocket;
+use tokio::sync::{Mutex, Notify, Semaphore, oneshot};
+use uuid::Uuid;
 
-use crate::message::{Message, MessageID, ParseMessageError, parse_message, write_message};
+use crate::message::{self, Message, MessageID, ParseMessageError, ErrorCode, StorageInfo, Compression, ProtocolVersions, AuthToken, WireFormat, MessageLimits, parse_message, write_message_as};
+use crate::fault_injection::{FaultInjector, ConnectionFault};
+use crate::hashing::ContentDigest;
+use crate::owned_task::OwnedTask;
+use crate::tls;
 use super::config::StorageNodeConfig;
+use super::metrics::Histogram;
+use super::tys;
 
-/// A connection to a storage node
+/// A stream to a storage node
 /// An "inner" connection is not thread-safe, but must be wrapped in a Mutex to use
 struct StorageNodeConnectionInner {
-    stream: tcp::OwnedWriteHalf,
+    stream: Box<dyn AsyncWrite + Send + Unpin>,
     next_message_id: MessageID,
 
     /// If the channel dies, all senders are dropped
     waiting_responses: HashMap<MessageID, oneshot::Sender<Message>>,
-    // todo: auth token
 
     /// In case any communication error occurs, we want any attempt to `communicate`
     /// with this connection to fail. This bool is "sticky", it cannot be unset
     is_disconnected: bool,
+
+    /// when the latest response arrived, or when the stream was opened
+    last_heard: Instant,
 }
 
-/// Only locks the mutex while a message is being sent
-pub struct StorageNodeConnection {
+/// One of the streams of a connection, with its own requests in flight. Only locks the mutex while
+/// a message is being sent
+struct NodeStream {
     inner: Arc<Mutex<StorageNodeConnectionInner>>,
     #[allow(unused)]
-    pub disconnect: Arc<Notify>,
+    disconnect: Arc<Notify>,
+    /// a permit per request in flight. Closed once disconnected, so waiting requests fail
+    in_flight: Arc<Semaphore>,
+    /// reads responses. Dropped with the stream, which closes its read half
+    _recv_task: OwnedTask,
+    /// pings the node while the stream is idle, see heartbeat. None until started
+    heartbeat: std::sync::Mutex<Option<OwnedTask>>,
+    /// when the latest Pong arrived, and how long after its Ping
+    last_pong: std::sync::Mutex<Option<(Instant, Duration)>>,
 }
 
+/// One or more streams to a storage node, see StorageNodeConfig::connections. Each request goes to
+/// the stream with the fewest requests in flight, so a large file being sent on one doesn't hold
+/// up the others. The node keeps chunked writes per stream, so they stay on the stream they
+/// started on. Lost streams are skipped until replace_lost_streams makes new ones, and the
+/// connection as a whole is only lost once all of them are
+pub struct StorageNodeConnection {
+    streams: std::sync::RwLock<Vec<Arc<NodeStream>>>,
+    /// chunked writes in progress, by the stream they were started on
+    chunked_writes: std::sync::Mutex<HashMap<Uuid, Arc<NodeStream>>>,
+    faults: FaultInjector,
+    /// Protocol features agreed on in hello. Empty until then, and for nodes which don't know Hello
+    features: std::sync::RwLock<Vec<String>>,
+    /// Protocol version agreed on in hello. 1 until then, and for nodes which don't know Hello
+    protocol_version: AtomicU32,
+    /// for data sent both ways, if the node agrees to zstd in hello
+    compression: Option<Compression>,
+    /// how long communicate waits for a response
+    request_timeout: Duration,
+    /// requests in flight at once on each stream
+    max_in_flight: usize,
+    /// how long a stream is idle before the node is pinged, zero to never ping. See heartbeat
+    heartbeat_interval: Duration,
+    /// pings in a row the node can leave unanswered before the stream is lost
+    heartbeat_max_missed: u32,
+    /// time until each answered request was answered, for GET /metrics
+    pub latency: Histogram,
+}
+
+/// Request timeout for connections not made from a StorageNodeConfig, e.g. to an in-process node
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Likewise for the number of requests in flight
+const DEFAULT_MAX_IN_FLIGHT: usize = 16;
+
+/// Protocol features the front node asks for in Hello
+const WANTED_FEATURES: &[&str] = &[
+    message::FEATURE_WRITE_ACK,
+    message::FEATURE_ERROR_CODES,
+    message::FEATURE_READ_RANGE,
+    message::FEATURE_CHUNKED_WRITE,
+    message::FEATURE_CHECKSUMS,
+    message::FEATURE_PING,
+];
+
 /// If an error occurs, the calling code should unconditionally abort
 /// An long-living task
 #[derive(Debug, Clone, Copy)]
 pub enum ConnectionError {
     ClientDisconnected,
+    /// the node didn't respond within the request timeout. the connection may still be usable
+    Timeout,
 }
 
-impl StorageNodeConnection {
-    #[instrument(level = "debug")]
-    pub async fn connect(cfg: &StorageNodeConfig) -> Result<Self, Error> {
-        let socket = TcpSocket::new_v4()?;
-        socket.set_keepalive(true)?;
+/// Resolving the address and connecting share the timeout. Each address the host resolves to is
+/// tried in turn, so a hostname with both IPv6 and IPv4 addresses works if either is reachable.
+/// The TLS handshake, if cfg.tls is set, gets the same timeout again
+async fn open_stream(cfg: &StorageNodeConfig, max_in_flight: usize) -> Result<NodeStream, Error> {
+    let (host, port) = cfg.host_and_port().map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    let timeout_duration = std::time::Duration::from_secs(cfg.timeout_s);
 
-        let addr = cfg.addr
-            .to_socket_addrs()?
-            .next()
-            .ok_or(Error::new(ErrorKind::AddrNotAvailable, format!("Address {} did not resolve", cfg.addr)))?;
+    let connecting = async {
+        let mut last_error = Error::new(ErrorKind::AddrNotAvailable, format!("Address {} did not resolve", cfg.addr));
+        for addr in tokio::net::lookup_host((host, port)).await? {
+            let socket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+            socket.set_keepalive(true)?;
+            match socket.connect(addr).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => {
+                    debug!(%addr, ?e, "Could not connect to address");
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    };
+    let stream = match tokio::time::timeout(timeout_duration, connecting).await {
+        Ok(x) => x?,
+        Err(_) => {
+            return Err(Error::new(ErrorKind::ConnectionAborted, format!("Connection timed out after {} seconds", cfg.timeout_s)));
+        }
+    };
 
-        let timeout_duration = std::time::Duration::from_secs(cfg.timeout_s);
+    trace!("Established TCP stream");
 
-        let stream = match tokio::time::timeout(timeout_duration, socket.connect(addr)).await {
-            Ok(x) => x?,
-            Err(_) => {
-                return Err(Error::new(ErrorKind::ConnectionAborted, format!("Connection timed out after {} seconds", cfg.timeout_s)));
+    if !cfg.tls {
+        return Ok(NodeStream::new(stream, max_in_flight, cfg.message_limits()));
+    }
+    let ca_path = cfg.tls_ca_file.as_ref().ok_or_else(|| Error::new(ErrorKind::InvalidInput, "tls needs tls_ca_file"))?;
+    let server_name = tls::server_name(cfg.tls_server_name.as_deref().unwrap_or(host))?;
+    let handshake = tls::connector(ca_path)?.connect(server_name, stream);
+    let stream = match tokio::time::timeout(timeout_duration, handshake).await {
+        Ok(x) => x.map_err(|e| Error::new(e.kind(), format!("TLS handshake failed: {e}")))?,
+        Err(_) => {
+            return Err(Error::new(ErrorKind::TimedOut, format!("TLS handshake timed out after {} seconds", cfg.timeout_s)));
+        }
+    };
+    trace!("Established TLS session");
+    Ok(NodeStream::new(stream, max_in_flight, cfg.message_limits()))
+}
+
+/// Fails the requests in flight on a stream, and those waiting to be sent, and marks it as lost
+async fn lose_stream(inner: &Mutex<StorageNodeConnectionInner>, disconnect: &Notify, in_flight: &Semaphore) {
+    disconnect.notify_waiters();
+    in_flight.close();
+
+    let mut inner = inner.lock().await;
+    inner.is_disconnected = true;
+    for (_id, sender) in inner.waiting_responses.drain() {
+        std::mem::drop(sender);
+    }
+}
+
+/// Pings the node each `interval` the stream has been idle, with nothing in flight and nothing
+/// heard. A node whose host crashed or was cut off never closes the stream, so after `max_missed`
+/// pings in a row go unanswered the stream is lost. Busy streams aren't pinged, as the node answers
+/// in order and a large request would hold the pong up; their requests time out on their own
+async fn heartbeat(stream: Weak<NodeStream>, format: WireFormat, interval: Duration, max_missed: u32) {
+    let mut missed = 0;
+    loop {
+        tokio::time::sleep(interval).await;
+        let Some(stream) = stream.upgrade() else {
+            break;
+        };
+        if stream.is_disconnected() {
+            break;
+        }
+        {
+            let inner = stream.inner.lock().await;
+            if !inner.waiting_responses.is_empty() || inner.last_heard.elapsed() < interval {
+                continue;
             }
+        }
+
+        let sent = Instant::now();
+        let Ok((id, listener)) = stream.send(Message::Ping, &format).await else {
+            // the receiving task finds out too
+            break;
         };
+        match tokio::time::timeout(interval, listener).await {
+            Ok(Ok(Message::Pong)) => {
+                let latency = sent.elapsed();
+                trace!(?latency, "Got pong");
+                *stream.last_pong.lock().unwrap_or_else(|e| e.into_inner()) = Some((Instant::now(), latency));
+                missed = 0;
+            }
+            Ok(Ok(x)) => warn!(%x, "Unexpected response to ping"),
+            Ok(Err(_recverror)) => break,
+            Err(_) => {
+                stream.inner.lock().await.waiting_responses.remove(&id);
+                missed += 1;
+                warn!(missed, "No pong from storage node");
+                if missed >= max_missed {
+                    error!(missed, "Storage node stopped answering pings. Killing stream");
+                    stream.lose().await;
+                    break;
+                }
+            }
+        }
+    }
+}
 
-        let (mut read, write) = stream.into_split();
-        trace!("Established TCP stream");
+impl NodeStream {
+    /// Responses over `limits` are skipped, and fail the request they answer
+    fn new<S: AsyncRead + AsyncWrite + Send + 'static>(stream: S, max_in_flight: usize, limits: MessageLimits) -> Self {
+        let (mut read, write) = tokio::io::split(stream);
 
         let inner = StorageNodeConnectionInner {
-            stream: write,
+            stream: Box::new(write),
             next_message_id: MessageID(0),
             waiting_responses: HashMap::new(),
             is_disconnected: false,
+            last_heard: Instant::now(),
         };
         let inner = Arc::new(Mutex::new(inner));
         let disconnect = Arc::new(Notify::new());
+        let in_flight = Arc::new(Semaphore::new(max_in_flight));
 
         trace!("Spawning receiving task");
         let recv_span = span!(Level::DEBUG, "recv");
-        // TODO: do we wanna store the task somewhere?
-        // It could outlive the connection which is not great
-        let _recv_task = tokio::spawn({
+        let recv_task = OwnedTask::spawn({
             let inner = inner.clone();
             let disconnect = disconnect.clone();
+            let in_flight = in_flight.clone();
 
             async move {
                 loop {
-                    match parse_message(&mut read).await {
+                    match parse_message(&mut read, &limits).await {
+                        Ok((id, msg)) if id == message::UNSOLICITED_ID => {
+                            // nothing subscribes to these yet
+                            debug!(%msg, "Got unsolicited message. Ignoring");
+                        }
                         Ok((id, msg)) => {
                             debug!(?id, %msg, "Got response");
                             let mut inner = inner.lock().await;
+                            inner.last_heard = Instant::now();
                             let Some(sender) = inner.waiting_responses.remove(&id) else {
                                 debug!(?id, %msg, "Got response to non-existant request {id:?}. Ignoring");
                                 continue;
                             };
                             std::mem::drop(inner);
-                            if let Err(_) = sender.send(msg.clone()) {
+                            if sender.send(msg.clone()).is_err() {
                                 error!(?id, %msg, "Got response to request that does exist, but no one's waiting for it. Ignoring");
                             }
                         }
                         Err(e) => {
-                            error!("Parsing message failed:");
                             match e {
-                                ParseMessageError::IOError(e) => {
-                                    error!("IO Error: {e:?}");
+                                // the message was read in full, so the stream is still in sync.
+                                // The request fails right away, rather than timing out
+                                e @ (ParseMessageError::ParseJsonError { id, .. }
+                                    | ParseMessageError::ParseUuidError { id, .. }
+                                    | ParseMessageError::DecompressError { id, .. }
+                                    | ParseMessageError::ParseBinaryError { id, .. }) => {
+                                    error!(?id, ?e, "Invalid response received, skipping it");
+                                    let sender = inner.lock().await.waiting_responses.remove(&id);
+                                    if let Some(sender) = sender {
+                                        let _ = sender.send(Message::Error(format!("could not decode response: {e:?}")));
+                                    }
+                                    continue;
                                 }
-                                ParseMessageError::ParseJsonError(e) => {
-                                    error!("Invalid JSON received: {e:?}");
+                                // only the preamble was read. The request fails right away,
+                                // rather than timing out
+                                ParseMessageError::MessageTooLarge { id, unread } => {
+                                    warn!(?id, unread, ?limits, "Response is over the size limits. Skipping it");
+                                    match message::skip_message(&mut read, unread).await {
+                                        Ok(()) => {
+                                            let sender = inner.lock().await.waiting_responses.remove(&id);
+                                            if let Some(sender) = sender {
+                                                let detail = format!("response of {unread} bytes is over the limits, {limits:?}");
+                                                let _ = sender.send(Message::Error(detail));
+                                            }
+                                            continue;
+                                        }
+                                        Err(e) => error!("Parsing message failed: IO Error: {e:?}"),
+                                    }
                                 }
-                                ParseMessageError::ParseUuidError(e) => {
-                                    error!("Invalid UUID received: {e:?}");
+                                ParseMessageError::IOError(e) => {
+                                    error!("Parsing message failed: IO Error: {e:?}");
                                 }
                                 ParseMessageError::RequestTooLarge(n) => {
-                                    error!("Tried to allocate {} MiB", n>>20);
+                                    error!("Parsing message failed: Tried to allocate {} MiB", n>>20);
                                 }
                             }
                             error!("Killing connection.");
-                            disconnect.notify_waiters();
-
-                            let mut inner = inner.lock().await;
-                            inner.is_disconnected = true;
-                            for (_id, sender) in inner.waiting_responses.drain() {
-                                std::mem::drop(sender);
-                            }
+                            lose_stream(&inner, &disconnect, &in_flight).await;
                             break;
                         }
                     }
@@ -127,50 +304,614 @@ impl StorageNodeConnection {
             }
         }.instrument(recv_span));
 
-        Ok(StorageNodeConnection {
+        NodeStream {
             inner,
             disconnect,
-        })
+            in_flight,
+            _recv_task: recv_task,
+            heartbeat: std::sync::Mutex::new(None),
+            last_pong: std::sync::Mutex::new(None),
+        }
+    }
+
+    fn is_disconnected(&self) -> bool {
+        self.in_flight.is_closed()
+    }
+
+    fn requests_in_flight(&self, max_in_flight: usize) -> usize {
+        max_in_flight.saturating_sub(self.in_flight.available_permits())
+    }
+
+    /// Sends a message, returning its ID and where the response will arrive
+    async fn send(&self, message: Message, format: &WireFormat) -> Result<(MessageID, oneshot::Receiver<Message>), ConnectionError> {
+        let mut inner = self.inner.lock().await;
+        trace!("Generating ID for message");
+        let id = {
+            let id = inner.next_message_id;
+
+            while {
+                inner.next_message_id.0 = inner.next_message_id.0.wrapping_add(1);
+                inner.next_message_id == message::UNSOLICITED_ID
+                    || inner.waiting_responses.contains_key(&inner.next_message_id)
+            } {}
+
+            id
+        };
+        trace!(?id, "Generated ID");
+
+        let (sender, listener) = oneshot::channel();
+        inner.waiting_responses.insert(id, sender);
+
+        debug!(?id, "Sending message");
+        write_message_as(&mut inner.stream, id, message, format)
+            .await
+            .map_err(|_| ConnectionError::ClientDisconnected)?;
+        Ok((id, listener))
+    }
+
+    async fn lose(&self) {
+        lose_stream(&self.inner, &self.disconnect, &self.in_flight).await;
+    }
+
+    /// Like lose, for when it can't be awaited. If a message is being sent, the requests are
+    /// failed from a spawned task once it has been
+    fn lose_now(self: Arc<Self>) {
+        self.disconnect.notify_waiters();
+        self.in_flight.close();
+        let lost = match self.inner.try_lock() {
+            Ok(mut inner) => {
+                inner.is_disconnected = true;
+                inner.waiting_responses.clear();
+                true
+            }
+            Err(_) => false,
+        };
+        if !lost {
+            tokio::spawn(async move { self.lose().await });
+        }
+    }
+}
+
+impl Drop for StorageNodeConnection {
+    /// Requests in flight each hold on to their stream, so it would outlive the connection, and
+    /// they'd keep waiting for their response
+    fn drop(&mut self) {
+        let streams = std::mem::take(self.streams.get_mut().unwrap_or_else(|e| e.into_inner()));
+        for stream in streams {
+            stream.lose_now();
+        }
+    }
+}
+
+impl StorageNodeConnection {
+    fn new(streams: Vec<NodeStream>, max_in_flight: usize, faults: FaultInjector) -> Self {
+        StorageNodeConnection {
+            streams: std::sync::RwLock::new(streams.into_iter().map(Arc::new).collect()),
+            chunked_writes: std::sync::Mutex::new(HashMap::new()),
+            faults,
+            features: std::sync::RwLock::new(Vec::new()),
+            protocol_version: AtomicU32::new(1),
+            compression: None,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            max_in_flight,
+            heartbeat_interval: Duration::ZERO,
+            heartbeat_max_missed: 0,
+            latency: Histogram::default(),
+        }
+    }
+
+    fn streams(&self) -> Vec<Arc<NodeStream>> {
+        self.streams.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Number of messages sent which haven't been answered yet
+    pub async fn pending_messages(&self) -> usize {
+        let mut pending = 0;
+        for stream in self.streams() {
+            pending += stream.inner.lock().await.waiting_responses.len();
+        }
+        pending
+    }
+
+    /// Number of requests holding a permit to be in flight. Requests waiting for one aren't counted
+    pub fn requests_in_flight(&self) -> usize {
+        self.streams().iter().map(|stream| stream.requests_in_flight(self.max_in_flight)).sum()
+    }
+
+    /// Whether the connection was lost, i.e. all of its streams. Lost connections stay lost; a new
+    /// one has to be made
+    pub fn is_disconnected(&self) -> bool {
+        self.streams().iter().all(|stream| stream.is_disconnected())
+    }
+
+    /// Number of streams which were lost and not replaced yet
+    pub fn lost_streams(&self) -> usize {
+        self.streams().iter().filter(|stream| stream.is_disconnected()).count()
+    }
+
+    #[instrument(level = "debug", skip(cfg, faults), fields(addr = %cfg.addr))]
+    pub async fn connect(cfg: &StorageNodeConfig, faults: FaultInjector) -> Result<Self, Error> {
+        let timeout_duration = std::time::Duration::from_secs(cfg.timeout_s);
+
+        let mut streams = Vec::new();
+        for _ in 0..cfg.connections {
+            streams.push(open_stream(cfg, cfg.max_in_flight).await?);
+        }
+        let mut conn = Self::new(streams, cfg.max_in_flight, faults);
+        conn.request_timeout = Duration::from_secs(cfg.request_timeout_s);
+        conn.heartbeat_interval = Duration::from_secs(cfg.heartbeat_interval_s);
+        conn.heartbeat_max_missed = cfg.heartbeat_max_missed;
+        conn.compression = cfg.compression.as_ref().map(|options| Compression { level: options.level, min_bytes: options.min_bytes });
+        if let Some(token) = cfg.auth_token().await? {
+            conn.authenticate(token, timeout_duration).await?;
+        }
+        conn.hello(timeout_duration).await?;
+        for stream in conn.streams() {
+            conn.start_heartbeat(&stream);
+        }
+        Ok(conn)
+    }
+
+    /// Makes new streams in place of the lost ones, for a connection made with `cfg`. The new
+    /// streams have to agree on the same features as the rest, as requests may go to any of them
+    #[instrument(level = "debug", skip(self, cfg), fields(addr = %cfg.addr))]
+    pub async fn replace_lost_streams(&self, cfg: &StorageNodeConfig) -> Result<(), Error> {
+        let timeout_duration = std::time::Duration::from_secs(cfg.timeout_s);
+        for _ in 0..self.lost_streams() {
+            let stream = open_stream(cfg, self.max_in_flight).await?;
+            if let Some(token) = cfg.auth_token().await? {
+                self.authenticate_stream(&stream, token, timeout_duration).await?;
+            }
+            let (version, features) = self.hello_stream(&stream, timeout_duration).await?;
+            if features != *self.features.read().unwrap_or_else(|e| e.into_inner()) {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    format!("a new stream agreed on different features, {features:?}. The node may have been upgraded"),
+                ));
+            }
+            if version != self.protocol_version.load(Ordering::Relaxed) {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    format!("a new stream agreed on a different protocol version, {version}. The node may have been upgraded"),
+                ));
+            }
+
+            let stream = Arc::new(stream);
+            self.start_heartbeat(&stream);
+            let mut streams = self.streams.write().unwrap_or_else(|e| e.into_inner());
+            if let Some(lost) = streams.iter_mut().find(|stream| stream.is_disconnected()) {
+                *lost = stream;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets up a connection over an already established stream. The stream does not need to be TCP,
+    /// e.g. an in-process storage node can be connected to over a tokio::io::duplex
+    #[cfg_attr(not(any(feature = "dev-mode", test)), allow(unused))]
+    pub fn from_stream<S: AsyncRead + AsyncWrite + Send + 'static>(stream: S) -> Self {
+        Self::from_stream_with_faults(stream, FaultInjector::default())
+    }
+
+    #[cfg_attr(not(any(feature = "dev-mode", test)), allow(unused))]
+    pub fn from_stream_with_faults<S: AsyncRead + AsyncWrite + Send + 'static>(stream: S, faults: FaultInjector) -> Self {
+        Self::new(vec![NodeStream::new(stream, DEFAULT_MAX_IN_FLIGHT, MessageLimits::default())], DEFAULT_MAX_IN_FLIGHT, faults)
+    }
+
+    /// Sends the node's auth token on each stream, which has to come before any other request
+    pub async fn authenticate(&self, token: AuthToken, timeout: Duration) -> Result<(), Error> {
+        for stream in self.streams() {
+            self.authenticate_stream(&stream, token.clone(), timeout).await?;
+        }
+        Ok(())
+    }
+
+    /// Nothing else is in flight on the stream yet, so the answer is the only response waited for.
+    /// Fails with ErrorKind::PermissionDenied if the node refuses the token, after which it closes
+    /// the stream
+    #[instrument(level = "debug", skip_all)]
+    async fn authenticate_stream(&self, stream: &NodeStream, token: AuthToken, timeout: Duration) -> Result<(), Error> {
+        let (id, listener) = self.send(stream, Message::Authenticate(token)).await
+            .map_err(|e| Error::new(ErrorKind::ConnectionAborted, format!("Could not send auth token: {e:?}")))?;
+        match tokio::time::timeout(timeout, listener).await {
+            Ok(Ok(Message::Ack)) => {
+                debug!("Authenticated");
+                Ok(())
+            }
+            Ok(Ok(Message::Error(detail) | Message::ErrorCode { detail, .. })) => {
+                Err(Error::new(ErrorKind::PermissionDenied, format!("storage node refused the auth token: {detail}")))
+            }
+            Ok(Ok(x)) => Err(Error::new(ErrorKind::InvalidData, format!("unexpected response to Authenticate: {x}"))),
+            Ok(Err(_recverror)) => {
+                Err(Error::new(ErrorKind::ConnectionAborted, "storage node disconnected during authentication"))
+            }
+            Err(_) => {
+                stream.inner.lock().await.waiting_responses.remove(&id);
+                Err(Error::new(
+                    ErrorKind::TimedOut,
+                    "no response to Authenticate; the storage node may predate auth tokens",
+                ))
+            }
+        }
+    }
+
+    /// Agrees on a protocol version and features with the node, on each stream. Features are only
+    /// used if all streams agreed on them, and the version is the oldest any stream agreed on
+    pub async fn hello(&self, timeout: Duration) -> Result<(), Error> {
+        let mut agreed: Option<(u32, Vec<String>)> = None;
+        for stream in self.streams() {
+            let (version, features) = self.hello_stream(&stream, timeout).await?;
+            agreed = Some(match agreed {
+                Some((agreed_version, agreed)) => (
+                    agreed_version.min(version),
+                    agreed.into_iter().filter(|feature| features.contains(feature)).collect(),
+                ),
+                None => (version, features),
+            });
+        }
+        let (version, features) = agreed.unwrap_or((1, Vec::new()));
+        debug!(version, ?features, "Agreed on protocol version and features");
+        self.protocol_version.store(version, Ordering::Relaxed);
+        *self.features.write().unwrap_or_else(|e| e.into_inner()) = features;
+        Ok(())
+    }
+
+    /// The protocol version and features agreed on for one stream. Fails with ErrorKind::Unsupported if the node speaks
+    /// no protocol version this front node does. Nodes which predate Hello don't answer it, so
+    /// after `timeout` the stream carries on with protocol version 1 and no features
+    #[instrument(level = "debug", skip(self, stream))]
+    async fn hello_stream(&self, stream: &NodeStream, timeout: Duration) -> Result<(u32, Vec<String>), Error> {
+        let mut features: Vec<String> = WANTED_FEATURES.iter().map(|feature| feature.to_string()).collect();
+        if self.compression.is_some() {
+            features.push(message::FEATURE_ZSTD.to_string());
+        }
+        let hello = Message::Hello { protocol: ProtocolVersions::OURS, features, compression: self.compression };
+        let (id, listener) = match self.send(stream, hello).await {
+            Ok(x) => x,
+            Err(e) => {
+                warn!(?e, "Could not send hello");
+                return Ok((1, Vec::new()));
+            }
+        };
+        let agreed = match tokio::time::timeout(timeout, listener).await {
+            Ok(Ok(Message::HelloBack { protocol, features })) => {
+                let Some(version) = ProtocolVersions::OURS.negotiate(&protocol) else {
+                    return Err(Error::new(
+                        ErrorKind::Unsupported,
+                        format!(
+                            "storage node speaks protocol versions {protocol}, but this front node speaks {}",
+                            ProtocolVersions::OURS,
+                        ),
+                    ));
+                };
+                debug!(version, "Agreed on protocol version");
+                (version, features)
+            }
+            Ok(Ok(x)) => {
+                warn!(%x, "Unexpected response to hello");
+                (1, Vec::new())
+            }
+            Ok(Err(_recverror)) => {
+                warn!("Disconnected during hello");
+                (1, Vec::new())
+            }
+            Err(_) => {
+                info!("No response to hello, node predates it");
+                // otherwise the request would look like it's in flight forever
+                stream.inner.lock().await.waiting_responses.remove(&id);
+                (1, Vec::new())
+            }
+        };
+        Ok(agreed)
+    }
+
+    /// Starts pinging the node while the stream is idle, if it agreed to FEATURE_PING in hello
+    fn start_heartbeat(&self, stream: &Arc<NodeStream>) {
+        if self.heartbeat_interval.is_zero() || !self.has_feature(message::FEATURE_PING) {
+            return;
+        }
+        let heartbeat_span = span!(Level::DEBUG, "heartbeat");
+        let task = OwnedTask::spawn(
+            heartbeat(Arc::downgrade(stream), self.wire_format(), self.heartbeat_interval, self.heartbeat_max_missed)
+                .instrument(heartbeat_span),
+        );
+        *stream.heartbeat.lock().unwrap_or_else(|e| e.into_inner()) = Some(task);
+    }
+
+    /// Round trip time of the latest ping answered on any of the streams. None until one was, and
+    /// for nodes which aren't pinged
+    pub fn ping_latency(&self) -> Option<Duration> {
+        self.streams().iter()
+            .filter_map(|stream| *stream.last_pong.lock().unwrap_or_else(|e| e.into_inner()))
+            .max_by_key(|(at, _)| *at)
+            .map(|(_, latency)| latency)
+    }
+
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features.read().unwrap_or_else(|e| e.into_inner()).iter().any(|f| f == feature)
+    }
+
+    /// The stream to send a message on. Chunked writes stay on the stream they were started on,
+    /// and everything else goes to the live stream with the fewest requests in flight
+    fn stream_for(&self, message: &Message) -> Result<Arc<NodeStream>, ConnectionError> {
+        let pinned = match message {
+            Message::WriteFileChunk(uuid, _) => {
+                self.chunked_writes.lock().unwrap_or_else(|e| e.into_inner()).get(uuid).cloned()
+            }
+            // these end the write
+            Message::WriteFileEnd(uuid) | Message::WriteFileEndChecked(uuid, _) | Message::WriteFileAbort(uuid) => {
+                self.chunked_writes.lock().unwrap_or_else(|e| e.into_inner()).remove(uuid)
+            }
+            _ => None,
+        };
+        if let Some(stream) = pinned {
+            return Ok(stream);
+        }
+
+        let streams = self.streams();
+        let stream = streams.into_iter()
+            .filter(|stream| !stream.is_disconnected())
+            .min_by_key(|stream| stream.requests_in_flight(self.max_in_flight))
+            .ok_or(ConnectionError::ClientDisconnected)?;
+
+        if let Message::WriteFileStart(uuid) = message {
+            let mut chunked_writes = self.chunked_writes.lock().unwrap_or_else(|e| e.into_inner());
+            // the node dropped the writes of lost streams along with them
+            chunked_writes.retain(|_, stream| !stream.is_disconnected());
+            chunked_writes.insert(*uuid, stream.clone());
+        }
+        Ok(stream)
     }
 
-    // TODO: Register a timeout task
     #[instrument(level = "debug", skip(self))]
     pub async fn communicate(
         &self,
         message: Message,
     ) -> Result<Message, ConnectionError> {
-        let listener = {
-            let mut inner = self.inner.lock().await;
-            trace!("Generating ID for message");
-            let id = {
-                let id = inner.next_message_id;
-
-                while {
-                    inner.next_message_id.0 = inner.next_message_id.0.wrapping_add(1);
-                    inner.waiting_responses.contains_key(&inner.next_message_id)
-                } {}
-
-                id
-            };
-            trace!(?id, "Generated ID");
-
-            let (sender, listener) = oneshot::channel();
-            inner.waiting_responses.insert(id, sender);
+        let fault = self.faults.next_connection_fault();
+        match fault {
+            Some(ConnectionFault::Disconnect) => return Err(ConnectionError::ClientDisconnected),
+            Some(ConnectionFault::Delay(duration)) => tokio::time::sleep(duration).await,
+            _ => {}
+        }
 
-            debug!(?id, "Sending message");
-            write_message(&mut inner.stream, id, message)
-                .await
-                .map_err(|_| ConnectionError::ClientDisconnected)?;
-            listener
-        };
+        let stream = self.stream_for(&message)?;
+        // held until the response arrives or the request is given up on. requests over the limit wait
+        // here, before taking the stream's lock
+        let _permit = stream.in_flight.acquire().await.map_err(|_| {
+            debug!("Connection closed while waiting to send");
+            ConnectionError::ClientDisconnected
+        })?;
+        let started = std::time::Instant::now();
+        let (id, listener) = self.send(&stream, message).await?;
 
         trace!("Waiting for response");
-        match listener.await {
-            Ok(m) => Ok(m),
-            Err(_recverror) => {
+        let response = match tokio::time::timeout(self.request_timeout, listener).await {
+            Ok(Ok(m)) => {
+                self.latency.observe(started.elapsed());
+                m
+            }
+            Ok(Err(_recverror)) => {
                 error!("Client disconnected");
                 return Err(ConnectionError::ClientDisconnected);
             }
+            Err(_) => {
+                warn!(?id, timeout = ?self.request_timeout, "Request timed out");
+                // a late response is ignored by the receiving task
+                stream.inner.lock().await.waiting_responses.remove(&id);
+                return Err(ConnectionError::Timeout);
+            }
+        };
+        match (fault, response) {
+            // as if the response never arrived
+            (Some(ConnectionFault::DropResponse), _) => {
+                tokio::time::sleep(self.request_timeout).await;
+                Err(ConnectionError::Timeout)
+            }
+            (Some(ConnectionFault::CorruptPayload), Message::FileContents(mut data)) => {
+                data.iter_mut().for_each(|byte| *byte = !*byte);
+                Ok(Message::FileContents(data))
+            }
+            (Some(ConnectionFault::CorruptPayload), Message::CheckedFileContents { digest, mut data }) => {
+                data.iter_mut().for_each(|byte| *byte = !*byte);
+                Ok(Message::CheckedFileContents { digest, data })
+            }
+            (Some(ConnectionFault::CorruptPayload), Message::FileRange { file_size, mut data }) => {
+                data.iter_mut().for_each(|byte| *byte = !*byte);
+                Ok(Message::FileRange { file_size, data })
+            }
+            (_, m) => Ok(m),
         }
     }
+
+    /// Like communicate, but error responses are returned as errors. A missing file is UnknownUUID
+    pub async fn request(&self, message: Message) -> Result<Message, tys::Error> {
+        match self.communicate(message).await? {
+            Message::ErrorCode { code: ErrorCode::NotFound, .. } => Err(tys::Error::UnknownUUID),
+            Message::ErrorCode { code, detail } => Err(tys::Error::StorageNode { code, detail }),
+            // nodes without error codes can't tell what went wrong
+            Message::Error(detail) => Err(tys::Error::StorageNode { code: ErrorCode::Internal, detail }),
+            response => Ok(response),
+        }
+    }
+
+    /// Reads a whole file. Nodes with checksums send the digest the file was written with, which the
+    /// contents are checked against, so that corruption on disk or on the way is an error
+    #[instrument(level = "debug", skip(self))]
+    pub async fn read_file(&self, uuid: uuid::Uuid) -> Result<Vec<u8>, tys::Error> {
+        match self.request(Message::ReadFile(uuid)).await? {
+            Message::FileContents(data) | Message::CheckedFileContents { digest: None, data } => Ok(data),
+            Message::CheckedFileContents { digest: Some(expected), data } => {
+                let actual = ContentDigest::of(expected.algorithm, &data);
+                if actual != expected {
+                    error!(%uuid, %expected, %actual, "File read from storage node does not match its digest");
+                    return Err(tys::Error::DigestMismatch { uuid, expected, actual });
+                }
+                Ok(data)
+            }
+            x => Err(tys::Error::UnexpectedResponse(x)),
+        }
+    }
+
+    /// Asks the node for its storage space and file count
+    #[instrument(level = "debug", skip(self))]
+    pub async fn storage_info(&self) -> Result<StorageInfo, tys::Error> {
+        match self.request(Message::StorageInfo).await? {
+            Message::StorageInfoIs(info) => Ok(info),
+            x => Err(tys::Error::UnexpectedResponse(x)),
+        }
+    }
+
+    /// Sends a message on a stream, in the format agreed on in hello
+    async fn send(&self, stream: &NodeStream, message: Message) -> Result<(MessageID, oneshot::Receiver<Message>), ConnectionError> {
+        stream.send(message, &self.wire_format()).await
+    }
+
+    fn wire_format(&self) -> WireFormat {
+        WireFormat {
+            compression: self.compression.filter(|_| self.has_feature(message::FEATURE_ZSTD)),
+            binary_headers: WireFormat::binary_headers_for(self.protocol_version.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "testing")]
+    use super::super::test_support::TestStorageNode;
+
+    /// A node which agrees to `features` in hello, and then never answers again
+    async fn silent_node(features: &[&str]) -> StorageNodeConnection {
+        let (ours, mut theirs) = tokio::io::duplex(1 << 16);
+        let features: Vec<String> = features.iter().map(|feature| feature.to_string()).collect();
+        tokio::spawn(async move {
+            let limits = MessageLimits::default();
+            let Ok((id, Message::Hello { .. })) = parse_message(&mut theirs, &limits).await else {
+                return;
+            };
+            let hello_back = Message::HelloBack { protocol: ProtocolVersions::OURS, features };
+            write_message_as(&mut theirs, id, hello_back, &WireFormat::default()).await.unwrap();
+            // read, so that sending doesn't block
+            while parse_message(&mut theirs, &limits).await.is_ok() {}
+        });
+        let conn = StorageNodeConnection::from_stream(ours);
+        conn.hello(Duration::from_secs(5)).await.unwrap();
+        conn
+    }
+
+    #[tokio::test]
+    async fn unanswered_request_times_out() {
+        let mut conn = silent_node(&[]).await;
+        conn.request_timeout = Duration::from_millis(50);
+
+        assert!(matches!(conn.communicate(Message::StorageInfo).await, Err(ConnectionError::Timeout)));
+        // given up on, but the node may just be slow, so the connection is kept
+        assert_eq!(conn.pending_messages().await, 0);
+        assert_eq!(conn.requests_in_flight(), 0);
+        assert!(!conn.is_disconnected());
+    }
+
+    #[tokio::test]
+    async fn node_which_stops_answering_pings_is_disconnected() {
+        let mut conn = silent_node(&[message::FEATURE_PING]).await;
+        conn.heartbeat_interval = Duration::from_millis(20);
+        conn.heartbeat_max_missed = 2;
+        for stream in conn.streams() {
+            conn.start_heartbeat(&stream);
+        }
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !conn.is_disconnected() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.expect("the stream wasn't lost");
+        assert!(matches!(conn.communicate(Message::StorageInfo).await, Err(ConnectionError::ClientDisconnected)));
+    }
+
+    #[tokio::test]
+    async fn connects_to_nodes_over_tls() {
+        let certificates = tls::tests::Certificates::write();
+        let data_folder = std::env::temp_dir().join(format!("bnuystore-test-{}", Uuid::now_v7()));
+        let node = crate::storage_node::Node::new(data_folder.clone(), crate::storage_node::DEFAULT_LOCK_WARN_AFTER, None).await.unwrap();
+
+        let acceptor = tls::acceptor(&certificates.node_cert, &certificates.node_key).unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                if let Ok(stream) = acceptor.accept(stream).await {
+                    tokio::spawn(crate::storage_node::serve_connection(node.clone(), stream, MessageLimits::default()));
+                }
+            }
+        });
+
+        let config = |ca: &std::path::Path| -> StorageNodeConfig {
+            let mut cfg: StorageNodeConfig = toml::from_str(&format!("addr = \"{addr}\"")).unwrap();
+            cfg.tls = true;
+            cfg.tls_ca_file = Some(ca.to_path_buf());
+            cfg.tls_server_name = Some("localhost".to_string());
+            cfg
+        };
+
+        let conn = StorageNodeConnection::connect(&config(&certificates.ca), FaultInjector::default()).await.unwrap();
+        let uuid = Uuid::now_v7();
+        let reply = conn.request(Message::WriteFile(uuid, b"over tls".to_vec())).await.unwrap();
+        assert!(matches!(reply, Message::WriteAck { bytes_written: 8, .. }), "{reply}");
+        assert_eq!(conn.read_file(uuid).await.unwrap(), b"over tls");
+
+        let e = StorageNodeConnection::connect(&config(&certificates.other_ca), FaultInjector::default()).await.err().unwrap();
+        assert!(e.to_string().starts_with("TLS handshake failed"), "{e}");
+
+        std::fs::remove_dir_all(data_folder).unwrap();
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn dropped_response_times_out() {
+        let storage = TestStorageNode::start().await;
+        let faults = FaultInjector::new();
+        let mut conn = storage.connect(faults.clone()).await;
+        conn.request_timeout = Duration::from_millis(50);
+
+        faults.inject_connection_fault(ConnectionFault::DropResponse);
+        assert!(matches!(conn.communicate(Message::StorageInfo).await, Err(ConnectionError::Timeout)));
+        assert!(conn.storage_info().await.is_ok());
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn injected_disconnect_fails_without_sending() {
+        let storage = TestStorageNode::start().await;
+        let faults = FaultInjector::new();
+        let conn = storage.connect(faults.clone()).await;
+
+        faults.inject_connection_fault(ConnectionFault::Disconnect);
+        let uuid = Uuid::now_v7();
+        let write = conn.communicate(Message::WriteFile(uuid, b"bnuy".to_vec())).await;
+        assert!(matches!(write, Err(ConnectionError::ClientDisconnected)));
+        assert!(!storage.has_file(uuid));
+        assert!(matches!(conn.read_file(uuid).await, Err(tys::Error::UnknownUUID)));
+    }
+
+    #[tokio::test]
+    async fn dropping_the_connection_wakes_waiters() {
+        // a node which never answers
+        let (ours, _theirs) = tokio::io::duplex(1 << 16);
+        let conn = StorageNodeConnection::from_stream(ours);
+
+        // as communicate does, the request holds on to its stream
+        let stream = conn.streams().remove(0);
+        let (_id, listener) = conn.send(&stream, Message::StorageInfo).await.unwrap();
+        assert_eq!(conn.pending_messages().await, 1);
+
+        drop(conn);
+        let response = tokio::time::timeout(Duration::from_secs(5), listener).await
+            .expect("waiter wasn't woken");
+        assert!(response.is_err());
+        assert!(stream.is_disconnected());
+        assert!(stream.in_flight.acquire().await.is_err());
+        assert!(stream.inner.lock().await.is_disconnected);
+    }
 }
@@ -0,0 +1,309 @@
+//! Uploads and downloads which never hold a whole file in memory.
+//!
+//! Uploads are sent on to their storage node as they arrive, with WriteFileStart, a WriteFileChunk
+//! per CHUNK_BYTES and WriteFileEnd. Downloads are read with a ReadFileRange per CHUNK_BYTES. Storage
+//! nodes without these features are sent and read whole files, as before.
+
+#[allow(unused)]
+use tracing::{trace, debug, info, warn, error, instrument};
+
+use futures::{Stream, StreamExt};
+use uuid::Uuid;
+
+use std::pin::Pin;
+
+use super::{FrontNode, GetFileInfo, UploadFileInfo, UploadOptions, content_type};
+use super::storage_node_connection::StorageNodeConnection;
+use super::tys::{DirectoryID, Error};
+use super::upload_policy::UploadPolicy;
+use super::upload_progress::UploadProgressHandle;
+use crate::hashing::Hasher;
+use crate::message::{self, Message};
+
+/// How much of a file is sent to or read from a storage node at a time
+pub const CHUNK_BYTES: usize = 1024 * 1024;
+
+/// The contents of a file, a chunk at a time. If reading fails, the error is the last item
+pub type FileStream = Pin<Box<dyn Stream<Item = Result<Vec<u8>, Error>> + Send>>;
+
+impl FrontNode {
+    /// Like upload_file, but the contents are sent on to the storage node as they are received.
+    /// `expected_size` (e.g. a Content-Length) is used to place the file, while the policy's size
+    /// limit is checked against what is actually received. Only starting the write can fail over,
+    /// as the contents can't be received again
+    #[instrument(level = "info", skip(self, body))]
+    pub async fn upload_stream<S, B, E>(
+        &self,
+        filename: String,
+        dir: DirectoryID,
+        expected_size: Option<u64>,
+        body: S,
+        options: UploadOptions<'_>,
+    ) -> Result<Uuid, Error>
+    where
+        S: Stream<Item = Result<B, E>>,
+        B: AsRef<[u8]>,
+        E: std::fmt::Debug,
+    {
+        let content_type = options.content_type;
+        let policy = self.upload_policy(dir).await?;
+        check_policy(policy.as_deref(), &filename, expected_size, content_type)?;
+        self.check_name_free(&filename, dir, options.overwrite).await?;
+
+        let uuid = Uuid::now_v7();
+        let info = UploadFileInfo {
+            data_length: expected_size.unwrap_or(0) as usize,
+            content_type: content_type.and_then(content_type::from_upload),
+            overwrite: options.overwrite,
+        };
+        let placement = self.placement_for(&info).await?;
+        let (conn, storage_node_id) = self.with_node_failover(placement, |_, conn| async move {
+            if conn.has_feature(message::FEATURE_CHUNKED_WRITE) {
+                match conn.request(Message::WriteFileStart(uuid)).await? {
+                    Message::Ack => {}
+                    x => return Err(Error::UnexpectedResponse(x)),
+                }
+            }
+            Ok(conn)
+        }).await?;
+
+        let mut body = std::pin::pin!(body);
+
+        if !conn.has_feature(message::FEATURE_CHUNKED_WRITE) {
+            // upload_file places the file again, which is fine as it has to be buffered anyway
+            debug!(?storage_node_id, "Storage node can't write in chunks; receiving the whole file first");
+            let mut contents = Vec::new();
+            while let Some(received) = body.next().await {
+                let received = received.map_err(|e| {
+                    debug!(?e, "Error receiving body");
+                    Error::UploadInterrupted
+                })?;
+                contents.extend_from_slice(received.as_ref());
+            }
+            return self.upload_file(filename, dir, contents, options).await;
+        }
+
+        let sent = async {
+            let mut hasher = Hasher::new(self.hash_algorithm);
+            let mut size = 0;
+            let mut chunk = Vec::with_capacity(CHUNK_BYTES);
+            while let Some(received) = body.next().await {
+                let received = received.map_err(|e| {
+                    debug!(?e, "Error receiving body");
+                    Error::UploadInterrupted
+                })?;
+                let mut received = received.as_ref();
+                hasher.update(received);
+                size += received.len() as u64;
+
+                while !received.is_empty() {
+                    let n = (CHUNK_BYTES - chunk.len()).min(received.len());
+                    chunk.extend_from_slice(&received[..n]);
+                    received = &received[n..];
+                    if chunk.len() == CHUNK_BYTES {
+                        check_policy(policy.as_deref(), &filename, Some(size), content_type)?;
+                        let full = std::mem::replace(&mut chunk, Vec::with_capacity(CHUNK_BYTES));
+                        send_chunk(&conn, uuid, full, options.progress).await?;
+                    }
+                }
+            }
+            check_policy(policy.as_deref(), &filename, Some(size), content_type)?;
+            // the size the upload was let in with was only what the client claimed
+            let growth = match options.user {
+                Some(user) => self.check_quota(user, &filename, dir, options.overwrite, size).await?,
+                None => 0,
+            };
+            if !chunk.is_empty() {
+                send_chunk(&conn, uuid, chunk, options.progress).await?;
+            }
+            Ok((size, hasher.finalize(), growth))
+        }.await;
+
+        let (size, digest, growth) = match sent {
+            Ok(x) => x,
+            Err(e) => {
+                // the node also throws the partial file away if the connection is lost
+                match conn.request(Message::WriteFileAbort(uuid)).await {
+                    Ok(_) => trace!(%uuid, "Aborted chunked write"),
+                    Err(abort_error) => warn!(?abort_error, %uuid, "Could not abort chunked write"),
+                }
+                return Err(e);
+            }
+        };
+        debug!(size, "Sent file in chunks");
+
+        let end = match conn.has_feature(message::FEATURE_CHECKSUMS) {
+            true => Message::WriteFileEndChecked(uuid, digest.clone()),
+            false => Message::WriteFileEnd(uuid),
+        };
+        let response = conn.request(end).await?;
+        self.check_write_ack(&conn, uuid, size, response)?;
+        if options.verify {
+            self.verify_written(&conn, uuid, size, &digest).await?;
+        }
+
+        let info = UploadFileInfo { data_length: size as usize, ..info };
+        self.record_upload(uuid, filename, dir, storage_node_id, info, digest).await?;
+        if let Some(user) = options.user {
+            self.add_usage(user, growth);
+        }
+        Ok(uuid)
+    }
+
+    /// At most `len` bytes of a file from `offset`, read from its node a chunk at a time. The first
+    /// chunk is read before returning, so that a missing file is an error here rather than in the
+    /// stream. Starting at or past the end of a non-empty file is Error::ReadPastEnd
+    #[instrument(level = "debug", skip(self))]
+    pub async fn stream_file(
+        &self,
+        uuid: Uuid,
+        offset: u64,
+        len: Option<u64>,
+    ) -> Result<(FileStream, GetFileInfo), Error> {
+        let location = self.file_location(uuid).await?;
+        let blob = location.blob;
+
+        let first_len = len.unwrap_or(u64::MAX).min(CHUNK_BYTES as u64) as u32;
+        let ((conn, first, file_size), _) = self.with_node_failover(vec![location.node], |_, conn| async move {
+            // nodes without ranges send the whole file, so the first chunk is all there is
+            if !conn.has_feature(message::FEATURE_READ_RANGE) {
+                let mut data = conn.read_file(blob).await?;
+                let file_size = data.len() as u64;
+                let end = len.map_or(file_size, |len| file_size.min(offset.saturating_add(len)));
+                data.truncate(end as usize);
+                let data = data.split_off(offset.min(end) as usize);
+                return Ok((conn, data, file_size));
+            }
+            match conn.request(Message::ReadFileRange(blob, offset, first_len)).await? {
+                Message::FileRange { file_size, data } => Ok((conn, data, file_size)),
+                x => Err(Error::UnexpectedResponse(x)),
+            }
+        }).await?;
+
+        if offset >= file_size && offset > 0 {
+            return Err(Error::ReadPastEnd { file_size });
+        }
+        let end = len.map_or(file_size, |len| file_size.min(offset.saturating_add(len)));
+
+        let next = offset + first.len() as u64;
+        let rest = futures::stream::unfold(next, move |offset| {
+            let conn = conn.clone();
+            async move {
+                if offset >= end {
+                    return None;
+                }
+                let len = (end - offset).min(CHUNK_BYTES as u64) as u32;
+                match conn.request(Message::ReadFileRange(blob, offset, len)).await {
+                    Ok(Message::FileRange { data, .. }) if !data.is_empty() => {
+                        let next = offset + data.len() as u64;
+                        Some((Ok(data), next))
+                    }
+                    // the file got shorter since the first chunk was read
+                    Ok(Message::FileRange { file_size, .. }) => Some((Err(Error::ReadPastEnd { file_size }), end)),
+                    Ok(x) => Some((Err(Error::UnexpectedResponse(x)), end)),
+                    Err(e) => Some((Err(e), end)),
+                }
+            }
+        });
+        let first = (!first.is_empty()).then_some(Ok(first));
+        let stream = futures::stream::iter(first).chain(rest);
+
+        self.accessed_files.lock().unwrap().insert(uuid);
+        let info = GetFileInfo {
+            uuid,
+            node_name: location.node_name,
+            file_size,
+            content_type: location.content_type,
+            sha256: location.sha256,
+        };
+        Ok((Box::pin(stream), info))
+    }
+}
+
+fn check_policy(
+    policy: Option<&UploadPolicy>,
+    filename: &str,
+    size: Option<u64>,
+    content_type: Option<&str>,
+) -> Result<(), Error> {
+    if let Some(Err(rule)) = policy.map(|policy| policy.check(filename, size, content_type)) {
+        info!(rule, "Upload refused by policy");
+        return Err(Error::PolicyViolation { rule });
+    }
+    Ok(())
+}
+
+async fn send_chunk(
+    conn: &StorageNodeConnection,
+    uuid: Uuid,
+    chunk: Vec<u8>,
+    progress: Option<&UploadProgressHandle>,
+) -> Result<(), Error> {
+    trace!(%uuid, chunk.len = chunk.len(), "Sending chunk");
+    let len = chunk.len();
+    match conn.request(Message::WriteFileChunk(uuid, chunk)).await? {
+        Message::Ack => {}
+        x => return Err(Error::UnexpectedResponse(x)),
+    }
+    if let Some(progress) = progress {
+        progress.forwarded(len).await;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::test_support::{self, DatabaseFrontNode};
+    use crate::fault_injection::FaultInjector;
+
+    const FILE_BYTES: usize = 64 * CHUNK_BYTES;
+    /// The size of each piece of the body, as a client would send it
+    const PIECE_BYTES: usize = 64 * 1024;
+
+    /// The byte at `position` in the generated file
+    fn pattern(position: usize) -> u8 {
+        (position / PIECE_BYTES) as u8
+    }
+
+    /// FILE_BYTES of pattern, generated as it's read
+    fn generated_body() -> impl Stream<Item = Result<Vec<u8>, std::io::Error>> {
+        futures::stream::iter((0..FILE_BYTES / PIECE_BYTES).map(|i| Ok(vec![pattern(i * PIECE_BYTES); PIECE_BYTES])))
+    }
+
+    #[test]
+    #[ignore = "needs a MySQL database, see test_support::DATABASE_URL_VAR"]
+    fn large_transfers_take_bounded_memory() {
+        // on one thread, so that the storage node and the connection's tasks are measured too
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let cfg = test_support::database_config();
+        let front = runtime.block_on(DatabaseFrontNode::start_with_faults(&cfg, FaultInjector::default()));
+        let bound = 16 * CHUNK_BYTES;
+
+        let (uploaded, peak) = test_support::peak_allocation(|| runtime.block_on(front.node.upload_stream(
+            "large".to_string(),
+            front.dir,
+            Some(FILE_BYTES as u64),
+            generated_body(),
+            UploadOptions::default(),
+        )));
+        let uuid = uploaded.unwrap();
+        assert!(peak < bound, "uploading {FILE_BYTES} bytes had {peak} allocated at once");
+
+        let (received, peak) = test_support::peak_allocation(|| runtime.block_on(async {
+            let (mut stream, info) = front.node.stream_file(uuid, 0, None).await.unwrap();
+            assert_eq!(info.file_size, FILE_BYTES as u64);
+            let mut received = 0;
+            while let Some(chunk) = stream.next().await {
+                let chunk = chunk.unwrap();
+                assert!(chunk.iter().enumerate().all(|(i, &byte)| byte == pattern(received + i)), "wrong contents at {received}");
+                received += chunk.len();
+            }
+            received
+        }));
+        assert_eq!(received, FILE_BYTES);
+        assert!(peak < bound, "downloading {FILE_BYTES} bytes had {peak} allocated at once");
+
+        runtime.block_on(front.node.remove_file(uuid, true)).unwrap();
+    }
+}
@@ -0,0 +1,524 @@
+//! Moving files between storage tiers, if tiering is configured.
+//!
+//! New files go to the fastest tier with room, see placement_for. Every tiering.interval_s, files
+//! which haven't been read for tiering.cold_after_s are moved one tier slower, at most
+//! tiering.bytes_per_s at a time. When a file was last used is the later of its upload and its
+//! last_accessed, which is only updated in batches, see flush_accessed_files. Files aren't moved
+//! back when read again.
+//!
+//! A directory can be pinned to a tier in the tier_pins table, which keeps the files in it and
+//! everything below it on that tier, until a subdirectory has a pin of its own. Pinned files are
+//! moved to their tier whenever they aren't on it, however recently they were read.
+//!
+//! When several front nodes share the database, only the leader moves files, see leader.rs. Files
+//! whose contents are shared with other files (see deduplication.rs) aren't moved.
+
+#[allow(unused)]
+use tracing::{trace, debug, info, warn, error, instrument, Instrument};
+
+use mysql_async::prelude::*;
+use uuid::Uuid;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use super::{config, FrontNode, StorageSpace, UploadFileInfo};
+use super::leader::Leadership;
+use super::rehash::stored_digest;
+use super::tys::{DirectoryID, StorageNodeID, Error};
+use crate::hashing::ContentDigest;
+
+/// Files are looked through this many at a time
+const TIERING_BATCH_SIZE: u32 = 100;
+
+/// Directories with a pin, and every directory below them with the tier of the nearest pin above it
+const PINNED_DIRECTORIES: &str = r#"
+    WITH RECURSIVE pinned (id, tier) AS (
+        SELECT directory_id, tier FROM tier_pins
+        UNION ALL
+        SELECT directories.id, pinned.tier
+            FROM directories JOIN pinned ON directories.parent_id = pinned.id
+            WHERE directories.id NOT IN (SELECT directory_id FROM tier_pins)
+    )
+"#;
+
+/// uuid, stored_on_node_id, unix time of last_accessed, pinned tier, hash_algorithm, digest
+type CandidateRow = (Uuid, StorageNodeID, Option<u64>, Option<u32>, Option<String>, Option<Vec<u8>>);
+
+/// A file the tiering pass may move
+#[derive(Debug, Clone)]
+struct Candidate {
+    uuid: Uuid,
+    node: StorageNodeID,
+    /// the later of when the file was uploaded and when it was last read
+    last_used: SystemTime,
+    /// of the nearest pinned directory above the file
+    pinned_tier: Option<u32>,
+    digest: Option<ContentDigest>,
+}
+
+impl Candidate {
+    fn from_row((uuid, node, last_accessed, pinned_tier, algorithm, digest): CandidateRow) -> Candidate {
+        let uploaded = uuid.get_timestamp().map(|timestamp| {
+            let (seconds, nanos) = timestamp.to_unix();
+            SystemTime::UNIX_EPOCH + Duration::new(seconds, nanos)
+        });
+        let accessed = last_accessed.map(|seconds| SystemTime::UNIX_EPOCH + Duration::from_secs(seconds));
+        Candidate {
+            uuid,
+            node,
+            last_used: uploaded.max(accessed).unwrap_or(SystemTime::UNIX_EPOCH),
+            pinned_tier,
+            digest: stored_digest(algorithm, digest),
+        }
+    }
+}
+
+/// The tier a file on `current_tier` should be moved to at `now`, None if it stays. `tiers` are the
+/// tiers with a connected node. A pin to a tier without one is ignored
+fn migration_target(
+    current_tier: u32,
+    last_used: SystemTime,
+    pinned_tier: Option<u32>,
+    tiers: &BTreeSet<u32>,
+    now: SystemTime,
+    cold_after: Duration,
+) -> Option<u32> {
+    if let Some(pinned_tier) = pinned_tier.filter(|pinned_tier| tiers.contains(pinned_tier)) {
+        return (pinned_tier != current_tier).then_some(pinned_tier);
+    }
+    let unused_for = now.duration_since(last_used).unwrap_or_default();
+    if unused_for < cold_after {
+        return None;
+    }
+    tiers.range(current_tier + 1..).next().copied()
+}
+
+#[derive(Debug, Default, Clone, Copy, serde::Serialize)]
+pub struct TieringSummary {
+    pub migrated: u64,
+    pub bytes_migrated: u64,
+    /// files which couldn't be moved, e.g. because no node in their new tier has room. they are
+    /// tried again in the next pass
+    pub failed: u64,
+}
+
+/// What is stored on a tier, for GET /admin/tiers
+#[derive(Debug, PartialEq, Eq, serde::Serialize)]
+pub struct TierUsage {
+    pub tier: u32,
+    /// names of the storage nodes in the tier, connected or not
+    pub nodes: Vec<String>,
+    /// summed over the connected nodes which reported their space
+    pub bytes_total: u64,
+    pub bytes_available: u64,
+    /// files whose contents are stored on the tier, including those in the trash and old versions.
+    /// files sharing contents are counted once
+    pub files: u64,
+    pub bytes_stored: u64,
+}
+
+// one entry per tier with a node, fastest first. `stored` is the file count and bytes by node
+fn tier_usage(
+    node_tiers: &HashMap<StorageNodeID, u32>,
+    node_names: &HashMap<StorageNodeID, String>,
+    spaces: &HashMap<StorageNodeID, StorageSpace>,
+    stored: &HashMap<StorageNodeID, (u64, u64)>,
+) -> Vec<TierUsage> {
+    let tier_of = |id: &StorageNodeID| node_tiers.get(id).copied().unwrap_or(0);
+    let mut tiers: BTreeMap<u32, TierUsage> = BTreeMap::new();
+    for (id, name) in node_names {
+        let tier = tier_of(id);
+        let usage = tiers.entry(tier).or_insert_with(|| TierUsage {
+            tier,
+            nodes: Vec::new(),
+            bytes_total: 0,
+            bytes_available: 0,
+            files: 0,
+            bytes_stored: 0,
+        });
+        usage.nodes.push(name.clone());
+        if let Some(space) = spaces.get(id) {
+            usage.bytes_total += space.bytes_total;
+            usage.bytes_available += space.bytes_available;
+        }
+        if let Some((files, bytes)) = stored.get(id) {
+            usage.files += files;
+            usage.bytes_stored += bytes;
+        }
+    }
+    tiers.into_values()
+        .map(|mut usage| {
+            usage.nodes.sort();
+            usage
+        })
+        .collect()
+}
+
+impl FrontNode {
+    fn tier_of(&self, node: StorageNodeID) -> u32 {
+        self.node_tiers.read().unwrap().get(&node).copied().unwrap_or(0)
+    }
+
+    /// Tiers with at least one connected node
+    async fn connected_tiers(&self) -> BTreeSet<u32> {
+        let connected: Vec<StorageNodeID> = self.active_connections.read().await.keys().copied().collect();
+        connected.into_iter().map(|id| self.tier_of(id)).collect()
+    }
+
+    /// What each tier stores and has room for
+    #[instrument(level = "debug", skip(self))]
+    pub async fn tier_usage(&self) -> Result<Vec<TierUsage>, Error> {
+        let query = r#"
+            SELECT stored_on_node_id, COUNT(*), CAST(COALESCE(SUM(size), 0) AS UNSIGNED) FROM files
+                WHERE blob_uuid IS NULL
+                GROUP BY stored_on_node_id;
+        "#;
+        let rows: Vec<(StorageNodeID, u64, u64)> = query.fetch(self.pool()?).await?;
+        let stored = rows.into_iter().map(|(id, files, bytes)| (id, (files, bytes))).collect();
+        let spaces = self.node_spaces().await.unwrap_or_default();
+        let node_tiers = self.node_tiers.read().unwrap().clone();
+        let node_names = self.node_names.read().unwrap().clone();
+        Ok(tier_usage(&node_tiers, &node_names, &spaces, &stored))
+    }
+
+    /// Pins the directory to a tier, or unpins it with None. Pinning to a tier no connected node is
+    /// in is Error::NoNodeWithSpace
+    #[instrument(level = "info", skip(self))]
+    pub async fn pin_to_tier(&self, dir: DirectoryID, tier: Option<u32>) -> Result<(), Error> {
+        let Some(tier) = tier else {
+            "DELETE FROM tier_pins WHERE directory_id = :dir;".with(params! { "dir" => dir }).ignore(self.pool()?).await?;
+            return Ok(());
+        };
+        if !self.connected_tiers().await.contains(&tier) {
+            return Err(Error::NoNodeWithSpace);
+        }
+        "REPLACE INTO tier_pins(directory_id, tier) VALUES (:dir, :tier);"
+            .with(params! { "dir" => dir, "tier" => tier })
+            .ignore(self.pool()?)
+            .await?;
+        Ok(())
+    }
+
+    /// Moves the files which should be on another tier at `now`, see migration_target. Moves are
+    /// paced to about `options.bytes_per_s`
+    #[instrument(level = "info", skip(self, options))]
+    pub async fn migrate_between_tiers(&self, now: SystemTime, options: &config::TieringOptions) -> Result<TieringSummary, Error> {
+        let mut summary = TieringSummary::default();
+        let tiers = self.connected_tiers().await;
+        if tiers.len() < 2 {
+            trace!(?tiers, "Fewer than two tiers connected; nothing to move between");
+            return Ok(summary);
+        }
+        let cold_after = Duration::from_secs(options.cold_after_s);
+        // files are walked in UUID order, so files which fail aren't picked up again
+        let mut after = Uuid::nil();
+
+        loop {
+            let query = format!(r#"
+                {PINNED_DIRECTORIES}
+                SELECT files.uuid, files.stored_on_node_id, CAST(UNIX_TIMESTAMP(files.last_accessed) AS UNSIGNED),
+                       pinned.tier, files.hash_algorithm, files.digest
+                    FROM files LEFT JOIN pinned ON pinned.id = files.directory_id
+                    WHERE files.uuid > :after AND files.blob_uuid IS NULL
+                        AND NOT EXISTS (SELECT * FROM files sharing WHERE sharing.blob_uuid = files.uuid)
+                    ORDER BY files.uuid
+                    LIMIT :batch_size;
+            "#);
+            let batch: Vec<CandidateRow> = query.with(params! {
+                "after" => after,
+                "batch_size" => TIERING_BATCH_SIZE,
+            }).fetch(self.pool()?).await?;
+            let Some(&(last, ..)) = batch.last() else {
+                break;
+            };
+            after = last;
+
+            for candidate in batch.into_iter().map(Candidate::from_row) {
+                let current_tier = self.tier_of(candidate.node);
+                let Some(tier) = migration_target(current_tier, candidate.last_used, candidate.pinned_tier, &tiers, now, cold_after) else {
+                    continue;
+                };
+                match self.migrate_file(&candidate, tier).await {
+                    Ok(Some(size)) => {
+                        debug!(uuid = %candidate.uuid, from = current_tier, to = tier, size, "Moved file to another tier");
+                        summary.migrated += 1;
+                        summary.bytes_migrated += size;
+                        // read once and written once, so twice the size counts against the budget
+                        let pause = 2.0 * size as f64 / options.bytes_per_s.max(1) as f64;
+                        tokio::time::sleep(Duration::from_secs_f64(pause)).await;
+                    }
+                    Ok(None) => trace!(uuid = %candidate.uuid, "File changed while being moved; leaving it"),
+                    Err(e) => {
+                        warn!(?e, uuid = %candidate.uuid, tier, "Could not move file to another tier");
+                        summary.failed += 1;
+                    }
+                }
+            }
+        }
+
+        info!(?summary, "Tiering pass done");
+        Ok(summary)
+    }
+
+    // copies the file to a node in `tier`, points its row there, and deletes it from the old node.
+    // returns the file's size, or None if the file was deleted or started sharing its contents
+    // meanwhile, in which case it stays where it was
+    async fn migrate_file(&self, candidate: &Candidate, tier: u32) -> Result<Option<u64>, Error> {
+        let uuid = candidate.uuid;
+        let (contents, _) = self.with_node_failover(vec![candidate.node], |_, conn| async move {
+            conn.read_file(uuid).await
+        }).await?;
+        let size = contents.len() as u64;
+        // with the stored digest, nodes with checksums refuse contents which were corrupted on the old node
+        let digest = candidate.digest.clone().unwrap_or_else(|| ContentDigest::of(self.hash_algorithm, &contents));
+
+        let info = UploadFileInfo { data_length: contents.len(), content_type: None, overwrite: false };
+        let placement: Vec<StorageNodeID> = self.placement_for(&info).await?
+            .into_iter()
+            .filter(|&id| self.tier_of(id) == tier)
+            .collect();
+        if placement.is_empty() {
+            return Err(Error::NoNodeWithSpace);
+        }
+        let ((), target) = self.with_node_failover(placement, |_, conn| {
+            let contents = contents.clone();
+            let digest = &digest;
+            async move { self.write_to_node(&conn, uuid, contents, digest, true).await }
+        }).await?;
+
+        let mut transaction = self.pool()?.start_transaction(mysql_async::TxOpts::default()).await?;
+        let query = "SELECT stored_on_node_id FROM files WHERE uuid = :uuid OR blob_uuid = :uuid FOR UPDATE;";
+        let rows: Vec<StorageNodeID> = query.with(params! { "uuid" => uuid }).fetch(&mut transaction).await?;
+        let (moved_from, keep) = match rows.as_slice() {
+            [node] if *node == candidate.node => {
+                "UPDATE files SET stored_on_node_id = :target WHERE uuid = :uuid;"
+                    .with(params! { "target" => target, "uuid" => uuid })
+                    .ignore(&mut transaction)
+                    .await?;
+                transaction.commit().await?;
+                (candidate.node, Some(size))
+            }
+            _ => {
+                transaction.rollback().await?;
+                (target, None)
+            }
+        };
+
+        // downloads which looked up the old node just before may fail once. failing here only
+        // leaves an orphan, which scrubbing finds
+        if let Err(e) = self.delete_contents(moved_from, uuid).await {
+            warn!(?e, %uuid, node = ?moved_from, "Could not delete the contents left behind by a move; orphan possible");
+        }
+        Ok(keep)
+    }
+}
+
+/// Moves files between tiers every tiering.interval_s while this front node is the leader, if
+/// tiering is configured. Stops once the front node is dropped
+pub fn migrate_periodically(node: &Arc<FrontNode>) {
+    let Some(options) = node.tiering.clone() else {
+        return;
+    };
+    let leadership = Leadership::start(node.conn_pool.clone(), "tiering");
+    let node = Arc::downgrade(node);
+    let interval = Duration::from_secs(options.interval_s);
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            let Some(node) = node.upgrade() else {
+                return;
+            };
+            if !leadership.is_leader() {
+                trace!("Not the leader; another front node moves files");
+                continue;
+            }
+            match node.migrate_between_tiers(SystemTime::now(), &options).await {
+                Ok(TieringSummary { failed: 0, .. }) => {}
+                Ok(summary) => warn!(?summary, "Some files could not be moved; trying again in {interval:?}"),
+                Err(e) => warn!(?e, "Could not move files between tiers; trying again in {interval:?}"),
+            }
+        }
+    }.instrument(tracing::info_span!("tiering")));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::front_node::test_support;
+    #[cfg(feature = "testing")]
+    use crate::front_node::test_support::{DatabaseFrontNode, TestStorageNode};
+    #[cfg(feature = "testing")]
+    use crate::fault_injection::FaultInjector;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    /// A clock which only moves when told to, and the reads of one file
+    struct FakeClock {
+        now: SystemTime,
+        last_used: SystemTime,
+    }
+
+    impl FakeClock {
+        fn upload() -> FakeClock {
+            let now = SystemTime::UNIX_EPOCH + 20_000 * DAY;
+            FakeClock { now, last_used: now }
+        }
+
+        fn advance(&mut self, by: Duration) {
+            self.now += by;
+        }
+
+        fn read(&mut self) {
+            self.last_used = self.now;
+        }
+
+        fn target(&self, current_tier: u32, pinned_tier: Option<u32>, tiers: &BTreeSet<u32>) -> Option<u32> {
+            migration_target(current_tier, self.last_used, pinned_tier, tiers, self.now, 30 * DAY)
+        }
+    }
+
+    #[test]
+    fn cold_files_move_one_tier_slower() {
+        let tiers = BTreeSet::from([0, 1, 3]);
+        let mut clock = FakeClock::upload();
+        assert_eq!(clock.target(0, None, &tiers), None);
+
+        clock.advance(20 * DAY);
+        clock.read();
+        // 40 days since the upload, but only 20 since the last read
+        clock.advance(20 * DAY);
+        assert_eq!(clock.target(0, None, &tiers), None);
+
+        clock.advance(10 * DAY);
+        assert_eq!(clock.target(0, None, &tiers), Some(1));
+        // still cold once moved, so it keeps going. tier 2 has no connected node, so it's skipped
+        assert_eq!(clock.target(1, None, &tiers), Some(3));
+        assert_eq!(clock.target(3, None, &tiers), None);
+
+        // reads don't move files back
+        clock.read();
+        assert_eq!(clock.target(3, None, &tiers), None);
+
+        // a clock behind the last read, e.g. another front node's, counts as just read
+        clock.last_used = clock.now + DAY;
+        assert_eq!(clock.target(0, None, &tiers), None);
+    }
+
+    #[test]
+    fn pinned_files_move_to_their_tier() {
+        let tiers = BTreeSet::from([0, 1, 2]);
+        let mut clock = FakeClock::upload();
+        // however recently read
+        assert_eq!(clock.target(0, Some(2), &tiers), Some(2));
+        assert_eq!(clock.target(2, Some(0), &tiers), Some(0));
+        assert_eq!(clock.target(1, Some(1), &tiers), None);
+
+        // and never away from it
+        clock.advance(365 * DAY);
+        assert_eq!(clock.target(0, Some(0), &tiers), None);
+        // unless no node in the tier is connected, when it's as if unpinned
+        assert_eq!(clock.target(0, Some(5), &tiers), Some(1));
+    }
+
+    #[test]
+    fn last_used_is_the_later_of_upload_and_read() {
+        let uuid = Uuid::now_v7();
+        let uploaded = SystemTime::now();
+        let row = |last_accessed| (uuid, StorageNodeID(1), last_accessed, None, None, None);
+
+        let never_read = Candidate::from_row(row(None));
+        let since_upload = uploaded.duration_since(never_read.last_used).unwrap_or_default();
+        assert!(since_upload < Duration::from_secs(5), "{since_upload:?}");
+
+        let read_later = SystemTime::now() + DAY;
+        let read_later_s = read_later.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        let read = Candidate::from_row(row(Some(read_later_s)));
+        assert_eq!(read.last_used, SystemTime::UNIX_EPOCH + Duration::from_secs(read_later_s));
+
+        // read before the UUID says it was made, e.g. a clock set back
+        assert!(Candidate::from_row(row(Some(0))).last_used > SystemTime::UNIX_EPOCH);
+
+        // other UUIDs don't tell when the file was uploaded
+        let old = Candidate::from_row((Uuid::from_u128(7), StorageNodeID(1), None, None, None, None));
+        assert_eq!(old.last_used, SystemTime::UNIX_EPOCH);
+    }
+
+    #[test]
+    fn usage_is_summed_by_tier() {
+        let id = StorageNodeID;
+        let node_tiers = HashMap::from([(id(1), 0), (id(2), 1), (id(3), 1)]);
+        let node_names: HashMap<StorageNodeID, String> = [(1, "fast"), (2, "slow-b"), (3, "slow-a"), (4, "untiered")]
+            .into_iter()
+            .map(|(n, name)| (id(n), name.to_string()))
+            .collect();
+        let space = |bytes_total, bytes_available| StorageSpace { bytes_total, bytes_available };
+        // 3 isn't connected, so its space is unknown
+        let spaces = HashMap::from([(id(1), space(100, 40)), (id(2), space(1000, 900)), (id(4), space(10, 10))]);
+        let stored = HashMap::from([(id(1), (3, 60)), (id(2), (1, 100)), (id(3), (2, 5))]);
+
+        let usage = tier_usage(&node_tiers, &node_names, &spaces, &stored);
+        assert_eq!(usage, [
+            TierUsage { tier: 0, nodes: vec!["fast".to_string(), "untiered".to_string()], bytes_total: 110, bytes_available: 50, files: 3, bytes_stored: 60 },
+            TierUsage { tier: 1, nodes: vec!["slow-a".to_string(), "slow-b".to_string()], bytes_total: 1000, bytes_available: 900, files: 3, bytes_stored: 105 },
+        ]);
+    }
+
+    #[tokio::test]
+    async fn pins_need_a_connected_tier() {
+        let node = test_support::offline(&test_support::offline_config());
+        let conn = test_support::mock_node(&[], |_| None).await;
+        test_support::attach_offline(&node, StorageNodeID(1), conn).await;
+        node.node_tiers.write().unwrap().insert(StorageNodeID(1), 2);
+
+        assert!(matches!(node.pin_to_tier(DirectoryID(1), Some(0)).await, Err(Error::NoNodeWithSpace)));
+        // offline, so getting past the check fails on the database
+        assert!(matches!(node.pin_to_tier(DirectoryID(1), Some(2)).await, Err(Error::Database(_))));
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    #[ignore = "needs a MySQL database, see test_support::DATABASE_URL_VAR"]
+    async fn cold_and_pinned_files_are_moved() {
+        let front = DatabaseFrontNode::start_with_faults(&test_support::database_config(), FaultInjector::default()).await;
+        let node = &front.node;
+        let slow = TestStorageNode::start().await;
+        let slow_id = node.attach_connection(&format!("test-slow-{}", Uuid::now_v7()), slow.connect(FaultInjector::default()).await).await.unwrap();
+        node.node_tiers.write().unwrap().insert(slow_id, 1);
+        node.node_tiers.write().unwrap().insert(front.storage_id, 0);
+
+        let pinned = node.create_directories("pinned", Some(front.dir)).await.unwrap();
+        let cold = node.upload_file("cold".to_string(), front.dir, b"brr".to_vec(), Default::default()).await.unwrap();
+        let kept = node.upload_file("kept".to_string(), pinned, b"warm".to_vec(), Default::default()).await.unwrap();
+        node.pin_to_tier(pinned, Some(0)).await.unwrap();
+        assert!(front.storage.has_file(cold));
+
+        let options = config::TieringOptions { cold_after_s: 30 * 24 * 60 * 60, interval_s: 1, bytes_per_s: 1 << 30 };
+        // nothing is cold yet
+        node.migrate_between_tiers(SystemTime::now(), &options).await.unwrap();
+        assert!(front.storage.has_file(cold));
+
+        let later = SystemTime::now() + Duration::from_secs(options.cold_after_s + 1);
+        let summary = node.migrate_between_tiers(later, &options).await.unwrap();
+        assert_eq!(summary.failed, 0);
+        assert!(slow.has_file(cold));
+        assert!(!front.storage.has_file(cold));
+        assert_eq!(node.get_file(cold).await.unwrap().0, b"brr");
+        // pinned to the fast tier, so kept there however cold
+        assert!(front.storage.has_file(kept));
+        assert!(!slow.has_file(kept));
+
+        // and moved back when pinned elsewhere
+        node.pin_to_tier(pinned, Some(1)).await.unwrap();
+        node.migrate_between_tiers(SystemTime::now(), &options).await.unwrap();
+        assert!(slow.has_file(kept));
+        assert_eq!(node.get_file(kept).await.unwrap().0, b"warm");
+
+        let usage = node.tier_usage().await.unwrap();
+        assert!(usage.iter().any(|tier| tier.tier == 1 && tier.files >= 2), "{usage:?}");
+    }
+}
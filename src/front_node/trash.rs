@@ -0,0 +1,171 @@
+//! Deleted files are kept for a while before their contents are deleted, if trash is configured.
+//!
+//! Deleting a file moves its row to the trash directory, which no path leads to, under its UUID as
+//! its name. The directory and name it had are kept, so it can be restored there as long as that
+//! directory exists and the name is free. Files which have been in the trash for longer than
+//! trash.retention_s are deleted for good, the same way as with trash disabled.
+
+#[allow(unused)]
+use tracing::{trace, debug, info, warn, error, instrument, Instrument};
+
+use mysql_async::prelude::*;
+use uuid::Uuid;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::{FrontNode, ER_DUP_ENTRY};
+use super::tys::{DirectoryID, Error};
+
+/// Files deleted for good at a time
+const PURGE_BATCH_SIZE: u32 = 256;
+
+/// Moves a file which isn't in the trash to it. False if there is no such file
+pub(super) async fn move_to_trash(transaction: &mut mysql_async::Transaction<'_>, uuid: Uuid) -> Result<bool, Error> {
+    // assignments are made left to right, so the deleted_ columns get the old directory and name
+    let query = r#"
+        UPDATE files SET
+            deleted_at = NOW(),
+            deleted_from_directory_id = directory_id,
+            deleted_name = name,
+            directory_id = (SELECT directory_id FROM trash_directory),
+            name = :trash_name
+            WHERE uuid = :uuid AND deleted_at IS NULL;
+    "#;
+    query
+        .with(params! { "trash_name" => uuid.to_string(), "uuid" => uuid })
+        .ignore(&mut *transaction)
+        .await?;
+    Ok(transaction.affected_rows() > 0)
+}
+
+impl FrontNode {
+    /// Moves a file to the trash if trash is configured and the delete isn't `permanent`, and
+    /// otherwise deletes it right away, see delete_file
+    #[instrument(level = "info", skip(self))]
+    pub async fn remove_file(&self, uuid: Uuid, permanent: bool) -> Result<(), Error> {
+        match self.trash {
+            Some(_) if !permanent => self.trash_file(uuid).await,
+            _ => self.delete_file(uuid).await,
+        }
+    }
+
+    /// Moves a file to the trash. Its contents stay on the storage node until it's purged
+    async fn trash_file(&self, uuid: Uuid) -> Result<(), Error> {
+        let mut transaction = self.pool()?.start_transaction(mysql_async::TxOpts::default()).await?;
+        if !move_to_trash(&mut transaction, uuid).await? {
+            return Err(Error::NoSuchFile);
+        }
+        transaction.commit().await?;
+        self.path_cache.invalidate_files();
+        debug!("Moved file to the trash");
+        Ok(())
+    }
+
+    /// Restores the file most recently deleted from `dir` under `name`. Error::NoSuchFile if no such
+    /// file is in the trash, and Error::FileExists if the name has been taken since
+    #[instrument(level = "info", skip(self))]
+    pub async fn restore_file(&self, dir: DirectoryID, name: &str) -> Result<Uuid, Error> {
+        let mut transaction = self.pool()?.start_transaction(mysql_async::TxOpts::default()).await?;
+
+        let query = r#"
+            SELECT uuid FROM files
+                WHERE deleted_from_directory_id = :dir AND deleted_name = :name AND deleted_at IS NOT NULL
+                ORDER BY deleted_at DESC, uuid DESC
+                LIMIT 1
+                FOR UPDATE;
+        "#;
+        let Some(uuid): Option<Uuid> = query.with(params! { "dir" => dir, "name" => name }).first(&mut transaction).await? else {
+            return Err(Error::NoSuchFile);
+        };
+
+        let query = "SELECT EXISTS(SELECT * FROM files WHERE name = :name AND directory_id = :dir);";
+        let taken: Option<bool> = query.with(params! { "name" => name, "dir" => dir }).first(&mut transaction).await?;
+        if taken.unwrap_or(false) {
+            return Err(Error::FileExists);
+        }
+
+        // as in move_to_trash, directory_id and name are set before the deleted_ columns are cleared
+        let query = r#"
+            UPDATE files SET
+                directory_id = deleted_from_directory_id,
+                name = deleted_name,
+                deleted_at = NULL,
+                deleted_from_directory_id = NULL,
+                deleted_name = NULL
+                WHERE uuid = :uuid;
+        "#;
+        match query.with(params! { "uuid" => uuid }).ignore(&mut transaction).await {
+            Ok(()) => {}
+            // uploaded by someone else since the name was checked
+            Err(mysql_async::Error::Server(e)) if e.code == ER_DUP_ENTRY => return Err(Error::FileExists),
+            Err(e) => return Err(e.into()),
+        }
+        transaction.commit().await?;
+        self.path_cache.invalidate_files();
+        info!(%uuid, "Restored file");
+        Ok(uuid)
+    }
+
+    /// Deletes the files which have been in the trash for longer than `retention`, and returns how
+    /// many were deleted and how many couldn't be
+    #[instrument(level = "debug", skip(self))]
+    pub async fn purge_trash(&self, retention: Duration) -> Result<(u64, u64), Error> {
+        let (mut purged, mut failed) = (0, 0);
+        loop {
+            let query = r#"
+                SELECT uuid FROM files
+                    WHERE deleted_at < NOW() - INTERVAL :retention_s SECOND
+                    ORDER BY deleted_at, uuid
+                    LIMIT :limit OFFSET :offset;
+            "#;
+            // files which couldn't be deleted are still there, so they are skipped
+            let uuids: Vec<Uuid> = query
+                .with(params! { "retention_s" => retention.as_secs(), "limit" => PURGE_BATCH_SIZE, "offset" => failed })
+                .fetch(self.pool()?)
+                .await?;
+            let n_uuids = uuids.len();
+            for uuid in uuids {
+                match self.delete_file(uuid).await {
+                    Ok(()) => purged += 1,
+                    // purged by another front node meanwhile
+                    Err(Error::NoSuchFile) => {}
+                    Err(e) => {
+                        warn!(?e, %uuid, "Could not purge file from the trash");
+                        failed += 1;
+                    }
+                }
+            }
+            if n_uuids < PURGE_BATCH_SIZE as usize {
+                return Ok((purged, failed));
+            }
+        }
+    }
+}
+
+/// Purges the trash every trash.purge_interval_s, if trash is configured. Stops once the front node
+/// is dropped
+pub fn purge_periodically(node: &Arc<FrontNode>) {
+    let Some(options) = node.trash.clone() else {
+        return;
+    };
+    let node = Arc::downgrade(node);
+    let retention = Duration::from_secs(options.retention_s);
+    let interval = Duration::from_secs(options.purge_interval_s);
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            let Some(node) = node.upgrade() else {
+                return;
+            };
+            match node.purge_trash(retention).await {
+                Ok((0, 0)) => trace!("Nothing to purge"),
+                Ok((purged, 0)) => info!(purged, "Purged the trash"),
+                Ok((purged, failed)) => warn!(purged, failed, "Some files could not be purged from the trash; trying again in {interval:?}"),
+                Err(e) => warn!(?e, "Could not purge the trash; trying again in {interval:?}"),
+            }
+        }
+    }.instrument(tracing::info_span!("purge_trash")));
+}
@@ -45,20 +45,60 @@ impl FromValue for DirectoryID {
 pub enum Error {
     // none of these should hopefully occur
     IO(std::io::Error),
-    DatabaseError(mysql_async::Error),
-    ConnectionError(ConnectionError),
-    MalformedUUIDError(Vec<u8>, uuid::Error),
+    Database(mysql_async::Error),
+    Connection(ConnectionError),
+    MalformedUUID(Vec<u8>, uuid::Error),
     UnknownUUID,
     UnexpectedResponse(crate::message::Message),
+    // the storage node couldn't do what was asked. missing files are UnknownUUID instead
+    StorageNode { code: crate::message::ErrorCode, detail: String },
+    // the storage node's view of a written file doesn't match what was sent
+    VerificationFailed { uuid: uuid::Uuid, expected_size: u64, actual_size: Option<u64> },
+    // the storage node acked a write with fewer bytes than were sent
+    ShortWrite { uuid: uuid::Uuid, expected: u64, written: u64 },
+    // the storage node's digest of a file doesn't match the digest we have
+    DigestMismatch { uuid: uuid::Uuid, expected: crate::hashing::ContentDigest, actual: crate::hashing::ContentDigest },
+    // the database breaks an assumption the front node relies on, e.g. root_directory was emptied
+    DatabaseInconsistent { detail: String },
 
     // these may occur and should be handled prettily
     NotConnectedToAnyNode,
     NotConnectedToNode,
+    // a storage node took longer than the retry policy allows
+    AttemptTimedOut,
+    // every attempt of an operation failed, see failover.rs
+    AllAttemptsFailed(Vec<super::failover::FailedAttempt>),
+    // no connected storage node has room for the file
+    NoNodeWithSpace,
+    // the upload breaks the directory's upload policy. rule describes which part
+    PolicyViolation { rule: String },
+    // the upload would take its user over their quota. message says how much of it they use
+    QuotaExceeded { message: String },
 
     // these are "user errors" and should be pretty-printed
     NoSuchFile,
+    // a read started at or past the end of the file
+    ReadPastEnd { file_size: u64 },
+    // the client stopped sending an upload before it was complete
+    UploadInterrupted,
+    // a file or directory with that name is already in the directory
+    FileExists,
+    // a directory can't be created where one with that name is already
+    AlreadyExists,
+    // names can't be empty or contain a slash
+    InvalidName { name: String },
+    // directories can only be removed once empty
+    DirectoryNotEmpty,
+    CannotRemoveRoot,
+    // users.home_directory references it, so it can only be removed once its users are
+    DirectoryIsHome,
+    // a directory can't be moved into itself or any directory below it. this includes moving the root
+    MoveIntoOwnSubtree,
     NoSuchDirectory { topmost_existing_directory: String },
     NoSuchUser { name: String },
+    UserExists { name: String },
+    // a home directory which is the root or another user's home too can't be deleted with its user
+    HomeDirectoryShared,
 }
 
 impl From<std::io::Error> for Error {
@@ -66,10 +106,13 @@ impl From<std::io::Error> for Error {
 }
 
 impl From<mysql_async::Error> for Error {
-    fn from(value: mysql_async::Error) -> Self { Error::DatabaseError(value) }
+    fn from(value: mysql_async::Error) -> Self {
+        super::metrics::count_database_error();
+        Error::Database(value)
+    }
 }
 
 impl From<ConnectionError> for Error {
-    fn from(value: ConnectionError) -> Self { Error::ConnectionError(value) }
+    fn from(value: ConnectionError) -> Self { Error::Connection(value) }
 }
 
@@ -0,0 +1,297 @@
+//! Per-directory restrictions on what may be uploaded, from the upload_policies table.
+//!
+//! A directory's policy applies to everything below it, until a subdirectory has a policy of its
+//! own. The nearest policy wins as a whole, policies are not merged.
+
+#[allow(unused)]
+use tracing::{trace, debug, info, warn, error, instrument};
+
+use mysql_async::prelude::*;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::FrontNode;
+use super::tys::{DirectoryID, Error};
+
+/// How long a directory's effective policy is reused for. Policies are edited directly in the
+/// database, so changes take up to this long to apply
+const POLICY_CACHE_TTL: Duration = Duration::from_secs(30);
+
+/// denied_names, allowed_names, max_file_size, allowed_content_types, has_policy, parent_id
+type PolicyRow = (Option<String>, Option<String>, Option<u64>, Option<String>, bool, Option<DirectoryID>);
+
+#[derive(Debug, Clone)]
+pub struct UploadPolicy {
+    /// the directory the policy is set on
+    pub directory: DirectoryID,
+    /// globs, `*` and `?` only. a name matching any of these is refused
+    pub denied_names: Vec<String>,
+    /// globs. if not empty, names must match one of these
+    pub allowed_names: Vec<String>,
+    pub max_file_size: Option<u64>,
+    /// prefixes, e.g. `image/`. if set, uploads must have a content type starting with one of these
+    pub allowed_content_types: Option<Vec<String>>,
+}
+
+pub type PolicyCache = std::sync::Mutex<HashMap<DirectoryID, (Instant, Option<Arc<UploadPolicy>>)>>;
+
+// policy columns hold one entry per line
+fn split_lines(column: Option<String>) -> Vec<String> {
+    column.iter()
+        .flat_map(|column| column.lines())
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Matches `*` (any run of characters) and `?` (any one character). Everything else is literal
+fn glob_matches(glob: &str, name: &str) -> bool {
+    let (glob, name): (Vec<char>, Vec<char>) = (glob.chars().collect(), name.chars().collect());
+    let (mut g, mut n) = (0, 0);
+    // where to resume if the current attempt fails: just after the last `*`, and the name
+    // position it is currently assumed to match up to
+    let mut backtrack = None;
+    while n < name.len() {
+        match glob.get(g) {
+            Some('*') => {
+                backtrack = Some((g + 1, n));
+                g += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                g += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star_g, star_n)) => {
+                    g = star_g;
+                    n = star_n + 1;
+                    backtrack = Some((star_g, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    glob[g..].iter().all(|&c| c == '*')
+}
+
+impl UploadPolicy {
+    /// The rule the upload breaks, if any. Without a size, the size limit isn't checked
+    pub fn check(&self, name: &str, size: Option<u64>, content_type: Option<&str>) -> Result<(), String> {
+        let dir = self.directory.0;
+        if let Some(glob) = self.denied_names.iter().find(|glob| glob_matches(glob, name)) {
+            return Err(format!("name matches denied pattern {glob:?} (policy on directory {dir})"));
+        }
+        if !self.allowed_names.is_empty() && !self.allowed_names.iter().any(|glob| glob_matches(glob, name)) {
+            return Err(format!("name matches none of the allowed patterns {:?} (policy on directory {dir})", self.allowed_names));
+        }
+        if let Some(max_file_size) = self.max_file_size.filter(|&max| size.is_some_and(|size| size > max)) {
+            return Err(format!("file is larger than {max_file_size} bytes (policy on directory {dir})"));
+        }
+        if let Some(ref prefixes) = self.allowed_content_types {
+            let allowed = content_type.is_some_and(|content_type| prefixes.iter().any(|prefix| content_type.starts_with(prefix.as_str())));
+            if !allowed {
+                return Err(format!("content type {content_type:?} is not one of {prefixes:?} (policy on directory {dir})"));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FrontNode {
+    /// The policy applying to uploads into `dir`: its own, or that of its nearest ancestor with one
+    #[instrument(level = "trace", skip(self))]
+    pub async fn upload_policy(&self, dir: DirectoryID) -> Result<Option<Arc<UploadPolicy>>, Error> {
+        if let Some((fetched_at, policy)) = self.policy_cache.lock().unwrap().get(&dir) {
+            if fetched_at.elapsed() < POLICY_CACHE_TTL {
+                return Ok(policy.clone());
+            }
+        }
+
+        let query = r#"
+            SELECT p.denied_names, p.allowed_names, p.max_file_size, p.allowed_content_types,
+                   p.directory_id IS NOT NULL, d.parent_id
+                FROM directories d
+                LEFT JOIN upload_policies p ON p.directory_id = d.id
+                WHERE d.id = :dir;
+        "#;
+        let mut current = Some(dir);
+        let mut policy = None;
+        while let Some(directory) = current {
+            let row: Option<PolicyRow> = query.with(params! { "dir" => directory }).first(self.pool()?).await?;
+            let Some((denied_names, allowed_names, max_file_size, allowed_content_types, has_policy, parent_id)) = row else {
+                break;
+            };
+            if has_policy {
+                policy = Some(Arc::new(UploadPolicy {
+                    directory,
+                    denied_names: split_lines(denied_names),
+                    allowed_names: split_lines(allowed_names),
+                    max_file_size,
+                    allowed_content_types: allowed_content_types.map(|types| split_lines(Some(types))),
+                }));
+                break;
+            }
+            current = parent_id;
+        }
+
+        trace!(?policy, "Resolved upload policy");
+        self.policy_cache.lock().unwrap().insert(dir, (Instant::now(), policy.clone()));
+        Ok(policy)
+    }
+
+    /// Forgets all cached policies, for after the upload_policies table is changed
+    #[cfg_attr(not(feature = "sftp"), allow(unused))]
+    pub fn invalidate_upload_policies(&self) {
+        self.policy_cache.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::front_node::UploadOptions;
+    use crate::front_node::test_support::{self, DatabaseFrontNode};
+    use crate::fault_injection::FaultInjector;
+
+    #[test]
+    fn globs() {
+        let cases = [
+            ("*.exe", "setup.exe", true),
+            ("*.exe", "setup.exe.txt", false),
+            ("*.exe", ".exe", true),
+            (".*", ".bashrc", true),
+            (".*", "bashrc", false),
+            ("?.txt", "a.txt", true),
+            ("?.txt", "ab.txt", false),
+            ("a*b*c", "aXXbYYbZc", true),
+            ("a*b*c", "aXXbYYc", true),
+            ("a*b*c", "acb", false),
+            ("*", "", true),
+            ("", "", true),
+            ("", "a", false),
+            ("bnuy", "bnuy", true),
+            ("bnuy", "Bnuy", false),
+            ("*🐇*", "a 🐇 b", true),
+        ];
+        for (glob, name, matches) in cases {
+            assert_eq!(glob_matches(glob, name), matches, "{glob:?} on {name:?}");
+        }
+    }
+
+    fn policy() -> UploadPolicy {
+        UploadPolicy {
+            directory: DirectoryID(7),
+            denied_names: vec!["*.exe".to_string(), ".*".to_string()],
+            allowed_names: Vec::new(),
+            max_file_size: None,
+            allowed_content_types: None,
+        }
+    }
+
+    #[test]
+    fn checks() {
+        let images = UploadPolicy {
+            allowed_names: vec!["*.png".to_string(), "*.jpg".to_string()],
+            max_file_size: Some(100),
+            allowed_content_types: Some(vec!["image/".to_string()]),
+            ..policy()
+        };
+        let cases = [
+            (policy(), "notes.txt", Some(1 << 40), None, None),
+            (policy(), "setup.exe", None, None, Some("denied pattern \"*.exe\"")),
+            (policy(), ".env", None, None, Some("denied pattern \".*\"")),
+            (images.clone(), "cat.png", Some(100), Some("image/png"), None),
+            // without a size, e.g. an upload of unknown length, the size isn't checked
+            (images.clone(), "cat.png", None, Some("image/png"), None),
+            (images.clone(), "cat.png", Some(101), Some("image/png"), Some("larger than 100 bytes")),
+            (images.clone(), "cat.gif", Some(1), Some("image/gif"), Some("none of the allowed patterns")),
+            (images.clone(), "cat.png", Some(1), Some("text/plain"), Some("content type Some(\"text/plain\")")),
+            (images.clone(), "cat.png", Some(1), None, Some("content type None")),
+            // denied patterns apply before allowed ones
+            (UploadPolicy { denied_names: vec!["cat.*".to_string()], ..images }, "cat.png", Some(1), Some("image/png"), Some("denied pattern")),
+        ];
+        for (policy, name, size, content_type, broken) in cases {
+            match (policy.check(name, size, content_type), broken) {
+                (Ok(()), None) => {}
+                (Err(rule), Some(broken)) => {
+                    assert!(rule.contains(broken), "{name}: {rule:?} should say {broken:?}");
+                    assert!(rule.ends_with("(policy on directory 7)"), "{rule}");
+                }
+                (checked, _) => panic!("{name} {size:?} {content_type:?}: expected {broken:?}, got {checked:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MySQL database, see test_support::DATABASE_URL_VAR"]
+    async fn nearest_policy_wins() {
+        let front = DatabaseFrontNode::start_with_faults(&test_support::database_config(), FaultInjector::default()).await;
+        let node = &front.node;
+        let dir = |path: &str| {
+            let path = format!("{}/{path}", front.path);
+            async move { node.create_directories(&path, None).await.unwrap() }
+        };
+        let set_policy = |directory: DirectoryID, denied: Option<&str>, allowed: Option<&str>, max_file_size: Option<u64>| {
+            let query = r#"
+                REPLACE INTO upload_policies(directory_id, denied_names, allowed_names, max_file_size)
+                    VALUES (:directory, :denied, :allowed, :max_file_size);
+            "#;
+            let params = params! { "directory" => directory, "denied" => denied, "allowed" => allowed, "max_file_size" => max_file_size };
+            async move { query.with(params).ignore(&node.conn_pool).await.unwrap() }
+        };
+
+        // shared denies executables for everything below it. images only allows small images, and
+        // open has an empty policy, which allows anything
+        let shared = dir("shared").await;
+        let inherited = dir("shared/inherited/deeper").await;
+        let images = dir("shared/images").await;
+        let thumbnails = dir("shared/images/thumbnails").await;
+        let open = dir("shared/open").await;
+        set_policy(shared, Some("*.exe\n.*"), None, None).await;
+        set_policy(images, None, Some("*.png"), Some(10)).await;
+        set_policy(open, None, None, None).await;
+
+        let cases = [
+            (front.dir, "setup.exe", 1, None),
+            (shared, "setup.exe", 1, Some(shared)),
+            (shared, ".hidden", 1, Some(shared)),
+            (shared, "big.png", 1000, None),
+            (inherited, "setup.exe", 1, Some(shared)),
+            (inherited, "notes.txt", 1, None),
+            // overridden as a whole, so executables are only refused for not being images
+            (images, "setup.exe", 1, Some(images)),
+            (images, "cat.png", 10, None),
+            (images, "big.png", 11, Some(images)),
+            (thumbnails, "big.png", 11, Some(images)),
+            (thumbnails, "notes.txt", 1, Some(images)),
+            (open, "setup.exe", 1, None),
+        ];
+        for (dir, name, size, refused_by) in cases {
+            let policy = node.upload_policy(dir).await.unwrap();
+            let checked = policy.as_ref().map_or(Ok(()), |policy| policy.check(name, Some(size), None));
+            match refused_by {
+                None => assert!(checked.is_ok(), "{name} in {dir:?}: {checked:?}"),
+                Some(refused_by) => {
+                    assert_eq!(policy.unwrap().directory, refused_by, "{name} in {dir:?}");
+                    assert!(checked.is_err(), "{name} in {dir:?}");
+                }
+            }
+        }
+
+        let uploaded = node.upload_file("setup.exe".to_string(), inherited, b"MZ".to_vec(), UploadOptions::default()).await;
+        assert!(matches!(uploaded, Err(Error::PolicyViolation { ref rule }) if rule.contains("*.exe")), "{uploaded:?}");
+
+        // cached until invalidated
+        set_policy(shared, None, None, None).await;
+        assert!(node.upload_policy(inherited).await.unwrap().unwrap().check("setup.exe", None, None).is_err());
+        node.invalidate_upload_policies();
+        assert!(node.upload_policy(inherited).await.unwrap().unwrap().check("setup.exe", None, None).is_ok());
+
+        let query = "DELETE FROM upload_policies WHERE directory_id IN (:shared, :images, :open);";
+        query.with(params! { "shared" => shared, "images" => images, "open" => open }).ignore(&node.conn_pool).await.unwrap();
+    }
+}
@@ -0,0 +1,255 @@
+//! Progress of uploads, for clients to poll at GET /upload/progress/:id while an upload runs.
+//!
+//! This is the only way progress is reported. hyper's server sends no 1xx responses besides 100
+//! Continue, and an upload's response is only sent once the file is stored, so neither 102
+//! Processing responses nor trailers can carry it.
+
+#[allow(unused)]
+use tracing::{trace, debug, info, warn, error, instrument};
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{Stream, StreamExt};
+use tokio::sync::RwLock;
+
+/// How long a finished upload stays queryable before it is forgotten
+const EXPIRE_AFTER_FINISHED: Duration = Duration::from_secs(30);
+
+#[derive(serde::Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum UploadState {
+    Receiving,
+    Forwarding,
+    Done,
+    Failed,
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct UploadProgress {
+    pub state: UploadState,
+    /// bytes read from the client so far
+    pub bytes_received: u64,
+    /// bytes acknowledged by the storage node so far
+    pub bytes_forwarded: u64,
+    /// taken from the Content-Length of the upload, if the client sent one
+    pub expected_bytes: Option<u64>,
+}
+
+/// In-memory map of client-supplied upload IDs (the `X-Upload-Id` header) to their progress.
+/// Entries are only visible on the front node handling the upload.
+#[derive(Clone, Default, Debug)]
+pub struct UploadProgressMap {
+    // each entry has a generation number, so that an expiry task for an old upload doesn't remove
+    // a newer upload which reused the same ID
+    inner: Arc<RwLock<HashMap<String, (u64, UploadProgress)>>>,
+    next_generation: Arc<std::sync::atomic::AtomicU64>,
+}
+
+/// Handle held by the upload handler while the upload is in progress.
+/// If dropped before `finish` is called, the upload is marked as failed.
+#[derive(Debug)]
+pub struct UploadProgressHandle {
+    map: UploadProgressMap,
+    id: String,
+    generation: u64,
+    finished: bool,
+}
+
+impl UploadProgressMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    pub async fn start(&self, id: String, expected_bytes: Option<u64>) -> UploadProgressHandle {
+        let generation = self.next_generation.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let progress = UploadProgress {
+            state: UploadState::Receiving,
+            bytes_received: 0,
+            bytes_forwarded: 0,
+            expected_bytes,
+        };
+
+        if self.inner.write().await.insert(id.clone(), (generation, progress)).is_some() {
+            debug!(id, "Upload ID reused; replacing previous progress");
+        }
+
+        UploadProgressHandle {
+            map: self.clone(),
+            id,
+            generation,
+            finished: false,
+        }
+    }
+
+    pub async fn get(&self, id: &str) -> Option<UploadProgress> {
+        self.inner.read().await.get(id).map(|(_, progress)| progress.clone())
+    }
+
+    async fn update(&self, id: &str, generation: u64, f: impl FnOnce(&mut UploadProgress)) {
+        let mut inner = self.inner.write().await;
+        match inner.get_mut(id) {
+            Some((gen, progress)) if *gen == generation => f(progress),
+            _ => trace!(id, "Progress entry was replaced; not updating"),
+        }
+    }
+
+    fn expire_later(&self, id: String, generation: u64) {
+        let map = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(EXPIRE_AFTER_FINISHED).await;
+            let mut inner = map.inner.write().await;
+            if inner.get(&id).is_some_and(|(gen, _)| *gen == generation) {
+                trace!(id, "Expiring upload progress");
+                inner.remove(&id);
+            }
+        });
+    }
+}
+
+impl UploadProgressHandle {
+    pub async fn received(&self, n_bytes: usize) {
+        self.map.update(&self.id, self.generation, |p| p.bytes_received += n_bytes as u64).await;
+    }
+
+    /// Called as each chunk is acknowledged by the storage node
+    pub async fn forwarded(&self, n_bytes: usize) {
+        self.map.update(&self.id, self.generation, |p| p.bytes_forwarded += n_bytes as u64).await;
+    }
+
+    pub async fn forwarding(&self) {
+        self.map.update(&self.id, self.generation, |p| p.state = UploadState::Forwarding).await;
+    }
+
+    /// Counts the chunks of a body as they are received, and moves on to Forwarding once it ends
+    pub fn track<'a, S, B, E>(&'a self, body: S) -> impl Stream<Item = Result<B, E>> + Send + 'a
+    where
+        S: Stream<Item = Result<B, E>> + Send + Unpin + 'a,
+        B: AsRef<[u8]> + Send + 'a,
+        E: Send + 'a,
+    {
+        futures::stream::unfold(Some(body), move |body| async move {
+            let mut body = body?;
+            match body.next().await {
+                Some(chunk) => {
+                    if let Ok(ref received) = chunk {
+                        self.received(received.as_ref().len()).await;
+                    }
+                    Some((chunk, Some(body)))
+                }
+                None => {
+                    self.forwarding().await;
+                    None
+                }
+            }
+        })
+    }
+
+    pub async fn finish(mut self, success: bool) {
+        self.finished = true;
+        self.map.update(&self.id, self.generation, |p| {
+            if success {
+                p.state = UploadState::Done;
+                // nodes without chunked writes are sent the whole file at once, so nothing was
+                // counted as forwarded until now
+                p.bytes_forwarded = p.bytes_received;
+            } else {
+                p.state = UploadState::Failed;
+            }
+        }).await;
+        self.map.expire_later(self.id.clone(), self.generation);
+    }
+}
+
+impl Drop for UploadProgressHandle {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        // the handler was cancelled (e.g. the client disconnected mid-upload)
+        let map = self.map.clone();
+        let id = std::mem::take(&mut self.id);
+        let generation = self.generation;
+        tokio::spawn(async move {
+            map.update(&id, generation, |p| p.state = UploadState::Failed).await;
+            map.expire_later(id, generation);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::convert::Infallible;
+
+    const CHUNKS: usize = 10;
+    const CHUNK_LEN: usize = 100;
+
+    /// A body arriving a chunk at a time, like one from a client on a slow link
+    fn slow_body() -> impl Stream<Item = Result<Vec<u8>, Infallible>> + Send + Unpin {
+        Box::pin(futures::stream::iter(0..CHUNKS).then(|_| async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            Ok(vec![0; CHUNK_LEN])
+        }))
+    }
+
+    #[tokio::test]
+    async fn progress_grows_while_uploading() {
+        let map = UploadProgressMap::new();
+        let total = (CHUNKS * CHUNK_LEN) as u64;
+        let handle = map.start("slow".to_string(), Some(total)).await;
+
+        let upload = async {
+            {
+                let mut body = std::pin::pin!(handle.track(slow_body()));
+                while let Some(chunk) = body.next().await {
+                    // as upload_stream does once the storage node acknowledges the chunk
+                    handle.forwarded(chunk.unwrap().len()).await;
+                }
+            }
+            handle.finish(true).await;
+        };
+        let poll = async {
+            let mut seen: Vec<UploadProgress> = Vec::new();
+            loop {
+                let progress = map.get("slow").await.expect("progress is tracked");
+                let done = progress.state == UploadState::Done;
+                seen.push(progress);
+                if done {
+                    return seen;
+                }
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        };
+        let ((), seen) = tokio::join!(upload, poll);
+
+        for pair in seen.windows(2) {
+            assert!(pair[0].bytes_received <= pair[1].bytes_received, "{seen:?}");
+            assert!(pair[0].bytes_forwarded <= pair[1].bytes_forwarded, "{seen:?}");
+        }
+        assert!(seen.iter().all(|p| p.bytes_forwarded <= p.bytes_received), "{seen:?}");
+        // the upload was seen partway, not just before and after
+        assert!(seen.iter().any(|p| 0 < p.bytes_received && p.bytes_received < total), "{seen:?}");
+        assert!(seen.iter().any(|p| 0 < p.bytes_forwarded && p.bytes_forwarded < total), "{seen:?}");
+
+        let last = seen.last().unwrap();
+        assert_eq!((last.bytes_received, last.bytes_forwarded, last.expected_bytes), (total, total, Some(total)));
+    }
+
+    #[tokio::test]
+    async fn dropped_upload_is_failed() {
+        let map = UploadProgressMap::new();
+        drop(map.start("dropped".to_string(), None).await);
+        // marked from a spawned task
+        for _ in 0..100 {
+            if map.get("dropped").await.unwrap().state == UploadState::Failed {
+                return;
+            }
+            tokio::task::yield_now().await;
+        }
+        panic!("upload wasn't marked as failed");
+    }
+}
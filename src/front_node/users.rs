@@ -0,0 +1,184 @@
+//! Creating, listing and deleting users, for the /admin/users routes. Each user gets a home
+//! directory named after them under admin.home_parent.
+
+#[allow(unused)]
+use tracing::{trace, debug, info, warn, error, instrument};
+
+use mysql_async::prelude::*;
+
+use super::{FrontNode, ER_DUP_ENTRY};
+use super::recursive_delete::DirectoryDeletion;
+use super::tys::{DirectoryID, Error};
+
+#[derive(Debug, serde::Serialize)]
+pub struct UserInfo {
+    pub id: u32,
+    pub name: String,
+    pub home_directory: DirectoryID,
+    pub has_ssh_pubkey: bool,
+    pub has_password: bool,
+    pub has_http_token: bool,
+}
+
+/// id, username, home_directory, has ssh_pubkey, has password_hash, has http_token_sha256
+type UserRow = (u32, String, DirectoryID, bool, bool, bool);
+
+/// Whether `key` is among `stored`, a users.ssh_pubkey in authorized_keys form: one OpenSSH public
+/// key per line, with blank lines and # comments skipped. Only the keys are compared, not their
+/// comments
+#[cfg(feature = "sftp")]
+fn authorized_key(stored: &str, key: &ssh_key::PublicKey) -> bool {
+    stored.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| match ssh_key::PublicKey::from_openssh(line) {
+            Ok(stored) => Some(stored),
+            Err(e) => {
+                warn!(?e, "Stored SSH public key does not parse");
+                None
+            }
+        })
+        .any(|stored| stored.key_data() == key.key_data())
+}
+
+impl FrontNode {
+    /// Whether the user exists and `key` is one of their SSH public keys, see users.ssh_pubkey
+    #[cfg(feature = "sftp")]
+    #[instrument(level = "debug", skip(self, key))]
+    pub async fn check_ssh_pubkey(&self, user: &str, key: &ssh_key::PublicKey) -> Result<bool, Error> {
+        let query = "SELECT ssh_pubkey FROM users WHERE username = :user;";
+        let stored: Option<Option<String>> = query.with(params! { "user" => user }).first(self.pool()?).await?;
+        let Some(Some(stored)) = stored else {
+            debug!("No such user, or the user has no SSH key");
+            return Ok(false);
+        };
+        Ok(authorized_key(&stored, key))
+    }
+
+    /// Creates a user and their home directory, `home_parent/<name>`. home_parent is a path from the
+    /// root, and is created if it doesn't exist. Error::UserExists if the name is taken, and
+    /// Error::AlreadyExists if the home directory already exists, e.g. from a deleted user
+    #[instrument(level = "info", skip(self, ssh_pubkey))]
+    pub async fn create_user(
+        &self,
+        name: &str,
+        ssh_pubkey: Option<&str>,
+        home_parent: &str,
+    ) -> Result<(u32, DirectoryID), Error> {
+        if name.is_empty() || name.contains('/') {
+            return Err(Error::InvalidName { name: name.to_string() });
+        }
+        let query = "SELECT EXISTS(SELECT * FROM users WHERE username = :name);";
+        let exists: Option<bool> = query.with(params! { "name" => name }).first(self.pool()?).await?;
+        if exists.unwrap_or(false) {
+            return Err(Error::UserExists { name: name.to_string() });
+        }
+
+        let parent = self.create_directories(home_parent.trim_matches('/'), None).await?;
+        let home = self.create_directory(parent, name.to_string()).await?;
+
+        let mut conn = self.pool()?.get_conn().await?;
+        let query = "INSERT INTO users(username, ssh_pubkey, home_directory) VALUES (:name, :ssh_pubkey, :home);";
+        let inserted = query
+            .with(params! { "name" => name, "ssh_pubkey" => ssh_pubkey, "home" => home })
+            .ignore(&mut conn)
+            .await;
+        let error = match inserted {
+            Ok(()) => {
+                let id = conn.last_insert_id().expect("users.id is AUTO_INCREMENT") as u32;
+                info!(id, ?home, "Created user");
+                return Ok((id, home));
+            }
+            // created by someone else since it was checked
+            Err(mysql_async::Error::Server(e)) if e.code == ER_DUP_ENTRY => Error::UserExists { name: name.to_string() },
+            Err(e) => e.into(),
+        };
+        if let Err(e) = self.remove_directory(home).await {
+            warn!(?e, ?home, "Could not remove the home directory of a user which wasn't created");
+        }
+        Err(error)
+    }
+
+    /// All users, by name
+    #[instrument(level = "debug", skip(self))]
+    pub async fn list_users(&self) -> Result<Vec<UserInfo>, Error> {
+        let query = r#"
+            SELECT id, username, home_directory,
+                   ssh_pubkey IS NOT NULL, password_hash IS NOT NULL, http_token_sha256 IS NOT NULL
+                FROM users
+                ORDER BY username;
+        "#;
+        let rows: Vec<UserRow> = query.fetch(self.pool()?).await?;
+        Ok(rows.into_iter().map(|(id, name, home_directory, has_ssh_pubkey, has_password, has_http_token)| UserInfo {
+            id,
+            name,
+            home_directory,
+            has_ssh_pubkey,
+            has_password,
+            has_http_token,
+        }).collect())
+    }
+
+    /// Deletes a user. With `delete_home`, their home directory is deleted along with everything in
+    /// it, which is refused with Error::HomeDirectoryShared if it's the root or another user's home
+    /// too. Without it, the home directory is left as it is
+    #[instrument(level = "info", skip(self))]
+    pub async fn delete_user(&self, name: &str, delete_home: bool) -> Result<Option<DirectoryDeletion>, Error> {
+        let query = "SELECT home_directory FROM users WHERE username = :name;";
+        let Some(home): Option<DirectoryID> = query.with(params! { "name" => name }).first(self.pool()?).await? else {
+            return Err(Error::NoSuchUser { name: name.to_string() });
+        };
+
+        if delete_home {
+            let query = r#"
+                SELECT EXISTS(SELECT * FROM users WHERE home_directory = :home AND username != :name)
+                    OR EXISTS(SELECT * FROM root_directory WHERE directory_id = :home);
+            "#;
+            let shared: Option<bool> = query.with(params! { "home" => home, "name" => name }).first(self.pool()?).await?;
+            if shared.unwrap_or(false) {
+                return Err(Error::HomeDirectoryShared);
+            }
+        }
+
+        "DELETE FROM users WHERE username = :name;"
+            .with(params! { "name" => name })
+            .ignore(self.pool()?)
+            .await?;
+        info!(?home, "Deleted user");
+
+        if !delete_home {
+            return Ok(None);
+        }
+        match self.delete_directory(home, true, false).await {
+            Ok(deletion) => Ok(Some(deletion)),
+            // deleted by hand already
+            Err(Error::NoSuchDirectory { .. }) => Ok(Some(DirectoryDeletion::default())),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "sftp"))]
+mod tests {
+    use super::*;
+
+    use ssh_key::{private::PrivateKey, Algorithm};
+
+    fn random_key() -> ssh_key::PublicKey {
+        PrivateKey::random(&mut rand::rngs::OsRng, Algorithm::Ed25519).unwrap().public_key().clone()
+    }
+
+    #[test]
+    fn only_stored_keys_are_authorized() {
+        let (first, second, other) = (random_key(), random_key(), random_key());
+        let stored = format!(
+            "# laptop\n{} bnuy@laptop\n\nnot a key\n  {}  \n",
+            first.to_openssh().unwrap(),
+            second.to_openssh().unwrap(),
+        );
+        assert!(authorized_key(&stored, &first));
+        assert!(authorized_key(&stored, &second));
+        assert!(!authorized_key(&stored, &other));
+        assert!(!authorized_key("", &first));
+    }
+}
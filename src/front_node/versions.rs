@@ -0,0 +1,173 @@
+//! Old versions of overwritten files, if versioning is configured.
+//!
+//! An upload which replaces a file gets a new UUID as always. The replaced file's row is moved to the
+//! versions directory, which no path leads to, and recorded in file_versions as a version of the new
+//! file. Path resolution finds the new file, as the only one left at the path. Versions belong to the
+//! current file, so they move over to each file which replaces it, follow it into the trash, and are
+//! deleted along with it. Only the newest versioning.keep_versions are kept.
+
+#[allow(unused)]
+use tracing::{trace, debug, info, warn, error, instrument};
+
+use mysql_async::prelude::*;
+use uuid::Uuid;
+
+use super::FrontNode;
+use super::tys::Error;
+
+/// What happens to a file replaced by an upload
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum Replacement {
+    /// deleted right away, contents and all
+    Deleted,
+    /// moved to the trash, see trash.rs
+    Trashed,
+    /// kept as a version of the new file
+    Versioned,
+}
+
+/// One version of a file, newest first in listings
+#[derive(Debug, serde::Serialize)]
+pub struct FileVersion {
+    /// 1 for the first file at the path
+    pub version: u32,
+    pub uuid: Uuid,
+    /// None for files uploaded before sizes were stored
+    pub size: Option<u64>,
+    /// when this version was uploaded, in seconds since the unix epoch, read from its v7 UUID
+    pub modified: Option<u64>,
+    /// when a newer version replaced this one, in seconds since the unix epoch. None for the current
+    /// version
+    pub replaced_at: Option<u64>,
+}
+
+/// Moves a file's row out of its directory, so that a new file can take its name
+pub(super) async fn move_to_versions_directory(transaction: &mut mysql_async::Transaction<'_>, uuid: Uuid) -> Result<(), Error> {
+    let query = r#"
+        UPDATE files SET
+            directory_id = (SELECT directory_id FROM versions_directory),
+            name = :versions_name
+            WHERE uuid = :uuid;
+    "#;
+    query
+        .with(params! { "versions_name" => uuid.to_string(), "uuid" => uuid })
+        .ignore(transaction)
+        .await?;
+    Ok(())
+}
+
+/// After `new` was inserted in place of `old`, which was moved to the versions directory: moves the
+/// versions of `old` over to `new`, and then keeps `old` as the newest of them, or deletes its row.
+/// The contents of a deleted row are left for the caller to delete once committed
+pub(super) async fn replace(
+    transaction: &mut mysql_async::Transaction<'_>,
+    old: Uuid,
+    new: Uuid,
+    keep_old: bool,
+) -> Result<(), Error> {
+    let query = "SELECT COALESCE(MAX(version), 0) + 1 FROM file_versions WHERE current_uuid = :old;";
+    let version: Option<u32> = query.with(params! { "old" => old }).first(&mut *transaction).await?;
+
+    "UPDATE file_versions SET current_uuid = :new WHERE current_uuid = :old;"
+        .with(params! { "new" => new, "old" => old })
+        .ignore(&mut *transaction)
+        .await?;
+
+    if keep_old {
+        "INSERT INTO file_versions(uuid, current_uuid, version) VALUES (:old, :new, :version);"
+            .with(params! { "old" => old, "new" => new, "version" => version.unwrap_or(1) })
+            .ignore(&mut *transaction)
+            .await?;
+    } else {
+        "DELETE FROM backup_queue WHERE uuid = :uuid;"
+            .with(params! { "uuid" => old })
+            .ignore(&mut *transaction)
+            .await?;
+        "DELETE FROM files WHERE uuid = :uuid;"
+            .with(params! { "uuid" => old })
+            .ignore(&mut *transaction)
+            .await?;
+    }
+    Ok(())
+}
+
+impl FrontNode {
+    pub(super) fn replacement(&self) -> Replacement {
+        match (&self.versioning, &self.trash) {
+            (Some(_), _) => Replacement::Versioned,
+            (None, Some(_)) => Replacement::Trashed,
+            (None, None) => Replacement::Deleted,
+        }
+    }
+
+    /// The file and its old versions, newest first
+    #[instrument(level = "debug", skip(self))]
+    pub async fn list_versions(&self, uuid: Uuid) -> Result<Vec<FileVersion>, Error> {
+        let size_query = "SELECT size FROM files WHERE uuid = :uuid;";
+        let size: Option<Option<u64>> = size_query.with(params! { "uuid" => uuid }).first(self.pool()?).await?;
+        let Some(size) = size else {
+            return Err(Error::NoSuchFile);
+        };
+
+        let query = r#"
+            SELECT file_versions.uuid, file_versions.version, files.size, UNIX_TIMESTAMP(file_versions.replaced_at)
+                FROM file_versions INNER JOIN files ON files.uuid = file_versions.uuid
+                WHERE file_versions.current_uuid = :uuid
+                ORDER BY file_versions.version DESC;
+        "#;
+        let old: Vec<(Uuid, u32, Option<u64>, u64)> = query.with(params! { "uuid" => uuid }).fetch(self.pool()?).await?;
+
+        let current = FileVersion {
+            version: old.first().map_or(1, |(_, version, _, _)| version + 1),
+            uuid,
+            size,
+            modified: uuid.get_timestamp().map(|timestamp| timestamp.to_unix().0),
+            replaced_at: None,
+        };
+        let old = old.into_iter().map(|(uuid, version, size, replaced_at)| FileVersion {
+            version,
+            uuid,
+            size,
+            modified: uuid.get_timestamp().map(|timestamp| timestamp.to_unix().0),
+            replaced_at: Some(replaced_at),
+        });
+        Ok(std::iter::once(current).chain(old).collect())
+    }
+
+    /// Whether the UUID is an old version of some file, rather than a current file
+    #[instrument(level = "trace", skip(self))]
+    pub async fn is_old_version(&self, uuid: Uuid) -> Result<bool, Error> {
+        let query = "SELECT EXISTS(SELECT * FROM file_versions WHERE uuid = :uuid);";
+        let exists: Option<bool> = query.with(params! { "uuid" => uuid }).first(self.pool()?).await?;
+        Ok(exists.unwrap_or(false))
+    }
+
+    /// Deletes all but the newest `keep` old versions of a file. Failures only leave extra versions
+    /// behind until the next time, so they are logged rather than returned
+    pub(super) async fn trim_versions(&self, uuid: Uuid, keep: u32) {
+        // as large as MySQL takes, as it has no OFFSET without LIMIT
+        let query = r#"
+            SELECT uuid FROM file_versions
+                WHERE current_uuid = :uuid
+                ORDER BY version DESC
+                LIMIT 18446744073709551615 OFFSET :keep;
+        "#;
+        let expired: Result<Vec<Uuid>, Error> = async {
+            Ok(query.with(params! { "uuid" => uuid, "keep" => keep }).fetch(self.pool()?).await?)
+        }.await;
+        let expired = match expired {
+            Ok(expired) => expired,
+            Err(e) => {
+                warn!(?e, %uuid, "Could not look up old versions to delete");
+                return;
+            }
+        };
+        for version in expired {
+            match self.delete_single_file(version).await {
+                Ok(()) => debug!(%version, "Deleted old version"),
+                Err(Error::NoSuchFile) => {}
+                Err(e) => warn!(?e, %version, "Could not delete old version"),
+            }
+        }
+    }
+}
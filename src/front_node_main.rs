@@ -1,289 +1,2933 @@
 #[allow(unused)]
-use tracing::{trace, debug, info, warn, error, instrument};
+use tracing::{trace, debug, info, warn, error, instrument, info_span, Instrument};
 
-use tracing_subscriber::fmt::{self, format::FmtSpan};
-use tracing_subscriber::filter::EnvFilter;
-use tracing_subscriber::prelude::*;
 
 use std::sync::Arc;
 use std::path::PathBuf;
 use std::net::SocketAddr;
+use std::process::ExitCode;
 use clap::Parser;
 
 use axum::{
-    routing::{get, post},
-    extract::{Path, State},
-    response::Response,
-    body::{Bytes, Body},
+    routing::{get, post, put, MethodRouter},
+    extract::{Path, Query, State, Request, FromRequestParts, ConnectInfo, MatchedPath, rejection::PathRejection},
+    response::{Response, IntoResponse},
+    middleware::{self, Next},
+    body::Body,
     Router,
 };
-use http::status::StatusCode;
+use http::{status::StatusCode, HeaderMap, HeaderValue, request::Parts};
+use futures::StreamExt;
 use uuid::Uuid;
 
 mod front_node;
 mod message;
+mod hashing;
+mod fault_injection;
+mod logging;
+mod owned_task;
+mod tls;
+#[cfg_attr(not(feature = "sftp"), allow(unused))]
+mod listing_format;
+#[cfg(any(feature = "dev-mode", test))]
+mod storage_node;
 
-use front_node::tys::Error;
+use front_node::UploadOptions;
+use hashing::HashAlgorithm;
+use front_node::tys::{Error, StorageNodeID};
+use front_node::failover::FailedAttempt;
+use front_node::upload_progress::UploadProgressMap;
+use front_node::http_range::{RangeRequest, if_range_matches, if_none_match_matches};
+use front_node::startup_summary::StartupSummary;
+use front_node::listing_json::{encode_entries, encode_listing, encode_tree, LISTING_BUFFER_ROWS};
+use front_node::concurrency::RouteClass;
+use front_node::access_log::{AccessLogEntry, PendingEntry};
+use front_node::forwarding::{Forwarder, secrets_match, FORWARDED_BY_HEADER, SECRET_HEADER, INSTANCE_HEADER};
+use message::ErrorCode;
 
 #[derive(Parser)]
-struct CLI {
+struct Cli {
     /// Path to config toml file
     #[arg(short='c', long="config-file")]
-    config_file: PathBuf,
+    config_file: Option<PathBuf>,
+
+    #[command(flatten)]
+    log: logging::LogOptions,
+
+    /// Apply pending database schema migrations and exit
+    #[arg(long="migrate-only")]
+    migrate_only: bool,
+
+    /// Run a storage node in-process, generate SFTP host keys and an admin token, and listen on
+    /// localhost. The config file is optional in this mode. Still needs a MariaDB database, by
+    /// default the local server's bnuybase
+    #[cfg(feature = "dev-mode")]
+    #[arg(long="dev-mode")]
+    dev_mode: bool,
+
+    /// Where dev mode keeps stored files and host keys. Defaults to a new temporary directory
+    #[cfg(feature = "dev-mode")]
+    #[arg(long="dev-data-dir", requires="dev_mode")]
+    dev_data_dir: Option<PathBuf>,
 }
 
 #[derive(Clone)]
 struct AppState {
     node: Arc<front_node::FrontNode>,
+    uploads: UploadProgressMap,
+    debug_errors: bool,
+    listing_chunk_bytes: usize,
+    scope_to_home_directories: bool,
+    recursive_listing_max_depth: u32,
+    recursive_listing_max_entries: u64,
+    caching: Arc<front_node::config::DownloadCachingOptions>,
+    forwarder: Arc<Forwarder>,
+    /// None if the /admin routes aren't served
+    admin: Option<Arc<front_node::config::AdminOptions>>,
 }
 
 #[tokio::main]
-async fn main() {
-    tracing_subscriber::registry()
-        .with(
-            fmt::layer()
-                .compact()
-                .with_target(false)
-                .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE)
-        )
-        .with(EnvFilter::from_default_env())
-        .init();
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let _log_guard = match logging::init(&cli.log, true) {
+        Ok(guard) => guard,
+        Err(e) => {
+            eprintln!("Could not set up logging: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut summary = StartupSummary {
+        config_path: cli.config_file.clone().unwrap_or("<dev mode defaults>".into()),
+        #[cfg(feature = "dev-mode")]
+        dev_mode: cli.dev_mode,
+        ..Default::default()
+    };
 
-    let cli = CLI::parse();
+    #[allow(unused_mut)]
+    let mut cfg = match cli.config_file.clone() {
+        Some(path) => front_node::config::Config::read_from_path(path).await,
+        #[cfg(feature = "dev-mode")]
+        None if cli.dev_mode => front_node::dev_mode::default_config(),
+        None => {
+            error!("No config file given. Pass one with --config-file");
+            return ExitCode::FAILURE;
+        }
+    };
 
+    #[cfg(feature = "dev-mode")]
+    let dev_data_dir = match cli.dev_mode {
+        true => Some(front_node::dev_mode::prepare(&mut cfg, cli.dev_data_dir).await),
+        false => None,
+    };
 
-    let cfg = front_node::config::Config::read_from_path(cli.config_file).await;
+    #[cfg(not(feature = "sftp"))]
+    if cfg.sftp_server.is_some() {
+        error!("The config has an [sftp_server] section, but this build has no SFTP support. Remove the section or rebuild with the sftp feature");
+        return ExitCode::FAILURE;
+    }
 
+    let errors = cfg.errors();
+    if !errors.is_empty() {
+        for e in &errors {
+            error!("Invalid config: {e}");
+        }
+        return ExitCode::FAILURE;
+    }
+    if cli.migrate_only {
+        match front_node::migrations::migrate_only(&cfg.database_connection).await {
+            Ok(applied) => info!(?applied, "Schema is up to date"),
+            Err(e) => {
+                error!(?e, "Could not apply migrations");
+                return ExitCode::FAILURE;
+            }
+        }
+        return ExitCode::SUCCESS;
+    }
+    summary.warnings = cfg.warnings();
+    summary.http_listen_addr = cfg.http_server.listen_addr.clone();
 
     let Ok(addr) = cfg.http_server.listen_addr.parse::<SocketAddr>() else {
         error!("Could not parse HTTP address {}. Format must be IP:PORT", cfg.http_server.listen_addr);
-        return;
+        return ExitCode::FAILURE;
     };
 
     debug!("Loaded config. Starting node");
-    let front_node = front_node::FrontNode::start_from_config(&cfg).await.expect("could not start front node");
+    let front_node = match front_node::FrontNode::start_from_config(&cfg, &mut summary).await {
+        Ok(front_node) => front_node,
+        Err(e) => {
+            error!(?e, "Could not start front node");
+            summary.log();
+            return ExitCode::FAILURE;
+        }
+    };
+    #[cfg(feature = "dev-mode")]
+    if let Some(data_dir) = &dev_data_dir {
+        front_node::dev_mode::start_embedded_node(&front_node, data_dir, &mut summary).await;
+    }
     let front_node = Arc::new(front_node);
 
-    info!("Starting SSH server");
+    let grace = std::time::Duration::from_secs(cfg.http_server.shutdown_grace_s);
+    let shutdown = match front_node::shutdown::Shutdown::start(front_node.clone(), grace) {
+        Ok(shutdown) => shutdown,
+        Err(e) => {
+            error!(?e, "Could not listen for shutdown signals");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // dev mode replaces the configured storage nodes with its own
+    #[cfg(feature = "dev-mode")]
+    let reload_path = cli.config_file.filter(|_| dev_data_dir.is_none());
+    #[cfg(not(feature = "dev-mode"))]
+    let reload_path = cli.config_file;
+    if let Some(path) = reload_path {
+        if let Err(e) = front_node::node_monitor::reload_on_sighup(front_node.clone(), path) {
+            warn!(?e, "Could not listen for SIGHUP; storage nodes can't be reloaded without a restart");
+        }
+    }
+
+    front_node::trash::purge_periodically(&front_node);
+    front_node::tiering::migrate_periodically(&front_node);
 
-    // TODO: Grab handle to monitor ssh task status maybe
-    // or create some channel to monitor more than just if it's alive?
-    tokio::task::spawn({
-        let front_node = front_node.clone();
-        async move {
-            front_node::sftp::launch_sftp_server(&cfg.sftp_server, front_node).await;
-            error!("SFTP server shut down. Not restarting.");
+    #[cfg(feature = "sftp")]
+    if let Some(ref sftp_cfg) = cfg.sftp_server {
+        info!("Starting SSH server");
+
+        // TODO: Grab handle to monitor ssh task status maybe
+        // or create some channel to monitor more than just if it's alive?
+        if let Some(sftp_server) = front_node::sftp::bind_sftp_server(sftp_cfg, front_node.clone(), &mut summary).await {
+            tokio::task::spawn(sftp_server.run(shutdown.clone().begun()));
         }
-    });
+    }
+    let state_node = front_node.clone();
 
-    let state = AppState {
-        node: front_node,
-    };
+    let state = AppState::new(front_node, &cfg);
 
     info!("Starting HTTP router.");
-    let router = Router::new()
-        .route("/version", get(|| async {
-            format!("{name} {bin} {ver}", name=env!("CARGO_PKG_NAME"), bin=env!("CARGO_BIN_NAME"), ver=env!("CARGO_PKG_VERSION"))
-        }))
-        .route("/get/file-by-path/*full_path", get(get_file_by_name))
-        .route("/upload/file-by-path/*full_path", post(upload_file))
-        .route("/create/directory-by-path/*full_path", post(create_directory))
-        .route("/list-directory/*full_path", get(list_directory))
-        .route("/list-directory/", get(|state| list_directory(Path("".to_string()), state)))
-        .with_state(state)
-        ;
+    let router = router(state, &mut summary);
 
     let listener = match tokio::net::TcpListener::bind(addr).await {
-        Ok(l) => l,
+        Ok(l) => {
+            summary.http_bound = Some(Ok(()));
+            l
+        }
         Err(e) => {
             error!(%addr, ?e, "Could not bind to HTTP address");
-            return;
+            summary.http_bound = Some(Err(e.to_string()));
+            summary.log();
+            return ExitCode::FAILURE;
         }
     };
 
+    summary.log();
+    #[cfg(feature = "dev-mode")]
+    if dev_data_dir.is_some() {
+        front_node::dev_mode::print_urls(&cfg);
+    }
+
     info!("Front node starting.");
-    axum::serve(listener, router).await.expect("HTTP server failed");
+    axum::serve(listener, router.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown.clone().http_drained(state_node.clone()))
+        .await
+        .expect("HTTP server failed");
+
+    info!("HTTP requests drained");
+    shutdown.drained(&state_node).await;
+    state_node.disconnect().await;
+    info!("Shut down cleanly");
+    ExitCode::SUCCESS
 }
 
-fn error_response(status: StatusCode, message: &str) -> Response {
-    Response::builder()
-        .status(status)
-        .body(Body::from(message.to_string()))
-        .unwrap()
+impl AppState {
+    fn new(node: Arc<front_node::FrontNode>, cfg: &front_node::config::Config) -> AppState {
+        AppState {
+            node,
+            uploads: UploadProgressMap::new(),
+            debug_errors: cfg.http_server.debug_errors,
+            listing_chunk_bytes: cfg.http_server.listing_chunk_bytes,
+            scope_to_home_directories: cfg.http_server.scope_to_home_directories,
+            recursive_listing_max_depth: cfg.http_server.recursive_listing_max_depth,
+            recursive_listing_max_entries: cfg.http_server.recursive_listing_max_entries,
+            caching: Arc::new(cfg.download_caching.clone()),
+            forwarder: Arc::new(Forwarder::new(cfg.cluster.clone())),
+            admin: cfg.admin.clone().map(Arc::new),
+        }
+    }
 }
 
-#[instrument(skip(state))]
-async fn get_file_by_name(
-    Path(full_path): Path<String>,
-    State(state): State<AppState>,
-) -> Response {
-    let uuid = match state.node.file_uuid_for_path(&full_path, None).await {
-        Ok(uuid) => uuid,
-        Err(Error::NoSuchFile) => {
-            debug!("No such file");
-            return error_response(StatusCode::NOT_FOUND, "No such file");
+fn router(state: AppState, summary: &mut StartupSummary) -> Router {
+    let router = Router::new()
+        .route("/version", get(|| async {
+            format!("{name} {bin} {ver}", name=env!("CARGO_PKG_NAME"), bin=env!("CARGO_BIN_NAME"), ver=env!("CARGO_PKG_VERSION"))
+        }))
+        .route("/health", get(health))
+        .route("/metrics", get(metrics))
+        .route("/upload/session", post(new_upload_session))
+        .route("/upload/progress/:upload_id", get(upload_progress));
+    let router = router.route("/get/file-by-uuid/:uuid", get(get_file_by_uuid));
+    let router = router.route("/stat/file-by-uuid/:uuid", get(stat_file_by_uuid));
+    let router = router.route("/get/file-version/:uuid", get(get_file_version));
+    // a path starting with file-by-uuid/ can't be stated this way, as the route above takes it
+    let router = route_with_path(router, "/stat", get(stat_path));
+    let router = route_with_path(router, "/get/file-by-path", get(get_file_by_name));
+    let router = route_with_path(router, "/upload/file-by-path", post(upload_file));
+    let router = route_with_path(router, "/create/directory-by-path", post(create_directory));
+    let router = route_with_path(router, "/move/file-by-path", post(move_file));
+    let router = route_with_path(router, "/delete/directory-by-path", post(delete_directory));
+    let router = route_with_path(router, "/restore/file-by-path", post(restore_file));
+    let router = route_with_path(router, "/versions", get(list_versions));
+    let router = route_with_path(router, "/list-directory", get(list_directory));
+    let router = route_with_path(router, "/list-directory-recursive", get(list_directory_recursive));
+    let router = match state.admin {
+        Some(_) => {
+            summary.features.push("admin routes".to_string());
+            let admin_router = Router::new()
+                .route("/backup", get(backup_status))
+                .route("/rehash", post(rehash_files))
+                .route("/scrub", get(scrub_reports))
+                .route("/scrub/:node_id", post(scrub_node))
+                .route("/users", get(list_users))
+                .route("/users/:name", post(create_user).delete(delete_user))
+                .route("/users/:name/password", put(set_user_password).delete(remove_user_password))
+                .route("/tiers", get(tier_usage));
+            let admin_router = route_with_path(admin_router, "/tier-pins", put(pin_to_tier).delete(unpin_from_tier))
+                .route_layer(middleware::from_fn_with_state(state.clone(), require_admin_token));
+            router.nest("/admin", admin_router)
         }
-        Err(Error::NoSuchDirectory { topmost_existing_directory: _ }) => {
-            debug!("No such directory");
-            return error_response(StatusCode::NOT_FOUND, "No such parent directory");
+        None => router,
+    };
+    router
+        // only requests which reach a route are counted, e.g. not ones rejected by the concurrency limits
+        .route_layer(middleware::from_fn_with_state(state.clone(), record_metrics))
+        .layer(middleware::from_fn_with_state(state.clone(), forward_to_owner))
+        .layer(middleware::from_fn_with_state(state.clone(), limit_concurrency))
+        .layer(middleware::from_fn_with_state(state.clone(), refuse_while_shutting_down))
+        .layer(middleware::from_fn_with_state(state.clone(), log_access))
+        .layer(middleware::from_fn(assign_request_id))
+        .with_state(state)
+}
+
+// Mounts a handler taking a FullPath at `{prefix}/*full_path`, and at `{prefix}/` and `{prefix}` for the
+// root, as the wildcard doesn't match an empty path
+fn route_with_path(router: Router<AppState>, prefix: &str, method_router: MethodRouter<AppState>) -> Router<AppState> {
+    router
+        .route(prefix, method_router.clone())
+        .route(&format!("{prefix}/"), method_router.clone())
+        .route(&format!("{prefix}/*full_path"), method_router)
+}
+
+/// The path following the route prefix, without leading or trailing slashes. Empty for the root
+#[derive(Debug)]
+struct FullPath(String);
+
+#[axum::async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for FullPath {
+    type Rejection = PathRejection;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        // mounted without the wildcard, i.e. the root, there's no full_path. Taking the params as a
+        // map, as a String would need exactly one
+        match Path::<std::collections::HashMap<String, String>>::from_request_parts(parts, state).await {
+            Ok(Path(mut params)) => {
+                let path = params.remove("full_path").unwrap_or_default();
+                Ok(FullPath(path.trim_matches('/').to_string()))
+            }
+            Err(PathRejection::MissingPathParams(_)) => Ok(FullPath(String::new())),
+            Err(e) => Err(e),
         }
-        Err(e) => {
-            error!(?e, "Error finding file");
-            return error_response(StatusCode::INTERNAL_SERVER_ERROR, "Could not find file.");
+    }
+}
+
+/// The directory the request's paths are resolved from. With scope_to_home_directories, the home
+/// of the user whose token the request carries, or the root (None) for the admin token. Without it,
+/// always the root. Looked up once per request
+#[derive(Debug, Clone, Copy)]
+struct Base(Option<front_node::tys::DirectoryID>);
+
+#[axum::async_trait]
+impl FromRequestParts<AppState> for Base {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        if let Some(&base) = parts.extensions.get::<Base>() {
+            return Ok(base);
+        }
+        if !state.scope_to_home_directories {
+            return Ok(Base(None));
         }
-    };
 
-    match state.node.get_file(uuid).await {
-        Ok((data, info)) => {
-            debug!(data.len = data.len(), %info.uuid, info.node_name, "Got file");
-            let uuid_str = info.uuid.as_hyphenated().encode_lower(&mut Uuid::encode_buffer()).to_string();
-            Response::builder()
-                .status(StatusCode::OK)
-                .header("X-File-UUID", uuid_str)
-                .header("X-Node-Name", info.node_name)
-                .body(Body::from(data))
-                .unwrap()
+        let unauthorized = |message| {
+            let mut response = error_response(StatusCode::UNAUTHORIZED, message);
+            response.headers_mut().insert(http::header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            response
+        };
+        let Some(token) = bearer_token(&parts.headers) else {
+            return Err(unauthorized("A user token is required"));
+        };
+        let base = if state.admin.as_ref().is_some_and(|admin| secrets_match(admin.token.as_bytes(), token.as_bytes())) {
+            debug!("Admin token; resolving paths from the root");
+            Base(None)
+        } else {
+            match state.node.user_for_http_token(token).await {
+                Ok(Some((user, home))) => {
+                    debug!(user, ?home, "Resolving paths from the user's home");
+                    parts.extensions.insert(HttpUser(Some(user)));
+                    Base(Some(home))
+                }
+                Ok(None) => {
+                    warn!("Request with an unknown user token");
+                    return Err(unauthorized("Invalid user token"));
+                }
+                Err(e) => {
+                    error!(?e, "Error looking up user token");
+                    return Err(internal_error(state, StatusCode::INTERNAL_SERVER_ERROR, "Error looking up user", &e));
+                }
+            }
+        };
+        parts.extensions.insert(base);
+        Ok(base)
+    }
+}
+
+/// The user whose token the request carries, for their quota and concurrency limit. None for the
+/// admin token, and without scope_to_home_directories. Found by Base
+#[derive(Debug, Clone)]
+struct HttpUser(Option<String>);
+
+#[axum::async_trait]
+impl FromRequestParts<AppState> for HttpUser {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        Base::from_request_parts(parts, state).await?;
+        Ok(parts.extensions.get::<HttpUser>().cloned().unwrap_or(HttpUser(None)))
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers.get(http::header::AUTHORIZATION)
+        .and_then(|authorization| authorization.to_str().ok())
+        .and_then(|authorization| authorization.strip_prefix("Bearer "))
+}
+
+// The extractors below resolve the FullPath, or check the UUID, against the metadata database from
+// the request's Base, and turn failures into responses, so the handlers only see files which exist
+
+/// The file with the UUID in the path. With a user's Base, only files in their home
+#[derive(Debug)]
+struct ScopedUuid(Uuid);
+
+#[axum::async_trait]
+impl FromRequestParts<AppState> for ScopedUuid {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        let Path(uuid) = Path::<Uuid>::from_request_parts(parts, state).await.map_err(IntoResponse::into_response)?;
+        let Base(Some(home)) = Base::from_request_parts(parts, state).await? else {
+            return Ok(ScopedUuid(uuid));
+        };
+        match state.node.file_is_under(uuid, home).await {
+            Ok(true) => Ok(ScopedUuid(uuid)),
+            // as if it didn't exist, so other users' UUIDs can't be probed for
+            Ok(false) => {
+                debug!(%uuid, "File is outside the user's home");
+                Err(error_response(StatusCode::NOT_FOUND, "No such file"))
+            }
+            Err(e) => {
+                error!(?e, %uuid, "Error finding file");
+                Err(internal_error(state, StatusCode::INTERNAL_SERVER_ERROR, "Could not find file", &e))
+            }
         }
-        Err(e) => {
-            error!(?e, "Error reading file");
-            return error_response(StatusCode::INTERNAL_SERVER_ERROR, "Could not read file.");
+    }
+}
+
+/// The file at the FullPath
+#[derive(Debug)]
+struct ResolvedFile(Uuid);
+
+#[axum::async_trait]
+impl FromRequestParts<AppState> for ResolvedFile {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        let FullPath(full_path) = FullPath::from_request_parts(parts, state).await.map_err(IntoResponse::into_response)?;
+        let Base(base) = Base::from_request_parts(parts, state).await?;
+        match state.node.file_uuid_for_path(&full_path, base).await {
+            Ok(uuid) => Ok(ResolvedFile(uuid)),
+            Err(Error::NoSuchFile) => {
+                debug!(full_path, "No such file");
+                Err(error_response(StatusCode::NOT_FOUND, "No such file"))
+            }
+            Err(Error::NoSuchDirectory { topmost_existing_directory: _ }) => {
+                debug!(full_path, "No such directory");
+                Err(error_response(StatusCode::NOT_FOUND, "No such parent directory"))
+            }
+            Err(e) => {
+                error!(?e, full_path, "Error finding file");
+                Err(internal_error(state, StatusCode::INTERNAL_SERVER_ERROR, "Could not find file", &e))
+            }
         }
     }
 }
 
-#[instrument(skip(state, body), fields(body.len = body.len()))]
-async fn upload_file(
-    Path(full_path): Path<String>,
-    State(state): State<AppState>,
-    body: Bytes,
-) -> Response {
-    let (path, file) = full_path.rsplit_once('/')
-        .map(|(path, file)| (path.to_string(), file.to_string()))
-        .unwrap_or(("".to_string(), full_path));
+/// The directory at the FullPath. The empty path is the root directory
+#[derive(Debug)]
+struct ResolvedDirectory(front_node::tys::DirectoryID);
 
-    info!("Uploading file");
+#[axum::async_trait]
+impl FromRequestParts<AppState> for ResolvedDirectory {
+    type Rejection = Response;
 
-    let dir = match state.node.directory_id_for_path(&path, None).await {
-        Ok(id) => id,
-        Err(Error::NoSuchDirectory { topmost_existing_directory: _ }) => {
-            debug!("No such directory");
-            return Response::builder()
-                .status(StatusCode::NOT_FOUND)
-                .body(Body::from("No such directory"))
-                .unwrap();
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        let FullPath(path) = FullPath::from_request_parts(parts, state).await.map_err(IntoResponse::into_response)?;
+        let Base(base) = Base::from_request_parts(parts, state).await?;
+        match state.node.directory_id_for_path(&path, base).await {
+            Ok(id) => Ok(ResolvedDirectory(id)),
+            Err(Error::NoSuchDirectory { topmost_existing_directory: _ }) => {
+                debug!(path, "No such directory");
+                Err(error_response(StatusCode::NOT_FOUND, "No such directory"))
+            }
+            Err(e) => {
+                error!(?e, path, "Error finding directory");
+                Err(internal_error(state, StatusCode::INTERNAL_SERVER_ERROR, "Error finding directory", &e))
+            }
         }
-        Err(e) => {
-            error!(?e, "Error finding directory");
-            return Response::builder()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Body::from(format!("Error finding directory")))
-                .unwrap();
+    }
+}
+
+/// What a ParentAndName is about to create, which decides its error messages
+trait NewEntry: Send {
+    const NO_NAME: &'static str;
+    const NO_PARENT: &'static str;
+    const PARENT_ERROR: &'static str;
+}
+
+#[derive(Debug)]
+enum NewFile {}
+
+impl NewEntry for NewFile {
+    const NO_NAME: &'static str = "No file name given";
+    const NO_PARENT: &'static str = "No such directory";
+    const PARENT_ERROR: &'static str = "Error finding directory";
+}
+
+#[derive(Debug)]
+enum NewDirectory {}
+
+impl NewEntry for NewDirectory {
+    const NO_NAME: &'static str = "No directory name given";
+    const NO_PARENT: &'static str = "No parent directory";
+    const PARENT_ERROR: &'static str = "Error finding parent";
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ParentOptions {
+    /// create the parent directory and any missing directories above it, like mkdir -p
+    #[serde(default)]
+    create_parents: bool,
+}
+
+/// The FullPath split into a parent directory and a non-empty name, for creating something new. The
+/// name itself is not checked for existence. With ?create_parents=true the parent may be missing, and
+/// is only created with Parent::get_or_create, so that nothing is created for requests which are
+/// refused
+#[derive(Debug)]
+struct ParentAndName<E: NewEntry>(Parent, String, std::marker::PhantomData<E>);
+
+#[derive(Debug)]
+enum Parent {
+    Existing(front_node::tys::DirectoryID),
+    /// `path` is what is missing, from `deepest`, the deepest directory of the parent path which exists
+    Missing { deepest: front_node::tys::DirectoryID, path: String },
+}
+
+impl Parent {
+    /// The parent if it exists, or else the deepest directory above it which does. Whatever applies
+    /// to the parent through its ancestors, like upload policies, applies to that one too
+    fn deepest(&self) -> front_node::tys::DirectoryID {
+        match *self {
+            Parent::Existing(dir) => dir,
+            Parent::Missing { deepest, .. } => deepest,
         }
-    };
+    }
 
-    match state.node.upload_file(file, dir, body.to_vec()).await {
-        Ok(uuid) => {
-            let uuid_str = uuid.as_hyphenated().encode_lower(&mut Uuid::encode_buffer()).to_string();
-            info!(uuid_str, "File uploaded");
-            Response::builder()
-                .status(StatusCode::OK)
-                .header("X-File-UUID", uuid_str)
-                .body(Body::from("upload successful"))
-                .unwrap()
+    /// The parent, created first with its missing ancestors if it doesn't exist
+    async fn get_or_create<E: NewEntry>(self, state: &AppState) -> Result<front_node::tys::DirectoryID, Response> {
+        let Parent::Missing { deepest, path } = self else {
+            return Ok(self.deepest());
+        };
+        state.node.create_directories(&path, Some(deepest)).await
+            .map_err(|e| parent_error::<E>(state, e, &path))
+    }
+}
+
+fn parent_error<E: NewEntry>(state: &AppState, e: Error, parent_path: &str) -> Response {
+    match e {
+        Error::NoSuchDirectory { topmost_existing_directory: _ } => {
+            debug!(parent_path, "No parent directory");
+            error_response(StatusCode::NOT_FOUND, E::NO_PARENT)
         }
-        Err(e) => {
-            error!(?e, "Error uploading file");
-            Response::builder()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Body::from(format!("Error finding file")))
-                .unwrap()
+        e => {
+            error!(?e, parent_path, "Error finding parent");
+            internal_error(state, StatusCode::INTERNAL_SERVER_ERROR, E::PARENT_ERROR, &e)
         }
     }
 }
 
-#[instrument(skip(state))]
-async fn create_directory(
-    Path(full_path): Path<String>,
-    State(state): State<AppState>,
-) -> Response {
-    let (parent_path, dir) = full_path.rsplit_once('/')
-        .map(|(parent, dir)| (parent.to_string(), dir.to_string()))
-        .unwrap_or(("".to_string(), full_path));
+#[axum::async_trait]
+impl<E: NewEntry> FromRequestParts<AppState> for ParentAndName<E> {
+    type Rejection = Response;
 
-    info!(parent_path, dir, "Creating directory");
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        let FullPath(full_path) = FullPath::from_request_parts(parts, state).await.map_err(IntoResponse::into_response)?;
+        let (parent_path, name) = full_path.rsplit_once('/')
+            .map(|(parent, name)| (parent.to_string(), name.to_string()))
+            .unwrap_or(("".to_string(), full_path));
+        if name.is_empty() {
+            return Err(error_response(StatusCode::BAD_REQUEST, E::NO_NAME));
+        }
 
-    let parent = match state.node.directory_id_for_path(&parent_path, None).await {
-        Ok(id) => id,
-        Err(Error::NoSuchDirectory { topmost_existing_directory: _ }) => {
-            debug!("No parent directory");
-            return Response::builder()
-                .status(StatusCode::NOT_FOUND)
-                .body(Body::from("No parent directory"))
-                .unwrap();
+        let Base(base) = Base::from_request_parts(parts, state).await?;
+        let Query(ParentOptions { create_parents }) = Query::from_request_parts(parts, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        if create_parents && !parent_path.is_empty() && parent_path.split('/').any(str::is_empty) {
+            return Err(error_response(StatusCode::BAD_REQUEST, "Directory names can't be empty"));
         }
-        Err(e) => {
-            error!(?e, "Error finding parent");
-            return Response::builder()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Body::from(format!("Error finding parent")))
-                .unwrap();
+        let topmost_existing_directory = match state.node.directory_id_for_path(&parent_path, base).await {
+            Ok(id) => return Ok(ParentAndName(Parent::Existing(id), name, std::marker::PhantomData)),
+            Err(Error::NoSuchDirectory { topmost_existing_directory }) if create_parents => topmost_existing_directory,
+            Err(e) => return Err(parent_error::<E>(state, e, &parent_path)),
+        };
+        let existing_path = topmost_existing_directory.strip_suffix('/').unwrap_or_default();
+        match state.node.directory_id_for_path(existing_path, base).await {
+            Ok(deepest) => {
+                let path = parent_path[topmost_existing_directory.len()..].to_string();
+                Ok(ParentAndName(Parent::Missing { deepest, path }, name, std::marker::PhantomData))
+            }
+            Err(e) => Err(parent_error::<E>(state, e, &parent_path)),
         }
+    }
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response {
+    Response::builder()
+        .status(status)
+        .body(Body::from(message.to_string()))
+        .unwrap()
+}
+
+tokio::task_local! {
+    static REQUEST_ID: Uuid;
+}
+
+// Gives every request an ID, which is attached to all log lines for the request and sent back in X-Request-Id
+async fn assign_request_id(request: Request, next: Next) -> Response {
+    let request_id = Uuid::now_v7();
+    let span = info_span!("request", %request_id);
+
+    let mut response = REQUEST_ID.scope(request_id, next.run(request))
+        .instrument(span)
+        .await;
+
+    let request_id_str = request_id.as_hyphenated().encode_lower(&mut Uuid::encode_buffer()).to_string();
+    response.headers_mut().insert("X-Request-Id", HeaderValue::from_str(&request_id_str).unwrap());
+    response
+}
+
+/// Sent with 429 responses. Concurrency limits free up as soon as a request finishes, so this is short
+const RETRY_AFTER_S: u64 = 1;
+
+fn route_class(path: &str) -> Option<RouteClass> {
+    if path.starts_with("/get/") || path.starts_with("/stat/") {
+        Some(RouteClass::Reads)
+    } else if ["/upload/file-by-path", "/create/", "/move/", "/delete/", "/restore/"].iter().any(|prefix| path.starts_with(prefix)) {
+        Some(RouteClass::Writes)
+    } else if path.starts_with("/list-directory") || path.starts_with("/versions") {
+        Some(RouteClass::Listings)
+    } else {
+        None
+    }
+}
+
+// Once shutdown has begun, requests in flight get to finish, but new ones are refused, except for
+// /health, which then has the drain report, and /metrics
+async fn refuse_while_shutting_down(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    if state.node.shutdown_remaining().is_none() || ["/health", "/metrics"].contains(&request.uri().path()) {
+        return next.run(request).await;
+    }
+    Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .header(http::header::CONNECTION, "close")
+        .body(Body::from("Shutting down"))
+        .unwrap()
+}
+
+// Rejects requests over the route class' concurrency limit, or over their user's, which their SFTP
+// sessions count towards too. The permits are held until the response body has been sent, as
+// listings and downloads are streamed
+async fn limit_concurrency(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let Some(class) = route_class(request.uri().path()) else {
+        return next.run(request).await;
+    };
+    let too_many_requests = || Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .header(http::header::RETRY_AFTER, RETRY_AFTER_S)
+        .body(Body::from("Too many concurrent requests, try again later"))
+        .unwrap();
+    let Some(route_permit) = state.node.limits.try_acquire_route(class) else {
+        return too_many_requests();
     };
 
-    match state.node.create_directory(parent, dir).await {
-        Ok(()) => {
-            Response::builder()
-                .status(StatusCode::OK)
-                .body(Body::from("create successful"))
-                .unwrap()
+    let (mut parts, body) = request.into_parts();
+    let user_permit = match HttpUser::from_request_parts(&mut parts, &state).await {
+        Ok(HttpUser(Some(user))) => match state.node.limits.try_acquire_user(&user) {
+            Some(permit) => Some(permit),
+            None => return too_many_requests(),
+        },
+        Ok(HttpUser(None)) => None,
+        Err(rejection) => return rejection,
+    };
+    let request = Request::from_parts(parts, body);
+
+    let (parts, body) = next.run(request).await.into_parts();
+    let body = body.into_data_stream().map(move |chunk| {
+        let _permits = (&route_permit, &user_permit);
+        chunk
+    });
+    Response::from_parts(parts, Body::from_stream(body))
+}
+
+// The upload session a request belongs to, if any
+fn session_token(request: &Request) -> Option<&str> {
+    let path = request.uri().path();
+    if let Some(token) = path.strip_prefix("/upload/progress/") {
+        Some(token)
+    } else if path.starts_with("/upload/file-by-path") {
+        request.headers().get("X-Upload-Id").and_then(|v| v.to_str().ok())
+    } else {
+        None
+    }
+}
+
+// Proxies requests for upload sessions owned by another front node to that node. If the owner can't
+// be reached, the session is lost, and the client is told to start a new one
+async fn forward_to_owner(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let forwarder = &state.forwarder;
+    let forwarded_by = request.headers().get(FORWARDED_BY_HEADER).cloned();
+    let authenticated = forwarded_by.is_none() || forwarder.is_authenticated(request.headers().get(SECRET_HEADER));
+
+    let owner = session_token(&request)
+        .and_then(|token| forwarder.remote_owner(token))
+        .map(|owner| owner.to_string());
+    let mut response = match owner {
+        _ if !authenticated => {
+            warn!(?forwarded_by, "Forwarded request without the cluster secret");
+            error_response(StatusCode::FORBIDDEN, "Invalid cluster secret")
         }
-        Err(e) => {
-            error!(?e, "Error creating directory");
-            Response::builder()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Body::from(format!("Error creating directory: {e:?}")))
-                .unwrap()
+        None => next.run(request).await,
+        Some(owner) if forwarded_by.is_some() => {
+            error!(owner, ?forwarded_by, "Got a forwarded request for a session owned by another node; not forwarding again");
+            error_response(StatusCode::LOOP_DETECTED, "Upload session was forwarded to the wrong front node")
+        }
+        Some(owner) => match forwarder.forward(&owner, request).await {
+            Ok(response) => response,
+            Err(e) => {
+                warn!(?e, owner, "Could not forward request to the session's owner");
+                error_response(StatusCode::CONFLICT, &format!(
+                    "Upload session belongs to front node {owner}, which can't be reached. Start a new upload session",
+                ))
+            }
+        },
+    };
+
+    // a forwarded response already names the owner
+    if !response.headers().contains_key(INSTANCE_HEADER) {
+        if let Ok(instance) = HeaderValue::from_str(forwarder.instance_id()) {
+            response.headers_mut().insert(INSTANCE_HEADER, instance);
         }
     }
+    response
 }
 
-#[instrument(skip(state))]
-async fn list_directory(
-    Path(path): Path<String>,
-    State(state): State<AppState>,
-) -> Response {
-    debug!(path, "Listing directory contents.");
+// Whether the request carries the admin token. Never if the admin routes aren't enabled
+fn has_admin_token(state: &AppState, headers: &HeaderMap) -> bool {
+    match (&state.admin, bearer_token(headers)) {
+        (Some(admin), Some(token)) => secrets_match(admin.token.as_bytes(), token.as_bytes()),
+        _ => false,
+    }
+}
 
-    let dir = match state.node.directory_id_for_path(&path, None).await {
-        Ok(id) => id,
-        Err(Error::NoSuchDirectory { topmost_existing_directory: _ }) => {
-            debug!("No such directory");
-            return Response::builder()
-                .status(StatusCode::NOT_FOUND)
-                .body(Body::from("No such directory"))
-                .unwrap();
+// Refuses requests without the admin token, for the /admin routes
+async fn require_admin_token(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let token = bearer_token(request.headers());
+    if !has_admin_token(&state, request.headers()) {
+        warn!(has_token = token.is_some(), "Admin request without a valid token");
+        let mut response = error_response(StatusCode::UNAUTHORIZED, "Invalid admin token");
+        response.headers_mut().insert(http::header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        return response;
+    }
+    next.run(request).await
+}
+
+// Writes a line to the access log once the response body has been sent
+async fn log_access(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let started = std::time::Instant::now();
+    let entry = AccessLogEntry {
+        time: std::time::SystemTime::now(),
+        client: request.extensions().get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(addr)| addr.ip()),
+        // user tokens are only looked up further in, see Base
+        user: None,
+        method: request.method().to_string(),
+        path: request.uri().path().to_string(),
+        protocol: format!("{:?}", request.version()),
+        status: 0,
+        bytes: 0,
+        duration: std::time::Duration::ZERO,
+    };
+
+    let response = next.run(request).await;
+    let mut entry = PendingEntry::new(state.node.access_log.clone(), AccessLogEntry { status: response.status().as_u16(), ..entry }, started);
+
+    let (parts, body) = response.into_parts();
+    let body = body.into_data_stream().map(move |chunk| {
+        if let Ok(ref chunk) = chunk {
+            entry.add_bytes(chunk.len() as u64);
         }
-        Err(e) => {
-            error!(?e, "Error finding parent");
-            return Response::builder()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Body::from(format!("Error finding directory")))
-                .unwrap();
+        chunk
+    });
+    Response::from_parts(parts, Body::from_stream(body))
+}
+
+// Counts the request, and its body bytes both ways, under the route it matched
+async fn record_metrics(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let started = std::time::Instant::now();
+    let route = match request.extensions().get::<MatchedPath>() {
+        Some(matched) => matched.as_str().to_string(),
+        None => "unmatched".to_string(),
+    };
+    let metrics = state.node.metrics.route(&route);
+
+    let (parts, body) = request.into_parts();
+    let request_metrics = metrics.clone();
+    let body = body.into_data_stream().map(move |chunk| {
+        if let Ok(ref chunk) = chunk {
+            request_metrics.request_bytes.fetch_add(chunk.len() as u64, std::sync::atomic::Ordering::Relaxed);
+        }
+        chunk
+    });
+    let response = next.run(Request::from_parts(parts, Body::from_stream(body))).await;
+    metrics.count_response(response.status().as_u16());
+    metrics.duration.observe(started.elapsed());
+
+    let (parts, body) = response.into_parts();
+    let body = body.into_data_stream().map(move |chunk| {
+        if let Ok(ref chunk) = chunk {
+            metrics.response_bytes.fetch_add(chunk.len() as u64, std::sync::atomic::Ordering::Relaxed);
         }
+        chunk
+    });
+    Response::from_parts(parts, Body::from_stream(body))
+}
+
+#[derive(serde::Serialize)]
+struct InternalErrorBody<'a> {
+    error: &'a str,
+    /// the request ID, so the error can be found in the logs
+    incident_id: Option<Uuid>,
+    /// only included with http_server.debug_errors, as it can include internal details
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+    /// for operations which were retried, why each attempt failed
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    attempts: Vec<String>,
+    /// why the storage node refused an operation which wasn't retried
+    #[serde(skip_serializing_if = "Option::is_none")]
+    storage_node_error: Option<String>,
+}
+
+// The caller is responsible for logging the error
+fn internal_error(state: &AppState, status: StatusCode, message: &str, detail: &dyn std::fmt::Debug) -> Response {
+    let body = InternalErrorBody {
+        error: message,
+        incident_id: REQUEST_ID.try_with(|id| *id).ok(),
+        detail: state.debug_errors.then(|| format!("{detail:?}")),
+        attempts: Vec::new(),
+        storage_node_error: None,
+    };
+    (status, axum::Json(body)).into_response()
+}
+
+// For an Error::StorageNode which wasn't retried. The node's reason is included, as it's
+// about the request rather than internal. 503 if the node is shutting down, otherwise 502
+fn storage_node_failed(message: &str, code: ErrorCode, detail: &str) -> Response {
+    let status = match code {
+        ErrorCode::ShuttingDown => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::BAD_GATEWAY,
+    };
+    let body = InternalErrorBody {
+        error: message,
+        incident_id: REQUEST_ID.try_with(|id| *id).ok(),
+        detail: None,
+        attempts: Vec::new(),
+        storage_node_error: Some(format!("{code:?} error: {detail}")),
+    };
+    (status, axum::Json(body)).into_response()
+}
+
+// For Error::AllAttemptsFailed. 503 if no node could be reached at all, 504 if every attempt timed
+// out, 507 if every node was out of space, 502 if some node failed
+fn attempts_failed(message: &str, attempts: &[FailedAttempt]) -> Response {
+    let unreachable = attempts.iter().all(|attempt| matches!(attempt.error, Error::NotConnectedToNode));
+    let timed_out = attempts.iter().all(FailedAttempt::timed_out);
+    let out_of_space = attempts.iter().all(|attempt| {
+        matches!(attempt.error, Error::StorageNode { code: ErrorCode::NoSpace, .. })
+    });
+    let status = match (unreachable, timed_out, out_of_space) {
+        (true, _, _) => StatusCode::SERVICE_UNAVAILABLE,
+        (false, true, _) => StatusCode::GATEWAY_TIMEOUT,
+        (false, false, true) => StatusCode::INSUFFICIENT_STORAGE,
+        (false, false, false) => StatusCode::BAD_GATEWAY,
+    };
+    let body = InternalErrorBody {
+        error: message,
+        incident_id: REQUEST_ID.try_with(|id| *id).ok(),
+        detail: None,
+        attempts: attempts.iter().map(ToString::to_string).collect(),
+        storage_node_error: None,
     };
+    (status, axum::Json(body)).into_response()
+}
 
-    match state.node.list_directory(dir).await {
-        Ok(list) => {
-            use axum::response::IntoResponse;
-            (StatusCode::OK, axum::Json(list)).into_response()
+#[instrument(skip(state, headers))]
+async fn get_file_by_name(
+    ResolvedFile(uuid): ResolvedFile,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Response {
+    if state.caching.redirect_paths_to_uuids {
+        let uuid_str = uuid.as_hyphenated().encode_lower(&mut Uuid::encode_buffer()).to_string();
+        debug!(uuid_str, "Redirecting to download by UUID");
+        let mut response = Response::builder()
+            .status(StatusCode::FOUND)
+            .header(http::header::LOCATION, format!("/get/file-by-uuid/{uuid_str}"));
+        if state.caching.enabled {
+            // the path may point to another file later
+            response = response.header(http::header::CACHE_CONTROL, "no-cache");
         }
+        return response.body(Body::empty()).unwrap();
+    }
+
+    // the path may point to another file later, so caches have to revalidate with the ETag
+    let cache_control = state.caching.enabled.then(|| "no-cache".to_string());
+    file_response(&state, uuid, &headers, cache_control).await
+}
+
+#[instrument(skip(state, headers))]
+async fn get_file_by_uuid(
+    ScopedUuid(uuid): ScopedUuid,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Response {
+    // the contents of a UUID never change
+    let cache_control = state.caching.enabled
+        .then(|| format!("public, max-age={}, immutable", state.caching.immutable_max_age_s));
+    file_response(&state, uuid, &headers, cache_control).await
+}
+
+// Like get_file_by_uuid, but only for old versions of files, see list_versions
+#[instrument(skip(state))]
+async fn get_file_version(
+    ScopedUuid(uuid): ScopedUuid,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Response {
+    match state.node.is_old_version(uuid).await {
+        Ok(true) => {}
+        Ok(false) => return error_response(StatusCode::NOT_FOUND, "No such old version"),
         Err(e) => {
-            error!(?e, "Error listing directory");
-            Response::builder()
-                .status(500)
-                .body(Body::from(format!("Error finding file: {e:?}")))
-                .unwrap()
+            error!(?e, "Error looking up version");
+            return internal_error(&state, StatusCode::INTERNAL_SERVER_ERROR, "Error looking up version", &e);
         }
     }
+    let cache_control = state.caching.enabled
+        .then(|| format!("public, max-age={}, immutable", state.caching.immutable_max_age_s));
+    file_response(&state, uuid, &headers, cache_control).await
 }
 
+// The file at the path and its old versions as a JSON list, newest first, see FileVersion
+#[instrument(skip(state))]
+async fn list_versions(
+    ResolvedFile(uuid): ResolvedFile,
+    State(state): State<AppState>,
+) -> Response {
+    match state.node.list_versions(uuid).await {
+        Ok(versions) => (StatusCode::OK, axum::Json(versions)).into_response(),
+        // deleted since the path was resolved
+        Err(Error::NoSuchFile) => error_response(StatusCode::NOT_FOUND, "No such file"),
+        Err(e) => {
+            error!(?e, "Error listing versions");
+            internal_error(&state, StatusCode::INTERNAL_SERVER_ERROR, "Error listing versions", &e)
+        }
+    }
+}
+
+// The file's metadata as JSON, see FileStat. Only the database is asked, so this works while the
+// file's storage node is down
+#[instrument(skip(state))]
+async fn stat_file_by_uuid(
+    ScopedUuid(uuid): ScopedUuid,
+    State(state): State<AppState>,
+) -> Response {
+    match state.node.file_stat(uuid).await {
+        Ok(stat) => (StatusCode::OK, [("X-Node-Name", stat.node_name.clone())], axum::Json(stat)).into_response(),
+        Err(Error::UnknownUUID) => {
+            debug!("No such file");
+            error_response(StatusCode::NOT_FOUND, "No such file")
+        }
+        Err(e) => {
+            error!(?e, "Error looking up file");
+            internal_error(&state, StatusCode::INTERNAL_SERVER_ERROR, "Error looking up file", &e)
+        }
+    }
+}
+
+// What is at the path as JSON, see PathStat. A missing path is a 404 saying how far it resolved
+#[instrument(skip(state))]
+async fn stat_path(
+    FullPath(path): FullPath,
+    Base(base): Base,
+    State(state): State<AppState>,
+) -> Response {
+    match state.node.stat_path(&path, base).await {
+        Ok(stat) => (StatusCode::OK, axum::Json(stat)).into_response(),
+        Err(Error::NoSuchDirectory { topmost_existing_directory }) => {
+            debug!(topmost_existing_directory, "Nothing at path");
+            let body = serde_json::json!({
+                "error": "No such file or directory",
+                "topmost_existing_directory": topmost_existing_directory,
+            });
+            (StatusCode::NOT_FOUND, axum::Json(body)).into_response()
+        }
+        // deleted since the path was resolved
+        Err(Error::UnknownUUID) => error_response(StatusCode::NOT_FOUND, "No such file or directory"),
+        Err(e) => {
+            error!(?e, "Error looking up path");
+            internal_error(&state, StatusCode::INTERNAL_SERVER_ERROR, "Error looking up path", &e)
+        }
+    }
+}
+
+// Sends the file, or the range of it asked for. Answers 304 without reading the file if the client
+// already has it
+async fn file_response(state: &AppState, uuid: Uuid, headers: &HeaderMap, cache_control: Option<String>) -> Response {
+    let uuid_str = uuid.as_hyphenated().encode_lower(&mut Uuid::encode_buffer()).to_string();
+    // the contents of a UUID never change, so the UUID works as a strong validator
+    let etag = format!("\"{uuid_str}\"");
+
+    let mut response = Response::builder()
+        .header("X-File-UUID", uuid_str.clone())
+        .header(http::header::ETAG, etag.clone())
+        // browsers would otherwise second-guess guessed content types
+        .header(http::header::X_CONTENT_TYPE_OPTIONS, "nosniff");
+    if let Some(cache_control) = cache_control {
+        response = response.header(http::header::CACHE_CONTROL, cache_control);
+    }
+
+    let if_none_match = headers.get(http::header::IF_NONE_MATCH).and_then(|v| v.to_str().ok());
+    if if_none_match.is_some_and(|if_none_match| if_none_match_matches(if_none_match, &etag)) {
+        // ScopedUuid only looks the file up for users, and a deleted file mustn't stay cached
+        match state.node.file_size(uuid).await {
+            Ok(_) => {}
+            Err(Error::NoSuchFile) => {
+                debug!(etag, "Client has a file which doesn't exist");
+                return error_response(StatusCode::NOT_FOUND, "No such file");
+            }
+            Err(e) => {
+                error!(?e, "Error finding file");
+                return internal_error(state, StatusCode::INTERNAL_SERVER_ERROR, "Could not find file", &e);
+            }
+        }
+        debug!(etag, "Client has the file already");
+        // not Body::empty(), which axum would send with Content-Length: 0. A 304 may only have the
+        // length of the file, and caches take it as such
+        return response
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::from_stream(futures::stream::empty::<Result<axum::body::Bytes, std::io::Error>>()))
+            .unwrap();
+    }
+
+    let range_header = requested_range(headers, &etag);
+    let response = response.header(http::header::ACCEPT_RANGES, "bytes");
+
+    // the range is resolved with the stored size, so only the range is read from the storage node
+    let (start, len) = match range_header {
+        None => (0, None),
+        Some(range_header) => match state.node.file_size(uuid).await {
+            Ok(Some(file_size)) => match RangeRequest::parse(range_header, file_size) {
+                RangeRequest::Full => (0, None),
+                RangeRequest::Partial { start, end } => (start, Some(end - start + 1)),
+                RangeRequest::Unsatisfiable => return range_not_satisfiable(response, file_size),
+            },
+            _ => return buffered_file_response(state, uuid, range_header, response).await,
+        },
+    };
+
+    match state.node.stream_file(uuid, start, len).await {
+        Ok((stream, info)) => {
+            debug!(%info.uuid, info.node_name, info.file_size, "Streaming file");
+            let mut response = response
+                .header("X-Node-Name", info.node_name)
+                .header(http::header::CONTENT_TYPE, info.content_type);
+            if let Some(sha256) = info.sha256 {
+                response = response.header("X-Content-SHA256", sha256);
+            }
+            let body = Body::from_stream(stream.map(|chunk| chunk.map_err(|e| {
+                // the status is already sent, so all that can be done is cutting the body short
+                error!(?e, "Could not read file after starting to send it");
+                std::io::Error::other(format!("{e:?}"))
+            })));
+
+            match len {
+                None => {
+                    response
+                        .status(StatusCode::OK)
+                        .header(http::header::CONTENT_LENGTH, info.file_size)
+                        .body(body)
+                        .unwrap()
+                }
+                Some(len) if start < info.file_size => {
+                    partial_content(response, start, len.min(info.file_size - start), info.file_size, body)
+                }
+                // the file is shorter than the stored size says
+                Some(_) => range_not_satisfiable(response, info.file_size),
+            }
+        }
+        Err(Error::ReadPastEnd { file_size }) => range_not_satisfiable(response, file_size),
+        Err(e) => read_error(state, e),
+    }
+}
+
+// The Range header to answer, if any. If-Range makes it conditional on the client's partial copy
+// being of this file, and otherwise the whole file is sent
+fn requested_range<'a>(headers: &'a HeaderMap, etag: &str) -> Option<&'a str> {
+    let range_header = headers.get(http::header::RANGE).and_then(|v| v.to_str().ok())?;
+    match headers.get(http::header::IF_RANGE).and_then(|v| v.to_str().ok()) {
+        Some(if_range) if !if_range_matches(if_range, etag) => {
+            debug!(if_range, etag, "If-Range does not match; sending the whole file");
+            None
+        }
+        _ => Some(range_header),
+    }
+}
+
+// For range requests on files uploaded before sizes were stored. These are read whole, to resolve
+// the range against their size
+async fn buffered_file_response(
+    state: &AppState,
+    uuid: Uuid,
+    range_header: &str,
+    response: http::response::Builder,
+) -> Response {
+    match state.node.get_file(uuid).await {
+        Ok((data, info)) => {
+            debug!(data.len = data.len(), %info.uuid, info.node_name, "Got file");
+            let mut response = response
+                .header("X-Node-Name", info.node_name)
+                .header(http::header::CONTENT_TYPE, info.content_type);
+            if let Some(sha256) = info.sha256 {
+                response = response.header("X-Content-SHA256", sha256);
+            }
+            let file_size = data.len() as u64;
+
+            match RangeRequest::parse(range_header, file_size) {
+                RangeRequest::Full => {
+                    response
+                        .status(StatusCode::OK)
+                        .header(http::header::CONTENT_LENGTH, data.len())
+                        .body(Body::from(data))
+                        .unwrap()
+                }
+                RangeRequest::Partial { start, end } => {
+                    let part = data[start as usize..=end as usize].to_vec();
+                    partial_content(response, start, end - start + 1, file_size, Body::from(part))
+                }
+                RangeRequest::Unsatisfiable => range_not_satisfiable(response, file_size),
+            }
+        }
+        Err(e) => read_error(state, e),
+    }
+}
+
+fn partial_content(response: http::response::Builder, start: u64, len: u64, file_size: u64, body: Body) -> Response {
+    let end = start + len - 1;
+    debug!(start, end, "Sending partial content");
+    response
+        .status(StatusCode::PARTIAL_CONTENT)
+        .header(http::header::CONTENT_RANGE, format!("bytes {start}-{end}/{file_size}"))
+        .header(http::header::CONTENT_LENGTH, len)
+        .body(body)
+        .unwrap()
+}
+
+fn range_not_satisfiable(response: http::response::Builder, file_size: u64) -> Response {
+    debug!("Unsatisfiable range");
+    response
+        .status(StatusCode::RANGE_NOT_SATISFIABLE)
+        .header(http::header::CONTENT_RANGE, format!("bytes */{file_size}"))
+        .body(Body::empty())
+        .unwrap()
+}
+
+fn read_error(state: &AppState, e: Error) -> Response {
+    match e {
+        Error::UnknownUUID => {
+            debug!("No such file");
+            error_response(StatusCode::NOT_FOUND, "No such file")
+        }
+        Error::AllAttemptsFailed(attempts) => {
+            error!(attempts = ?attempts.iter().map(ToString::to_string).collect::<Vec<_>>(), "Could not read file from any node");
+            attempts_failed("Could not read file", &attempts)
+        }
+        Error::StorageNode { code, detail } => {
+            error!(?code, detail, "Storage node could not read file");
+            storage_node_failed("Could not read file", code, &detail)
+        }
+        e => {
+            error!(?e, "Error reading file");
+            internal_error(state, StatusCode::INTERNAL_SERVER_ERROR, "Could not read file", &e)
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct UploadQuery {
+    /// replace a file with the same name. otherwise, uploading to a taken name is a 409
+    #[serde(default)]
+    overwrite: bool,
+}
+
+#[instrument(skip(state, headers, body))]
+async fn upload_file(
+    ParentAndName(parent, file, _): ParentAndName<NewFile>,
+    HttpUser(user): HttpUser,
+    Query(UploadQuery { overwrite }): Query<UploadQuery>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    info!("Uploading file");
+
+    let expected_bytes: Option<u64> = headers.get(http::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok());
+    let content_type = headers.get(http::header::CONTENT_TYPE).and_then(|v| v.to_str().ok());
+
+    let verify = match headers.get("X-Verify").map(|v| v.as_bytes()) {
+        Some(b"true") => true,
+        Some(b"false") => false,
+        Some(_) => {
+            return error_response(StatusCode::BAD_REQUEST, "X-Verify must be true or false");
+        }
+        None => state.node.verify_uploads_by_default,
+    };
+    let mut options = UploadOptions { content_type, verify, overwrite, progress: None, user: user.as_deref() };
+
+    // everything that can be checked without the body is checked before reading it. clients
+    // sending Expect: 100-continue only get the 100 once the body is read, so they are told about
+    // failures here without sending the body at all
+    let parent_exists = matches!(parent, Parent::Existing(_));
+    if let Err(e) = state.node.check_upload(&file, parent.deepest(), parent_exists, expected_bytes, options).await {
+        return upload_error(&state, e);
+    }
+    let dir = match parent.get_or_create::<NewFile>(&state).await {
+        Ok(dir) => dir,
+        Err(response) => return response,
+    };
+
+    let progress = match headers.get("X-Upload-Id").map(|v| v.to_str()) {
+        Some(Ok(upload_id)) => Some(state.uploads.start(upload_id.to_string(), expected_bytes).await),
+        Some(Err(_)) => {
+            return error_response(StatusCode::BAD_REQUEST, "X-Upload-Id must be ASCII");
+        }
+        None => None,
+    };
+
+    options.progress = progress.as_ref();
+    // sent on to the storage node as it arrives
+    let body = match progress {
+        Some(ref progress) => progress.track(body.into_data_stream()).left_stream(),
+        None => body.into_data_stream().right_stream(),
+    };
+    let result = state.node.upload_stream(file, dir, expected_bytes, body, options).await;
+    if let Some(progress) = progress {
+        progress.finish(result.is_ok()).await;
+    }
+
+    match result {
+        Ok(uuid) => {
+            let uuid_str = uuid.as_hyphenated().encode_lower(&mut Uuid::encode_buffer()).to_string();
+            info!(uuid_str, "File uploaded");
+            Response::builder()
+                .status(StatusCode::OK)
+                .header("X-File-UUID", uuid_str)
+                .body(Body::from("upload successful"))
+                .unwrap()
+        }
+        Err(e) => upload_error(&state, e),
+    }
+}
+
+fn upload_error(state: &AppState, e: Error) -> Response {
+    match e {
+        Error::PolicyViolation { rule } => {
+            error_response(StatusCode::UNPROCESSABLE_ENTITY, &format!("Upload refused by directory policy: {rule}"))
+        }
+        // as WebDAV servers answer uploads over quota
+        Error::QuotaExceeded { message } => error_response(StatusCode::INSUFFICIENT_STORAGE, &message),
+        Error::NoNodeWithSpace | Error::StorageNode { code: ErrorCode::NoSpace, .. } => {
+            error!("No storage node has room for the file");
+            error_response(StatusCode::INSUFFICIENT_STORAGE, "No storage node has room for the file")
+        }
+        Error::UploadInterrupted => error_response(StatusCode::BAD_REQUEST, "Could not receive body"),
+        Error::FileExists => {
+            error_response(StatusCode::CONFLICT, "A file with that name already exists. Use ?overwrite=true to replace it")
+        }
+        Error::AllAttemptsFailed(attempts) => {
+            error!(attempts = ?attempts.iter().map(ToString::to_string).collect::<Vec<_>>(), "Could not write file to any node");
+            attempts_failed("Upload failed", &attempts)
+        }
+        Error::StorageNode { code, detail } => {
+            error!(?code, detail, "Storage node could not write file");
+            storage_node_failed("Upload failed", code, &detail)
+        }
+        e @ (Error::VerificationFailed { .. } | Error::DigestMismatch { .. } | Error::ShortWrite { .. }) => {
+            // already logged by upload_file
+            internal_error(state, StatusCode::BAD_GATEWAY, "Upload verification failed", &e)
+        }
+        e => {
+            error!(?e, "Error uploading file");
+            internal_error(state, StatusCode::INTERNAL_SERVER_ERROR, "Error uploading file", &e)
+        }
+    }
+}
+
+// 503 when no storage node is connected or while shutting down, so load balancers stop sending requests here
+#[instrument(skip(state))]
+async fn health(State(state): State<AppState>) -> Response {
+    let report = state.node.health().await;
+    let status = if report.is_healthy() { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, axum::Json(report)).into_response()
+}
+
+// For Prometheus to scrape
+async fn metrics(State(state): State<AppState>) -> Response {
+    let body = state.node.render_metrics().await;
+    ([(http::header::CONTENT_TYPE, "text/plain; version=0.0.4")], body).into_response()
+}
+
+// Hands out an upload ID naming this front node, so progress can be followed through any front node
+#[instrument(skip(state))]
+async fn new_upload_session(State(state): State<AppState>) -> String {
+    state.forwarder.new_session_token()
+}
+
+// Progress is only tracked for uploads which supplied an X-Upload-Id header
+#[instrument(skip(state))]
+async fn upload_progress(
+    Path(upload_id): Path<String>,
+    State(state): State<AppState>,
+) -> Response {
+    match state.uploads.get(&upload_id).await {
+        Some(progress) => {
+            (StatusCode::OK, axum::Json(progress)).into_response()
+        }
+        None => error_response(StatusCode::NOT_FOUND, "No such upload"),
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct CreateDirectoryOptions {
+    /// succeed if the directory exists already, rather than with 409
+    #[serde(default)]
+    exist_ok: bool,
+}
+
+// The ID of the directory is sent in X-Directory-ID, also when it already existed
+#[instrument(skip(state))]
+async fn create_directory(
+    ParentAndName(parent, dir, _): ParentAndName<NewDirectory>,
+    Query(CreateDirectoryOptions { exist_ok }): Query<CreateDirectoryOptions>,
+    State(state): State<AppState>,
+) -> Response {
+    info!(dir, "Creating directory");
+    let parent = match parent.get_or_create::<NewDirectory>(&state).await {
+        Ok(parent) => parent,
+        Err(response) => return response,
+    };
+
+    let created = match state.node.create_directory(parent, dir.clone()).await {
+        Err(Error::AlreadyExists) if exist_ok => {
+            debug!("Directory exists already");
+            state.node.directory_id_for_path(&dir, Some(parent)).await
+        }
+        created => created,
+    };
+    match created {
+        Ok(id) => {
+            Response::builder()
+                .status(StatusCode::OK)
+                .header("X-Directory-ID", id.0)
+                .body(Body::from("create successful"))
+                .unwrap()
+        }
+        Err(Error::AlreadyExists) => {
+            error_response(StatusCode::CONFLICT, "A directory with that name already exists. Use ?exist_ok=true to accept it")
+        }
+        Err(Error::InvalidName { .. }) => error_response(StatusCode::BAD_REQUEST, "Directory names can't be empty or contain /"),
+        // removed again since it was found to exist
+        Err(Error::NoSuchDirectory { .. }) => error_response(StatusCode::CONFLICT, "Directory was removed while creating it"),
+        Err(e) => {
+            error!(?e, "Error creating directory");
+            internal_error(&state, StatusCode::INTERNAL_SERVER_ERROR, "Error creating directory", &e)
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct DeleteOptions {
+    /// delete everything in the directory too, rather than requiring it to be empty
+    #[serde(default)]
+    recursive: bool,
+    /// delete files right away rather than moving them to the trash
+    #[serde(default)]
+    permanent: bool,
+}
+
+// Responds with a DirectoryDeletion. If some of the directory's contents could not be deleted, they are
+// kept along with the directories holding them, and the status is 503 so the delete can be retried
+#[instrument(skip(state))]
+async fn delete_directory(
+    ResolvedDirectory(dir): ResolvedDirectory,
+    Query(DeleteOptions { recursive, permanent }): Query<DeleteOptions>,
+    State(state): State<AppState>,
+) -> Response {
+    info!("Deleting directory");
+
+    match state.node.delete_directory(dir, recursive, permanent).await {
+        Ok(report) if report.is_complete() => (StatusCode::OK, axum::Json(report)).into_response(),
+        Ok(report) => (StatusCode::SERVICE_UNAVAILABLE, axum::Json(report)).into_response(),
+        Err(Error::DirectoryNotEmpty) => {
+            error_response(StatusCode::CONFLICT, "Directory not empty. Use ?recursive=true to delete its contents too")
+        }
+        Err(Error::CannotRemoveRoot) => error_response(StatusCode::FORBIDDEN, "Cannot delete the root directory"),
+        Err(Error::DirectoryIsHome) => {
+            error_response(StatusCode::CONFLICT, "Directory is a user's home. Delete the user or change their home first")
+        }
+        Err(Error::NoSuchDirectory { .. }) => error_response(StatusCode::NOT_FOUND, "No such directory"),
+        Err(e) => {
+            error!(?e, "Error deleting directory");
+            internal_error(&state, StatusCode::INTERNAL_SERVER_ERROR, "Error deleting directory", &e)
+        }
+    }
+}
+
+// Restores the file most recently deleted from the path, if it's still in the trash. The UUID is sent in
+// X-File-UUID
+#[instrument(skip(state))]
+async fn restore_file(
+    ParentAndName(parent, name, _): ParentAndName<NewFile>,
+    State(state): State<AppState>,
+) -> Response {
+    let dir = match parent.get_or_create::<NewFile>(&state).await {
+        Ok(dir) => dir,
+        Err(response) => return response,
+    };
+    match state.node.restore_file(dir, &name).await {
+        Ok(uuid) => {
+            Response::builder()
+                .status(StatusCode::OK)
+                .header("X-File-UUID", uuid.to_string())
+                .body(Body::from("restore successful"))
+                .unwrap()
+        }
+        Err(Error::NoSuchFile) => error_response(StatusCode::NOT_FOUND, "No file deleted from that path is in the trash"),
+        Err(Error::FileExists) => error_response(StatusCode::CONFLICT, "A file with that name already exists"),
+        Err(e) => {
+            error!(?e, "Error restoring file");
+            internal_error(&state, StatusCode::INTERNAL_SERVER_ERROR, "Error restoring file", &e)
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct MoveTarget {
+    /// the new path of the file, from the root, or the user's home with scope_to_home_directories
+    to: String,
+}
+
+#[instrument(skip(state))]
+async fn move_file(
+    ResolvedFile(uuid): ResolvedFile,
+    Base(base): Base,
+    Query(MoveTarget { to }): Query<MoveTarget>,
+    State(state): State<AppState>,
+) -> Response {
+    let to = to.trim_matches('/');
+    let (parent_path, name) = to.rsplit_once('/').unwrap_or(("", to));
+    if name.is_empty() {
+        return error_response(StatusCode::BAD_REQUEST, "No destination file name given");
+    }
+    let new_dir = match state.node.directory_id_for_path(parent_path, base).await {
+        Ok(id) => id,
+        Err(Error::NoSuchDirectory { topmost_existing_directory: _ }) => {
+            debug!(parent_path, "No destination directory");
+            return error_response(StatusCode::NOT_FOUND, "No such destination directory");
+        }
+        Err(e) => {
+            error!(?e, parent_path, "Error finding destination directory");
+            return internal_error(&state, StatusCode::INTERNAL_SERVER_ERROR, "Error finding destination directory", &e);
+        }
+    };
+
+    info!(%uuid, to, "Moving file");
+    match state.node.move_file(uuid, new_dir, name.to_string()).await {
+        Ok(()) => {
+            Response::builder()
+                .status(StatusCode::OK)
+                .body(Body::from("move successful"))
+                .unwrap()
+        }
+        Err(Error::FileExists) => error_response(StatusCode::CONFLICT, "A file with that name already exists"),
+        // removed between resolving the path and moving
+        Err(Error::NoSuchFile) => error_response(StatusCode::NOT_FOUND, "No such file"),
+        Err(Error::NoSuchDirectory { topmost_existing_directory: _ }) => {
+            error_response(StatusCode::NOT_FOUND, "No such destination directory")
+        }
+        Err(e) => {
+            error!(?e, "Error moving file");
+            internal_error(&state, StatusCode::INTERNAL_SERVER_ERROR, "Error moving file", &e)
+        }
+    }
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ListingFormat {
+    #[default]
+    Entries,
+    /// file_uuids_and_names and directory_ids_and_names, unpaginated. To be removed next release
+    Legacy,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ListingOptions {
+    /// at most this many entries, in name order
+    limit: Option<u64>,
+    /// skip this many entries, e.g. the next_offset of the previous page
+    offset: Option<u64>,
+    #[serde(default)]
+    format: ListingFormat,
+    /// also list files deleted from the directory which are still in the trash. needs the admin token
+    #[serde(default)]
+    include_deleted: bool,
+}
+
+#[instrument(skip(state))]
+async fn list_directory(
+    ResolvedDirectory(dir): ResolvedDirectory,
+    Query(ListingOptions { limit, offset, format, include_deleted }): Query<ListingOptions>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> Response {
+    debug!("Listing directory contents.");
+
+    if include_deleted && !has_admin_token(&state, &headers) {
+        let mut response = error_response(StatusCode::UNAUTHORIZED, "include_deleted needs the admin token");
+        response.headers_mut().insert(http::header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        return response;
+    }
+    let mut rows = match format {
+        ListingFormat::Entries if limit == Some(0) => {
+            return error_response(StatusCode::BAD_REQUEST, "limit must be at least 1");
+        }
+        ListingFormat::Entries => {
+            state.node.list_directory_page(dir, offset.unwrap_or(0), limit, include_deleted, LISTING_BUFFER_ROWS)
+        }
+        ListingFormat::Legacy if limit.is_some() || offset.is_some() => {
+            return error_response(StatusCode::BAD_REQUEST, "The legacy format can't be paginated");
+        }
+        ListingFormat::Legacy if include_deleted => {
+            return error_response(StatusCode::BAD_REQUEST, "The legacy format can't list deleted files");
+        }
+        ListingFormat::Legacy => state.node.list_directory(dir, LISTING_BUFFER_ROWS),
+    };
+
+    // errors after the first row can only abort the response, but an error before it (e.g. the database
+    // being unreachable) can still get a proper status code
+    let first = match rows.next().await {
+        Some(Err(e)) => {
+            error!(?e, "Error listing directory");
+            return internal_error(&state, StatusCode::INTERNAL_SERVER_ERROR, "Error listing directory", &e);
+        }
+        first => first,
+    };
+    let rows = futures::stream::iter(first).chain(rows);
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(match format {
+            ListingFormat::Entries => {
+                Body::from_stream(encode_entries(rows, offset.unwrap_or(0), limit, state.listing_chunk_bytes))
+            }
+            ListingFormat::Legacy => Body::from_stream(encode_listing(rows, state.listing_chunk_bytes)),
+        })
+        .unwrap()
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct RecursiveListingOptions {
+    /// how many directories down to list, at most the configured limit. 1 lists what is directly in
+    /// the directory
+    max_depth: Option<u32>,
+    /// at most the configured limit
+    max_entries: Option<u64>,
+}
+
+// Everything below a directory, with paths relative to it. Directories at the depth limit are marked
+// depth_limited if they have contents which weren't listed, and the listing is marked truncated if
+// it hit the entry limit
+#[instrument(skip(state))]
+async fn list_directory_recursive(
+    ResolvedDirectory(dir): ResolvedDirectory,
+    Query(RecursiveListingOptions { max_depth, max_entries }): Query<RecursiveListingOptions>,
+    State(state): State<AppState>,
+) -> Response {
+    debug!("Listing directory tree.");
+
+    let max_depth = max_depth.map_or(state.recursive_listing_max_depth, |depth| depth.min(state.recursive_listing_max_depth));
+    let max_entries = max_entries.map_or(state.recursive_listing_max_entries, |n| n.min(state.recursive_listing_max_entries));
+    if max_depth == 0 || max_entries == 0 {
+        return error_response(StatusCode::BAD_REQUEST, "max_depth and max_entries must be at least 1");
+    }
+
+    let mut rows = state.node.list_tree(dir, max_depth, max_entries, LISTING_BUFFER_ROWS);
+
+    // as in list_directory, only an error before the first row can get a proper status code
+    let first = match rows.next().await {
+        Some(Err(e)) => {
+            error!(?e, "Error listing directory tree");
+            return internal_error(&state, StatusCode::INTERNAL_SERVER_ERROR, "Error listing directory tree", &e);
+        }
+        first => first,
+    };
+    let rows = futures::stream::iter(first).chain(rows);
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(Body::from_stream(encode_tree(rows, max_entries, state.listing_chunk_bytes)))
+        .unwrap()
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ScrubOptions {
+    /// delete files on the node which aren't in the database, if they are older than this
+    delete_orphans_older_than_s: Option<u64>,
+    /// have the node check every file against its digest
+    #[serde(default)]
+    verify_contents: bool,
+}
+
+// Compares the files on a storage node with the database. Takes as long as listing the node
+#[instrument(skip(state))]
+async fn scrub_node(
+    Path(node_id): Path<i64>,
+    Query(ScrubOptions { delete_orphans_older_than_s, verify_contents }): Query<ScrubOptions>,
+    State(state): State<AppState>,
+) -> Response {
+    let delete_orphans_older_than = delete_orphans_older_than_s.map(std::time::Duration::from_secs);
+    match state.node.scrub_node(StorageNodeID(node_id), delete_orphans_older_than, verify_contents).await {
+        Ok(report) => (StatusCode::OK, axum::Json(report)).into_response(),
+        Err(Error::NotConnectedToNode) => error_response(StatusCode::NOT_FOUND, "Not connected to that storage node"),
+        Err(e) => {
+            error!(?e, node_id, "Error scrubbing storage node");
+            internal_error(&state, StatusCode::INTERNAL_SERVER_ERROR, "Error scrubbing storage node", &e)
+        }
+    }
+}
+
+// The latest scrub of each node, for monitoring
+#[instrument(skip(state))]
+async fn scrub_reports(State(state): State<AppState>) -> Response {
+    (StatusCode::OK, axum::Json(state.node.scrub_reports())).into_response()
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct RehashOptions {
+    /// files hashed with anything else are rehashed with this
+    algorithm: HashAlgorithm,
+    /// how fast the storage nodes read files to hash them
+    bytes_per_s: u64,
+}
+
+// Moves every file's digest to another hash algorithm. Takes as long as reading every file at bytes_per_s
+#[instrument(skip(state))]
+async fn rehash_files(
+    Query(RehashOptions { algorithm, bytes_per_s }): Query<RehashOptions>,
+    State(state): State<AppState>,
+) -> Response {
+    match state.node.rehash_files(algorithm, bytes_per_s).await {
+        Ok(summary) => (StatusCode::OK, axum::Json(summary)).into_response(),
+        Err(e) => {
+            error!(?e, "Error rehashing files");
+            internal_error(&state, StatusCode::INTERNAL_SERVER_ERROR, "Error rehashing files", &e)
+        }
+    }
+}
+
+// Backlog of the backup queue, for monitoring
+#[instrument(skip(state))]
+async fn backup_status(State(state): State<AppState>) -> Response {
+    match state.node.backup_status().await {
+        Ok(Some(status)) => (StatusCode::OK, axum::Json(status)).into_response(),
+        Ok(None) => error_response(StatusCode::NOT_FOUND, "Backups are not configured"),
+        Err(e) => {
+            error!(?e, "Error getting backup status");
+            internal_error(&state, StatusCode::INTERNAL_SERVER_ERROR, "Error getting backup status", &e)
+        }
+    }
+}
+
+// What each storage tier stores and has room for
+#[instrument(skip(state))]
+async fn tier_usage(State(state): State<AppState>) -> Response {
+    match state.node.tier_usage().await {
+        Ok(usage) => (StatusCode::OK, axum::Json(usage)).into_response(),
+        Err(e) => {
+            error!(?e, "Error getting tier usage");
+            internal_error(&state, StatusCode::INTERNAL_SERVER_ERROR, "Error getting tier usage", &e)
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct PinOptions {
+    tier: u32,
+}
+
+// Keeps the files in a directory and below it on a tier, see tiering.rs. Paths are from the root
+#[instrument(skip(state))]
+async fn pin_to_tier(FullPath(path): FullPath, Query(PinOptions { tier }): Query<PinOptions>, State(state): State<AppState>) -> Response {
+    set_tier_pin(&state, &path, Some(tier)).await
+}
+
+#[instrument(skip(state))]
+async fn unpin_from_tier(FullPath(path): FullPath, State(state): State<AppState>) -> Response {
+    set_tier_pin(&state, &path, None).await
+}
+
+async fn set_tier_pin(state: &AppState, path: &str, tier: Option<u32>) -> Response {
+    let dir = match state.node.directory_id_for_path(path, None).await {
+        Ok(dir) => dir,
+        Err(Error::NoSuchDirectory { .. }) => return error_response(StatusCode::NOT_FOUND, "No such directory"),
+        Err(e) => {
+            error!(?e, path, "Error resolving directory");
+            return internal_error(state, StatusCode::INTERNAL_SERVER_ERROR, "Error resolving directory", &e);
+        }
+    };
+    match state.node.pin_to_tier(dir, tier).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(Error::NoNodeWithSpace) => error_response(StatusCode::BAD_REQUEST, "No connected storage node is in that tier"),
+        Err(e) => {
+            error!(?e, path, "Error pinning directory to tier");
+            internal_error(state, StatusCode::INTERNAL_SERVER_ERROR, "Error pinning directory to tier", &e)
+        }
+    }
+}
+
+#[instrument(skip(state))]
+async fn list_users(State(state): State<AppState>) -> Response {
+    match state.node.list_users().await {
+        Ok(users) => (StatusCode::OK, axum::Json(users)).into_response(),
+        Err(e) => {
+            error!(?e, "Error listing users");
+            internal_error(&state, StatusCode::INTERNAL_SERVER_ERROR, "Error listing users", &e)
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+struct CreatedUser {
+    id: u32,
+    name: String,
+    home_directory: front_node::tys::DirectoryID,
+}
+
+// Creates a user with their home directory. The request body is their SSH public key, if any
+#[instrument(skip(state, ssh_pubkey))]
+async fn create_user(Path(name): Path<String>, State(state): State<AppState>, ssh_pubkey: String) -> Response {
+    let Some(ref admin) = state.admin else {
+        return error_response(StatusCode::NOT_FOUND, "Admin routes are not enabled");
+    };
+    let ssh_pubkey = Some(ssh_pubkey.trim()).filter(|key| !key.is_empty());
+    match state.node.create_user(&name, ssh_pubkey, &admin.home_parent).await {
+        Ok((id, home_directory)) => (StatusCode::CREATED, axum::Json(CreatedUser { id, name, home_directory })).into_response(),
+        Err(Error::InvalidName { .. }) => error_response(StatusCode::BAD_REQUEST, "User names can't be empty or contain /"),
+        Err(Error::UserExists { .. }) => error_response(StatusCode::CONFLICT, "A user with that name already exists"),
+        Err(Error::AlreadyExists) => error_response(StatusCode::CONFLICT, "The user's home directory already exists"),
+        Err(e) => {
+            error!(?e, "Error creating user");
+            internal_error(&state, StatusCode::INTERNAL_SERVER_ERROR, "Error creating user", &e)
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct DeleteUserOptions {
+    /// delete the user's home directory and everything in it, rather than leaving it orphaned
+    #[serde(default)]
+    delete_home: bool,
+}
+
+// Responds with the DirectoryDeletion of the home directory, or null if it was kept. Like
+// delete_directory, a 503 if some of it could not be deleted
+#[instrument(skip(state))]
+async fn delete_user(
+    Path(name): Path<String>,
+    Query(DeleteUserOptions { delete_home }): Query<DeleteUserOptions>,
+    State(state): State<AppState>,
+) -> Response {
+    match state.node.delete_user(&name, delete_home).await {
+        Ok(Some(report)) if !report.is_complete() => (StatusCode::SERVICE_UNAVAILABLE, axum::Json(Some(report))).into_response(),
+        Ok(report) => (StatusCode::OK, axum::Json(report)).into_response(),
+        Err(Error::NoSuchUser { .. }) => error_response(StatusCode::NOT_FOUND, "No such user"),
+        Err(Error::HomeDirectoryShared) => error_response(
+            StatusCode::CONFLICT,
+            "The user's home directory is the root or another user's home too. Delete the user without ?delete_home=true",
+        ),
+        Err(e) => {
+            error!(?e, "Error deleting user");
+            internal_error(&state, StatusCode::INTERNAL_SERVER_ERROR, "Error deleting user", &e)
+        }
+    }
+}
+
+// Sets a user's SFTP password to the request body
+#[instrument(skip(state, password))]
+async fn set_user_password(Path(name): Path<String>, State(state): State<AppState>, password: String) -> Response {
+    if password.is_empty() {
+        return error_response(StatusCode::BAD_REQUEST, "Empty password");
+    }
+    password_response(&state, state.node.set_password(&name, Some(&password)).await)
+}
+
+// Removes a user's SFTP password, so that they can only log in with their key
+#[instrument(skip(state))]
+async fn remove_user_password(Path(name): Path<String>, State(state): State<AppState>) -> Response {
+    password_response(&state, state.node.set_password(&name, None).await)
+}
+
+fn password_response(state: &AppState, result: Result<(), Error>) -> Response {
+    match result {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(Error::NoSuchUser { .. }) => error_response(StatusCode::NOT_FOUND, "No such user"),
+        Err(e) => {
+            error!(?e, "Error setting password");
+            internal_error(state, StatusCode::INTERNAL_SERVER_ERROR, "Error setting password", &e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use http_body_util::BodyExt;
+    use hyper_util::rt::TokioIo;
+
+    use front_node::test_support::{self, DatabaseFrontNode};
+    use crate::fault_injection::FaultInjector;
+
+    /// Serves the HTTP API of `node` on a free local port
+    async fn serve(node: Arc<front_node::FrontNode>, cfg: &front_node::config::Config) -> SocketAddr {
+        serve_on(tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap(), node, cfg)
+    }
+
+    /// Like serve, for when the address has to be known before the config
+    fn serve_on(listener: tokio::net::TcpListener, node: Arc<front_node::FrontNode>, cfg: &front_node::config::Config) -> SocketAddr {
+        let router = router(AppState::new(node, cfg), &mut StartupSummary::default());
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::serve(listener, router.into_make_service_with_connect_info::<SocketAddr>()).await
+        });
+        addr
+    }
+
+    /// Sends `request` over a fresh connection, returning the response with its whole body
+    async fn send(addr: SocketAddr, request: http::Request<Body>) -> (http::response::Parts, Vec<u8>) {
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await.unwrap();
+        tokio::spawn(connection);
+        let (parts, body) = sender.send_request(request).await.unwrap().into_parts();
+        (parts, axum::body::to_bytes(Body::new(body), usize::MAX).await.unwrap().to_vec())
+    }
+
+    async fn get(addr: SocketAddr, path: &str, headers: &[(http::HeaderName, &str)]) -> (http::response::Parts, Vec<u8>) {
+        let mut request = http::Request::get(path).header(http::header::HOST, addr.to_string());
+        for (name, value) in headers {
+            request = request.header(name, *value);
+        }
+        send(addr, request.body(Body::empty()).unwrap()).await
+    }
+
+    async fn post(addr: SocketAddr, path: &str, body: &[u8]) -> (http::response::Parts, Vec<u8>) {
+        let request = http::Request::post(path)
+            .header(http::header::HOST, addr.to_string())
+            .header(http::header::CONTENT_LENGTH, body.len())
+            .body(Body::from(body.to_vec()))
+            .unwrap();
+        send(addr, request).await
+    }
+
+    fn json(body: &[u8]) -> serde_json::Value {
+        serde_json::from_slice(body).unwrap_or_else(|e| panic!("{e}: {}", String::from_utf8_lossy(body)))
+    }
+
+    fn header(parts: &http::response::Parts, name: http::HeaderName) -> Option<&str> {
+        parts.headers.get(name).map(|value| value.to_str().unwrap())
+    }
+
+    #[test]
+    fn range_responses_describe_the_range() {
+        let response = partial_content(Response::builder(), 2, 4, 10, Body::empty());
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[http::header::CONTENT_RANGE], "bytes 2-5/10");
+        assert_eq!(response.headers()[http::header::CONTENT_LENGTH], "4");
+
+        let response = range_not_satisfiable(Response::builder(), 10);
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()[http::header::CONTENT_RANGE], "bytes */10");
+    }
+
+    #[tokio::test]
+    async fn failed_attempts_pick_the_status() {
+        fn attempt(node: i64, error: Error) -> FailedAttempt {
+            FailedAttempt { node: StorageNodeID(node), error }
+        }
+        let no_space = || Error::StorageNode { code: ErrorCode::NoSpace, detail: "disk full".to_string() };
+        let cases = [
+            (vec![attempt(1, Error::NotConnectedToNode), attempt(2, Error::NotConnectedToNode)], StatusCode::SERVICE_UNAVAILABLE),
+            (vec![attempt(1, Error::AttemptTimedOut), attempt(2, Error::AttemptTimedOut)], StatusCode::GATEWAY_TIMEOUT),
+            (vec![attempt(1, no_space()), attempt(2, no_space())], StatusCode::INSUFFICIENT_STORAGE),
+            (vec![attempt(1, Error::NotConnectedToNode), attempt(2, no_space())], StatusCode::BAD_GATEWAY),
+        ];
+        for (attempts, status) in cases {
+            let (parts, body) = attempts_failed("Upload failed", &attempts).into_parts();
+            assert_eq!(parts.status, status);
+            let body = json(&body.collect().await.unwrap().to_bytes());
+            assert_eq!(body["error"], "Upload failed");
+            let rendered: Vec<String> = attempts.iter().map(ToString::to_string).collect();
+            assert_eq!(body["attempts"], serde_json::json!(rendered));
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MySQL database, see test_support::DATABASE_URL_VAR"]
+    async fn matching_etag_is_not_modified() {
+        let cfg = test_support::database_config();
+        let front = DatabaseFrontNode::start_with_faults(&cfg, FaultInjector::default()).await;
+        let uuid = front.node.upload_file("cached".to_string(), front.dir, b"bnuy".to_vec(), UploadOptions::default()).await.unwrap();
+        let addr = serve(front.node.clone(), &cfg).await;
+
+        let (parts, body) = get(addr, &format!("/get/file-by-uuid/{uuid}"), &[(http::header::IF_NONE_MATCH, &format!("\"{uuid}\""))]).await;
+        assert_eq!(parts.status, StatusCode::NOT_MODIFIED);
+        assert_eq!(header(&parts, http::header::ETAG), Some(format!("\"{uuid}\"").as_str()));
+        assert!(body.is_empty());
+
+        // a client holding on to a deleted or made up file is told it's gone
+        front.node.remove_file(uuid, true).await.unwrap();
+        for uuid in [uuid, Uuid::now_v7()] {
+            let (parts, body) = get(addr, &format!("/get/file-by-uuid/{uuid}"), &[(http::header::IF_NONE_MATCH, &format!("\"{uuid}\""))]).await;
+            assert_eq!(parts.status, StatusCode::NOT_FOUND, "{uuid}");
+            assert_eq!(body, b"No such file");
+            assert_eq!(header(&parts, http::header::CACHE_CONTROL), None);
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MySQL database, see test_support::DATABASE_URL_VAR"]
+    async fn range_requests() {
+        let cfg = test_support::database_config();
+        let front = DatabaseFrontNode::start_with_faults(&cfg, FaultInjector::default()).await;
+        let uuid = front.node.upload_file("digits".to_string(), front.dir, b"0123456789".to_vec(), UploadOptions::default()).await.unwrap();
+        let addr = serve(front.node.clone(), &cfg).await;
+        let by_path = format!("/get/file-by-path/{}/digits", front.path);
+        let etag = format!("\"{}\"", uuid.hyphenated());
+
+        let (parts, body) = get(addr, &by_path, &[]).await;
+        assert_eq!(parts.status, StatusCode::OK);
+        assert_eq!(header(&parts, http::header::ACCEPT_RANGES), Some("bytes"));
+        assert_eq!(body, b"0123456789");
+
+        let (parts, body) = get(addr, &by_path, &[(http::header::RANGE, "bytes=2-5")]).await;
+        assert_eq!(parts.status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(header(&parts, http::header::CONTENT_RANGE), Some("bytes 2-5/10"));
+        assert_eq!(header(&parts, http::header::CONTENT_LENGTH), Some("4"));
+        assert_eq!(body, b"2345");
+
+        let (parts, body) = get(addr, &by_path, &[(http::header::RANGE, "bytes=-3")]).await;
+        assert_eq!(parts.status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(body, b"789");
+
+        let (parts, body) = get(addr, &by_path, &[(http::header::RANGE, "bytes=10-")]).await;
+        assert_eq!(parts.status, StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(header(&parts, http::header::CONTENT_RANGE), Some("bytes */10"));
+        assert!(body.is_empty());
+
+        // the range only applies while the file is the one the client has part of
+        let (parts, body) = get(addr, &by_path, &[(http::header::RANGE, "bytes=2-5"), (http::header::IF_RANGE, &etag)]).await;
+        assert_eq!(parts.status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(body, b"2345");
+        let (parts, body) = get(addr, &by_path, &[(http::header::RANGE, "bytes=2-5"), (http::header::IF_RANGE, "\"stale\"")]).await;
+        assert_eq!(parts.status, StatusCode::OK);
+        assert_eq!(body, b"0123456789");
+    }
+
+    fn admin_config(mut cfg: front_node::config::Config) -> front_node::config::Config {
+        cfg.admin = Some(front_node::config::AdminOptions { token: "admin-token".to_string(), home_parent: "home".to_string() });
+        cfg
+    }
+
+    #[tokio::test]
+    async fn backup_status_needs_backups() {
+        let cfg = admin_config(test_support::offline_config());
+        let addr = serve(test_support::offline(&cfg), &cfg).await;
+
+        let (parts, _) = get(addr, "/admin/backup", &[]).await;
+        assert_eq!(parts.status, StatusCode::UNAUTHORIZED);
+        let (parts, body) = get(addr, "/admin/backup", &[(http::header::AUTHORIZATION, "Bearer admin-token")]).await;
+        assert_eq!(parts.status, StatusCode::NOT_FOUND);
+        assert_eq!(body, b"Backups are not configured");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MySQL database, see test_support::DATABASE_URL_VAR"]
+    async fn backup_status_reports_the_backlog() {
+        let sink = std::env::temp_dir().join(format!("bnuystore-test-{}", Uuid::now_v7()));
+        std::fs::create_dir(&sink).unwrap();
+        let mut cfg = admin_config(test_support::database_config());
+        cfg.backup = Some(front_node::config::BackupOptions {
+            sink: front_node::config::BackupSinkOptions::Directory { path: sink.clone() },
+            run_worker: false,
+            verify_sample_size: 0,
+            verify_interval_s: 3600,
+        });
+        let front = DatabaseFrontNode::start_with_faults(&cfg, FaultInjector::default()).await;
+        front.node.upload_file("backed up".to_string(), front.dir, b"bnuy".to_vec(), UploadOptions::default()).await.unwrap();
+        let addr = serve(front.node.clone(), &cfg).await;
+
+        let (parts, body) = get(addr, "/admin/backup", &[(http::header::AUTHORIZATION, "Bearer admin-token")]).await;
+        assert_eq!(parts.status, StatusCode::OK);
+        let status = json(&body);
+        // without a worker the upload stays queued
+        assert!(status["pending"].as_u64().unwrap() >= 1, "{status}");
+        assert!(status["lag_s"].is_u64(), "{status}");
+        assert_eq!(status["verify_mismatches"], 0);
+        std::fs::remove_dir_all(sink).unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MySQL database, see test_support::DATABASE_URL_VAR"]
+    async fn directories_are_pinned_to_tiers() {
+        let cfg = admin_config(test_support::database_config());
+        let front = DatabaseFrontNode::start_with_faults(&cfg, FaultInjector::default()).await;
+        front.node.upload_file("pinned".to_string(), front.dir, b"bnuy".to_vec(), UploadOptions::default()).await.unwrap();
+        let addr = serve(front.node.clone(), &cfg).await;
+
+        let (parts, _) = get(addr, "/admin/tiers", &[]).await;
+        assert_eq!(parts.status, StatusCode::UNAUTHORIZED);
+        let (parts, body) = get(addr, "/admin/tiers", &[(http::header::AUTHORIZATION, "Bearer admin-token")]).await;
+        assert_eq!(parts.status, StatusCode::OK);
+        let tiers = json(&body);
+        assert_eq!(tiers[0]["tier"], 0, "{tiers}");
+        assert!(tiers[0]["files"].as_u64().unwrap() >= 1, "{tiers}");
+
+        let pin = |method: http::Method, path: &str| {
+            http::Request::builder()
+                .method(method)
+                .uri(path)
+                .header(http::header::HOST, addr.to_string())
+                .header(http::header::AUTHORIZATION, "Bearer admin-token")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let dir = front.path.as_str();
+        let (parts, _) = send(addr, pin(http::Method::PUT, &format!("/admin/tier-pins/{dir}?tier=0"))).await;
+        assert_eq!(parts.status, StatusCode::NO_CONTENT);
+        let (parts, body) = send(addr, pin(http::Method::PUT, &format!("/admin/tier-pins/{dir}?tier=7"))).await;
+        assert_eq!(parts.status, StatusCode::BAD_REQUEST);
+        assert_eq!(body, b"No connected storage node is in that tier");
+        let (parts, _) = send(addr, pin(http::Method::PUT, &format!("/admin/tier-pins/{dir}/missing?tier=0"))).await;
+        assert_eq!(parts.status, StatusCode::NOT_FOUND);
+        let (parts, _) = send(addr, pin(http::Method::DELETE, &format!("/admin/tier-pins/{dir}"))).await;
+        assert_eq!(parts.status, StatusCode::NO_CONTENT);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MySQL database, see test_support::DATABASE_URL_VAR"]
+    async fn rehash_moves_digests_to_the_new_algorithm() {
+        use crate::hashing::ContentDigest;
+
+        let mut cfg = admin_config(test_support::database_config());
+        cfg.uploads.hash_algorithm = HashAlgorithm::Sha256;
+        // so the corrupted file has contents of its own
+        cfg.uploads.deduplicate = false;
+        let front = DatabaseFrontNode::start_with_faults(&cfg, FaultInjector::default()).await;
+        let addr = serve(front.node.clone(), &cfg).await;
+        front.node.upload_file("intact".to_string(), front.dir, b"bnuy".to_vec(), UploadOptions::default()).await.unwrap();
+        let corrupted = front.node.upload_file("corrupted".to_string(), front.dir, b"bnuy".to_vec(), UploadOptions::default()).await.unwrap();
+        std::fs::write(front.storage.data_folder.join(corrupted.hyphenated().to_string()), b"bun").unwrap();
+
+        let rehash = |query: &str| {
+            http::Request::post(format!("/admin/rehash?{query}"))
+                .header(http::header::HOST, addr.to_string())
+                .header(http::header::AUTHORIZATION, "Bearer admin-token")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let (parts, _) = send(addr, rehash("algorithm=md5&bytes_per_s=1000000")).await;
+        assert_eq!(parts.status, StatusCode::BAD_REQUEST);
+
+        let (parts, body) = send(addr, rehash("algorithm=blake3&bytes_per_s=1000000")).await;
+        assert_eq!(parts.status, StatusCode::OK);
+        // files left in the shared database by other tests are counted too
+        let summary = json(&body);
+        assert!(summary["rehashed"].as_u64().unwrap() >= 1, "{summary}");
+        assert!(summary["mismatched"].as_u64().unwrap() >= 1, "{summary}");
+
+        let dir = front.path.as_str();
+        let digest = |name: &'static str| async move {
+            let (parts, body) = get(addr, &format!("/stat/{dir}/{name}"), &[]).await;
+            assert_eq!(parts.status, StatusCode::OK);
+            json(&body)["digest"].clone()
+        };
+        assert_eq!(digest("intact").await, ContentDigest::of(HashAlgorithm::Blake3, b"bnuy").to_string());
+        // a corrupted file keeps the digest of what it should contain
+        assert_eq!(digest("corrupted").await, ContentDigest::of(HashAlgorithm::Sha256, b"bnuy").to_string());
+    }
+
+    #[tokio::test]
+    async fn uuid_routes_need_a_token_when_scoped() {
+        let mut cfg = test_support::offline_config();
+        cfg.http_server.scope_to_home_directories = true;
+        let addr = serve(test_support::offline(&cfg), &cfg).await;
+        let uuid = Uuid::now_v7();
+
+        for route in [format!("/get/file-by-uuid/{uuid}"), format!("/stat/file-by-uuid/{uuid}"), format!("/get/file-version/{uuid}")] {
+            let (parts, _) = get(addr, &route, &[]).await;
+            assert_eq!(parts.status, StatusCode::UNAUTHORIZED, "{route}");
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MySQL database, see test_support::DATABASE_URL_VAR"]
+    async fn uuid_routes_are_scoped_to_home_directories() {
+        let mut cfg = admin_config(test_support::database_config());
+        cfg.http_server.scope_to_home_directories = true;
+        cfg.versioning = Some(front_node::config::VersioningOptions { keep_versions: 10 });
+        let front = DatabaseFrontNode::start_with_faults(&cfg, FaultInjector::default()).await;
+        let addr = serve(front.node.clone(), &cfg).await;
+        let home_parent = format!("{}/home", front.path);
+        let alice = format!("alice-{}", Uuid::now_v7());
+        let bob = format!("bob-{}", Uuid::now_v7());
+        let (_, alice_home) = front.node.create_user(&alice, None, &home_parent).await.unwrap();
+        front.node.create_user(&bob, None, &home_parent).await.unwrap();
+        test_support::set_http_token(&front.node, &alice, "alice-token").await;
+        test_support::set_http_token(&front.node, &bob, "bob-token").await;
+
+        let old = front.node.upload_file("notes".to_string(), alice_home, b"old".to_vec(), UploadOptions::default()).await.unwrap();
+        let overwrite = UploadOptions { overwrite: true, ..UploadOptions::default() };
+        let current = front.node.upload_file("notes".to_string(), alice_home, b"new".to_vec(), overwrite).await.unwrap();
+
+        let routes = [
+            format!("/get/file-by-uuid/{current}"),
+            format!("/stat/file-by-uuid/{current}"),
+            format!("/get/file-version/{old}"),
+        ];
+        for route in &routes {
+            let (parts, _) = get(addr, route, &[(http::header::AUTHORIZATION, "Bearer alice-token")]).await;
+            assert_eq!(parts.status, StatusCode::OK, "{route} as the owner");
+            let (parts, _) = get(addr, route, &[(http::header::AUTHORIZATION, "Bearer admin-token")]).await;
+            assert_eq!(parts.status, StatusCode::OK, "{route} as the admin");
+            let (parts, body) = get(addr, route, &[(http::header::AUTHORIZATION, "Bearer bob-token")]).await;
+            assert_eq!(parts.status, StatusCode::NOT_FOUND, "{route} as another user");
+            assert_eq!(body, b"No such file");
+            let (parts, _) = get(addr, route, &[]).await;
+            assert_eq!(parts.status, StatusCode::UNAUTHORIZED, "{route} without a token");
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MySQL database, see test_support::DATABASE_URL_VAR"]
+    async fn users_are_held_to_their_quota_and_concurrency_limit() {
+        let mut cfg = test_support::database_config();
+        cfg.http_server.scope_to_home_directories = true;
+        cfg.concurrency_limits.per_user = 1;
+        let front = DatabaseFrontNode::start_with_faults(&cfg, FaultInjector::default()).await;
+        let addr = serve(front.node.clone(), &cfg).await;
+        let alice = format!("alice-{}", Uuid::now_v7());
+        let bob = format!("bob-{}", Uuid::now_v7());
+        front.node.create_user(&alice, None, &front.path).await.unwrap();
+        front.node.create_user(&bob, None, &front.path).await.unwrap();
+        let (alice_token, bob_token) = (format!("Bearer {alice}"), format!("Bearer {bob}"));
+        test_support::set_http_token(&front.node, &alice, &alice).await;
+        test_support::set_http_token(&front.node, &bob, &bob).await;
+        test_support::set_quota(&front.node, &alice, 10).await;
+
+        let upload = |token: &str, path: &str, body: Body, length: Option<usize>| {
+            let mut request = http::Request::post(format!("/upload/file-by-path/{path}"))
+                .header(http::header::HOST, addr.to_string())
+                .header(http::header::AUTHORIZATION, token);
+            if let Some(length) = length {
+                request = request.header(http::header::CONTENT_LENGTH, length);
+            }
+            request.body(body).unwrap()
+        };
+        let (parts, _) = send(addr, upload(&alice_token, "small", Body::from("12345678"), Some(8))).await;
+        assert_eq!(parts.status, StatusCode::OK);
+        // refused by its Content-Length
+        let (parts, body) = send(addr, upload(&alice_token, "big", Body::from("12345"), Some(5))).await;
+        assert_eq!(parts.status, StatusCode::INSUFFICIENT_STORAGE);
+        assert_eq!(body, b"quota exceeded: 8 bytes used of 10");
+        // without one, once the body has been received
+        let chunks = futures::stream::iter([Ok::<_, std::io::Error>("123"), Ok("45")]);
+        let (parts, body) = send(addr, upload(&alice_token, "big", Body::from_stream(chunks), None)).await;
+        assert_eq!(parts.status, StatusCode::INSUFFICIENT_STORAGE);
+        assert_eq!(body, b"quota exceeded: 8 bytes used of 10");
+        let (parts, _) = get(addr, "/stat/big", &[(http::header::AUTHORIZATION, &alice_token)]).await;
+        assert_eq!(parts.status, StatusCode::NOT_FOUND);
+        // replacing a file only counts what it adds
+        let (parts, _) = send(addr, upload(&alice_token, "small?overwrite=true", Body::from("1234567890"), Some(10))).await;
+        assert_eq!(parts.status, StatusCode::OK);
+        assert_eq!(front.node.usage_for_user(&alice).await.unwrap().used_bytes, 10);
+        // bob has no quota
+        let (parts, _) = send(addr, upload(&bob_token, "big", Body::from(vec![0; 1000]), Some(1000))).await;
+        assert_eq!(parts.status, StatusCode::OK);
+
+        // alice's only permit is taken, e.g. by an SFTP session
+        let held = front.node.limits.try_acquire_user(&alice).unwrap();
+        let (parts, _) = get(addr, "/list-directory/", &[(http::header::AUTHORIZATION, &alice_token)]).await;
+        assert_eq!(parts.status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header(&parts, http::header::RETRY_AFTER), Some("1"));
+        let (parts, _) = get(addr, "/list-directory/", &[(http::header::AUTHORIZATION, &bob_token)]).await;
+        assert_eq!(parts.status, StatusCode::OK);
+        drop(held);
+        let (parts, _) = get(addr, "/list-directory/", &[(http::header::AUTHORIZATION, &alice_token)]).await;
+        assert_eq!(parts.status, StatusCode::OK);
+        assert!(front.node.limits.user_utilization().is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MySQL database, see test_support::DATABASE_URL_VAR"]
+    async fn home_directories_are_not_deleted() {
+        let cfg = test_support::database_config();
+        let front = DatabaseFrontNode::start_with_faults(&cfg, FaultInjector::default()).await;
+        let addr = serve(front.node.clone(), &cfg).await;
+        let name = format!("hare-{}", Uuid::now_v7());
+        front.node.create_user(&name, None, &front.path).await.unwrap();
+        let (parts, _) = post(addr, &format!("/upload/file-by-path/{}/{name}/kept.txt", front.path), b"bnuy").await;
+        assert_eq!(parts.status, StatusCode::OK);
+
+        for query in ["", "?recursive=true"] {
+            let (parts, body) = post(addr, &format!("/delete/directory-by-path/{}/{name}{query}", front.path), b"").await;
+            assert_eq!(parts.status, StatusCode::CONFLICT, "{query}");
+            assert_eq!(body, b"Directory is a user's home. Delete the user or change their home first");
+        }
+        // nothing in it was deleted either
+        let (parts, _) = get(addr, &format!("/get/file-by-path/{}/{name}/kept.txt", front.path), &[]).await;
+        assert_eq!(parts.status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MySQL database, see test_support::DATABASE_URL_VAR"]
+    async fn empty_files() {
+        let cfg = test_support::database_config();
+        let front = DatabaseFrontNode::start_with_faults(&cfg, FaultInjector::default()).await;
+        let addr = serve(front.node.clone(), &cfg).await;
+        let path = format!("{}/empty", front.path);
+
+        let (parts, _) = post(addr, &format!("/upload/file-by-path/{path}"), b"").await;
+        assert_eq!(parts.status, StatusCode::OK);
+
+        let (parts, body) = get(addr, &format!("/stat/{path}"), &[]).await;
+        assert_eq!(parts.status, StatusCode::OK);
+        let stat = json(&body);
+        assert_eq!(stat["kind"], "file");
+        assert_eq!(stat["size"], 0);
+        let empty_digest = crate::hashing::ContentDigest::of(Default::default(), b"");
+        assert_eq!(stat["digest"], empty_digest.to_string());
+
+        let (parts, body) = get(addr, &format!("/list-directory/{}", front.path), &[]).await;
+        assert_eq!(parts.status, StatusCode::OK);
+        assert_eq!(json(&body)["entries"], serde_json::json!([{ "kind": "file", "name": "empty", "uuid": stat["uuid"], "size": 0 }]));
+
+        let (parts, body) = get(addr, &format!("/get/file-by-path/{path}"), &[]).await;
+        assert_eq!(parts.status, StatusCode::OK);
+        assert_eq!(header(&parts, http::header::CONTENT_LENGTH), Some("0"));
+        assert!(body.is_empty());
+        // an empty file has no satisfiable ranges
+        let (parts, _) = get(addr, &format!("/get/file-by-path/{path}"), &[(http::header::RANGE, "bytes=0-")]).await;
+        assert_eq!(parts.status, StatusCode::RANGE_NOT_SATISFIABLE);
+
+        // overwriting with empty contents leaves nothing of the old ones
+        let (parts, _) = post(addr, &format!("/upload/file-by-path/{path}?overwrite=true"), b"bnuy").await;
+        assert_eq!(parts.status, StatusCode::OK);
+        let (parts, _) = post(addr, &format!("/upload/file-by-path/{path}?overwrite=true"), b"").await;
+        assert_eq!(parts.status, StatusCode::OK);
+        let (_, body) = get(addr, &format!("/get/file-by-path/{path}"), &[]).await;
+        assert!(body.is_empty());
+
+        // there's no HTTP route deleting files, they are deleted over SFTP (see sftp::tests::empty_files)
+        let (_, body) = get(addr, &format!("/stat/{path}"), &[]).await;
+        let uuid: Uuid = json(&body)["uuid"].as_str().unwrap().parse().unwrap();
+        front.node.remove_file(uuid, true).await.unwrap();
+        let (parts, _) = get(addr, &format!("/stat/{path}"), &[]).await;
+        assert_eq!(parts.status, StatusCode::NOT_FOUND);
+        let (parts, _) = get(addr, &format!("/get/file-by-uuid/{uuid}"), &[]).await;
+        assert_eq!(parts.status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MySQL database, see test_support::DATABASE_URL_VAR"]
+    async fn interrupted_download_resumes() {
+        use crate::hashing::{ContentDigest, HashAlgorithm};
+
+        let cfg = test_support::database_config();
+        let front = DatabaseFrontNode::start_with_faults(&cfg, FaultInjector::default()).await;
+        // large enough to be streamed in several chunks
+        let contents: Vec<u8> = (0..3_000_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        front.node.upload_file("big".to_string(), front.dir, contents.clone(), UploadOptions::default()).await.unwrap();
+        let addr = serve(front.node.clone(), &cfg).await;
+        let path = format!("/get/file-by-path/{}/big", front.path);
+
+        // the connection is dropped partway through the body
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await.unwrap();
+        let connection = tokio::spawn(connection);
+        let request = http::Request::get(&path).header(http::header::HOST, addr.to_string()).body(Body::empty()).unwrap();
+        let (parts, mut body) = sender.send_request(request).await.unwrap().into_parts();
+        assert_eq!(header(&parts, http::header::ACCEPT_RANGES), Some("bytes"));
+        let etag = header(&parts, http::header::ETAG).unwrap().to_string();
+        let mut received = Vec::new();
+        while received.len() < 1_000_000 {
+            let frame = body.frame().await.unwrap().unwrap();
+            received.extend_from_slice(frame.data_ref().unwrap());
+        }
+        drop((body, sender));
+        connection.abort();
+        assert!(received.len() < contents.len());
+
+        let range = format!("bytes={}-", received.len());
+        let (parts, rest) = get(addr, &path, &[(http::header::RANGE, &range), (http::header::IF_RANGE, &etag)]).await;
+        assert_eq!(parts.status, StatusCode::PARTIAL_CONTENT);
+        let content_range = format!("bytes {}-{}/{}", received.len(), contents.len() - 1, contents.len());
+        assert_eq!(header(&parts, http::header::CONTENT_RANGE), Some(content_range.as_str()));
+        received.extend_from_slice(&rest);
+        assert_eq!(ContentDigest::of(HashAlgorithm::Sha256, &received), ContentDigest::of(HashAlgorithm::Sha256, &contents));
+
+        // once the file is replaced, the client's part is of other contents, so it gets the new file whole
+        front.node.upload_file("big".to_string(), front.dir, b"new".to_vec(), UploadOptions { overwrite: true, ..Default::default() }).await.unwrap();
+        let (parts, body) = get(addr, &path, &[(http::header::RANGE, &range), (http::header::IF_RANGE, &etag)]).await;
+        assert_eq!(parts.status, StatusCode::OK);
+        assert_eq!(body, b"new");
+    }
+
+    #[test]
+    fn ranges_are_resumed_only_for_the_same_file() {
+        let etag = "\"0192b2d4-4d0e-7c53-a5d7-5e1e5f3f2c11\"";
+        let headers = |pairs: &[(http::header::HeaderName, &str)]| {
+            pairs.iter().map(|(name, value)| (name.clone(), HeaderValue::from_str(value).unwrap())).collect::<HeaderMap>()
+        };
+        let range = "bytes=1000-";
+
+        assert_eq!(requested_range(&headers(&[]), etag), None);
+        assert_eq!(requested_range(&headers(&[(http::header::RANGE, range)]), etag), Some("bytes=1000-"));
+        assert_eq!(requested_range(&headers(&[(http::header::RANGE, range), (http::header::IF_RANGE, etag)]), etag), Some("bytes=1000-"));
+        // the file was replaced since the client's part was downloaded
+        let other = "\"0192b2d4-4d0e-7c53-a5d7-000000000000\"";
+        assert_eq!(requested_range(&headers(&[(http::header::RANGE, range), (http::header::IF_RANGE, other)]), etag), None);
+        // weak validators and dates can't say the bytes are the same
+        let weak = format!("W/{etag}");
+        assert_eq!(requested_range(&headers(&[(http::header::RANGE, range), (http::header::IF_RANGE, &weak)]), etag), None);
+        let date = "Wed, 21 Oct 2015 07:28:00 GMT";
+        assert_eq!(requested_range(&headers(&[(http::header::RANGE, range), (http::header::IF_RANGE, date)]), etag), None);
+        assert_eq!(requested_range(&headers(&[(http::header::IF_RANGE, etag)]), etag), None);
+    }
+
+    #[tokio::test]
+    async fn internal_errors_are_terse_unless_debugging() {
+        for debug_errors in [false, true] {
+            let mut cfg = test_support::offline_config();
+            cfg.http_server.debug_errors = debug_errors;
+            let addr = serve(test_support::offline(&cfg), &cfg).await;
+
+            // the database isn't there, so looking anything up fails
+            let (parts, body) = get(addr, "/stat/burrow/carrots.txt", &[]).await;
+            assert_eq!(parts.status, StatusCode::INTERNAL_SERVER_ERROR);
+            let body = json(&body);
+            assert_eq!(body["error"], "Error looking up path");
+            // the incident can be found in the logs by the request's ID
+            assert_eq!(body["incident_id"], header(&parts, "x-request-id".parse().unwrap()).unwrap());
+
+            let fields: Vec<&String> = body.as_object().unwrap().keys().collect();
+            if debug_errors {
+                assert_eq!(fields, ["detail", "error", "incident_id"]);
+                assert!(body["detail"].as_str().unwrap().contains("Database"), "{body}");
+            } else {
+                assert_eq!(fields, ["error", "incident_id"]);
+                assert!(!body.to_string().contains("127.0.0.1"), "{body}");
+            }
+        }
+    }
+
+    #[cfg(feature = "dev-mode")]
+    #[tokio::test]
+    #[ignore = "needs a MySQL database, see test_support::DATABASE_URL_VAR"]
+    async fn dev_mode_smoke_test() {
+        let data_dir = std::env::temp_dir().join(format!("bnuystore-test-{}", Uuid::now_v7()));
+        let mut cfg = front_node::dev_mode::default_config();
+        cfg.database_connection = test_support::database_config().database_connection;
+        cfg.http_server.listen_addr = "127.0.0.1:0".to_string();
+        front_node::dev_mode::prepare(&mut cfg, Some(data_dir.clone())).await;
+
+        let mut summary = StartupSummary::default();
+        let node = front_node::FrontNode::start_from_config(&cfg, &mut summary).await.unwrap();
+        front_node::dev_mode::start_embedded_node(&node, &data_dir, &mut summary).await;
+        assert!(summary.storage_nodes.iter().all(|node| node.connected.is_ok()), "{:?}", summary.storage_nodes);
+        assert!(summary.features.contains(&"dev mode".to_string()));
+        let addr = serve(Arc::new(node), &cfg).await;
+
+        let dir = format!("test-{}", Uuid::now_v7());
+        let (parts, _) = post(addr, &format!("/create/directory-by-path/{dir}"), b"").await;
+        assert!(parts.status.is_success(), "{:?}", parts.status);
+        let (parts, _) = post(addr, &format!("/upload/file-by-path/{dir}/hello.txt"), b"bnuy").await;
+        assert_eq!(parts.status, StatusCode::OK);
+        let (parts, body) = get(addr, &format!("/get/file-by-path/{dir}/hello.txt"), &[]).await;
+        assert_eq!(parts.status, StatusCode::OK);
+        assert_eq!(body, b"bnuy");
+        let (_, body) = get(addr, &format!("/list-directory/{dir}"), &[]).await;
+        assert_eq!(json(&body)["entries"][0]["name"], "hello.txt");
+
+        let bearer = format!("Bearer {}", cfg.admin.as_ref().unwrap().token);
+        let (parts, _) = get(addr, "/admin/users", &[(http::header::AUTHORIZATION, bearer.as_str())]).await;
+        assert_eq!(parts.status, StatusCode::OK);
+
+        std::fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn health_has_the_drain_report_while_shutting_down() {
+        let mut cfg = test_support::offline_config();
+        cfg.concurrency_limits.reads = 4;
+        let node = test_support::offline(&cfg);
+        let addr = serve(node.clone(), &cfg).await;
+        let (_, body) = get(addr, "/health", &[]).await;
+        assert!(json(&body).get("draining").is_none());
+
+        // a download still being sent, which holds its permit as limit_concurrency does
+        let download = node.limits.try_acquire_route(front_node::concurrency::RouteClass::Reads).unwrap();
+        let grace = std::time::Duration::from_secs(60);
+        let (trigger, triggered) = tokio::sync::oneshot::channel();
+        let shutdown = front_node::shutdown::Shutdown::start_on(node.clone(), grace, async { triggered.await.unwrap() });
+        trigger.send(()).unwrap();
+        shutdown.clone().begun().await;
+
+        let (parts, body) = get(addr, "/health", &[]).await;
+        assert_eq!(parts.status, StatusCode::SERVICE_UNAVAILABLE);
+        let draining = &json(&body)["draining"];
+        assert_eq!(draining["http_in_flight"], serde_json::json!([["reads", 1]]));
+        let remaining = draining["deadline_remaining_s"].as_f64().unwrap();
+        assert!(remaining > 0.0 && remaining <= grace.as_secs_f64(), "{remaining}");
+        // new requests are refused, while the one in flight finishes
+        let (parts, body) = get(addr, "/list-directory/", &[]).await;
+        assert_eq!((parts.status, body.as_slice()), (StatusCode::SERVICE_UNAVAILABLE, b"Shutting down".as_slice()));
+
+        let http_drained = tokio::spawn(shutdown.http_drained(node.clone()));
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        assert!(!http_drained.is_finished());
+        drop(download);
+        tokio::time::timeout(std::time::Duration::from_secs(1), http_drained).await
+            .expect("the HTTP server should stop once its requests are done")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn full_route_class_is_refused() {
+        let mut cfg = test_support::offline_config();
+        cfg.concurrency_limits.reads = 1;
+        let node = test_support::offline(&cfg);
+        let addr = serve(node.clone(), &cfg).await;
+        let uuid = Uuid::now_v7();
+
+        let read = node.limits.try_acquire_route(front_node::concurrency::RouteClass::Reads).unwrap();
+        let (parts, _) = get(addr, &format!("/get/file-by-uuid/{uuid}"), &[]).await;
+        assert_eq!(parts.status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header(&parts, http::header::RETRY_AFTER), Some("1"));
+        // other classes aren't held up by reads
+        let (parts, _) = get(addr, "/list-directory/", &[]).await;
+        assert_ne!(parts.status, StatusCode::TOO_MANY_REQUESTS);
+
+        drop(read);
+        // let through, to fail for the database not being there
+        let (parts, _) = get(addr, &format!("/get/file-by-uuid/{uuid}"), &[]).await;
+        assert_eq!(parts.status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(node.limits.route_utilization().is_empty());
+    }
+
+    #[tokio::test]
+    async fn paths_are_the_same_with_or_without_slashes() {
+        let router = route_with_path(Router::new(), "/echo", axum::routing::get(|FullPath(path): FullPath| async move { path }));
+        let cfg = test_support::offline_config();
+        let router = router.with_state(AppState::new(test_support::offline(&cfg), &cfg));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        for (path, expected) in [
+            ("/echo", ""),
+            ("/echo/", ""),
+            ("/echo//", ""),
+            ("/echo/burrow", "burrow"),
+            ("/echo/burrow/", "burrow"),
+            ("/echo/burrow/carrots.txt", "burrow/carrots.txt"),
+            ("/echo//burrow//", "burrow"),
+        ] {
+            let (parts, body) = get(addr, path, &[]).await;
+            assert_eq!(parts.status, StatusCode::OK, "{path}");
+            assert_eq!(String::from_utf8(body).unwrap(), expected, "{path}");
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MySQL database, see test_support::DATABASE_URL_VAR"]
+    async fn root_level_operations() {
+        let cfg = test_support::database_config();
+        let front = DatabaseFrontNode::start_with_faults(&cfg, FaultInjector::default()).await;
+        let addr = serve(front.node.clone(), &cfg).await;
+        // tests share the database, so what's made at the root has a name of its own
+        let name = format!("test-{}", Uuid::now_v7());
+
+        for root in ["/stat", "/stat/"] {
+            let (parts, body) = get(addr, root, &[]).await;
+            assert_eq!(parts.status, StatusCode::OK, "{root}");
+            assert_eq!(json(&body)["kind"], "directory", "{root}");
+        }
+
+        let (parts, _) = post(addr, &format!("/upload/file-by-path/{name}.txt"), b"bnuy").await;
+        assert_eq!(parts.status, StatusCode::OK);
+        let (parts, _) = post(addr, &format!("/create/directory-by-path/{name}/"), b"").await;
+        assert!(parts.status.is_success(), "{:?}", parts.status);
+        let (_, body) = get(addr, &format!("/stat/{name}"), &[]).await;
+        assert_eq!(json(&body)["kind"], "directory");
+        let (_, body) = get(addr, &format!("/stat/{name}/"), &[]).await;
+        assert_eq!(json(&body)["kind"], "directory");
+
+        for root in ["/list-directory", "/list-directory/"] {
+            let (parts, body) = get(addr, root, &[]).await;
+            assert_eq!(parts.status, StatusCode::OK, "{root}");
+            let names: Vec<String> = json(&body)["entries"].as_array().unwrap().iter()
+                .map(|entry| entry["name"].as_str().unwrap().to_string())
+                .collect();
+            assert!(names.contains(&name) && names.contains(&format!("{name}.txt")), "{root}: {names:?}");
+        }
+
+        let uuid = front.node.file_uuid_for_path(&format!("{name}.txt"), None).await.unwrap();
+        front.node.remove_file(uuid, true).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn requests_are_access_logged() {
+        let path = std::env::temp_dir().join(format!("bnuystore-test-{}.log", Uuid::now_v7()));
+        let mut cfg = test_support::offline_config();
+        cfg.access_log = Some(toml::from_str(&format!("path = {path:?}\nformat = \"json\"")).unwrap());
+        let addr = serve(test_support::offline_with_access_log(&cfg).await, &cfg).await;
+
+        let (_, version) = get(addr, "/version", &[]).await;
+        get(addr, "/no/such/route", &[]).await;
+
+        // written once each response is sent, so not necessarily in order
+        let mut lines: Vec<serde_json::Value> = front_node::access_log::tests::wait_for_lines(&path, 2).await.iter()
+            .map(|line| json(line.as_bytes()))
+            .collect();
+        lines.sort_by_key(|line| line["status"].as_u64());
+        for line in &lines {
+            assert_eq!(line["client"], "127.0.0.1");
+            assert_eq!(line["user"], serde_json::Value::Null);
+            assert_eq!(line["method"], "GET");
+            assert_eq!(line["protocol"], "HTTP/1.1");
+            assert!(line["time"].as_str().unwrap().ends_with('Z'));
+        }
+        assert_eq!((&lines[0]["path"], &lines[0]["status"], &lines[0]["bytes"]), (&"/version".into(), &200.into(), &version.len().into()));
+        assert_eq!((&lines[1]["path"], &lines[1]["status"], &lines[1]["bytes"]), (&"/no/such/route".into(), &404.into(), &0.into()));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn empty_names_are_refused() {
+        let cfg = test_support::offline_config();
+        let addr = serve(test_support::offline(&cfg), &cfg).await;
+
+        // refused before the database is asked about the parent, which offline would be a 500
+        let cases = [
+            ("/upload/file-by-path", "No file name given"),
+            ("/upload/file-by-path/", "No file name given"),
+            ("/upload/file-by-path//", "No file name given"),
+            ("/restore/file-by-path/", "No file name given"),
+            ("/create/directory-by-path", "No directory name given"),
+            ("/create/directory-by-path/", "No directory name given"),
+            ("/upload/file-by-path/a//b/f?create_parents=true", "Directory names can't be empty"),
+        ];
+        for (path, message) in cases {
+            let (parts, body) = post(addr, path, b"bnuy").await;
+            assert_eq!((parts.status, String::from_utf8(body).unwrap().as_str()), (StatusCode::BAD_REQUEST, message), "{path}");
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MySQL database, see test_support::DATABASE_URL_VAR"]
+    async fn path_routes_resolve_paths() {
+        let cfg = test_support::database_config();
+        let front = DatabaseFrontNode::start_with_faults(&cfg, FaultInjector::default()).await;
+        let addr = serve(front.node.clone(), &cfg).await;
+        let dir = &front.path;
+
+        let missing = [
+            ("GET", format!("/get/file-by-path/{dir}/nope"), "No such file"),
+            ("GET", format!("/get/file-by-path/{dir}/nope/f"), "No such parent directory"),
+            ("GET", format!("/list-directory/{dir}/nope"), "No such directory"),
+            ("POST", format!("/upload/file-by-path/{dir}/nope/f"), "No such directory"),
+            ("POST", format!("/create/directory-by-path/{dir}/nope/d"), "No parent directory"),
+        ];
+        for (method, path, message) in missing {
+            let (parts, body) = match method {
+                "GET" => get(addr, &path, &[]).await,
+                _ => post(addr, &path, b"bnuy").await,
+            };
+            assert_eq!((parts.status, String::from_utf8(body).unwrap().as_str()), (StatusCode::NOT_FOUND, message), "{method} {path}");
+        }
+
+        // trailing slashes are dropped, so this is a file named sub
+        let (parts, _) = post(addr, &format!("/upload/file-by-path/{dir}/sub/"), b"bnuy").await;
+        assert_eq!(parts.status, StatusCode::OK);
+        let (parts, body) = get(addr, &format!("/get/file-by-path/{dir}/sub"), &[]).await;
+        assert_eq!((parts.status, body.as_slice()), (StatusCode::OK, b"bnuy".as_slice()));
+
+        let (parts, _) = post(addr, &format!("/create/directory-by-path/{dir}/new/"), b"").await;
+        assert_eq!(parts.status, StatusCode::OK);
+        let (parts, _) = post(addr, &format!("/upload/file-by-path/{dir}/new/f"), b"").await;
+        assert_eq!(parts.status, StatusCode::OK);
+        let (parts, body) = get(addr, &format!("/list-directory/{dir}/new/"), &[]).await;
+        assert_eq!(parts.status, StatusCode::OK);
+        let entries = &json(&body)["entries"];
+        assert_eq!(entries.as_array().unwrap().len(), 1);
+        assert_eq!(entries[0]["name"], "f");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MySQL database, see test_support::DATABASE_URL_VAR"]
+    async fn duplicate_directories_are_refused() {
+        let cfg = test_support::database_config();
+        let front = DatabaseFrontNode::start_with_faults(&cfg, FaultInjector::default()).await;
+        let addr = serve(front.node.clone(), &cfg).await;
+        let path = format!("/create/directory-by-path/{}/twice", front.path);
+
+        let (parts, _) = post(addr, &path, b"").await;
+        assert_eq!(parts.status, StatusCode::OK);
+        let id = header(&parts, "X-Directory-ID".parse().unwrap()).unwrap().to_string();
+        let (parts, _) = post(addr, &path, b"").await;
+        assert_eq!(parts.status, StatusCode::CONFLICT);
+        let (parts, _) = post(addr, &format!("{path}?exist_ok=true"), b"").await;
+        assert_eq!(parts.status, StatusCode::OK);
+        assert_eq!(header(&parts, "X-Directory-ID".parse().unwrap()), Some(id.as_str()));
+
+        let created = front.node.create_directory(front.dir, "twice".to_string()).await;
+        assert!(matches!(created, Err(Error::AlreadyExists)), "{created:?}");
+    }
+
+    /// The response's headers, without Date and X-Request-Id, which differ for every response
+    fn header_set(parts: &http::response::Parts) -> std::collections::BTreeMap<String, String> {
+        assert!(parts.headers.contains_key(http::header::DATE));
+        assert!(parts.headers.contains_key("X-Request-Id"));
+        parts.headers.iter()
+            .filter(|(name, _)| *name != http::header::DATE && *name != "x-request-id")
+            .map(|(name, value)| (name.to_string(), value.to_str().unwrap().to_string()))
+            .collect()
+    }
+
+    fn headers<const N: usize>(headers: [(&str, &str); N]) -> std::collections::BTreeMap<String, String> {
+        headers.into_iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MySQL database, see test_support::DATABASE_URL_VAR"]
+    async fn not_modified_caching_headers() {
+        let mut cfg = test_support::database_config();
+        let front = DatabaseFrontNode::start_with_faults(&cfg, FaultInjector::default()).await;
+        let uuid = front.node.upload_file("cached".to_string(), front.dir, b"bnuy".to_vec(), UploadOptions::default()).await.unwrap();
+        let etag = format!("\"{uuid}\"");
+        let uuid = uuid.to_string();
+        for (caching, cache_control) in [
+            ("", Some("public, max-age=31536000, immutable")),
+            ("immutable_max_age_s = 60", Some("public, max-age=60, immutable")),
+            ("enabled = false", None),
+        ] {
+            cfg.download_caching = toml::from_str(caching).unwrap();
+            let addr = serve(front.node.clone(), &cfg).await;
+
+            let (parts, _) = get(addr, &format!("/get/file-by-uuid/{uuid}"), &[(http::header::IF_NONE_MATCH, &etag)]).await;
+            assert_eq!(parts.status, StatusCode::NOT_MODIFIED);
+            // random for each front node
+            let instance = parts.headers[INSTANCE_HEADER].to_str().unwrap().to_string();
+            // no Content-Length, as it would have to be the file's
+            let mut expected = headers([
+                ("etag", &etag),
+                ("x-file-uuid", &uuid),
+                ("x-content-type-options", "nosniff"),
+                ("x-bnuy-instance", &instance),
+            ]);
+            if let Some(cache_control) = cache_control {
+                expected.insert("cache-control".to_string(), cache_control.to_string());
+            }
+            assert_eq!(header_set(&parts), expected, "{caching}");
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MySQL database, see test_support::DATABASE_URL_VAR"]
+    async fn download_caching_headers() {
+        let mut cfg = test_support::database_config();
+        let front = DatabaseFrontNode::start_with_faults(&cfg, FaultInjector::default()).await;
+        let uuid = front.node.upload_file("notes.txt".to_string(), front.dir, b"bnuy".to_vec(), UploadOptions::default()).await.unwrap();
+        let etag = format!("\"{uuid}\"");
+        let by_path = format!("/get/file-by-path/{}/notes.txt", front.path);
+        let by_uuid = format!("/get/file-by-uuid/{uuid}");
+
+        let addr = serve(front.node.clone(), &cfg).await;
+        for (path, cache_control) in [(&by_path, "no-cache"), (&by_uuid, "public, max-age=31536000, immutable")] {
+            let (parts, body) = get(addr, path, &[]).await;
+            assert_eq!((parts.status, body.as_slice()), (StatusCode::OK, b"bnuy".as_slice()), "{path}");
+            let node_name = parts.headers["X-Node-Name"].to_str().unwrap();
+            assert!(node_name.starts_with("test-"), "{node_name}");
+            let instance = parts.headers[INSTANCE_HEADER].to_str().unwrap();
+            assert_eq!(header_set(&parts), headers([
+                ("accept-ranges", "bytes"),
+                ("cache-control", cache_control),
+                ("content-length", "4"),
+                ("content-type", "text/plain"),
+                ("etag", &etag),
+                ("x-bnuy-instance", instance),
+                ("x-content-type-options", "nosniff"),
+                ("x-file-uuid", &uuid.to_string()),
+                ("x-node-name", node_name),
+            ]), "{path}");
+        }
+
+        cfg.download_caching = toml::from_str("redirect_paths_to_uuids = true").unwrap();
+        let addr = serve(front.node.clone(), &cfg).await;
+        let (parts, body) = get(addr, &by_path, &[]).await;
+        assert_eq!(parts.status, StatusCode::FOUND);
+        assert!(body.is_empty());
+        let instance = parts.headers[INSTANCE_HEADER].to_str().unwrap();
+        assert_eq!(header_set(&parts), headers([
+            ("cache-control", "no-cache"),
+            ("content-length", "0"),
+            ("location", &by_uuid),
+            ("x-bnuy-instance", instance),
+        ]));
+
+        front.node.remove_file(uuid, true).await.unwrap();
+    }
+
+    /// Two front nodes, a and b, which forward upload sessions to each other. Each is made by `node`
+    /// from `cfg` with a cluster section added. Their peer c is down
+    async fn cluster(cfg: &front_node::config::Config, node: impl Fn(&front_node::config::Config) -> Arc<front_node::FrontNode>) -> (SocketAddr, SocketAddr) {
+        let listeners = [
+            tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap(),
+            tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        ];
+        let addrs = listeners.each_ref().map(|listener| listener.local_addr().unwrap());
+        let [a, b] = listeners.map(|listener| {
+            let (instance_id, peer, peer_addr) = match listener.local_addr().unwrap() == addrs[0] {
+                true => ("a", "b", addrs[1]),
+                false => ("b", "a", addrs[0]),
+            };
+            let mut cfg = cfg.clone();
+            cfg.cluster = Some(toml::from_str(&format!(r#"
+                instance_id = "{instance_id}"
+                shared_secret = "carrots"
+                peers = {{ {peer} = "{peer_addr}", c = "127.0.0.1:1" }}
+            "#)).unwrap());
+            serve_on(listener, node(&cfg), &cfg)
+        });
+        (a, b)
+    }
+
+    /// The status, answering instance and body of a response
+    fn text(response: (http::response::Parts, Vec<u8>)) -> (StatusCode, String, String) {
+        let (parts, body) = response;
+        let instance = parts.headers[INSTANCE_HEADER].to_str().unwrap().to_string();
+        (parts.status, instance, String::from_utf8(body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn sessions_are_forwarded_to_their_owner() {
+        let (a, b) = cluster(&test_support::offline_config(), test_support::offline).await;
+
+        let (parts, token) = post(a, "/upload/session", b"").await;
+        assert_eq!(parts.status, StatusCode::OK);
+        let token = String::from_utf8(token).unwrap();
+        assert!(token.starts_with("a:"), "{token}");
+
+        // a has no upload for the session yet, which it says itself, also when asked through b
+        let progress = format!("/upload/progress/{token}");
+        let not_found = (StatusCode::NOT_FOUND, "a".to_string(), "No such upload".to_string());
+        assert_eq!(text(get(a, &progress, &[]).await), not_found);
+        assert_eq!(text(get(b, &progress, &[]).await), not_found);
+
+        // upload IDs chosen by the client stay where they are
+        let (status, instance, _) = text(get(b, "/upload/progress/my-upload", &[]).await);
+        assert_eq!((status, instance.as_str()), (StatusCode::NOT_FOUND, "b"));
+
+        let (status, instance, body) = text(get(b, &format!("/upload/progress/c:{}", Uuid::now_v7()), &[]).await);
+        assert_eq!((status, instance.as_str()), (StatusCode::CONFLICT, "b"));
+        assert!(body.contains("front node c, which can't be reached"), "{body}");
+        let (status, _, body) = text(get(b, &format!("/upload/progress/unknown:{}", Uuid::now_v7()), &[]).await);
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert!(body.contains("Start a new upload session"), "{body}");
+    }
+
+    #[tokio::test]
+    async fn forwarded_requests_are_checked() {
+        let (_, b) = cluster(&test_support::offline_config(), test_support::offline).await;
+        let progress = format!("/upload/progress/a:{}", Uuid::now_v7());
+
+        let forwarded_by: http::HeaderName = FORWARDED_BY_HEADER.parse().unwrap();
+        let secret: http::HeaderName = SECRET_HEADER.parse().unwrap();
+        let (status, _, _) = text(get(b, &progress, &[(forwarded_by.clone(), "a"), (secret.clone(), "lettuce")]).await);
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _, _) = text(get(b, &progress, &[(forwarded_by.clone(), "a")]).await);
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        // a forwarded the request to b, which thinks a owns it, so it isn't sent back
+        let (status, instance, _) = text(get(b, &progress, &[(forwarded_by, "a"), (secret, "carrots")]).await);
+        assert_eq!((status, instance.as_str()), (StatusCode::LOOP_DETECTED, "b"));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MySQL database, see test_support::DATABASE_URL_VAR"]
+    async fn uploads_through_another_node_are_tracked_by_the_owner() {
+        let cfg = test_support::database_config();
+        let front = DatabaseFrontNode::start_with_faults(&cfg, FaultInjector::default()).await;
+        // both front nodes share the database and storage node
+        let (a, b) = cluster(&cfg, |_| front.node.clone()).await;
+
+        let (_, token) = post(a, "/upload/session", b"").await;
+        let token = String::from_utf8(token).unwrap();
+        let upload = http::Request::post(format!("/upload/file-by-path/{}/forwarded", front.path))
+            .header(http::header::HOST, b.to_string())
+            .header(http::header::CONTENT_LENGTH, 4)
+            .header("X-Upload-Id", &token)
+            .body(Body::from("bnuy"))
+            .unwrap();
+        let (status, instance, _) = text(send(b, upload).await);
+        assert_eq!((status, instance.as_str()), (StatusCode::OK, "a"));
+
+        let (parts, body) = get(b, &format!("/upload/progress/{token}"), &[]).await;
+        assert_eq!(parts.status, StatusCode::OK);
+        let progress = json(&body);
+        assert_eq!((&progress["state"], &progress["bytes_received"]), (&"done".into(), &4.into()));
+
+        let uuid = front.node.file_uuid_for_path(&format!("{}/forwarded", front.path), None).await.unwrap();
+        front.node.remove_file(uuid, true).await.unwrap();
+    }
+
+    /// Sends the head of an upload asking for Expect: 100-continue, and reads the first response
+    /// head to come back. The body isn't sent, and the connection is returned to send it on
+    async fn expect_continue(addr: SocketAddr, path: &str, body_len: usize) -> (tokio::net::TcpStream, String) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let head = format!("POST {path} HTTP/1.1\r\nHost: {addr}\r\nContent-Length: {body_len}\r\nExpect: 100-continue\r\n\r\n");
+        stream.write_all(head.as_bytes()).await.unwrap();
+
+        let mut response = Vec::new();
+        let read_head = async {
+            while !response.ends_with(b"\r\n\r\n") {
+                response.push(stream.read_u8().await.unwrap());
+            }
+        };
+        // a server waiting for the body before answering would hang here
+        tokio::time::timeout(std::time::Duration::from_secs(5), read_head).await.expect("no response without the body");
+        (stream, String::from_utf8(response).unwrap())
+    }
+
+    #[tokio::test]
+    async fn refused_uploads_dont_ask_for_the_body() {
+        let cfg = test_support::offline_config();
+        let addr = serve(test_support::offline(&cfg), &cfg).await;
+
+        let (_, head) = expect_continue(addr, "/upload/file-by-path/", 4).await;
+        assert!(head.starts_with("HTTP/1.1 400 "), "{head}");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MySQL database, see test_support::DATABASE_URL_VAR"]
+    async fn uploads_continue_once_checked() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let cfg = test_support::database_config();
+        let front = DatabaseFrontNode::start_with_faults(&cfg, FaultInjector::default()).await;
+        let addr = serve(front.node.clone(), &cfg).await;
+        let path = format!("/upload/file-by-path/{}/continued", front.path);
+
+        let (mut stream, head) = expect_continue(addr, &path, 4).await;
+        assert_eq!(head, "HTTP/1.1 100 Continue\r\n\r\n");
+        stream.write_all(b"bnuy").await.unwrap();
+        let mut response = vec![0; 1024];
+        let n = stream.read(&mut response).await.unwrap();
+        let response = String::from_utf8_lossy(&response[..n]);
+        assert!(response.starts_with("HTTP/1.1 200 "), "{response}");
+        let uuid = front.node.file_uuid_for_path(&format!("{}/continued", front.path), None).await.unwrap();
+        assert!(front.storage.has_file(uuid));
+
+        // the name is taken now, which is known before the body is sent
+        let (_, head) = expect_continue(addr, &path, 4).await;
+        assert!(head.starts_with("HTTP/1.1 409 "), "{head}");
+
+        front.node.remove_file(uuid, true).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MySQL database, see test_support::DATABASE_URL_VAR"]
+    async fn refused_uploads_dont_create_parents() {
+        let cfg = test_support::database_config();
+        let front = DatabaseFrontNode::start_with_faults(&cfg, FaultInjector::default()).await;
+        let addr = serve(front.node.clone(), &cfg).await;
+        let path = format!("/upload/file-by-path/{}/new/deeper/file?create_parents=true", front.path);
+
+        // no node has room for it, which is known before the body is sent
+        let (_, head) = expect_continue(addr, &path, 1 << 60).await;
+        assert!(head.starts_with("HTTP/1.1 507 "), "{head}");
+        let (parts, _) = get(addr, &format!("/stat/{}/new", front.path), &[]).await;
+        assert_eq!(parts.status, StatusCode::NOT_FOUND);
+
+        let (parts, _) = post(addr, &path, b"bnuy").await;
+        assert_eq!(parts.status, StatusCode::OK);
+        let (_, body) = get(addr, &format!("/stat/{}/new/deeper", front.path), &[]).await;
+        assert_eq!(json(&body)["kind"], "directory");
+    }
+}
@@ -0,0 +1,175 @@
+//! Content hashes of stored files. The algorithm is stored next to each digest, so files hashed
+//! with different algorithms can coexist and be migrated between.
+
+use sha2::Digest as _;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    #[default]
+    Blake3,
+    /// for when compliance requires it
+    Sha256,
+}
+
+impl HashAlgorithm {
+    /// As stored in files.hash_algorithm
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Sha256 => "sha256",
+        }
+    }
+
+    #[allow(unused)]
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "blake3" => Some(HashAlgorithm::Blake3),
+            "sha256" => Some(HashAlgorithm::Sha256),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Incremental hasher for any supported algorithm
+pub enum Hasher {
+    Blake3(Box<blake3::Hasher>),
+    Sha256(sha2::Sha256),
+}
+
+impl Hasher {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+            HashAlgorithm::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Blake3(hasher) => { hasher.update(data); }
+            Hasher::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    pub fn finalize(self) -> ContentDigest {
+        match self {
+            Hasher::Blake3(hasher) => ContentDigest {
+                algorithm: HashAlgorithm::Blake3,
+                bytes: hasher.finalize().as_bytes().to_vec(),
+            },
+            Hasher::Sha256(hasher) => ContentDigest {
+                algorithm: HashAlgorithm::Sha256,
+                bytes: hasher.finalize().to_vec(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContentDigest {
+    pub algorithm: HashAlgorithm,
+    pub bytes: Vec<u8>,
+}
+
+#[allow(unused)]
+impl ContentDigest {
+    pub fn of(algorithm: HashAlgorithm, data: &[u8]) -> Self {
+        let mut hasher = Hasher::new(algorithm);
+        hasher.update(data);
+        hasher.finalize()
+    }
+
+    pub fn to_hex(&self) -> String {
+        self.bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    /// Parses the `algorithm:hex` form it's displayed in
+    pub fn parse(s: &str) -> Option<Self> {
+        let (algorithm, hex) = s.split_once(':')?;
+        let algorithm = HashAlgorithm::from_name(algorithm)?;
+        if !hex.len().is_multiple_of(2) || !hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .ok()?;
+        Some(ContentDigest { algorithm, bytes })
+    }
+}
+
+impl std::fmt::Display for ContentDigest {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}", self.algorithm, self.to_hex())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(algorithm: HashAlgorithm, data: &[u8]) -> String {
+        ContentDigest::of(algorithm, data).to_hex()
+    }
+
+    // from FIPS 180-2's examples
+    #[test]
+    fn sha256_vectors() {
+        assert_eq!(hex(HashAlgorithm::Sha256, b""), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(hex(HashAlgorithm::Sha256, b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(
+            hex(HashAlgorithm::Sha256, b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1",
+        );
+        assert_eq!(hex(HashAlgorithm::Sha256, &[b'a'; 1_000_000]), "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0");
+    }
+
+    #[test]
+    fn blake3_vectors() {
+        assert_eq!(hex(HashAlgorithm::Blake3, b""), "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262");
+        assert_eq!(hex(HashAlgorithm::Blake3, b"abc"), "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85");
+    }
+
+    // files are hashed chunk by chunk, so any split has to give the digest of the whole
+    #[test]
+    fn incremental_hashing_matches_one_shot() {
+        // across blake3's 1024 byte chunks and sha256's 64 byte blocks
+        let data: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+        for algorithm in [HashAlgorithm::Blake3, HashAlgorithm::Sha256] {
+            let whole = ContentDigest::of(algorithm, &data);
+            for chunk_size in [1, 63, 64, 65, 1023, 1024, 1025, 4096] {
+                let mut hasher = Hasher::new(algorithm);
+                for chunk in data.chunks(chunk_size) {
+                    hasher.update(chunk);
+                }
+                assert_eq!(hasher.finalize(), whole, "{algorithm} in chunks of {chunk_size}");
+            }
+        }
+        assert_ne!(ContentDigest::of(HashAlgorithm::Blake3, &data).bytes, ContentDigest::of(HashAlgorithm::Sha256, &data).bytes);
+    }
+
+    #[test]
+    fn digests_parse_as_displayed() {
+        for algorithm in [HashAlgorithm::Blake3, HashAlgorithm::Sha256] {
+            assert_eq!(HashAlgorithm::from_name(algorithm.name()), Some(algorithm));
+            let digest = ContentDigest::of(algorithm, b"bnuy");
+            assert_eq!(digest.bytes.len(), 32);
+            assert_eq!(ContentDigest::parse(&digest.to_string()), Some(digest));
+        }
+        assert_eq!(
+            ContentDigest::parse("sha256:00ff").map(|digest| digest.bytes),
+            Some(vec![0x00, 0xff]),
+        );
+        for malformed in ["", "00ff", "md5:00ff", "sha256:0ff", "sha256:zz", "blake3:00fé"] {
+            assert_eq!(ContentDigest::parse(malformed), None, "{malformed}");
+        }
+    }
+}
@@ -0,0 +1,167 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Files older than this (or in the future) are shown with their year instead of the time of day
+const SIX_MONTHS: Duration = Duration::from_secs(365 * 24 * 60 * 60 / 2);
+
+pub const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(unused)]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+}
+
+#[derive(Debug, Clone)]
+pub struct ListingEntry<'a> {
+    pub name: &'a str,
+    pub kind: EntryKind,
+    pub size: u64,
+    pub mtime: SystemTime,
+    /// only the lower 9 bits (rwxrwxrwx) are used
+    pub permissions: u32,
+    pub owner: &'a str,
+    pub group: &'a str,
+}
+
+/// Formats an entry the same way OpenSSH's sftp server does, i.e.
+/// `drwxr-xr-x    1 owner    group        4096 Mar 25 14:29 name`
+/// Times are shown in UTC.
+pub fn format_longname(entry: &ListingEntry, now: SystemTime) -> String {
+    let mode = format_mode(entry.kind, entry.permissions);
+
+    let recent = entry.mtime <= now
+        && now.duration_since(entry.mtime).is_ok_and(|age| age < SIX_MONTHS);
+    let date = format_date(entry.mtime, recent);
+
+    let owner_width = entry.owner.len().max(8);
+    let group_width = entry.group.len().max(8);
+
+    format!(
+        "{mode}  {nlink:>3} {owner:<owner_width$} {group:<group_width$} {size:>8} {date} {name}",
+        nlink = 1,
+        owner = entry.owner,
+        group = entry.group,
+        size = entry.size,
+        name = entry.name,
+    )
+}
+
+fn format_mode(kind: EntryKind, permissions: u32) -> String {
+    let mut mode = String::with_capacity(10);
+    mode.push(match kind {
+        EntryKind::File => '-',
+        EntryKind::Directory => 'd',
+        EntryKind::Symlink => 'l',
+    });
+    for shift in [6, 3, 0] {
+        let bits = (permissions >> shift) & 0o7;
+        mode.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        mode.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        mode.push(if bits & 0o1 != 0 { 'x' } else { '-' });
+    }
+    mode
+}
+
+// "%b %e %H:%M" for recent times, "%b %e  %Y" otherwise
+fn format_date(time: SystemTime, recent: bool) -> String {
+    // times before the epoch are clamped to it
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let days = secs / 86400;
+    let secs_of_day = secs % 86400;
+
+    let (year, month, day) = civil_from_days(days as i64);
+    let month = MONTHS[month as usize - 1];
+
+    if recent {
+        let hour = secs_of_day / 3600;
+        let minute = (secs_of_day / 60) % 60;
+        format!("{month} {day:>2} {hour:02}:{minute:02}")
+    } else {
+        format!("{month} {day:>2}  {year}")
+    }
+}
+
+// days since 1970-01-01 to (year, month [1-12], day [1-31])
+// see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    // 2024-03-05 14:29:00 UTC
+    const MARCH_5: u64 = 1709648940;
+
+    fn entry(name: &str, kind: EntryKind, size: u64, mtime: SystemTime, permissions: u32) -> ListingEntry<'_> {
+        ListingEntry { name, kind, size, mtime, permissions, owner: "bnuy", group: "burrow" }
+    }
+
+    // the expected lines are what OpenSSH's ls_file prints, i.e. printf("%s %3u %-*s %-*s %8llu %s %s")
+    // with strmode's trailing space after the mode
+    #[test]
+    fn lines_match_openssh() {
+        let now = at(MARCH_5 + 86400);
+        let cases = [
+            (entry("empty", EntryKind::File, 0, at(MARCH_5), 0o644),
+                "-rw-r--r--    1 bnuy     burrow          0 Mar  5 14:29 empty"),
+            (entry("old dir", EntryKind::Directory, 0, at(1638259200), 0o755),
+                "drwxr-xr-x    1 bnuy     burrow          0 Nov 30  2021 old dir"),
+            // wider than the column, which then just grows
+            (entry("big.iso", EntryKind::File, 5_000_000_000, at(MARCH_5), 0o600),
+                "-rw-------    1 bnuy     burrow   5000000000 Mar  5 14:29 big.iso"),
+            (entry("link", EntryKind::Symlink, 12, at(MARCH_5), 0o777),
+                "lrwxrwxrwx    1 bnuy     burrow         12 Mar  5 14:29 link"),
+            // in the future, so the year is shown
+            (entry("later", EntryKind::File, 348911, at(MARCH_5 + 2 * 86400), 0o640),
+                "-rw-r-----    1 bnuy     burrow     348911 Mar  7  2024 later"),
+            (entry("leap", EntryKind::File, 1, at(951868740), 0o400),
+                "-r--------    1 bnuy     burrow          1 Feb 29  2000 leap"),
+        ];
+        for (entry, expected) in cases {
+            assert_eq!(format_longname(&entry, now), expected);
+        }
+    }
+
+    #[test]
+    fn long_owners_widen_their_columns() {
+        let entry = ListingEntry { owner: "a-very-long-owner", group: "wheelgroup", ..entry("f", EntryKind::File, 7, at(MARCH_5), 0o644) };
+        assert_eq!(
+            format_longname(&entry, at(MARCH_5)),
+            "-rw-r--r--    1 a-very-long-owner wheelgroup        7 Mar  5 14:29 f",
+        );
+    }
+
+    #[test]
+    fn six_months_switches_to_the_year() {
+        let mtime = at(MARCH_5);
+        let file = entry("f", EntryKind::File, 0, mtime, 0o644);
+        assert!(format_longname(&file, mtime + SIX_MONTHS - Duration::from_secs(1)).ends_with("Mar  5 14:29 f"));
+        assert!(format_longname(&file, mtime + SIX_MONTHS).ends_with("Mar  5  2024 f"));
+    }
+
+    #[test]
+    fn civil_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(11016), (2000, 2, 29));
+        // 2024-12-31
+        assert_eq!(civil_from_days(1735603500 / 86400), (2024, 12, 31));
+    }
+}
@@ -0,0 +1,80 @@
+//! Tracing setup shared by the front node and the storage node, so that their log options match.
+
+use std::path::PathBuf;
+
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::{self, format::FmtSpan, writer::BoxMakeWriter};
+use tracing_subscriber::filter::EnvFilter;
+use tracing_subscriber::prelude::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum LogFormat {
+    Text,
+    /// one object per line, with the event's fields at the top level
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum LogRotation {
+    Never,
+    Hourly,
+    Daily,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct LogOptions {
+    #[arg(long="log-format", value_enum, default_value_t=LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    /// write logs to this file rather than stdout
+    #[arg(long="log-file")]
+    pub log_file: Option<PathBuf>,
+
+    /// start a new log file this often. rotated files have the date and hour appended to their name
+    #[arg(long="log-rotation", value_enum, default_value_t=LogRotation::Never, requires="log_file")]
+    pub log_rotation: LogRotation,
+}
+
+/// Installs the global subscriber, filtered by RUST_LOG. `compact` picks the shorter text format.
+/// File logs are written in the background, and the returned guard flushes them when dropped, so
+/// it has to be held until the process exits
+pub fn init(options: &LogOptions, compact: bool) -> std::io::Result<Option<WorkerGuard>> {
+    let (writer, guard) = match options.log_file {
+        Some(ref path) => {
+            let rotation = match options.log_rotation {
+                LogRotation::Never => Rotation::NEVER,
+                LogRotation::Hourly => Rotation::HOURLY,
+                LogRotation::Daily => Rotation::DAILY,
+            };
+            let directory = path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(".".as_ref());
+            let file_name = path.file_name().ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{} is not a file path", path.display()))
+            })?;
+            let appender = RollingFileAppender::builder()
+                .rotation(rotation)
+                .filename_prefix(file_name.to_string_lossy())
+                .build(directory)
+                .map_err(std::io::Error::other)?;
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+            (BoxMakeWriter::new(writer), Some(guard))
+        }
+        None => (BoxMakeWriter::new(std::io::stdout), None),
+    };
+
+    let layer = fmt::layer()
+        .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE)
+        .with_ansi(options.log_file.is_none())
+        .with_writer(writer);
+    let layer = match (options.log_format, compact) {
+        (LogFormat::Json, _) => layer.json().flatten_event(true).boxed(),
+        (LogFormat::Text, true) => layer.compact().with_target(false).boxed(),
+        (LogFormat::Text, false) => layer.boxed(),
+    };
+
+    tracing_subscriber::registry()
+        .with(layer)
+        .with(EnvFilter::from_default_env())
+        .init();
+    Ok(guard)
+}
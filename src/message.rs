@@ -3,16 +3,34 @@ use serde::{Serialize, Deserialize};
 
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
+use crate::hashing::{ContentDigest, HashAlgorithm};
+
+mod binary;
+
 #[derive(Debug, Hash, PartialEq, Eq, Clone, Copy)]
 pub struct MessageID(pub u32);
 
+/// Reserved for messages the storage node sends on its own, like Activity, rather than in response
+/// to a request. Never used for requests
+#[allow(unused)]
+pub const UNSOLICITED_ID: MessageID = MessageID(u32::MAX);
+
 #[derive(Debug)]
 #[allow(unused)]
 pub enum ParseMessageError {
     IOError(std::io::Error),
-    ParseJsonError(serde_json::Error),
-    ParseUuidError(uuid::Error),
     RequestTooLarge(usize), // number of bytes to allocate
+    // the header or data is over the MessageLimits parse_message was given. Only the preamble was
+    // read; skip_message reads past the rest, which keeps the stream usable
+    MessageTooLarge { id: MessageID, unread: u64 },
+    // the rest were read in full but don't decode, so the message is skipped and the stream stays
+    // usable. The ID is the message's, so that it can still be answered
+    ParseJsonError { id: MessageID, error: serde_json::Error },
+    ParseUuidError { id: MessageID, error: uuid::Error },
+    // the data was marked compressed, but isn't valid zstd of the length it was sent with
+    DecompressError { id: MessageID, error: std::io::Error },
+    // a binary header, or binary data like a file list, which doesn't decode
+    ParseBinaryError { id: MessageID, why: String },
 }
 type Result<T> = std::result::Result<T, ParseMessageError>;
 
@@ -22,65 +40,509 @@ impl From<std::io::Error> for ParseMessageError {
     }
 }
 
-impl From<serde_json::Error> for ParseMessageError {
-    fn from(e: serde_json::Error) -> Self {
-        ParseMessageError::ParseJsonError(e)
+impl ParseMessageError {
+    /// The ID of a message which was read in full, but couldn't be decoded
+    #[allow(unused)]
+    pub fn undecodable_id(&self) -> Option<MessageID> {
+        match self {
+            ParseMessageError::ParseJsonError { id, .. }
+                | ParseMessageError::ParseUuidError { id, .. }
+                | ParseMessageError::DecompressError { id, .. }
+                | ParseMessageError::ParseBinaryError { id, .. } => Some(*id),
+            ParseMessageError::IOError(_)
+                | ParseMessageError::RequestTooLarge(_)
+                | ParseMessageError::MessageTooLarge { .. } => None,
+        }
+    }
+}
+
+/// Why a message which was read in full couldn't be decoded. parse_message adds the message's ID
+#[derive(Debug)]
+enum Undecodable {
+    Json(serde_json::Error),
+    Uuid(uuid::Error),
+    Decompress(std::io::Error),
+    Binary(String),
+}
+
+impl Undecodable {
+    fn of_message(self, id: MessageID) -> ParseMessageError {
+        match self {
+            Undecodable::Json(error) => ParseMessageError::ParseJsonError { id, error },
+            Undecodable::Uuid(error) => ParseMessageError::ParseUuidError { id, error },
+            Undecodable::Decompress(error) => ParseMessageError::DecompressError { id, error },
+            Undecodable::Binary(why) => ParseMessageError::ParseBinaryError { id, why },
+        }
+    }
+}
+
+/// Optional protocol features, agreed on per connection with Hello. Nodes without a feature never
+/// see the messages it adds
+#[allow(unused)]
+pub const FEATURE_WRITE_ACK: &str = "write-ack"; // WriteFile is answered with WriteAck instead of Ack
+#[allow(unused)]
+pub const FEATURE_ERROR_CODES: &str = "error-codes"; // failed requests are answered with ErrorCode instead of Error
+#[allow(unused)]
+pub const FEATURE_READ_RANGE: &str = "read-range"; // ReadFileRange
+#[allow(unused)]
+pub const FEATURE_CHUNKED_WRITE: &str = "chunked-write"; // WriteFileStart, WriteFileChunk, WriteFileEnd and WriteFileAbort
+#[allow(unused)]
+pub const FEATURE_CHECKSUMS: &str = "checksums"; // WriteFileChecked, WriteFileEndChecked and VerifyFile. ReadFile is answered with CheckedFileContents
+#[allow(unused)]
+pub const FEATURE_ZSTD: &str = "zstd"; // data may be compressed both ways, see write_message_as
+#[allow(unused)]
+pub const FEATURE_PING: &str = "ping"; // Ping
+/// Every feature above
+#[allow(unused)]
+pub const FEATURES: &[&str] = &[
+    FEATURE_WRITE_ACK,
+    FEATURE_ERROR_CODES,
+    FEATURE_READ_RANGE,
+    FEATURE_CHUNKED_WRITE,
+    FEATURE_CHECKSUMS,
+    FEATURE_ZSTD,
+    FEATURE_PING,
+];
+
+/// Version of the protocol as a whole, for changes which can't be made optional as a feature.
+/// Bumped with each such change
+pub const PROTOCOL_VERSION: u32 = 2;
+/// The oldest protocol version this build still speaks
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// The protocol versions a node speaks, sent both ways in Hello
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProtocolVersions {
+    pub min: u32,
+    pub current: u32,
+}
+
+impl ProtocolVersions {
+    #[allow(unused)]
+    pub const OURS: ProtocolVersions = ProtocolVersions { min: MIN_PROTOCOL_VERSION, current: PROTOCOL_VERSION };
+
+    /// The version both sides speak, the newest one if there are several. None if they have none
+    /// in common
+    #[allow(unused)]
+    pub fn negotiate(&self, other: &ProtocolVersions) -> Option<u32> {
+        let version = self.current.min(other.current);
+        (version >= self.min.max(other.min)).then_some(version)
+    }
+}
+
+/// Nodes from before protocol versions don't send theirs, and speak version 1
+impl Default for ProtocolVersions {
+    fn default() -> Self {
+        ProtocolVersions { min: 1, current: 1 }
     }
 }
 
+impl std::fmt::Display for ProtocolVersions {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}..={}", self.min, self.current)
+    }
+}
+
+/// From this protocol version on, message headers are binary rather than JSON, see WireFormat
+#[allow(unused)]
+pub const BINARY_HEADERS_VERSION: u32 = 2;
+
+/// Set in the header length when the data is compressed. Headers never come near this long
+const COMPRESSED_FLAG: u32 = 1 << 31;
+/// Set in the header length when the header is binary rather than JSON
+const BINARY_FLAG: u32 = 1 << 30;
+
+/// How to compress the data of messages sent over a connection with FEATURE_ZSTD
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Compression {
+    /// zstd level, see zstd::compression_level_range
+    pub level: i32,
+    /// data shorter than this is sent as is
+    pub min_bytes: usize,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression { level: zstd::DEFAULT_COMPRESSION_LEVEL, min_bytes: 4096 }
+    }
+}
+
+impl Compression {
+    /// None if the data is too short to bother with, or doesn't get any shorter
+    fn compress(&self, data: &[u8]) -> Option<Vec<u8>> {
+        if data.len() < self.min_bytes {
+            return None;
+        }
+        let compressed = zstd::bulk::compress(data, self.level).ok()?;
+        (compressed.len() < data.len()).then_some(compressed)
+    }
+}
+
+/// How messages are written on a connection, from what was agreed on in Hello. Either side reads
+/// both kinds of header, so the default, uncompressed JSON, is understood by every peer
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WireFormat {
+    /// for data, on connections which agreed on FEATURE_ZSTD
+    pub compression: Option<Compression>,
+    /// on connections which agreed on BINARY_HEADERS_VERSION or later. The handshake, Hello,
+    /// HelloBack and Authenticate, is always JSON, as it's sent before a version is agreed on
+    pub binary_headers: bool,
+}
+
+impl WireFormat {
+    #[allow(unused)]
+    pub fn binary_headers_for(version: u32) -> bool {
+        version >= BINARY_HEADERS_VERSION
+    }
+}
+
+/// Shared between a storage node and the front nodes connecting to it, see Message::Authenticate
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct AuthToken(pub String);
+
+/// The token is left out of logs
+impl std::fmt::Debug for AuthToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "AuthToken(..)")
+    }
+}
+
+impl AuthToken {
+    /// Takes as long for any two tokens of the same length, so that a token can't be guessed a
+    /// byte at a time by timing the answers
+    #[allow(unused)]
+    pub fn matches(&self, other: &AuthToken) -> bool {
+        let (a, b) = (self.0.as_bytes(), other.0.as_bytes());
+        a.len() == b.len() && a.iter().zip(b).fold(0, |difference, (x, y)| difference | (x ^ y)) == 0
+    }
+}
+
+/// Why a storage node couldn't do what was asked
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    /// no file with the UUID
+    NotFound,
+    /// the node's disk is full
+    NoSpace,
+    /// any other IO error
+    Io,
+    /// the request makes no sense, e.g. a response sent as a request
+    BadRequest,
+    /// the node is shutting down, and takes no more requests
+    ShuttingDown,
+    /// the data doesn't match the digest sent with it, so it was corrupted on the way
+    ChecksumMismatch,
+    Internal,
+}
+
 #[derive(Debug, Clone)]
 pub enum Message {
     // requests
+    // Returns a HelloBack with the node's protocol versions and the features it also supports, or
+    // no features if no protocol version is spoken by both. Older nodes don't answer.
+    // compression is what the node should compress its responses with, if FEATURE_ZSTD is agreed on
+    Hello { protocol: ProtocolVersions, features: Vec<String>, compression: Option<Compression> },
+    // The first message on a connection to a node with an auth token, which refuses anything else
+    // until then. Returns an Ack, or an Error after which the node closes the connection
+    Authenticate(AuthToken),
     GetVersion, // returns a MyVersionIs
     ReadFile(Uuid), // returns a FileContents
-    WriteFile(Uuid, Vec<u8>), // data currently raw, may be compressed in the future. Returns a Response::Ack
+    ReadFileRange(Uuid, u64, u32), // offset and max length. Returns a FileRange
+    WriteFile(Uuid, Vec<u8>), // Returns a Response::Ack
+    // writing a file a chunk at a time. The file only appears once ended. Each returns an Ack,
+    // except WriteFileEnd, which returns what WriteFile would
+    WriteFileStart(Uuid),
+    WriteFileChunk(Uuid, Vec<u8>),
+    WriteFileEnd(Uuid),
+    WriteFileAbort(Uuid),
+    // like WriteFile and WriteFileEnd, but the node checks the data against the digest before
+    // writing it, and keeps the digest to check the file against later
+    WriteFileChecked(Uuid, ContentDigest, Vec<u8>),
+    WriteFileEndChecked(Uuid, ContentDigest),
     DeleteFile(Uuid), // Returns a Respanse::Ack
-    // TODO: StorageInfo, ListFiles
+    StatFile(Uuid), // Returns a FileStat
+    StorageInfo, // Returns a StorageInfoIs
+    GetMetrics, // Returns a Metrics
+    HashFile(Uuid, HashAlgorithm), // Returns a FileHash
+    VerifyFile(Uuid), // Returns a FileVerified
+    SubscribeActivity, // Returns an Ack. Afterwards, Activity and ActivityDropped are sent unsolicited
+    UnsubscribeActivity, // Returns an Ack
+    ListFiles, // Returns a FileList
+    Ping, // Returns a Pong. Sent on idle connections, to find ones which were lost without being closed
 
     // responses
+    HelloBack { protocol: ProtocolVersions, features: Vec<String> },
     MyVersionIs(String),
     FileContents(Vec<u8>),
+    // digest is the one the file was written with, None for files written without one
+    CheckedFileContents { digest: Option<ContentDigest>, data: Vec<u8> },
+    FileRange { file_size: u64, data: Vec<u8> }, // data is short at the end of the file, and empty past it
+    FileStat { size: u64 },
+    StorageInfoIs(StorageInfo),
+    Metrics(NodeMetrics),
+    FileHash { size: u64, digest: ContentDigest },
+    FileVerified { size: u64, matches: Option<bool> }, // None for files written without a digest
+    WriteAck { bytes_written: u64, fsynced: bool },
+    FileList(Vec<(Uuid, u64)>), // uuid and size of every stored file
+    Pong,
     Ack,
-    Error(String),
+    Error(String), // from nodes without the error-codes feature
+    ErrorCode { code: ErrorCode, detail: String },
+
+    // unsolicited, sent with UNSOLICITED_ID
+    Activity(ActivityEvent),
+    ActivityDropped { count: u64 }, // the node's buffer overflowed, and this many events were skipped
+}
+
+/// An operation a storage node performed, sent to subscribed connections
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ActivityEvent {
+    /// milliseconds since the unix epoch, by the node's clock, when the operation finished
+    pub at_ms: u64,
+    /// the request, e.g. "ReadFile"
+    pub op: String,
+    pub uuid: Option<Uuid>,
+    /// read or written
+    pub bytes: u64,
+    pub duration_us: u64,
+    /// None on success
+    pub error: Option<String>,
+}
+
+/// Space on the storage node's data folder's filesystem, and how many files it stores
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct StorageInfo {
+    pub bytes_total: u64,
+    pub bytes_available: u64,
+    /// None from nodes which predate it
+    #[serde(default)]
+    pub file_count: Option<u64>,
+}
+
+/// Counters are totals since the storage node started
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct NodeMetrics {
+    pub files_stored: u64,
+    pub bytes_stored: u64,
+    pub bytes_total: u64,
+    pub bytes_available: u64,
+
+    pub reads: u64,
+    pub writes: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// requests which were answered with an Error
+    pub errors: u64,
+
+    pub lock_acquisitions: u64,
+    /// total time spent waiting for file locks
+    pub lock_wait_us: u64,
 }
 
 impl std::fmt::Display for Message {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
+            Message::Hello { protocol, features, compression } =>
+                write!(f, "Hello {{ protocol = {protocol}, features = {features:?}, compression = {compression:?} }}"),
+            Message::Authenticate(token) => write!(f, "Authenticate({token:?})"),
             Message::GetVersion => write!(f, "GetVersion"),
             Message::ReadFile(uuid) => write!(f, "ReadFile({uuid})"),
+            Message::ReadFileRange(uuid, offset, len) => write!(f, "ReadFileRange({uuid}, offset = {offset}, len = {len})"),
             Message::WriteFile(uuid, data) => write!(f, "WriteFile({uuid}, data.len = {})", data.len()),
+            Message::WriteFileStart(uuid) => write!(f, "WriteFileStart({uuid})"),
+            Message::WriteFileChunk(uuid, data) => write!(f, "WriteFileChunk({uuid}, data.len = {})", data.len()),
+            Message::WriteFileEnd(uuid) => write!(f, "WriteFileEnd({uuid})"),
+            Message::WriteFileAbort(uuid) => write!(f, "WriteFileAbort({uuid})"),
+            Message::WriteFileChecked(uuid, digest, data) => write!(f, "WriteFileChecked({uuid}, {digest}, data.len = {})", data.len()),
+            Message::WriteFileEndChecked(uuid, digest) => write!(f, "WriteFileEndChecked({uuid}, {digest})"),
             Message::DeleteFile(uuid) => write!(f, "DeleteFile({uuid})"),
+            Message::StatFile(uuid) => write!(f, "StatFile({uuid})"),
+            Message::StorageInfo => write!(f, "StorageInfo"),
+            Message::GetMetrics => write!(f, "GetMetrics"),
+            Message::HashFile(uuid, algorithm) => write!(f, "HashFile({uuid}, {algorithm})"),
+            Message::VerifyFile(uuid) => write!(f, "VerifyFile({uuid})"),
+            Message::SubscribeActivity => write!(f, "SubscribeActivity"),
+            Message::UnsubscribeActivity => write!(f, "UnsubscribeActivity"),
+            Message::ListFiles => write!(f, "ListFiles"),
+            Message::Ping => write!(f, "Ping"),
 
+            Message::HelloBack { protocol, features } => write!(f, "HelloBack {{ protocol = {protocol}, features = {features:?} }}"),
             Message::MyVersionIs(ver) => write!(f, "MyVersionIs({ver:?})"),
             Message::FileContents(data) => write!(f, "FileContents(data.len = {})", data.len()),
+            Message::CheckedFileContents { digest: Some(digest), data } => write!(f, "CheckedFileContents {{ digest = {digest}, data.len = {} }}", data.len()),
+            Message::CheckedFileContents { digest: None, data } => write!(f, "CheckedFileContents {{ digest = None, data.len = {} }}", data.len()),
+            Message::FileRange { file_size, data } => write!(f, "FileRange {{ file_size = {file_size}, data.len = {} }}", data.len()),
+            Message::FileStat { size } => write!(f, "FileStat {{ size = {size} }}"),
+            Message::StorageInfoIs(info) => write!(f, "{info:?}"),
+            Message::Metrics(metrics) => write!(f, "{metrics:?}"),
+            Message::FileHash { size, digest } => write!(f, "FileHash {{ size = {size}, digest = {digest} }}"),
+            Message::FileVerified { size, matches } => write!(f, "FileVerified {{ size = {size}, matches = {matches:?} }}"),
+            Message::WriteAck { bytes_written, fsynced } => write!(f, "WriteAck {{ bytes_written = {bytes_written}, fsynced = {fsynced} }}"),
+            Message::FileList(files) => write!(f, "FileList(files.len = {})", files.len()),
+            Message::Pong => write!(f, "Pong"),
             Message::Ack => write!(f, "Ack"),
             Message::Error(err) => write!(f, "Error({err:?})"),
+            Message::ErrorCode { code, detail } => write!(f, "ErrorCode {{ code = {code:?}, detail = {detail:?} }}"),
+
+            Message::Activity(event) => write!(f, "{event:?}"),
+            Message::ActivityDropped { count } => write!(f, "ActivityDropped {{ count = {count} }}"),
+        }
+    }
+}
+
+impl Message {
+    /// The variant, e.g. "FileContents", without any of its contents
+    #[allow(unused)]
+    pub fn name(&self) -> &'static str {
+        match self {
+            Message::Hello { .. } => "Hello",
+            Message::Authenticate(_) => "Authenticate",
+            Message::GetVersion => "GetVersion",
+            Message::ReadFile(_) => "ReadFile",
+            Message::ReadFileRange(..) => "ReadFileRange",
+            Message::WriteFile(..) => "WriteFile",
+            Message::WriteFileStart(_) => "WriteFileStart",
+            Message::WriteFileChunk(..) => "WriteFileChunk",
+            Message::WriteFileEnd(_) => "WriteFileEnd",
+            Message::WriteFileAbort(_) => "WriteFileAbort",
+            Message::WriteFileChecked(..) => "WriteFileChecked",
+            Message::WriteFileEndChecked(..) => "WriteFileEndChecked",
+            Message::DeleteFile(_) => "DeleteFile",
+            Message::StatFile(_) => "StatFile",
+            Message::StorageInfo => "StorageInfo",
+            Message::GetMetrics => "GetMetrics",
+            Message::HashFile(..) => "HashFile",
+            Message::VerifyFile(_) => "VerifyFile",
+            Message::SubscribeActivity => "SubscribeActivity",
+            Message::UnsubscribeActivity => "UnsubscribeActivity",
+            Message::ListFiles => "ListFiles",
+            Message::Ping => "Ping",
+
+            Message::HelloBack { .. } => "HelloBack",
+            Message::MyVersionIs(_) => "MyVersionIs",
+            Message::FileContents(_) => "FileContents",
+            Message::CheckedFileContents { .. } => "CheckedFileContents",
+            Message::FileRange { .. } => "FileRange",
+            Message::FileStat { .. } => "FileStat",
+            Message::StorageInfoIs(_) => "StorageInfoIs",
+            Message::Metrics(_) => "Metrics",
+            Message::FileHash { .. } => "FileHash",
+            Message::FileVerified { .. } => "FileVerified",
+            Message::WriteAck { .. } => "WriteAck",
+            Message::FileList(_) => "FileList",
+            Message::Pong => "Pong",
+            Message::Ack => "Ack",
+            Message::Error(_) => "Error",
+            Message::ErrorCode { .. } => "ErrorCode",
+
+            Message::Activity(_) => "Activity",
+            Message::ActivityDropped { .. } => "ActivityDropped",
         }
     }
 }
 
 /// the representation of the message that is sent over the stream
 /// differs from Message in that, Uuids are stringified and large data
-/// are sent separately
+/// are sent separately, compressed if FEATURE_ZSTD was agreed on. Connections which agreed on
+/// BINARY_HEADERS_VERSION send headers encoded by the binary module instead
 #[derive(Debug, Serialize, Deserialize)]
 enum MessageOverWire {
+    Hello {
+        // left out by nodes from before protocol versions
+        #[serde(default)]
+        protocol: ProtocolVersions,
+        features: Vec<String>,
+        // left out by older front nodes, and ignored by older storage nodes
+        #[serde(default)]
+        compression: Option<Compression>,
+    },
+    Authenticate(AuthToken),
     GetVersion,
     ReadFile(String),
+    ReadFileRange(String, u64, u32),
     WriteFile(String),
+    WriteFileStart(String),
+    WriteFileChunk(String),
+    WriteFileEnd(String),
+    WriteFileAbort(String),
+    // digests are sent as displayed, see ContentDigest::parse
+    WriteFileChecked(String, String),
+    WriteFileEndChecked(String, String),
     DeleteFile(String),
+    StatFile(String),
+    StorageInfo,
+    GetMetrics,
+    HashFile(String, HashAlgorithm),
+    VerifyFile(String),
+    SubscribeActivity,
+    UnsubscribeActivity,
+    ListFiles,
+    Ping,
+    HelloBack {
+        #[serde(default)]
+        protocol: ProtocolVersions,
+        features: Vec<String>,
+    },
     MyVersionIs(String),
     FileContents,
+    CheckedFileContents { digest: Option<String> },
+    FileRange { file_size: u64 },
+    FileStat { size: u64 },
+    StorageInfoIs(StorageInfo),
+    Metrics(NodeMetrics),
+    // the digest is sent as the data
+    FileHash { size: u64, algorithm: HashAlgorithm },
+    FileVerified { size: u64, matches: Option<bool> },
+    WriteAck { bytes_written: u64, fsynced: bool },
+    // the files are sent as the data, FILE_LIST_ENTRY_BYTES per file
+    FileList,
+    Pong,
     Ack,
     Error(String),
+    ErrorCode { code: ErrorCode, detail: String },
+    Activity(ActivityEvent),
+    ActivityDropped { count: u64 },
+}
+
+pub const DEFAULT_MAX_HEADER_BYTES: u32 = 64 * 1024;
+pub const DEFAULT_MAX_DATA_BYTES: u64 = 1 << 30;
+
+/// The largest messages parse_message reads, so that a peer can't make it allocate more than
+/// this. Data is limited both as sent and once decompressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageLimits {
+    pub max_header_bytes: u32,
+    pub max_data_bytes: u64,
+}
+
+impl Default for MessageLimits {
+    fn default() -> Self {
+        MessageLimits { max_header_bytes: DEFAULT_MAX_HEADER_BYTES, max_data_bytes: DEFAULT_MAX_DATA_BYTES }
+    }
 }
 
 pub async fn parse_message<F: AsyncRead + Unpin>(
     stream: &mut F,
+    limits: &MessageLimits,
 ) -> Result<(MessageID, Message)> {
     let id = MessageID(stream.read_u32().await?);
     let message_length = stream.read_u32().await?;
     let data_length = stream.read_u64().await?;
+    // compressed data is followed by its length once decompressed
+    let uncompressed_length = match message_length & COMPRESSED_FLAG {
+        0 => None,
+        _ => Some(stream.read_u64().await?),
+    };
+    let binary_header = message_length & BINARY_FLAG != 0;
+    let message_length = message_length & !(COMPRESSED_FLAG | BINARY_FLAG);
+
+    if message_length > limits.max_header_bytes
+        || data_length > limits.max_data_bytes
+        || uncompressed_length.is_some_and(|length| length > limits.max_data_bytes)
+    {
+        return Err(ParseMessageError::MessageTooLarge { id, unread: message_length as u64 + data_length });
+    }
 
     let mut wire_message_buf = Vec::new();
     wire_message_buf.try_reserve(message_length as usize)
@@ -94,61 +556,355 @@ pub async fn parse_message<F: AsyncRead + Unpin>(
     data_buf.resize(data_length as usize, 0);
     stream.read_exact(&mut data_buf).await?;
 
-    let wire_message: MessageOverWire = serde_json::from_slice(&wire_message_buf)?;
-    let message = wire_message.to_message(data_buf)?;
+    // the whole message has been read by now, so failing to decode it leaves the stream usable
+    let message = decode_message(&wire_message_buf, data_buf, uncompressed_length, binary_header)
+        .map_err(|e| e.of_message(id))?;
 
     Ok((id, message))
 }
 
+fn decode_message(
+    header: &[u8],
+    mut data: Vec<u8>,
+    uncompressed_length: Option<u64>,
+    binary_header: bool,
+) -> std::result::Result<Message, Undecodable> {
+    if let Some(uncompressed_length) = uncompressed_length {
+        data = decompress(&data, uncompressed_length)?;
+    }
+    if binary_header {
+        binary::decode(header, data)
+    } else {
+        let wire_message: MessageOverWire = serde_json::from_slice(header).map_err(Undecodable::Json)?;
+        wire_message.into_message(data)
+    }
+}
+
+/// Reads past the rest of a message which was too large to parse, see
+/// ParseMessageError::MessageTooLarge
+#[allow(unused)]
+pub async fn skip_message<F: AsyncRead + Unpin>(stream: &mut F, unread: u64) -> std::io::Result<()> {
+    let skipped = tokio::io::copy(&mut stream.take(unread), &mut tokio::io::sink()).await?;
+    if skipped < unread {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+fn decompress(compressed: &[u8], uncompressed_length: u64) -> std::result::Result<Vec<u8>, Undecodable> {
+    let mut data = Vec::new();
+    data.try_reserve(uncompressed_length as usize)
+        .map_err(|_| Undecodable::Decompress(std::io::ErrorKind::OutOfMemory.into()))?;
+    // fails rather than growing the buffer if the data decompresses to more than it should
+    zstd::bulk::Decompressor::new()
+        .and_then(|mut decompressor| decompressor.decompress_to_buffer(compressed, &mut data))
+        .map_err(Undecodable::Decompress)?;
+    if data.len() as u64 != uncompressed_length {
+        return Err(Undecodable::Decompress(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("data decompressed to {} bytes, but was sent as {uncompressed_length}", data.len()),
+        )));
+    }
+    Ok(data)
+}
+
+#[allow(unused)]
 pub async fn write_message<F: AsyncWrite + Unpin>(
     stream: &mut F,
     id: MessageID,
     message: Message,
+) -> Result<()> {
+    write_message_as(stream, id, message, &WireFormat::default()).await
+}
+
+/// Like write_message, but in the format agreed on for the connection. The data is compressed if
+/// it's worth it
+pub async fn write_message_as<F: AsyncWrite + Unpin>(
+    stream: &mut F,
+    id: MessageID,
+    message: Message,
+    format: &WireFormat,
 ) -> Result<()> {
     stream.write_u32(id.0).await?;
 
-    let (wire_message, data) = MessageOverWire::from_message(message);
-    let wire_message_buf = serde_json::to_vec(&wire_message)?;
+    let handshake = matches!(message, Message::Hello { .. } | Message::HelloBack { .. } | Message::Authenticate(_));
+    let (wire_message_buf, data, flags) = if format.binary_headers && !handshake {
+        let (header, data) = binary::encode(message);
+        (header, data, BINARY_FLAG)
+    } else {
+        let (wire_message, data) = MessageOverWire::from_message(message);
+        let header = serde_json::to_vec(&wire_message).map_err(|error| ParseMessageError::ParseJsonError { id, error })?;
+        (header, data, 0)
+    };
+    debug_assert!((wire_message_buf.len() as u32) & (COMPRESSED_FLAG | BINARY_FLAG) == 0, "header too long for its length");
 
-    stream.write_u32(wire_message_buf.len() as u32).await?;
-    stream.write_u64(data.len() as u64).await?;
+    match format.compression.as_ref().and_then(|compression| compression.compress(&data)) {
+        Some(compressed) => {
+            stream.write_u32(wire_message_buf.len() as u32 | flags | COMPRESSED_FLAG).await?;
+            stream.write_u64(compressed.len() as u64).await?;
+            stream.write_u64(data.len() as u64).await?;
 
-    stream.write_all(&wire_message_buf).await?;
-    stream.write_all(&data).await?;
+            stream.write_all(&wire_message_buf).await?;
+            stream.write_all(&compressed).await?;
+        }
+        None => {
+            stream.write_u32(wire_message_buf.len() as u32 | flags).await?;
+            stream.write_u64(data.len() as u64).await?;
+
+            stream.write_all(&wire_message_buf).await?;
+            stream.write_all(&data).await?;
+        }
+    }
 
     Ok(())
 }
 
+/// 16 bytes of UUID, then the size as a big endian u64
+const FILE_LIST_ENTRY_BYTES: usize = 24;
+
+fn encode_file_list(files: Vec<(Uuid, u64)>) -> Vec<u8> {
+    let mut data = Vec::with_capacity(files.len() * FILE_LIST_ENTRY_BYTES);
+    for (uuid, size) in files {
+        data.extend_from_slice(uuid.as_bytes());
+        data.extend_from_slice(&size.to_be_bytes());
+    }
+    data
+}
+
+fn decode_file_list(data: Vec<u8>) -> std::result::Result<Vec<(Uuid, u64)>, Undecodable> {
+    if !data.len().is_multiple_of(FILE_LIST_ENTRY_BYTES) {
+        return Err(Undecodable::Binary(
+            format!("file list of {} bytes is not made of {FILE_LIST_ENTRY_BYTES} byte entries", data.len()),
+        ));
+    }
+    Ok(data.chunks_exact(FILE_LIST_ENTRY_BYTES).map(|entry| {
+        let (uuid, size) = entry.split_at(16);
+        let uuid = Uuid::from_slice(uuid).expect("entries start with 16 bytes");
+        let size = u64::from_be_bytes(size.try_into().expect("entries end with 8 bytes"));
+        (uuid, size)
+    }).collect())
+}
+
+fn parse_digest(stringified: String) -> std::result::Result<ContentDigest, Undecodable> {
+    ContentDigest::parse(&stringified).ok_or_else(|| {
+        Undecodable::Json(serde::de::Error::custom(format!("{stringified:?} is not a digest")))
+    })
+}
+
 fn stringify_uuid(uuid: Uuid) -> String {
     uuid.hyphenated().encode_lower(&mut Uuid::encode_buffer()).to_string()
 }
-fn parse_uuid(stringified: String) -> Result<Uuid> {
-    Uuid::try_parse(&stringified).map_err(ParseMessageError::ParseUuidError)
+fn parse_uuid(stringified: String) -> std::result::Result<Uuid, Undecodable> {
+    Uuid::try_parse(&stringified).map_err(Undecodable::Uuid)
 }
 
 impl MessageOverWire {
     fn from_message(cmd: Message) -> (MessageOverWire, Vec<u8>) {
         match cmd {
+            Message::Hello { protocol, features, compression } => (MessageOverWire::Hello { protocol, features, compression }, vec![]),
+            Message::Authenticate(token) => (MessageOverWire::Authenticate(token), vec![]),
             Message::GetVersion => (MessageOverWire::GetVersion, vec![]),
             Message::ReadFile(u) => (MessageOverWire::ReadFile(stringify_uuid(u)), vec![]),
-            Message::WriteFile(u, data) => (MessageOverWire::WriteFile(stringify_uuid(u)), data), // TODO: Compression
+            Message::ReadFileRange(u, offset, len) => (MessageOverWire::ReadFileRange(stringify_uuid(u), offset, len), vec![]),
+            Message::WriteFile(u, data) => (MessageOverWire::WriteFile(stringify_uuid(u)), data),
+            Message::WriteFileStart(u) => (MessageOverWire::WriteFileStart(stringify_uuid(u)), vec![]),
+            Message::WriteFileChunk(u, data) => (MessageOverWire::WriteFileChunk(stringify_uuid(u)), data),
+            Message::WriteFileEnd(u) => (MessageOverWire::WriteFileEnd(stringify_uuid(u)), vec![]),
+            Message::WriteFileAbort(u) => (MessageOverWire::WriteFileAbort(stringify_uuid(u)), vec![]),
+            Message::WriteFileChecked(u, digest, data) => (MessageOverWire::WriteFileChecked(stringify_uuid(u), digest.to_string()), data),
+            Message::WriteFileEndChecked(u, digest) => (MessageOverWire::WriteFileEndChecked(stringify_uuid(u), digest.to_string()), vec![]),
             Message::DeleteFile(u) => (MessageOverWire::DeleteFile(stringify_uuid(u)), vec![]),
+            Message::StatFile(u) => (MessageOverWire::StatFile(stringify_uuid(u)), vec![]),
+            Message::StorageInfo => (MessageOverWire::StorageInfo, vec![]),
+            Message::GetMetrics => (MessageOverWire::GetMetrics, vec![]),
+            Message::HashFile(u, algorithm) => (MessageOverWire::HashFile(stringify_uuid(u), algorithm), vec![]),
+            Message::VerifyFile(u) => (MessageOverWire::VerifyFile(stringify_uuid(u)), vec![]),
+            Message::SubscribeActivity => (MessageOverWire::SubscribeActivity, vec![]),
+            Message::UnsubscribeActivity => (MessageOverWire::UnsubscribeActivity, vec![]),
+            Message::ListFiles => (MessageOverWire::ListFiles, vec![]),
+            Message::Ping => (MessageOverWire::Ping, vec![]),
+            Message::HelloBack { protocol, features } => (MessageOverWire::HelloBack { protocol, features }, vec![]),
             Message::MyVersionIs(v) => (MessageOverWire::MyVersionIs(v), vec![]),
-            Message::FileContents(data) => (MessageOverWire::FileContents, data), // TODO: Compression
+            Message::FileContents(data) => (MessageOverWire::FileContents, data),
+            Message::CheckedFileContents { digest, data } =>
+                (MessageOverWire::CheckedFileContents { digest: digest.map(|digest| digest.to_string()) }, data),
+            Message::FileRange { file_size, data } => (MessageOverWire::FileRange { file_size }, data),
+            Message::FileStat { size } => (MessageOverWire::FileStat { size }, vec![]),
+            Message::StorageInfoIs(info) => (MessageOverWire::StorageInfoIs(info), vec![]),
+            Message::Metrics(metrics) => (MessageOverWire::Metrics(metrics), vec![]),
+            Message::FileHash { size, digest } =>
+                (MessageOverWire::FileHash { size, algorithm: digest.algorithm }, digest.bytes),
+            Message::FileVerified { size, matches } => (MessageOverWire::FileVerified { size, matches }, vec![]),
+            Message::WriteAck { bytes_written, fsynced } => (MessageOverWire::WriteAck { bytes_written, fsynced }, vec![]),
+            Message::FileList(files) => (MessageOverWire::FileList, encode_file_list(files)),
+            Message::Pong => (MessageOverWire::Pong, vec![]),
             Message::Ack => (MessageOverWire::Ack, vec![]),
             Message::Error(e) => (MessageOverWire::Error(e), vec![]),
+            Message::ErrorCode { code, detail } => (MessageOverWire::ErrorCode { code, detail }, vec![]),
+            Message::Activity(event) => (MessageOverWire::Activity(event), vec![]),
+            Message::ActivityDropped { count } => (MessageOverWire::ActivityDropped { count }, vec![]),
         }
     }
-    fn to_message(self, data: Vec<u8>) -> Result<Message> {
+    fn into_message(self, data: Vec<u8>) -> std::result::Result<Message, Undecodable> {
         Ok(match self {
+            MessageOverWire::Hello { protocol, features, compression } => Message::Hello { protocol, features, compression },
+            MessageOverWire::Authenticate(token) => Message::Authenticate(token),
             MessageOverWire::GetVersion => Message::GetVersion,
             MessageOverWire::ReadFile(u) => Message::ReadFile(parse_uuid(u)?),
-            MessageOverWire::WriteFile(u) => Message::WriteFile(parse_uuid(u)?, data), // TODO: Compression
+            MessageOverWire::ReadFileRange(u, offset, len) => Message::ReadFileRange(parse_uuid(u)?, offset, len),
+            MessageOverWire::WriteFile(u) => Message::WriteFile(parse_uuid(u)?, data),
+            MessageOverWire::WriteFileStart(u) => Message::WriteFileStart(parse_uuid(u)?),
+            MessageOverWire::WriteFileChunk(u) => Message::WriteFileChunk(parse_uuid(u)?, data),
+            MessageOverWire::WriteFileEnd(u) => Message::WriteFileEnd(parse_uuid(u)?),
+            MessageOverWire::WriteFileAbort(u) => Message::WriteFileAbort(parse_uuid(u)?),
+            MessageOverWire::WriteFileChecked(u, digest) => Message::WriteFileChecked(parse_uuid(u)?, parse_digest(digest)?, data),
+            MessageOverWire::WriteFileEndChecked(u, digest) => Message::WriteFileEndChecked(parse_uuid(u)?, parse_digest(digest)?),
             MessageOverWire::DeleteFile(u) => Message::DeleteFile(parse_uuid(u)?),
+            MessageOverWire::StatFile(u) => Message::StatFile(parse_uuid(u)?),
+            MessageOverWire::StorageInfo => Message::StorageInfo,
+            MessageOverWire::GetMetrics => Message::GetMetrics,
+            MessageOverWire::HashFile(u, algorithm) => Message::HashFile(parse_uuid(u)?, algorithm),
+            MessageOverWire::VerifyFile(u) => Message::VerifyFile(parse_uuid(u)?),
+            MessageOverWire::SubscribeActivity => Message::SubscribeActivity,
+            MessageOverWire::UnsubscribeActivity => Message::UnsubscribeActivity,
+            MessageOverWire::ListFiles => Message::ListFiles,
+            MessageOverWire::Ping => Message::Ping,
+            MessageOverWire::HelloBack { protocol, features } => Message::HelloBack { protocol, features },
             MessageOverWire::MyVersionIs(v) => Message::MyVersionIs(v),
-            MessageOverWire::FileContents => Message::FileContents(data), // TODO: Compression
+            MessageOverWire::FileContents => Message::FileContents(data),
+            MessageOverWire::CheckedFileContents { digest } =>
+                Message::CheckedFileContents { digest: digest.map(parse_digest).transpose()?, data },
+            MessageOverWire::FileRange { file_size } => Message::FileRange { file_size, data },
+            MessageOverWire::FileStat { size } => Message::FileStat { size },
+            MessageOverWire::StorageInfoIs(info) => Message::StorageInfoIs(info),
+            MessageOverWire::Metrics(metrics) => Message::Metrics(metrics),
+            MessageOverWire::FileHash { size, algorithm } =>
+                Message::FileHash { size, digest: ContentDigest { algorithm, bytes: data } },
+            MessageOverWire::FileVerified { size, matches } => Message::FileVerified { size, matches },
+            MessageOverWire::WriteAck { bytes_written, fsynced } => Message::WriteAck { bytes_written, fsynced },
+            MessageOverWire::FileList => Message::FileList(decode_file_list(data)?),
+            MessageOverWire::Pong => Message::Pong,
             MessageOverWire::Ack => Message::Ack,
             MessageOverWire::Error(e) => Message::Error(e),
+            MessageOverWire::ErrorCode { code, detail } => Message::ErrorCode { code, detail },
+            MessageOverWire::Activity(event) => Message::Activity(event),
+            MessageOverWire::ActivityDropped { count } => Message::ActivityDropped { count },
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::RngCore;
+
+    const ZSTD: WireFormat = WireFormat {
+        compression: Some(Compression { level: 3, min_bytes: 4096 }),
+        binary_headers: false,
+    };
+
+    async fn encode(message: Message, format: &WireFormat) -> Vec<u8> {
+        let mut wire = Vec::new();
+        write_message_as(&mut wire, MessageID(7), message, format).await.unwrap();
+        wire
+    }
+
+    async fn decode(mut wire: &[u8]) -> Result<(MessageID, Message)> {
+        let decoded = parse_message(&mut wire, &MessageLimits::default()).await;
+        assert!(decoded.is_err() || wire.is_empty(), "{} bytes left unread", wire.len());
+        decoded
+    }
+
+    fn is_compressed(wire: &[u8]) -> bool {
+        u32::from_be_bytes(wire[4..8].try_into().unwrap()) & COMPRESSED_FLAG != 0
+    }
+
+    /// Where the data of a compressed message starts
+    fn compressed_data_offset(wire: &[u8]) -> usize {
+        let header_length = u32::from_be_bytes(wire[4..8].try_into().unwrap()) & !(COMPRESSED_FLAG | BINARY_FLAG);
+        4 + 4 + 8 + 8 + header_length as usize
+    }
+
+    async fn round_trip_data(data: Vec<u8>, format: &WireFormat) -> Vec<u8> {
+        let uuid = Uuid::now_v7();
+        let wire = encode(Message::WriteFile(uuid, data.clone()), format).await;
+        let (id, message) = decode(&wire).await.unwrap();
+        assert_eq!(id, MessageID(7));
+        let Message::WriteFile(decoded_uuid, decoded) = message else { panic!("expected WriteFile, got {message}") };
+        assert_eq!(decoded_uuid, uuid);
+        assert!(decoded == data, "data changed on the way");
+        wire
+    }
+
+    #[tokio::test]
+    async fn compressible_data_is_compressed() {
+        let data: Vec<u8> = b"bnuy ".iter().copied().cycle().take(1 << 20).collect();
+        let wire = round_trip_data(data.clone(), &ZSTD).await;
+        assert!(is_compressed(&wire));
+        assert!(wire.len() < data.len() / 100, "{} bytes on the wire", wire.len());
+    }
+
+    #[tokio::test]
+    async fn incompressible_data_is_sent_as_is() {
+        let mut data = vec![0; 1 << 20];
+        rand::thread_rng().fill_bytes(&mut data);
+        let wire = round_trip_data(data.clone(), &ZSTD).await;
+        assert!(!is_compressed(&wire));
+
+        // too short to bother with
+        let wire = round_trip_data(vec![0; 4095], &ZSTD).await;
+        assert!(!is_compressed(&wire));
+        // and sent as is without compression agreed on
+        let wire = round_trip_data(vec![0; 1 << 20], &WireFormat::default()).await;
+        assert!(!is_compressed(&wire));
+    }
+
+    #[tokio::test]
+    async fn corrupt_compressed_data_is_a_parse_error() {
+        let data: Vec<u8> = (0..1 << 16).map(|i| (i % 7) as u8).collect();
+        let wire = encode(Message::WriteFile(Uuid::now_v7(), data), &ZSTD).await;
+        assert!(is_compressed(&wire));
+        let data_offset = compressed_data_offset(&wire);
+
+        let mut not_zstd = wire.clone();
+        not_zstd[data_offset] ^= 0xff;
+        // lengths once decompressed which don't match the data
+        let mut longer = wire.clone();
+        longer[16..24].copy_from_slice(&(1u64 << 17).to_be_bytes());
+        let mut shorter = wire.clone();
+        shorter[16..24].copy_from_slice(&1000u64.to_be_bytes());
+        for corrupt in [not_zstd, longer, shorter] {
+            match decode(&corrupt).await {
+                Err(ParseMessageError::DecompressError { id: MessageID(7), .. }) => {}
+                decoded => panic!("expected a decompression error, got {decoded:?}"),
+            }
+        }
+
+        // any byte may be damaged. not every change is detectable, but none may panic, or decode
+        // to data of another length
+        for i in data_offset..wire.len() {
+            let mut corrupt = wire.clone();
+            corrupt[i] ^= 0x55;
+            match decode(&corrupt).await {
+                Ok((_, Message::WriteFile(_, data))) => assert_eq!(data.len(), 1 << 16),
+                Ok((_, message)) => panic!("decoded to {message}"),
+                Err(e) => assert!(matches!(e, ParseMessageError::DecompressError { .. }), "{e:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn stream_is_usable_after_corrupt_data() {
+        let mut wire = encode(Message::WriteFile(Uuid::now_v7(), vec![1; 1 << 16]), &ZSTD).await;
+        let data_offset = compressed_data_offset(&wire);
+        wire[data_offset] ^= 0xff;
+        wire.extend(encode(Message::Ping, &ZSTD).await);
+
+        let mut stream = wire.as_slice();
+        let limits = MessageLimits::default();
+        let e = parse_message(&mut stream, &limits).await.unwrap_err();
+        assert_eq!(e.undecodable_id(), Some(MessageID(7)));
+        assert!(matches!(parse_message(&mut stream, &limits).await, Ok((MessageID(7), Message::Ping))));
+    }
+}
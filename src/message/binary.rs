@@ -0,0 +1,835 @@
+//! Binary message headers, used instead of JSON on connections which agreed on protocol version
+//! BINARY_HEADERS_VERSION or later. A header is a tag byte for the kind of message, then its
+//! fields in order: integers big endian, UUIDs as their 16 bytes, strings, byte strings and lists
+//! prefixed with their length as a u32, and booleans and options as a 0 or 1 byte. Large data goes
+//! in the data section, as with JSON headers.
+//!
+//! Tags are never reused. New messages get the next one.
+
+use uuid::Uuid;
+
+use super::{Message, Undecodable, ProtocolVersions, Compression, ErrorCode, StorageInfo, NodeMetrics, ActivityEvent};
+use super::{encode_file_list, decode_file_list};
+use crate::hashing::{ContentDigest, HashAlgorithm};
+
+const HELLO: u8 = 0;
+const AUTHENTICATE: u8 = 1;
+const GET_VERSION: u8 = 2;
+const READ_FILE: u8 = 3;
+const READ_FILE_RANGE: u8 = 4;
+const WRITE_FILE: u8 = 5;
+const WRITE_FILE_START: u8 = 6;
+const WRITE_FILE_CHUNK: u8 = 7;
+const WRITE_FILE_END: u8 = 8;
+const WRITE_FILE_ABORT: u8 = 9;
+const WRITE_FILE_CHECKED: u8 = 10;
+const WRITE_FILE_END_CHECKED: u8 = 11;
+const DELETE_FILE: u8 = 12;
+const STAT_FILE: u8 = 13;
+const STORAGE_INFO: u8 = 14;
+const GET_METRICS: u8 = 15;
+const HASH_FILE: u8 = 16;
+const VERIFY_FILE: u8 = 17;
+const SUBSCRIBE_ACTIVITY: u8 = 18;
+const UNSUBSCRIBE_ACTIVITY: u8 = 19;
+const LIST_FILES: u8 = 20;
+const PING: u8 = 21;
+
+const HELLO_BACK: u8 = 64;
+const MY_VERSION_IS: u8 = 65;
+const FILE_CONTENTS: u8 = 66;
+const CHECKED_FILE_CONTENTS: u8 = 67;
+const FILE_RANGE: u8 = 68;
+const FILE_STAT: u8 = 69;
+const STORAGE_INFO_IS: u8 = 70;
+const METRICS: u8 = 71;
+const FILE_HASH: u8 = 72;
+const FILE_VERIFIED: u8 = 73;
+const WRITE_ACK: u8 = 74;
+const FILE_LIST: u8 = 75;
+const ACK: u8 = 76;
+const ERROR: u8 = 77;
+const ERROR_CODE: u8 = 78;
+const PONG: u8 = 79;
+
+const ACTIVITY: u8 = 128;
+const ACTIVITY_DROPPED: u8 = 129;
+
+type Result<T> = std::result::Result<T, Undecodable>;
+
+fn invalid(why: impl Into<String>) -> Undecodable {
+    Undecodable::Binary(why.into())
+}
+
+#[derive(Default)]
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn u8(&mut self, x: u8) {
+        self.0.push(x);
+    }
+    fn u32(&mut self, x: u32) {
+        self.0.extend_from_slice(&x.to_be_bytes());
+    }
+    fn u64(&mut self, x: u64) {
+        self.0.extend_from_slice(&x.to_be_bytes());
+    }
+    fn i32(&mut self, x: i32) {
+        self.0.extend_from_slice(&x.to_be_bytes());
+    }
+    fn bool(&mut self, x: bool) {
+        self.u8(x as u8);
+    }
+    fn uuid(&mut self, uuid: Uuid) {
+        self.0.extend_from_slice(uuid.as_bytes());
+    }
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.0.extend_from_slice(bytes);
+    }
+    fn string(&mut self, string: &str) {
+        self.bytes(string.as_bytes());
+    }
+    fn strings(&mut self, strings: &[String]) {
+        self.u32(strings.len() as u32);
+        for string in strings {
+            self.string(string);
+        }
+    }
+    fn option<T>(&mut self, x: Option<T>, encode: impl FnOnce(&mut Self, T)) {
+        self.bool(x.is_some());
+        if let Some(x) = x {
+            encode(self, x);
+        }
+    }
+    fn protocol(&mut self, protocol: ProtocolVersions) {
+        self.u32(protocol.min);
+        self.u32(protocol.current);
+    }
+    fn algorithm(&mut self, algorithm: HashAlgorithm) {
+        self.u8(match algorithm {
+            HashAlgorithm::Blake3 => 0,
+            HashAlgorithm::Sha256 => 1,
+        });
+    }
+    fn digest(&mut self, digest: &ContentDigest) {
+        self.algorithm(digest.algorithm);
+        self.bytes(&digest.bytes);
+    }
+    fn error_code(&mut self, code: ErrorCode) {
+        self.u8(match code {
+            ErrorCode::NotFound => 0,
+            ErrorCode::NoSpace => 1,
+            ErrorCode::Io => 2,
+            ErrorCode::BadRequest => 3,
+            ErrorCode::ShuttingDown => 4,
+            ErrorCode::ChecksumMismatch => 5,
+            ErrorCode::Internal => 6,
+        });
+    }
+}
+
+/// Reads fields off the front of a header, failing rather than reading past its end
+struct Decoder<'a>(&'a [u8]);
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(invalid(format!("header ends {} bytes short of a field", n - self.0.len())));
+        }
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(taken)
+    }
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().expect("took N bytes"))
+    }
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.array::<1>()?[0])
+    }
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.array()?))
+    }
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.array()?))
+    }
+    fn i32(&mut self) -> Result<i32> {
+        Ok(i32::from_be_bytes(self.array()?))
+    }
+    fn bool(&mut self) -> Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            x => Err(invalid(format!("{x} is not a boolean"))),
+        }
+    }
+    fn uuid(&mut self) -> Result<Uuid> {
+        Ok(Uuid::from_bytes(self.array()?))
+    }
+    fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.u32()?;
+        self.take(len as usize)
+    }
+    fn string(&mut self) -> Result<String> {
+        let bytes = self.bytes()?;
+        String::from_utf8(bytes.to_vec()).map_err(|e| invalid(format!("string is not UTF-8: {e}")))
+    }
+    fn strings(&mut self) -> Result<Vec<String>> {
+        // not allocated up front, as the count isn't checked against the header's length. Each
+        // string takes at least 4 bytes, so a short header can't make this loop for long
+        let count = self.u32()?;
+        (0..count).map(|_| self.string()).collect()
+    }
+    fn option<T>(&mut self, decode: impl FnOnce(&mut Self) -> Result<T>) -> Result<Option<T>> {
+        match self.bool()? {
+            true => Ok(Some(decode(self)?)),
+            false => Ok(None),
+        }
+    }
+    fn protocol(&mut self) -> Result<ProtocolVersions> {
+        Ok(ProtocolVersions { min: self.u32()?, current: self.u32()? })
+    }
+    fn algorithm(&mut self) -> Result<HashAlgorithm> {
+        match self.u8()? {
+            0 => Ok(HashAlgorithm::Blake3),
+            1 => Ok(HashAlgorithm::Sha256),
+            x => Err(invalid(format!("unknown hash algorithm {x}"))),
+        }
+    }
+    fn digest(&mut self) -> Result<ContentDigest> {
+        Ok(ContentDigest { algorithm: self.algorithm()?, bytes: self.bytes()?.to_vec() })
+    }
+    fn error_code(&mut self) -> Result<ErrorCode> {
+        Ok(match self.u8()? {
+            0 => ErrorCode::NotFound,
+            1 => ErrorCode::NoSpace,
+            2 => ErrorCode::Io,
+            3 => ErrorCode::BadRequest,
+            4 => ErrorCode::ShuttingDown,
+            5 => ErrorCode::ChecksumMismatch,
+            6 => ErrorCode::Internal,
+            x => return Err(invalid(format!("unknown error code {x}"))),
+        })
+    }
+    fn finish(self) -> Result<()> {
+        match self.0.len() {
+            0 => Ok(()),
+            n => Err(invalid(format!("{n} bytes left over at the end of the header"))),
+        }
+    }
+}
+
+/// The header and the data of a message
+pub(super) fn encode(message: Message) -> (Vec<u8>, Vec<u8>) {
+    let mut e = Encoder::default();
+    let data = match message {
+        Message::Hello { protocol, features, compression } => {
+            e.u8(HELLO);
+            e.protocol(protocol);
+            e.strings(&features);
+            e.option(compression, |e, compression| {
+                e.i32(compression.level);
+                e.u64(compression.min_bytes as u64);
+            });
+            vec![]
+        }
+        Message::Authenticate(token) => {
+            e.u8(AUTHENTICATE);
+            e.string(&token.0);
+            vec![]
+        }
+        Message::GetVersion => {
+            e.u8(GET_VERSION);
+            vec![]
+        }
+        Message::ReadFile(uuid) => {
+            e.u8(READ_FILE);
+            e.uuid(uuid);
+            vec![]
+        }
+        Message::ReadFileRange(uuid, offset, len) => {
+            e.u8(READ_FILE_RANGE);
+            e.uuid(uuid);
+            e.u64(offset);
+            e.u32(len);
+            vec![]
+        }
+        Message::WriteFile(uuid, data) => {
+            e.u8(WRITE_FILE);
+            e.uuid(uuid);
+            data
+        }
+        Message::WriteFileStart(uuid) => {
+            e.u8(WRITE_FILE_START);
+            e.uuid(uuid);
+            vec![]
+        }
+        Message::WriteFileChunk(uuid, data) => {
+            e.u8(WRITE_FILE_CHUNK);
+            e.uuid(uuid);
+            data
+        }
+        Message::WriteFileEnd(uuid) => {
+            e.u8(WRITE_FILE_END);
+            e.uuid(uuid);
+            vec![]
+        }
+        Message::WriteFileAbort(uuid) => {
+            e.u8(WRITE_FILE_ABORT);
+            e.uuid(uuid);
+            vec![]
+        }
+        Message::WriteFileChecked(uuid, digest, data) => {
+            e.u8(WRITE_FILE_CHECKED);
+            e.uuid(uuid);
+            e.digest(&digest);
+            data
+        }
+        Message::WriteFileEndChecked(uuid, digest) => {
+            e.u8(WRITE_FILE_END_CHECKED);
+            e.uuid(uuid);
+            e.digest(&digest);
+            vec![]
+        }
+        Message::DeleteFile(uuid) => {
+            e.u8(DELETE_FILE);
+            e.uuid(uuid);
+            vec![]
+        }
+        Message::StatFile(uuid) => {
+            e.u8(STAT_FILE);
+            e.uuid(uuid);
+            vec![]
+        }
+        Message::StorageInfo => {
+            e.u8(STORAGE_INFO);
+            vec![]
+        }
+        Message::GetMetrics => {
+            e.u8(GET_METRICS);
+            vec![]
+        }
+        Message::HashFile(uuid, algorithm) => {
+            e.u8(HASH_FILE);
+            e.uuid(uuid);
+            e.algorithm(algorithm);
+            vec![]
+        }
+        Message::VerifyFile(uuid) => {
+            e.u8(VERIFY_FILE);
+            e.uuid(uuid);
+            vec![]
+        }
+        Message::SubscribeActivity => {
+            e.u8(SUBSCRIBE_ACTIVITY);
+            vec![]
+        }
+        Message::UnsubscribeActivity => {
+            e.u8(UNSUBSCRIBE_ACTIVITY);
+            vec![]
+        }
+        Message::ListFiles => {
+            e.u8(LIST_FILES);
+            vec![]
+        }
+        Message::Ping => {
+            e.u8(PING);
+            vec![]
+        }
+
+        Message::HelloBack { protocol, features } => {
+            e.u8(HELLO_BACK);
+            e.protocol(protocol);
+            e.strings(&features);
+            vec![]
+        }
+        Message::MyVersionIs(version) => {
+            e.u8(MY_VERSION_IS);
+            e.string(&version);
+            vec![]
+        }
+        Message::FileContents(data) => {
+            e.u8(FILE_CONTENTS);
+            data
+        }
+        Message::CheckedFileContents { digest, data } => {
+            e.u8(CHECKED_FILE_CONTENTS);
+            e.option(digest.as_ref(), Encoder::digest);
+            data
+        }
+        Message::FileRange { file_size, data } => {
+            e.u8(FILE_RANGE);
+            e.u64(file_size);
+            data
+        }
+        Message::FileStat { size } => {
+            e.u8(FILE_STAT);
+            e.u64(size);
+            vec![]
+        }
+        Message::StorageInfoIs(info) => {
+            e.u8(STORAGE_INFO_IS);
+            e.u64(info.bytes_total);
+            e.u64(info.bytes_available);
+            e.option(info.file_count, Encoder::u64);
+            vec![]
+        }
+        Message::Metrics(metrics) => {
+            e.u8(METRICS);
+            for counter in [
+                metrics.files_stored,
+                metrics.bytes_stored,
+                metrics.bytes_total,
+                metrics.bytes_available,
+                metrics.reads,
+                metrics.writes,
+                metrics.bytes_read,
+                metrics.bytes_written,
+                metrics.errors,
+                metrics.lock_acquisitions,
+                metrics.lock_wait_us,
+            ] {
+                e.u64(counter);
+            }
+            vec![]
+        }
+        // the digest is sent as the data, as with JSON
+        Message::FileHash { size, digest } => {
+            e.u8(FILE_HASH);
+            e.u64(size);
+            e.algorithm(digest.algorithm);
+            digest.bytes
+        }
+        Message::FileVerified { size, matches } => {
+            e.u8(FILE_VERIFIED);
+            e.u64(size);
+            e.option(matches, Encoder::bool);
+            vec![]
+        }
+        Message::WriteAck { bytes_written, fsynced } => {
+            e.u8(WRITE_ACK);
+            e.u64(bytes_written);
+            e.bool(fsynced);
+            vec![]
+        }
+        Message::FileList(files) => {
+            e.u8(FILE_LIST);
+            encode_file_list(files)
+        }
+        Message::Pong => {
+            e.u8(PONG);
+            vec![]
+        }
+        Message::Ack => {
+            e.u8(ACK);
+            vec![]
+        }
+        Message::Error(error) => {
+            e.u8(ERROR);
+            e.string(&error);
+            vec![]
+        }
+        Message::ErrorCode { code, detail } => {
+            e.u8(ERROR_CODE);
+            e.error_code(code);
+            e.string(&detail);
+            vec![]
+        }
+
+        Message::Activity(event) => {
+            e.u8(ACTIVITY);
+            e.u64(event.at_ms);
+            e.string(&event.op);
+            e.option(event.uuid, Encoder::uuid);
+            e.u64(event.bytes);
+            e.u64(event.duration_us);
+            e.option(event.error.as_deref(), Encoder::string);
+            vec![]
+        }
+        Message::ActivityDropped { count } => {
+            e.u8(ACTIVITY_DROPPED);
+            e.u64(count);
+            vec![]
+        }
+    };
+    (e.0, data)
+}
+
+pub(super) fn decode(header: &[u8], data: Vec<u8>) -> Result<Message> {
+    let mut d = Decoder(header);
+    let message = match d.u8()? {
+        HELLO => Message::Hello {
+            protocol: d.protocol()?,
+            features: d.strings()?,
+            compression: d.option(|d| Ok(Compression { level: d.i32()?, min_bytes: d.u64()? as usize }))?,
+        },
+        AUTHENTICATE => Message::Authenticate(super::AuthToken(d.string()?)),
+        GET_VERSION => Message::GetVersion,
+        READ_FILE => Message::ReadFile(d.uuid()?),
+        READ_FILE_RANGE => Message::ReadFileRange(d.uuid()?, d.u64()?, d.u32()?),
+        WRITE_FILE => Message::WriteFile(d.uuid()?, data),
+        WRITE_FILE_START => Message::WriteFileStart(d.uuid()?),
+        WRITE_FILE_CHUNK => Message::WriteFileChunk(d.uuid()?, data),
+        WRITE_FILE_END => Message::WriteFileEnd(d.uuid()?),
+        WRITE_FILE_ABORT => Message::WriteFileAbort(d.uuid()?),
+        WRITE_FILE_CHECKED => Message::WriteFileChecked(d.uuid()?, d.digest()?, data),
+        WRITE_FILE_END_CHECKED => Message::WriteFileEndChecked(d.uuid()?, d.digest()?),
+        DELETE_FILE => Message::DeleteFile(d.uuid()?),
+        STAT_FILE => Message::StatFile(d.uuid()?),
+        STORAGE_INFO => Message::StorageInfo,
+        GET_METRICS => Message::GetMetrics,
+        HASH_FILE => Message::HashFile(d.uuid()?, d.algorithm()?),
+        VERIFY_FILE => Message::VerifyFile(d.uuid()?),
+        SUBSCRIBE_ACTIVITY => Message::SubscribeActivity,
+        UNSUBSCRIBE_ACTIVITY => Message::UnsubscribeActivity,
+        LIST_FILES => Message::ListFiles,
+        PING => Message::Ping,
+
+        HELLO_BACK => Message::HelloBack { protocol: d.protocol()?, features: d.strings()? },
+        MY_VERSION_IS => Message::MyVersionIs(d.string()?),
+        FILE_CONTENTS => Message::FileContents(data),
+        CHECKED_FILE_CONTENTS => Message::CheckedFileContents { digest: d.option(Decoder::digest)?, data },
+        FILE_RANGE => Message::FileRange { file_size: d.u64()?, data },
+        FILE_STAT => Message::FileStat { size: d.u64()? },
+        STORAGE_INFO_IS => Message::StorageInfoIs(StorageInfo {
+            bytes_total: d.u64()?,
+            bytes_available: d.u64()?,
+            file_count: d.option(Decoder::u64)?,
+        }),
+        METRICS => Message::Metrics(NodeMetrics {
+            files_stored: d.u64()?,
+            bytes_stored: d.u64()?,
+            bytes_total: d.u64()?,
+            bytes_available: d.u64()?,
+            reads: d.u64()?,
+            writes: d.u64()?,
+            bytes_read: d.u64()?,
+            bytes_written: d.u64()?,
+            errors: d.u64()?,
+            lock_acquisitions: d.u64()?,
+            lock_wait_us: d.u64()?,
+        }),
+        FILE_HASH => Message::FileHash {
+            size: d.u64()?,
+            digest: ContentDigest { algorithm: d.algorithm()?, bytes: data },
+        },
+        FILE_VERIFIED => Message::FileVerified { size: d.u64()?, matches: d.option(Decoder::bool)? },
+        WRITE_ACK => Message::WriteAck { bytes_written: d.u64()?, fsynced: d.bool()? },
+        FILE_LIST => Message::FileList(decode_file_list(data)?),
+        PONG => Message::Pong,
+        ACK => Message::Ack,
+        ERROR => Message::Error(d.string()?),
+        ERROR_CODE => Message::ErrorCode { code: d.error_code()?, detail: d.string()? },
+
+        ACTIVITY => Message::Activity(ActivityEvent {
+            at_ms: d.u64()?,
+            op: d.string()?,
+            uuid: d.option(Decoder::uuid)?,
+            bytes: d.u64()?,
+            duration_us: d.u64()?,
+            error: d.option(Decoder::string)?,
+        }),
+        ACTIVITY_DROPPED => Message::ActivityDropped { count: d.u64()? },
+
+        tag => return Err(invalid(format!("unknown message tag {tag}"))),
+    };
+    d.finish()?;
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::BTreeSet;
+
+    use rand::{Rng, RngCore, SeedableRng};
+    use rand::rngs::StdRng;
+
+    use crate::message::{AuthToken, MessageID, MessageLimits, ParseMessageError, WireFormat, parse_message, write_message_as};
+
+    /// At least one of each kind of message, with fields at their edges: empty and non-ASCII
+    /// strings, zero and maximal integers, and options both set and not
+    fn every_message() -> Vec<Message> {
+        let uuid = Uuid::from_u128(0x0190_d1c2_7a3b_7c4d_8e5f_6a7b_8c9d_0e1f);
+        let blake3 = ContentDigest::of(HashAlgorithm::Blake3, b"bnuy");
+        let sha256 = ContentDigest::of(HashAlgorithm::Sha256, b"bnuy");
+        vec![
+            Message::Hello { protocol: ProtocolVersions::OURS, features: vec![], compression: None },
+            Message::Hello {
+                protocol: ProtocolVersions { min: 0, current: u32::MAX },
+                features: vec!["zstd".to_string(), String::new(), "🐇".to_string()],
+                compression: Some(Compression { level: -7, min_bytes: 4096 }),
+            },
+            Message::Authenticate(AuthToken("hunter2".to_string())),
+            Message::GetVersion,
+            Message::ReadFile(uuid),
+            Message::ReadFileRange(uuid, u64::MAX, u32::MAX),
+            Message::WriteFile(uuid, b"contents".to_vec()),
+            Message::WriteFile(Uuid::nil(), vec![]),
+            Message::WriteFileStart(uuid),
+            Message::WriteFileChunk(uuid, vec![0; 1000]),
+            Message::WriteFileEnd(uuid),
+            Message::WriteFileAbort(uuid),
+            Message::WriteFileChecked(uuid, blake3.clone(), b"bnuy".to_vec()),
+            Message::WriteFileEndChecked(uuid, sha256.clone()),
+            Message::DeleteFile(uuid),
+            Message::StatFile(uuid),
+            Message::StorageInfo,
+            Message::GetMetrics,
+            Message::HashFile(uuid, HashAlgorithm::Blake3),
+            Message::HashFile(uuid, HashAlgorithm::Sha256),
+            Message::VerifyFile(uuid),
+            Message::SubscribeActivity,
+            Message::UnsubscribeActivity,
+            Message::ListFiles,
+            Message::Ping,
+
+            Message::HelloBack { protocol: ProtocolVersions::OURS, features: vec!["ping".to_string()] },
+            Message::MyVersionIs("bnuystore 0.1.0 (ünïcödé)".to_string()),
+            Message::FileContents(b"contents".to_vec()),
+            Message::CheckedFileContents { digest: Some(sha256), data: b"bnuy".to_vec() },
+            Message::CheckedFileContents { digest: None, data: vec![] },
+            Message::FileRange { file_size: 12, data: b"tents".to_vec() },
+            Message::FileStat { size: u64::MAX },
+            Message::StorageInfoIs(StorageInfo { bytes_total: 1 << 40, bytes_available: 0, file_count: Some(3) }),
+            Message::StorageInfoIs(StorageInfo { bytes_total: 0, bytes_available: 0, file_count: None }),
+            Message::Metrics(NodeMetrics {
+                files_stored: 1,
+                bytes_stored: 2,
+                bytes_total: 3,
+                bytes_available: 4,
+                reads: 5,
+                writes: 6,
+                bytes_read: 7,
+                bytes_written: 8,
+                errors: 9,
+                lock_acquisitions: 10,
+                lock_wait_us: u64::MAX,
+            }),
+            Message::FileHash { size: 4, digest: blake3 },
+            Message::FileVerified { size: 4, matches: Some(false) },
+            Message::FileVerified { size: 0, matches: None },
+            Message::WriteAck { bytes_written: 8, fsynced: true },
+            Message::FileList(vec![(uuid, 8), (Uuid::max(), u64::MAX)]),
+            Message::FileList(vec![]),
+            Message::Pong,
+            Message::Ack,
+            Message::Error(String::new()),
+            Message::ErrorCode { code: ErrorCode::NotFound, detail: "no such file".to_string() },
+            Message::ErrorCode { code: ErrorCode::NoSpace, detail: String::new() },
+            Message::ErrorCode { code: ErrorCode::Io, detail: String::new() },
+            Message::ErrorCode { code: ErrorCode::BadRequest, detail: String::new() },
+            Message::ErrorCode { code: ErrorCode::ShuttingDown, detail: String::new() },
+            Message::ErrorCode { code: ErrorCode::ChecksumMismatch, detail: String::new() },
+            Message::ErrorCode { code: ErrorCode::Internal, detail: String::new() },
+
+            Message::Activity(ActivityEvent {
+                at_ms: 1_700_000_000_000,
+                op: "WriteFile".to_string(),
+                uuid: Some(uuid),
+                bytes: 8,
+                duration_us: 120,
+                error: Some("disk on fire".to_string()),
+            }),
+            Message::Activity(ActivityEvent { at_ms: 0, op: String::new(), uuid: None, bytes: 0, duration_us: 0, error: None }),
+            Message::ActivityDropped { count: u64::MAX },
+        ]
+    }
+
+    /// Messages don't implement PartialEq, so they're compared by their Debug output, which leaves
+    /// out auth tokens
+    fn assert_same(decoded: &Message, expected: &Message) {
+        assert_eq!(format!("{decoded:?}"), format!("{expected:?}"));
+        if let (Message::Authenticate(decoded), Message::Authenticate(expected)) = (decoded, expected) {
+            assert!(decoded == expected, "auth token changed on the way");
+        }
+    }
+
+    #[test]
+    fn every_message_round_trips() {
+        for message in every_message() {
+            let (header, data) = encode(message.clone());
+            let decoded = decode(&header, data).unwrap_or_else(|e| panic!("{message}: {e:?}"));
+            assert_same(&decoded, &message);
+        }
+    }
+
+    #[test]
+    fn every_tag_is_tested() {
+        let tested: BTreeSet<u8> = every_message().into_iter().map(|message| encode(message).0[0]).collect();
+        // tags the decoder doesn't know fail before looking at the rest of the header
+        let known: BTreeSet<u8> = (0..=u8::MAX)
+            .filter(|&tag| !matches!(decode(&[tag], vec![]), Err(Undecodable::Binary(why)) if why.starts_with("unknown message tag")))
+            .collect();
+        assert_eq!(tested, known);
+    }
+
+    #[tokio::test]
+    async fn every_message_round_trips_over_the_wire() {
+        let formats = [
+            WireFormat { compression: None, binary_headers: true },
+            WireFormat { compression: Some(Compression { level: 3, min_bytes: 0 }), binary_headers: true },
+        ];
+        for format in formats {
+            let mut wire = Vec::new();
+            for (i, message) in every_message().into_iter().enumerate() {
+                write_message_as(&mut wire, MessageID(i as u32), message, &format).await.unwrap();
+            }
+
+            let mut stream = &wire[..];
+            for (i, message) in every_message().into_iter().enumerate() {
+                let (id, decoded) = parse_message(&mut stream, &MessageLimits::default()).await
+                    .unwrap_or_else(|e| panic!("{message}: {e:?}"));
+                assert_eq!(id, MessageID(i as u32));
+                assert_same(&decoded, &message);
+            }
+            assert!(stream.is_empty());
+        }
+    }
+
+    #[test]
+    fn truncated_and_padded_headers_are_refused() {
+        for message in every_message() {
+            let (header, data) = encode(message.clone());
+            for len in 0..header.len() {
+                assert!(decode(&header[..len], data.clone()).is_err(), "{message} decoded from {len} of {} bytes", header.len());
+            }
+            let mut padded = header.clone();
+            padded.push(0);
+            assert!(decode(&padded, data).is_err(), "{message} decoded with a byte too many");
+        }
+    }
+
+    #[test]
+    fn malformed_fields_are_refused() {
+        let uuid = Uuid::now_v7();
+        let (mut header, _) = encode(Message::HashFile(uuid, HashAlgorithm::Blake3));
+        *header.last_mut().unwrap() = 2;
+        assert!(decode(&header, vec![]).is_err());
+
+        let (mut header, _) = encode(Message::WriteAck { bytes_written: 0, fsynced: false });
+        *header.last_mut().unwrap() = 2;
+        assert!(decode(&header, vec![]).is_err());
+
+        let (mut header, _) = encode(Message::ErrorCode { code: ErrorCode::Io, detail: String::new() });
+        header[1] = 200;
+        assert!(decode(&header, vec![]).is_err());
+
+        let (mut header, _) = encode(Message::MyVersionIs("ab".to_string()));
+        *header.last_mut().unwrap() = 0xff;
+        assert!(decode(&header, vec![]).is_err());
+
+        // a length past the end of the header
+        let (mut header, _) = encode(Message::Error("ab".to_string()));
+        header[1..5].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(decode(&header, vec![]).is_err());
+
+        // a count of strings much larger than what follows
+        let (mut header, _) = encode(Message::HelloBack { protocol: ProtocolVersions::OURS, features: vec![] });
+        let count = header.len() - 4;
+        header[count..].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(decode(&header, vec![]).is_err());
+
+        assert!(decode(&[FILE_LIST], vec![0; 25]).is_err());
+    }
+
+    const FUZZ_LIMITS: MessageLimits = MessageLimits { max_header_bytes: 4096, max_data_bytes: 1 << 16 };
+    /// id, header length and data length, and the length once decompressed for compressed data
+    const PREAMBLE_BYTES: usize = 4 + 4 + 8 + 8;
+
+    /// Parses one message off the front of `wire`, which must fail or succeed without panicking.
+    /// Buffers are sized by the lengths in the preamble, so a message larger than FUZZ_LIMITS has to
+    /// be refused right after it, and nothing else may take more than the limits allow
+    async fn parse_fuzzed(wire: &[u8], seed: u64) {
+        let mut stream = wire;
+        let parsed = parse_message(&mut stream, &FUZZ_LIMITS).await;
+        let read = wire.len() - stream.len();
+        match parsed {
+            Err(ParseMessageError::MessageTooLarge { .. }) => {
+                assert!(read <= PREAMBLE_BYTES, "read {read} bytes of a message which is too large (seed {seed})");
+            }
+            _ => {
+                let most = PREAMBLE_BYTES + FUZZ_LIMITS.max_header_bytes as usize + FUZZ_LIMITS.max_data_bytes as usize;
+                assert!(read <= most, "read {read} bytes, more than the limits allow (seed {seed})");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn random_bytes_dont_panic_or_over_allocate() {
+        let seed = rand::thread_rng().next_u64();
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        // bytes with no structure at all
+        for _ in 0..2000 {
+            let mut wire = vec![0; rng.gen_range(0..256)];
+            rng.fill_bytes(&mut wire);
+            parse_fuzzed(&wire, seed).await;
+        }
+
+        // plausible preambles, with random headers and data of the lengths they give, or a little off
+        let tags: Vec<u8> = every_message().into_iter().map(|message| encode(message).0[0]).collect();
+        for _ in 0..5000 {
+            let header_len = rng.gen_range(0..64u32);
+            let data_len = rng.gen_range(0..64u64);
+            let flags = rng.gen_range(0..4u32) << 30;
+            let mut wire = Vec::new();
+            wire.extend_from_slice(&rng.next_u32().to_be_bytes());
+            wire.extend_from_slice(&(header_len | flags).to_be_bytes());
+            wire.extend_from_slice(&data_len.to_be_bytes());
+            if flags & (1 << 31) != 0 {
+                let uncompressed_len = rng.gen_range(0..2 * FUZZ_LIMITS.max_data_bytes);
+                wire.extend_from_slice(&uncompressed_len.to_be_bytes());
+            }
+            let mut body = vec![0; (header_len as u64 + data_len) as usize];
+            rng.fill_bytes(&mut body);
+            // usually a tag that's in use, so that the decoding of fields gets exercised
+            if let (Some(tag), true) = (body.first_mut(), rng.gen_bool(0.8)) {
+                *tag = tags[rng.gen_range(0..tags.len())];
+            }
+            wire.extend_from_slice(&body);
+            wire.truncate(wire.len().saturating_sub(rng.gen_range(0..2)));
+            parse_fuzzed(&wire, seed).await;
+        }
+
+        // real messages with a few bytes changed
+        let formats = [
+            WireFormat { compression: None, binary_headers: true },
+            WireFormat { compression: Some(Compression { level: 1, min_bytes: 0 }), binary_headers: true },
+            WireFormat::default(),
+        ];
+        for message in every_message() {
+            for format in &formats {
+                let mut wire = Vec::new();
+                write_message_as(&mut wire, MessageID(1), message.clone(), format).await.unwrap();
+                for _ in 0..100 {
+                    let mut corrupt = wire.clone();
+                    for _ in 0..rng.gen_range(1..4) {
+                        let i = rng.gen_range(0..corrupt.len());
+                        corrupt[i] = rng.gen();
+                    }
+                    parse_fuzzed(&corrupt, seed).await;
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn huge_lengths_are_refused_before_reading_on() {
+        for (header_len, data_len) in [(u32::MAX >> 2, 0), (0, u64::MAX), (FUZZ_LIMITS.max_header_bytes + 1, FUZZ_LIMITS.max_data_bytes + 1)] {
+            let mut wire = Vec::new();
+            wire.extend_from_slice(&9u32.to_be_bytes());
+            wire.extend_from_slice(&header_len.to_be_bytes());
+            wire.extend_from_slice(&data_len.to_be_bytes());
+            wire.extend_from_slice(&[0; 64]);
+
+            let mut stream = &wire[..];
+            match parse_message(&mut stream, &FUZZ_LIMITS).await {
+                Err(ParseMessageError::MessageTooLarge { id: MessageID(9), .. }) => {}
+                parsed => panic!("expected MessageTooLarge, got {parsed:?}"),
+            }
+            assert_eq!(stream.len(), 64);
+        }
+    }
+}
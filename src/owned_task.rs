@@ -32,6 +32,7 @@ impl OwnedTask {
         }
     }
 
+    #[allow(unused)]
     pub async fn wait_until_finished(&self) {
         if !self.handle.is_finished() {
             // for the was_finished to be notified, either the internal task needs to finish
@@ -1,23 +1,72 @@
 #[allow(unused)]
 use tracing::{trace, debug, info, warn, error, instrument};
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
 use std::mem::drop;
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
 
 use uuid::Uuid;
-use tokio::sync::{RwLock, Notify};
-use tokio::runtime::Handle;
+use tokio::sync::{broadcast, Notify};
+use futures::StreamExt;
 use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use std::io::SeekFrom;
 use std::io::ErrorKind;
 
+use crate::message::{self, Message, ErrorCode, NodeMetrics, StorageInfo, ActivityEvent, Compression, ProtocolVersions, AuthToken, WireFormat, MessageLimits};
+use crate::fault_injection::{FaultInjector, WriteFault};
+use crate::hashing::{ContentDigest, HashAlgorithm, Hasher};
+
+/// Files are read in chunks of this size when hashing, so hashing doesn't load whole files
+const HASH_CHUNK_BYTES: usize = 64 * 1024;
+
+/// How long a file may be locked before it is warned about, unless the node is given another limit
+pub const DEFAULT_LOCK_WARN_AFTER: Duration = Duration::from_secs(5);
+
+/// Files written in chunks are written to their UUID and the write's number with this appended, and
+/// renamed once complete. As these aren't UUIDs, they aren't listed
+const PARTIAL_SUFFIX: &str = ".partial";
+
+/// The digest a file was written with is kept next to it, in a file named its UUID with this
+/// appended. Not listed either
+const DIGEST_SUFFIX: &str = ".digest";
+
+/// Activity events buffered per subscriber. Subscribers falling further behind miss events, so
+/// request handling never waits for them
+const ACTIVITY_BUFFER: usize = 1024;
+
 #[derive(Debug)]
 #[allow(unused)]
 pub enum OperationError {
     NoFileWithUuid(Uuid),
     IOError(std::io::Error),
+    /// the node is shutting down, so no more files can be locked
+    ShuttingDown,
+    /// a response was sent as a request
+    NotARequest,
+    /// a WriteFileChunk or WriteFileEnd without a WriteFileStart
+    NoChunkedWrite(Uuid),
+    /// the data of a checked write doesn't match the digest sent with it
+    ChecksumMismatch { expected: ContentDigest, actual: ContentDigest },
+}
+
+impl OperationError {
+    /// Sent to front nodes with the error-codes feature
+    fn code(&self) -> ErrorCode {
+        match self {
+            OperationError::NoFileWithUuid(_) => ErrorCode::NotFound,
+            OperationError::IOError(e) if matches!(e.kind(), ErrorKind::StorageFull | ErrorKind::QuotaExceeded) => ErrorCode::NoSpace,
+            OperationError::IOError(_) => ErrorCode::Io,
+            OperationError::ShuttingDown => ErrorCode::ShuttingDown,
+            OperationError::NotARequest | OperationError::NoChunkedWrite(_) => ErrorCode::BadRequest,
+            OperationError::ChecksumMismatch { .. } => ErrorCode::ChecksumMismatch,
+        }
+    }
 }
 
 type Result<T> = std::result::Result<T, OperationError>;
@@ -29,52 +78,127 @@ struct NodeInner {
     /// Safety: while running, this folder may not be modified. Files may not be deleted etc.
     data_folder: PathBuf,
 
-    // TODO: We should really track whether each file is being read or written to
-    // If multiple threads wanna read from the same file, that is okay
+    /// Locked files, and whether the node is shutting down. This is a synchronous mutex, so that
+    /// FileLock::drop can release its lock without needing the runtime, which may be shutting down
+    locked_files: Mutex<LockTable>,
 
-    /// List of locked files on disk. Each item in the map is locked, with a debugging string
-    /// attached, saying why it's locked. Debugging strings are useful for diagnosing deadlocks
-    locked_files: RwLock<HashMap<Uuid, String>>,
-
-    /// Whenever a file is unlocked, this notify is notified to make any pending lock_file calls
-    /// re-check if their file has been unlocked.
+    /// Whenever a file is unlocked, or the node starts shutting down, this notify is notified to make
+    /// any pending lock_file calls re-check if their file has been unlocked.
     file_unlocked: Notify,
+    /// locks held longer than this are warned about, again each time this passes. zero to never warn
+    lock_warn_after: Duration,
+
+    faults: FaultInjector,
+
+    counters: Counters,
+
+    /// operations performed, for connections subscribed with SubscribeActivity
+    activity: broadcast::Sender<ActivityEvent>,
+
+    /// connections have to send this in Authenticate before anything else. None to accept anyone who
+    /// can connect
+    auth_token: Option<AuthToken>,
+
+    /// numbers chunked writes, so that writes of the same UUID on different connections each have
+    /// their own partial file
+    next_chunked_write: AtomicU64,
+}
+
+#[derive(Default)]
+struct LockTable {
+    /// Each item in the map is locked, with debugging strings attached, saying why it's locked.
+    /// Debugging strings are useful for diagnosing deadlocks
+    locked: HashMap<Uuid, LockState>,
+    shutting_down: bool,
+}
+
+#[derive(Debug)]
+enum LockState {
+    /// any number of readers, with the reason of each
+    Read(Vec<String>),
+    Write(String),
+}
+
+impl LockState {
+    fn reasons(&self) -> &[String] {
+        match self {
+            LockState::Read(reasons) => reasons,
+            LockState::Write(reason) => std::slice::from_ref(reason),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LockMode {
+    /// shared with other readers
+    Read,
+    /// exclusive
+    Write,
+}
+
+/// Totals since the node started, reported in NodeMetrics
+#[derive(Default)]
+struct Counters {
+    reads: AtomicU64,
+    writes: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    errors: AtomicU64,
+    lock_acquisitions: AtomicU64,
+    lock_wait_us: AtomicU64,
 }
 
 pub struct FileLock {
     for_uuid: Uuid,
+    mode: LockMode,
+    /// to find this lock's reason among the readers' on release
+    reason: String,
     node: Node,
-    runtime_handle: Option<Handle>,
+    /// warns while the lock is held. aborted on release
+    watchdog: Option<tokio::task::JoinHandle<()>>,
 }
 
 impl std::fmt::Debug for FileLock {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "FileLock {{ for_uuid = {}, .. }}", self.for_uuid)
+        write!(f, "FileLock {{ for_uuid = {}, mode = {:?}, .. }}", self.for_uuid, self.mode)
     }
 }
 
 impl Drop for FileLock {
     fn drop(&mut self) {
+        if let Some(ref watchdog) = self.watchdog {
+            watchdog.abort();
+        }
         let for_uuid = self.for_uuid;
         trace!(%for_uuid, "Releasing lock");
 
-        let node = self.node.0.clone();
-        let runtime_handle = self.runtime_handle.take().expect("Dropped FileLock multiple times");
-        let for_uuid = self.for_uuid.clone();
-
-        runtime_handle.spawn(async move {
-            let mut locked_files = node.locked_files.write().await;
-            match locked_files.remove(&for_uuid) {
-                Some(reason) => {
-                    trace!(%for_uuid, reason, "Lock released");
-                }
-                None => {
-                    warn!(%for_uuid, "Lock was not held");
+        let node = &self.node.0;
+        // a poisoned table still has to be unlocked, or waiters would hang
+        let mut locked_files = node.locked_files.lock().unwrap_or_else(|e| e.into_inner());
+        let released = match (locked_files.locked.get_mut(&for_uuid), self.mode) {
+            (Some(LockState::Read(reasons)), LockMode::Read) => match reasons.iter().position(|reason| *reason == self.reason) {
+                Some(i) => {
+                    reasons.swap_remove(i);
+                    if reasons.is_empty() {
+                        locked_files.locked.remove(&for_uuid);
+                    }
+                    true
                 }
+                None => false,
+            },
+            (Some(LockState::Write(_)), LockMode::Write) => {
+                locked_files.locked.remove(&for_uuid);
+                true
             }
-            drop(locked_files);
-            node.file_unlocked.notify_waiters();
-        });
+            _ => false,
+        };
+        if released {
+            trace!(%for_uuid, reason = self.reason, mode = ?self.mode, "Lock released");
+        } else {
+            warn!(%for_uuid, reason = self.reason, mode = ?self.mode, "Lock was not held");
+        }
+        drop(locked_files);
+        node.file_unlocked.notify_waiters();
     }
 }
 
@@ -97,11 +221,15 @@ impl FileLock {
             .open(&path)
             .await;
 
+        if let Some(kind) = self.node.0.faults.next_read_fault() {
+            return Err(OperationError::IOError(kind.into()));
+        }
+
         let mut f = match fres {
             Ok(f) => f,
             Err(e) if e.kind() == ErrorKind::NotFound => {
                 error!("Could not read file: not found");
-                return Err(OperationError::NoFileWithUuid(self.for_uuid.clone()));
+                return Err(OperationError::NoFileWithUuid(self.for_uuid));
             }
             Err(e) => {
                 error!(?e, "Could not read file");
@@ -118,34 +246,203 @@ impl FileLock {
         Ok(buf)
     }
 
-    #[instrument(level = "debug", skip(data), fields(data.len = data.len()))]
-    pub async fn write(&self, data: Vec<u8>) -> Result<()> {
+    /// Reads at most `len` bytes from `offset`. Returns the size of the whole file too. The data is
+    /// empty if `offset` is at or past the end
+    #[instrument(level = "debug")]
+    pub async fn read_range(&self, offset: u64, len: u32) -> Result<(u64, Vec<u8>)> {
         let path = self.path();
-        let mut f = File::options()
-            .write(true)
-            .create(true)
-            .open(&path)
-            .await
-            .map_err(OperationError::IOError)?;
+        let fres = File::open(&path).await;
 
-        trace!(path = %path.display(), "File opened");
+        if let Some(kind) = self.node.0.faults.next_read_fault() {
+            return Err(OperationError::IOError(kind.into()));
+        }
+
+        let mut f = match fres {
+            Ok(f) => f,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                error!("Could not read file: not found");
+                return Err(OperationError::NoFileWithUuid(self.for_uuid));
+            }
+            Err(e) => {
+                error!(?e, "Could not read file");
+                return Err(OperationError::IOError(e));
+            }
+        };
+        let file_size = f.metadata().await.map_err(OperationError::IOError)?.len();
+        if offset >= file_size {
+            trace!(file_size, "Read past end of file");
+            return Ok((file_size, Vec::new()));
+        }
 
-        f.write_all(&data).await.map_err(OperationError::IOError)?;
+        f.seek(SeekFrom::Start(offset)).await.map_err(OperationError::IOError)?;
+        let mut buf = Vec::with_capacity((len as u64).min(file_size - offset) as usize);
+        f.take(len as u64).read_to_end(&mut buf).await.map_err(OperationError::IOError)?;
 
-        trace!(path = %path.display(), "Wrote");
+        trace!(file_size, n_bytes = buf.len(), "Read range");
+
+        Ok((file_size, buf))
+    }
+
+    /// Returns the size and digest of the file
+    #[instrument(level = "debug")]
+    pub async fn hash(&self, algorithm: HashAlgorithm) -> Result<(u64, ContentDigest)> {
+        let path = self.path();
+        let f = match File::open(&path).await {
+            Ok(f) => f,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                error!("Could not hash file: not found");
+                return Err(OperationError::NoFileWithUuid(self.for_uuid));
+            }
+            Err(e) => {
+                error!(?e, "Could not hash file");
+                return Err(OperationError::IOError(e));
+            }
+        };
+        let (size, digest) = hash_file(f, algorithm).await?;
+        trace!(size, %digest, "Hashed file");
+
+        Ok((size, digest))
+    }
+
+    fn digest_path(&self) -> PathBuf {
+        let mut path = self.path().into_os_string();
+        path.push(DIGEST_SUFFIX);
+        path.into()
+    }
+
+    /// The digest the file was written with. None for files written without one
+    #[instrument(level = "debug")]
+    pub async fn read_digest(&self) -> Result<Option<ContentDigest>> {
+        let path = self.digest_path();
+        let stored = match tokio::fs::read_to_string(&path).await {
+            Ok(stored) => stored,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                error!(?e, path = %path.display(), "Could not read digest");
+                return Err(OperationError::IOError(e));
+            }
+        };
+        match ContentDigest::parse(stored.trim()) {
+            Some(digest) => Ok(Some(digest)),
+            None => {
+                warn!(path = %path.display(), stored, "Ignoring unreadable digest");
+                Ok(None)
+            }
+        }
+    }
+
+    /// Keeps the digest the file was written with, or removes the one of its old contents
+    #[instrument(level = "debug")]
+    pub async fn write_digest(&self, digest: Option<&ContentDigest>) -> Result<()> {
+        debug_assert_eq!(self.mode, LockMode::Write, "writing under a read lock");
+        let path = self.digest_path();
+        let Some(digest) = digest else {
+            return match tokio::fs::remove_file(&path).await {
+                Ok(()) => Ok(()),
+                Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+                Err(e) => {
+                    error!(?e, path = %path.display(), "Could not remove old digest");
+                    Err(OperationError::IOError(e))
+                }
+            };
+        };
+        let mut f = File::create(&path).await.map_err(OperationError::IOError)?;
+        f.write_all(digest.to_string().as_bytes()).await.map_err(OperationError::IOError)?;
+        f.sync_all().await.map_err(OperationError::IOError)?;
+
+        trace!(path = %path.display(), %digest, "Wrote digest");
 
         Ok(())
     }
 
-    #[allow(unused)]
+    #[instrument(level = "debug", skip(data), fields(data.len = data.len()))]
+    /// Returns the size of the file once written and fsynced, which is less than `data.len()` if the
+    /// write was cut short. The data goes to a partial file which then replaces the old one, so a
+    /// failed write leaves the old contents and digest as they were. The digest is removed, for the
+    /// caller to write the new one
+    pub async fn write(&self, data: Vec<u8>) -> Result<u64> {
+        debug_assert_eq!(self.mode, LockMode::Write, "writing under a read lock");
+        let path = self.path();
+        let partial = self.node.partial_path(self.for_uuid);
+        let written: Result<u64> = async {
+            let mut f = File::options()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&partial)
+                .await
+                .map_err(OperationError::IOError)?;
+
+            trace!(path = %partial.display(), "Partial file opened");
+
+            let data = match self.node.0.faults.next_write_fault() {
+                Some(WriteFault::IOError(kind)) => return Err(OperationError::IOError(kind.into())),
+                Some(WriteFault::ShortWrite(n)) => &data[..n.min(data.len())],
+                None => &data[..],
+            };
+            f.write_all(data).await.map_err(OperationError::IOError)?;
+            f.sync_all().await.map_err(OperationError::IOError)?;
+            // the old digest goes first, so a crash can't leave the new contents with it
+            self.write_digest(None).await?;
+            tokio::fs::rename(&partial, &path).await.map_err(OperationError::IOError)?;
+            Ok(f.metadata().await.map_err(OperationError::IOError)?.len())
+        }.await;
+        let written = match written {
+            Ok(written) => written,
+            Err(e) => {
+                error!(?e, path = %partial.display(), "Could not write partial file");
+                if let Err(e) = tokio::fs::remove_file(&partial).await {
+                    if e.kind() != ErrorKind::NotFound {
+                        warn!(?e, path = %partial.display(), "Could not remove partial file");
+                    }
+                }
+                return Err(e);
+            }
+        };
+
+        trace!(path = %path.display(), written, "Wrote");
+
+        Ok(written)
+    }
+
+    /// Replaces the file with the finished file of a chunked write. Returns its size
+    #[instrument(level = "debug")]
+    async fn replace_with(&self, partial: &Path) -> Result<u64> {
+        debug_assert_eq!(self.mode, LockMode::Write, "replacing under a read lock");
+        let path = self.path();
+        tokio::fs::rename(partial, &path).await.map_err(OperationError::IOError)?;
+        let written = tokio::fs::metadata(&path).await.map_err(OperationError::IOError)?.len();
+
+        trace!(path = %path.display(), written, "Replaced with partial file");
+
+        Ok(written)
+    }
+
+    #[instrument(level = "debug")]
+    pub async fn size(&self) -> Result<u64> {
+        let path = self.path();
+        match tokio::fs::metadata(&path).await {
+            Ok(metadata) => Ok(metadata.len()),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                error!(path = %path.display(), "Could not stat file: not found");
+                Err(OperationError::NoFileWithUuid(self.for_uuid))
+            }
+            Err(e) => {
+                error!(?e, path = %path.display(), "Could not stat file");
+                Err(OperationError::IOError(e))
+            }
+        }
+    }
+
     #[instrument(level = "debug")]
     pub async fn delete(&self) -> Result<()> {
+        debug_assert_eq!(self.mode, LockMode::Write, "deleting under a read lock");
         let path = self.path();
         match tokio::fs::remove_file(&path).await {
-            Ok(_) => Ok(()),
+            Ok(_) => self.write_digest(None).await,
             Err(e) if e.kind() == ErrorKind::NotFound => {
                 error!(path = %path.display(), "Could not delete file: not found");
-                return Err(OperationError::NoFileWithUuid(self.for_uuid.clone()));
+                return Err(OperationError::NoFileWithUuid(self.for_uuid));
             }
             Err(e) => {
                 error!(?e, path = %path.display(), "Could not delete file");
@@ -155,43 +452,1280 @@ impl FileLock {
     }
 }
 
+/// Size and digest of what's left of an open file
+async fn hash_file(mut f: File, algorithm: HashAlgorithm) -> Result<(u64, ContentDigest)> {
+    let mut hasher = Hasher::new(algorithm);
+    let mut size = 0;
+    let mut buf = vec![0; HASH_CHUNK_BYTES];
+    loop {
+        let n = f.read(&mut buf).await.map_err(OperationError::IOError)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        size += n as u64;
+    }
+    Ok((size, hasher.finalize()))
+}
+
+/// Whether a file in the data folder is a stored file, rather than a digest or an unfinished write
+fn is_stored_file(name: &std::ffi::OsStr) -> bool {
+    !name.to_str().is_some_and(|name| name.ends_with(DIGEST_SUFFIX) || name.ends_with(PARTIAL_SUFFIX))
+}
+
 impl Node {
-    pub async fn new(data_folder: PathBuf) -> Result<Node> {
+    /// Locks held longer than `lock_warn_after` are logged, with the reason they were taken
+    pub async fn new(data_folder: PathBuf, lock_warn_after: Duration, auth_token: Option<AuthToken>) -> Result<Node> {
+        Node::with_faults(data_folder, lock_warn_after, auth_token, FaultInjector::default()).await
+    }
+
+    pub async fn with_faults(
+        data_folder: PathBuf,
+        lock_warn_after: Duration,
+        auth_token: Option<AuthToken>,
+        faults: FaultInjector,
+    ) -> Result<Node> {
         if !data_folder.exists() {
             debug!(data_folder = %data_folder.display(), "Creating data folder");
             tokio::fs::create_dir(&data_folder).await.map_err(OperationError::IOError)?;
         }
 
+        // left by writes which were cut short by the node stopping
+        let mut entries = tokio::fs::read_dir(&data_folder).await.map_err(OperationError::IOError)?;
+        while let Some(entry) = entries.next_entry().await.map_err(OperationError::IOError)? {
+            if entry.file_name().to_str().is_some_and(|name| name.ends_with(PARTIAL_SUFFIX)) {
+                warn!(path = %entry.path().display(), "Removing partial file of an unfinished write");
+                tokio::fs::remove_file(entry.path()).await.map_err(OperationError::IOError)?;
+            }
+        }
+
         Ok(Node(Arc::new(NodeInner {
             data_folder,
-            locked_files: RwLock::new(HashMap::new()),
+            locked_files: Mutex::new(LockTable::default()),
             file_unlocked: Notify::new(),
+            lock_warn_after,
+            faults,
+            counters: Counters::default(),
+            activity: broadcast::channel(ACTIVITY_BUFFER).0,
+            auth_token,
+            next_chunked_write: AtomicU64::new(0),
         })))
     }
 
+    /// Total and available bytes on the filesystem the data folder is on
+    // the casts are no-ops on 64-bit targets only
+    #[allow(clippy::unnecessary_cast)]
+    #[instrument(level = "debug", skip(self))]
+    pub fn storage_info(&self) -> Result<(u64, u64)> {
+        let path = CString::new(self.0.data_folder.as_os_str().as_bytes())
+            .map_err(|e| OperationError::IOError(e.into()))?;
+
+        // Safety: path is a valid nul-terminated string, and statvfs only writes into stat
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+            let e = std::io::Error::last_os_error();
+            error!(?e, "Could not statvfs data folder");
+            return Err(OperationError::IOError(e));
+        }
+
+        let fragment_size = stat.f_frsize as u64;
+        Ok((stat.f_blocks as u64 * fragment_size, stat.f_bavail as u64 * fragment_size))
+    }
+
+    /// A new path for a chunked write of the UUID
+    fn partial_path(&self, uuid: Uuid) -> PathBuf {
+        let write = self.0.next_chunked_write.fetch_add(1, Ordering::Relaxed);
+        self.0.data_folder.join(format!("{}.{write}{PARTIAL_SUFFIX}", uuid.hyphenated()))
+    }
+
+    /// Number of files in the data folder. Lists the whole folder, but without looking at each file
+    #[instrument(level = "debug", skip(self))]
+    pub async fn file_count(&self) -> Result<u64> {
+        let mut file_count = 0;
+        let mut entries = tokio::fs::read_dir(&self.0.data_folder).await.map_err(OperationError::IOError)?;
+        while let Some(entry) = entries.next_entry().await.map_err(OperationError::IOError)? {
+            // files may be removed while listing
+            if entry.file_type().await.is_ok_and(|file_type| file_type.is_file()) && is_stored_file(&entry.file_name()) {
+                file_count += 1;
+            }
+        }
+        Ok(file_count)
+    }
+
+    /// UUID and size of every file in the data folder. No files are locked, so files being written
+    /// while listing may be listed with a partial size, and files deleted meanwhile are left out
+    #[instrument(level = "debug", skip(self))]
+    pub async fn list_files(&self) -> Result<Vec<(Uuid, u64)>> {
+        let mut files = Vec::new();
+        let mut entries = tokio::fs::read_dir(&self.0.data_folder).await.map_err(OperationError::IOError)?;
+        while let Some(entry) = entries.next_entry().await.map_err(OperationError::IOError)? {
+            let Some(uuid) = entry.file_name().to_str().and_then(|name| Uuid::try_parse(name).ok()) else {
+                trace!(name = ?entry.file_name(), "Not listing file without a UUID name");
+                continue;
+            };
+            if let Ok(metadata) = entry.metadata().await {
+                if metadata.is_file() {
+                    files.push((uuid, metadata.len()));
+                }
+            }
+        }
+        debug!(n_files = files.len(), "Listed files");
+        Ok(files)
+    }
+
+    /// Counters, plus the number and size of stored files. Counting the files lists the whole data
+    /// folder, so this shouldn't be called too often
+    #[instrument(level = "debug", skip(self))]
+    pub async fn metrics(&self) -> Result<NodeMetrics> {
+        let mut files_stored = 0;
+        let mut bytes_stored = 0;
+        let mut entries = tokio::fs::read_dir(&self.0.data_folder).await.map_err(OperationError::IOError)?;
+        while let Some(entry) = entries.next_entry().await.map_err(OperationError::IOError)? {
+            if !is_stored_file(&entry.file_name()) {
+                continue;
+            }
+            // files may be removed while listing
+            if let Ok(metadata) = entry.metadata().await {
+                if metadata.is_file() {
+                    files_stored += 1;
+                    bytes_stored += metadata.len();
+                }
+            }
+        }
+        let (bytes_total, bytes_available) = self.storage_info()?;
+
+        let counters = &self.0.counters;
+        Ok(NodeMetrics {
+            files_stored,
+            bytes_stored,
+            bytes_total,
+            bytes_available,
+            reads: counters.reads.load(Ordering::Relaxed),
+            writes: counters.writes.load(Ordering::Relaxed),
+            bytes_read: counters.bytes_read.load(Ordering::Relaxed),
+            bytes_written: counters.bytes_written.load(Ordering::Relaxed),
+            errors: counters.errors.load(Ordering::Relaxed),
+            lock_acquisitions: counters.lock_acquisitions.load(Ordering::Relaxed),
+            lock_wait_us: counters.lock_wait_us.load(Ordering::Relaxed),
+        })
+    }
+
+    /// Sends the handling of a request to activity subscribers, if there are any
+    fn record_activity(&self, request: &Message, result: &Result<Message>, duration: Duration) {
+        if self.0.activity.receiver_count() == 0 {
+            return;
+        }
+        let (op, uuid) = match request {
+            Message::ReadFile(uuid) => ("ReadFile", Some(*uuid)),
+            Message::ReadFileRange(uuid, ..) => ("ReadFileRange", Some(*uuid)),
+            Message::WriteFile(uuid, _) => ("WriteFile", Some(*uuid)),
+            Message::WriteFileStart(uuid) => ("WriteFileStart", Some(*uuid)),
+            Message::WriteFileChunk(uuid, _) => ("WriteFileChunk", Some(*uuid)),
+            Message::WriteFileEnd(uuid) => ("WriteFileEnd", Some(*uuid)),
+            Message::WriteFileAbort(uuid) => ("WriteFileAbort", Some(*uuid)),
+            Message::WriteFileChecked(uuid, ..) => ("WriteFileChecked", Some(*uuid)),
+            Message::WriteFileEndChecked(uuid, _) => ("WriteFileEndChecked", Some(*uuid)),
+            Message::DeleteFile(uuid) => ("DeleteFile", Some(*uuid)),
+            Message::StatFile(uuid) => ("StatFile", Some(*uuid)),
+            Message::HashFile(uuid, _) => ("HashFile", Some(*uuid)),
+            Message::VerifyFile(uuid) => ("VerifyFile", Some(*uuid)),
+            Message::StorageInfo => ("StorageInfo", None),
+            Message::GetMetrics => ("GetMetrics", None),
+            Message::GetVersion => ("GetVersion", None),
+            Message::ListFiles => ("ListFiles", None),
+            // connection setup and heartbeats rather than operations
+            _ => return,
+        };
+        let bytes = match (request, result) {
+            (_, Ok(Message::FileContents(data) | Message::CheckedFileContents { data, .. })) => data.len() as u64,
+            (_, Ok(Message::FileRange { data, .. })) => data.len() as u64,
+            (_, Ok(Message::WriteAck { bytes_written, .. })) => *bytes_written,
+            (_, Ok(Message::FileHash { size, .. } | Message::FileVerified { size, .. })) => *size,
+            (Message::WriteFile(_, data) | Message::WriteFileChunk(_, data) | Message::WriteFileChecked(_, _, data), _) => data.len() as u64,
+            _ => 0,
+        };
+        let at_ms = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_millis() as u64);
+        // fails only if everyone unsubscribed since the check above
+        let _ = self.0.activity.send(ActivityEvent {
+            at_ms,
+            op: op.to_string(),
+            uuid,
+            bytes,
+            duration_us: duration.as_micros() as u64,
+            error: result.as_ref().err().map(|e| format!("{e:?}")),
+        });
+    }
+
+    /// Makes all pending and future lock_file calls fail with OperationError::ShuttingDown.
+    /// Locks which are already held are unaffected
+    #[instrument(level = "info", skip(self))]
+    pub fn shut_down(&self) {
+        let mut locked_files = self.0.locked_files.lock().unwrap_or_else(|e| e.into_inner());
+        locked_files.shutting_down = true;
+        if !locked_files.locked.is_empty() {
+            info!(locked = ?locked_files.locked, "Shutting down with files locked");
+        }
+        drop(locked_files);
+        self.0.file_unlocked.notify_waiters();
+    }
+
+    /// Waits for every file to be unlocked, for at most `timeout`. Returns whether they were. Call
+    /// after shut_down, as otherwise new locks can keep this waiting
+    #[instrument(level = "info", skip(self))]
+    pub async fn wait_unlocked(&self, timeout: Duration) -> bool {
+        let deadline = tokio::time::sleep(timeout);
+        tokio::pin!(deadline);
+        loop {
+            let unlocked = self.0.file_unlocked.notified();
+            tokio::pin!(unlocked);
+            unlocked.as_mut().enable();
+
+            {
+                let locked_files = self.0.locked_files.lock().unwrap_or_else(|e| e.into_inner());
+                if locked_files.locked.is_empty() {
+                    return true;
+                }
+                debug!(n_locked = locked_files.locked.len(), "Waiting for files to be unlocked");
+            }
+
+            tokio::select! {
+                _ = &mut unlocked => {}
+                _ = &mut deadline => {
+                    let locked_files = self.0.locked_files.lock().unwrap_or_else(|e| e.into_inner());
+                    warn!(locked = ?locked_files.locked, "Files still locked after the shutdown grace period");
+                    return false;
+                }
+            }
+        }
+    }
+
+    /// Syncs the data folder, so that files created, renamed or removed before this survive a crash.
+    /// Their contents are synced as they are written
+    #[instrument(level = "info", skip(self))]
+    pub async fn sync_data_folder(&self) -> Result<()> {
+        let folder = File::open(&self.0.data_folder).await.map_err(OperationError::IOError)?;
+        folder.sync_all().await.map_err(OperationError::IOError)
+    }
+
+    /// Refuses new requests, and gives the ones holding locks at most `grace` to finish before
+    /// syncing the data folder. Writes which don't get their lock are refused, so each file is
+    /// either written whole or left as it was
+    #[instrument(level = "info", skip(self))]
+    pub async fn stop(&self, grace: Duration) {
+        self.shut_down();
+        if self.wait_unlocked(grace).await {
+            info!("All files unlocked");
+        }
+        if let Err(e) = self.sync_data_folder().await {
+            error!(?e, "Could not sync data folder");
+        }
+    }
+
+    /// Lets other readers, but no writers, access this file. Waits for any writer to finish first.
+    /// The lock is released when the FileLock is dropped.
+    pub async fn lock_file_read(&self, uuid: &Uuid, reason: &str) -> Result<FileLock> {
+        self.lock_file(uuid, reason, LockMode::Read).await
+    }
+
     /// Block any other task from accessing this file.
-    /// If the file is already locked, this function waits until the file is unlocked to continue
-    /// MAKE SURE to call `unlock_file` to drop the lock.
+    /// If the file is already locked, this function waits until the file is unlocked to continue.
+    /// The lock is released when the FileLock is dropped.
+    pub async fn lock_file_write(&self, uuid: &Uuid, reason: &str) -> Result<FileLock> {
+        self.lock_file(uuid, reason, LockMode::Write).await
+    }
 
-    // TODO: maybe start a task that waits for 3 seconds or something, sees if the file is still locked and logs a
-    // warning (we probably don't want files to be locked for that long)
     #[instrument(level = "trace", skip(self))]
-    pub async fn lock_file(&self, uuid: &Uuid, reason: &str) -> FileLock {
+    async fn lock_file(&self, uuid: &Uuid, reason: &str, mode: LockMode) -> Result<FileLock> {
+        let started = Instant::now();
         loop {
-            let mut locked_files = self.0.locked_files.write().await;
-            if !locked_files.contains_key(&uuid) {
-                trace!(%uuid, reason, "Locked file");
-                locked_files.insert(uuid.clone(), reason.to_string());
-                return FileLock {
-                    for_uuid: uuid.clone(),
-                    node: self.clone(),
-                    runtime_handle: Some(Handle::current()),
+            // registered before checking, so an unlock between the check and the await isn't missed
+            let unlocked = self.0.file_unlocked.notified();
+            tokio::pin!(unlocked);
+            unlocked.as_mut().enable();
+
+            // scoped rather than dropped, as the guard isn't Send and must not look held across the await
+            {
+                let mut locked_files = self.0.locked_files.lock().unwrap_or_else(|e| e.into_inner());
+                if locked_files.shutting_down {
+                    debug!(%uuid, reason, "Not locking file, shutting down");
+                    return Err(OperationError::ShuttingDown);
+                }
+                let acquired = match (locked_files.locked.get_mut(uuid), mode) {
+                    (None, LockMode::Read) => {
+                        locked_files.locked.insert(*uuid, LockState::Read(vec![reason.to_string()]));
+                        true
+                    }
+                    (None, LockMode::Write) => {
+                        locked_files.locked.insert(*uuid, LockState::Write(reason.to_string()));
+                        true
+                    }
+                    (Some(LockState::Read(reasons)), LockMode::Read) => {
+                        reasons.push(reason.to_string());
+                        true
+                    }
+                    (Some(state), _) => {
+                        debug!(%uuid, reason, ?mode, held_for = ?state.reasons(), "File already locked, waiting...");
+                        false
+                    }
                 };
+                if acquired {
+                    trace!(%uuid, reason, ?mode, "Locked file");
+                    let counters = &self.0.counters;
+                    counters.lock_acquisitions.fetch_add(1, Ordering::Relaxed);
+                    counters.lock_wait_us.fetch_add(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+                    let warn_after = self.0.lock_warn_after;
+                    let watchdog = (!warn_after.is_zero())
+                        .then(|| tokio::spawn(watch_lock(*uuid, reason.to_string(), mode, warn_after)));
+                    return Ok(FileLock {
+                        for_uuid: *uuid,
+                        mode,
+                        reason: reason.to_string(),
+                        node: self.clone(),
+                        watchdog,
+                    });
+                }
             }
-            debug!(%uuid, reason, "File already locked, waiting...");
-            drop(locked_files);
             // if the file is locked, we wait until some file has been unlocked and we try again
-            self.0.file_unlocked.notified().await;
+            unlocked.await;
+        }
+    }
+}
+
+/// Warns each time `warn_after` passes, until aborted when the lock is released. Long-held locks
+/// make every other request for the file wait, e.g. behind a stalled upload
+async fn watch_lock(uuid: Uuid, reason: String, mode: LockMode, warn_after: Duration) {
+    let mut held_for = Duration::ZERO;
+    loop {
+        tokio::time::sleep(warn_after).await;
+        held_for += warn_after;
+        warn!(%uuid, reason, ?mode, ?held_for, "File has been locked for a long time");
+    }
+}
+
+/// Protocol features this node supports, see Message::Hello
+const SUPPORTED_FEATURES: &[&str] = &[
+    message::FEATURE_WRITE_ACK,
+    message::FEATURE_ERROR_CODES,
+    message::FEATURE_READ_RANGE,
+    message::FEATURE_CHUNKED_WRITE,
+    message::FEATURE_CHECKSUMS,
+    message::FEATURE_ZSTD,
+    message::FEATURE_PING,
+];
+
+/// Per-connection state, set up by the front node's Authenticate and Hello
+#[derive(Debug, Default)]
+struct ConnectionState {
+    /// set once Authenticate was sent the node's auth token, or right away if it has none
+    authenticated: bool,
+    write_ack: bool,
+    error_codes: bool,
+    checksums: bool,
+    /// for responses, as the front node asked in Hello. None unless zstd was agreed on
+    compression: Option<Compression>,
+    /// set if the agreed protocol version has binary headers
+    binary_headers: bool,
+    /// set while the connection is subscribed to activity
+    activity: Option<broadcast::Receiver<ActivityEvent>>,
+    /// started with WriteFileStart, and not yet ended or aborted
+    chunked_writes: HashMap<Uuid, ChunkedWrite>,
+}
+
+impl ConnectionState {
+    fn wire_format(&self) -> WireFormat {
+        WireFormat { compression: self.compression, binary_headers: self.binary_headers }
+    }
+}
+
+/// A file being written in chunks. Nothing is locked until it is ended, as the partial file is only
+/// seen by this connection
+#[derive(Debug)]
+struct ChunkedWrite {
+    file: File,
+    path: PathBuf,
+    written: u64,
+    /// from a ShortWrite fault, bytes past this are dropped
+    limit: Option<u64>,
+}
+
+impl ChunkedWrite {
+    /// Moves the partial file into place, replacing any file with the UUID. Returns its size. With
+    /// a digest, the partial file is checked against it first
+    async fn finish(self, node: &Node, uuid: Uuid, digest: Option<&ContentDigest>) -> Result<u64> {
+        let result = async {
+            self.file.sync_all().await.map_err(OperationError::IOError)?;
+            if let Some(expected) = digest {
+                let partial = File::open(&self.path).await.map_err(OperationError::IOError)?;
+                let (_, actual) = hash_file(partial, expected.algorithm).await?;
+                check_digest(uuid, expected, actual)?;
+            }
+            let lock = node.lock_file_write(&uuid, "WriteFileEnd request").await?;
+            lock.write_digest(None).await?;
+            let written = lock.replace_with(&self.path).await?;
+            lock.write_digest(digest).await?;
+            Ok(written)
+        }.await;
+        if result.is_err() {
+            self.discard().await;
+        }
+        result
+    }
+
+    async fn discard(self) {
+        drop(self.file);
+        match tokio::fs::remove_file(&self.path).await {
+            Ok(()) => trace!(path = %self.path.display(), "Removed partial file"),
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => error!(?e, path = %self.path.display(), "Could not remove partial file"),
+        }
+    }
+}
+
+fn check_digest(uuid: Uuid, expected: &ContentDigest, actual: ContentDigest) -> Result<()> {
+    if actual != *expected {
+        error!(%uuid, %expected, %actual, "Data does not match the digest it was sent with");
+        return Err(OperationError::ChecksumMismatch { expected: expected.clone(), actual });
+    }
+    Ok(())
+}
+
+/// Writes a whole file, and keeps its digest if it was sent with one
+async fn write_file(
+    node: &Node,
+    state: &ConnectionState,
+    uuid: &Uuid,
+    data: &[u8],
+    digest: Option<&ContentDigest>,
+) -> Result<Message> {
+    let lock = node.lock_file_write(uuid, "WriteFile request").await?;
+    let bytes_written = lock.write(data.to_vec()).await?;
+    lock.write_digest(digest).await?;
+    node.0.counters.writes.fetch_add(1, Ordering::Relaxed);
+    node.0.counters.bytes_written.fetch_add(bytes_written, Ordering::Relaxed);
+    if bytes_written != data.len() as u64 {
+        warn!(%uuid, bytes_written, expected = data.len(), "Short write");
+    }
+
+    Ok(if state.write_ack {
+        Message::WriteAck { bytes_written, fsynced: true }
+    } else {
+        Message::Ack
+    })
+}
+
+// never resolves while unsubscribed
+async fn next_activity(
+    activity: &mut Option<broadcast::Receiver<ActivityEvent>>,
+) -> std::result::Result<ActivityEvent, broadcast::error::RecvError> {
+    match activity {
+        Some(activity) => activity.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Accepts front node connections on `listener`, over TLS if there is an acceptor, until `shutdown`
+/// resolves. Then new requests and requests waiting for a lock get an error, while the ones holding
+/// a lock get `grace` to finish. storage_node_main shuts down on SIGTERM and ctrl-c
+// dev mode connects its node in-process instead
+#[cfg_attr(feature = "dev-mode", allow(unused))]
+pub async fn serve(
+    node: Node,
+    listener: tokio::net::TcpListener,
+    tls: Option<tokio_rustls::TlsAcceptor>,
+    limits: MessageLimits,
+    grace: Duration,
+    shutdown: impl std::future::Future<Output = ()>,
+) {
+    tokio::pin!(shutdown);
+    loop {
+        let (stream, addr) = tokio::select! {
+            accepted = listener.accept() => accepted.expect("Could not accept connection"),
+            _ = &mut shutdown => break,
+        };
+        info!(%addr, "Got a connection");
+
+        let node = node.clone();
+        match tls {
+            Some(ref acceptor) => {
+                let accepting = acceptor.accept(stream);
+                tokio::task::spawn(async move {
+                    match accepting.await {
+                        Ok(stream) => serve_connection(node, stream, limits).await,
+                        Err(e) => warn!(%addr, ?e, "TLS handshake failed"),
+                    }
+                });
+            }
+            None => {
+                tokio::task::spawn(serve_connection(node, stream, limits));
+            }
+        }
+    }
+
+    info!("Shutting down");
+    drop(listener);
+    node.stop(grace).await;
+}
+
+/// Handles messages from a front node until the stream is closed. Activity events are sent in
+/// between responses while subscribed. Messages over `limits` are skipped and answered with an error
+pub async fn serve_connection<S: AsyncRead + AsyncWrite + Unpin>(node: Node, stream: S, limits: MessageLimits) {
+    let (read, mut stream) = tokio::io::split(stream);
+    // a stream, rather than calling parse_message in the select, as a partly parsed message
+    // would be lost whenever an activity event arrived first
+    let messages = futures::stream::unfold(read, move |mut read| async move {
+        let mut parsed = message::parse_message(&mut read, &limits).await;
+        // skipped here, so that the next message is read from its start
+        if let Err(message::ParseMessageError::MessageTooLarge { unread, .. }) = parsed {
+            if let Err(e) = message::skip_message(&mut read, unread).await {
+                parsed = Err(e.into());
+            }
+        }
+        Some((parsed, read))
+    });
+    tokio::pin!(messages);
+
+    let mut state = ConnectionState { authenticated: node.0.auth_token.is_none(), ..Default::default() };
+    loop {
+        let parsed = tokio::select! {
+            parsed = messages.next() => parsed.expect("the message stream never ends"),
+            event = next_activity(&mut state.activity) => {
+                let event = match event {
+                    Ok(event) => Message::Activity(event),
+                    Err(broadcast::error::RecvError::Lagged(count)) => Message::ActivityDropped { count },
+                    Err(broadcast::error::RecvError::Closed) => unreachable!("the node outlives its connections"),
+                };
+                trace!(%event, "Sending activity");
+                if let Err(e) = message::write_message_as(&mut stream, message::UNSOLICITED_ID, event, &state.wire_format()).await {
+                    error!(?e, "IO error sending activity. Terminating");
+                    break;
+                }
+                continue;
+            }
+        };
+        let (id, message) = match parsed {
+            Ok(x) => x,
+            Err(message::ParseMessageError::IOError(e) ) => {
+                error!(?e, "IO error parsing command. Terminating");
+                break;
+            }
+            Err(e) => {
+                // skipped messages are answered, so the front node doesn't wait for a reply until it
+                // times out. Anything else leaves the stream out of sync
+                let (id, detail) = match e {
+                    message::ParseMessageError::MessageTooLarge { id, unread } => {
+                        warn!(?id, unread, ?limits, "Skipped a message over the size limits");
+                        (id, format!("message of {unread} bytes is over the node's limits, {limits:?}"))
+                    }
+                    e => match e.undecodable_id() {
+                        Some(id) => {
+                            warn!(?id, ?e, "Skipped a message which couldn't be decoded");
+                            (id, format!("could not decode message: {e:?}"))
+                        }
+                        None => {
+                            error!(?e, "Error parsing command. Terminating");
+                            break;
+                        }
+                    },
+                };
+                if !state.authenticated {
+                    warn!("Refused a connection which didn't authenticate. Terminating");
+                    break;
+                }
+                node.0.counters.errors.fetch_add(1, Ordering::Relaxed);
+                let reply = if state.error_codes {
+                    Message::ErrorCode { code: ErrorCode::BadRequest, detail }
+                } else {
+                    Message::Error(detail)
+                };
+                if let Err(e) = message::write_message_as(&mut stream, id, reply, &state.wire_format()).await {
+                    error!(?e, "IO error sending response. Terminating");
+                    break;
+                }
+                continue;
+            }
+        };
+
+        debug!(?id, %message, "Got a message");
+        if !state.authenticated {
+            match authenticate(&node, &message) {
+                Ok(()) => {
+                    debug!("Authenticated");
+                    state.authenticated = true;
+                    if let Err(e) = message::write_message(&mut stream, id, Message::Ack).await {
+                        error!(?e, "IO error sending response. Terminating");
+                        break;
+                    }
+                    continue;
+                }
+                Err(why) => {
+                    warn!(why, "Refused a connection which didn't authenticate. Terminating");
+                    // features aren't agreed on yet, so this is an Error rather than an ErrorCode
+                    if let Err(e) = message::write_message(&mut stream, id, Message::Error(why.to_string())).await {
+                        debug!(?e, "IO error sending refusal");
+                    }
+                    break;
+                }
+            }
+        }
+        let started = Instant::now();
+        let result = handle_message(&node, &mut state, &message).await;
+        node.record_activity(&message, &result, started.elapsed());
+        let reply = match result {
+            Ok(reply) => {
+                debug!(?id, %reply, "Replying");
+                reply
+            }
+            Err(e) => {
+                node.0.counters.errors.fetch_add(1, Ordering::Relaxed);
+                debug!(?e, %message, "Error handling message");
+                if state.error_codes {
+                    Message::ErrorCode { code: e.code(), detail: format!("{e:?}") }
+                } else {
+                    Message::Error(format!("{e:?}"))
+                }
+            }
+        };
+        if let Err(e) = message::write_message_as(&mut stream, id, reply, &state.wire_format()).await {
+            error!(?e, "IO error sending response. Terminating");
+            break;
+        }
+    }
+
+    for (uuid, write) in state.chunked_writes.drain() {
+        debug!(%uuid, written = write.written, "Connection closed during chunked write");
+        write.discard().await;
+    }
+}
+
+/// Whether the first message on a connection authenticates it, for nodes with an auth token
+fn authenticate(node: &Node, message: &Message) -> std::result::Result<(), &'static str> {
+    let Some(ref expected) = node.0.auth_token else {
+        return Ok(());
+    };
+    match message {
+        Message::Authenticate(token) if token.matches(expected) => Ok(()),
+        Message::Authenticate(_) => Err("wrong auth token"),
+        _ => Err("not authenticated; send Authenticate first"),
+    }
+}
+
+async fn handle_message(
+    node: &Node,
+    state: &mut ConnectionState,
+    message: &Message,
+) -> Result<Message> {
+    Ok(match message {
+        Message::Hello { protocol, features, compression } => {
+            let features: Vec<String> = match protocol.negotiate(&ProtocolVersions::OURS) {
+                Some(version) => {
+                    debug!(version, "Agreed on protocol version");
+                    state.binary_headers = WireFormat::binary_headers_for(version);
+                    features.iter()
+                        .filter(|feature| SUPPORTED_FEATURES.contains(&feature.as_str()))
+                        .cloned()
+                        .collect()
+                }
+                None => {
+                    // the front node gives up on the connection once it sees our versions
+                    warn!(%protocol, ours = %ProtocolVersions::OURS, "Front node speaks no protocol version this node does");
+                    state.binary_headers = false;
+                    Vec::new()
+                }
+            };
+            state.write_ack = features.iter().any(|feature| feature == message::FEATURE_WRITE_ACK);
+            state.error_codes = features.iter().any(|feature| feature == message::FEATURE_ERROR_CODES);
+            state.checksums = features.iter().any(|feature| feature == message::FEATURE_CHECKSUMS);
+            state.compression = features.iter()
+                .any(|feature| feature == message::FEATURE_ZSTD)
+                .then(|| compression.unwrap_or_default());
+            debug!(?features, compression = ?state.compression, "Enabled features");
+
+            Message::HelloBack { protocol: ProtocolVersions::OURS, features }
+        }
+        // already authenticated, or the node has no auth token
+        Message::Authenticate(_) => {
+            Message::Ack
+        }
+        Message::SubscribeActivity => {
+            debug!("Subscribed to activity");
+            state.activity = Some(node.0.activity.subscribe());
+            Message::Ack
+        }
+        Message::UnsubscribeActivity => {
+            debug!("Unsubscribed from activity");
+            state.activity = None;
+            Message::Ack
+        }
+        // answered right away, without touching any files
+        Message::Ping => {
+            Message::Pong
+        }
+        Message::GetVersion => {
+            Message::MyVersionIs(env!("CARGO_PKG_VERSION").to_string())
+        }
+        Message::ReadFile(uuid) => {
+            let lock = node.lock_file_read(uuid, "ReadFile request").await?;
+            let data = lock.read().await?;
+            node.0.counters.reads.fetch_add(1, Ordering::Relaxed);
+            node.0.counters.bytes_read.fetch_add(data.len() as u64, Ordering::Relaxed);
+
+            if state.checksums {
+                Message::CheckedFileContents { digest: lock.read_digest().await?, data }
+            } else {
+                Message::FileContents(data)
+            }
+        }
+        Message::ReadFileRange(uuid, offset, len) => {
+            let lock = node.lock_file_read(uuid, "ReadFileRange request").await?;
+            let (file_size, data) = lock.read_range(*offset, *len).await?;
+            node.0.counters.reads.fetch_add(1, Ordering::Relaxed);
+            node.0.counters.bytes_read.fetch_add(data.len() as u64, Ordering::Relaxed);
+
+            Message::FileRange { file_size, data }
+        }
+        Message::WriteFile(uuid, data) => write_file(node, state, uuid, data, None).await?,
+        Message::WriteFileChecked(uuid, digest, data) => {
+            check_digest(*uuid, digest, ContentDigest::of(digest.algorithm, data))?;
+            write_file(node, state, uuid, data, Some(digest)).await?
+        }
+        Message::WriteFileStart(uuid) => {
+            let limit = match node.0.faults.next_write_fault() {
+                Some(WriteFault::IOError(kind)) => return Err(OperationError::IOError(kind.into())),
+                Some(WriteFault::ShortWrite(n)) => Some(n as u64),
+                None => None,
+            };
+            let path = node.partial_path(*uuid);
+            let file = File::options()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&path)
+                .await
+                .map_err(OperationError::IOError)?;
+            trace!(path = %path.display(), "Partial file opened");
+
+            // starting again throws away what was written
+            if let Some(previous) = state.chunked_writes.insert(*uuid, ChunkedWrite { file, path, written: 0, limit }) {
+                debug!(%uuid, written = previous.written, "Restarted chunked write");
+            }
+            Message::Ack
+        }
+        Message::WriteFileChunk(uuid, data) => {
+            let Some(write) = state.chunked_writes.get_mut(uuid) else {
+                return Err(OperationError::NoChunkedWrite(*uuid));
+            };
+            let data = match write.limit {
+                Some(limit) => &data[..(limit.saturating_sub(write.written) as usize).min(data.len())],
+                None => &data[..],
+            };
+            if let Err(e) = write.file.write_all(data).await {
+                error!(?e, %uuid, "Could not write chunk; abandoning chunked write");
+                if let Some(write) = state.chunked_writes.remove(uuid) {
+                    write.discard().await;
+                }
+                return Err(OperationError::IOError(e));
+            }
+            write.written += data.len() as u64;
+
+            Message::Ack
+        }
+        Message::WriteFileEnd(uuid) | Message::WriteFileEndChecked(uuid, _) => {
+            let Some(write) = state.chunked_writes.remove(uuid) else {
+                return Err(OperationError::NoChunkedWrite(*uuid));
+            };
+            let digest = match message {
+                Message::WriteFileEndChecked(_, digest) => Some(digest),
+                _ => None,
+            };
+            let bytes_written = write.finish(node, *uuid, digest).await?;
+            node.0.counters.writes.fetch_add(1, Ordering::Relaxed);
+            node.0.counters.bytes_written.fetch_add(bytes_written, Ordering::Relaxed);
+
+            if state.write_ack {
+                Message::WriteAck { bytes_written, fsynced: true }
+            } else {
+                Message::Ack
+            }
+        }
+        Message::WriteFileAbort(uuid) => {
+            // nothing to abort is fine, e.g. when the write already failed
+            if let Some(write) = state.chunked_writes.remove(uuid) {
+                debug!(%uuid, written = write.written, "Aborted chunked write");
+                write.discard().await;
+            }
+            Message::Ack
+        }
+        Message::StatFile(uuid) => {
+            let lock = node.lock_file_read(uuid, "StatFile request").await?;
+            let size = lock.size().await?;
+
+            Message::FileStat { size }
+        }
+        Message::StorageInfo => {
+            let (bytes_total, bytes_available) = node.storage_info()?;
+            let file_count = node.file_count().await?;
+
+            Message::StorageInfoIs(StorageInfo { bytes_total, bytes_available, file_count: Some(file_count) })
+        }
+        Message::GetMetrics => {
+            Message::Metrics(node.metrics().await?)
+        }
+        Message::HashFile(uuid, algorithm) => {
+            let lock = node.lock_file_read(uuid, "HashFile request").await?;
+            let (size, digest) = lock.hash(*algorithm).await?;
+            node.0.counters.reads.fetch_add(1, Ordering::Relaxed);
+            node.0.counters.bytes_read.fetch_add(size, Ordering::Relaxed);
+
+            Message::FileHash { size, digest }
+        }
+        Message::VerifyFile(uuid) => {
+            let lock = node.lock_file_read(uuid, "VerifyFile request").await?;
+            let Some(expected) = lock.read_digest().await? else {
+                return Ok(Message::FileVerified { size: lock.size().await?, matches: None });
+            };
+            let (size, actual) = lock.hash(expected.algorithm).await?;
+            node.0.counters.reads.fetch_add(1, Ordering::Relaxed);
+            node.0.counters.bytes_read.fetch_add(size, Ordering::Relaxed);
+            if actual != expected {
+                error!(%uuid, %expected, %actual, "File no longer matches the digest it was written with");
+            }
+
+            Message::FileVerified { size, matches: Some(actual == expected) }
+        }
+        Message::DeleteFile(uuid) => {
+            let lock = node.lock_file_write(uuid, "DeleteFile request").await?;
+            lock.delete().await?;
+
+            Message::Ack
+        }
+        Message::ListFiles => {
+            Message::FileList(node.list_files().await?)
+        }
+        // responses
+        Message::HelloBack { .. }
+            | Message::MyVersionIs(_)
+            | Message::FileContents(_)
+            | Message::CheckedFileContents { .. }
+            | Message::FileRange { .. }
+            | Message::FileStat { .. }
+            | Message::StorageInfoIs { .. }
+            | Message::Metrics(_)
+            | Message::FileHash { .. }
+            | Message::FileVerified { .. }
+            | Message::WriteAck { .. }
+            | Message::FileList(_)
+            | Message::Pong
+            | Message::Ack
+            | Message::Error(_)
+            | Message::ErrorCode { .. }
+            | Message::Activity(_)
+            | Message::ActivityDropped { .. } => {
+            return Err(OperationError::NotARequest);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::DuplexStream;
+
+    use crate::message::{MessageID, ParseMessageError};
+
+    /// A node keeping its files in a fresh temporary folder, and a connection to it
+    struct TestNode {
+        node: Node,
+        /// removed once done with, unless this is another connection to the node
+        data_folder: Option<PathBuf>,
+        stream: DuplexStream,
+        next_id: u32,
+        /// as agreed on in hello
+        format: WireFormat,
+    }
+
+    impl TestNode {
+        async fn start() -> TestNode {
+            TestNode::start_with(None, FaultInjector::default()).await
+        }
+
+        async fn start_with(auth_token: Option<AuthToken>, faults: FaultInjector) -> TestNode {
+            let data_folder = std::env::temp_dir().join(format!("bnuystore-test-{}", Uuid::now_v7()));
+            let node = Node::with_faults(data_folder.clone(), Duration::ZERO, auth_token, faults).await.unwrap();
+            let stream = connect(&node);
+            TestNode { node, data_folder: Some(data_folder), stream, next_id: 0, format: WireFormat::default() }
+        }
+
+        /// Another connection to the same node, without any features agreed on
+        fn connect(&self) -> TestNode {
+            let stream = connect(&self.node);
+            TestNode { node: self.node.clone(), data_folder: None, stream, next_id: 0, format: WireFormat::default() }
+        }
+
+        fn file_names(&self) -> Vec<String> {
+            std::fs::read_dir(self.data_folder.as_ref().unwrap()).unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .collect()
+        }
+
+        async fn send(&mut self, request: Message) -> MessageID {
+            let id = MessageID(self.next_id);
+            self.next_id += 1;
+            message::write_message_as(&mut self.stream, id, request, &self.format).await.unwrap();
+            id
+        }
+
+        async fn reply(&mut self) -> std::result::Result<(MessageID, Message), ParseMessageError> {
+            message::parse_message(&mut self.stream, &MessageLimits::default()).await
+        }
+
+        async fn request(&mut self, request: Message) -> Message {
+            let id = self.send(request).await;
+            let (reply_id, reply) = self.reply().await.unwrap();
+            assert_eq!(reply_id, id);
+            reply
+        }
+
+        async fn read(&mut self, uuid: Uuid) -> Vec<u8> {
+            let reply = self.request(Message::ReadFile(uuid)).await;
+            let Message::CheckedFileContents { data, .. } = reply else { panic!("expected CheckedFileContents, got {reply}") };
+            data
+        }
+
+        /// Agrees on the current protocol version and every feature, as front nodes do
+        async fn hello(&mut self) {
+            let compression = Compression::default();
+            let hello = Message::Hello {
+                protocol: ProtocolVersions::OURS,
+                features: message::FEATURES.iter().map(|feature| feature.to_string()).collect(),
+                compression: Some(compression),
+            };
+            let Message::HelloBack { protocol, features } = self.request(hello).await else {
+                panic!("expected HelloBack");
+            };
+            assert_eq!(features.len(), message::FEATURES.len());
+            let version = protocol.negotiate(&ProtocolVersions::OURS).unwrap();
+            self.format = WireFormat { compression: Some(compression), binary_headers: WireFormat::binary_headers_for(version) };
+        }
+
+        /// A message with a header which isn't JSON, as some broken peer would send
+        async fn send_garbage(&mut self) -> MessageID {
+            let id = MessageID(self.next_id);
+            self.next_id += 1;
+            let header = b"this is not json";
+            self.stream.write_u32(id.0).await.unwrap();
+            self.stream.write_u32(header.len() as u32).await.unwrap();
+            self.stream.write_u64(0).await.unwrap();
+            self.stream.write_all(header).await.unwrap();
+            id
+        }
+    }
+
+    impl Drop for TestNode {
+        fn drop(&mut self) {
+            if let Some(data_folder) = &self.data_folder {
+                let _ = std::fs::remove_dir_all(data_folder);
+            }
+        }
+    }
+
+    fn connect(node: &Node) -> DuplexStream {
+        let (ours, theirs) = tokio::io::duplex(1 << 20);
+        tokio::spawn(serve_connection(node.clone(), theirs, MessageLimits::default()));
+        ours
+    }
+
+    #[tokio::test]
+    async fn undecodable_message_is_answered() {
+        let mut node = TestNode::start().await;
+        node.hello().await;
+
+        let id = node.send_garbage().await;
+        let (reply_id, reply) = node.reply().await.unwrap();
+        assert_eq!(reply_id, id);
+        assert!(matches!(reply, Message::ErrorCode { code: ErrorCode::BadRequest, .. }), "{reply}");
+
+        // the message was skipped whole, so the connection is still usable
+        assert!(matches!(node.request(Message::GetVersion).await, Message::MyVersionIs(_)));
+    }
+
+    #[tokio::test]
+    async fn failed_reads_are_answered() {
+        let mut node = TestNode::start().await;
+
+        // before agreeing on error codes, errors are plain
+        let reply = node.request(Message::ReadFile(Uuid::now_v7())).await;
+        assert!(matches!(reply, Message::Error(_)), "{reply}");
+
+        node.hello().await;
+        let reply = node.request(Message::ReadFile(Uuid::now_v7())).await;
+        assert!(matches!(reply, Message::ErrorCode { code: ErrorCode::NotFound, .. }), "{reply}");
+        // something which exists, but can't be read as a file
+        let unreadable = Uuid::now_v7();
+        std::fs::create_dir(node.data_folder.as_ref().unwrap().join(unreadable.hyphenated().to_string())).unwrap();
+        let reply = node.request(Message::ReadFile(unreadable)).await;
+        assert!(matches!(reply, Message::ErrorCode { code: ErrorCode::Io, .. }), "{reply}");
+
+        // the node and the connection are both still usable
+        let uuid = Uuid::now_v7();
+        assert!(matches!(node.request(Message::WriteFile(uuid, b"bnuy".to_vec())).await, Message::WriteAck { .. }));
+        assert_eq!(node.read(uuid).await, b"bnuy");
+        assert!(matches!(node.connect().request(Message::GetVersion).await, Message::MyVersionIs(_)));
+    }
+
+    #[tokio::test]
+    async fn undecodable_message_before_authenticating_closes_connection() {
+        let mut node = TestNode::start_with(Some(AuthToken("secret".to_string())), FaultInjector::default()).await;
+
+        node.send_garbage().await;
+        match node.reply().await {
+            Err(ParseMessageError::IOError(e)) if e.kind() == ErrorKind::UnexpectedEof => {}
+            reply => panic!("expected the connection to be closed, got {reply:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn chunked_writes_of_the_same_uuid_dont_mix() {
+        let mut a = TestNode::start().await;
+        a.hello().await;
+        let mut b = a.connect();
+        b.hello().await;
+
+        let uuid = Uuid::now_v7();
+        let (a_data, b_data) = (vec![b'a'; 3000], vec![b'b'; 1000]);
+        assert!(matches!(a.request(Message::WriteFileStart(uuid)).await, Message::Ack));
+        assert!(matches!(b.request(Message::WriteFileStart(uuid)).await, Message::Ack));
+        assert!(matches!(a.request(Message::WriteFileChunk(uuid, a_data[..2000].to_vec())).await, Message::Ack));
+        assert!(matches!(b.request(Message::WriteFileChunk(uuid, b_data.clone())).await, Message::Ack));
+        assert!(matches!(a.request(Message::WriteFileChunk(uuid, a_data[2000..].to_vec())).await, Message::Ack));
+
+        // each write replaces the file whole when it ends
+        assert!(matches!(b.request(Message::WriteFileEnd(uuid)).await, Message::WriteAck { bytes_written: 1000, .. }));
+        let Message::CheckedFileContents { data, .. } = a.request(Message::ReadFile(uuid)).await else { panic!() };
+        assert_eq!(data, b_data);
+
+        assert!(matches!(a.request(Message::WriteFileEnd(uuid)).await, Message::WriteAck { bytes_written: 3000, .. }));
+        let Message::CheckedFileContents { data, .. } = b.request(Message::ReadFile(uuid)).await else { panic!() };
+        assert_eq!(data, a_data);
+    }
+
+    #[tokio::test]
+    async fn empty_files_are_read_as_empty() {
+        let mut node = TestNode::start().await;
+        node.hello().await;
+        let uuid = Uuid::now_v7();
+
+        assert!(matches!(node.request(Message::WriteFile(uuid, Vec::new())).await, Message::WriteAck { bytes_written: 0, .. }));
+        assert_eq!(node.read(uuid).await, Vec::<u8>::new());
+        // the first range is already at the end, which the front node takes as end of file
+        let reply = node.request(Message::ReadFileRange(uuid, 0, 4096)).await;
+        assert!(matches!(reply, Message::FileRange { file_size: 0, ref data } if data.is_empty()), "{reply}");
+
+        assert!(matches!(node.request(Message::WriteFileStart(uuid)).await, Message::Ack));
+        assert!(matches!(node.request(Message::WriteFileEnd(uuid)).await, Message::WriteAck { bytes_written: 0, .. }));
+        assert_eq!(node.read(uuid).await, Vec::<u8>::new());
+    }
+
+    #[tokio::test]
+    async fn unfinished_writes_are_not_counted() {
+        let mut node = TestNode::start().await;
+        node.hello().await;
+
+        let uuid = Uuid::now_v7();
+        assert!(matches!(node.request(Message::WriteFileStart(uuid)).await, Message::Ack));
+        assert!(matches!(node.request(Message::WriteFileChunk(uuid, vec![1; 100])).await, Message::Ack));
+
+        let Message::StorageInfoIs(info) = node.request(Message::StorageInfo).await else { panic!() };
+        assert_eq!(info.file_count, Some(0));
+        let Message::Metrics(metrics) = node.request(Message::GetMetrics).await else { panic!() };
+        assert_eq!((metrics.files_stored, metrics.bytes_stored), (0, 0));
+        let Message::FileList(files) = node.request(Message::ListFiles).await else { panic!() };
+        assert!(files.is_empty());
+    }
+
+    #[tokio::test]
+    async fn metrics_count_operations() {
+        let mut node = TestNode::start().await;
+        node.hello().await;
+        let Message::Metrics(before) = node.request(Message::GetMetrics).await else { panic!() };
+        assert_eq!((before.reads, before.writes, before.errors, before.lock_acquisitions), (0, 0, 0, 0));
+
+        let uuid = Uuid::now_v7();
+        assert!(matches!(node.request(Message::WriteFile(uuid, vec![1; 100])).await, Message::WriteAck { bytes_written: 100, .. }));
+        assert!(matches!(node.request(Message::WriteFileStart(uuid)).await, Message::Ack));
+        assert!(matches!(node.request(Message::WriteFileChunk(uuid, vec![2; 30])).await, Message::Ack));
+        assert!(matches!(node.request(Message::WriteFileEnd(uuid)).await, Message::WriteAck { bytes_written: 30, .. }));
+        assert_eq!(node.read(uuid).await, vec![2; 30]);
+        let missing = node.request(Message::ReadFile(Uuid::now_v7())).await;
+        assert!(matches!(missing, Message::ErrorCode { .. }), "{missing}");
+
+        let Message::Metrics(after) = node.request(Message::GetMetrics).await else { panic!() };
+        assert_eq!((after.files_stored, after.bytes_stored), (1, 30));
+        assert_eq!((after.writes, after.bytes_written), (2, 130));
+        assert_eq!((after.reads, after.bytes_read), (1, 30));
+        assert_eq!(after.errors, 1);
+        assert!(after.lock_acquisitions >= 3, "{}", after.lock_acquisitions);
+        assert!(after.bytes_total >= after.bytes_available);
+    }
+
+    #[tokio::test]
+    async fn lock_waiters_get_the_shutdown_error() {
+        let mut node = TestNode::start().await;
+        node.hello().await;
+        let uuid = Uuid::now_v7();
+        let held = node.node.lock_file_write(&uuid, "held").await.unwrap();
+
+        let waiter = tokio::spawn({
+            let node = node.node.clone();
+            async move { node.lock_file_read(&uuid, "waiter").await.map(|_| ()) }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiter.is_finished(), "the waiter should be waiting for the held lock");
+
+        node.node.shut_down();
+        let waited = tokio::time::timeout(Duration::from_secs(1), waiter).await
+            .expect("the waiter should be woken by the shutdown")
+            .unwrap();
+        assert!(matches!(waited, Err(OperationError::ShuttingDown)), "{waited:?}");
+        let reply = node.request(Message::ReadFile(Uuid::now_v7())).await;
+        assert!(matches!(reply, Message::ErrorCode { code: ErrorCode::ShuttingDown, .. }), "{reply}");
+
+        // held locks are unaffected, and waited for
+        assert!(!node.node.wait_unlocked(Duration::from_millis(10)).await);
+        drop(held);
+        assert!(node.node.wait_unlocked(Duration::from_secs(1)).await);
+    }
+
+    #[test]
+    fn locks_dropped_while_the_runtime_shuts_down_are_released() {
+        let data_folder = std::env::temp_dir().join(format!("bnuystore-test-{}", Uuid::now_v7()));
+        let uuids: Vec<Uuid> = (0..8).map(|_| Uuid::now_v7()).collect();
+
+        for _ in 0..20 {
+            let runtime = tokio::runtime::Builder::new_multi_thread().worker_threads(2).enable_all().build().unwrap();
+            // with watchdogs, so their tasks are aborted from Drop too
+            let node = runtime.block_on(Node::new(data_folder.clone(), Duration::from_secs(60), None)).unwrap();
+            for i in 0..64 {
+                let (node, uuid) = (node.clone(), uuids[i % uuids.len()]);
+                runtime.spawn(async move {
+                    let lock = match i % 2 {
+                        0 => node.lock_file_read(&uuid, "stress").await,
+                        _ => node.lock_file_write(&uuid, "stress").await,
+                    };
+                    // held, or waited for, until the runtime drops the task
+                    std::future::pending::<()>().await;
+                    drop(lock);
+                });
+            }
+            runtime.block_on(async { tokio::time::sleep(Duration::from_millis(5)).await });
+            drop(runtime);
+
+            let locked_files = node.0.locked_files.lock().unwrap();
+            assert!(locked_files.locked.is_empty(), "{:?}", locked_files.locked);
+        }
+        std::fs::remove_dir_all(data_folder).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn writes_are_whole_or_absent_after_shutdown() {
+        let mut node = TestNode::start().await;
+        node.hello().await;
+        let data_folder = node.data_folder.clone().unwrap();
+        // stands in for SIGTERM, which storage_node_main passes
+        let (shut_down, signalled) = tokio::sync::oneshot::channel::<()>();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let serving = tokio::spawn(serve(node.node.clone(), listener, None, MessageLimits::default(), Duration::from_secs(30), async {
+            let _ = signalled.await;
+        }));
+
+        // one chunked write still being sent, and one large write being written, when the signal comes
+        let chunked = Uuid::now_v7();
+        assert!(matches!(node.request(Message::WriteFileStart(chunked)).await, Message::Ack));
+        assert!(matches!(node.request(Message::WriteFileChunk(chunked, vec![1; 1 << 20])).await, Message::Ack));
+        let whole = Uuid::now_v7();
+        let data = vec![2; 32 << 20];
+        let id = node.send(Message::WriteFile(whole, data.clone())).await;
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        shut_down.send(()).unwrap();
+
+        let (reply_id, reply) = node.reply().await.unwrap();
+        assert_eq!(reply_id, id);
+        let on_disk = std::fs::read(data_folder.join(whole.hyphenated().to_string()));
+        match reply {
+            Message::WriteAck { bytes_written, .. } => {
+                assert_eq!(bytes_written, data.len() as u64);
+                assert!(on_disk.unwrap() == data, "the acknowledged file isn't whole");
+            }
+            Message::ErrorCode { code: ErrorCode::ShuttingDown, .. } => {
+                assert_eq!(on_disk.unwrap_err().kind(), ErrorKind::NotFound);
+            }
+            reply => panic!("expected the write to finish or be refused, got {reply}"),
         }
+        serving.await.unwrap();
+
+        let reply = node.request(Message::WriteFileEnd(chunked)).await;
+        assert!(matches!(reply, Message::ErrorCode { code: ErrorCode::ShuttingDown, .. }), "{reply}");
+        let left: Vec<String> = std::fs::read_dir(&data_folder).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.starts_with(&chunked.hyphenated().to_string()))
+            .collect();
+        assert!(left.is_empty(), "the refused chunked write left {left:?}");
+    }
+
+    #[tokio::test]
+    async fn shorter_writes_replace_longer_files() {
+        let node = TestNode::start().await;
+        let uuid = Uuid::now_v7();
+        let lock = node.node.lock_file_write(&uuid, "test").await.unwrap();
+
+        assert_eq!(lock.write(vec![1; 1000]).await.unwrap(), 1000);
+        assert_eq!(lock.write(vec![2; 10]).await.unwrap(), 10);
+        assert_eq!(lock.read().await.unwrap(), vec![2; 10]);
+
+        assert_eq!(lock.write(Vec::new()).await.unwrap(), 0);
+        assert_eq!(lock.read().await.unwrap(), Vec::<u8>::new());
+
+        // and no partial file is left behind
+        assert_eq!(node.file_names(), [lock.basename()]);
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn failed_writes_keep_the_old_contents() {
+        let faults = FaultInjector::new();
+        let node = TestNode::start_with(None, faults.clone()).await;
+        let uuid = Uuid::now_v7();
+        let lock = node.node.lock_file_write(&uuid, "test").await.unwrap();
+        lock.write(b"old contents".to_vec()).await.unwrap();
+        let digest = ContentDigest::of(HashAlgorithm::Blake3, b"old contents");
+        lock.write_digest(Some(&digest)).await.unwrap();
+
+        faults.inject_write_fault(WriteFault::IOError(ErrorKind::Other));
+        assert!(matches!(lock.write(b"new".to_vec()).await, Err(OperationError::IOError(_))));
+        // along with the digest it was written with
+        assert_eq!(lock.read().await.unwrap(), b"old contents");
+        assert_eq!(lock.read_digest().await.unwrap(), Some(digest));
+        let mut file_names = node.file_names();
+        file_names.sort();
+        assert_eq!(file_names, [lock.basename(), format!("{}{DIGEST_SUFFIX}", lock.basename())]);
+
+        // a write which goes through replaces both
+        assert_eq!(lock.write(b"new".to_vec()).await.unwrap(), 3);
+        assert_eq!(lock.read().await.unwrap(), b"new");
+        assert_eq!(lock.read_digest().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn partial_files_are_removed_at_startup() {
+        let node = TestNode::start().await;
+        let data_folder = node.data_folder.clone().unwrap();
+        let partial = data_folder.join(format!("{}{PARTIAL_SUFFIX}", Uuid::now_v7()));
+        std::fs::write(&partial, b"cut short").unwrap();
+
+        Node::new(data_folder, Duration::ZERO, None).await.unwrap();
+        assert!(!partial.exists());
     }
 }
@@ -1,24 +1,29 @@
 #[allow(unused)]
 use tracing::{trace, debug, info, warn, error, instrument};
 
-use tracing_subscriber::fmt::{self, format::FmtSpan};
-use tracing_subscriber::filter::EnvFilter;
-use tracing_subscriber::prelude::*;
-
 use clap::Parser;
 use std::path::PathBuf;
 use std::net::SocketAddr;
+use std::time::Duration;
 use tokio::net::TcpSocket;
+use tokio::signal::unix::{signal, SignalKind};
 
 mod message;
-use message::Message;
+mod hashing;
+mod fault_injection;
+mod logging;
+mod tls;
 
 mod storage_node;
-use storage_node::{Node, OperationError};
+use storage_node::Node;
+
+/// Connections waiting to be accepted. Connections are accepted as soon as they arrive, so this
+/// only matters when several front nodes connect at once
+const LISTEN_BACKLOG: u32 = 64;
 
 #[derive(Debug, Parser)]
 #[command(version, about)]
-struct CLI {
+struct Cli {
     /// address to bind on, ip:port
     #[arg(short='a', long="addr")]
     bind_addr: String,
@@ -29,19 +34,42 @@ struct CLI {
     /// folder to store all files in
     #[arg(short='d', long="data-dir")]
     data_directory: PathBuf,
+
+    /// file containing a token front nodes have to send before anything else, given as auth_token
+    /// in their config. without it, anyone who can connect can read and write files
+    #[arg(long="auth-token-file")]
+    auth_token_file: Option<PathBuf>,
+
+    /// certificate chain to serve connections with over TLS, as PEM. front nodes need tls = true
+    #[arg(long="tls-cert", requires="tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// private key for --tls-cert, as PEM
+    #[arg(long="tls-key", requires="tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// warn about files locked for longer than this, again each time it passes. 0 to never warn
+    #[arg(long="lock-warn-after-s", default_value_t=storage_node::DEFAULT_LOCK_WARN_AFTER.as_secs_f64())]
+    lock_warn_after_s: f64,
+
+    /// messages from front nodes with longer headers are skipped and answered with an error
+    #[arg(long="max-header-bytes", default_value_t=message::DEFAULT_MAX_HEADER_BYTES)]
+    max_header_bytes: u32,
+    /// likewise for data, e.g. the contents of WriteFile. files larger than this can't be written
+    #[arg(long="max-data-bytes", default_value_t=message::DEFAULT_MAX_DATA_BYTES)]
+    max_data_bytes: u64,
+
+    /// when stopping, wait this long for in-flight operations to finish
+    #[arg(long="shutdown-grace-s", default_value_t=30.0)]
+    shutdown_grace_s: f64,
+
+    #[command(flatten)]
+    log: logging::LogOptions,
 }
 
 #[tokio::main]
 async fn main() {
-    tracing_subscriber::registry()
-        .with(
-            fmt::layer()
-                .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE)
-        )
-        .with(EnvFilter::from_default_env())
-        .init();
-
-    let cli = CLI::parse();
+    let cli = Cli::parse();
+    let _log_guard = logging::init(&cli.log, false).expect("Could not set up logging");
 
     let addr: SocketAddr = cli.bind_addr.parse().expect("Could not parse socket address");
 
@@ -57,76 +85,41 @@ async fn main() {
     }
 
     socket.bind(addr).expect("Could not bind socket to address");
-    let listener = socket.listen(1).expect("Could not listen on socket"); // backlog of 1, we should never have more than one connection
+    // each front node makes a few connections, see connections in its config
+    let listener = socket.listen(LISTEN_BACKLOG).expect("Could not listen on socket");
 
     info!("Listening for connections");
 
-    let node = Node::new(cli.data_directory).await.expect("Could not initialize node");
-
-    loop {
-        let (mut stream, addr) = listener.accept().await.expect("Could not accept connection");
-        info!(%addr, "Got a connection");
-
-        let node = node.clone();
-        tokio::task::spawn(async move {
-            loop {
-                let (id, message) = match message::parse_message(&mut stream).await {
-                    Ok(x) => x,
-                    Err(message::ParseMessageError::IOError(e) ) => {
-                        error!(?e, "IO error parsing command. Terminating");
-                        break;
-                    }
-                    Err(e) => {
-                        error!(?e, "(recoverable?) Error parsing command");
-                        continue;
-                    }
-                };
-
-                debug!(?id, %message, "Got a message");
-                match handle_message(&node, &message).await {
-                    Ok(reply) => {
-                        debug!(?id, %reply, "Replying");
-                        message::write_message(&mut stream, id, reply)
-                            .await
-                            .expect("Could not send response")
-                    }
-                    Err(e) => {
-                        debug!(?e, %message, ?e, "Error handling message");
-                        let reply = Message::Error(format!("{e:?}"));
-                        message::write_message(&mut stream, id, reply)
-                            .await
-                            .expect("Could not send response")
-                    }
-                }
+    let lock_warn_after = Duration::try_from_secs_f64(cli.lock_warn_after_s).expect("Invalid --lock-warn-after-s");
+    let shutdown_grace = Duration::try_from_secs_f64(cli.shutdown_grace_s).expect("Invalid --shutdown-grace-s");
+    let auth_token = match cli.auth_token_file {
+        Some(path) => {
+            let contents = std::fs::read_to_string(&path).expect("Could not read --auth-token-file");
+            let token = contents.trim_end_matches(['\r', '\n']);
+            if token.is_empty() {
+                panic!("--auth-token-file {} is empty", path.display());
             }
-        });
-    }
-}
-
-async fn handle_message(
-    node: &Node,
-    message: &Message,
-) -> Result<Message, OperationError> {
-    Ok(match message {
-        Message::GetVersion => {
-            Message::MyVersionIs(env!("CARGO_PKG_VERSION").to_string())
+            Some(message::AuthToken(token.to_string()))
         }
-        Message::ReadFile(uuid) => {
-            let lock = node.lock_file(uuid, "ReadFile request").await;
-            let data = lock.read().await.expect("could not read specified file");
-
-            Message::FileContents(data)
+        None => {
+            warn!("No --auth-token-file given; accepting requests from anyone who can connect");
+            None
         }
-        Message::WriteFile(uuid, data) => {
-            let lock = node.lock_file(uuid, "WriteFile request").await;
-            lock.write(data.clone()).await.expect("could not read specified file");
-
-            Message::Ack
+    };
+    let tls = match (cli.tls_cert, cli.tls_key) {
+        (Some(cert), Some(key)) => Some(tls::acceptor(&cert, &key).expect("Could not load the TLS certificate")),
+        _ => None,
+    };
+    let limits = message::MessageLimits { max_header_bytes: cli.max_header_bytes, max_data_bytes: cli.max_data_bytes };
+    let node = Node::new(cli.data_directory, lock_warn_after, auth_token).await.expect("Could not initialize node");
+
+    let mut sigterm = signal(SignalKind::terminate()).expect("Could not listen for SIGTERM");
+    let signalled = async move {
+        tokio::select! {
+            _ = sigterm.recv() => info!("Got SIGTERM"),
+            _ = tokio::signal::ctrl_c() => info!("Got ctrl-c"),
         }
-        Message::DeleteFile(_) => todo!(),
-        Message::MyVersionIs(_) => todo!(),
-        Message::FileContents(_) => todo!(),
-        Message::Ack => todo!(),
-        Message::Error(_) => todo!(),
-    })
+    };
+    storage_node::serve(node, listener, tls, limits, shutdown_grace, signalled).await;
+    info!("Shut down");
 }